@@ -1,50 +1,1248 @@
+use crate::leaf::{LeafHasher, LeafHashing};
 use crate::merkle;
+use crate::sha3::{hash_both, hash_content, tombstone_of};
+use crate::signing;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+macro_rules! number_newtype {
+    ($(#[$meta:meta])* $name:ident($inner:ty)) => {
+        $(#[$meta])*
+        #[derive(
+            Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+        )]
+        #[serde(transparent)]
+        pub struct $name(pub $inner);
+
+        impl $name {
+            pub fn as_usize(self) -> usize {
+                self.0 as usize
+            }
+        }
+
+        impl From<$inner> for $name {
+            fn from(value: $inner) -> Self {
+                Self(value)
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = std::num::ParseIntError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                s.parse().map(Self)
+            }
+        }
+    };
+}
+
+number_newtype! {
+    /// Id of stored file, given out in upload order. Unlike leaf index it keeps naming the same
+    /// file across epochs, deletions and undeletions.
+    FileId(u32)
+}
+
+number_newtype! {
+    /// Position of a leaf in the tree of all leaves, epoch trees start at their `first_leaf`
+    LeafIndex(u64)
+}
+
+number_newtype! {
+    /// Number of leaves of a tree or of its prefix, reserved slots included
+    TreeSize(u64)
+}
+
+impl FileId {
+    /// Ids are handed out by storage as positions in its file table
+    pub fn from_usize(id: usize) -> Self {
+        Self(id as u32)
+    }
+}
+
+impl From<usize> for LeafIndex {
+    fn from(index: usize) -> Self {
+        Self(index as u64)
+    }
+}
+
+impl From<usize> for TreeSize {
+    fn from(size: usize) -> Self {
+        Self(size as u64)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct File {
-    pub id: u32,
+    pub id: FileId,
+    pub name: String,
+    /// grows when files with the same name are uploaded again
+    #[serde(default)]
+    pub version: u32,
+    /// content type detected on upload
+    #[serde(default)]
+    pub mime: String,
+    /// content length in bytes
+    #[serde(default)]
+    pub size: u64,
+    /// unix time in seconds after which file is tombstoned
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// file was tombstoned because it expired, listed only with `include_expired`
+    #[serde(default)]
+    pub expired: bool,
+    /// CID of the content once it was exported to IPFS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipfs_cid: Option<String>,
+    /// file was tombstoned, listed only with `include_expired` or `deleted`
+    #[serde(default)]
+    pub deleted: bool,
+    /// id of deleted file this one was undeleted from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub undeleted_from: Option<FileId>,
+    /// leaf of the file, listed only when `leaves` are requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leaf: Option<ListedLeaf>,
+    /// times content of the file was downloaded
+    #[serde(default)]
+    pub downloads: u64,
+    /// unix time in seconds of the latest download
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_access: Option<u64>,
+    /// dataset the file was uploaded into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dataset: Option<String>,
+}
+
+/// Leaf of listed file, which lets clients check listed name against leaves they already trust
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListedLeaf {
+    pub leaf_index: LeafIndex,
+    pub hash: merkle::Sha3Hash,
+    /// hash of the content, listed with name-hash leaves which are derived from it and the name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<merkle::Sha3Hash>,
+}
+
+/// Response of `POST /files`, root is taken under the same lock the leaf was appended with, so it
+/// can't include leaves of other writers appended after it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredFile {
+    #[serde(flatten)]
+    pub file: File,
+    /// root and size of the epoch tree right after the leaf was appended, missing while earlier
+    /// reserved slots are not filled
+    #[serde(default)]
+    pub root: Option<RootHash>,
+    /// missing if server has no key or root is missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<UploadReceipt>,
+}
+
+/// Files uploaded or downloaded in one batch request at most, batches are answered in one body
+pub const MAX_BATCH_FILES: usize = 1000;
+
+/// Bytes of file contents downloaded in one batch at most, as much as a batch upload body can
+/// carry
+pub const MAX_BATCH_BYTES: u64 = crate::codec::MAX_BODY_SIZE as u64;
+
+/// Request of `POST /files/batch/upload`, files are stored all together or none of them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewFileBatch {
+    pub files: Vec<BatchUploadFile>,
+    /// seconds after which files expire, kept forever if missing
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// works as in [`NewFile`] for the first file of the batch
+    #[serde(default)]
+    pub expected_tree_size: Option<TreeSize>,
+    /// dataset all files join in batch order
+    #[serde(default)]
+    pub dataset: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchUploadFile {
+    #[serde(with = "base64")]
+    pub content: Vec<u8>,
     pub name: String,
 }
 
+/// Response of `POST /files/batch/upload`, leaves of the files are consecutive
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredBatch {
+    pub files: Vec<StoredBatchFile>,
+    /// root right after the last leaf of the batch was appended, missing while earlier reserved
+    /// slots are not filled
+    #[serde(default)]
+    pub root: Option<RootHash>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredBatchFile {
+    #[serde(flatten)]
+    pub file: File,
+    pub leaf_index: LeafIndex,
+    /// missing if server has no key or root is missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<UploadReceipt>,
+}
+
+/// Query of `GET /files`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FileListQuery {
+    /// also list expired files, including ones already tombstoned
+    #[serde(default)]
+    pub include_expired: bool,
+    /// list only tombstoned files, both deleted and expired
+    #[serde(default)]
+    pub deleted: bool,
+    /// attach leaf to each listed file, see [`ListedLeaf`]
+    #[serde(default)]
+    pub leaves: bool,
+    #[serde(default)]
+    pub sort: FileSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// files are listed in groups instead of a flat list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<FileGrouping>,
+    /// files listed in one page at most, capped by [`MAX_PAGE_LIMIT`]. Paged listing is a
+    /// snapshot of the tree as it was when the first page was asked for, see [`Cursor`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<Cursor>,
+}
+
+/// Entries listed in one page at most
+pub const MAX_PAGE_LIMIT: usize = 1000;
+
+/// Position in paged listing: how many entries of the snapshot of a tree of `tree_size` leaves
+/// were listed already. Pages keep listing that snapshot while other writers append and delete,
+/// so no entry is skipped or listed twice. Opaque to clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub tree_size: u64,
+    pub offset: u64,
+    /// id of the last listed file, file listing seeks past it instead of skipping `offset`
+    /// entries
+    pub after: Option<u64>,
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ::base64::Engine;
+        let mut bytes = self.tree_size.to_be_bytes().to_vec();
+        bytes.extend(self.offset.to_be_bytes());
+        if let Some(after) = self.after {
+            bytes.extend(after.to_be_bytes());
+        }
+        let encoded = ::base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+        write!(f, "{encoded}")
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ::base64::Engine;
+        let bytes = ::base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(s)?;
+        if bytes.len() != 16 && bytes.len() != 24 {
+            anyhow::bail!("invalid cursor {s}");
+        }
+        let mut words = bytes
+            .chunks_exact(8)
+            .map(|word| u64::from_be_bytes(word.try_into().expect("should be 8 bytes")));
+        Ok(Cursor {
+            tree_size: words.next().expect("should have tree size"),
+            offset: words.next().expect("should have offset"),
+            after: words.next(),
+        })
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let cursor = String::deserialize(deserializer)?;
+        cursor.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Query of `GET /leaves`, all leaves are listed without `limit`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PageQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<Cursor>,
+}
+
+/// What files are listed by
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSort {
+    /// upload order
+    #[default]
+    Created,
+    Name,
+    Size,
+}
+
+impl FromStr for FileSort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "created" => Ok(FileSort::Created),
+            "name" => Ok(FileSort::Name),
+            "size" => Ok(FileSort::Size),
+            _ => Err(anyhow::anyhow!("sort must be one of created, name or size")),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl FromStr for SortOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(anyhow::anyhow!("order must be either asc or desc")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileGrouping {
+    /// by top level directory of the name
+    Prefix,
+}
+
+impl FromStr for FileGrouping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefix" => Ok(FileGrouping::Prefix),
+            _ => Err(anyhow::anyhow!("files can only be grouped by prefix")),
+        }
+    }
+}
+
+/// Response of `GET /files`, grouped listing leaves `files` empty
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileList {
     pub files: Vec<File>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<FileGroup>,
+    /// cursor of the next page of paged listing, missing on the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<Cursor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileGroup {
+    /// top level directory including trailing `/`, empty for files outside of any directory
+    pub prefix: String,
+    pub files: Vec<File>,
+}
+
+impl FileGroup {
+    /// Groups listed files by top level directory of their names. Groups come in order of their
+    /// first file, files keep their order within the group.
+    pub fn by_prefix(files: Vec<File>) -> Vec<FileGroup> {
+        let mut groups: Vec<FileGroup> = Vec::new();
+        let mut index = HashMap::new();
+        for file in files {
+            let prefix = match file.name.split_once('/') {
+                Some((dir, _)) => format!("{dir}/"),
+                None => String::new(),
+            };
+            let group = *index.entry(prefix.clone()).or_insert_with(|| {
+                groups.push(FileGroup {
+                    prefix,
+                    files: Vec::new(),
+                });
+                groups.len() - 1
+            });
+            groups[group].files.push(file);
+        }
+        groups
+    }
+}
+
+/// Query of `GET /files/changes`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FileChangesQuery {
+    /// tree size returned by the previous poll, 0 lists everything
+    #[serde(default)]
+    pub since_tree_size: TreeSize,
+}
+
+/// Listing delta since a tree size, files appended after it and ids deleted since
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileChanges {
+    pub files: Vec<File>,
+    #[serde(default)]
+    pub deleted: Vec<FileId>,
+    /// committed tree size to pass as `since_tree_size` next time
+    pub tree_size: TreeSize,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileContent {
-    pub id: u32,
+    pub id: FileId,
     pub name: String,
     #[serde(with = "base64")]
     pub content: Vec<u8>,
+    /// proof against root of `epoch`, which is the current tree or sealed checkpoint
+    pub proof: merkle::Sha3Proof,
+    #[serde(default)]
+    pub epoch: u32,
+}
+
+/// Metadata of `GET /files/{id}/raw`, whose body is the raw content
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RawFileMeta {
+    pub id: FileId,
+    pub name: String,
+    pub epoch: u32,
     pub proof: merkle::Sha3Proof,
 }
 
+/// Request of `POST /files/batch`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchRequest {
+    pub ids: Vec<FileId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchFile {
+    pub id: FileId,
+    pub name: String,
+    pub leaf_index: LeafIndex,
+    #[serde(with = "base64")]
+    pub content: Vec<u8>,
+}
+
+/// Files of the same epoch proven all at once by a single multi-proof against `root`. Proof
+/// covers leaves of the epoch tree, which starts at `first_leaf`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileBatch {
+    pub files: Vec<BatchFile>,
+    pub proof: merkle::Sha3MultiProof,
+    pub root: merkle::Sha3Hash,
+    #[serde(default)]
+    pub epoch: u32,
+    #[serde(default)]
+    pub first_leaf: LeafIndex,
+}
+
+impl FileBatch {
+    /// Files are proven by leaves derived with given hasher, which has to match the server one
+    pub fn verify(&self, root: &merkle::Sha3Hash, hasher: &dyn LeafHasher) -> bool {
+        let leaves: Vec<_> = self
+            .files
+            .iter()
+            .map(|file| {
+                let index = file.leaf_index.0.checked_sub(self.first_leaf.0)?;
+                Some((index as usize, hasher.leaf(&file.name, &file.content)))
+            })
+            .collect::<Option<_>>()
+            .unwrap_or_default();
+        !leaves.is_empty() && self.root == *root && self.proof.verify(root, &leaves)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewFile {
     #[serde(with = "base64")]
     pub content: Vec<u8>,
     pub name: String,
+    /// seconds after which file expires, kept forever if missing
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// upload is refused with conflict unless the tree has exactly this many leaves, including
+    /// reserved ones, so writer can tell nobody else appended since it last looked
+    #[serde(default)]
+    pub expected_tree_size: Option<TreeSize>,
+    /// dataset file joins, see [`Dataset`]
+    #[serde(default)]
+    pub dataset: Option<String>,
 }
 
+/// Query of `POST /files/stream`, content is the raw request body
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamQuery {
+    pub name: String,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// same as [`NewFile::expected_tree_size`]
+    #[serde(default)]
+    pub expected_tree_size: Option<TreeSize>,
+    #[serde(default)]
+    pub dataset: Option<String>,
+}
+
+/// Query of `POST /files/quarantine`, content is the raw request body. Ttl starts once the file is
+/// committed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuarantineQuery {
+    pub name: String,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+/// Stage of two-phase ingestion, files uploaded any other way are committed right away
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IngestStatus {
+    /// kept in quarantine out of the tree until validation decides about it
+    Pending,
+    Committed,
+    Rejected,
+}
+
+/// Response of `POST /files/quarantine` and `GET /files/{id}/status`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileStatus {
+    pub id: FileId,
+    pub status: IngestStatus,
+    /// why validation rejected the upload
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// leaf hash of committed file, with its proof against root of its epoch tree
+    #[serde(default)]
+    pub hash: Option<merkle::Sha3Hash>,
+    #[serde(default)]
+    pub proof: Option<merkle::Sha3Proof>,
+    #[serde(default)]
+    pub root: Option<RootHash>,
+    /// receipt of committed file, missing if server has no key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<UploadReceipt>,
+}
+
+/// Request of `POST /files/import`, content is fetched by the server from given url
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportFile {
+    pub url: String,
+    /// name to store content under, last url path segment is used if missing
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+/// Imported file with its leaf hash, since client never saw the content
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportedFile {
+    pub file: File,
+    pub hash: merkle::Sha3Hash,
+    /// proof of the leaf and root it leads to, missing while earlier reserved slots are not filled
+    pub proof: Option<merkle::Sha3Proof>,
+    pub root: Option<merkle::Sha3Hash>,
+    /// missing if server has no key or root is missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<UploadReceipt>,
+}
+
+/// Response of `POST /files/{id}/ipfs`, CID of the content pinned to IPFS node next to its leaf
+/// hash, so both can be checked to be of the same content
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IpfsExport {
+    pub id: FileId,
+    pub cid: String,
+    pub hash: merkle::Sha3Hash,
+}
+
+/// Query of `GET /files/{id}/preview`, `bytes` is capped by the server
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreviewQuery {
+    pub bytes: Option<usize>,
+}
+
+/// Leaf slot reserved for file which will be uploaded with `PUT /files/{id}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reservation {
+    pub id: FileId,
+    pub leaf_index: LeafIndex,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeafList {
+    pub leaves: Vec<merkle::Sha3Hash>,
+    /// cursor of the next page of paged listing, missing on the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<Cursor>,
+}
+
+/// Latest tree head: root of the current epoch tree with its size
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RootHash {
     pub hash: merkle::Sha3Hash,
+    #[serde(default)]
+    pub size: TreeSize,
+    #[serde(default)]
+    pub epoch: u32,
+}
+
+/// Query of `GET /consistency`, `new_size` defaults to the whole tree size and `epoch` to the
+/// current one
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConsistencyQuery {
+    pub old_size: TreeSize,
+    #[serde(default)]
+    pub new_size: Option<TreeSize>,
+    #[serde(default)]
+    pub epoch: Option<u32>,
+}
+
+/// Proof that tree of `old_size` leaves is a prefix of tree of `new_size` leaves with given root
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Consistency {
+    pub proof: merkle::Sha3ConsistencyProof,
+    pub root: merkle::Sha3Hash,
+}
+
+/// Membership proof of the first leaf with hash asked for by `GET /proofs/by-hash/{hex}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HashProof {
+    pub leaf_index: LeafIndex,
+    /// proof against root of `epoch`, which is the current tree or sealed checkpoint
+    pub proof: merkle::Sha3Proof,
+    pub epoch: u32,
+    pub root: merkle::Sha3Hash,
+}
+
+/// Query of `GET /files/{id}/proof`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoricalProofQuery {
+    /// size of the epoch tree prefix the proof is made for
+    pub tree_size: TreeSize,
+}
+
+/// Proof of file leaf against root of its epoch tree as it was when it had `tree_size` leaves
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoricalProof {
+    pub id: FileId,
+    pub epoch: u32,
+    /// index of the leaf within its epoch
+    pub leaf_index: LeafIndex,
+    pub tree_size: TreeSize,
+    pub proof: merkle::Sha3Proof,
+    pub root: merkle::Sha3Hash,
+}
+
+/// Shard of a cluster owning content whose hash starts with a byte in `first_byte..=last_byte`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShardInfo {
+    pub url: String,
+    pub first_byte: u8,
+    pub last_byte: u8,
+    /// current root of the shard, missing while it has no files
+    #[serde(default)]
+    pub root: Option<RootHash>,
+    /// proof of the shard leaf against cluster root
+    pub proof: merkle::Sha3Proof,
+}
+
+/// Answer of router `GET /cluster`: shards in order with the root of a tree over their roots
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClusterInfo {
+    pub shards: Vec<ShardInfo>,
+    pub root: merkle::Sha3Hash,
+}
+
+/// Leaf of shard in cluster tree, shards without files get a fixed one
+pub fn shard_leaf(root: Option<&RootHash>) -> merkle::Sha3Hash {
+    match root {
+        Some(root) => root.hash.clone(),
+        None => hash_content(b"empty shard"),
+    }
+}
+
+impl ClusterInfo {
+    /// Every shard root is proven against cluster root at its position, out of at most
+    /// [`MAX_SHARDS`](crate::storage::MAX_SHARDS) shards
+    pub fn verify(&self) -> bool {
+        (1..=crate::storage::MAX_SHARDS).contains(&self.shards.len())
+            && self.shards.iter().all(|shard| {
+                shard
+                    .proof
+                    .verify(&self.root, &shard_leaf(shard.root.as_ref()))
+            })
+    }
+}
+
+/// Leaf committing dataset root of given size to the main tree
+pub fn dataset_leaf(name: &str, root: &merkle::Sha3Hash, size: u64) -> merkle::Sha3Hash {
+    hash_both(&hash_content(format!("dataset {size} {name}")), root)
+}
+
+/// Files uploaded into the same dataset, proven by a tree of their own. Every file joining it
+/// appends [`dataset_leaf`] of the grown dataset tree to the main tree, so the latest one commits
+/// exactly the files in the dataset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dataset {
+    pub name: String,
+    /// files in the dataset, deleted ones included
+    pub size: u64,
+    /// root of the dataset tree, built of leaves of its files in the order they joined
+    pub root: merkle::Sha3Hash,
+    /// index of the latest dataset leaf in the main tree
+    pub leaf_index: LeafIndex,
+    /// proof of the dataset leaf against `tree_root` of `epoch`, which is the current tree or
+    /// sealed checkpoint
+    pub proof: merkle::Sha3Proof,
+    pub epoch: u32,
+    pub tree_root: merkle::Sha3Hash,
+}
+
+impl Dataset {
+    pub fn leaf(&self) -> merkle::Sha3Hash {
+        dataset_leaf(&self.name, &self.root, self.size)
+    }
+
+    /// Dataset root is committed to given trusted root of its epoch, which has to be the root
+    /// the server proved it against
+    pub fn verify(&self, root: &merkle::Sha3Hash) -> bool {
+        self.tree_root == *root && self.proof.verify(root, &self.leaf())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DatasetList {
+    pub datasets: Vec<Dataset>,
+}
+
+/// Proof of file leaf against dataset root, which is proven against the main tree in turn
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DatasetProof {
+    pub id: FileId,
+    /// position of the file in the dataset tree
+    pub index: u64,
+    pub proof: merkle::Sha3Proof,
+    pub dataset: Dataset,
+}
+
+impl DatasetProof {
+    pub fn verify(&self, leaf: &merkle::Sha3Hash, root: &merkle::Sha3Hash) -> bool {
+        self.dataset.verify(root) && self.proof.verify(&self.dataset.root, leaf)
+    }
+}
+
+/// Root of sealed epoch, its tree of leaves `first_leaf..first_leaf + size` never changes again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub epoch: u32,
+    pub first_leaf: u64,
+    pub size: u64,
+    pub root: merkle::Sha3Hash,
+    /// unix time in seconds
+    pub sealed_at: u64,
+    /// id of the server key signature was made with, not part of the signed message
+    #[serde(default)]
+    pub key_id: Option<String>,
+    /// hex encoded ed25519 signature of [`Checkpoint::signed_message`], missing if server has no key
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+impl Checkpoint {
+    pub fn signed_message(&self) -> Vec<u8> {
+        format!(
+            "safe-storage checkpoint\n{}\n{}\n{}\n{}\n{}",
+            self.epoch, self.first_leaf, self.size, self.root, self.sealed_at
+        )
+        .into_bytes()
+    }
+
+    /// Checks signature against hex encoded public key of the server
+    pub fn verify(&self, public_key: &str) -> bool {
+        self.signature
+            .as_ref()
+            .is_some_and(|signature| signing::verify(public_key, &self.signed_message(), signature))
+    }
+}
+
+/// File of exported epoch, stored under a new id when archive is merged into other storage
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedFile {
+    pub id: FileId,
+    pub name: String,
+    #[serde(default)]
+    pub version: u32,
+    pub leaf_index: LeafIndex,
+    #[serde(with = "base64")]
+    pub content: Vec<u8>,
+}
+
+/// Sealed epoch with all its leaves and contents of files which are not deleted
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EpochArchive {
+    pub checkpoint: Checkpoint,
+    pub leaves: Vec<merkle::Sha3Hash>,
+    pub files: Vec<ArchivedFile>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImportedEpoch {
+    pub checkpoint: Checkpoint,
+    /// files whose content was stored
+    pub files: u32,
+    /// epoch was already sealed here, so only missing contents were restored
+    pub restored: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckpointList {
+    pub checkpoints: Vec<Checkpoint>,
+    /// epoch new leaves are appended to
+    pub current_epoch: u32,
+    /// hex encoded key checkpoints are signed with
+    pub public_key: Option<String>,
+}
+
+/// Key server signs or has signed checkpoints with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PublicKey {
+    pub key_id: String,
+    /// hex encoded ed25519 public key
+    pub public_key: String,
+    /// new checkpoints are signed with this key
+    pub active: bool,
+    /// unix time in seconds key was rotated in, missing for keys server started with
+    #[serde(default)]
+    pub active_from: Option<u64>,
+    /// unix time in seconds key was rotated out
+    #[serde(default)]
+    pub retired_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyList {
+    pub keys: Vec<PublicKey>,
+}
+
+/// Body of `POST /admin/keys/rotate`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyRotation {
+    /// file on the server holding hex encoded 32 byte secret seed of the new signing key, so the
+    /// secret never travels over http and stays for `--signing-key` of the next start
+    pub key_file: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Usage {
+    pub name: String,
+    pub bytes: u64,
+    pub files: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UsageList {
+    pub usage: Vec<Usage>,
+}
+
+/// Secondary index rebuilt in background once storage is opened, while it is served already
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Migration {
+    pub name: String,
+    /// leaves indexed so far out of all to index
+    pub done: u64,
+    pub total: u64,
+    /// unix times in seconds
+    pub started_at: u64,
+    #[serde(default)]
+    pub finished_at: Option<u64>,
+}
+
+/// Response of `GET /admin/migrations`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MigrationList {
+    pub migrations: Vec<Migration>,
+}
+
+/// Query of `GET /search`, matched case-insensitively for ascii letters
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    /// of files in the response, defaults to [`MAX_PAGE_LIMIT`]
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// Response of `GET /search`, files in upload order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+}
+
+/// Text file containing the query, with its leaf so the file can be verified after download
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub id: FileId,
+    pub name: String,
+    pub leaf: ListedLeaf,
+    /// first matches in the file
+    pub matches: Vec<SearchMatch>,
+}
+
+/// Byte offsets into file content
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchMatch {
+    pub offset: u64,
+    /// range of the snippet around the match, cut at character boundaries
+    pub snippet_start: u64,
+    pub snippet_end: u64,
+}
+
+/// Version of requests, responses and proofs, bumped on changes older clients can't follow
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Hash function of leaves and tree nodes
+pub const HASH_ALGORITHM: &str = "sha3-256";
+
+/// Answer of `GET /info`, servers older than this reported version and leaf hashing only
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub version: String,
+    /// see [`PROTOCOL_VERSION`]
+    #[serde(default = "default_protocol")]
+    pub protocol: u32,
+    #[serde(default = "default_hash_algorithm")]
+    pub hash_algorithm: String,
+    pub leaf_hashing: LeafHashing,
+    #[serde(default)]
+    pub tree_mode: TreeMode,
+    #[serde(default)]
+    pub features: ServerFeatures,
+    #[serde(default)]
+    pub limits: ServerLimits,
+}
+
+fn default_protocol() -> u32 {
+    PROTOCOL_VERSION
+}
+
+fn default_hash_algorithm() -> String {
+    HASH_ALGORITHM.to_string()
+}
+
+/// How server grows its tree
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TreeMode {
+    /// single tree growing forever
+    #[default]
+    Single,
+    /// tree is sealed into checkpointed epochs and a new one is started, see `GET /epochs`
+    Epochs,
+}
+
+impl std::fmt::Display for TreeMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TreeMode::Single => write!(f, "single"),
+            TreeMode::Epochs => write!(f, "epochs"),
+        }
+    }
+}
+
+/// Optional capabilities server was started with
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerFeatures {
+    /// leaves are roots of content chunks, see [`LeafHashing::Chunked`]
+    #[serde(default)]
+    pub chunked_leaves: bool,
+    /// uploads require api key
+    #[serde(default)]
+    pub auth: bool,
+    /// checkpoints of sealed epochs are signed, see `GET /keys`
+    #[serde(default)]
+    pub signed_checkpoints: bool,
+    /// files can be pinned to IPFS by `POST /files/{id}/ipfs`
+    #[serde(default)]
+    pub ipfs_export: bool,
+    /// deleted files can be restored by `POST /files/{id}/undelete`
+    #[serde(default)]
+    pub undelete: bool,
+    /// text files can be searched by `GET /search`
+    #[serde(default)]
+    pub search: bool,
+    /// files are named by hex of their content hash, whatever name they were uploaded with
+    #[serde(default)]
+    pub name_by_hash: bool,
+    /// signed roots of peers are collected at `GET /attestations`
+    #[serde(default)]
+    pub federation: bool,
+}
+
+/// Limits of requests, missing ones are not limited
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerLimits {
+    /// of request bodies which are not streamed
+    #[serde(default)]
+    pub max_body_bytes: u64,
+    #[serde(default)]
+    pub max_batch_files: u64,
+    #[serde(default)]
+    pub max_upload_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_import_bytes: u64,
+    /// bytes stored per api key
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    /// files uploaded per api key
+    #[serde(default)]
+    pub quota_files: Option<u64>,
+    /// leaves of an epoch before it is sealed
+    #[serde(default)]
+    pub epoch_max_leaves: Option<u64>,
+    /// leaves of an epoch tree, anything taking more is refused with `507` unless the tree is
+    /// sealed once full
+    #[serde(default)]
+    pub max_tree_leaves: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub hash_threads: usize,
+    pub hash_queue_depth: usize,
+    /// contents kept by blob store with limited capacity, e.g. in-memory one with a budget
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blobs: Option<BlobUsage>,
+    #[serde(default)]
+    pub runtime: RuntimeStats,
+    /// content downloads of all files so far
+    #[serde(default)]
+    pub downloads: u64,
+    /// committed files which are not deleted, files queued behind reservations are left out
+    #[serde(default)]
+    pub files: usize,
+    /// shape of the current epoch tree, long proofs or big memory suggest sealing epochs sooner
+    #[serde(default)]
+    pub tree: merkle::TreeStats,
+}
+
+/// Signs of request handling being starved, see [`crate::metrics::LagProbe`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RuntimeStats {
+    /// how late the latest wakeup of probe tasks on server workers was
+    pub poll_lag_micros: u64,
+    /// worst wakeup lag since stats were read last time
+    pub max_poll_lag_micros: u64,
+    /// how long this stats request waited for the storage lock
+    pub storage_lock_wait_micros: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BlobUsage {
+    pub used_bytes: u64,
+    pub max_bytes: u64,
+}
+
+/// Range of leaves whose stored or recomputed hashes don't match persisted root chain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Divergence {
+    pub first_leaf: LeafIndex,
+    pub last_leaf: LeafIndex,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    /// integrity check failed, storage is read-only
+    Quarantined,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Health {
+    pub status: HealthStatus,
+    pub divergence: Option<Divergence>,
+}
+
+/// Evidence that file was logically removed: original leaf was part of the tree with `root_before`
+/// and tombstone of that leaf was appended right after, producing `root_after`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeletionReceipt {
+    pub id: FileId,
+    pub leaf_hash: merkle::Sha3Hash,
+    pub leaf_proof: merkle::Sha3Proof,
+    pub root_before: merkle::Sha3Hash,
+    pub tombstone_hash: merkle::Sha3Hash,
+    pub tombstone_proof: merkle::Sha3Proof,
+    pub root_after: merkle::Sha3Hash,
+}
+
+impl DeletionReceipt {
+    pub fn verify(&self) -> bool {
+        self.tombstone_hash == tombstone_of(&self.leaf_hash)
+            && self.leaf_proof.verify(&self.root_before, &self.leaf_hash)
+            && self
+                .tombstone_proof
+                .verify(&self.root_after, &self.tombstone_hash)
+    }
+}
+
+/// Server statement that it accepted content with `leaf_hash` as leaf `leaf_index`, proven to
+/// lead to `root` of epoch tree of `tree_size` leaves right after the upload. Signed with the
+/// active server key, so it can be checked offline long after the upload.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadReceipt {
+    pub id: FileId,
+    pub leaf_index: LeafIndex,
+    pub leaf_hash: merkle::Sha3Hash,
+    pub proof: merkle::Sha3Proof,
+    pub epoch: u32,
+    pub tree_size: TreeSize,
+    pub root: merkle::Sha3Hash,
+    /// unix time in seconds
+    pub issued_at: u64,
+    /// id of the server key signature was made with, not part of the signed message
+    pub key_id: String,
+    /// hex encoded ed25519 signature of [`UploadReceipt::signed_message`]
+    pub signature: String,
+}
+
+impl UploadReceipt {
+    pub fn signed_message(&self) -> Vec<u8> {
+        format!(
+            "safe-storage upload receipt\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            self.id,
+            self.leaf_index,
+            self.leaf_hash,
+            self.epoch,
+            self.tree_size,
+            self.root,
+            self.issued_at
+        )
+        .into_bytes()
+    }
+
+    /// Checks signature against hex encoded public key of the server and the proof of the leaf
+    pub fn verify(&self, public_key: &str) -> bool {
+        self.proof.verify(&self.root, &self.leaf_hash)
+            && signing::verify(public_key, &self.signed_message(), &self.signature)
+    }
+}
+
+/// Server statement that its epoch tree of `tree_size` leaves has `root`, submitted to peers in
+/// federation mode. Carries the public key, so anyone can check it without knowing the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedRoot {
+    pub epoch: u32,
+    pub tree_size: TreeSize,
+    pub root: merkle::Sha3Hash,
+    /// unix time in seconds
+    pub issued_at: u64,
+    /// hex encoded ed25519 public key signature was made with
+    pub public_key: String,
+    /// hex encoded ed25519 signature of [`SignedRoot::signed_message`]
+    pub signature: String,
+}
+
+impl SignedRoot {
+    pub fn signed_message(&self) -> Vec<u8> {
+        format!(
+            "safe-storage signed root\n{}\n{}\n{}\n{}",
+            self.epoch, self.tree_size, self.root, self.issued_at
+        )
+        .into_bytes()
+    }
+
+    pub fn verify(&self) -> bool {
+        signing::verify(&self.public_key, &self.signed_message(), &self.signature)
+    }
+
+    /// Both are signed with the same key for the same tree but name different roots, which an
+    /// honest server never does
+    pub fn conflicts_with(&self, other: &SignedRoot) -> bool {
+        self.public_key == other.public_key
+            && (self.epoch, self.tree_size) == (other.epoch, other.tree_size)
+            && self.root != other.root
+    }
+}
+
+/// Two conflicting roots signed by the same server, see [`SignedRoot::conflicts_with`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Equivocation {
+    pub first: SignedRoot,
+    pub second: SignedRoot,
+}
+
+/// Response of `GET /attestations`: roots peers submitted, oldest first, and conflicts among
+/// them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttestationList {
+    pub attestations: Vec<SignedRoot>,
+    pub equivocations: Vec<Equivocation>,
+}
+
+/// Query of `GET /attestations`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AttestationQuery {
+    /// only roots signed with this hex encoded public key
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+/// Response of `POST /attestations`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SubmittedAttestation {
+    /// root was not known yet
+    pub recorded: bool,
+    /// earlier root it conflicts with, if any
+    pub equivocation: Option<Equivocation>,
 }
 
-mod base64 {
+/// Base64 string in human readable formats like json, raw bytes in binary ones
+pub(crate) mod base64 {
     use base64::Engine;
     use serde::{Deserialize, Serialize};
     use serde::{Deserializer, Serializer};
 
     pub fn serialize<S: Serializer>(v: &Vec<u8>, s: S) -> Result<S::Ok, S::Error> {
+        if !s.is_human_readable() {
+            return serde_bytes::serialize(v, s);
+        }
         let base64 = base64::engine::general_purpose::STANDARD.encode(v);
         String::serialize(&base64, s)
     }
 
     pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        if !d.is_human_readable() {
+            return serde_bytes::deserialize(d);
+        }
         let base64 = String::deserialize(d)?;
         base64::engine::general_purpose::STANDARD
             .decode(base64.as_bytes())
@@ -0,0 +1,157 @@
+use crate::sha3::hash_content;
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{error, web, FromRequest, HttpRequest};
+use anyhow::anyhow;
+use std::fmt::{Debug, Formatter};
+use std::future::{ready, Ready};
+use std::str::FromStr;
+
+/// Name used to track usage of requests without credentials when no api keys are configured
+pub const ANONYMOUS: &str = "anonymous";
+
+/// Api key given as `name:secret`, where name identifies credential in usage reports
+#[derive(Clone, PartialEq)]
+pub struct ApiKey {
+    pub name: String,
+    pub secret: String,
+    pub admin: bool,
+}
+
+/// Secret is left out, so keys can't leak into logs
+impl Debug for ApiKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("name", &self.name)
+            .field("secret", &"<redacted>")
+            .field("admin", &self.admin)
+            .finish()
+    }
+}
+
+impl FromStr for ApiKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((name, secret)) if !name.is_empty() && !secret.is_empty() => Ok(ApiKey {
+                name: name.to_string(),
+                secret: secret.to_string(),
+                admin: false,
+            }),
+            _ => Err(anyhow!("api key must be given as name:secret")),
+        }
+    }
+}
+
+/// Configured credentials, when empty service is open for anyone
+#[derive(Debug, Default, Clone)]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+impl ApiKeys {
+    pub fn new(keys: Vec<ApiKey>) -> Self {
+        Self { keys }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Digests of secrets are compared byte by byte without stopping at the first difference,
+    /// against every key, so response time doesn't tell how much of a guess matched
+    fn find(&self, secret: &str) -> Option<&ApiKey> {
+        let digest = hash_content(secret);
+        self.keys.iter().fold(None, |found, key| {
+            let diff = hash_content(&key.secret)
+                .as_bytes()
+                .iter()
+                .zip(digest.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b));
+            match diff {
+                0 => found.or(Some(key)),
+                _ => found,
+            }
+        })
+    }
+}
+
+/// Identity of request sender, extracted from `Authorization: Bearer <secret>` header
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caller {
+    pub name: String,
+    pub admin: bool,
+}
+
+impl Caller {
+    fn identify(req: &HttpRequest) -> Result<Caller, actix_web::Error> {
+        let keys = match req.app_data::<web::Data<ApiKeys>>() {
+            Some(keys) if !keys.is_empty() => keys,
+            _ => {
+                return Ok(Caller {
+                    name: ANONYMOUS.to_string(),
+                    admin: false,
+                })
+            }
+        };
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|secret| keys.find(secret.trim()))
+            .map(|key| Caller {
+                name: key.name.clone(),
+                admin: key.admin,
+            })
+            .ok_or_else(|| error::ErrorUnauthorized("missing or invalid api key"))
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Caller::identify(req))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_parse_api_key() {
+        let key = ApiKey::from_str("ci:s3cr3t").expect("should parse");
+        assert_eq!(key.name, "ci");
+        assert_eq!(key.secret, "s3cr3t");
+        assert!(!format!("{key:?}").contains("s3cr3t"));
+        assert!(ApiKey::from_str("ci").is_err());
+        assert!(ApiKey::from_str(":s3cr3t").is_err());
+    }
+
+    #[test]
+    fn test_caller_extraction() {
+        let keys = web::Data::new(ApiKeys::new(vec![ApiKey::from_str("ci:s3cr3t").unwrap()]));
+
+        let req = TestRequest::default()
+            .app_data(keys.clone())
+            .insert_header((AUTHORIZATION, "Bearer s3cr3t"))
+            .to_http_request();
+        assert_eq!(Caller::identify(&req).expect("should authorize").name, "ci");
+
+        let req = TestRequest::default()
+            .app_data(keys)
+            .insert_header((AUTHORIZATION, "Bearer wrong"))
+            .to_http_request();
+        assert!(Caller::identify(&req).is_err());
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(
+            Caller::identify(&req).expect("should be anonymous").name,
+            ANONYMOUS
+        );
+    }
+}
@@ -0,0 +1,583 @@
+use crate::api::{Checkpoint, DeletionReceipt};
+use crate::merkle::Sha3Hash;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Everything storage knows about a file except its content
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileMeta {
+    pub name: String,
+    pub owner: String,
+    /// version of the name, starting from 1
+    pub version: u32,
+    /// content type detected on upload
+    pub mime: String,
+    /// content length in bytes
+    pub size: u64,
+    pub leaf_index: usize,
+    /// leaf hash of the content, missing while reserved slot is not filled yet
+    pub hash: Option<Sha3Hash>,
+    /// file is tombstoned by [`crate::storage::Storage::delete_expired`] once this time passes
+    pub expires_at: Option<SystemTime>,
+    /// deleted because of expiry rather than explicit deletion
+    pub expired: bool,
+    pub deleted: Option<DeletionReceipt>,
+    /// leaf index of the tombstone appended on deletion
+    pub tombstone_index: Option<usize>,
+    /// CID content was pinned to IPFS under, see [`crate::ipfs::IpfsNode`]
+    pub ipfs_cid: Option<String>,
+    /// upload kept out of the tree by two-phase ingestion, it has no leaf index nor hash until
+    /// it is released
+    pub held: Option<Hold>,
+    /// deleted file whose content was appended again as this file
+    pub undeleted_from: Option<usize>,
+    /// file this deleted one was undeleted as, it can't be undeleted again
+    #[serde(default)]
+    pub undeleted_to: Option<usize>,
+    /// when the file was accepted, idle files are measured from it until first download
+    pub stored_at: SystemTime,
+    /// times content was served, see [`crate::storage::Storage::record_download`]
+    pub downloads: u64,
+    pub last_access: Option<SystemTime>,
+    /// dataset the file joined, see [`crate::storage::Storage::join_dataset`]
+    pub dataset: Option<String>,
+    /// leaf index of the dataset leaf appended when the file joined its dataset
+    pub dataset_leaf_index: Option<usize>,
+    /// hash of the content alone, kept with name-hash leaves which are derived from it
+    #[serde(default)]
+    pub content_hash: Option<Sha3Hash>,
+}
+
+/// Why file uploaded in two phases is not in the tree
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Hold {
+    /// content with given leaf hash waits for validation
+    Pending(Sha3Hash),
+    /// validation refused content, it is removed
+    Rejected(String),
+}
+
+/// Keeps file records, tree leaves and root chain (root after each leaf). Ids are assigned
+/// sequentially starting from 0, leaves are only ever appended, so the tree can be rebuilt from
+/// them and checked against the chain.
+///
+/// Writes between [`MetadataStore::begin`] and [`MetadataStore::commit`] are visible to reads
+/// right away, but persistent stores must not keep them if the process dies before commit.
+pub trait MetadataStore: Send {
+    /// Stores record of a new file and returns its id
+    fn insert(&mut self, file: FileMeta) -> io::Result<usize>;
+
+    fn update(&mut self, id: usize, file: FileMeta) -> io::Result<()>;
+
+    fn get(&self, id: usize) -> io::Result<Option<FileMeta>>;
+
+    /// All records ordered by id
+    fn all(&self) -> io::Result<Vec<FileMeta>>;
+
+    /// Appends leaf together with root of the tree it was appended to
+    fn append_leaf(&mut self, hash: Sha3Hash, root: Sha3Hash) -> io::Result<()>;
+
+    /// All leaves in tree order
+    fn leaves(&self) -> io::Result<Vec<Sha3Hash>>;
+
+    /// Leaves in given range of tree indexes, cut short at the last leaf
+    fn leaves_in(&self, range: Range<usize>) -> io::Result<Vec<Sha3Hash>> {
+        let leaves = self.leaves()?;
+        let end = range.end.min(leaves.len());
+        Ok(leaves[range.start.min(end)..end].to_vec())
+    }
+
+    /// Root after each leaf, in tree order. Roots are of the epoch tree leaf was appended to.
+    fn roots(&self) -> io::Result<Vec<Sha3Hash>>;
+
+    /// Records checkpoint of sealed epoch, leaves after it belong to the next one
+    fn seal_epoch(&mut self, checkpoint: Checkpoint) -> io::Result<()>;
+
+    /// Checkpoints of all sealed epochs, in order
+    fn checkpoints(&self) -> io::Result<Vec<Checkpoint>>;
+
+    /// Starts transaction, transactions don't nest
+    fn begin(&mut self) -> io::Result<()>;
+
+    /// Keeps writes since [`MetadataStore::begin`], does nothing without open transaction
+    fn commit(&mut self) -> io::Result<()>;
+
+    /// Drops writes since [`MetadataStore::begin`], does nothing without open transaction
+    fn rollback(&mut self) -> io::Result<()>;
+}
+
+/// Keeps file contents by file id, with transactions working the same way as the ones of
+/// [`MetadataStore`]
+pub trait BlobStore: Send {
+    fn put(&mut self, id: usize, content: Vec<u8>) -> io::Result<()>;
+
+    fn get(&self, id: usize) -> io::Result<Option<Vec<u8>>>;
+
+    /// Removing missing blob is not an error
+    fn remove(&mut self, id: usize) -> io::Result<()>;
+
+    /// Takes over content staged in given file, e.g. by a streamed upload. Stores which can't move
+    /// files read it into memory.
+    fn put_file(&mut self, id: usize, staged: &Path) -> io::Result<()> {
+        self.put(id, std::fs::read(staged)?)?;
+        std::fs::remove_file(staged)
+    }
+
+    /// Bytes kept and the most that can be kept, missing for stores without a limit
+    fn capacity(&self) -> Option<BlobCapacity> {
+        None
+    }
+
+    /// Inner store of wrappers transforming contents, e.g. encrypting them, which keeps blobs as
+    /// they are at rest. Backups copy blobs from it, so they are no less protected than storage.
+    fn at_rest(&self) -> Option<&dyn BlobStore> {
+        None
+    }
+
+    fn at_rest_mut(&mut self) -> Option<&mut dyn BlobStore> {
+        None
+    }
+
+    fn begin(&mut self) -> io::Result<()>;
+
+    /// Keeps blobs put since [`BlobStore::begin`]. Blobs removed meanwhile may stay until
+    /// [`BlobStore::purge`], so they are still there if metadata fails to commit after them.
+    fn commit(&mut self) -> io::Result<()>;
+
+    /// Drops blobs removed by the last committed transaction, once metadata no longer refers to
+    /// them. Rollback keeps them instead.
+    fn purge(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> io::Result<()>;
+}
+
+fn already_open() -> io::Error {
+    io::Error::other("transaction is already open")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlobCapacity {
+    pub used: u64,
+    pub max: u64,
+}
+
+#[derive(Default)]
+pub struct MemoryMetadata {
+    files: Vec<FileMeta>,
+    leaves: Vec<Sha3Hash>,
+    roots: Vec<Sha3Hash>,
+    checkpoints: Vec<Checkpoint>,
+    /// what rollback of the open transaction restores
+    undo: Option<MetadataUndo>,
+}
+
+/// Lengths of append only lists when transaction began, with records it updated as they were
+/// before
+struct MetadataUndo {
+    files: usize,
+    leaves: usize,
+    checkpoints: usize,
+    updated: HashMap<usize, FileMeta>,
+}
+
+impl MetadataStore for MemoryMetadata {
+    fn insert(&mut self, file: FileMeta) -> io::Result<usize> {
+        self.files.push(file);
+        Ok(self.files.len() - 1)
+    }
+
+    fn update(&mut self, id: usize, file: FileMeta) -> io::Result<()> {
+        match self.files.get_mut(id) {
+            Some(stored) => {
+                if let Some(undo) = self.undo.as_mut().filter(|undo| id < undo.files) {
+                    undo.updated.entry(id).or_insert_with(|| stored.clone());
+                }
+                *stored = file;
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no metadata for file {id}"),
+            )),
+        }
+    }
+
+    fn get(&self, id: usize) -> io::Result<Option<FileMeta>> {
+        Ok(self.files.get(id).cloned())
+    }
+
+    fn all(&self) -> io::Result<Vec<FileMeta>> {
+        Ok(self.files.clone())
+    }
+
+    fn append_leaf(&mut self, hash: Sha3Hash, root: Sha3Hash) -> io::Result<()> {
+        self.leaves.push(hash);
+        self.roots.push(root);
+        Ok(())
+    }
+
+    fn leaves(&self) -> io::Result<Vec<Sha3Hash>> {
+        Ok(self.leaves.clone())
+    }
+
+    fn leaves_in(&self, range: Range<usize>) -> io::Result<Vec<Sha3Hash>> {
+        let end = range.end.min(self.leaves.len());
+        Ok(self.leaves[range.start.min(end)..end].to_vec())
+    }
+
+    fn roots(&self) -> io::Result<Vec<Sha3Hash>> {
+        Ok(self.roots.clone())
+    }
+
+    fn seal_epoch(&mut self, checkpoint: Checkpoint) -> io::Result<()> {
+        self.checkpoints.push(checkpoint);
+        Ok(())
+    }
+
+    fn checkpoints(&self) -> io::Result<Vec<Checkpoint>> {
+        Ok(self.checkpoints.clone())
+    }
+
+    fn begin(&mut self) -> io::Result<()> {
+        if self.undo.is_some() {
+            return Err(already_open());
+        }
+        self.undo = Some(MetadataUndo {
+            files: self.files.len(),
+            leaves: self.leaves.len(),
+            checkpoints: self.checkpoints.len(),
+            updated: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.undo = None;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> io::Result<()> {
+        if let Some(undo) = self.undo.take() {
+            self.files.truncate(undo.files);
+            self.leaves.truncate(undo.leaves);
+            self.roots.truncate(undo.leaves);
+            self.checkpoints.truncate(undo.checkpoints);
+            for (id, file) in undo.updated {
+                self.files[id] = file;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryBlobs {
+    blobs: HashMap<usize, Vec<u8>>,
+    /// bytes of all kept contents
+    used: u64,
+    max_bytes: Option<u64>,
+    /// contents the open transaction replaced or removed, missing for blobs it added
+    undo: Option<HashMap<usize, Option<Vec<u8>>>>,
+}
+
+impl MemoryBlobs {
+    /// Keeps at most `max_bytes` of contents, so a demo server fails uploads instead of being
+    /// killed for running out of memory
+    pub fn with_budget(max_bytes: u64) -> Self {
+        Self {
+            max_bytes: Some(max_bytes),
+            ..Default::default()
+        }
+    }
+
+    fn remember(&mut self, id: usize) {
+        if let Some(undo) = self.undo.as_mut() {
+            undo.entry(id)
+                .or_insert_with(|| self.blobs.get(&id).cloned());
+        }
+    }
+}
+
+impl BlobStore for MemoryBlobs {
+    fn put(&mut self, id: usize, content: Vec<u8>) -> io::Result<()> {
+        let replaced = self.blobs.get(&id).map_or(0, |blob| blob.len() as u64);
+        let used = self.used - replaced + content.len() as u64;
+        if let Some(max_bytes) = self.max_bytes.filter(|max| used > *max) {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!("memory budget of {max_bytes} bytes exceeded"),
+            ));
+        }
+        self.remember(id);
+        self.used = used;
+        self.blobs.insert(id, content);
+        Ok(())
+    }
+
+    fn get(&self, id: usize) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.blobs.get(&id).cloned())
+    }
+
+    fn remove(&mut self, id: usize) -> io::Result<()> {
+        self.remember(id);
+        if let Some(blob) = self.blobs.remove(&id) {
+            self.used -= blob.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> Option<BlobCapacity> {
+        self.max_bytes.map(|max| BlobCapacity {
+            used: self.used,
+            max,
+        })
+    }
+
+    fn begin(&mut self) -> io::Result<()> {
+        if self.undo.is_some() {
+            return Err(already_open());
+        }
+        self.undo = Some(HashMap::new());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.undo = None;
+        Ok(())
+    }
+
+    /// Restores previous contents without checking the budget, they fitted before
+    fn rollback(&mut self) -> io::Result<()> {
+        for (id, content) in self.undo.take().unwrap_or_default() {
+            if let Some(blob) = self.blobs.remove(&id) {
+                self.used -= blob.len() as u64;
+            }
+            if let Some(content) = content {
+                self.used += content.len() as u64;
+                self.blobs.insert(id, content);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps each file content in a separate file named by file id inside given directory. Contents
+/// put by a transaction are written to `{id}.pending` files renamed on commit, and removals wait
+/// for commit, so blobs of a transaction the process died in are never mistaken for committed.
+pub struct DiskBlobs {
+    dir: PathBuf,
+    /// blobs put or removed by the open transaction
+    staged: Option<BTreeMap<usize, Staged>>,
+    /// blobs removed by committed transaction, dropped by [`BlobStore::purge`]
+    removed: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Staged {
+    Put,
+    Removed,
+}
+
+const PENDING: &str = "pending";
+
+impl DiskBlobs {
+    /// Creates directory if it doesn't exist yet, pending blobs left there by a transaction which
+    /// never committed are removed
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == PENDING) {
+                remove_existing(&path)?;
+            }
+        }
+        Ok(Self {
+            dir,
+            staged: None,
+            removed: Vec::new(),
+        })
+    }
+
+    fn path(&self, id: usize) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    fn pending_path(&self, id: usize) -> PathBuf {
+        self.path(id).with_extension(PENDING)
+    }
+
+    /// Where content put now goes, marking it staged inside transaction
+    fn target(&mut self, id: usize) -> PathBuf {
+        match self.staged.as_mut() {
+            Some(staged) => {
+                staged.insert(id, Staged::Put);
+                self.pending_path(id)
+            }
+            None => self.path(id),
+        }
+    }
+}
+
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+impl BlobStore for DiskBlobs {
+    fn put(&mut self, id: usize, content: Vec<u8>) -> io::Result<()> {
+        std::fs::write(self.target(id), content)
+    }
+
+    fn get(&self, id: usize) -> io::Result<Option<Vec<u8>>> {
+        let path = match self.staged.as_ref().and_then(|staged| staged.get(&id)) {
+            Some(Staged::Put) => self.pending_path(id),
+            Some(Staged::Removed) => return Ok(None),
+            None => self.path(id),
+        };
+        match std::fs::read(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn remove(&mut self, id: usize) -> io::Result<()> {
+        match self.staged.as_mut() {
+            Some(staged) => {
+                if staged.insert(id, Staged::Removed) == Some(Staged::Put) {
+                    remove_existing(&self.pending_path(id))?;
+                }
+                Ok(())
+            }
+            None => remove_existing(&self.path(id)),
+        }
+    }
+
+    /// Renames staged file into the directory, copying it if it is on another file system
+    fn put_file(&mut self, id: usize, staged: &Path) -> io::Result<()> {
+        let target = self.target(id);
+        if std::fs::rename(staged, &target).is_err() {
+            std::fs::copy(staged, &target)?;
+            std::fs::remove_file(staged)?;
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self) -> io::Result<()> {
+        if self.staged.is_some() {
+            return Err(already_open());
+        }
+        self.staged = Some(BTreeMap::new());
+        self.removed.clear();
+        Ok(())
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        for (id, staged) in self.staged.take().unwrap_or_default() {
+            match staged {
+                Staged::Put => std::fs::rename(self.pending_path(id), self.path(id))?,
+                Staged::Removed => self.removed.push(id),
+            }
+        }
+        Ok(())
+    }
+
+    fn purge(&mut self) -> io::Result<()> {
+        for id in std::mem::take(&mut self.removed) {
+            remove_existing(&self.path(id))?;
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> io::Result<()> {
+        self.removed.clear();
+        for (id, staged) in self.staged.take().unwrap_or_default() {
+            if staged == Staged::Put {
+                remove_existing(&self.pending_path(id))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disk_blobs() {
+        let dir = std::env::temp_dir().join("safe_storage_disk_blobs_test");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut blobs = DiskBlobs::open(&dir).expect("should open");
+        blobs.put(0, b"first".to_vec()).expect("should put");
+        blobs.put(1, b"second".to_vec()).expect("should put");
+        assert_eq!(blobs.get(1).expect("should read"), Some(b"second".to_vec()));
+
+        blobs.remove(1).expect("should remove");
+        blobs.remove(1).expect("should ignore missing blob");
+        assert_eq!(blobs.get(1).expect("should read"), None);
+
+        let staged = dir.join("staged");
+        std::fs::write(&staged, b"third").expect("should write");
+        blobs.put_file(2, &staged).expect("should move");
+        assert!(!staged.exists());
+        assert_eq!(blobs.get(2).expect("should read"), Some(b"third".to_vec()));
+
+        let reopened = DiskBlobs::open(&dir).expect("should reopen");
+        assert_eq!(
+            reopened.get(0).expect("should read"),
+            Some(b"first".to_vec())
+        );
+        std::fs::remove_dir_all(&dir).expect("should remove dir");
+    }
+
+    #[test]
+    fn test_disk_blobs_removed_after_purge() {
+        let dir = std::env::temp_dir().join("safe_storage_disk_blobs_purge_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut blobs = DiskBlobs::open(&dir).expect("should open");
+        blobs.put(0, b"first".to_vec()).expect("should put");
+
+        blobs.begin().expect("should begin");
+        blobs.remove(0).expect("should remove");
+        blobs.commit().expect("should commit");
+        assert!(dir.join("0").exists(), "metadata may still fail to commit");
+        blobs.rollback().expect("should roll back");
+        blobs.purge().expect("should purge");
+        assert_eq!(blobs.get(0).expect("should read"), Some(b"first".to_vec()));
+
+        blobs.begin().expect("should begin");
+        blobs.remove(0).expect("should remove");
+        blobs.commit().expect("should commit");
+        blobs.purge().expect("should purge");
+        assert_eq!(blobs.get(0).expect("should read"), None);
+        std::fs::remove_dir_all(&dir).expect("should remove dir");
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let mut blobs = MemoryBlobs::with_budget(10);
+        blobs.put(0, b"first".to_vec()).expect("should put");
+        assert_eq!(
+            blobs.put(1, b"second".to_vec()).map_err(|e| e.kind()),
+            Err(io::ErrorKind::OutOfMemory)
+        );
+        assert_eq!(blobs.get(1).expect("should read"), None);
+        assert_eq!(blobs.capacity(), Some(BlobCapacity { used: 5, max: 10 }));
+
+        blobs.remove(0).expect("should remove");
+        blobs.put(1, b"second".to_vec()).expect("should fit now");
+        assert_eq!(blobs.capacity(), Some(BlobCapacity { used: 6, max: 10 }));
+        assert_eq!(MemoryBlobs::default().capacity(), None);
+    }
+}
@@ -0,0 +1,536 @@
+use crate::api::Checkpoint;
+use crate::backend::{BlobStore, FileMeta, MetadataStore};
+use crate::merkle::{Sha3Hash, Sha3Tree};
+use crate::sha3::hash_content;
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const OBJECTS: &str = "objects";
+const MANIFESTS: &str = "manifests";
+
+/// One backup of a chain kept in a backup directory. Contents are stored once under
+/// `objects/<content hash>` whichever backup first saw them, leaves and roots are split across
+/// the chain so each backup carries only the ones appended since the previous one. File records
+/// and checkpoints change in place, so every backup has all of them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// position in the chain, starting from 0 for the full backup
+    pub sequence: u64,
+    /// hash of the previous manifest file, missing for the full backup
+    pub parent: Option<Sha3Hash>,
+    pub created_at: SystemTime,
+    /// index of the first leaf of this backup
+    pub first_leaf: usize,
+    pub leaves: Vec<Sha3Hash>,
+    /// root after each of the leaves
+    pub roots: Vec<Sha3Hash>,
+    pub checkpoints: Vec<Checkpoint>,
+    /// records in id order
+    pub files: Vec<FileMeta>,
+    /// content hashes of files which have content, by id
+    pub blobs: BTreeMap<usize, Sha3Hash>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BackupSummary {
+    pub sequence: u64,
+    /// leaves appended since the previous backup
+    pub leaves: usize,
+    /// contents not found among objects yet
+    pub objects: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RestoredBackup {
+    /// backups applied, the full one included
+    pub backups: usize,
+    pub files: usize,
+    pub leaves: usize,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn manifest_path(dir: &Path, sequence: u64) -> PathBuf {
+    dir.join(MANIFESTS).join(format!("{sequence:08}.json"))
+}
+
+/// Manifests of the chain in given directory in order, checked to link to each other, together
+/// with the hash of the last one
+fn read_chain(dir: &Path) -> io::Result<(Vec<BackupManifest>, Option<Sha3Hash>)> {
+    let mut chain = Vec::new();
+    let mut parent = None;
+    loop {
+        let path = manifest_path(dir, chain.len() as u64);
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((chain, parent)),
+            Err(err) => return Err(err),
+        };
+        let manifest: BackupManifest = serde_json::from_slice(&bytes)
+            .map_err(|err| invalid(format!("{}: {err}", path.display())))?;
+        let leaves = chain.last().map_or(0, |last: &BackupManifest| {
+            last.first_leaf + last.leaves.len()
+        });
+        if manifest.sequence != chain.len() as u64
+            || manifest.parent != parent
+            || manifest.first_leaf != leaves
+            || manifest.roots.len() != manifest.leaves.len()
+        {
+            return Err(invalid(format!(
+                "{} doesn't follow previous backup of the chain",
+                path.display()
+            )));
+        }
+        parent = Some(hash_content(&bytes));
+        chain.push(manifest);
+    }
+}
+
+/// Whether given directory has a full backup incremental ones can follow
+pub fn has_backups(dir: &Path) -> bool {
+    manifest_path(dir, 0).exists()
+}
+
+/// Writes backup of storage to given directory: a full one starting a new chain, which needs
+/// the directory to have no backups yet, or an `incremental` one following the chain there.
+/// Incremental backup fails if leaves backed up already are not in the tree anymore, e.g. once
+/// storage was replaced.
+pub fn backup(storage: &Storage, dir: &Path, incremental: bool) -> io::Result<BackupSummary> {
+    let mut pending = start(storage, dir, incremental)?;
+    while !pending.is_read() {
+        let contents = pending.read_contents(storage, usize::MAX)?;
+        pending.write_contents(contents)?;
+    }
+    pending.finish()
+}
+
+/// Backup with file records, leaves and checkpoints taken, whose contents not backed up yet are
+/// copied a batch at a time, so storage doesn't have to be locked for the whole backup
+pub struct PendingBackup {
+    dir: PathBuf,
+    manifest: BackupManifest,
+    /// files whose contents are still to be read, last first
+    unread: Vec<usize>,
+    /// anything changed since the previous backup of the chain
+    changed: bool,
+    summary: BackupSummary,
+}
+
+/// Starts backup of storage like [`backup`] does, taking everything but contents
+pub fn start(storage: &Storage, dir: &Path, incremental: bool) -> io::Result<PendingBackup> {
+    let (chain, parent) = read_chain(dir)?;
+    match (incremental, chain.is_empty()) {
+        (true, true) => {
+            return Err(invalid(format!(
+                "{} has no full backup to follow",
+                dir.display()
+            )))
+        }
+        (false, false) => {
+            return Err(invalid(format!(
+                "{} has backups already, back up incrementally or into other directory",
+                dir.display()
+            )))
+        }
+        _ => {}
+    }
+    let (metadata, _) = storage.stores();
+    let leaves = metadata.leaves()?;
+    let roots = metadata.roots()?;
+    let last = chain.last();
+    let first_leaf = last.map_or(0, |last| last.first_leaf + last.leaves.len());
+    let backed_up_root = last.and_then(|last| last.roots.last());
+    if first_leaf > leaves.len()
+        || backed_up_root
+            .is_some_and(|root| Some(root) != first_leaf.checked_sub(1).and_then(|i| roots.get(i)))
+    {
+        return Err(invalid(format!(
+            "storage diverged from backups in {}, start a full backup elsewhere",
+            dir.display()
+        )));
+    }
+
+    let files = metadata.all()?;
+    let checkpoints = metadata.checkpoints()?;
+    let changed = match last {
+        None => true,
+        Some(last) => {
+            first_leaf < leaves.len()
+                || checkpoints != last.checkpoints
+                || serialized(&files)? != serialized(&last.files)?
+        }
+    };
+    let mut backed_up = BTreeMap::new();
+    let mut unread = Vec::new();
+    for (id, file) in files.iter().enumerate().rev() {
+        // content of an id never changes, it is only removed once the file is deleted
+        let known = last.and_then(|last| last.blobs.get(&id));
+        match (known, &file.deleted) {
+            (Some(hash), None) => {
+                backed_up.insert(id, hash.clone());
+            }
+            _ => unread.push(id),
+        }
+    }
+    Ok(PendingBackup {
+        dir: dir.to_path_buf(),
+        manifest: BackupManifest {
+            sequence: chain.len() as u64,
+            parent,
+            created_at: SystemTime::now(),
+            first_leaf,
+            leaves: leaves[first_leaf..].to_vec(),
+            roots: roots[first_leaf..].to_vec(),
+            checkpoints,
+            files,
+            blobs: backed_up,
+        },
+        unread,
+        changed,
+        summary: BackupSummary {
+            sequence: chain.len() as u64,
+            leaves: leaves.len() - first_leaf,
+            objects: 0,
+            bytes: 0,
+        },
+    })
+}
+
+fn serialized<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    serde_json::to_vec(value).map_err(io::Error::other)
+}
+
+impl PendingBackup {
+    /// Whether file records, leaves or checkpoints changed since the previous backup of the chain,
+    /// backups without changes are not worth writing
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    /// All contents to back up were read
+    pub fn is_read(&self) -> bool {
+        self.unread.is_empty()
+    }
+
+    /// Reads contents of up to `batch` files from the blob store as it keeps them at rest, so
+    /// encrypted contents stay encrypted in the backup. Files deleted meanwhile have none.
+    pub fn read_contents(
+        &mut self,
+        storage: &Storage,
+        batch: usize,
+    ) -> io::Result<Vec<(usize, Vec<u8>)>> {
+        let (_, blobs) = storage.stores();
+        let blobs = blobs.at_rest().unwrap_or(blobs);
+        let mut contents = Vec::new();
+        while contents.len() < batch {
+            let Some(id) = self.unread.pop() else {
+                break;
+            };
+            if let Some(content) = blobs.get(id)? {
+                contents.push((id, content));
+            }
+        }
+        Ok(contents)
+    }
+
+    /// Writes contents read by [`PendingBackup::read_contents`] to objects, storage is not needed
+    pub fn write_contents(&mut self, contents: Vec<(usize, Vec<u8>)>) -> io::Result<()> {
+        let objects = self.dir.join(OBJECTS);
+        std::fs::create_dir_all(&objects)?;
+        for (id, content) in contents {
+            let hash = hash_content(&content);
+            let path = objects.join(hash.to_string());
+            if !path.exists() {
+                self.summary.objects += 1;
+                self.summary.bytes += content.len() as u64;
+                write_atomically(&path, &content)?;
+            }
+            self.manifest.blobs.insert(id, hash);
+        }
+        Ok(())
+    }
+
+    /// Writes the manifest once all contents were written, which makes backup part of the chain
+    pub fn finish(self) -> io::Result<BackupSummary> {
+        if !self.is_read() {
+            return Err(io::Error::other("backup has contents left to read"));
+        }
+        std::fs::create_dir_all(self.dir.join(OBJECTS))?;
+        std::fs::create_dir_all(self.dir.join(MANIFESTS))?;
+        write_atomically(
+            &manifest_path(&self.dir, self.summary.sequence),
+            &serialized(&self.manifest)?,
+        )?;
+        Ok(self.summary)
+    }
+}
+
+/// Written under temporary name first, so a backup interrupted midway leaves no partial files
+fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, content)?;
+    std::fs::rename(temporary, path)
+}
+
+/// Applies backup chain in given directory to empty stores, after checking that manifests link
+/// to each other, leaves give the backed up roots and checkpoints and contents match their hashes.
+/// Nothing is written unless all checks pass. Contents go to the store keeping blobs at rest, so
+/// backups of encrypted storage are restored with the same master key.
+pub fn restore(
+    dir: &Path,
+    metadata: &mut dyn MetadataStore,
+    blobs: &mut dyn BlobStore,
+) -> io::Result<RestoredBackup> {
+    let blobs = match blobs.at_rest().is_some() {
+        true => blobs.at_rest_mut().expect("should have store at rest"),
+        false => blobs,
+    };
+    if !metadata.leaves()?.is_empty() || !metadata.all()?.is_empty() {
+        return Err(io::Error::other(
+            "backup can be restored only into empty storage",
+        ));
+    }
+    let (chain, _) = read_chain(dir)?;
+    let Some(last) = chain.last() else {
+        return Err(invalid(format!("{} has no backups", dir.display())));
+    };
+    let leaves: Vec<_> = chain.iter().flat_map(|manifest| &manifest.leaves).collect();
+    let roots: Vec<_> = chain.iter().flat_map(|manifest| &manifest.roots).collect();
+    check_roots(&leaves, &roots, &last.checkpoints)?;
+    let mut contents = BTreeMap::new();
+    for (id, hash) in &last.blobs {
+        let content = std::fs::read(dir.join(OBJECTS).join(hash.to_string()))?;
+        if hash_content(&content) != *hash {
+            return Err(invalid(format!(
+                "backed up content of file {id} is corrupted"
+            )));
+        }
+        contents.insert(*id, content);
+    }
+
+    metadata.begin()?;
+    blobs.begin()?;
+    let restored = (|| {
+        for file in &last.files {
+            metadata.insert(file.clone())?;
+        }
+        for (leaf, root) in leaves.iter().zip(&roots) {
+            metadata.append_leaf((*leaf).clone(), (*root).clone())?;
+        }
+        for checkpoint in &last.checkpoints {
+            metadata.seal_epoch(checkpoint.clone())?;
+        }
+        for (id, content) in contents {
+            blobs.put(id, content)?;
+        }
+        blobs.commit()?;
+        metadata.commit()
+    })();
+    if restored.is_err() {
+        blobs.rollback()?;
+        metadata.rollback()?;
+    }
+    restored.map(|()| RestoredBackup {
+        backups: chain.len(),
+        files: last.files.len(),
+        leaves: leaves.len(),
+    })
+}
+
+/// Rebuilds epoch trees from leaves, each leaf must give the root recorded after it and each
+/// sealed epoch the root of its checkpoint
+fn check_roots(
+    leaves: &[&Sha3Hash],
+    roots: &[&Sha3Hash],
+    checkpoints: &[Checkpoint],
+) -> io::Result<()> {
+    let mut tree = Sha3Tree::new();
+    let mut checkpoints = checkpoints.iter().peekable();
+    for (index, (leaf, root)) in leaves.iter().zip(roots).enumerate() {
+        tree.append((*leaf).clone());
+        if tree.root().as_ref() != Some(*root) {
+            return Err(invalid(format!(
+                "backed up leaf {index} doesn't give root recorded after it"
+            )));
+        }
+        if let Some(checkpoint) =
+            checkpoints.next_if(|c| index + 1 == (c.first_leaf + c.size) as usize)
+        {
+            if tree.root().as_ref() != Some(&checkpoint.root) {
+                return Err(invalid(format!(
+                    "backed up leaves don't give root of epoch {}",
+                    checkpoint.epoch
+                )));
+            }
+            tree = Sha3Tree::new();
+        }
+    }
+    match checkpoints.next() {
+        Some(checkpoint) => Err(invalid(format!(
+            "leaves of epoch {} are missing from backups",
+            checkpoint.epoch
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::{MemoryBlobs, MemoryMetadata};
+    use crate::storage::EpochPolicy;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_incremental_backups_restore() {
+        let dir = temp_dir("safe_storage_backup_test");
+        let mut storage = Storage::new().with_epoch_policy(EpochPolicy {
+            max_leaves: Some(2),
+            ..Default::default()
+        });
+        let first = storage
+            .add_new_file("a.txt".to_string(), b"a".to_vec())
+            .unwrap();
+        assert!(backup(&storage, &dir, true).is_err());
+        let full = backup(&storage, &dir, false).unwrap();
+        assert_eq!((full.sequence, full.leaves, full.objects), (0, 1, 1));
+        assert!(backup(&storage, &dir, false).is_err());
+
+        storage
+            .add_new_file("b.txt".to_string(), b"a".to_vec())
+            .unwrap();
+        storage
+            .add_new_file("c.txt".to_string(), b"c".to_vec())
+            .unwrap();
+        storage.delete_file(first).unwrap();
+        let incremental = backup(&storage, &dir, true).unwrap();
+        // same content is kept once
+        assert_eq!((incremental.sequence, incremental.leaves), (1, 3));
+        assert_eq!((incremental.objects, incremental.bytes), (1, 1));
+
+        let mut metadata = MemoryMetadata::default();
+        let mut blobs = MemoryBlobs::default();
+        let restored = restore(&dir, &mut metadata, &mut blobs).unwrap();
+        assert_eq!(
+            restored,
+            RestoredBackup {
+                backups: 2,
+                files: 3,
+                leaves: 4,
+            }
+        );
+        assert!(restore(&dir, &mut metadata, &mut blobs).is_err());
+        let mut restored = Storage::open(Box::new(metadata), Box::new(blobs)).unwrap();
+        assert_eq!(restored.check_integrity().unwrap(), None);
+        assert_eq!(restored.root_hash(), storage.root_hash());
+        assert_eq!(restored.checkpoints(), storage.checkpoints());
+        let names: Vec<_> = restored
+            .list_all_files()
+            .map(|file| {
+                let file = file.unwrap();
+                (file.name, restored.file_content(file.id).unwrap())
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("b.txt".to_string(), b"a".to_vec()),
+                ("c.txt".to_string(), b"c".to_vec())
+            ]
+        );
+
+        // tampered content is found before anything is restored
+        let object = dir.join(OBJECTS).join(hash_content(b"c").to_string());
+        std::fs::write(&object, b"x").unwrap();
+        let mut metadata = MemoryMetadata::default();
+        let mut blobs = MemoryBlobs::default();
+        assert!(restore(&dir, &mut metadata, &mut blobs).is_err());
+        assert!(metadata.all().unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_backup_only_when_changed() {
+        let dir = temp_dir("safe_storage_backup_changed_test");
+        let mut storage = Storage::new();
+        let id = storage
+            .add_new_file("a.txt".to_string(), b"a".to_vec())
+            .expect("should add");
+        assert!(start(&storage, &dir, false)
+            .expect("should start")
+            .changed());
+        backup(&storage, &dir, false).expect("should back up");
+        assert!(!start(&storage, &dir, true).expect("should start").changed());
+
+        // records change without the tree growing
+        storage.record_download(&[id], SystemTime::now());
+        storage.flush_downloads().expect("should flush");
+        let mut pending = start(&storage, &dir, true).expect("should start");
+        assert!(pending.changed());
+        assert!(pending.is_read(), "content is backed up already");
+        assert!(pending
+            .read_contents(&storage, 1)
+            .expect("should read")
+            .is_empty());
+        assert_eq!(pending.finish().expect("should finish").sequence, 1);
+        std::fs::remove_dir_all(&dir).expect("should remove");
+    }
+
+    #[test]
+    fn test_encrypted_backup() {
+        use crate::encryption::{EncryptedBlobs, MasterKey};
+
+        let key = || "01".repeat(32).parse::<MasterKey>().expect("should parse");
+        let encrypted = || EncryptedBlobs::new(Box::<MemoryBlobs>::default(), Box::new(key()));
+        let dir = temp_dir("safe_storage_backup_encrypted_test");
+        let mut storage = Storage::open(Box::<MemoryMetadata>::default(), Box::new(encrypted()))
+            .expect("should open");
+        let id = storage
+            .add_new_file("a.txt".to_string(), b"secret".to_vec())
+            .expect("should add");
+        backup(&storage, &dir, false).expect("should back up");
+        for object in std::fs::read_dir(dir.join(OBJECTS)).expect("should list") {
+            let content = std::fs::read(object.expect("should list").path()).expect("should read");
+            assert!(!content.windows(6).any(|window| window == b"secret"));
+        }
+
+        let mut metadata = MemoryMetadata::default();
+        let mut blobs = encrypted();
+        restore(&dir, &mut metadata, &mut blobs).expect("should restore");
+        let restored = Storage::open(Box::new(metadata), Box::new(blobs)).expect("should open");
+        assert_eq!(
+            restored.file_content(id).expect("should decrypt"),
+            b"secret".to_vec()
+        );
+        std::fs::remove_dir_all(&dir).expect("should remove");
+    }
+
+    #[test]
+    fn test_backup_of_replaced_storage() {
+        let dir = temp_dir("safe_storage_backup_replaced_test");
+        let mut storage = Storage::new();
+        storage
+            .add_new_file("a.txt".to_string(), b"a".to_vec())
+            .unwrap();
+        backup(&storage, &dir, false).unwrap();
+
+        let mut other = Storage::new();
+        other
+            .add_new_file("b.txt".to_string(), b"b".to_vec())
+            .unwrap();
+        assert!(backup(&other, &dir, true).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
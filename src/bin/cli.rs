@@ -1,23 +1,258 @@
 use anyhow::anyhow;
-use clap::{ArgAction, Parser, Subcommand};
-use safe_storage::client::Client;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use futures_util::future::try_join_all;
+use notify::{RecursiveMode, Watcher};
+use safe_storage::api::{
+    Checkpoint, File, FileGrouping, FileId, FileListQuery, FileSort, HistoricalProof, LeafIndex,
+    SortOrder, TreeSize, UploadReceipt, MAX_BATCH_FILES, MAX_PAGE_LIMIT,
+};
+use safe_storage::client::{
+    Client, HttpError, Measurement, NameCheck, VerificationError, VerificationSummary,
+};
+use safe_storage::codec::{Codec, MAX_BODY_SIZE};
+use safe_storage::compression::Codings;
+use safe_storage::encryption::MasterKey;
+use safe_storage::hashing::canonical_name;
+use safe_storage::import::{Importer, DEFAULT_MAX_IMPORT_SIZE};
+use safe_storage::leaf::{ContentLeaves, LeafHasher, LeafHashing};
 use safe_storage::merkle;
+use safe_storage::merkle::LeafDiff;
 use safe_storage::sha3::hash_content;
+use safe_storage::share::ShareLink;
+use safe_storage::signing::{key_id, TrustedKey};
+use safe_storage::storage::{normalize_name, validate_name};
+use safe_storage::testvectors::{self, TestVectors};
+use safe_storage::throttle::RateLimit;
+use safe_storage::trace::TraceContext;
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Set by `--quiet`
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--progress json`
+static PROGRESS_JSON: AtomicBool = AtomicBool::new(false);
+
+/// Prints status message unless `--quiet` is given, command results are always printed
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
 
 /// A simple command line interface to interact with safe-storage server (must be already running)
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct CmdArgs {
+    /// base url of the server, or unix://PATH of its socket
     #[arg(long, default_value = "http://localhost:8080")]
     server_url: String,
     #[arg(short, long, default_value = ".state.json")]
     state_file: String,
+    /// limit upload and download bandwidth, in bytes per second with optional K, M or G suffix
+    #[arg(long, value_name = "RATE")]
+    limit_rate: Option<RateLimit>,
+    /// api key secret sent to the server, required if server has api keys configured
+    #[arg(long, value_name = "SECRET")]
+    api_key: Option<String>,
+    /// talk http/2 with prior knowledge to the server
+    #[arg(long)]
+    http2: bool,
+    /// wire format of requests and responses: json, cbor or msgpack
+    #[arg(long, default_value = "json")]
+    codec: Codec,
+    /// compress request bodies with the first of given content codings and accept responses
+    /// compressed with any of them: zstd, gzip, or identity alone
+    #[arg(long, value_name = "CODINGS", default_value = "identity")]
+    compression: Codings,
+    /// hex encoded public key of the server checkpoints of sealed epochs must be signed with,
+    /// optionally limited to checkpoints sealed within `@FROM..UNTIL` unix times. Can be repeated
+    /// to accept keys server rotated through.
+    #[arg(long, value_name = "HEX[@FROM..UNTIL]")]
+    server_key: Vec<TrustedKey>,
+    /// W3C trace context all requests are traced under, a new trace is started without it
+    #[arg(long, value_name = "HEADER")]
+    traceparent: Option<TraceContext>,
+    /// how leaves are derived from file names and contents: content, name-content, name-hash,
+    /// chunked:BYTES or keyed:HEX. Detected from server `/info` if omitted, offline commands
+    /// hash content alone then.
+    #[arg(long, value_name = "STRATEGY")]
+    leaf_hashing: Option<LeafHashing>,
+    /// keep contents verified by `download --stream` in directory, they are downloaded again
+    /// only if server reports their content changed. Proofs verified by `audit proofs` are kept
+    /// there too.
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    /// print only command results and errors, no status messages
+    #[arg(short, long)]
+    quiet: bool,
+    /// `json` also writes progress of uploads and downloads to stderr as newline-delimited json
+    /// events: started, chunk-uploaded, verified and done
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    progress: OutputFormat,
     #[command(subcommand)]
     command: Command,
 }
 
+/// Kinds of failures scripts can tell apart by exit code, other failures exit with 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Failure {
+    /// proof, receipt, checkpoint or consistency from the server doesn't verify
+    Verification,
+    /// server can't be reached or connection broke
+    Network,
+    /// requested file or epoch doesn't exist on the server
+    NotFound,
+    /// local state or audit log is missing or can't be parsed
+    State,
+}
+
+impl Failure {
+    fn of(err: &anyhow::Error) -> Option<Failure> {
+        err.chain().find_map(|cause| {
+            if cause.is::<VerificationError>() {
+                Some(Failure::Verification)
+            } else if cause.is::<StateError>() {
+                Some(Failure::State)
+            } else if cause.is::<reqwest::Error>() {
+                Some(Failure::Network)
+            } else {
+                match cause.downcast_ref::<HttpError>() {
+                    Some(err) if err.status == 404 => Some(Failure::NotFound),
+                    _ => None,
+                }
+            }
+        })
+    }
+
+    fn exit_code(self) -> u8 {
+        match self {
+            Failure::Verification => 2,
+            Failure::Network => 3,
+            Failure::NotFound => 4,
+            Failure::State => 5,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StateError(String);
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StateError {}
+
+fn verification_failed(message: impl Into<String>) -> anyhow::Error {
+    VerificationError(message.into()).into()
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Progress event of `--progress json`, tagged by `event`
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum Progress<'a> {
+    Started {
+        command: &'a str,
+        files: usize,
+    },
+    /// content of a file is stored by the server, `done` of `total` files so far
+    ChunkUploaded {
+        file: &'a str,
+        id: FileId,
+        bytes: u64,
+        done: usize,
+        total: usize,
+    },
+    /// downloaded file verified against trusted root, or local root after uploads matches the
+    /// server one
+    Verified {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<FileId>,
+        root: &'a merkle::Sha3Hash,
+    },
+    Done {
+        command: &'a str,
+        elapsed_ms: u128,
+    },
+}
+
+fn progress(event: Progress) {
+    if PROGRESS_JSON.load(Ordering::Relaxed) {
+        match serde_json::to_string(&event) {
+            Ok(json) => eprintln!("{json}"),
+            Err(err) => eprintln!("Progress event not written: {err}"),
+        }
+    }
+}
+
+fn chunk_uploaded(file: &UploadedFile, done: usize, total: usize) {
+    progress(Progress::ChunkUploaded {
+        file: &file.file,
+        id: file.id,
+        bytes: file.bytes,
+        done,
+        total,
+    });
+}
+
+/// Where and how downloaded files are written
+#[derive(Args, Debug)]
+struct SaveOptions {
+    /// directory to save downloaded files to
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    dir: PathBuf,
+    /// overwrite existing files
+    #[arg(long)]
+    force: bool,
+}
+
+/// How files are sent to the server
+#[derive(Args, Debug)]
+struct UploadOptions {
+    /// reserve leaf slots first and upload all files concurrently
+    #[arg(long)]
+    parallel: bool,
+    /// refuse upload if any other writer appended to server tree since local state was
+    /// updated, instead of letting local state silently diverge
+    #[arg(long, conflicts_with = "parallel")]
+    exclusive: bool,
+    /// send raw content straight from disk, so neither client nor server holds big files whole
+    /// in memory
+    #[arg(long, conflicts_with = "parallel")]
+    stream: bool,
+    /// upload files one request each instead of batches stored all together or not at all, a
+    /// failure in the middle leaves earlier files stored but not in local state
+    #[arg(long, conflicts_with = "parallel")]
+    sequential: bool,
+    /// stream files into server quarantine, they are committed only once server validates them
+    /// and `wait` appends them to local state
+    #[arg(long, conflicts_with_all = ["parallel", "exclusive", "manifest"])]
+    quarantine: bool,
+    /// name files by hex of their content hash instead of their paths, the name servers started
+    /// with `--name-by-hash` store them under
+    #[arg(long)]
+    name_by_hash: bool,
+}
+
 #[derive(Subcommand, Debug)]
 enum Command {
     /// Upload one or more files to the server, storing calculated merkle root hash in local state
@@ -25,111 +260,4337 @@ enum Command {
         /// file list to upload
         #[arg(action = ArgAction::Append)]
         files: Vec<String>,
+        #[command(flatten)]
+        upload: UploadOptions,
+        /// how to print upload results
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+        /// also write json manifest of uploaded files, ids, leaf indices, hashes and roots to file
+        #[arg(long, value_name = "FILE")]
+        manifest: Option<String>,
+        /// seconds after which server tombstones uploaded files
+        #[arg(long, value_name = "SECS")]
+        ttl: Option<u64>,
+        /// dataset uploaded files join, see `dataset`
+        #[arg(long, value_name = "NAME")]
+        dataset: Option<String>,
+        /// directory signed upload receipts are written to as `<id>.json`, if server has a key
+        #[arg(long, value_name = "DIR", default_value = ".receipts")]
+        receipts: PathBuf,
+    },
+    /// Keep uploading files created or modified in a directory until interrupted, like a
+    /// continuous backup, appending their leaves to local state and printing each new root
+    Watch {
+        /// directory to watch, including its subdirectories
+        dir: PathBuf,
+        /// milliseconds without further changes before changed files are uploaded
+        #[arg(long, value_name = "MILLIS", default_value_t = 500)]
+        debounce: u64,
+    },
+    /// Wait until file uploaded with `upload --quarantine` is validated by server, appending its
+    /// leaf hash reported by server to local state once it is committed
+    Wait {
+        /// id of the quarantined file
+        id: FileId,
+        /// seconds between status checks
+        #[arg(long, value_name = "SECS", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        /// directory signed upload receipt is written to as `<id>.json`, if server has a key
+        #[arg(long, value_name = "DIR", default_value = ".receipts")]
+        receipts: PathBuf,
+    },
+    /// Let server fetch and store file from given url, appending its leaf hash reported by server
+    /// to local state
+    Import {
+        /// url of the file, server allows https only by default
+        #[arg(required_unless_present = "manifest")]
+        url: Option<String>,
+        /// name to store file under, last url path segment is used if omitted
+        #[arg(long, conflicts_with = "manifest")]
+        name: Option<String>,
+        /// seconds after which server tombstones imported file
+        #[arg(long, value_name = "SECS")]
+        ttl: Option<u64>,
+        /// download urls listed in file, one per line, here instead and upload them hashed
+        /// locally, recording which id and leaf each url became in local state. Urls recorded
+        /// already are skipped, so interrupted migration can be run again.
+        #[arg(long, value_name = "FILE", conflicts_with = "url")]
+        manifest: Option<PathBuf>,
+        /// biggest content downloaded from a manifest url, in bytes
+        #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_IMPORT_SIZE, requires = "manifest")]
+        max_bytes: u64,
+        /// directory signed upload receipt of the imported url is written to as `<id>.json`, if
+        /// server has a key
+        #[arg(
+            long,
+            value_name = "DIR",
+            default_value = ".receipts",
+            conflicts_with = "manifest"
+        )]
+        receipts: PathBuf,
     },
     /// List all files available on server
-    List,
+    List {
+        /// also list expired files, including already tombstoned ones
+        #[arg(long)]
+        include_expired: bool,
+        /// only list tombstoned files, both deleted and expired
+        #[arg(long, conflicts_with = "include_expired")]
+        deleted: bool,
+        /// only list changes since tree size returned by previous `list --since`
+        #[arg(long, value_name = "TREE_SIZE", conflicts_with_all = ["include_expired", "deleted"])]
+        since: Option<TreeSize>,
+        /// created, name or size
+        #[arg(long, value_name = "KEY", default_value = "created")]
+        sort: FileSort,
+        /// asc or desc
+        #[arg(long, default_value = "asc")]
+        order: SortOrder,
+        /// group files by top level directory
+        #[arg(long, value_name = "prefix")]
+        group: Option<FileGrouping>,
+        /// check listed names against leaves of local state, needs name-hash leaves
+        #[arg(long, conflicts_with_all = ["since", "deleted", "group"])]
+        verify_names: bool,
+        /// list in pages of this many files, which all list the tree as it was at the first one
+        #[arg(long, value_name = "FILES", conflicts_with_all = ["since", "group", "verify_names"])]
+        page_size: Option<usize>,
+    },
     /// Download any file by given id from the list automatically verifying integrity with proof
     /// from server and merkle root from local storage
     Download {
         /// file id to download
-        id: u32,
-        /// optionally specify under which name to save file content, otherwise original name will be used
+        id: FileId,
+        /// optionally specify under which name to save file content, otherwise original name
+        /// without directories will be used
+        #[arg(long, value_name = "FILENAME")]
+        save_as: Option<String>,
+        /// stream content to disk hashing it on the way, instead of keeping it whole in memory
+        #[arg(long)]
+        stream: bool,
+        /// hex encoded sha3-256 hash content must have, checked before its proof
+        #[arg(long, value_name = "HEX")]
+        expect_hash: Option<merkle::Sha3Hash>,
+        #[command(flatten)]
+        save: SaveOptions,
+    },
+    /// Print detected content type and first bytes of file, without verification
+    Head {
+        /// file id to preview
+        id: FileId,
+        /// how many bytes to print, server default is used if omitted
+        #[arg(long)]
+        bytes: Option<usize>,
+    },
+    /// Download all listed files concurrently, verifying each of them like download does
+    DownloadAll {
+        #[command(flatten)]
+        save: SaveOptions,
+    },
+    /// Download given files in a single request, verified all at once with a multi-proof
+    DownloadBatch {
+        /// file ids to download
+        #[arg(required = true)]
+        ids: Vec<FileId>,
+        #[command(flatten)]
+        save: SaveOptions,
+    },
+    /// Restore latest version of every file present in the tree at a historical state, each
+    /// verified against root of that state. Files deleted since can't be restored.
+    Restore {
+        /// tree size of the first epoch, whose root is computed from local state, or root pinned
+        /// in local state or of a sealed epoch
+        #[arg(long, value_name = "ROOT|TREE_SIZE")]
+        checkpoint: TreeState,
+        #[command(flatten)]
+        save: SaveOptions,
+    },
+    /// Delete file by given id, verifying deletion receipt and appending tombstone to local state
+    Delete {
+        /// file id to delete
+        id: FileId,
+    },
+    /// Append content of deleted file again as a new file, if server kept it
+    Undelete {
+        /// id of deleted file
+        id: FileId,
+    },
+    /// Let server pin files to its IPFS node, CIDs are listed with the files afterwards
+    ExportIpfs {
+        /// ids of files to export
+        #[arg(required_unless_present = "all")]
+        ids: Vec<FileId>,
+        /// export all listed files
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+    /// Print link to file which anyone with this cli can download and verify against the latest
+    /// root pinned in local state with `open-link`, including key of the file if it was encrypted
+    ShareLink {
+        /// id of the file to share
+        id: FileId,
+        /// environment variable with hex encoded 32 byte key file was encrypted with, as
+        /// `encryption.key_env` of backup profile. Only key of this file gets into the link.
+        #[arg(long, value_name = "VAR")]
+        key_env: Option<String>,
+    },
+    /// Download file of a link made by `share-link`, verify it against root in the link and
+    /// decrypt it if the link has a key. Server of the link is used instead of `--server-url`.
+    OpenLink {
+        link: ShareLink,
+        /// optionally specify under which name to save file content, otherwise original name
+        /// without directories will be used
         #[arg(long, value_name = "FILENAME")]
         save_as: Option<String>,
+        #[command(flatten)]
+        save: SaveOptions,
+    },
+    /// Fetch and verify deletion receipt of previously deleted file, or verify upload receipts
+    /// offline
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Receipt {
+        #[command(subcommand)]
+        command: Option<ReceiptCommand>,
+        /// deleted file id
+        #[arg(required = true)]
+        id: Option<FileId>,
+    },
+    /// Print local and pinned roots next to the server root, failing if server tree is not the
+    /// local one or, with --verify, is not proven to extend it
+    Root {
+        /// check with consistency proof from the server that its bigger tree extends the local
+        /// one, instead of only comparing roots of trees of the same size
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Compute merkle root offline for all files in a directory (sorted by path) or for files
+    /// listed one per line in a manifest file, in the same order as they would be uploaded
+    RootOf {
+        /// directory or manifest file
+        path: String,
+    },
+    /// Find text files containing given text on a server started with `--search`, checking their
+    /// leaves against leaves of local state
+    Search {
+        query: String,
+        /// files listed at most
+        #[arg(long, value_name = "COUNT")]
+        limit: Option<usize>,
+    },
+    /// Check that server stores content with given hash, without knowing id of its file
+    Lookup {
+        /// hex encoded sha3-256 hash of the content
+        #[arg(required_unless_present = "file")]
+        hash: Option<merkle::Sha3Hash>,
+        /// derive leaf of this file instead
+        #[arg(long, value_name = "FILE", conflicts_with = "hash")]
+        file: Option<PathBuf>,
+    },
+    /// Compare leaves of local state with another state file, or with server leaves if omitted
+    Diff {
+        /// other state file to compare with
+        other_state: Option<String>,
+    },
+    /// Compare leaves and pinned roots of local state with state of another client of the same
+    /// server, detecting server showing different trees to different clients
+    CrossCheck {
+        /// local state of the other client
+        #[arg(long, value_name = "FILE")]
+        peer_state: String,
+    },
+    /// Show roots pinned in local state after each run changing the tree, oldest first
+    History {
+        /// check with consistency proofs from the server that each pinned tree extends the
+        /// previous one
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Show storage usage of used api key, or of all api keys with --all (admin key required)
+    Usage {
+        #[arg(long)]
+        all: bool,
+    },
+    /// Measure proof and metadata overhead and round trips of downloading files sampled evenly
+    /// from the listing, to compare leaf hashing and epoch settings of servers
+    Bench {
+        /// amount of files to sample
+        #[arg(long, default_value_t = 10)]
+        samples: usize,
+        /// how to print measurements
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+    /// Check that server tree only grows, like a transparency log auditor
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+    /// Back up files of profiles defined in a config file, suitable for cron
+    Backup {
+        #[command(subcommand)]
+        command: BackupCommand,
+    },
+    /// Show shards of experimental cluster, with `--server` pointing to its router, verifying
+    /// their roots against cluster root
+    Cluster,
+    /// Show dataset root verified against root pinned in local state or a trusted checkpoint, or
+    /// all datasets if no name is given
+    Dataset {
+        name: Option<String>,
+        /// download this file and verify it against the dataset root
+        #[arg(long, value_name = "ID", requires = "name")]
+        file: Option<FileId>,
+    },
+    /// Inspect local state or upgrade it to the newest layout
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+    /// Produce portable evidence that file is stored on the server, which anyone can check offline
+    /// with `attest verify`
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Attest {
+        #[command(subcommand)]
+        command: Option<AttestCommand>,
+        /// id of the file to attest
+        #[arg(required = true)]
+        id: Option<FileId>,
+        /// write attestation bundle to file instead of stdout
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Check offline that this build derives leaves, roots and proofs the same as canonical test
+    /// vectors, or vectors written by another build
+    Selftest {
+        /// vectors to check instead of the built-in canonical ones
+        #[arg(long, value_name = "FILE")]
+        vectors: Option<PathBuf>,
+        /// write vectors computed by this build to given file instead of checking any
+        #[arg(long, value_name = "FILE", conflicts_with = "vectors")]
+        write: Option<PathBuf>,
+    },
+}
+
+impl Command {
+    /// Commands working with local files only, leaf hashing is not detected from server for them
+    fn is_offline(&self) -> bool {
+        matches!(
+            self,
+            Command::RootOf { .. }
+                | Command::State { .. }
+                | Command::CrossCheck { .. }
+                | Command::History { verify: false }
+                | Command::Selftest { .. }
+                | Command::Audit {
+                    command: AuditCommand::Proofs { offline: true, .. }
+                }
+                | Command::Receipt {
+                    command: Some(ReceiptCommand::Verify { .. }),
+                    ..
+                }
+                | Command::Attest {
+                    command: Some(AttestCommand::Verify { .. }),
+                    ..
+                }
+        )
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum AttestCommand {
+    /// Check attestation bundle without talking to the server or reading local state. Root of
+    /// not yet sealed epoch is attested by whoever made the bundle only, so it is refused when
+    /// `--server-key` is given.
+    Verify {
+        /// attestation bundle made by `attest <id>`
+        bundle: PathBuf,
+        /// also check that content of this file is the attested one
+        #[arg(long, value_name = "FILE")]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ReceiptCommand {
+    /// Check signed upload receipts stored by `upload` without talking to the server, against
+    /// `--server-key`. Valid receipt proves server accepted the leaf at its index.
+    Verify {
+        /// receipt files, or directories of them
+        #[arg(required = true)]
+        receipts: Vec<PathBuf>,
+        /// also check that content of this file is the one of the receipt, needs a single receipt
+        #[arg(long, value_name = "FILE")]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum StateCommand {
+    /// Upgrade local state written by older client to the newest layout, old file is kept as
+    /// backup next to it
+    Migrate {
+        /// only report state version and integrity, nothing is written
+        #[arg(long)]
+        check: bool,
+    },
+    /// Save a named copy of local state with the server it was taken against, e.g. before
+    /// trying uploads against a staging server
+    Snapshot {
+        name: String,
+        /// replace existing snapshot of the same name
+        #[arg(long)]
+        force: bool,
+    },
+    /// Replace local state with a named snapshot, current state is kept as backup next to it
+    Restore { name: String },
+    /// List snapshots of local state, oldest first
+    Snapshots,
+}
+
+#[derive(Subcommand, Debug)]
+enum BackupCommand {
+    /// Upload new and changed files of given profile, appending their leaves to local state, then
+    /// pin the root and write a report of the run. Fails if any file failed to upload.
+    Run {
+        /// name of the profile in config file
+        profile: String,
+        /// json file with backup profiles
+        #[arg(long, value_name = "FILE", default_value = "backup.json")]
+        config: PathBuf,
+    },
+    /// Download files listed by the latest report of given profile, verified against pinned roots
+    /// and decrypted, into directory under their source paths. Fails if any file failed to
+    /// restore.
+    Restore {
+        /// name of the profile in config file
+        profile: String,
+        /// json file with backup profiles
+        #[arg(long, value_name = "FILE", default_value = "backup.json")]
+        config: PathBuf,
+        #[command(flatten)]
+        save: SaveOptions,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AuditCommand {
+    /// Fetch latest tree head and verify it is consistent with the last audited one, recording
+    /// result to audit log. Fails on any inconsistency, suitable for cron.
+    Run {
+        /// keep auditing every given amount of seconds instead of once
+        #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        interval: Option<u64>,
+        /// file audited tree heads are appended to, one json per line
+        #[arg(long, value_name = "FILE", default_value = ".audit.jsonl")]
+        log: PathBuf,
+    },
+    /// Verify given files against a root pinned in local state. Proofs verified once are kept in
+    /// `--cache-dir`, so later runs check them again without the server.
+    Proofs {
+        /// ids of files to verify
+        #[arg(required = true)]
+        ids: Vec<FileId>,
+        /// size of the pinned tree to verify against, the latest pinned one by default
+        #[arg(long, value_name = "SIZE")]
+        tree_size: Option<usize>,
+        /// only use proofs cached in `--cache-dir`, never ask the server
+        #[arg(long)]
+        offline: bool,
     },
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
     let cmd_args = CmdArgs::parse();
+    QUIET.store(cmd_args.quiet, Ordering::Relaxed);
+    PROGRESS_JSON.store(cmd_args.progress == OutputFormat::Json, Ordering::Relaxed);
+    match run(cmd_args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(Failure::of(&err).map_or(1, Failure::exit_code))
+        }
+    }
+}
+
+async fn run(cmd_args: CmdArgs) -> anyhow::Result<()> {
+    let server_url = match &cmd_args.command {
+        Command::OpenLink { link, .. } => link.server_url.clone(),
+        _ => cmd_args.server_url,
+    };
+    let client = Client::try_new(server_url)?
+        .with_rate_limit(cmd_args.limit_rate)
+        .with_api_key(cmd_args.api_key)
+        .with_http2(cmd_args.http2)
+        .with_codec(cmd_args.codec)
+        .with_codings(cmd_args.compression)
+        .with_cache_dir(cmd_args.cache_dir)
+        .with_trace(Some(
+            cmd_args.traceparent.unwrap_or_else(TraceContext::new_root),
+        ));
+    let client = match cmd_args.leaf_hashing {
+        Some(leaf_hashing) => client.with_leaf_hasher(leaf_hashing.hasher()),
+        None if cmd_args.command.is_offline() => client,
+        None => client.detect_leaf_hashing().await?,
+    };
     match cmd_args.command {
-        Command::Download { id, save_as } => {
-            download_file(cmd_args.server_url, cmd_args.state_file, id, save_as).await
+        Command::Download {
+            id,
+            save_as,
+            stream,
+            expect_hash,
+            save,
+        } => {
+            let server_keys = &cmd_args.server_key;
+            let state_file = cmd_args.state_file;
+            let expected = expect_hash.as_ref();
+            if stream {
+                stream_file(client, state_file, server_keys, id, save_as, expected, save).await
+            } else {
+                download_file(client, state_file, server_keys, id, save_as, expected, save).await
+            }
+        }
+        Command::Upload {
+            files,
+            upload,
+            output,
+            manifest,
+            ttl,
+            dataset,
+            receipts,
+        } => {
+            upload_files(
+                client
+                    .with_ttl(ttl.map(Duration::from_secs))
+                    .with_dataset(dataset),
+                cmd_args.state_file,
+                files,
+                upload,
+                output,
+                manifest,
+                receipts,
+            )
+            .await
+        }
+        Command::List {
+            since: Some(since), ..
+        } => list_changes(client, since).await,
+        Command::List {
+            verify_names: true, ..
+        } => verify_listed_names(client, cmd_args.state_file).await,
+        Command::List {
+            include_expired,
+            deleted,
+            sort,
+            order,
+            group,
+            page_size,
+            ..
+        } => {
+            let query = FileListQuery {
+                include_expired,
+                deleted,
+                sort,
+                order,
+                group,
+                ..Default::default()
+            };
+            list_all_files(client, query, page_size).await
+        }
+        Command::Watch { dir, debounce } => {
+            let debounce = Duration::from_millis(debounce);
+            watch_dir(client, cmd_args.state_file, dir, debounce).await
+        }
+        Command::Wait {
+            id,
+            interval,
+            receipts,
+        } => {
+            let interval = Duration::from_secs(interval);
+            wait_for_file(client, cmd_args.state_file, id, interval, receipts).await
+        }
+        Command::Import {
+            manifest: Some(manifest),
+            ttl,
+            max_bytes,
+            ..
+        } => {
+            let client = client.with_ttl(ttl.map(Duration::from_secs));
+            import_manifest(client, cmd_args.state_file, &manifest, max_bytes).await
+        }
+        Command::Import {
+            url,
+            name,
+            ttl,
+            receipts,
+            ..
+        } => {
+            let client = client.with_ttl(ttl.map(Duration::from_secs));
+            let url = url.expect("url is required without manifest");
+            import_file(client, cmd_args.state_file, url, name, receipts).await
+        }
+        Command::Head { id, bytes } => head_file(client, id, bytes).await,
+        Command::DownloadAll { save } => {
+            let server_keys = &cmd_args.server_key;
+            download_all_files(client, cmd_args.state_file, server_keys, save).await
+        }
+        Command::DownloadBatch { ids, save } => {
+            let server_keys = &cmd_args.server_key;
+            download_batch(client, cmd_args.state_file, server_keys, ids, save).await
+        }
+        Command::Restore { checkpoint, save } => {
+            let server_keys = &cmd_args.server_key;
+            restore_files(client, cmd_args.state_file, server_keys, checkpoint, save).await
+        }
+        Command::Delete { id } => delete_file(client, cmd_args.state_file, id).await,
+        Command::Undelete { id } => undelete_file(client, cmd_args.state_file, id).await,
+        Command::ExportIpfs { ids, all } => {
+            export_ipfs(client, cmd_args.state_file, ids, all).await
+        }
+        Command::ShareLink { id, key_env } => {
+            share_link(client, cmd_args.state_file, id, key_env).await
+        }
+        Command::OpenLink {
+            link,
+            save_as,
+            save,
+        } => open_link(client, link, save_as, save).await,
+        Command::Receipt {
+            command: Some(ReceiptCommand::Verify { receipts, file }),
+            ..
+        } => {
+            let leaf_hasher = client.leaf_hasher().as_ref();
+            verify_receipts(leaf_hasher, &cmd_args.server_key, receipts, file).await
+        }
+        Command::Receipt { id, .. } => {
+            let id = id.ok_or_else(|| anyhow!("Deleted file id is required"))?;
+            show_receipt(client, id).await
+        }
+        Command::Usage { all } => show_usage(client, all).await,
+        Command::Bench { samples, output } => bench(client, samples, output).await,
+        Command::RootOf { path } => root_of(client.leaf_hasher().as_ref(), path).await,
+        Command::Selftest { vectors, write } => selftest(vectors, write).await,
+        Command::Lookup { hash, file } => {
+            let hash = match (hash, file) {
+                (Some(hash), _) => hash,
+                (None, Some(file)) => client.leaf_hasher().leaf(
+                    &upload_name(&file.to_string_lossy()),
+                    &tokio::fs::read(&file).await?,
+                ),
+                (None, None) => return Err(anyhow!("Content hash or file is required")),
+            };
+            let server_keys = &cmd_args.server_key;
+            lookup_hash(client, cmd_args.state_file, server_keys, hash).await
+        }
+        Command::Diff { other_state } => diff_state(client, cmd_args.state_file, other_state).await,
+        Command::CrossCheck { peer_state } => cross_check(cmd_args.state_file, peer_state).await,
+        Command::History { verify } => show_history(client, cmd_args.state_file, verify).await,
+        Command::Root { verify } => show_root(client, cmd_args.state_file, verify).await,
+        Command::Search { query, limit } => {
+            search_files(client, cmd_args.state_file, query, limit).await
+        }
+        Command::Audit {
+            command: AuditCommand::Run { interval, log },
+        } => {
+            let interval = interval.map(Duration::from_secs);
+            audit_run(client, &cmd_args.server_key, log, interval).await
+        }
+        Command::Audit {
+            command:
+                AuditCommand::Proofs {
+                    ids,
+                    tree_size,
+                    offline,
+                },
+        } => {
+            let client = client.with_offline_proofs(offline);
+            audit_proofs(client, cmd_args.state_file, ids, tree_size).await
+        }
+        Command::Backup {
+            command: BackupCommand::Run { profile, config },
+        } => backup_run(client, cmd_args.state_file, config, profile).await,
+        Command::Backup {
+            command:
+                BackupCommand::Restore {
+                    profile,
+                    config,
+                    save,
+                },
+        } => {
+            let server_keys = &cmd_args.server_key;
+            backup_restore(
+                client,
+                cmd_args.state_file,
+                server_keys,
+                config,
+                profile,
+                save,
+            )
+            .await
+        }
+        Command::Cluster => show_cluster(client).await,
+        Command::Dataset { name, file } => {
+            let server_keys = &cmd_args.server_key;
+            show_dataset(client, cmd_args.state_file, server_keys, name, file).await
+        }
+        Command::State {
+            command: StateCommand::Migrate { check },
+        } => migrate_state(cmd_args.state_file, check).await,
+        Command::State {
+            command: StateCommand::Snapshot { name, force },
+        } => {
+            snapshot_state(
+                cmd_args.state_file,
+                client.server_url().to_string(),
+                name,
+                force,
+            )
+            .await
+        }
+        Command::State {
+            command: StateCommand::Restore { name },
+        } => restore_state(cmd_args.state_file, name).await,
+        Command::State {
+            command: StateCommand::Snapshots,
+        } => list_snapshots(cmd_args.state_file).await,
+        Command::Attest {
+            command: Some(AttestCommand::Verify { bundle, file }),
+            ..
+        } => verify_attestation(&cmd_args.server_key, bundle, file).await,
+        Command::Attest { id, output, .. } => {
+            let id = id.ok_or_else(|| anyhow!("File id to attest is required"))?;
+            let server_keys = &cmd_args.server_key;
+            attest_file(client, cmd_args.state_file, server_keys, id, output).await
+        }
+    }
+}
+
+async fn show_cluster(client: Client) -> anyhow::Result<()> {
+    let cluster = client.fetch_cluster().await?;
+    if !cluster.verify() {
+        return Err(VerificationError("shard roots don't match cluster root".to_string()).into());
+    }
+    println!("cluster root: {}", cluster.root);
+    for (index, shard) in cluster.shards.iter().enumerate() {
+        let root = shard.root.as_ref().map_or("empty".to_string(), |root| {
+            format!("{} (epoch {}, {} leaves)", root.hash, root.epoch, root.size)
+        });
+        println!(
+            "{index}: {:02x}-{:02x} {} {root}",
+            shard.first_byte, shard.last_byte, shard.url
+        );
+    }
+    Ok(())
+}
+
+/// Datasets are checked against roots pinned in local state or trusted checkpoints, as the root
+/// they are proven with comes from the server
+async fn show_dataset(
+    client: Client,
+    state_filename: String,
+    server_keys: &[TrustedKey],
+    name: Option<String>,
+    file: Option<FileId>,
+) -> anyhow::Result<()> {
+    let state = load_state(state_filename).await?;
+    let roots = epoch_roots(&client, &state, server_keys).await?;
+    let root_of = |epoch| roots.of(epoch).cloned();
+    let datasets = match &name {
+        Some(name) => vec![client.fetch_dataset(name, root_of).await?],
+        None => {
+            let datasets = client.fetch_datasets().await?.datasets;
+            for dataset in &datasets {
+                if !dataset.verify(&root_of(dataset.epoch)?) {
+                    return Err(verification_failed(format!(
+                        "Dataset {} is not committed to trusted root of epoch {}",
+                        dataset.name, dataset.epoch
+                    )));
+                }
+            }
+            datasets
+        }
+    };
+    for dataset in &datasets {
+        println!(
+            "{}: {} ({} files, leaf {} of epoch {} with root {})",
+            dataset.name,
+            dataset.root,
+            dataset.size,
+            dataset.leaf_index,
+            dataset.epoch,
+            dataset.tree_root
+        );
+    }
+    if let (Some(id), [dataset]) = (file, datasets.as_slice()) {
+        let (file, proof) = client
+            .download_dataset_file(&dataset.name, id, root_of)
+            .await?;
+        println!(
+            "{} ({} bytes) verified as file {} of the dataset",
+            file.name,
+            file.content.len(),
+            proof.index
+        );
+    }
+    Ok(())
+}
+
+async fn list_all_files(
+    client: Client,
+    query: FileListQuery,
+    page_size: Option<usize>,
+) -> anyhow::Result<()> {
+    if let Some(page_size) = page_size {
+        for file in client.list_files_paged(query, page_size).await? {
+            println!("{}", describe_listed(&file));
+        }
+        return Ok(());
+    }
+    let list = client.list_files_by(&query).await?;
+    for group in list.groups {
+        match group.prefix.as_str() {
+            "" => println!("[no directory]"),
+            prefix => println!("[{prefix}]"),
         }
-        Command::Upload { files } => {
-            upload_files(cmd_args.server_url, cmd_args.state_file, files).await
+        for file in group.files {
+            println!("  {}", describe_listed(&file));
         }
-        Command::List => list_all_files(cmd_args.server_url).await,
     }
+    for file in list.files {
+        println!("{}", describe_listed(&file));
+    }
+    Ok(())
+}
+
+/// Flags listed names which don't match leaves of local state, which only a server renaming
+/// files could cause
+async fn verify_listed_names(client: Client, state_filename: String) -> anyhow::Result<()> {
+    let state = load_state(state_filename).await?;
+    let root = state
+        .light_tree
+        .root()
+        .ok_or_else(|| anyhow!("local state has no leaves to check names against"))?;
+    let mut mismatches = 0;
+    for (file, check) in client.get_verified_file_list(&state.leaves, &root).await? {
+        let mark = match check {
+            NameCheck::Verified => "[name verified]",
+            NameCheck::Mismatch => {
+                mismatches += 1;
+                "[NAME MISMATCH]"
+            }
+            NameCheck::Unproven => "[name unproven]",
+        };
+        println!("{} {mark}", describe_listed(&file));
+    }
+    if mismatches > 0 {
+        return Err(verification_failed(format!(
+            "{mismatches} listed names don't match their leaves"
+        )));
+    }
+    Ok(())
+}
+
+fn describe_listed(file: &File) -> String {
+    let mut line = format!("{}: {}", file.id, file.name);
+    if file.version > 1 {
+        line.push_str(&format!(" (version {})", file.version));
+    }
+    if let Some(cid) = &file.ipfs_cid {
+        line.push_str(&format!(" ipfs:{cid}"));
+    }
+    if let Some(id) = file.undeleted_from {
+        line.push_str(&format!(" (undeleted from {id})"));
+    }
+    if let Some(last_access) = file.last_access {
+        let secs = unix_time().saturating_sub(last_access);
+        line.push_str(&format!(
+            " ({} downloads, last {secs}s ago)",
+            file.downloads
+        ));
+    }
+    if file.expired {
+        line.push_str(" [expired]");
+    } else if file.deleted {
+        line.push_str(" [deleted]");
+    } else if let Some(expires_at) = file.expires_at {
+        let secs = expires_at.saturating_sub(unix_time());
+        line.push_str(&format!(" [expires in {secs}s]"));
+    }
+    line
+}
+
+async fn search_files(
+    client: Client,
+    state_filename: String,
+    query: String,
+    limit: Option<usize>,
+) -> anyhow::Result<()> {
+    let state = load_state(state_filename).await?;
+    let hits = client.search(&query, limit).await?.hits;
+    if hits.is_empty() {
+        println!("No files contain {query:?}");
+        return Ok(());
+    }
+    let mut mismatches = 0;
+    for hit in hits {
+        let mark = match state.leaves.get(hit.leaf.leaf_index.as_usize()) {
+            Some(trusted) if *trusted == hit.leaf.hash => "[leaf verified]",
+            Some(_) => {
+                mismatches += 1;
+                "[LEAF MISMATCH]"
+            }
+            None => "[leaf unproven]",
+        };
+        let offsets: Vec<_> = hit
+            .matches
+            .iter()
+            .map(|found| found.offset.to_string())
+            .collect();
+        println!("{}: {} at {} {mark}", hit.id, hit.name, offsets.join(", "));
+    }
+    if mismatches > 0 {
+        return Err(verification_failed(format!(
+            "{mismatches} found files don't match leaves of local state"
+        )));
+    }
+    Ok(())
 }
 
-async fn list_all_files(server_url: String) -> anyhow::Result<()> {
-    let client = Client::new(server_url);
-    let files = client.get_file_list().await?;
-    for file in files.files {
+async fn list_changes(client: Client, since: TreeSize) -> anyhow::Result<()> {
+    let changes = client.list_changes(since).await?;
+    for file in changes.files {
         println!("{}: {}", file.id, file.name);
     }
+    for id in changes.deleted {
+        println!("{id}: [deleted]");
+    }
+    status!("Tree size: {}", changes.tree_size);
+    Ok(())
+}
+
+async fn head_file(client: Client, id: FileId, bytes: Option<usize>) -> anyhow::Result<()> {
+    let (mime, content) = client.preview_file(id, bytes).await?;
+    eprintln!("Content type: {mime}");
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(&content).await?;
+    stdout.flush().await?;
     Ok(())
 }
 
 async fn upload_files(
-    server_url: String,
+    client: Client,
     state_filename: String,
     files: Vec<String>,
+    upload: UploadOptions,
+    output: OutputFormat,
+    manifest_file: Option<String>,
+    receipts_dir: PathBuf,
 ) -> anyhow::Result<()> {
-    let client = Client::new(server_url);
-    let mut light_tree = load_state(state_filename.clone()).await?.light_tree;
+    let mut state = load_state(state_filename.clone()).await?;
     if files.is_empty() {
-        println!("Nothing to upload");
+        status!("Nothing to upload");
         return Ok(());
     }
-    for file in files {
-        let content = tokio::fs::read(&file).await?;
-        light_tree.append(hash_content(&content));
-        let new_file = client.upload_new_file(&file, &content).await?;
-        println!("{file} uploaded with id: {}", new_file.id);
+    if upload.quarantine {
+        for file in files {
+            let name = match upload.name_by_hash {
+                true => hash_file(&ContentLeaves, "", Path::new(&file))
+                    .await?
+                    .to_string(),
+                false => upload_name(&file),
+            };
+            let status = client.upload_quarantined(&name, Path::new(&file)).await?;
+            status!("{file} quarantined with id: {}", status.id);
+        }
+        status!("Files are committed once server validates them, follow with `wait <id>`");
+        return Ok(());
     }
+    let started = Instant::now();
+    let total = files.len();
+    progress(Progress::Started {
+        command: "upload",
+        files: total,
+    });
+    let started_at = unix_time();
+    let pre_root = state.light_tree.root();
+    // root right after the last sequential upload, reserved uploads don't report it
+    let mut last_root = None;
+    let uploaded = if upload.parallel {
+        upload_files_in_parallel(&client, &mut state, files, upload.name_by_hash).await?
+    } else if !upload.stream && !upload.sequential {
+        let (uploaded, root) = upload_files_in_batch(&client, &mut state, files, &upload).await?;
+        last_root = root;
+        if output == OutputFormat::Text {
+            for file in &uploaded {
+                status!(
+                    "{} uploaded as {} with id: {}",
+                    file.file,
+                    file.stored_as,
+                    file.id
+                );
+            }
+        }
+        uploaded
+    } else {
+        let mut uploaded = Vec::with_capacity(files.len());
+        for file in files {
+            let content = match upload.stream {
+                true => None,
+                false => Some(tokio::fs::read(&file).await?),
+            };
+            let name = match (&content, upload.name_by_hash) {
+                (Some(content), true) => canonical_name(content),
+                (None, true) => hash_file(&ContentLeaves, "", Path::new(&file))
+                    .await?
+                    .to_string(),
+                (_, false) => upload_name(&file),
+            };
+            let bytes = match &content {
+                Some(content) => content.len() as u64,
+                None => tokio::fs::metadata(&file).await?.len(),
+            };
+            let hash = match &content {
+                Some(content) => client.leaf_hasher().leaf(&name, content),
+                None => hash_file(client.leaf_hasher().as_ref(), &name, Path::new(&file)).await?,
+            };
+            let leaf_index = state.light_tree.len();
+            state.append(hash.clone());
+            let expected_tree_size = upload.exclusive.then_some(leaf_index.into());
+            let stored = match &content {
+                Some(content) => {
+                    client
+                        .upload_new_file_at(&name, content, expected_tree_size)
+                        .await?
+                }
+                None => {
+                    client
+                        .upload_stream(&name, Path::new(&file), expected_tree_size)
+                        .await?
+                }
+            };
+            last_root = stored.root.map(|root| root.hash);
+            let new_file = stored.file;
+            if output == OutputFormat::Text {
+                status!(
+                    "{file} uploaded as {} with id: {}",
+                    new_file.name,
+                    new_file.id
+                );
+            }
+            uploaded.push(UploadedFile {
+                file,
+                stored_as: new_file.name,
+                id: new_file.id,
+                leaf_index,
+                hash,
+                bytes,
+                receipt: stored.receipt,
+            });
+            chunk_uploaded(&uploaded[uploaded.len() - 1], uploaded.len(), total);
+        }
+        uploaded
+    };
 
-    let local_hash = light_tree
+    let local_hash = state
+        .light_tree
         .root()
         .expect("should be present if at least one file was uploaded");
+    let remote_hash = match last_root {
+        Some(root) => root,
+        None => client.fetch_root().await?.hash,
+    };
+    let manifest = UploadManifest {
+        started_at,
+        finished_at: unix_time(),
+        pre_root,
+        post_root: local_hash.clone(),
+        remote_root: remote_hash.clone(),
+        files: uploaded,
+    };
+    match output {
+        OutputFormat::Text => {
+            status!("Local  hash: {local_hash}");
+            status!("Remote hash: {remote_hash}");
+            if local_hash != remote_hash {
+                status!("Local root hash differs from remote hash - server tree has leaves local state doesn't know about, verification won't work");
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&manifest)?),
+    }
+    if local_hash == remote_hash {
+        progress(Progress::Verified {
+            id: None,
+            root: &local_hash,
+        });
+    }
+    if let Some(manifest_file) = manifest_file {
+        tokio::fs::write(manifest_file, serde_json::to_vec_pretty(&manifest)?).await?;
+    }
+    store_receipts(&receipts_dir, &manifest.files).await?;
+
+    state.pin_root();
+    store_state(state_filename, state).await?;
+    progress(Progress::Done {
+        command: "upload",
+        elapsed_ms: started.elapsed().as_millis(),
+    });
+    Ok(())
+}
+
+/// Writes receipts of uploaded files, warning about those not matching leaf computed locally
+async fn store_receipts(dir: &Path, uploaded: &[UploadedFile]) -> anyhow::Result<()> {
+    let mut stored = 0;
+    for file in uploaded {
+        let Some(receipt) = &file.receipt else {
+            continue;
+        };
+        store_receipt(dir, &file.file, file.id, &file.hash, receipt).await?;
+        stored += 1;
+    }
+    if stored > 0 {
+        status!("{stored} upload receipts written to {}", dir.display());
+    }
+    Ok(())
+}
+
+/// Writes receipt as `<id>.json`, warning if it is not of given file and leaf
+async fn store_receipt(
+    dir: &Path,
+    file: &str,
+    id: FileId,
+    hash: &merkle::Sha3Hash,
+    receipt: &UploadReceipt,
+) -> anyhow::Result<PathBuf> {
+    if (receipt.id, &receipt.leaf_hash) != (id, hash) {
+        eprintln!("Receipt of {file} is not of its leaf {hash}");
+    }
+    tokio::fs::create_dir_all(dir).await?;
+    let path = dir.join(format!("{}.json", receipt.id));
+    tokio::fs::write(&path, serde_json::to_vec_pretty(receipt)?).await?;
+    Ok(path)
+}
+
+async fn import_file(
+    client: Client,
+    state_filename: String,
+    url: String,
+    name: Option<String>,
+    receipts_dir: PathBuf,
+) -> anyhow::Result<()> {
+    let mut state = load_state(state_filename.clone()).await?;
+    let imported = client.import_file(&url, name.as_deref()).await?;
+    status!(
+        "{url} imported as {} with id: {}",
+        imported.file.name,
+        imported.file.id
+    );
+    status!(
+        "Content hash reported by server: {} (content was not seen locally)",
+        imported.hash
+    );
+    if let (Some(proof), Some(root)) = (&imported.proof, &imported.root) {
+        if !proof.verify(root, &imported.hash) {
+            return Err(verification_failed(
+                "Proof of imported file doesn't match reported root",
+            ));
+        }
+    }
+    if let Some(receipt) = &imported.receipt {
+        let path = store_receipt(
+            &receipts_dir,
+            &url,
+            imported.file.id,
+            &imported.hash,
+            receipt,
+        )
+        .await?;
+        status!("Upload receipt written to {}", path.display());
+    }
+    state.append(imported.hash);
+    let local_hash = state
+        .light_tree
+        .root()
+        .expect("should be present after appending imported file");
     let remote_hash = client.fetch_root().await?.hash;
-    println!("Local  hash: {local_hash}");
-    println!("Remote hash: {remote_hash}");
+    status!("Local  hash: {local_hash}");
+    status!("Remote hash: {remote_hash}");
     if local_hash != remote_hash {
-        println!("Local root hash differs from remote hash - multiple uploads detected, which is not supported yet. Verification won't work");
-        println!("Service restart is required to clean the state")
+        status!("Local root hash differs from remote hash - verification won't work");
     }
+    state.pin_root();
+    store_state(state_filename, state).await
+}
 
-    store_state(state_filename, LocalState { light_tree }).await
+/// Urls listed in import manifest, blank lines and `#` comments are skipped
+fn manifest_urls(manifest: &str) -> Vec<&str> {
+    manifest
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
 }
 
-async fn download_file(
-    server_url: String,
+/// Downloads urls of the manifest and uploads their contents like `upload` does, so leaves are
+/// hashed from contents seen locally rather than reported by server. Urls which can't be fetched
+/// are reported and left for the next run, upload failure stops it with the state saved.
+async fn import_manifest(
+    client: Client,
     state_filename: String,
-    id: u32,
-    save_as: Option<String>,
+    manifest: &Path,
+    max_bytes: u64,
+) -> anyhow::Result<()> {
+    let mut state = load_state(state_filename.clone()).await?;
+    let manifest = tokio::fs::read_to_string(manifest).await?;
+    // urls are fetched by the user's own machine, which may reach internal hosts anyway
+    let importer = Importer::new(
+        vec!["http".to_string(), "https".to_string()],
+        vec!["*".to_string()],
+        max_bytes,
+    );
+    let mut failed = 0;
+    let mut uploaded = 0;
+    let mut result = Ok(());
+    for url in manifest_urls(&manifest) {
+        if let Some(imported) = state.imports.iter().find(|imported| imported.url == url) {
+            status!("{url} already imported with id: {}, skipped", imported.id);
+            continue;
+        }
+        let (name, content) = match importer.fetch(url, None, &TraceContext::new_root()).await {
+            Ok(fetched) => fetched,
+            Err(err) => {
+                eprintln!("{url} skipped: {err}");
+                failed += 1;
+                continue;
+            }
+        };
+        let hash = client.leaf_hasher().leaf(&name, &content);
+        let stored = match client.upload_new_file(&name, &content).await {
+            Ok(stored) => stored,
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        };
+        status!(
+            "{url} uploaded as {} with id: {}",
+            stored.file.name,
+            stored.file.id
+        );
+        state.append(hash.clone());
+        state.imports.push(ImportedUrl {
+            url: url.to_string(),
+            id: stored.file.id,
+            hash,
+        });
+        uploaded += 1;
+    }
+    if uploaded > 0 {
+        let local_hash = state
+            .light_tree
+            .root()
+            .expect("should be present after appending imported files");
+        let remote_hash = client.fetch_root().await?.hash;
+        status!("Local  hash: {local_hash}");
+        status!("Remote hash: {remote_hash}");
+        if local_hash != remote_hash {
+            status!("Local root hash differs from remote hash - server tree has leaves local state doesn't know about, verification won't work");
+        }
+        state.pin_root();
+        store_state(state_filename, state).await?;
+    }
+    result?;
+    match failed {
+        0 => Ok(()),
+        failed => Err(anyhow!(
+            "{failed} urls could not be fetched, run import again to retry them"
+        )),
+    }
+}
+
+/// Uploads files changed in `dir` once no further changes come for `debounce`. Files are named by
+/// their path relative to `dir`, hidden ones (e.g. local state or editor swap files) are skipped.
+async fn watch_dir(
+    client: Client,
+    state_filename: String,
+    dir: PathBuf,
+    debounce: Duration,
 ) -> anyhow::Result<()> {
-    let light_tree = load_state(state_filename).await?.light_tree;
-    let client = Client::new(server_url);
-    let file = client.download_file(id).await?;
-    let file_hash = hash_content(&file.content);
-    let verified = file
-        .proof
-        .verify(&light_tree.root().expect("must be present"), &file_hash);
-    if !verified {
-        return Err(anyhow!("Verification failed!"));
+    let dir = tokio::fs::canonicalize(&dir).await?;
+    let (events, mut changes) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+                for path in event.paths {
+                    let _ = events.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("Watching failed: {err}"),
+        })?;
+    watcher.watch(&dir, RecursiveMode::Recursive)?;
+    status!("Watching {} for changes", dir.display());
+    // leaf of the last upload of each file, so touching a file doesn't upload it again
+    let mut uploaded: HashMap<PathBuf, merkle::Sha3Hash> = HashMap::new();
+    while let Some(path) = changes.recv().await {
+        let mut changed = BTreeSet::from([path]);
+        while let Ok(Some(path)) = tokio::time::timeout(debounce, changes.recv()).await {
+            changed.insert(path);
+        }
+        let state_path = tokio::fs::canonicalize(&state_filename).await.ok();
+        let mut state = load_state(state_filename.clone()).await?;
+        let mut last_root = None;
+        for path in changed {
+            let Some(name) = watched_name(&dir, &path) else {
+                continue;
+            };
+            if state_path.as_ref() == Some(&path) {
+                continue;
+            }
+            // removed since or a directory
+            let Ok(content) = tokio::fs::read(&path).await else {
+                continue;
+            };
+            let hash = client.leaf_hasher().leaf(&name, &content);
+            if uploaded.get(&path) == Some(&hash) {
+                continue;
+            }
+            let stored = match client.upload_new_file(&name, &content).await {
+                Ok(stored) => stored,
+                // server may be just temporarily unavailable, file is uploaded on its next change
+                Err(err) => {
+                    eprintln!("Upload of {name} failed: {err}");
+                    continue;
+                }
+            };
+            state.append(hash.clone());
+            uploaded.insert(path, hash);
+            status!(
+                "{name} uploaded as {} with id: {}",
+                stored.file.name,
+                stored.file.id
+            );
+            last_root = stored.root.map(|root| root.hash);
+        }
+        let Some(remote_hash) = last_root else {
+            continue;
+        };
+        let local_hash = state
+            .light_tree
+            .root()
+            .expect("should be present after appending uploaded file");
+        println!("Root: {local_hash}");
+        if local_hash != remote_hash {
+            status!(
+                "Local root hash differs from remote hash {remote_hash} - verification won't work"
+            );
+        }
+        state.pin_root();
+        store_state(state_filename.clone(), state).await?;
     }
-    println!("File contents verified");
-    let path = save_as.unwrap_or(file.name);
-    tokio::fs::write(&path, &file.content).await?;
-    println!("File {id} saved as {path}");
     Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct LocalState {
-    light_tree: merkle::Sha3LightTree,
+/// Name changed file at `path` is uploaded under, none for hidden files and paths outside `dir`
+fn watched_name(dir: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(dir).ok()?;
+    let hidden = relative
+        .components()
+        .any(|component| component.as_os_str().to_string_lossy().starts_with('.'));
+    match hidden {
+        true => None,
+        false => Some(upload_name(&relative.to_string_lossy())).filter(|name| !name.is_empty()),
+    }
 }
 
-async fn load_state(filename: String) -> anyhow::Result<LocalState> {
-    let content = tokio::fs::read_to_string(filename).await?;
-    Ok(serde_json::from_str(&content)?)
+async fn wait_for_file(
+    client: Client,
+    state_filename: String,
+    id: FileId,
+    interval: Duration,
+    receipts_dir: PathBuf,
+) -> anyhow::Result<()> {
+    let mut state = load_state(state_filename.clone()).await?;
+    let status = client.wait_for_file(id, interval).await?;
+    let receipt = status.receipt;
+    let (Some(hash), Some(proof), Some(root)) = (status.hash, status.proof, status.root) else {
+        return Err(anyhow!(
+            "File {id} was rejected: {}",
+            status.reason.unwrap_or_default()
+        ));
+    };
+    if !proof.verify(&root.hash, &hash) {
+        return Err(verification_failed(
+            "Proof of validated file doesn't match reported root",
+        ));
+    }
+    status!("File {id} validated and committed, leaf hash: {hash}");
+    if let Some(receipt) = &receipt {
+        let path = store_receipt(&receipts_dir, &id.to_string(), id, &hash, receipt).await?;
+        status!("Upload receipt written to {}", path.display());
+    }
+    state.append(hash);
+    let local_hash = state
+        .light_tree
+        .root()
+        .expect("should be present after appending validated file");
+    let remote_hash = client.fetch_root().await?.hash;
+    status!("Local  hash: {local_hash}");
+    status!("Remote hash: {remote_hash}");
+    if local_hash != remote_hash {
+        status!("Local root hash differs from remote hash - verification won't work");
+    }
+    state.pin_root();
+    store_state(state_filename, state).await
 }
 
-async fn store_state(filename: String, state: LocalState) -> anyhow::Result<()> {
-    let serialized = serde_json::ser::to_vec_pretty(&state)?;
-    let mut file = tokio::fs::File::create(filename).await?;
-    file.write_all(&serialized).await?;
-    Ok(())
+async fn upload_files_in_parallel(
+    client: &Client,
+    state: &mut LocalState,
+    files: Vec<String>,
+    name_by_hash: bool,
+) -> anyhow::Result<Vec<UploadedFile>> {
+    let mut uploads = Vec::with_capacity(files.len());
+    for file in files {
+        let content = tokio::fs::read(&file).await?;
+        let reservation = client.reserve().await?;
+        let slot = state.light_tree.reserve();
+        if slot != reservation.leaf_index.as_usize() {
+            eprintln!(
+                "{file} reserved leaf {} on server, but leaf {slot} locally",
+                reservation.leaf_index
+            );
+        }
+        let name = match name_by_hash {
+            true => canonical_name(&content),
+            false => upload_name(&file),
+        };
+        uploads.push((file, name, content, reservation.id, slot));
+    }
+    let total = uploads.len();
+    let done = &Cell::new(0);
+    let uploaded = try_join_all(
+        uploads
+            .iter()
+            .map(|(file, name, content, id, _)| async move {
+                let stored = client.upload_reserved_file(*id, name, content).await?;
+                done.set(done.get() + 1);
+                progress(Progress::ChunkUploaded {
+                    file,
+                    id: stored.file.id,
+                    bytes: content.len() as u64,
+                    done: done.get(),
+                    total,
+                });
+                Ok::<_, anyhow::Error>(stored)
+            }),
+    )
+    .await?;
+    let mut files = Vec::with_capacity(total);
+    for ((file, name, content, _, slot), stored) in uploads.into_iter().zip(uploaded) {
+        let hash = client.leaf_hasher().leaf(&name, &content);
+        state.light_tree.fill(slot, hash.clone());
+        state.leaves.push(hash.clone());
+        // files filled before earlier slots were committed only once all slots are filled
+        let receipt = match stored.receipt {
+            Some(receipt) => Some(receipt),
+            None => client.fetch_file_status(stored.file.id).await?.receipt,
+        };
+        files.push(UploadedFile {
+            file,
+            stored_as: stored.file.name,
+            id: stored.file.id,
+            leaf_index: slot,
+            hash,
+            bytes: content.len() as u64,
+            receipt,
+        });
+    }
+    Ok(files)
+}
+
+/// Room for fields of batch request other than its files
+const BATCH_ENVELOPE_SIZE: usize = 1024;
+
+/// Upper bound of size a file takes in json batch request, content is base64 encoded
+fn batch_encoded_size(name: &str, content: &[u8]) -> usize {
+    content.len().div_ceil(3) * 4 + name.len() + 64
+}
+
+/// Files are sent in as few batches as fit into request body limit of the server, each stored
+/// all together or not at all. Leaves of a batch are appended to local state only once server
+/// stored it, so a refused batch leaves both sides as they were after the previous one. File too
+/// big for a batch of its own is streamed instead.
+async fn upload_files_in_batch(
+    client: &Client,
+    state: &mut LocalState,
+    files: Vec<String>,
+    upload: &UploadOptions,
+) -> anyhow::Result<(Vec<UploadedFile>, Option<merkle::Sha3Hash>)> {
+    let total = files.len();
+    let mut uploaded = Vec::with_capacity(total);
+    let mut root = None;
+    let mut batch = Vec::new();
+    let mut batch_size = BATCH_ENVELOPE_SIZE;
+    for file in files {
+        let content = tokio::fs::read(&file).await?;
+        let name = match upload.name_by_hash {
+            true => canonical_name(&content),
+            false => upload_name(&file),
+        };
+        let size = batch_encoded_size(&name, &content);
+        if !batch.is_empty()
+            && (batch_size + size > MAX_BODY_SIZE || batch.len() == MAX_BATCH_FILES)
+        {
+            let batch = std::mem::take(&mut batch);
+            root = upload_batch(client, state, batch, upload, &mut uploaded, total).await?;
+            batch_size = BATCH_ENVELOPE_SIZE;
+        }
+        batch_size += size;
+        batch.push((file, name, content));
+    }
+    if !batch.is_empty() {
+        root = upload_batch(client, state, batch, upload, &mut uploaded, total).await?;
+    }
+    Ok((uploaded, root))
+}
+
+/// Uploads one batch of [`upload_files_in_batch`], returning root right after it. Progress is
+/// reported for each of its files once the batch is stored, as `uploaded` of `total` so far.
+async fn upload_batch(
+    client: &Client,
+    state: &mut LocalState,
+    mut batch: Vec<(String, String, Vec<u8>)>,
+    upload: &UploadOptions,
+    uploaded: &mut Vec<UploadedFile>,
+    total: usize,
+) -> anyhow::Result<Option<merkle::Sha3Hash>> {
+    let too_big = |(_, name, content): &(String, String, Vec<u8>)| {
+        BATCH_ENVELOPE_SIZE + batch_encoded_size(name, content) > MAX_BODY_SIZE
+    };
+    if let [file] = batch.as_slice() {
+        if too_big(file) {
+            let (file, name, content) = batch.pop().expect("batch has one file");
+            let hash = client.leaf_hasher().leaf(&name, &content);
+            let leaf_index = state.light_tree.len();
+            let expected_tree_size = upload.exclusive.then_some(leaf_index.into());
+            let stored = client
+                .upload_stream(&name, Path::new(&file), expected_tree_size)
+                .await?;
+            state.append(hash.clone());
+            uploaded.push(UploadedFile {
+                file,
+                stored_as: stored.file.name,
+                id: stored.file.id,
+                leaf_index,
+                hash,
+                bytes: content.len() as u64,
+                receipt: stored.receipt,
+            });
+            chunk_uploaded(&uploaded[uploaded.len() - 1], uploaded.len(), total);
+            return Ok(stored.root.map(|root| root.hash));
+        }
+    }
+    let (files, contents): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .map(|(file, name, content)| (file, (name, content)))
+        .unzip();
+    let hashes: Vec<_> = contents
+        .iter()
+        .map(|(name, content)| {
+            let hash = client.leaf_hasher().leaf(name, content);
+            (hash, content.len() as u64)
+        })
+        .collect();
+    let first_leaf = state.light_tree.len();
+    let expected_tree_size = upload.exclusive.then_some(first_leaf.into());
+    let stored = client.upload_batch(contents, expected_tree_size).await?;
+    for ((file, stored_file), (hash, bytes)) in files.into_iter().zip(stored.files).zip(hashes) {
+        let leaf_index = state.light_tree.len();
+        if stored_file.leaf_index != LeafIndex::from(leaf_index) {
+            status!(
+                "{file} landed at leaf {} on server, but leaf {leaf_index} locally",
+                stored_file.leaf_index
+            );
+        }
+        state.append(hash.clone());
+        uploaded.push(UploadedFile {
+            file,
+            stored_as: stored_file.file.name,
+            id: stored_file.file.id,
+            leaf_index,
+            hash,
+            bytes,
+            receipt: stored_file.receipt,
+        });
+        chunk_uploaded(&uploaded[uploaded.len() - 1], uploaded.len(), total);
+    }
+    Ok(stored.root.map(|root| root.hash))
+}
+
+/// Record of single upload run, suitable for archiving by CI pipelines
+#[derive(Debug, Serialize)]
+struct UploadManifest {
+    /// unix timestamps in seconds
+    started_at: u64,
+    finished_at: u64,
+    /// local root before and after upload
+    pre_root: Option<merkle::Sha3Hash>,
+    post_root: merkle::Sha3Hash,
+    remote_root: merkle::Sha3Hash,
+    files: Vec<UploadedFile>,
+}
+
+#[derive(Debug, Serialize)]
+struct UploadedFile {
+    file: String,
+    /// name assigned by the server, may differ due to name collision policy
+    stored_as: String,
+    id: FileId,
+    leaf_index: usize,
+    hash: merkle::Sha3Hash,
+    #[serde(skip)]
+    bytes: u64,
+    /// written to receipts directory
+    #[serde(skip)]
+    receipt: Option<UploadReceipt>,
+}
+
+/// Leaf of file content read in chunks, for files too big to be read whole
+async fn hash_file(
+    hasher: &dyn LeafHasher,
+    name: &str,
+    path: &Path,
+) -> anyhow::Result<merkle::Sha3Hash> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut digest = hasher.start(name);
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut chunk).await? {
+            0 => return Ok(digest.finalize()),
+            read => digest.update(&chunk[..read]),
+        };
+    }
+}
+
+/// Relative path sent to the server as file name, server rejects `.`, `..` and absolute paths
+fn upload_name(file: &str) -> String {
+    Path::new(file)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+async fn download_file(
+    client: Client,
+    state_filename: String,
+    server_keys: &[TrustedKey],
+    id: FileId,
+    save_as: Option<String>,
+    expected: Option<&merkle::Sha3Hash>,
+    save: SaveOptions,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    progress(Progress::Started {
+        command: "download",
+        files: 1,
+    });
+    let roots = epoch_roots(&client, &load_state(state_filename).await?, server_keys).await?;
+    let root_of = |epoch| roots.of(epoch).cloned();
+    let (file, summary) = match expected {
+        Some(expected) => {
+            let file = client.download_expecting(id, expected).await?;
+            let summary = client.verify_file(&file, started, root_of)?;
+            (file, summary)
+        }
+        None => client.download_file(id, root_of).await?,
+    };
+    report_verification(&summary);
+    let name = match save_as {
+        Some(save_as) => save_as,
+        None => local_file_name(&file.name)?,
+    };
+    let path = save_file(&save, &name, &file.content).await?;
+    status!("File {id} saved as {}", path.display());
+    progress(Progress::Done {
+        command: "download",
+        elapsed_ms: started.elapsed().as_millis(),
+    });
+    Ok(())
+}
+
+/// Checks that file verifies against the latest pinned root, and that it decrypts if key is
+/// given, before printing the link to it
+async fn share_link(
+    client: Client,
+    state_filename: String,
+    id: FileId,
+    key_env: Option<String>,
+) -> anyhow::Result<()> {
+    let state = load_state(state_filename).await?;
+    let pinned = state
+        .roots
+        .last()
+        .ok_or_else(|| StateError("No root is pinned in local state".to_string()))?;
+    let file = client.fetch_file(id).await?;
+    let leaf = client.leaf_hasher().leaf(&file.name, &file.content);
+    client
+        .verify_historical_proof(
+            id,
+            pinned.size.into(),
+            |leaf_index| {
+                state
+                    .leaves
+                    .get(leaf_index.as_usize())
+                    .filter(|known| **known == leaf)
+                    .cloned()
+            },
+            &pinned.root,
+        )
+        .await?;
+    let key = match key_env {
+        Some(key_env) => {
+            let key = std::env::var(&key_env)
+                .map_err(|_| anyhow!("{key_env} is not set"))?
+                .parse::<MasterKey>()?
+                .file_key(&file.content, file.name.as_bytes());
+            key.decrypt(&file.content, file.name.as_bytes())
+                .map_err(|_| anyhow!("File {id} is not encrypted with key in {key_env}"))?;
+            Some(key)
+        }
+        None => None,
+    };
+    let link = ShareLink {
+        server_url: client.server_url().to_string(),
+        id,
+        tree_size: pinned.size.into(),
+        root: pinned.root.clone(),
+        key,
+    };
+    println!("{link}");
+    Ok(())
+}
+
+/// Downloads file of a share link, verified against the root in the link before it is decrypted
+async fn open_link(
+    client: Client,
+    link: ShareLink,
+    save_as: Option<String>,
+    save: SaveOptions,
+) -> anyhow::Result<()> {
+    let file = client.fetch_file(link.id).await?;
+    let leaf = client.leaf_hasher().leaf(&file.name, &file.content);
+    let (proof, _) = client
+        .verify_historical_proof(link.id, link.tree_size, |_| Some(leaf.clone()), &link.root)
+        .await?;
+    status!(
+        "File {} verified as leaf {} against {}",
+        link.id,
+        proof.leaf_index,
+        link.root
+    );
+    let content = match &link.key {
+        Some(key) => key
+            .decrypt(&file.content, file.name.as_bytes())
+            .map_err(|_| verification_failed(format!("File {} doesn't decrypt", link.id)))?,
+        None => file.content,
+    };
+    let name = match save_as {
+        Some(save_as) => save_as,
+        None => local_file_name(&file.name)?,
+    };
+    let path = save_file(&save, &name, &content).await?;
+    status!("File {} saved as {}", link.id, path.display());
+    Ok(())
+}
+
+/// Prints hashes of verified chunks, when leaves are chunked, and transfer throughput, and
+/// reports the file verified to progress events
+fn report_verification(summary: &VerificationSummary) {
+    for (index, chunk) in summary.chunks.iter().enumerate() {
+        status!(
+            "Chunk {index} (bytes {}..{}) {} verified",
+            chunk.offset,
+            chunk.offset + chunk.len,
+            chunk.hash
+        );
+    }
+    status!(
+        "File contents verified: {} bytes in {:.2?} ({:.1} KiB/s)",
+        summary.bytes,
+        summary.elapsed,
+        summary.throughput() / 1024.0
+    );
+    progress(Progress::Verified {
+        id: Some(summary.id),
+        root: &summary.root,
+    });
+}
+
+/// Same as [`download_file`] for files too big to be kept in memory: content is written to a
+/// partial file next to the target and moved in place once verified
+async fn stream_file(
+    client: Client,
+    state_filename: String,
+    server_keys: &[TrustedKey],
+    id: FileId,
+    save_as: Option<String>,
+    expected: Option<&merkle::Sha3Hash>,
+    save: SaveOptions,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    progress(Progress::Started {
+        command: "download",
+        files: 1,
+    });
+    let roots = epoch_roots(&client, &load_state(state_filename).await?, server_keys).await?;
+    tokio::fs::create_dir_all(&save.dir).await?;
+    let partial = save.dir.join(format!(".{id}.part"));
+    let (file, summary) = client
+        .download_expecting_to(id, &partial, expected, |epoch| roots.of(epoch).cloned())
+        .await?;
+    report_verification(&summary);
+    let name = match save_as {
+        Some(save_as) => Ok(save_as),
+        None => local_file_name(&file.name),
+    };
+    let path = name.map(|name| save.dir.join(name));
+    let path = match path {
+        Ok(path) if save.force || !tokio::fs::try_exists(&path).await? => path,
+        Ok(path) => {
+            tokio::fs::remove_file(&partial).await?;
+            return Err(anyhow!(
+                "{} already exists, use --force to overwrite",
+                path.display()
+            ));
+        }
+        Err(err) => {
+            tokio::fs::remove_file(&partial).await?;
+            return Err(err);
+        }
+    };
+    tokio::fs::rename(&partial, &path).await?;
+    status!("File {id} saved as {}", path.display());
+    progress(Progress::Done {
+        command: "download",
+        elapsed_ms: started.elapsed().as_millis(),
+    });
+    Ok(())
+}
+
+async fn download_all_files(
+    client: Client,
+    state_filename: String,
+    server_keys: &[TrustedKey],
+    save: SaveOptions,
+) -> anyhow::Result<()> {
+    let roots = epoch_roots(&client, &load_state(state_filename).await?, server_keys).await?;
+    for file in client.download_all().await? {
+        let leaf = client.leaf_hasher().leaf(&file.name, &file.content);
+        if !file.proof.verify(roots.of(file.epoch)?, &leaf) {
+            return Err(verification_failed(format!(
+                "Verification of file {} failed!",
+                file.id
+            )));
+        }
+        let path = save_file(&save, &local_file_name(&file.name)?, &file.content).await?;
+        status!("File {} verified and saved as {}", file.id, path.display());
+    }
+    Ok(())
+}
+
+async fn download_batch(
+    client: Client,
+    state_filename: String,
+    server_keys: &[TrustedKey],
+    ids: Vec<FileId>,
+    save: SaveOptions,
+) -> anyhow::Result<()> {
+    let roots = epoch_roots(&client, &load_state(state_filename).await?, server_keys).await?;
+    let mut files = Vec::with_capacity(ids.len());
+    // ids are split into batches of MAX_BATCH_FILES, ones over MAX_BATCH_BYTES of content are
+    // halved until they fit and a file too big for a batch of its own is downloaded alone
+    let mut pending: Vec<_> = ids
+        .chunks(MAX_BATCH_FILES)
+        .rev()
+        .map(<[_]>::to_vec)
+        .collect();
+    while let Some(batch_ids) = pending.pop() {
+        let batch = match client.download_batch(&batch_ids).await {
+            Ok(batch) => batch,
+            Err(err) if batch_too_large(&err) => {
+                if let [id] = batch_ids.as_slice() {
+                    let file = client.fetch_file(*id).await?;
+                    let leaf = client.leaf_hasher().leaf(&file.name, &file.content);
+                    if !file.proof.verify(roots.of(file.epoch)?, &leaf) {
+                        return Err(verification_failed(format!(
+                            "Verification of file {id} failed!"
+                        )));
+                    }
+                    files.push((file.id, file.name, file.content));
+                } else {
+                    let (first, second) = batch_ids.split_at(batch_ids.len() / 2);
+                    pending.push(second.to_vec());
+                    pending.push(first.to_vec());
+                }
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if !batch.verify(roots.of(batch.epoch)?, client.leaf_hasher().as_ref()) {
+            return Err(verification_failed("Verification of batch failed!"));
+        }
+        files.extend(
+            batch
+                .files
+                .into_iter()
+                .map(|file| (file.id, file.name, file.content)),
+        );
+    }
+    if let Some(missing) = ids
+        .iter()
+        .find(|id| !files.iter().any(|(file, ..)| file == *id))
+    {
+        return Err(verification_failed(format!(
+            "File {missing} is missing in batch"
+        )));
+    }
+    status!("Contents of {} files verified", files.len());
+    for (id, name, content) in files {
+        let path = save_file(&save, &local_file_name(&name)?, &content).await?;
+        status!("File {id} saved as {}", path.display());
+    }
+    Ok(())
+}
+
+/// Server refused batch for its content being over
+/// [`MAX_BATCH_BYTES`](safe_storage::api::MAX_BATCH_BYTES)
+fn batch_too_large(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<HttpError>()
+        .is_some_and(|err| err.status == 413)
+}
+
+/// Roots files are verified against: checkpoint roots of sealed epochs and trusted local root for
+/// the current epoch. Local state tracks leaves of all epochs in one list, each epoch being the
+/// range of it starting at `first_leaf` of its checkpoint.
+struct EpochRoots {
+    /// checkpoints of sealed epochs, `None` for ones neither signed with a trusted key nor
+    /// matching leaves of local state
+    sealed: Vec<Option<Checkpoint>>,
+    current: Option<merkle::Sha3Hash>,
+    /// hex encoded key server reports to sign checkpoints with
+    public_key: Option<String>,
+}
+
+impl EpochRoots {
+    fn of(&self, epoch: u32) -> anyhow::Result<&merkle::Sha3Hash> {
+        let root = match self.sealed.get(epoch as usize) {
+            Some(checkpoint) => checkpoint.as_ref().map(|checkpoint| &checkpoint.root),
+            None if epoch as usize == self.sealed.len() => self.current.as_ref(),
+            None => None,
+        };
+        root.ok_or_else(|| anyhow!("No trusted root to verify files of epoch {epoch}"))
+    }
+
+    /// Checkpoint of sealed epoch, if it is trusted
+    fn checkpoint(&self, epoch: u32) -> Option<&Checkpoint> {
+        self.sealed.get(epoch as usize).and_then(Option::as_ref)
+    }
+}
+
+/// Checkpoint is accepted by any of trusted keys, or no keys are given. Audit only uses it on
+/// top of consistency with tree heads it has already seen.
+fn trusted(server_keys: &[TrustedKey], checkpoint: &Checkpoint) -> bool {
+    server_keys.is_empty() || server_keys.iter().any(|key| key.accepts(checkpoint))
+}
+
+/// Checks checkpoints of sealed epochs against local state, so they can't replace roots it
+/// pinned. Checkpoint disagreeing with local leaves or not signed with a given server key is
+/// refused, one local state doesn't know leaves of is only trusted if signed with a given key.
+fn check_checkpoints(
+    state: &LocalState,
+    server_keys: &[TrustedKey],
+    checkpoints: Vec<Checkpoint>,
+) -> anyhow::Result<Vec<Option<Checkpoint>>> {
+    let mut sealed = Vec::with_capacity(checkpoints.len());
+    for checkpoint in checkpoints {
+        if checkpoint.epoch as usize != sealed.len() {
+            return Err(verification_failed(
+                "Checkpoints from the server are out of order",
+            ));
+        }
+        let signed = server_keys.iter().any(|key| key.accepts(&checkpoint));
+        if !server_keys.is_empty() && !signed {
+            return Err(verification_failed(format!(
+                "Checkpoint of epoch {} is not signed with server key",
+                checkpoint.epoch
+            )));
+        }
+        let first = checkpoint.first_leaf as usize;
+        match state.epoch_root(first, first + checkpoint.size as usize) {
+            Some(root) if root != checkpoint.root => {
+                return Err(verification_failed(format!(
+                    "Checkpoint of epoch {} doesn't match leaves of local state",
+                    checkpoint.epoch
+                )))
+            }
+            Some(_) => sealed.push(Some(checkpoint)),
+            None => sealed.push(signed.then_some(checkpoint)),
+        }
+    }
+    Ok(sealed)
+}
+
+/// Fetches checkpoints of sealed epochs, refusing ones not signed with server key if it is given
+/// or not matching local state. Root of the current epoch comes from local state only.
+async fn epoch_roots(
+    client: &Client,
+    state: &LocalState,
+    server_keys: &[TrustedKey],
+) -> anyhow::Result<EpochRoots> {
+    let epochs = client.fetch_epochs().await?;
+    let first_leaf = epochs
+        .checkpoints
+        .last()
+        .map(|checkpoint| (checkpoint.first_leaf + checkpoint.size) as usize);
+    let sealed = check_checkpoints(state, server_keys, epochs.checkpoints)?;
+    let current = match first_leaf {
+        None if !state.light_tree.is_empty() => Some(trusted_root(client, state).await?),
+        None => None,
+        Some(first) => state.epoch_root(first, state.leaves.len()),
+    };
+    Ok(EpochRoots {
+        sealed,
+        current,
+        public_key: epochs.public_key,
+    })
+}
+
+/// Evidence that content with given hash is a leaf of server tree, portable to parties who don't
+/// have local state of the uploader
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Attestation {
+    /// url of the server file is stored on
+    server: String,
+    /// hex encoded key server signs checkpoints with, if it has one
+    #[serde(default)]
+    public_key: Option<String>,
+    id: FileId,
+    name: String,
+    /// leaf of the file, derived from its name and content with `leaf_hashing`
+    content_hash: merkle::Sha3Hash,
+    /// leaf hashing of the server, bundles made before it was reported derive leaves from content
+    #[serde(default)]
+    leaf_hashing: LeafHashing,
+    epoch: u32,
+    proof: merkle::Sha3Proof,
+    root: merkle::Sha3Hash,
+    /// signed checkpoint of the epoch, missing while it is not sealed
+    #[serde(default)]
+    checkpoint: Option<Checkpoint>,
+    /// unix time in seconds
+    attested_at: u64,
+}
+
+impl Attestation {
+    /// Why the attestation doesn't hold. Checkpoint signature is checked only against trusted
+    /// keys, key carried by attestation itself proves nothing, so without them the bundle stays
+    /// unverified.
+    fn problems(&self, server_keys: &[TrustedKey]) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.proof.verify(&self.root, &self.content_hash) {
+            problems.push("proof doesn't lead from content hash to root".to_string());
+        }
+        match &self.checkpoint {
+            Some(checkpoint) => {
+                if checkpoint.epoch != self.epoch || checkpoint.root != self.root {
+                    problems.push(format!("checkpoint is not of root of epoch {}", self.epoch));
+                }
+                if !server_keys.is_empty() && !trusted(server_keys, checkpoint) {
+                    problems.push("checkpoint is not signed with server key".to_string());
+                }
+            }
+            None if !server_keys.is_empty() => problems.push(format!(
+                "epoch {} is not sealed, root is not signed",
+                self.epoch
+            )),
+            None => {}
+        }
+        problems
+    }
+}
+
+async fn attest_file(
+    client: Client,
+    state_filename: String,
+    server_keys: &[TrustedKey],
+    id: FileId,
+    output: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let roots = epoch_roots(&client, &load_state(state_filename).await?, server_keys).await?;
+    let (file, summary) = client
+        .download_file(id, |epoch| roots.of(epoch).cloned())
+        .await?;
+    let (content_hash, root) = (summary.leaf, summary.root);
+    let attestation = Attestation {
+        server: client.server_url().to_string(),
+        public_key: roots.public_key.clone(),
+        id,
+        name: file.name,
+        content_hash,
+        leaf_hashing: client.leaf_hasher().strategy(),
+        epoch: file.epoch,
+        proof: file.proof,
+        root,
+        checkpoint: roots.checkpoint(file.epoch).cloned(),
+        attested_at: unix_time(),
+    };
+    let json = serde_json::to_string_pretty(&attestation)?;
+    match output {
+        Some(path) => {
+            tokio::fs::write(&path, json).await?;
+            status!("Attestation of file {id} written to {}", path.display());
+        }
+        None => println!("{json}"),
+    }
+    if attestation.checkpoint.is_none() {
+        status!(
+            "Epoch {} is not sealed yet, root is attested by local state only",
+            attestation.epoch
+        );
+    }
+    Ok(())
+}
+
+async fn verify_attestation(
+    server_keys: &[TrustedKey],
+    bundle: PathBuf,
+    file: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let attestation: Attestation = serde_json::from_slice(&tokio::fs::read(&bundle).await?)
+        .map_err(|err| anyhow!("{} is not an attestation: {err}", bundle.display()))?;
+    let mut problems = attestation.problems(server_keys);
+    if let Some(file) = file {
+        let leaf = attestation
+            .leaf_hashing
+            .hasher()
+            .leaf(&attestation.name, &tokio::fs::read(&file).await?);
+        if leaf != attestation.content_hash {
+            problems.push(format!(
+                "content of {} is not the attested one",
+                file.display()
+            ));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(verification_failed(format!(
+            "Attestation doesn't hold: {}",
+            problems.join(", ")
+        )));
+    }
+    println!(
+        "File {} ({}) of {} attested at {} is included in root {} of epoch {}",
+        attestation.id,
+        attestation.name,
+        attestation.server,
+        attestation.attested_at,
+        attestation.root,
+        attestation.epoch
+    );
+    match (&attestation.checkpoint, &attestation.public_key) {
+        (Some(_), _) if !server_keys.is_empty() => {
+            println!("Checkpoint signed with trusted server key")
+        }
+        (Some(_), Some(key)) => println!(
+            "UNVERIFIED: checkpoint signature is not checked, pass --server-key to check it against a trusted key (attestation claims key {})",
+            key_id(key)
+        ),
+        (Some(_), None) => println!("Checkpoint is not signed, server has no signing key"),
+        (None, _) => {
+            println!("Epoch was not sealed, root is vouched for by whoever made attestation")
+        }
+    }
+    Ok(())
+}
+
+/// Tree head seen by audit, appended to audit log
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditRecord {
+    checked_at: u64,
+    epoch: u32,
+    size: u64,
+    root: merkle::Sha3Hash,
+    /// why the head is not consistent with the last audited one, missing if it is
+    #[serde(default)]
+    error: Option<String>,
+}
+
+async fn audit_run(
+    client: Client,
+    server_keys: &[TrustedKey],
+    log: PathBuf,
+    interval: Option<Duration>,
+) -> anyhow::Result<()> {
+    loop {
+        let last = match tokio::fs::read_to_string(&log).await {
+            Ok(content) => last_consistent(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        match audit_once(&client, server_keys, last.as_ref()).await {
+            Ok(record) => {
+                let mut line = serde_json::to_vec(&record)?;
+                line.push(b'\n');
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&log)
+                    .await?;
+                file.write_all(&line).await?;
+                if let Some(error) = record.error {
+                    return Err(verification_failed(format!(
+                        "Inconsistent tree head: {error}"
+                    )));
+                }
+                status!(
+                    "Tree head of epoch {} with {} leaves is consistent: {}",
+                    record.epoch,
+                    record.size,
+                    record.root
+                );
+            }
+            // server may be just temporarily unavailable, only inconsistency stops the auditor
+            Err(err) if interval.is_some() => eprintln!("Audit failed: {err}"),
+            Err(err) => return Err(err),
+        }
+        match interval {
+            Some(interval) => tokio::time::sleep(interval).await,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// The last consistent record of audit log
+fn last_consistent(log: &str) -> anyhow::Result<Option<AuditRecord>> {
+    let mut last = None;
+    for line in log.lines().filter(|line| !line.trim().is_empty()) {
+        let record: AuditRecord = serde_json::from_str(line)
+            .map_err(|err| StateError(format!("Audit log is corrupted: {err}")))?;
+        if record.error.is_none() {
+            last = Some(record);
+        }
+    }
+    Ok(last)
+}
+
+async fn audit_proofs(
+    client: Client,
+    state_filename: String,
+    ids: Vec<FileId>,
+    tree_size: Option<usize>,
+) -> anyhow::Result<()> {
+    let state = load_state(state_filename).await?;
+    let pinned = match tree_size {
+        Some(size) => state.roots.iter().find(|pinned| pinned.size == size),
+        None => state.roots.last(),
+    }
+    .ok_or_else(|| anyhow!("No root of given size is pinned in local state"))?;
+    let mut failed = 0;
+    for id in ids {
+        let verified = client
+            .verify_historical_proof(
+                id,
+                pinned.size.into(),
+                |leaf_index| state.leaves.get(leaf_index.as_usize()).cloned(),
+                &pinned.root,
+            )
+            .await;
+        match verified {
+            Ok((proof, cached)) => {
+                let source = if cached { "cached" } else { "fetched" };
+                println!(
+                    "{id}: leaf {} verified against {} ({source} proof)",
+                    proof.leaf_index, pinned.root
+                );
+            }
+            Err(err) if err.is::<VerificationError>() => {
+                println!("{id}: {err}");
+                failed += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    if failed > 0 {
+        return Err(verification_failed(format!(
+            "{failed} files don't verify against root of {} leaves",
+            pinned.size
+        )));
+    }
+    Ok(())
+}
+
+/// Latest tree head, with error set if it is not consistent with the last audited one
+async fn audit_once(
+    client: &Client,
+    server_keys: &[TrustedKey],
+    last: Option<&AuditRecord>,
+) -> anyhow::Result<AuditRecord> {
+    let head = client.fetch_root().await?;
+    let error = match last {
+        Some(last) => {
+            inconsistency(
+                client,
+                server_keys,
+                last,
+                head.epoch,
+                head.size.0,
+                &head.hash,
+            )
+            .await?
+        }
+        None => None,
+    };
+    Ok(AuditRecord {
+        checked_at: unix_time(),
+        epoch: head.epoch,
+        size: head.size.0,
+        root: head.hash,
+        error,
+    })
+}
+
+/// Why tree head is not an extension of the last audited one. Once the audited epoch is sealed,
+/// its checkpoint is checked instead of the current tree.
+async fn inconsistency(
+    client: &Client,
+    server_keys: &[TrustedKey],
+    last: &AuditRecord,
+    epoch: u32,
+    size: u64,
+    root: &merkle::Sha3Hash,
+) -> anyhow::Result<Option<String>> {
+    if (epoch, size) < (last.epoch, last.size) {
+        return Ok(Some(format!(
+            "tree shrank from {} leaves of epoch {} to {size} leaves of epoch {epoch}",
+            last.size, last.epoch
+        )));
+    }
+    let (size, root) = if epoch == last.epoch {
+        (size, root.clone())
+    } else {
+        let checkpoints = client.fetch_epochs().await?.checkpoints;
+        let Some(checkpoint) = checkpoints.into_iter().nth(last.epoch as usize) else {
+            return Ok(Some(format!("epoch {} is not sealed", last.epoch)));
+        };
+        if !trusted(server_keys, &checkpoint) {
+            return Ok(Some(format!(
+                "checkpoint of epoch {} is not signed with server key",
+                last.epoch
+            )));
+        }
+        (checkpoint.size, checkpoint.root)
+    };
+    if size == last.size {
+        return Ok((root != last.root)
+            .then(|| format!("root of {size} leaves changed from {} to {root}", last.root)));
+    }
+    let consistency = client
+        .fetch_epoch_consistency(Some(last.epoch), TreeSize(last.size), TreeSize(size))
+        .await?;
+    let proof = consistency.proof;
+    if consistency.root != root
+        || proof.old_size != last.size as usize
+        || proof.new_size != size as usize
+        || !proof.verify(&last.root, &root)
+    {
+        return Ok(Some(format!(
+            "tree of {size} leaves doesn't extend audited tree of {} leaves",
+            last.size
+        )));
+    }
+    Ok(None)
+}
+
+/// Latest local root, after checking with consistency proof from the server that the tree it
+/// belongs to extends the tree of the oldest pinned root, i.e. no leaves were rewritten since then
+async fn trusted_root(client: &Client, state: &LocalState) -> anyhow::Result<merkle::Sha3Hash> {
+    let latest = state
+        .light_tree
+        .root()
+        .ok_or_else(|| anyhow!("Local state has no root yet"))?;
+    let size = state.light_tree.len();
+    if let Some(oldest) = state.roots.first().filter(|oldest| oldest.size < size) {
+        let consistency = client
+            .fetch_consistency(oldest.size.into(), size.into())
+            .await?;
+        let proof = consistency.proof;
+        if proof.old_size != oldest.size
+            || proof.new_size != size
+            || !proof.verify(&oldest.root, &latest)
+        {
+            return Err(verification_failed(format!(
+                "Server tree is not consistent with root pinned at {} leaves: {}",
+                oldest.size, oldest.root
+            )));
+        }
+    }
+    Ok(latest)
+}
+
+/// File name suggested by the server can't be trusted, so only its last component is used, in
+/// NFC form, and anything trying to escape the download directory or not valid as a name the
+/// server would accept is refused
+fn local_file_name(name: &str) -> anyhow::Result<String> {
+    let traversal =
+        name.starts_with('/') || name.split(['/', '\\']).any(|component| component == "..");
+    let file_name = normalize_name(name.rsplit(['/', '\\']).next().unwrap_or_default());
+    if traversal || validate_name(&file_name).is_err() {
+        return Err(anyhow!("Refusing to save file with unsafe name {name:?}"));
+    }
+    Ok(file_name)
+}
+
+async fn save_file(save: &SaveOptions, name: &str, content: &[u8]) -> anyhow::Result<PathBuf> {
+    tokio::fs::create_dir_all(&save.dir).await?;
+    let path = save.dir.join(name);
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true);
+    if save.force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = match options.open(&path).await {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(anyhow!(
+                "{} already exists, use --force to overwrite",
+                path.display()
+            ))
+        }
+        file => file?,
+    };
+    file.write_all(content).await?;
+    Ok(path)
+}
+
+/// Historical tree state given by its root or size
+#[derive(Debug, Clone, PartialEq)]
+enum TreeState {
+    Root(merkle::Sha3Hash),
+    Size(usize),
+}
+
+impl FromStr for TreeState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(size) = s.parse() {
+            return Ok(TreeState::Size(size));
+        }
+        match s.len() {
+            64 => Ok(TreeState::Root(s.parse()?)),
+            _ => Err(anyhow!("{s} is neither tree size nor hex encoded root")),
+        }
+    }
+}
+
+/// Epoch, size and trusted root of historical tree state. Size is trusted through leaves of
+/// local state, root has to be pinned in it or be a checkpoint root.
+async fn resolve_tree_state(
+    client: &Client,
+    state: &LocalState,
+    server_keys: &[TrustedKey],
+    tree_state: TreeState,
+) -> anyhow::Result<(u32, usize, merkle::Sha3Hash)> {
+    match tree_state {
+        TreeState::Size(size) => {
+            let root = state
+                .leaves
+                .get(..size)
+                .and_then(|leaves| merkle::Sha3Tree::from_manifest(leaves.to_vec()).root())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Local state knows {} leaves, root of {size} leaves can't be trusted",
+                        state.leaves.len()
+                    )
+                })?;
+            Ok((0, size, root))
+        }
+        TreeState::Root(root) => {
+            if let Some(pinned) = state.roots.iter().find(|pinned| pinned.root == root) {
+                return Ok((0, pinned.size, root));
+            }
+            let roots = epoch_roots(client, state, server_keys).await?;
+            roots
+                .sealed
+                .iter()
+                .flatten()
+                .find(|checkpoint| checkpoint.root == root)
+                .map(|checkpoint| (checkpoint.epoch, checkpoint.size as usize, root.clone()))
+                .ok_or_else(|| {
+                    anyhow!("Root {root} is neither pinned in local state nor a checkpoint root")
+                })
+        }
+    }
+}
+
+async fn restore_files(
+    client: Client,
+    state_filename: String,
+    server_keys: &[TrustedKey],
+    tree_state: TreeState,
+    save: SaveOptions,
+) -> anyhow::Result<()> {
+    let state = load_state(state_filename).await?;
+    let (epoch, tree_size, root) =
+        resolve_tree_state(&client, &state, server_keys, tree_state).await?;
+    status!("Restoring files of epoch {epoch} tree of {tree_size} leaves with root {root}");
+    // latest version of each name present back then
+    let mut present: BTreeMap<String, HistoricalProof> = BTreeMap::new();
+    for file in client.get_file_list().await?.files {
+        let proof = match client
+            .fetch_historical_proof(file.id, tree_size.into())
+            .await
+        {
+            Ok(proof) => proof,
+            Err(err)
+                if err
+                    .downcast_ref::<HttpError>()
+                    .is_some_and(|err| err.status == 404) =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        };
+        if proof.epoch != epoch {
+            continue;
+        }
+        if proof.root != root {
+            return Err(verification_failed(format!(
+                "Server root of {tree_size} leaves {} differs from trusted root {root}",
+                proof.root
+            )));
+        }
+        if present
+            .get(&file.name)
+            .is_none_or(|latest| latest.leaf_index < proof.leaf_index)
+        {
+            present.insert(file.name, proof);
+        }
+    }
+    for (name, proof) in &present {
+        let file = client.fetch_file(proof.id).await?;
+        let leaf = client.leaf_hasher().leaf(&file.name, &file.content);
+        if file.name != *name || !proof.proof.verify(&root, &leaf) {
+            return Err(verification_failed(format!(
+                "Verification of file {} failed!",
+                proof.id
+            )));
+        }
+        let relative = restore_path(name)?;
+        if let Some(parent) = save.dir.join(&relative).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let path = save_file(&save, &relative.to_string_lossy(), &file.content).await?;
+        status!(
+            "File {} verified and restored as {}",
+            proof.id,
+            path.display()
+        );
+    }
+    println!("Restored {} files of tree with root {root}", present.len());
+    Ok(())
+}
+
+/// Relative path restored file is written to, keeping directories of its name but refusing
+/// anything escaping the restore directory
+fn restore_path(name: &str) -> anyhow::Result<PathBuf> {
+    let components: Vec<_> = name.split(['/', '\\']).map(normalize_name).collect();
+    if components
+        .iter()
+        .any(|component| validate_name(component).is_err())
+    {
+        return Err(anyhow!(
+            "Refusing to restore file with unsafe name {name:?}"
+        ));
+    }
+    Ok(components.iter().collect())
+}
+
+async fn delete_file(client: Client, state_filename: String, id: FileId) -> anyhow::Result<()> {
+    let mut state = load_state(state_filename.clone()).await?;
+    let receipt = client.delete_file(id).await?;
+    if !receipt.verify() {
+        return Err(verification_failed("Deletion receipt verification failed!"));
+    }
+    if state.light_tree.root().as_ref() != Some(&receipt.root_before) {
+        status!("Local root differs from the root before deletion, local state is out of sync");
+    }
+    state.append(receipt.tombstone_hash.clone());
+    if state.light_tree.root().as_ref() != Some(&receipt.root_after) {
+        status!("Local root differs from the root after deletion, local state is out of sync");
+    }
+    status!("File {id} deleted");
+    status!("Root before: {}", receipt.root_before);
+    status!("Root after:  {}", receipt.root_after);
+    state.pin_root();
+    store_state(state_filename, state).await
+}
+
+/// Restored content is downloaded to derive its leaf locally, so local state keeps up with the
+/// tree without trusting a leaf reported by server
+async fn undelete_file(client: Client, state_filename: String, id: FileId) -> anyhow::Result<()> {
+    let mut state = load_state(state_filename.clone()).await?;
+    let stored = client.undelete_file(id).await?;
+    let file = client.fetch_file(stored.file.id).await?;
+    state.append(client.leaf_hasher().leaf(&file.name, &file.content));
+    status!(
+        "File {id} undeleted as {} with id: {}",
+        stored.file.name,
+        stored.file.id
+    );
+    let local_hash = state
+        .light_tree
+        .root()
+        .expect("should be present after appending undeleted file");
+    let remote_hash = match stored.root {
+        Some(root) => root.hash,
+        None => client.fetch_root().await?.hash,
+    };
+    status!("Local  hash: {local_hash}");
+    status!("Remote hash: {remote_hash}");
+    if local_hash != remote_hash {
+        status!("Local root hash differs from remote hash - verification won't work");
+    }
+    state.pin_root();
+    store_state(state_filename, state).await
+}
+
+/// Exported leaf hashes are checked against local state, so each CID is tied to a leaf whose
+/// proofs the client can already verify
+async fn export_ipfs(
+    client: Client,
+    state_filename: String,
+    ids: Vec<FileId>,
+    all: bool,
+) -> anyhow::Result<()> {
+    let state = load_state(state_filename).await?;
+    let ids = match all {
+        true => client
+            .list_files(false)
+            .await?
+            .files
+            .into_iter()
+            .map(|file| file.id)
+            .collect(),
+        false => ids,
+    };
+    for id in ids {
+        let export = client.export_to_ipfs(id).await?;
+        if !state.leaves.contains(&export.hash) {
+            status!("Leaf of file {id} is not in local state, sync it to keep its proofs");
+        }
+        println!("{}: {} (leaf {})", export.id, export.cid, export.hash);
+    }
+    Ok(())
+}
+
+async fn lookup_hash(
+    client: Client,
+    state_filename: String,
+    server_keys: &[TrustedKey],
+    hash: merkle::Sha3Hash,
+) -> anyhow::Result<()> {
+    let roots = epoch_roots(&client, &load_state(state_filename).await?, server_keys).await?;
+    let found = client.fetch_proof_by_hash(&hash).await?;
+    if !found.proof.verify(roots.of(found.epoch)?, &hash) {
+        return Err(verification_failed("Verification failed!"));
+    }
+    println!(
+        "Content {hash} is leaf {} of epoch {}, proof verified",
+        found.leaf_index, found.epoch
+    );
+    Ok(())
+}
+
+/// Checks upload receipts offline: proof of the leaf against the root and signature of any
+/// trusted key covering the time the receipt was issued at
+async fn verify_receipts(
+    leaf_hasher: &dyn LeafHasher,
+    server_keys: &[TrustedKey],
+    paths: Vec<PathBuf>,
+    file: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    if server_keys.is_empty() {
+        return Err(anyhow!(
+            "Receipts are verified against --server-key, none given"
+        ));
+    }
+    let mut receipts = Vec::new();
+    for path in paths {
+        match path.is_dir() {
+            true => collect_files(&path, &mut receipts)?,
+            false => receipts.push(path),
+        }
+    }
+    receipts.sort();
+    let expected_leaf = match file {
+        Some(_) if receipts.len() != 1 => {
+            return Err(anyhow!(
+                "Content can be checked against a single receipt only"
+            ))
+        }
+        Some(file) => Some(leaf_hasher.leaf(
+            &upload_name(&file.to_string_lossy()),
+            &tokio::fs::read(&file).await?,
+        )),
+        None => None,
+    };
+    let mut failed = 0;
+    for path in &receipts {
+        let receipt: UploadReceipt = serde_json::from_slice(&tokio::fs::read(path).await?)
+            .map_err(|err| anyhow!("{} is not an upload receipt: {err}", path.display()))?;
+        let problem = if !server_keys.iter().any(|key| key.accepts_receipt(&receipt)) {
+            Some("is not signed by a trusted server key")
+        } else if expected_leaf
+            .as_ref()
+            .is_some_and(|leaf| *leaf != receipt.leaf_hash)
+        {
+            Some("is not of the given file content")
+        } else {
+            None
+        };
+        match problem {
+            Some(problem) => {
+                println!("{}: {problem}", path.display());
+                failed += 1;
+            }
+            None => println!(
+                "{}: file {} accepted as leaf {} {} of root {} ({} leaves of epoch {})",
+                path.display(),
+                receipt.id,
+                receipt.leaf_index,
+                receipt.leaf_hash,
+                receipt.root,
+                receipt.tree_size,
+                receipt.epoch
+            ),
+        }
+    }
+    if failed > 0 {
+        return Err(verification_failed(format!(
+            "{failed} of {} receipts don't verify",
+            receipts.len()
+        )));
+    }
+    Ok(())
+}
+
+async fn show_receipt(client: Client, id: FileId) -> anyhow::Result<()> {
+    let receipt = client.fetch_deletion_receipt(id).await?;
+    if !receipt.verify() {
+        return Err(verification_failed("Deletion receipt verification failed!"));
+    }
+    println!("Deletion receipt of file {id} verified");
+    println!("Leaf hash:      {}", receipt.leaf_hash);
+    println!("Root before:    {}", receipt.root_before);
+    println!("Tombstone hash: {}", receipt.tombstone_hash);
+    println!("Root after:     {}", receipt.root_after);
+    Ok(())
+}
+
+async fn show_usage(client: Client, all: bool) -> anyhow::Result<()> {
+    let usage = if all {
+        client.fetch_all_usage().await?.usage
+    } else {
+        vec![client.fetch_usage().await?]
+    };
+    for usage in usage {
+        println!(
+            "{}: {} bytes stored, {} files uploaded",
+            usage.name, usage.bytes, usage.files
+        );
+    }
+    Ok(())
+}
+
+async fn bench(client: Client, samples: usize, output: OutputFormat) -> anyhow::Result<()> {
+    let files = client.get_file_list().await?.files;
+    let ids: Vec<_> = sample_evenly(files.len(), samples)
+        .map(|index| files[index].id)
+        .collect();
+    let mut measured = Vec::with_capacity(ids.len());
+    for id in ids {
+        measured.push(client.measure(id).await?);
+    }
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&measured)?);
+        return Ok(());
+    }
+    let info = client.fetch_info().await?;
+    println!(
+        "leaf hashing: {}, tree mode: {}",
+        info.leaf_hashing, info.tree_mode
+    );
+    for m in &measured {
+        println!(
+            "file {}: {} content bytes, {} proof bytes ({} hashes), {} metadata bytes, round trips: root {}us, proof {}us, download {}us",
+            m.id, m.content_bytes, m.proof_bytes, m.proof_nodes, m.metadata_bytes, m.root_micros, m.proof_micros, m.download_micros
+        );
+    }
+    let Some(download) = median(measured.iter().map(|m| m.download_micros)) else {
+        println!("No files to measure");
+        return Ok(());
+    };
+    let sum = |bytes: fn(&Measurement) -> u64| measured.iter().map(bytes).sum::<u64>();
+    let total = sum(|m| m.content_bytes + m.proof_bytes + m.metadata_bytes).max(1) as f64;
+    println!(
+        "{} files: proof {:.1}% and metadata {:.1}% of downloaded bytes, median round trips: root {}us, proof {}us, download {}us",
+        measured.len(),
+        sum(|m| m.proof_bytes) as f64 * 100.0 / total,
+        sum(|m| m.metadata_bytes) as f64 * 100.0 / total,
+        median(measured.iter().map(|m| m.root_micros)).unwrap_or_default(),
+        median(measured.iter().map(|m| m.proof_micros)).unwrap_or_default(),
+        download
+    );
+    Ok(())
+}
+
+/// Indices of `samples` items spread evenly over `len` ones, all of them if there are fewer
+fn sample_evenly(len: usize, samples: usize) -> impl Iterator<Item = usize> {
+    let samples = samples.min(len);
+    (0..samples).map(move |i| i * len / samples)
+}
+
+fn median(values: impl Iterator<Item = u64>) -> Option<u64> {
+    let mut values: Vec<_> = values.collect();
+    values.sort_unstable();
+    values.get(values.len() / 2).copied()
+}
+
+async fn diff_state(
+    client: Client,
+    state_filename: String,
+    other_state: Option<String>,
+) -> anyhow::Result<()> {
+    let local = load_state(state_filename).await?.leaves;
+    let other = match other_state {
+        Some(filename) => load_state(filename).await?.leaves,
+        None => client.fetch_leaves_paged(MAX_PAGE_LIMIT).await?.leaves,
+    };
+    let differences = merkle::diff(&local, &other);
+    if differences.is_empty() {
+        println!("No differences, {} leaves match", local.len());
+        return Ok(());
+    }
+    for difference in differences {
+        match difference {
+            LeafDiff::Appended { index, hash } => println!("{index}: only in other: {hash}"),
+            LeafDiff::Missing { index, hash } => println!("{index}: only in local: {hash}"),
+            LeafDiff::Mismatched { index, old, new } => {
+                println!("{index}: local {old} differs from other {new}")
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn cross_check(state_filename: String, peer_state: String) -> anyhow::Result<()> {
+    let local = load_state(state_filename).await?;
+    let peer = load_state(peer_state).await?;
+    let divergences = local.divergences_from(&peer);
+    for divergence in &divergences {
+        println!("Divergence: {divergence}");
+    }
+    if !divergences.is_empty() {
+        return Err(verification_failed(format!(
+            "{} divergences from peer, server may be showing different trees to different clients",
+            divergences.len()
+        )));
+    }
+    let (local_size, peer_size) = (local.leaves.len(), peer.leaves.len());
+    status!(
+        "No divergence, first {} leaves and pinned roots agree (local has {local_size} leaves, peer {peer_size})",
+        local_size.min(peer_size)
+    );
+    Ok(())
+}
+
+async fn show_history(client: Client, state_filename: String, verify: bool) -> anyhow::Result<()> {
+    let state = load_state(state_filename).await?;
+    if state.roots.is_empty() {
+        println!("No roots pinned yet");
+        return Ok(());
+    }
+    let mut previous: Option<&PinnedRoot> = None;
+    let mut inconsistent = 0;
+    for pinned in &state.roots {
+        let pinned_at = match pinned.pinned_at {
+            0 => "unknown time".to_string(),
+            time => time.to_string(),
+        };
+        let added = pinned.size - previous.map_or(0, |previous| previous.size);
+        print!(
+            "{} leaves (+{added}): {} pinned at {pinned_at}",
+            pinned.size, pinned.root
+        );
+        match previous.filter(|_| verify) {
+            Some(previous) => {
+                let consistency = client
+                    .fetch_consistency(previous.size.into(), pinned.size.into())
+                    .await?;
+                let proof = consistency.proof;
+                if proof.old_size == previous.size
+                    && proof.new_size == pinned.size
+                    && proof.verify(&previous.root, &pinned.root)
+                {
+                    println!(", consistent");
+                } else {
+                    println!(", NOT consistent with previous root");
+                    inconsistent += 1;
+                }
+            }
+            None => println!(),
+        }
+        previous = Some(pinned);
+    }
+    if inconsistent > 0 {
+        return Err(verification_failed(format!(
+            "{inconsistent} pinned roots don't extend the previous one"
+        )));
+    }
+    Ok(())
+}
+
+async fn show_root(client: Client, state_filename: String, verify: bool) -> anyhow::Result<()> {
+    let state = load_state(state_filename).await?;
+    let local_size = state.light_tree.len();
+    let local_root = state.light_tree.root();
+    match &local_root {
+        Some(root) => println!("Local  {local_size} leaves: {root}"),
+        None => println!("Local  no leaves"),
+    }
+    for pinned in &state.roots {
+        println!("Pinned {} leaves: {}", pinned.size, pinned.root);
+    }
+    let remote = client.fetch_root().await?;
+    let remote_size = remote.size.as_usize();
+    println!(
+        "Remote {remote_size} leaves of epoch {}: {}",
+        remote.epoch, remote.hash
+    );
+    let Some(local_root) = local_root else {
+        return Ok(());
+    };
+    if remote_size < local_size {
+        return Err(verification_failed(format!(
+            "Server tree has {remote_size} leaves, fewer than {local_size} known locally"
+        )));
+    }
+    if remote_size == local_size {
+        if remote.hash != local_root {
+            return Err(verification_failed(
+                "Server root differs from local root of the same tree size",
+            ));
+        }
+        println!("Roots match");
+        return Ok(());
+    }
+    if !verify {
+        status!(
+            "Server has {} leaves local state doesn't know about, check with --verify that they were only appended",
+            remote_size - local_size
+        );
+        return Ok(());
+    }
+    let consistency = client
+        .fetch_consistency(local_size.into(), remote_size.into())
+        .await?;
+    let proof = consistency.proof;
+    if consistency.root != remote.hash
+        || proof.old_size != local_size
+        || proof.new_size != remote_size
+        || !proof.verify(&local_root, &remote.hash)
+    {
+        return Err(verification_failed(format!(
+            "Server tree of {remote_size} leaves doesn't extend local tree of {local_size} leaves"
+        )));
+    }
+    println!("Server tree extends local tree");
+    Ok(())
+}
+
+async fn selftest(vectors: Option<PathBuf>, write: Option<PathBuf>) -> anyhow::Result<()> {
+    if let Some(path) = write {
+        let generated = serde_json::to_string_pretty(&testvectors::generate())?;
+        tokio::fs::write(&path, generated).await?;
+        status!("Test vectors written to {}", path.display());
+        return Ok(());
+    }
+    let vectors: TestVectors = match &vectors {
+        Some(path) => serde_json::from_slice(&tokio::fs::read(path).await?)?,
+        None => testvectors::canonical(),
+    };
+    let mismatches = testvectors::check(&vectors);
+    for mismatch in &mismatches {
+        println!("{mismatch}");
+    }
+    if !mismatches.is_empty() {
+        return Err(verification_failed(format!(
+            "{} test vectors don't match, this build is not compatible",
+            mismatches.len()
+        )));
+    }
+    status!(
+        "{} leaf, {} tombstone and {} tree vectors match",
+        vectors.leaves.len(),
+        vectors.tombstones.len(),
+        vectors.trees.len()
+    );
+    Ok(())
+}
+
+async fn root_of(hasher: &dyn LeafHasher, path: String) -> anyhow::Result<()> {
+    let files = if Path::new(&path).is_dir() {
+        let mut files = Vec::new();
+        collect_files(Path::new(&path), &mut files)?;
+        files.sort();
+        files
+    } else {
+        tokio::fs::read_to_string(&path)
+            .await?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect()
+    };
+    let mut hashes = Vec::with_capacity(files.len());
+    for file in &files {
+        let name = upload_name(&file.to_string_lossy());
+        hashes.push(hasher.leaf(&name, &tokio::fs::read(file).await?));
+    }
+    match merkle::Sha3Tree::from_manifest(hashes).root() {
+        Some(root) => println!("Root of {} files: {root}", files.len()),
+        None => println!("No files found"),
+    }
+    Ok(())
+}
+
+/// Backup profiles by name, read by `backup run`
+#[derive(Debug, Deserialize)]
+struct BackupConfig {
+    profiles: HashMap<String, BackupProfile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BackupProfile {
+    /// files and directories to back up, directories recursively
+    sources: Vec<PathBuf>,
+    /// paths left out, matching whole path or any of its components, `*` matches any characters
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// prefix of names files are uploaded under, keeping profiles sharing server apart
+    #[serde(default)]
+    bucket: Option<String>,
+    /// contents are encrypted before upload when given, so server only sees ciphertext
+    #[serde(default)]
+    encryption: Option<BackupEncryption>,
+    /// directory run reports are written to, `.backup/<profile>` if omitted
+    #[serde(default)]
+    reports: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BackupEncryption {
+    /// environment variable with hex encoded 32 byte key
+    key_env: String,
+}
+
+/// Report of a single backup run, listing every file of the profile so the latest report is a
+/// catalog of the whole backup
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupReport {
+    profile: String,
+    /// unix timestamps in seconds
+    started_at: u64,
+    finished_at: u64,
+    /// local root pinned after the run and root reported by server
+    root: Option<merkle::Sha3Hash>,
+    remote_root: Option<merkle::Sha3Hash>,
+    tree_size: usize,
+    encrypted: bool,
+    files: Vec<BackedUpFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackedUpFile {
+    path: PathBuf,
+    name: String,
+    /// sha3-256 of content as read from disk, tells changed files apart between runs and binds
+    /// encrypted content to the file. Missing for skipped files.
+    #[serde(default)]
+    content_hash: Option<merkle::Sha3Hash>,
+    status: BackupStatus,
+    /// file on the server, carried over from earlier run for unchanged files
+    #[serde(default)]
+    id: Option<FileId>,
+    #[serde(default)]
+    leaf: Option<merkle::Sha3Hash>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BackupStatus {
+    Uploaded,
+    /// content is the same as in the previous run, nothing was uploaded
+    Unchanged,
+    Failed,
+    /// file couldn't be read
+    Skipped,
+}
+
+impl BackupStatus {
+    /// File is stored on the server, by this or an earlier run
+    fn is_stored(self) -> bool {
+        matches!(self, BackupStatus::Uploaded | BackupStatus::Unchanged)
+    }
+}
+
+/// Profile of given name in config file, key of its encryption and its reports directory
+fn backup_profile(
+    config: &Path,
+    content: &[u8],
+    profile_name: &str,
+) -> anyhow::Result<(BackupProfile, Option<MasterKey>, PathBuf)> {
+    let mut config: BackupConfig = serde_json::from_slice(content)
+        .map_err(|err| anyhow!("{} is not a backup config: {err}", config.display()))?;
+    let profile = config
+        .profiles
+        .remove(profile_name)
+        .ok_or_else(|| anyhow!("Backup profile {profile_name} is not configured"))?;
+    let key = match &profile.encryption {
+        Some(encryption) => Some(
+            std::env::var(&encryption.key_env)
+                .map_err(|_| anyhow!("{} is not set", encryption.key_env))?
+                .parse::<MasterKey>()?,
+        ),
+        None => None,
+    };
+    let reports = match &profile.reports {
+        Some(reports) => reports.clone(),
+        None => Path::new(".backup").join(profile_name),
+    };
+    Ok((profile, key, reports))
+}
+
+async fn backup_run(
+    client: Client,
+    state_filename: String,
+    config: PathBuf,
+    profile_name: String,
+) -> anyhow::Result<()> {
+    let (profile, key, reports) =
+        backup_profile(&config, &tokio::fs::read(&config).await?, &profile_name)?;
+    let previous = latest_backup_report(&reports).await?;
+    let previous: HashMap<&str, &BackedUpFile> = previous
+        .iter()
+        .flat_map(|report| &report.files)
+        .filter(|file| file.status.is_stored())
+        .map(|file| (file.name.as_str(), file))
+        .collect();
+    let mut files = Vec::new();
+    for source in &profile.sources {
+        match source.is_dir() {
+            true => collect_files(source, &mut files)?,
+            false => files.push(source.clone()),
+        }
+    }
+    files.sort();
+    files.retain(|path| {
+        !profile
+            .exclude
+            .iter()
+            .any(|pattern| backup_excludes(pattern, path))
+    });
+
+    let mut state = load_state(state_filename.clone()).await?;
+    let started_at = unix_time();
+    let mut backed_up = Vec::with_capacity(files.len());
+    for path in files {
+        let name = match &profile.bucket {
+            Some(bucket) => format!("{bucket}/{}", upload_name(&path.to_string_lossy())),
+            None => upload_name(&path.to_string_lossy()),
+        };
+        let content = match tokio::fs::read(&path).await {
+            Ok(content) => content,
+            Err(err) => {
+                eprintln!("{} can't be read, skipped: {err}", path.display());
+                backed_up.push(BackedUpFile {
+                    path,
+                    name,
+                    content_hash: None,
+                    status: BackupStatus::Skipped,
+                    id: None,
+                    leaf: None,
+                    error: Some(err.to_string()),
+                });
+                continue;
+            }
+        };
+        let content_hash = hash_content(&content);
+        if let Some(unchanged) = previous
+            .get(name.as_str())
+            .filter(|file| file.content_hash.as_ref() == Some(&content_hash))
+        {
+            backed_up.push(BackedUpFile {
+                path,
+                status: BackupStatus::Unchanged,
+                ..(*unchanged).clone()
+            });
+            continue;
+        }
+        let mut file = BackedUpFile {
+            path,
+            name,
+            content_hash: Some(content_hash.clone()),
+            status: BackupStatus::Failed,
+            id: None,
+            leaf: None,
+            error: None,
+        };
+        match upload_backup_file(&client, key.as_ref(), &file.name, &content_hash, content).await {
+            Ok((id, leaf)) => {
+                status!(
+                    "{} backed up as {} with id: {id}",
+                    file.path.display(),
+                    file.name
+                );
+                state.append(leaf.clone());
+                file.status = BackupStatus::Uploaded;
+                (file.id, file.leaf) = (Some(id), Some(leaf));
+            }
+            Err(err) => {
+                eprintln!("Backup of {} failed: {err}", file.path.display());
+                file.error = Some(err.to_string());
+            }
+        }
+        backed_up.push(file);
+    }
+    state.pin_root();
+    let report = BackupReport {
+        profile: profile_name,
+        started_at,
+        finished_at: unix_time(),
+        root: state.light_tree.root(),
+        remote_root: client.fetch_root().await.ok().map(|root| root.hash),
+        tree_size: state.light_tree.len(),
+        encrypted: key.is_some(),
+        files: backed_up,
+    };
+    store_state(state_filename, state).await?;
+
+    tokio::fs::create_dir_all(&reports).await?;
+    let report_file = reports.join(format!("run-{started_at}.json"));
+    tokio::fs::write(&report_file, serde_json::to_vec_pretty(&report)?).await?;
+    let count = |status| {
+        report
+            .files
+            .iter()
+            .filter(|file| file.status == status)
+            .count()
+    };
+    let failed = count(BackupStatus::Failed);
+    let skipped = count(BackupStatus::Skipped);
+    status!(
+        "{} uploaded, {} unchanged, {failed} failed, {skipped} skipped, report written to {}",
+        count(BackupStatus::Uploaded),
+        count(BackupStatus::Unchanged),
+        report_file.display()
+    );
+    if let Some(root) = &report.root {
+        println!("Root: {root}");
+        if report.remote_root.as_ref() != Some(root) {
+            status!("Local root hash differs from remote hash - verification won't work");
+        }
+    }
+    match failed + skipped {
+        0 => Ok(()),
+        failed => Err(anyhow!("{failed} files failed to back up")),
+    }
+}
+
+/// Uploads content, encrypted bound to its content hash if key is given, returning its id and
+/// leaf. Unlike the name, which server may change, the hash stays with the file in run reports.
+async fn upload_backup_file(
+    client: &Client,
+    key: Option<&MasterKey>,
+    name: &str,
+    content_hash: &merkle::Sha3Hash,
+    content: Vec<u8>,
+) -> anyhow::Result<(FileId, merkle::Sha3Hash)> {
+    let content = match key {
+        Some(key) => key.encrypt(&content, content_hash.as_bytes())?,
+        None => content,
+    };
+    let stored = client.upload_new_file(name, &content).await?;
+    let leaf = client.leaf_hasher().leaf(&stored.file.name, &content);
+    Ok((stored.file.id, leaf))
+}
+
+async fn backup_restore(
+    client: Client,
+    state_filename: String,
+    server_keys: &[TrustedKey],
+    config: PathBuf,
+    profile_name: String,
+    save: SaveOptions,
+) -> anyhow::Result<()> {
+    let (_, key, reports) =
+        backup_profile(&config, &tokio::fs::read(&config).await?, &profile_name)?;
+    let report = latest_backup_report(&reports).await?.ok_or_else(|| {
+        anyhow!(
+            "Profile {profile_name} has no backup reports in {}",
+            reports.display()
+        )
+    })?;
+    if report.encrypted && key.is_none() {
+        return Err(anyhow!(
+            "Backup of {profile_name} is encrypted, but profile has no key"
+        ));
+    }
+    let roots = epoch_roots(&client, &load_state(state_filename).await?, server_keys).await?;
+    let mut failed = 0;
+    for file in report.files.iter().filter(|file| file.status.is_stored()) {
+        match restore_backup_file(&client, &roots, key.as_ref(), file, &save).await {
+            Ok(path) => status!("{} restored to {}", file.path.display(), path.display()),
+            Err(err) => {
+                eprintln!("Restore of {} failed: {err}", file.path.display());
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(anyhow!("{failed} files failed to restore")),
+    }
+}
+
+/// Downloads file of backup report verified against pinned roots, decrypts it and checks it
+/// against content hash of the report before saving it under its source path
+async fn restore_backup_file(
+    client: &Client,
+    roots: &EpochRoots,
+    key: Option<&MasterKey>,
+    file: &BackedUpFile,
+    save: &SaveOptions,
+) -> anyhow::Result<PathBuf> {
+    let (Some(id), Some(content_hash)) = (file.id, &file.content_hash) else {
+        return Err(anyhow!("report has no id or content hash of it"));
+    };
+    let (downloaded, summary) = client
+        .download_file(id, |epoch| roots.of(epoch).cloned())
+        .await?;
+    if file.leaf.as_ref().is_some_and(|leaf| *leaf != summary.leaf) {
+        return Err(verification_failed(format!(
+            "file {id} is not the one backed up"
+        )));
+    }
+    let content = match key {
+        // earlier runs bound content to its upload name
+        Some(key) => key
+            .decrypt(&downloaded.content, content_hash.as_bytes())
+            .or_else(|_| key.decrypt(&downloaded.content, file.name.as_bytes()))
+            .map_err(|err| verification_failed(format!("file {id} can't be decrypted: {err}")))?,
+        None => downloaded.content,
+    };
+    if hash_content(&content) != *content_hash {
+        return Err(verification_failed(format!(
+            "content of file {id} is not the backed up one"
+        )));
+    }
+    let name = upload_name(&file.path.to_string_lossy());
+    if let Some(parent) = Path::new(&name).parent() {
+        tokio::fs::create_dir_all(save.dir.join(parent)).await?;
+    }
+    save_file(save, &name, &content).await
+}
+
+/// Report of the last run in given directory, none before the first run
+async fn latest_backup_report(reports: &Path) -> anyhow::Result<Option<BackupReport>> {
+    let mut entries = match tokio::fs::read_dir(reports).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut latest: Option<(u64, PathBuf)> = None;
+    while let Some(entry) = entries.next_entry().await? {
+        let started_at = entry.file_name().to_str().and_then(|name| {
+            name.strip_prefix("run-")?
+                .strip_suffix(".json")?
+                .parse()
+                .ok()
+        });
+        if let Some(started_at) = started_at.filter(|started_at| {
+            latest
+                .as_ref()
+                .is_none_or(|(latest, _)| started_at > latest)
+        }) {
+            latest = Some((started_at, entry.path()));
+        }
+    }
+    let Some((_, path)) = latest else {
+        return Ok(None);
+    };
+    let report = serde_json::from_slice(&tokio::fs::read(&path).await?).map_err(|err| {
+        StateError(format!(
+            "Backup report {} is corrupted: {err}",
+            path.display()
+        ))
+    })?;
+    Ok(Some(report))
+}
+
+/// Whether exclude pattern matches the whole path or any of its components
+fn backup_excludes(pattern: &str, path: &Path) -> bool {
+    wildcard_match(pattern, &path.to_string_lossy())
+        || path
+            .components()
+            .any(|component| wildcard_match(pattern, &component.as_os_str().to_string_lossy()))
+}
+
+/// `*` in pattern matches any characters, everything else matches itself
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            text.char_indices()
+                .map(|(at, _)| at)
+                .chain([text.len()])
+                .any(|at| wildcard_match(rest, &text[at..]))
+        }
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Layout version of local state written by this build
+const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LocalState {
+    /// layout version, states written before versioning have none and read as 0
+    #[serde(default)]
+    version: u32,
+    light_tree: merkle::Sha3LightTree,
+    /// hashes of all leaves appended by this client, used to pinpoint differences
+    #[serde(default)]
+    leaves: Vec<merkle::Sha3Hash>,
+    /// roots seen after each run changing the tree, oldest first
+    #[serde(default)]
+    roots: Vec<PinnedRoot>,
+    /// urls uploaded by `import --manifest`, in order
+    #[serde(default)]
+    imports: Vec<ImportedUrl>,
+}
+
+/// Url whose content was downloaded and uploaded as file `id` with leaf `hash`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ImportedUrl {
+    url: String,
+    id: FileId,
+    hash: merkle::Sha3Hash,
+}
+
+/// Root of the tree made of first `size` leaves
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PinnedRoot {
+    size: usize,
+    root: merkle::Sha3Hash,
+    /// unix time the root was pinned at, 0 for roots pinned before it was recorded
+    #[serde(default)]
+    pinned_at: u64,
+}
+
+impl LocalState {
+    fn append(&mut self, hash: merkle::Sha3Hash) {
+        self.leaves.push(hash.clone());
+        self.light_tree.append(hash);
+    }
+
+    /// Root of leaves from `first` up to `end`, if local state has them all. Pinned roots are
+    /// used while leaves are not tracked.
+    fn epoch_root(&self, first: usize, end: usize) -> Option<merkle::Sha3Hash> {
+        if self.leaves.len() != self.light_tree.len() {
+            return (first == 0)
+                .then(|| self.roots.iter().find(|pinned| pinned.size == end))
+                .flatten()
+                .map(|pinned| pinned.root.clone());
+        }
+        match self.leaves.get(first..end) {
+            Some(leaves) => merkle::Sha3Tree::from_manifest(leaves.to_vec()).root(),
+            None => None,
+        }
+    }
+
+    /// Upgrades state read from older layout, refuses states written by newer client
+    fn migrate(&mut self) -> Result<(), StateError> {
+        if self.version > STATE_VERSION {
+            return Err(StateError(format!(
+                "state version {} is newer than supported {STATE_VERSION}",
+                self.version
+            )));
+        }
+        if self.version == 0 && self.light_tree.is_empty() && self.light_tree.depth() > 0 {
+            // light tree written before it counted its leaves, count is recovered from leaves
+            let rebuilt =
+                self.leaves
+                    .iter()
+                    .cloned()
+                    .fold(merkle::Sha3LightTree::new(), |mut tree, hash| {
+                        tree.append(hash);
+                        tree
+                    });
+            if rebuilt.root() != self.light_tree.root() {
+                return Err(StateError(
+                    "state predates leaf tracking, its tree size can't be recovered".to_string(),
+                ));
+            }
+            self.light_tree = rebuilt;
+        }
+        self.version = STATE_VERSION;
+        Ok(())
+    }
+
+    /// Problems found by comparing light tree, leaves and pinned roots with each other
+    fn integrity_problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.light_tree.pending() > 0 {
+            problems.push(format!(
+                "{} reserved leaves were never filled",
+                self.light_tree.pending()
+            ));
+        }
+        if self.leaves.len() != self.light_tree.len() {
+            problems.push(format!(
+                "tree has {} leaves, leaf list has {}",
+                self.light_tree.len(),
+                self.leaves.len()
+            ));
+        }
+        let mut tree = merkle::Sha3LightTree::new();
+        let mut roots = self.roots.iter().peekable();
+        for (index, hash) in self.leaves.iter().enumerate() {
+            tree.append(hash.clone());
+            while let Some(pinned) = roots.next_if(|pinned| pinned.size <= index + 1) {
+                if pinned.size != tree.len() || Some(&pinned.root) != tree.root().as_ref() {
+                    problems.push(format!(
+                        "root pinned at {} leaves doesn't match leaves",
+                        pinned.size
+                    ));
+                }
+            }
+        }
+        if let Some(pinned) = roots.next() {
+            problems.push(format!(
+                "root pinned at {} leaves is beyond leaf list",
+                pinned.size
+            ));
+        }
+        if self.leaves.len() == self.light_tree.len() && tree.root() != self.light_tree.root() {
+            problems.push("tree root doesn't match leaves".to_string());
+        }
+        problems
+    }
+
+    /// Roots pinned so far together with the current one, by tree size
+    fn known_roots(&self) -> BTreeMap<usize, merkle::Sha3Hash> {
+        let mut roots: BTreeMap<_, _> = self
+            .roots
+            .iter()
+            .map(|pinned| (pinned.size, pinned.root.clone()))
+            .collect();
+        if let Some(root) = self.light_tree.root() {
+            roots.insert(self.light_tree.len(), root);
+        }
+        roots
+    }
+
+    /// Roots of the first `size` leaves for each of given sizes covered by the leaf list
+    fn roots_at(
+        &self,
+        sizes: impl IntoIterator<Item = usize>,
+    ) -> BTreeMap<usize, merkle::Sha3Hash> {
+        let sizes: BTreeSet<_> = sizes.into_iter().collect();
+        let mut roots = BTreeMap::new();
+        let mut tree = merkle::Sha3LightTree::new();
+        for hash in &self.leaves {
+            tree.append(hash.clone());
+            if let Some(root) = tree.root().filter(|_| sizes.contains(&tree.len())) {
+                roots.insert(tree.len(), root);
+            }
+        }
+        roots
+    }
+
+    /// Disagreements with state of another client of the same server: different leaves at the
+    /// same index, or roots pinned by one client which leaves or roots of the other don't match.
+    /// One client being behind the other is not a divergence.
+    fn divergences_from(&self, peer: &LocalState) -> Vec<String> {
+        let mut divergences: Vec<_> = merkle::diff(&self.leaves, &peer.leaves)
+            .into_iter()
+            .filter_map(|difference| match difference {
+                LeafDiff::Mismatched { index, old, new } => {
+                    Some(format!("leaf {index} is {old} locally, {new} at peer"))
+                }
+                _ => None,
+            })
+            .collect();
+        let (local_roots, peer_roots) = (self.known_roots(), peer.known_roots());
+        for (size, local) in &local_roots {
+            if let Some(other) = peer_roots.get(size).filter(|other| *other != local) {
+                divergences.push(format!(
+                    "root of {size} leaves is {local} locally, {other} at peer"
+                ));
+            }
+        }
+        let checks = [
+            ("local", &local_roots, peer, &peer_roots),
+            ("peer", &peer_roots, self, &local_roots),
+        ];
+        for (pinned_by, pinned, other, other_roots) in checks {
+            let unchecked = pinned.keys().filter(|size| !other_roots.contains_key(size));
+            let computed = other.roots_at(unchecked.copied());
+            for (size, root) in computed {
+                if pinned.get(&size) != Some(&root) {
+                    divergences.push(format!(
+                        "root of {size} leaves pinned by {pinned_by} doesn't match leaves of the other"
+                    ));
+                }
+            }
+        }
+        divergences
+    }
+
+    /// Remembers current root, unless it is already the latest pinned one
+    fn pin_root(&mut self) {
+        let size = self.light_tree.len();
+        let Some(root) = self.light_tree.root() else {
+            return;
+        };
+        if self.roots.last().map(|pinned| pinned.size) != Some(size) {
+            self.roots.push(PinnedRoot {
+                size,
+                root,
+                pinned_at: unix_time(),
+            });
+        }
+    }
+}
+
+/// Reads local state as it is stored, without migrating it
+async fn read_state(filename: &str) -> anyhow::Result<LocalState> {
+    let content = tokio::fs::read_to_string(filename)
+        .await
+        .map_err(|err| StateError(format!("Local state {filename} can't be read: {err}")))?;
+    Ok(serde_json::from_str(&content)
+        .map_err(|err| StateError(format!("Local state {filename} is corrupted: {err}")))?)
+}
+
+async fn load_state(filename: String) -> anyhow::Result<LocalState> {
+    let mut state = read_state(&filename).await?;
+    state
+        .migrate()
+        .map_err(|err| StateError(format!("Local state {filename} can't be loaded: {err}")))?;
+    Ok(state)
+}
+
+async fn migrate_state(filename: String, check: bool) -> anyhow::Result<()> {
+    let mut state = read_state(&filename).await?;
+    let (version, tree_version) = (state.version, state.light_tree.version());
+    println!("State version: {version} (newest {STATE_VERSION})");
+    println!(
+        "Tree format version: {} (newest {})",
+        tree_version.number(),
+        merkle::FormatVersion::CURRENT.number()
+    );
+    println!(
+        "Leaves: {}, pinned roots: {}",
+        state.leaves.len(),
+        state.roots.len()
+    );
+    state
+        .migrate()
+        .map_err(|err| StateError(format!("Local state {filename} can't be migrated: {err}")))?;
+    let problems = state.integrity_problems();
+    for problem in &problems {
+        println!("Integrity problem: {problem}");
+    }
+
+    if !check {
+        if version == STATE_VERSION && tree_version == merkle::FormatVersion::CURRENT {
+            status!("Local state is already in the newest layout");
+        } else {
+            let backup = format!("{filename}.v{version}.bak");
+            tokio::fs::copy(&filename, &backup).await?;
+            store_state(filename, state).await?;
+            status!("Local state migrated to version {STATE_VERSION}, backup saved as {backup}");
+        }
+    }
+    if !problems.is_empty() {
+        return Err(StateError(format!(
+            "Local state has {} integrity problems",
+            problems.len()
+        ))
+        .into());
+    }
+    status!("Local state integrity verified");
+    Ok(())
+}
+
+/// Copy of local state saved by `state snapshot`
+#[derive(Serialize, Deserialize)]
+struct StateSnapshot {
+    name: String,
+    /// unix time the snapshot was taken at
+    created_at: u64,
+    /// server the state was used with when the snapshot was taken
+    server_url: String,
+    tree_size: usize,
+    root: Option<merkle::Sha3Hash>,
+    state: LocalState,
+}
+
+/// Snapshots of state file are kept in a directory next to it, one json file per name
+fn snapshot_path(state_filename: &str, name: &str) -> anyhow::Result<PathBuf> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(anyhow!(
+            "Snapshot name may contain only letters, digits, '-', '_' and '.' not at the start"
+        ));
+    }
+    Ok(snapshot_dir(state_filename).join(format!("{name}.json")))
+}
+
+fn snapshot_dir(state_filename: &str) -> PathBuf {
+    PathBuf::from(format!("{state_filename}.snapshots"))
+}
+
+async fn read_snapshot(path: &Path) -> anyhow::Result<StateSnapshot> {
+    let content = tokio::fs::read(path)
+        .await
+        .map_err(|err| StateError(format!("Snapshot {} can't be read: {err}", path.display())))?;
+    Ok(serde_json::from_slice(&content)
+        .map_err(|err| StateError(format!("Snapshot {} is corrupted: {err}", path.display())))?)
+}
+
+async fn snapshot_state(
+    state_filename: String,
+    server_url: String,
+    name: String,
+    force: bool,
+) -> anyhow::Result<()> {
+    let path = snapshot_path(&state_filename, &name)?;
+    if !force && tokio::fs::try_exists(&path).await? {
+        return Err(anyhow!(
+            "Snapshot {name} already exists, use --force to replace it"
+        ));
+    }
+    let state = load_state(state_filename.clone()).await?;
+    let snapshot = StateSnapshot {
+        name,
+        created_at: unix_time(),
+        server_url,
+        tree_size: state.light_tree.len(),
+        root: state.light_tree.root(),
+        state,
+    };
+    tokio::fs::create_dir_all(snapshot_dir(&state_filename)).await?;
+    tokio::fs::write(&path, serde_json::to_vec_pretty(&snapshot)?).await?;
+    status!(
+        "Snapshot {} of {} leaves saved as {}",
+        snapshot.name,
+        snapshot.tree_size,
+        path.display()
+    );
+    Ok(())
+}
+
+async fn restore_state(state_filename: String, name: String) -> anyhow::Result<()> {
+    let snapshot = read_snapshot(&snapshot_path(&state_filename, &name)?).await?;
+    let mut state = snapshot.state;
+    state
+        .migrate()
+        .map_err(|err| StateError(format!("Snapshot {name} can't be loaded: {err}")))?;
+    if tokio::fs::try_exists(&state_filename).await? {
+        let backup = format!("{state_filename}.bak");
+        tokio::fs::copy(&state_filename, &backup).await?;
+        status!("Current local state saved as {backup}");
+    }
+    store_state(state_filename, state).await?;
+    status!(
+        "Local state restored to snapshot {name} of {} leaves taken at {} against {}",
+        snapshot.tree_size,
+        snapshot.created_at,
+        snapshot.server_url
+    );
+    Ok(())
+}
+
+async fn list_snapshots(state_filename: String) -> anyhow::Result<()> {
+    let dir = snapshot_dir(&state_filename);
+    let mut snapshots = vec![];
+    if tokio::fs::try_exists(&dir).await? {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some_and(|ext| ext == "json") {
+                snapshots.push(read_snapshot(&entry.path()).await?);
+            }
+        }
+    }
+    if snapshots.is_empty() {
+        println!("No snapshots of {state_filename}");
+        return Ok(());
+    }
+    snapshots.sort_by_key(|snapshot| snapshot.created_at);
+    for snapshot in snapshots {
+        let root = match &snapshot.root {
+            Some(root) => root.to_string(),
+            None => "no root".to_string(),
+        };
+        println!(
+            "{}: {} leaves, {root}, taken at {} against {}",
+            snapshot.name, snapshot.tree_size, snapshot.created_at, snapshot.server_url
+        );
+    }
+    Ok(())
+}
+
+async fn store_state(filename: String, state: LocalState) -> anyhow::Result<()> {
+    let serialized = serde_json::ser::to_vec_pretty(&state)?;
+    let mut file = tokio::fs::File::create(filename).await?;
+    file.write_all(&serialized).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pin_root() {
+        let mut state = LocalState {
+            version: STATE_VERSION,
+            light_tree: merkle::Sha3LightTree::new(),
+            leaves: vec![],
+            roots: vec![],
+            imports: vec![],
+        };
+        state.pin_root();
+        assert!(state.roots.is_empty());
+
+        state.append(hash_content(b"first"));
+        state.pin_root();
+        state.pin_root();
+        state.append(hash_content(b"second"));
+        state.pin_root();
+        let sizes: Vec<_> = state.roots.iter().map(|pinned| pinned.size).collect();
+        assert_eq!(sizes, vec![1, 2]);
+        assert!(state.roots.iter().all(|pinned| pinned.pinned_at > 0));
+        assert!(state.integrity_problems().is_empty());
+        state.roots[0].root = hash_content(b"second");
+        assert_eq!(
+            state.integrity_problems(),
+            vec!["root pinned at 1 leaves doesn't match leaves".to_string()]
+        );
+        assert_eq!(
+            state.roots.last().map(|pinned| &pinned.root),
+            state.light_tree.root().as_ref()
+        );
+    }
+
+    #[test]
+    fn test_divergences_from() {
+        let state_of = |contents: &[&[u8]]| {
+            let mut state = LocalState {
+                version: STATE_VERSION,
+                light_tree: merkle::Sha3LightTree::new(),
+                leaves: vec![],
+                roots: vec![],
+                imports: vec![],
+            };
+            for content in contents {
+                state.append(hash_content(content));
+                state.pin_root();
+            }
+            state
+        };
+        let local = state_of(&[b"first", b"second"]);
+        let peer = state_of(&[b"first", b"second", b"third"]);
+        assert!(local.divergences_from(&peer).is_empty());
+        assert!(peer.divergences_from(&local).is_empty());
+
+        let forked = state_of(&[b"first", b"forged", b"third"]);
+        let divergences = local.divergences_from(&forked);
+        assert_eq!(divergences.len(), 2);
+        assert!(divergences[0].starts_with("leaf 1 is"));
+        assert!(divergences[1].starts_with("root of 2 leaves is"));
+
+        // root pinned by peer without its leaves still has to match local leaves
+        let mut local = state_of(&[b"first", b"second", b"third"]);
+        local.roots.clear();
+        local.pin_root();
+        let mut unbacked = state_of(&[b"first"]);
+        unbacked.roots.push(PinnedRoot {
+            size: 2,
+            root: hash_content(b"forged"),
+            pinned_at: 0,
+        });
+        assert_eq!(
+            local.divergences_from(&unbacked),
+            vec!["root of 2 leaves pinned by peer doesn't match leaves of the other".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_migrate_state() {
+        let leaves = vec![hash_content(b"first"), hash_content(b"second")];
+        let mut light_tree = merkle::Sha3LightTree::new();
+        leaves
+            .iter()
+            .for_each(|hash| light_tree.append(hash.clone()));
+        let legacy_tree = serde_json::to_string(&light_tree)
+            .unwrap()
+            .replace(r#""version":1,"#, "")
+            .replace(r#","size":2"#, "");
+        let legacy = |leaves: &[merkle::Sha3Hash]| {
+            serde_json::from_str::<LocalState>(&format!(
+                r#"{{"light_tree": {legacy_tree}, "leaves": {}}}"#,
+                serde_json::to_string(leaves).unwrap()
+            ))
+            .expect("should parse")
+        };
+
+        let mut state = legacy(&leaves);
+        assert_eq!((state.version, state.light_tree.len()), (0, 0));
+        state.migrate().expect("should migrate");
+        assert_eq!((state.version, state.light_tree.len()), (STATE_VERSION, 2));
+        assert_eq!(state.light_tree.root(), light_tree.root());
+
+        assert!(state.integrity_problems().is_empty());
+        assert!(legacy(&leaves[..1]).migrate().is_err());
+        state.version = STATE_VERSION + 1;
+        assert!(state.migrate().is_err());
+    }
+
+    #[test]
+    fn test_last_consistent() {
+        let record = |size: u64, error: Option<&str>| AuditRecord {
+            checked_at: 0,
+            epoch: 0,
+            size,
+            root: hash_content(size.to_string()),
+            error: error.map(str::to_string),
+        };
+        let log: Vec<_> = [record(1, None), record(2, None), record(1, Some("shrank"))]
+            .iter()
+            .map(|record| serde_json::to_string(record).unwrap())
+            .collect();
+        let last = last_consistent(&log.join("\n")).unwrap();
+        assert_eq!(last.map(|record| record.size), Some(2));
+        assert!(last_consistent("").unwrap().is_none());
+        assert!(last_consistent("not json").is_err());
+    }
+
+    #[test]
+    fn test_local_file_name() {
+        assert_eq!(local_file_name("a.txt").unwrap(), "a.txt");
+        assert_eq!(local_file_name("docs/a.txt").unwrap(), "a.txt");
+        assert_eq!(local_file_name("docs\\a.txt").unwrap(), "a.txt");
+        for name in [
+            "../../etc/passwd",
+            "/etc/passwd",
+            "docs/../a.txt",
+            "docs/",
+            "..",
+            "a\nb",
+            "",
+            "docs/CON",
+            "nul.txt",
+            "a?.txt",
+            "a.txt.",
+        ] {
+            assert!(local_file_name(name).is_err(), "{name}");
+        }
+        assert_eq!(local_file_name("docs/cafe\u{301}.txt").unwrap(), "café.txt");
+        assert_eq!(local_file_name("😀/報告.txt").unwrap(), "報告.txt");
+    }
+
+    #[test]
+    fn test_attestation_problems() {
+        use safe_storage::signing::ServerKey;
+
+        let leaves: Vec<_> = ["first", "second", "third"]
+            .iter()
+            .map(|content| hash_content(content.as_bytes()))
+            .collect();
+        let tree = merkle::Sha3Tree::from_manifest(leaves.clone());
+        let root = tree.root().expect("should have root");
+        let key: ServerKey = "01".repeat(32).parse().expect("should parse");
+        let mut checkpoint = Checkpoint {
+            epoch: 0,
+            first_leaf: 0,
+            size: 3,
+            root: root.clone(),
+            sealed_at: 100,
+            key_id: Some(key.key_id()),
+            signature: None,
+        };
+        checkpoint.signature = Some(key.sign(&checkpoint.signed_message()));
+        let mut attestation = Attestation {
+            server: "http://localhost:8080".to_string(),
+            public_key: Some(key.public_key()),
+            id: FileId(1),
+            name: "second".to_string(),
+            content_hash: leaves[1].clone(),
+            leaf_hashing: LeafHashing::Content,
+            epoch: 0,
+            proof: tree.proof_for(1).expect("should have proof"),
+            root,
+            checkpoint: Some(checkpoint),
+            attested_at: 200,
+        };
+        let trusted: Vec<TrustedKey> = vec![key.public_key().parse().expect("should parse")];
+        assert!(attestation.problems(&[]).is_empty());
+        assert!(attestation.problems(&trusted).is_empty());
+        let other: TrustedKey = "02".repeat(32).parse().expect("should parse");
+        assert_eq!(attestation.problems(&[other]).len(), 1);
+        // key carried by the bundle is not trusted, anyone could sign with their own
+        let forger: ServerKey = "03".repeat(32).parse().expect("should parse");
+        let mut forged = attestation
+            .checkpoint
+            .clone()
+            .expect("should have checkpoint");
+        forged.signature = Some(forger.sign(&forged.signed_message()));
+        let forged = Attestation {
+            public_key: Some(forger.public_key()),
+            checkpoint: Some(forged),
+            ..attestation.clone()
+        };
+        assert!(forged.problems(&[]).is_empty(), "unverified, not refused");
+        assert_eq!(forged.problems(&trusted).len(), 1);
+
+        attestation.content_hash = leaves[0].clone();
+        assert_eq!(attestation.problems(&[]).len(), 1);
+        attestation.content_hash = leaves[1].clone();
+        attestation.checkpoint = None;
+        assert!(attestation.problems(&[]).is_empty());
+        assert_eq!(attestation.problems(&trusted).len(), 1);
+    }
+
+    #[test]
+    fn test_check_checkpoints() {
+        use safe_storage::signing::ServerKey;
+
+        let mut state = LocalState {
+            version: STATE_VERSION,
+            light_tree: merkle::Sha3LightTree::new(),
+            leaves: vec![],
+            roots: vec![],
+            imports: vec![],
+        };
+        for content in ["first", "second", "third"] {
+            state.append(hash_content(content.as_bytes()));
+        }
+        let root_of = |leaves: &[merkle::Sha3Hash]| {
+            merkle::Sha3Tree::from_manifest(leaves.to_vec())
+                .root()
+                .expect("should have root")
+        };
+        let key: ServerKey = "01".repeat(32).parse().expect("should parse");
+        let checkpoint = |epoch, first_leaf, size, root| Checkpoint {
+            epoch,
+            first_leaf,
+            size,
+            root,
+            sealed_at: 100,
+            key_id: None,
+            signature: None,
+        };
+        let sealed = checkpoint(0, 0, 2, root_of(&state.leaves[..2]));
+        let unknown = checkpoint(1, 3, 1, hash_content(b"fourth"));
+        let checked = check_checkpoints(&state, &[], vec![sealed.clone(), unknown.clone()])
+            .expect("should check");
+        assert_eq!(checked, vec![Some(sealed.clone()), None]);
+        let roots = EpochRoots {
+            sealed: checked,
+            current: state.epoch_root(3, state.leaves.len()),
+            public_key: None,
+        };
+        assert_eq!(
+            roots.of(0).expect("should be trusted"),
+            &root_of(&state.leaves[..2])
+        );
+        assert!(
+            roots.of(1).is_err(),
+            "unsigned checkpoint of unknown leaves"
+        );
+        assert!(roots.of(2).is_err(), "current epoch has no local leaves");
+
+        let forged = checkpoint(0, 0, 2, hash_content(b"forged"));
+        assert!(check_checkpoints(&state, &[], vec![forged]).is_err());
+
+        let mut signed = unknown.clone();
+        signed.signature = Some(key.sign(&signed.signed_message()));
+        let trusted: Vec<TrustedKey> = vec![key.public_key().parse().expect("should parse")];
+        assert!(check_checkpoints(&state, &trusted, vec![sealed.clone(), unknown]).is_err());
+        let checked = check_checkpoints(&state, &[], vec![sealed.clone(), signed.clone()])
+            .expect("should check");
+        assert_eq!(checked[1], None, "signature counts only for given keys");
+        let mut signed_sealed = sealed;
+        signed_sealed.signature = Some(key.sign(&signed_sealed.signed_message()));
+        let checked = check_checkpoints(&state, &trusted, vec![signed_sealed, signed.clone()])
+            .expect("should check");
+        assert_eq!(checked[1], Some(signed));
+
+        let roots = EpochRoots {
+            sealed: vec![Some(checkpoint(0, 0, 1, root_of(&state.leaves[..1])))],
+            current: state.epoch_root(1, state.leaves.len()),
+            public_key: None,
+        };
+        assert_eq!(
+            roots.of(1).expect("should resolve current epoch"),
+            &root_of(&state.leaves[1..])
+        );
+    }
+
+    #[test]
+    fn test_backup_excludes() {
+        let path = Path::new("projects/app/node_modules/lib/index.js");
+        assert!(backup_excludes("node_modules", path));
+        assert!(backup_excludes("*.js", path));
+        assert!(backup_excludes("projects/*/lib/*", path));
+        assert!(!backup_excludes("*.rs", path));
+        assert!(!backup_excludes("modules", path));
+        assert!(wildcard_match("a*b*c", "abbbc"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("a*b", "ab c"));
+    }
+
+    #[test]
+    fn test_backup_profile() {
+        let config = br#"{"profiles": {"docs": {"sources": ["docs"]}}}"#;
+        let (profile, key, reports) =
+            backup_profile(Path::new("backup.json"), config, "docs").expect("should parse");
+        assert_eq!(profile.sources, [PathBuf::from("docs")]);
+        assert!(key.is_none());
+        assert_eq!(reports, Path::new(".backup/docs"));
+        assert!(backup_profile(Path::new("backup.json"), config, "photos").is_err());
+
+        let report = r#"{"path": "docs/a.txt", "name": "docs/a.txt", "status": "skipped",
+            "error": "permission denied"}"#;
+        let skipped: BackedUpFile = serde_json::from_str(report).expect("should parse");
+        assert_eq!(skipped.content_hash, None);
+        assert!(!skipped.status.is_stored());
+        assert!(BackupStatus::Unchanged.is_stored());
+    }
+
+    #[test]
+    fn test_tree_state_and_restore_path() {
+        assert_eq!("12".parse::<TreeState>().ok(), Some(TreeState::Size(12)));
+        let root = "ab".repeat(32);
+        assert_eq!(
+            root.parse::<TreeState>().ok(),
+            Some(TreeState::Root(root.parse().expect("should parse")))
+        );
+        assert!("abcd".parse::<TreeState>().is_err());
+
+        assert_eq!(
+            restore_path("docs/a.txt").expect("should be safe"),
+            Path::new("docs").join("a.txt")
+        );
+        assert_eq!(
+            restore_path("cafe\u{301}/a.txt").expect("should be safe"),
+            Path::new("café").join("a.txt")
+        );
+        for name in [
+            "/etc/passwd",
+            "../a.txt",
+            "docs/../../a",
+            "a//b",
+            "",
+            "a\\..\\b",
+            "aux/a.txt",
+            "docs/a:b.txt",
+        ] {
+            assert!(restore_path(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_sample_evenly() {
+        assert_eq!(sample_evenly(10, 3).collect::<Vec<_>>(), vec![0, 3, 6]);
+        assert_eq!(sample_evenly(2, 5).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(sample_evenly(0, 5).count(), 0);
+        assert_eq!(median([5, 1, 3].into_iter()), Some(3));
+        assert_eq!(median(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn test_batch_encoded_size() {
+        use safe_storage::api::{BatchUploadFile, NewFileBatch};
+
+        let files: Vec<_> = [0, 1, 2, 3, 1000]
+            .into_iter()
+            .map(|size| (format!("dir/{size}.bin"), vec![0xff; size]))
+            .collect();
+        let estimated = files
+            .iter()
+            .map(|(name, content)| batch_encoded_size(name, content))
+            .sum::<usize>();
+        let batch = NewFileBatch {
+            files: files
+                .into_iter()
+                .map(|(name, content)| BatchUploadFile { content, name })
+                .collect(),
+            ttl_secs: Some(u64::MAX),
+            expected_tree_size: Some(TreeSize(u64::MAX)),
+            dataset: Some("d".repeat(255)),
+        };
+        let encoded = serde_json::to_vec(&batch).expect("should encode").len();
+        assert!(encoded <= BATCH_ENVELOPE_SIZE + estimated, "{encoded}");
+    }
+
+    #[test]
+    fn test_watched_name() {
+        let dir = Path::new("/backup");
+        assert_eq!(
+            watched_name(dir, Path::new("/backup/docs/a.txt")),
+            Some("docs/a.txt".to_string())
+        );
+        assert_eq!(watched_name(dir, Path::new("/backup/.state.json")), None);
+        assert_eq!(watched_name(dir, Path::new("/backup/.git/HEAD")), None);
+        assert_eq!(
+            watched_name(dir, Path::new("/backup/docs/.a.txt.swp")),
+            None
+        );
+        assert_eq!(watched_name(dir, Path::new("/other/a.txt")), None);
+        assert_eq!(watched_name(dir, dir), None);
+    }
+
+    #[test]
+    fn test_failure_exit_codes() {
+        let err = verification_failed("Verification failed!").context("download 1");
+        assert_eq!(Failure::of(&err), Some(Failure::Verification));
+        let err: anyhow::Error = StateError("corrupted".to_string()).into();
+        assert_eq!(Failure::of(&err).map(Failure::exit_code), Some(5));
+        let err: anyhow::Error = HttpError {
+            status: 404,
+            body: String::new(),
+        }
+        .into();
+        assert_eq!(Failure::of(&err), Some(Failure::NotFound));
+        let err: anyhow::Error = HttpError {
+            status: 500,
+            body: String::new(),
+        }
+        .into();
+        assert_eq!(Failure::of(&err), None);
+        assert_eq!(Failure::of(&anyhow!("other")), None);
+    }
+
+    #[test]
+    fn test_snapshot_path() {
+        assert_eq!(
+            snapshot_path(".state.json", "before-staging_1.2").unwrap(),
+            PathBuf::from(".state.json.snapshots/before-staging_1.2.json")
+        );
+        for invalid in ["", ".hidden", "../up", "a/b", "a b"] {
+            assert!(snapshot_path(".state.json", invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_progress_events() {
+        let root = hash_content(b"root");
+        let events = [
+            Progress::Started {
+                command: "upload",
+                files: 2,
+            },
+            Progress::ChunkUploaded {
+                file: "a.txt",
+                id: FileId(3),
+                bytes: 5,
+                done: 1,
+                total: 2,
+            },
+            Progress::Verified {
+                id: None,
+                root: &root,
+            },
+            Progress::Done {
+                command: "upload",
+                elapsed_ms: 12,
+            },
+        ];
+        let lines: Vec<_> = events
+            .iter()
+            .map(|event| serde_json::to_string(event).unwrap())
+            .collect();
+        assert_eq!(
+            lines[0],
+            r#"{"event":"started","command":"upload","files":2}"#
+        );
+        assert_eq!(
+            lines[1],
+            r#"{"event":"chunk-uploaded","file":"a.txt","id":3,"bytes":5,"done":1,"total":2}"#
+        );
+        assert_eq!(
+            lines[2],
+            format!(r#"{{"event":"verified","root":"{root}"}}"#)
+        );
+        assert_eq!(
+            lines[3],
+            r#"{"event":"done","command":"upload","elapsed_ms":12}"#
+        );
+    }
+
+    #[test]
+    fn test_manifest_urls() {
+        let manifest = "# datasets\nhttps://example.com/a.csv\n\n  http://example.com/b.csv  \n";
+        assert_eq!(
+            manifest_urls(manifest),
+            vec!["https://example.com/a.csv", "http://example.com/b.csv"]
+        );
+    }
 }
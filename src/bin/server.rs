@@ -1,8 +1,26 @@
-use actix_web::{web, App, HttpServer};
 use clap::Parser;
-use safe_storage::service::{get_file_content, get_file_list, get_tree_root, upload_new_file};
-use safe_storage::storage::Storage;
-use std::sync::Mutex;
+use safe_storage::api::ServerInfo;
+use safe_storage::auth::{ApiKey, ApiKeys};
+use safe_storage::backend::{BlobStore, DiskBlobs, MemoryBlobs};
+use safe_storage::compression::Codings;
+use safe_storage::encryption::{EncryptedBlobs, MasterKey};
+use safe_storage::federation::{FederationConfig, FEDERATION_API_KEY_ENV};
+use safe_storage::import::{Importer, DEFAULT_MAX_IMPORT_SIZE};
+use safe_storage::interceptor::{ClamAv, DeniedExtensions, UploadInterceptors};
+use safe_storage::ipfs::IpfsNode;
+use safe_storage::leaf::LeafHashing;
+use safe_storage::server::{
+    spawn, spawn_router, CorsConfig, CorsOrigin, ListenAddr, RouterConfig, ServerConfig,
+    DEFAULT_REBUILD_BATCH,
+};
+use safe_storage::signing::ServerKey;
+use safe_storage::storage::{
+    CollisionPolicy, EpochPolicy, Quota, ShardRange, TreeLimit, WhenFull, DEFAULT_HOT_PROOFS,
+};
+use safe_storage::throttle::RateLimit;
+use safe_storage::webhook::Webhook;
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// A merkle tree based "secure" storage service to upload files and download any of them later
 /// with merkle proof for verification
@@ -12,22 +30,381 @@ struct CmdArgs {
     /// listen for incoming requests on given port
     #[arg(short, long, value_name = "port", default_value_t = 8080)]
     listen_port: u16,
+    /// listen on given HOST:PORT or unix:PATH instead of the port, can be repeated
+    #[arg(long, value_name = "ADDR")]
+    listen: Vec<ListenAddr>,
+    /// limit bandwidth of each file download, in bytes per second with optional K, M or G suffix
+    #[arg(long, value_name = "RATE")]
+    limit_rate: Option<RateLimit>,
+    /// reject uploads bigger than given amount of bytes
+    #[arg(long, value_name = "BYTES")]
+    max_upload_size: Option<usize>,
+    /// reject uploads of files with given extension, can be repeated
+    #[arg(long, value_name = "EXT")]
+    deny_extension: Vec<String>,
+    /// scan every upload with clamd listening on given tcp address, e.g. localhost:3310
+    #[arg(long, value_name = "ADDR")]
+    clamav: Option<String>,
+    /// require api key given as name:secret for uploads, can be repeated
+    #[arg(long, value_name = "NAME:SECRET")]
+    api_key: Vec<ApiKey>,
+    /// api key with access to admin endpoints, given as name:secret, can be repeated
+    #[arg(long, value_name = "NAME:SECRET")]
+    admin_key: Vec<ApiKey>,
+    /// maximum amount of bytes stored per api key
+    #[arg(long, value_name = "BYTES")]
+    quota_bytes: Option<u64>,
+    /// maximum amount of files uploaded per api key
+    #[arg(long, value_name = "COUNT")]
+    quota_files: Option<u64>,
+    /// what to do with uploads named the same as stored file: reject, suffix or version
+    #[arg(long, value_name = "POLICY", default_value = "version")]
+    on_name_collision: CollisionPolicy,
+    /// url scheme allowed for server side imports, can be repeated
+    #[arg(long, value_name = "SCHEME", default_value = "https")]
+    import_scheme: Vec<String>,
+    /// host imports may fetch from although it has loopback, link-local or private address, `*`
+    /// allows any, can be repeated
+    #[arg(long, value_name = "HOST")]
+    import_allow_host: Vec<String>,
+    /// reject imports bigger than given amount of bytes
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_IMPORT_SIZE)]
+    max_import_size: u64,
+    /// base url of IPFS node rpc api stored files can be exported to, e.g. http://localhost:5001
+    #[arg(long, value_name = "URL")]
+    ipfs_api: Option<String>,
+    /// keep file contents in given directory instead of memory
+    #[arg(long, value_name = "DIR")]
+    blob_dir: Option<PathBuf>,
+    /// refuse uploads once contents kept in memory would take more than given amount of bytes,
+    /// reported by `GET /stats`
+    #[arg(long, value_name = "BYTES", conflicts_with = "blob_dir")]
+    max_memory: Option<u64>,
+    /// encrypt stored file contents with per-file keys wrapped by hex encoded master key from
+    /// SAFE_STORAGE_MASTER_KEY environment variable
+    #[arg(long)]
+    encrypt_at_rest: bool,
+    /// keep contents of deleted files so they can be undeleted, disk space is not reclaimed
+    #[arg(long)]
+    keep_deleted: bool,
+    /// keep text files up to 1MiB indexed in memory, so `GET /search?q=` finds files containing
+    /// given text
+    #[arg(long)]
+    search: bool,
+    /// name uploaded files by hex of their content hash, like a content addressed store, instead
+    /// of names they are uploaded with. Streamed uploads must use that name already
+    #[arg(long)]
+    name_by_hash: bool,
+    /// post stored and deleted files and root changes to given url, signed with key from
+    /// SAFE_STORAGE_WEBHOOK_KEY environment variable
+    #[arg(long, value_name = "URL")]
+    webhook_url: Option<String>,
+    /// keep tree size webhook changes were delivered up to in given file, so changes made while
+    /// server was down are delivered after restart
+    #[arg(long, value_name = "FILE", requires = "webhook_url")]
+    webhook_cursor: Option<PathBuf>,
+    /// collect signed roots peers submit, listed with equivocations among them at
+    /// `GET /attestations`
+    #[arg(long)]
+    federation: bool,
+    /// submit current root signed with `--signing-key` to `POST /attestations` of given peer or
+    /// log, can be repeated. Implies `--federation`
+    #[arg(long, value_name = "URL")]
+    federate_with: Vec<String>,
+    /// seconds between root submissions to `--federate-with` peers, which get api key from
+    /// SAFE_STORAGE_FEDERATION_API_KEY environment variable if they require one
+    #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    federation_interval: u64,
+    /// store all files under given directory at startup, named by their relative paths and
+    /// appended in order of them, before accepting requests. Ignored if storage has files already
+    #[arg(long, value_name = "DIR")]
+    seed_dir: Option<PathBuf>,
+    /// restore backups in given directory written by `--backup-dir` at startup, checking them
+    /// against their root chain. Ignored if storage has leaves already
+    #[arg(long, value_name = "DIR")]
+    restore_from: Option<PathBuf>,
+    /// back storage up to given directory, a full backup first and incremental ones following it
+    /// whenever anything changed, contents are kept once by their hash
+    #[arg(long, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+    /// seconds between backups to `--backup-dir`
+    #[arg(long, value_name = "SECS", default_value_t = 3600, value_parser = clap::value_parser!(u64).range(1..))]
+    backup_interval: u64,
+    /// inject faults into responses for testing clients, given as
+    /// delay=MS,corrupt=FRACTION,drop=FRACTION,seed=N
+    #[cfg(feature = "chaos")]
+    #[arg(long, value_name = "FAULTS")]
+    chaos: Option<safe_storage::chaos::Chaos>,
+    /// where streamed uploads are written before they are stored, defaults to `.staging` inside
+    /// blob directory or a temporary directory
+    #[arg(long, value_name = "DIR")]
+    staging_dir: Option<PathBuf>,
+    /// how leaves are derived from file names and contents: content, name-content, name-hash,
+    /// chunked:BYTES or keyed:HEX, reported to clients by `GET /info`
+    #[arg(long, value_name = "STRATEGY", default_value = "content")]
+    leaf_hashing: LeafHashing,
+    /// how many big uploads can be hashed in parallel, defaults to available cpu count
+    #[arg(long, value_name = "COUNT", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    hash_threads: Option<usize>,
+    /// content codings request bodies are accepted in and api responses are compressed with, in
+    /// order of preference: zstd, gzip, or identity alone to never compress
+    #[arg(long, value_name = "CODINGS", default_value = "zstd,gzip")]
+    compression: Codings,
+    /// leaves indexed at once while secondary indexes of an existing data dir are rebuilt in
+    /// background after startup, progress is reported by `GET /admin/migrations`
+    #[arg(long, value_name = "LEAVES", default_value_t = DEFAULT_REBUILD_BATCH)]
+    rebuild_batch: usize,
+    /// how long idle connections are kept open, in seconds
+    #[arg(long, value_name = "SECS", default_value_t = 75, value_parser = clap::value_parser!(u64).range(1..))]
+    keep_alive: u64,
+    /// how often files with passed ttl are tombstoned and download counts are written, in seconds
+    #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    expiry_interval: u64,
+    /// tombstone files not downloaded for given amount of seconds, counted from upload until
+    /// the first download
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    idle_ttl: Option<u64>,
+    /// abandon leaf reservations not filled for given amount of seconds, their slots are
+    /// tombstoned as expired so files queued behind them get committed
+    #[arg(long, value_name = "SECS", default_value_t = 3600, value_parser = clap::value_parser!(u64).range(1..))]
+    reservation_ttl: u64,
+    /// how often stored files are checked against persisted root chain, in seconds
+    #[arg(long, value_name = "SECS", default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+    integrity_interval: u64,
+    /// seal current tree into a checkpointed epoch once it has given amount of leaves
+    #[arg(long, value_name = "COUNT")]
+    epoch_max_leaves: Option<usize>,
+    /// seal current tree into a checkpointed epoch once it is given amount of seconds old
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    epoch_max_age: Option<u64>,
+    /// keep at most given amount of leaves in the in-memory epoch tree, see `--on-full-tree`
+    #[arg(long, value_name = "COUNT", conflicts_with = "max_tree_depth")]
+    max_tree_leaves: Option<usize>,
+    /// same as `--max-tree-leaves` of 2^DEPTH leaves
+    #[arg(long, value_name = "DEPTH", value_parser = clap::value_parser!(u32).range(..64))]
+    max_tree_depth: Option<u32>,
+    /// what to do once epoch tree is full: refuse uploads, deletions and reservations with 507
+    /// Insufficient Storage, or seal the epoch and start a new tree
+    #[arg(long, value_name = "POLICY", default_value = "refuse")]
+    on_full_tree: WhenFull,
+    /// file with hex encoded ed25519 secret key to sign checkpoints of sealed epochs with, can be
+    /// repeated with keys used before rotation first and the active one last
+    #[arg(long, value_name = "FILE")]
+    signing_key: Vec<PathBuf>,
+    /// amount of file proofs computed on upload and kept up to date, 0 computes them on request
+    #[arg(long, value_name = "COUNT", default_value_t = DEFAULT_HOT_PROOFS)]
+    hot_proofs: usize,
+    /// base url of OpenTelemetry collector to export request spans to over OTLP/HTTP, e.g.
+    /// http://localhost:4318
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+    /// origin browser apps may call the api from, e.g. https://app.example.com or * for any, can
+    /// be repeated
+    #[arg(long, value_name = "ORIGIN")]
+    cors_origin: Vec<CorsOrigin>,
+    /// method allowed in cross-origin requests, can be repeated
+    #[arg(long, value_name = "METHOD", default_values = ["GET", "POST", "PUT", "DELETE"])]
+    cors_method: Vec<String>,
+    /// header allowed in cross-origin requests, can be repeated
+    #[arg(
+        long,
+        value_name = "HEADER",
+        default_values = ["authorization", "content-type", "accept", "traceparent"]
+    )]
+    cors_header: Vec<String>,
+    /// experimental: accept only content whose hash starts with a byte in given hex range, e.g.
+    /// 00-7f, as a shard of a cluster
+    #[arg(long, value_name = "RANGE")]
+    shard_range: Option<ShardRange>,
+    /// experimental: run as cluster router over shard servers at given urls instead of storing
+    /// files, repeated in shard order
+    #[arg(long, value_name = "URL", conflicts_with = "shard_range")]
+    shard: Vec<String>,
+}
+
+fn api_keys(cmd_args: &CmdArgs) -> ApiKeys {
+    let admin_keys = cmd_args
+        .admin_key
+        .iter()
+        .cloned()
+        .map(|key| ApiKey { admin: true, ..key });
+    ApiKeys::new(cmd_args.api_key.iter().cloned().chain(admin_keys).collect())
+}
+
+fn upload_interceptors(cmd_args: &CmdArgs) -> UploadInterceptors {
+    let mut interceptors = UploadInterceptors::new();
+    if !cmd_args.deny_extension.is_empty() {
+        interceptors = interceptors.with(DeniedExtensions(cmd_args.deny_extension.clone()));
+    }
+    if let Some(addr) = &cmd_args.clamav {
+        interceptors = interceptors.with(ClamAv { addr: addr.clone() });
+    }
+    interceptors
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    #[cfg(feature = "tokio-console")]
+    console_subscriber::init();
     let cmd_args = CmdArgs::parse();
+    if !cmd_args.shard.is_empty() {
+        let router = RouterConfig {
+            host: ServerConfig::default().host,
+            port: cmd_args.listen_port,
+            shards: cmd_args.shard,
+        };
+        return spawn_router(router)?.wait().await;
+    }
+
+    let defaults = ServerConfig::default();
+    let blobs: Box<dyn BlobStore> = match (&cmd_args.blob_dir, cmd_args.max_memory) {
+        (Some(dir), _) => Box::new(DiskBlobs::open(dir)?),
+        (None, Some(max_memory)) => Box::new(MemoryBlobs::with_budget(max_memory)),
+        (None, None) => defaults.blobs,
+    };
+    let blobs = if cmd_args.encrypt_at_rest {
+        let master_key =
+            MasterKey::from_env().map_err(|err| std::io::Error::other(err.to_string()))?;
+        Box::new(EncryptedBlobs::new(blobs, Box::new(master_key)))
+    } else {
+        blobs
+    };
+    let webhook = match &cmd_args.webhook_url {
+        Some(url) => {
+            let webhook =
+                Webhook::from_env(url).map_err(|err| std::io::Error::other(err.to_string()))?;
+            Some(match &cmd_args.webhook_cursor {
+                Some(path) => webhook.with_cursor_file(path),
+                None => webhook,
+            })
+        }
+        None => None,
+    };
+    let staging_dir = match (&cmd_args.staging_dir, &cmd_args.blob_dir) {
+        (Some(dir), _) => dir.clone(),
+        (None, Some(blob_dir)) => blob_dir.join(".staging"),
+        (None, None) => defaults.staging_dir.clone(),
+    };
+    let server_keys = cmd_args
+        .signing_key
+        .iter()
+        .map(|path| {
+            std::fs::read_to_string(path)?
+                .parse::<ServerKey>()
+                .map_err(|err| std::io::Error::other(err.to_string()))
+        })
+        .collect::<std::io::Result<_>>()?;
+    let config = ServerConfig {
+        port: cmd_args.listen_port,
+        listen: cmd_args.listen.clone(),
+        limit_rate: cmd_args.limit_rate,
+        max_upload_size: cmd_args.max_upload_size,
+        interceptors: upload_interceptors(&cmd_args),
+        importer: Importer::new(
+            cmd_args.import_scheme.clone(),
+            cmd_args.import_allow_host.clone(),
+            cmd_args.max_import_size,
+        ),
+        api_keys: api_keys(&cmd_args),
+        quota: Quota {
+            max_bytes: cmd_args.quota_bytes,
+            max_files: cmd_args.quota_files,
+        },
+        collision_policy: cmd_args.on_name_collision,
+        blobs,
+        hash_threads: cmd_args.hash_threads.unwrap_or(defaults.hash_threads),
+        keep_alive: Duration::from_secs(cmd_args.keep_alive),
+        expiry_interval: Duration::from_secs(cmd_args.expiry_interval),
+        integrity_interval: Duration::from_secs(cmd_args.integrity_interval),
+        epoch_policy: EpochPolicy {
+            max_leaves: cmd_args.epoch_max_leaves,
+            max_age: cmd_args.epoch_max_age.map(Duration::from_secs),
+        },
+        tree_limit: cmd_args
+            .max_tree_leaves
+            .or(cmd_args.max_tree_depth.map(|depth| 1 << depth))
+            .map(|max_leaves| TreeLimit {
+                max_leaves,
+                when_full: cmd_args.on_full_tree,
+            }),
+        server_keys,
+        hot_proofs: cmd_args.hot_proofs,
+        otlp_endpoint: cmd_args.otlp_endpoint,
+        cors: CorsConfig {
+            origins: cmd_args.cors_origin,
+            methods: cmd_args.cors_method,
+            headers: cmd_args.cors_header,
+            max_age: defaults.cors.max_age,
+        },
+        staging_dir,
+        shard: cmd_args.shard_range,
+        ipfs: cmd_args.ipfs_api.as_deref().map(IpfsNode::new),
+        leaf_hashing: cmd_args.leaf_hashing,
+        keep_deleted: cmd_args.keep_deleted,
+        search: cmd_args.search,
+        name_by_hash: cmd_args.name_by_hash,
+        webhook,
+        federation: (cmd_args.federation || !cmd_args.federate_with.is_empty()).then(|| {
+            FederationConfig {
+                peers: cmd_args.federate_with.clone(),
+                interval: Duration::from_secs(cmd_args.federation_interval),
+                api_key: std::env::var(FEDERATION_API_KEY_ENV).ok(),
+            }
+        }),
+        idle_ttl: cmd_args.idle_ttl.map(Duration::from_secs),
+        reservation_ttl: Some(Duration::from_secs(cmd_args.reservation_ttl)),
+        seed_dir: cmd_args.seed_dir,
+        restore_dir: cmd_args.restore_from,
+        backup_dir: cmd_args.backup_dir,
+        backup_interval: Duration::from_secs(cmd_args.backup_interval),
+        rebuild_batch: cmd_args.rebuild_batch,
+        codings: cmd_args.compression,
+        #[cfg(feature = "chaos")]
+        chaos: cmd_args.chaos,
+        ..defaults
+    };
+    let info = config.info();
+    let server = spawn(config)?;
+    print_banner(&info, &server.url());
+    server.wait().await
+}
 
-    let storage = web::Data::new(Mutex::new(Storage::new()));
-    HttpServer::new(move || {
-        App::new()
-            .app_data(storage.clone())
-            .service(get_file_list)
-            .service(upload_new_file)
-            .service(get_file_content)
-            .service(get_tree_root)
-    })
-    .bind(("0.0.0.0", cmd_args.listen_port))?
-    .run()
-    .await
+/// What clients see in `GET /info`, one `key: value` per line
+fn print_banner(info: &ServerInfo, url: &str) {
+    let features = &info.features;
+    let features: Vec<_> = [
+        ("chunked-leaves", features.chunked_leaves),
+        ("auth", features.auth),
+        ("signed-checkpoints", features.signed_checkpoints),
+        ("ipfs-export", features.ipfs_export),
+        ("undelete", features.undelete),
+        ("search", features.search),
+        ("name-by-hash", features.name_by_hash),
+        ("federation", features.federation),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    let limit = |limit: Option<u64>| limit.map_or("none".to_string(), |limit| limit.to_string());
+    let limits = &info.limits;
+    eprintln!("safe-storage {}", info.version);
+    eprintln!("listening: {url}");
+    eprintln!("protocol: {}", info.protocol);
+    eprintln!("hash algorithm: {}", info.hash_algorithm);
+    eprintln!("leaf hashing: {}", info.leaf_hashing);
+    eprintln!("tree mode: {}", info.tree_mode);
+    eprintln!("features: {}", features.join(", "));
+    eprintln!(
+        "limits: upload {} bytes, import {} bytes, body {} bytes, batch {} files, tree {} leaves",
+        limit(limits.max_upload_bytes),
+        limits.max_import_bytes,
+        limits.max_body_bytes,
+        limits.max_batch_files,
+        limit(limits.max_tree_leaves)
+    );
+    eprintln!(
+        "quota: {} bytes, {} files",
+        limit(limits.quota_bytes),
+        limit(limits.quota_files)
+    );
 }
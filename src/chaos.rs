@@ -0,0 +1,274 @@
+use crate::codec::{from_header_value, to_header_value, FILE_META_HEADER};
+use actix_web::body::{to_bytes, BodyStream, BoxBody, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+use actix_web::web::Bytes;
+use anyhow::anyhow;
+use serde_json::Value;
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Faults injected into responses of a server built with `chaos` feature, so client retries and
+/// verification can be tested end-to-end. Every request draws its faults independently.
+#[derive(Debug)]
+pub struct Chaos {
+    /// added before every response
+    pub delay: Duration,
+    /// fraction of downloaded contents and proofs with a flipped byte, json responses and raw
+    /// downloads only
+    pub corrupt: f64,
+    /// fraction of responses whose connection is dropped before the body is sent
+    pub drop: f64,
+    /// xorshift state, seeded for reproducible runs
+    rng: Mutex<u64>,
+}
+
+impl Chaos {
+    pub fn new(delay: Duration, corrupt: f64, drop: f64, seed: u64) -> Self {
+        Self {
+            delay,
+            corrupt,
+            drop,
+            // xorshift never leaves zero state
+            rng: Mutex::new(seed.max(1)),
+        }
+    }
+
+    fn next(&self) -> u64 {
+        let mut state = self.rng.lock().expect("should lock");
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn roll(&self, fraction: f64) -> bool {
+        // 53 random bits make a uniform float in [0, 1)
+        let draw = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        fraction > 0.0 && draw < fraction
+    }
+
+    fn pick(&self, len: usize) -> usize {
+        (self.next() % len as u64) as usize
+    }
+
+    /// Delays response, then drops or corrupts it if the draw says so
+    pub async fn apply<B: MessageBody + 'static>(
+        &self,
+        response: ServiceResponse<B>,
+    ) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        if self.roll(self.drop) {
+            // failing body stream makes the server abort the connection mid-response
+            return Ok(response.map_body(|_, _| {
+                BoxBody::new(BodyStream::new(futures_util::stream::once(async {
+                    Err::<Bytes, _>(io::Error::other("connection dropped by chaos"))
+                })))
+            }));
+        }
+        let path = response.request().path();
+        let downloaded = path.starts_with("/files/") || path.starts_with("/proofs/");
+        if !downloaded || !response.status().is_success() || !self.roll(self.corrupt) {
+            return Ok(response.map_into_boxed_body());
+        }
+        let (request, response) = response.into_parts();
+        let (mut response, body) = response.into_parts();
+        let body = to_bytes(body).await.map_err(|err| {
+            let err: Box<dyn std::error::Error> = err.into();
+            actix_web::error::ErrorInternalServerError(err.to_string())
+        })?;
+        let mut body = body.to_vec();
+        let headers = response.headers_mut();
+        let meta = headers
+            .get(FILE_META_HEADER)
+            .and_then(|meta| meta.to_str().ok())
+            .map(str::to_string);
+        if let Some(meta) = meta {
+            if body.is_empty() {
+                let corrupted = from_header_value::<Value>(&meta)
+                    .ok()
+                    .and_then(|mut meta| self.corrupt_json(&mut meta).then_some(meta))
+                    .and_then(|meta| to_header_value(&meta).ok())
+                    .and_then(|meta| HeaderValue::from_str(&meta).ok());
+                if let Some(meta) = corrupted {
+                    headers.insert(FILE_META_HEADER.parse().expect("valid header name"), meta);
+                }
+            } else {
+                let at = self.pick(body.len());
+                body[at] ^= 1;
+            }
+        } else if headers
+            .get(CONTENT_TYPE)
+            .is_some_and(|mime| mime.as_bytes().starts_with(b"application/json"))
+        {
+            if let Ok(mut value) = serde_json::from_slice::<Value>(&body) {
+                if self.corrupt_json(&mut value) {
+                    body = serde_json::to_vec(&value)?;
+                }
+            }
+        }
+        Ok(ServiceResponse::new(
+            request,
+            response.set_body(BoxBody::new(body)),
+        ))
+    }
+
+    /// Changes single character of base64 `content` or of a hash inside `proof`, so the value
+    /// still decodes but doesn't verify. False if there is nothing to corrupt.
+    fn corrupt_json(&self, value: &mut Value) -> bool {
+        let mut found = Vec::new();
+        corruptible(value, false, &mut found);
+        if found.is_empty() {
+            return false;
+        }
+        let at = self.pick(found.len());
+        let target = &mut found[at];
+        let mut chars: Vec<char> = target.chars().collect();
+        // last base64 quartet may carry padding, hashes are hex all the way
+        let len = if is_hash(target) {
+            chars.len()
+        } else {
+            chars.len() - 4
+        };
+        let at = self.pick(len);
+        chars[at] = if chars[at] == '0' || chars[at] == 'A' {
+            '1'
+        } else {
+            '0'
+        };
+        **target = chars.into_iter().collect();
+        true
+    }
+}
+
+/// Clone continues the same random sequence independently
+impl Clone for Chaos {
+    fn clone(&self) -> Self {
+        Chaos::new(
+            self.delay,
+            self.corrupt,
+            self.drop,
+            *self.rng.lock().expect("should lock"),
+        )
+    }
+}
+
+fn is_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn corruptible<'a>(value: &'a mut Value, in_proof: bool, found: &mut Vec<&'a mut String>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                let is_content = key == "content"
+                    && matches!(value, Value::String(content) if content.len() > 4);
+                match (is_content, value) {
+                    (true, Value::String(content)) => found.push(content),
+                    (_, value) => corruptible(value, in_proof || key == "proof", found),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                corruptible(item, in_proof, found);
+            }
+        }
+        Value::String(hash) if in_proof && is_hash(hash) => found.push(hash),
+        _ => {}
+    }
+}
+
+/// `delay=MS,corrupt=FRACTION,drop=FRACTION,seed=N`, every part is optional
+impl FromStr for Chaos {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut delay = Duration::ZERO;
+        let mut corrupt = 0.0;
+        let mut drop = 0.0;
+        let mut seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        for part in s.split(',').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected KEY=VALUE, got {part}"))?;
+            match key {
+                "delay" => delay = Duration::from_millis(value.parse()?),
+                "corrupt" => corrupt = fraction(value)?,
+                "drop" => drop = fraction(value)?,
+                "seed" => seed = value.parse()?,
+                _ => return Err(anyhow!("unknown chaos fault {key}")),
+            }
+        }
+        Ok(Chaos::new(delay, corrupt, drop, seed))
+    }
+}
+
+fn fraction(value: &str) -> anyhow::Result<f64> {
+    let fraction: f64 = value.parse()?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(anyhow!("fraction must be between 0 and 1, got {value}"));
+    }
+    Ok(fraction)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{FileContent, FileId};
+    use crate::codec::Codec;
+    use crate::merkle::Sha3Tree;
+    use crate::sha3::hash_content;
+
+    #[test]
+    fn test_parse_chaos() {
+        let chaos: Chaos = "delay=20,corrupt=0.5,drop=1,seed=7"
+            .parse()
+            .expect("should parse");
+        assert_eq!(chaos.delay, Duration::from_millis(20));
+        assert_eq!((chaos.corrupt, chaos.drop), (0.5, 1.0));
+        assert!(chaos.roll(1.0));
+        assert!(!chaos.roll(0.0));
+        let none: Chaos = "".parse().expect("should parse");
+        assert_eq!(
+            (none.delay, none.corrupt, none.drop),
+            (Duration::ZERO, 0.0, 0.0)
+        );
+        for invalid in ["corrupt=2", "drop=-0.1", "delay", "flood=1"] {
+            assert!(invalid.parse::<Chaos>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_corrupted_file_still_decodes_but_fails_verification() {
+        let contents = ["first", "second", "third"];
+        let tree = Sha3Tree::from_manifest(contents.map(hash_content));
+        let root = tree.root().expect("should have root");
+        let file = FileContent {
+            id: FileId(1),
+            name: "b.txt".to_string(),
+            content: b"second".to_vec(),
+            proof: tree.proof_for(1).expect("should prove"),
+            epoch: 0,
+        };
+        let chaos = Chaos::new(Duration::ZERO, 1.0, 0.0, 42);
+        for _ in 0..20 {
+            let mut value = serde_json::to_value(&file).expect("should serialize");
+            assert!(chaos.corrupt_json(&mut value));
+            let corrupted: FileContent = Codec::Json
+                .decode(&serde_json::to_vec(&value).expect("should serialize"))
+                .expect("should still decode");
+            assert!(!corrupted
+                .proof
+                .verify(&root, &hash_content(&corrupted.content)));
+        }
+        assert!(!chaos.corrupt_json(&mut serde_json::json!({"name": "a.txt"})));
+    }
+}
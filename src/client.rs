@@ -1,52 +1,1340 @@
-use crate::api::{File, FileContent, FileList, NewFile, RootHash};
+use crate::api::{
+    AttestationList, AttestationQuery, BatchRequest, BatchUploadFile, CheckpointList, ClusterInfo,
+    Consistency, ConsistencyQuery, Dataset, DatasetList, DatasetProof, DeletionReceipt,
+    EpochArchive, File, FileBatch, FileChanges, FileChangesQuery, FileContent, FileId, FileList,
+    FileListQuery, FileStatus, HashProof, HistoricalProof, HistoricalProofQuery, ImportFile,
+    ImportedEpoch, ImportedFile, IngestStatus, IpfsExport, KeyList, KeyRotation, LeafIndex,
+    LeafList, ListedLeaf, MigrationList, NewFile, NewFileBatch, PageQuery, PreviewQuery, PublicKey,
+    QuarantineQuery, RawFileMeta, Reservation, RootHash, SearchQuery, SearchResults, ServerInfo,
+    SignedRoot, StoredBatch, StoredFile, StreamQuery, SubmittedAttestation, TreeSize, Usage,
+    UsageList, HASH_ALGORITHM, PROTOCOL_VERSION,
+};
+use crate::codec::{from_header_value, Codec, FILE_META_HEADER, MAX_BODY_SIZE};
+use crate::compression::{Codings, MIN_COMPRESSED_SIZE};
+use crate::leaf::{name_hash_leaf, LeafHasher, LeafHashing};
+use crate::merkle::{Sha3Hash, Sha3Tree};
+use crate::sha3::{hash_content, Hasher};
+use crate::storage::DEFAULT_MIME;
+use crate::throttle::RateLimit;
+use crate::trace::{TraceContext, TRACEPARENT};
 use anyhow::anyhow;
-use reqwest::Response;
+use futures_util::future::try_join_all;
+use futures_util::stream;
+use reqwest::header::{
+    ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH,
+};
+use reqwest::{Method, Request, RequestBuilder, Response, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+#[cfg(unix)]
+use std::future::Future;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(unix)]
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+/// Size of chunks cached raw content is read in
+const CACHE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Wait before the first retry, doubled for every next one
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Base of request urls sent over unix sockets, host is only there for the `host` header
+const UNIX_API_BASE: &str = "http://localhost";
+
+/// Hooks around every request client sends, e.g. for logging, metrics or headers of custom
+/// authentication, registered with [`Client::with_middleware`]. Called in order of registration.
+pub trait ClientMiddleware: Send + Sync {
+    /// Called before every attempt, after the client added its own headers
+    fn on_request(&self, _request: &mut Request) {}
+
+    /// Called with every response received, successful or not, before its body is read
+    fn on_response(&self, _method: &Method, _response: &Response, _elapsed: Duration) {}
+
+    /// Called when request failed with given error and is sent again, `attempt` counts from 1
+    fn on_retry(&self, _request: &Request, _attempt: u32, _error: &anyhow::Error) {}
+}
+
+/// Server answered with unsuccessful status
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http error: {} body: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// Content, proof or root from the server doesn't verify
+#[derive(Debug)]
+pub struct VerificationError(pub String);
+
+impl Display for VerificationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+/// Outcome of checking listed name against trusted leaves, see
+/// [`Client::get_verified_file_list`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NameCheck {
+    /// leaf derived from listed name and content hash is the trusted leaf at its index
+    Verified,
+    /// leaf derived from listed name differs from the trusted one, server may have renamed the
+    /// file
+    Mismatch,
+    /// file is past the trusted leaves or was listed without content hash
+    Unproven,
+}
+
+/// How downloaded file was verified, returned so callers can log it
+#[derive(Debug, Clone)]
+pub struct VerificationSummary {
+    pub id: FileId,
+    pub epoch: u32,
+    /// content length
+    pub bytes: u64,
+    /// spent downloading and verifying content
+    pub elapsed: Duration,
+    pub leaf: Sha3Hash,
+    /// root of file epoch the proof of leaf was verified against
+    pub root: Sha3Hash,
+    /// chunks leaf was derived from when leaves are chunked, all of them verified by the proof
+    /// of leaf
+    pub chunks: Vec<ChunkSummary>,
+}
+
+impl VerificationSummary {
+    /// Bytes per second
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// What downloading a file costs besides its content, see [`Client::measure`]
+#[derive(Debug, Clone, Serialize)]
+pub struct Measurement {
+    pub id: FileId,
+    pub epoch: u32,
+    pub content_bytes: u64,
+    /// proof as encoded in the client codec
+    pub proof_bytes: u64,
+    /// hashes the proof is made of
+    pub proof_nodes: usize,
+    /// rest of the download: id, name, epoch, field names and overhead of encoding the content,
+    /// e.g. base64 in json
+    pub metadata_bytes: u64,
+    /// round trip of `GET /root`, which carries next to nothing
+    pub root_micros: u64,
+    /// round trip of `GET /files/{id}/status`, which carries the proof without content
+    pub proof_micros: u64,
+    /// round trip of `GET /files/{id}`, content with its proof
+    pub download_micros: u64,
+}
+
+impl Measurement {
+    /// Bytes downloaded with the content per byte of content
+    pub fn overhead(&self) -> f64 {
+        (self.proof_bytes + self.metadata_bytes) as f64 / self.content_bytes.max(1) as f64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkSummary {
+    pub offset: u64,
+    pub len: u64,
+    pub hash: Sha3Hash,
+}
 
 pub struct Client {
+    /// url the client was created with, may be a `unix://` one
+    server_url: String,
+    /// http base requests are sent to, placeholder host for `unix://` urls
     api_base: String,
     client: reqwest::Client,
+    /// sends requests over the socket of `unix://` urls instead of `client`
+    unix: Option<UnixTransport>,
+    rate_limit: Option<RateLimit>,
+    api_key: Option<String>,
+    ttl: Option<Duration>,
+    dataset: Option<String>,
+    codec: Codec,
+    codings: Codings,
+    trace: Option<TraceContext>,
+    leaf_hasher: Arc<dyn LeafHasher>,
+    cache_dir: Option<PathBuf>,
+    offline_proofs: bool,
+    names_by_hash: bool,
+    middleware: Vec<Arc<dyn ClientMiddleware>>,
+    retries: u32,
 }
 
 impl Client {
-    pub fn new(api_base: String) -> Self {
-        Self {
+    /// Talks to server at given `http(s)://` base url. Urls given by users may be `unix://` ones
+    /// as well, see [`Client::try_new`].
+    ///
+    /// # Panics
+    ///
+    /// On `unix://` urls on platforms without unix sockets
+    pub fn new(server_url: String) -> Self {
+        Self::try_new(server_url).expect("unix sockets should be supported")
+    }
+
+    /// Talks to server at given `http(s)://` base url or at `unix://PATH` socket, which is
+    /// connected to directly for every request. Fails on `unix://` urls on platforms without
+    /// unix sockets.
+    pub fn try_new(server_url: String) -> anyhow::Result<Self> {
+        let (api_base, unix) = match server_url.strip_prefix("unix://") {
+            Some(path) => (
+                UNIX_API_BASE.to_string(),
+                Some(UnixTransport::new(Path::new(path))?),
+            ),
+            None => (server_url.clone(), None),
+        };
+        Ok(Self {
+            server_url,
             api_base,
-            client: reqwest::Client::new(),
+            client: http_client(false),
+            unix,
+            rate_limit: None,
+            api_key: None,
+            ttl: None,
+            dataset: None,
+            codec: Codec::Json,
+            codings: Codings::none(),
+            trace: None,
+            leaf_hasher: LeafHashing::default().hasher(),
+            cache_dir: None,
+            offline_proofs: false,
+            names_by_hash: false,
+            middleware: Vec::new(),
+            retries: 0,
+        })
+    }
+
+    /// Base url of the server
+    pub fn server_url(&self) -> &str {
+        &self.server_url
+    }
+
+    /// Talks http/2 with prior knowledge, multiplexing concurrent requests over single connection,
+    /// servers serve unix sockets with http/1.1 only
+    pub fn with_http2(mut self, enabled: bool) -> Self {
+        self.client = http_client(enabled);
+        self
+    }
+
+    /// Sends given secret as bearer token with every request
+    pub fn with_api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    /// Adds hooks called around every request, after the ones added before
+    pub fn with_middleware(mut self, middleware: impl ClientMiddleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Sends `GET` requests failing before any response came up to `retries` more times, with
+    /// backoff doubling from 100ms. Uploads are never retried, they could get stored twice.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Limits bandwidth used by uploads and downloads of this client
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Format requests are sent in and responses asked for, binary formats save base64 overhead
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Compresses request bodies with the preferred of given codings and asks for responses
+    /// compressed with any of them. Server has to support the preferred one.
+    pub fn with_codings(mut self, codings: Codings) -> Self {
+        self.codings = codings;
+        self
+    }
+
+    /// Files uploaded from now on expire after given time on the server
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Files uploaded from now on join given dataset, see [`Dataset`]
+    pub fn with_dataset(mut self, dataset: Option<String>) -> Self {
+        self.dataset = dataset;
+        self
+    }
+
+    /// Every request is sent as a new span of given trace in `traceparent` header
+    pub fn with_trace(mut self, trace: Option<TraceContext>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Leaves of downloaded files are derived by given hasher, it has to match the server one
+    pub fn with_leaf_hasher(mut self, leaf_hasher: Arc<dyn LeafHasher>) -> Self {
+        self.leaf_hasher = leaf_hasher;
+        self
+    }
+
+    /// Keeps verified raw downloads in given directory, they are downloaded again only if the
+    /// server says their content tag changed. Cached content is verified like downloaded one.
+    pub fn with_cache_dir(mut self, cache_dir: Option<PathBuf>) -> Self {
+        self.cache_dir = cache_dir;
+        self
+    }
+
+    /// Historical proofs are taken from cache directory only, server is never asked for them, see
+    /// [`Client::verify_historical_proof`]
+    pub fn with_offline_proofs(mut self, offline: bool) -> Self {
+        self.offline_proofs = offline;
+        self
+    }
+
+    /// Downloaded files must be named by hex of their content hash, as servers started with
+    /// `--name-by-hash` name them
+    pub fn with_names_by_hash(mut self, names_by_hash: bool) -> Self {
+        self.names_by_hash = names_by_hash;
+        self
+    }
+
+    pub fn leaf_hasher(&self) -> &Arc<dyn LeafHasher> {
+        &self.leaf_hasher
+    }
+
+    /// Picks leaf hashing and naming by hash reported by the server, see [`Client::server_info`]
+    pub async fn detect_leaf_hashing(self) -> anyhow::Result<Self> {
+        let info = self.server_info().await?;
+        Ok(self
+            .with_leaf_hasher(info.leaf_hashing.hasher())
+            .with_names_by_hash(info.features.name_by_hash))
+    }
+
+    /// Server info, failing if the server speaks newer protocol or hashes with other algorithm
+    /// than this client, which would make every verification fail later. Servers without
+    /// `GET /info` are the oldest ones, hashing content alone.
+    pub async fn server_info(&self) -> anyhow::Result<ServerInfo> {
+        let info = match self.fetch_info().await {
+            Ok(info) => info,
+            Err(err)
+                if err
+                    .downcast_ref::<HttpError>()
+                    .is_some_and(|err| err.status == 404) =>
+            {
+                ServerInfo {
+                    version: "unknown".to_string(),
+                    protocol: PROTOCOL_VERSION,
+                    hash_algorithm: HASH_ALGORITHM.to_string(),
+                    leaf_hashing: LeafHashing::Content,
+                    tree_mode: Default::default(),
+                    features: Default::default(),
+                    limits: Default::default(),
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        if info.protocol > PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "server {} speaks protocol {}, this client supports up to {PROTOCOL_VERSION}, upgrade the client",
+                info.version,
+                info.protocol
+            ));
         }
+        if info.hash_algorithm != HASH_ALGORITHM {
+            return Err(anyhow!(
+                "server {} hashes with {}, this client verifies {HASH_ALGORITHM} only",
+                info.version,
+                info.hash_algorithm
+            ));
+        }
+        Ok(info)
     }
 
     pub async fn get_file_list(&self) -> anyhow::Result<FileList> {
+        self.list_files(false).await
+    }
+
+    /// Lists files, optionally including expired ones which are hidden by default
+    pub async fn list_files(&self, include_expired: bool) -> anyhow::Result<FileList> {
+        self.list_files_by(&FileListQuery {
+            include_expired,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Lists files sorted and grouped by the server
+    pub async fn list_files_by(&self, query: &FileListQuery) -> anyhow::Result<FileList> {
         let url = format!("{}/files", self.api_base);
-        self.get(url).await
+        let resp = self
+            .execute(self.request(Method::GET, &url).query(query))
+            .await?;
+        self.check_response(resp).await
+    }
+
+    /// Lists files page by page, at most `limit` of them in a request. All pages list the same
+    /// snapshot of the tree, files appended or deleted meanwhile don't shift them. Groups are
+    /// not asked for.
+    pub async fn list_files_paged(
+        &self,
+        query: FileListQuery,
+        limit: usize,
+    ) -> anyhow::Result<Vec<File>> {
+        let mut query = FileListQuery {
+            limit: Some(limit),
+            group: None,
+            ..query
+        };
+        let mut files = Vec::new();
+        loop {
+            let page = self.list_files_by(&query).await?;
+            files.extend(page.files);
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => return Ok(files),
+            }
+        }
     }
 
-    pub async fn upload_new_file(&self, filename: &str, content: &[u8]) -> anyhow::Result<File> {
+    /// Lists files with their leaves and checks each listed name against `leaves` the caller
+    /// trusts (e.g. its local state), which must hash to `root`. Names can only be checked with
+    /// name-hash leaves, which are derived from the name and the content hash.
+    pub async fn get_verified_file_list(
+        &self,
+        leaves: &[Sha3Hash],
+        root: &Sha3Hash,
+    ) -> anyhow::Result<Vec<(File, NameCheck)>> {
+        let strategy = self.leaf_hasher.strategy();
+        if strategy != LeafHashing::NameAndHash {
+            return Err(anyhow!(
+                "names can only be verified with name-hash leaves, not {strategy}"
+            ));
+        }
+        if Sha3Tree::from_manifest(leaves.iter().cloned())
+            .root()
+            .as_ref()
+            != Some(root)
+        {
+            return Err(anyhow!("provided leaves don't hash to root {root}"));
+        }
+        let list = self
+            .list_files_by(&FileListQuery {
+                leaves: true,
+                ..Default::default()
+            })
+            .await?;
+        // every file has a leaf of its own, so a leaf claimed by several listed files proves
+        // none of them
+        let mut claims = HashMap::new();
+        for leaf in list.files.iter().filter_map(|file| file.leaf.as_ref()) {
+            *claims.entry(leaf.leaf_index).or_insert(0) += 1;
+        }
+        Ok(list
+            .files
+            .into_iter()
+            .map(|file| {
+                let check = match &file.leaf {
+                    Some(leaf) if claims[&leaf.leaf_index] > 1 => NameCheck::Mismatch,
+                    _ => check_listed_name(&file, leaves),
+                };
+                (file, check)
+            })
+            .collect())
+    }
+
+    /// Files appended and ids deleted after `since_tree_size`, pass the returned tree size to the
+    /// next call instead of re-fetching the whole listing
+    pub async fn list_changes(&self, since_tree_size: TreeSize) -> anyhow::Result<FileChanges> {
+        let url = format!("{}/files/changes", self.api_base);
+        let request = self
+            .request(Method::GET, &url)
+            .query(&FileChangesQuery { since_tree_size });
+        let resp = self.execute(request).await?;
+        self.check_response(resp).await
+    }
+
+    /// Uploads file, answer carries root right after its leaf was appended
+    pub async fn upload_new_file(
+        &self,
+        filename: &str,
+        content: &[u8],
+    ) -> anyhow::Result<StoredFile> {
+        self.upload_new_file_at(filename, content, None).await
+    }
+
+    /// Same as [`Client::upload_new_file`], refused with `409 Conflict` unless server tree has
+    /// `expected_tree_size` leaves, i.e. no other writer appended since local state was updated
+    pub async fn upload_new_file_at(
+        &self,
+        filename: &str,
+        content: &[u8],
+        expected_tree_size: Option<TreeSize>,
+    ) -> anyhow::Result<StoredFile> {
         let url = format!("{}/files", self.api_base);
         self.post(
             url,
             NewFile {
                 content: content.to_vec(),
                 name: filename.to_string(),
+                ttl_secs: self.ttl.map(|ttl| ttl.as_secs()),
+                expected_tree_size,
+                dataset: self.dataset.clone(),
+            },
+        )
+        .await
+    }
+
+    /// Uploads named contents in one request, server stores all of them with consecutive leaves
+    /// or none. `expected_tree_size` works as in [`Client::upload_new_file_at`].
+    pub async fn upload_batch(
+        &self,
+        files: Vec<(String, Vec<u8>)>,
+        expected_tree_size: Option<TreeSize>,
+    ) -> anyhow::Result<StoredBatch> {
+        let url = format!("{}/files/batch/upload", self.api_base);
+        self.post(
+            url,
+            NewFileBatch {
+                files: files
+                    .into_iter()
+                    .map(|(name, content)| BatchUploadFile { content, name })
+                    .collect(),
+                ttl_secs: self.ttl.map(|ttl| ttl.as_secs()),
+                expected_tree_size,
+                dataset: self.dataset.clone(),
+            },
+        )
+        .await
+    }
+
+    /// Uploads content of file at `path` as raw body streamed from disk, so big files are never
+    /// held in memory on either side. `expected_tree_size` works as in
+    /// [`Client::upload_new_file_at`].
+    pub async fn upload_stream(
+        &self,
+        filename: &str,
+        path: &Path,
+        expected_tree_size: Option<TreeSize>,
+    ) -> anyhow::Result<StoredFile> {
+        let url = format!("{}/files/stream", self.api_base);
+        let file = tokio::fs::File::open(path).await?;
+        let request = self
+            .request(Method::POST, &url)
+            .query(&StreamQuery {
+                name: filename.to_string(),
+                ttl_secs: self.ttl.map(|ttl| ttl.as_secs()),
+                expected_tree_size,
+                dataset: self.dataset.clone(),
+            })
+            .header(CONTENT_TYPE, DEFAULT_MIME)
+            .body(self.file_body(file));
+        let resp = self.execute(request).await?;
+        self.check_response(resp).await
+    }
+
+    /// Streams raw file content into server quarantine, file is committed only once server
+    /// validated it, see [`Client::wait_for_file`]
+    pub async fn upload_quarantined(
+        &self,
+        filename: &str,
+        path: &Path,
+    ) -> anyhow::Result<FileStatus> {
+        let url = format!("{}/files/quarantine", self.api_base);
+        let file = tokio::fs::File::open(path).await?;
+        let request = self
+            .request(Method::POST, &url)
+            .query(&QuarantineQuery {
+                name: filename.to_string(),
+                ttl_secs: self.ttl.map(|ttl| ttl.as_secs()),
+            })
+            .header(CONTENT_TYPE, DEFAULT_MIME)
+            .body(self.file_body(file));
+        let resp = self.execute(request).await?;
+        self.check_response(resp).await
+    }
+
+    pub async fn fetch_file_status(&self, id: FileId) -> anyhow::Result<FileStatus> {
+        let url = format!("{}/files/{}/status", self.api_base, id);
+        self.get(url).await
+    }
+
+    /// Downloads file with its proof and the proof alone, timing both against a bare round trip
+    /// and splitting downloaded bytes into content, proof and the rest. Sizes are of the client
+    /// codec before compression. Nothing is verified.
+    pub async fn measure(&self, id: FileId) -> anyhow::Result<Measurement> {
+        let micros = |started: Instant| started.elapsed().as_micros() as u64;
+        let started = Instant::now();
+        self.fetch_root().await?;
+        let root_micros = micros(started);
+        let started = Instant::now();
+        self.fetch_file_status(id).await?;
+        let proof_micros = micros(started);
+        let started = Instant::now();
+        let file = self.fetch_file(id).await?;
+        let download_micros = micros(started);
+
+        let response_bytes = self.codec.encode(&file)?.len() as u64;
+        let proof_bytes = self.codec.encode(&file.proof)?.len() as u64;
+        let content_bytes = file.content.len() as u64;
+        Ok(Measurement {
+            id,
+            epoch: file.epoch,
+            content_bytes,
+            proof_bytes,
+            proof_nodes: file.proof.nodes().len(),
+            metadata_bytes: response_bytes.saturating_sub(proof_bytes + content_bytes),
+            root_micros,
+            proof_micros,
+            download_micros,
+        })
+    }
+
+    /// Polls status of the file every `interval` until it is committed or rejected
+    pub async fn wait_for_file(
+        &self,
+        id: FileId,
+        interval: Duration,
+    ) -> anyhow::Result<FileStatus> {
+        loop {
+            let status = self.fetch_file_status(id).await?;
+            if status.status != IngestStatus::Pending {
+                return Ok(status);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Asks server to fetch content from given url and store it, optionally under given name
+    pub async fn import_file(
+        &self,
+        source: &str,
+        name: Option<&str>,
+    ) -> anyhow::Result<ImportedFile> {
+        let url = format!("{}/files/import", self.api_base);
+        self.post(
+            url,
+            ImportFile {
+                url: source.to_string(),
+                name: name.map(str::to_string),
+                ttl_secs: self.ttl.map(|ttl| ttl.as_secs()),
+            },
+        )
+        .await
+    }
+
+    /// Uploads files one by one in given order over reused connection, order matters since it
+    /// defines leaf positions
+    pub async fn upload_many(
+        &self,
+        files: &[(String, Vec<u8>)],
+    ) -> anyhow::Result<Vec<StoredFile>> {
+        let mut uploaded = Vec::with_capacity(files.len());
+        for (filename, content) in files {
+            uploaded.push(self.upload_new_file(filename, content).await?);
+        }
+        Ok(uploaded)
+    }
+
+    /// Downloads all listed files concurrently, without verifying them
+    pub async fn download_all(&self) -> anyhow::Result<Vec<FileContent>> {
+        let files = self.get_file_list().await?.files;
+        try_join_all(files.iter().map(|file| self.fetch_file(file.id))).await
+    }
+
+    /// Downloads given files in one request, proven by a single multi-proof
+    pub async fn download_batch(&self, ids: &[FileId]) -> anyhow::Result<FileBatch> {
+        let url = format!("{}/files/batch", self.api_base);
+        self.post(url, BatchRequest { ids: ids.to_vec() }).await
+    }
+
+    /// Reserves leaf slot for the next upload, letting multiple uploads run in parallel while
+    /// knowing their leaf positions upfront
+    pub async fn reserve(&self) -> anyhow::Result<Reservation> {
+        let url = format!("{}/files/reserve", self.api_base);
+        self.post(url, ()).await
+    }
+
+    pub async fn upload_reserved_file(
+        &self,
+        id: FileId,
+        filename: &str,
+        content: &[u8],
+    ) -> anyhow::Result<StoredFile> {
+        let url = format!("{}/files/{}", self.api_base, id);
+        self.send(
+            Method::PUT,
+            url,
+            NewFile {
+                content: content.to_vec(),
+                name: filename.to_string(),
+                ttl_secs: self.ttl.map(|ttl| ttl.as_secs()),
+                expected_tree_size: None,
+                dataset: None,
             },
         )
         .await
     }
 
-    pub async fn download_file(&self, id: u32) -> anyhow::Result<FileContent> {
+    /// Downloads file without verifying it, e.g. to verify it against other than its epoch root
+    pub async fn fetch_file(&self, id: FileId) -> anyhow::Result<FileContent> {
         let url = format!("{}/files/{}", self.api_base, id);
         self.get(url).await
     }
 
+    /// Downloads file and checks that its content hashes to `expected`, a digest the caller
+    /// knows from elsewhere (e.g. a release manifest), regardless of its proof
+    pub async fn download_expecting(
+        &self,
+        id: FileId,
+        expected: &Sha3Hash,
+    ) -> anyhow::Result<FileContent> {
+        let file = self.fetch_file(id).await?;
+        expect_content_hash(id, &hash_content(&file.content), expected)?;
+        Ok(file)
+    }
+
+    /// Downloads file and checks its proof against root of file epoch given by `root_of`
+    pub async fn download_file(
+        &self,
+        id: FileId,
+        root_of: impl FnOnce(u32) -> anyhow::Result<Sha3Hash>,
+    ) -> anyhow::Result<(FileContent, VerificationSummary)> {
+        let started = Instant::now();
+        let file = self.fetch_file(id).await?;
+        let summary = self.verify_file(&file, started, root_of)?;
+        Ok((file, summary))
+    }
+
+    /// Checks proof of file downloaded since `started` against root of file epoch given by
+    /// `root_of`, e.g. after [`Client::download_expecting`]
+    pub fn verify_file(
+        &self,
+        file: &FileContent,
+        started: Instant,
+        root_of: impl FnOnce(u32) -> anyhow::Result<Sha3Hash>,
+    ) -> anyhow::Result<VerificationSummary> {
+        let id = file.id;
+        let root = root_of(file.epoch)?;
+        if self.names_by_hash {
+            expect_canonical_name(id, &file.name, &hash_content(&file.content))?;
+        }
+        let mut digest = self.leaf_hasher.start(&file.name);
+        digest.update(&file.content);
+        let (leaf, chunks) = digest.finalize_chunks();
+        if !file.proof.verify(&root, &leaf) {
+            return Err(VerificationError(format!("Verification of file {id} failed!")).into());
+        }
+        let summary = VerificationSummary {
+            id,
+            epoch: file.epoch,
+            bytes: file.content.len() as u64,
+            elapsed: started.elapsed(),
+            leaf,
+            root,
+            chunks: self.chunk_summaries(file.content.len(), chunks),
+        };
+        Ok(summary)
+    }
+
+    /// Offsets and lengths of chunk hashes reported by [`crate::leaf::LeafDigest::finalize_chunks`]
+    fn chunk_summaries(&self, bytes: usize, chunks: Vec<Sha3Hash>) -> Vec<ChunkSummary> {
+        let chunk_size = match self.leaf_hasher.strategy() {
+            LeafHashing::Chunked(size) => size,
+            _ => bytes,
+        };
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, hash)| {
+                let offset = i * chunk_size;
+                ChunkSummary {
+                    offset: offset as u64,
+                    len: chunk_size.min(bytes - offset) as u64,
+                    hash,
+                }
+            })
+            .collect()
+    }
+
+    /// Streams file content to given path while hashing it and only then checks its proof against
+    /// root of file epoch given by `root_of`, so content is never buffered whole. File is removed
+    /// if download or verification fails. Content cached by [`Client::with_cache_dir`] is reused if the
+    /// server reports it unchanged.
+    pub async fn download_verify_to(
+        &self,
+        id: FileId,
+        path: &Path,
+        root_of: impl FnOnce(u32) -> anyhow::Result<Sha3Hash>,
+    ) -> anyhow::Result<(RawFileMeta, VerificationSummary)> {
+        self.download_expecting_to(id, path, None, root_of).await
+    }
+
+    /// Same as [`Client::download_verify_to`], content is also hashed on the way and checked
+    /// against `expected` first, if given, like [`Client::download_expecting`] does. Cached
+    /// content failing the checks is dropped and downloaded again.
+    pub async fn download_expecting_to(
+        &self,
+        id: FileId,
+        path: &Path,
+        expected: Option<&Sha3Hash>,
+        root_of: impl FnOnce(u32) -> anyhow::Result<Sha3Hash>,
+    ) -> anyhow::Result<(RawFileMeta, VerificationSummary)> {
+        // root is asked for once, the download from server after a corrupted cache reuses it
+        let mut root_of = Some(root_of);
+        let mut known: Option<(u32, Sha3Hash)> = None;
+        let mut root_of = |epoch| match &known {
+            Some((known_epoch, root)) if *known_epoch == epoch => Ok(root.clone()),
+            _ => {
+                let root_of = root_of
+                    .take()
+                    .ok_or_else(|| anyhow!("file {id} changed epoch while downloading"))?;
+                let root = root_of(epoch)?;
+                known = Some((epoch, root.clone()));
+                Ok(root)
+            }
+        };
+        match self
+            .download_raw_to(id, path, expected, &mut root_of, true)
+            .await?
+        {
+            Some(downloaded) => Ok(downloaded),
+            None => Ok(self
+                .download_raw_to(id, path, expected, &mut root_of, false)
+                .await?
+                .expect("download without cache is never dropped")),
+        }
+    }
+
+    /// Single download of [`Client::download_expecting_to`], revalidating cached content if
+    /// `use_cache` is set. None is returned once cached content fails the checks or can't be read,
+    /// after it is dropped from cache.
+    async fn download_raw_to(
+        &self,
+        id: FileId,
+        path: &Path,
+        expected: Option<&Sha3Hash>,
+        root_of: &mut impl FnMut(u32) -> anyhow::Result<Sha3Hash>,
+        use_cache: bool,
+    ) -> anyhow::Result<Option<(RawFileMeta, VerificationSummary)>> {
+        let url = format!("{}/files/{id}/raw", self.api_base);
+        let cached = self
+            .cache_dir
+            .as_ref()
+            .map(|dir| (dir.join(id.to_string()), dir.join(format!("{id}.etag"))));
+        let etag = match &cached {
+            Some((_, etag_path)) if use_cache => tokio::fs::read_to_string(etag_path).await.ok(),
+            _ => None,
+        };
+        let mut request = self.request(Method::GET, &url);
+        if let Some(etag) = &etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let resp = self.execute(request).await?;
+        let not_modified = etag.is_some() && resp.status() == StatusCode::NOT_MODIFIED;
+        let resp = if not_modified {
+            resp
+        } else {
+            error_for_status(resp).await?
+        };
+        let meta = resp
+            .headers()
+            .get(FILE_META_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| anyhow!("response has no file metadata"))?;
+        let meta: RawFileMeta = from_header_value(meta)?;
+        let new_etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let root = root_of(meta.epoch)?;
+
+        let mut body = match &cached {
+            Some((content_path, etag_path)) if not_modified => {
+                match tokio::fs::File::open(content_path).await {
+                    Ok(file) => RawBody::Cached(file),
+                    Err(_) => {
+                        let _ = tokio::fs::remove_file(etag_path).await;
+                        return Ok(None);
+                    }
+                }
+            }
+            _ => RawBody::Remote(resp),
+        };
+        let mut file = tokio::fs::File::create(path).await?;
+        let started = Instant::now();
+        let written = async {
+            let mut digest = self.leaf_hasher.start(&meta.name);
+            let mut content_hasher = (expected.is_some() || self.names_by_hash).then(Hasher::new);
+            let mut received = 0;
+            while let Some(chunk) = body.chunk().await? {
+                digest.update(&chunk);
+                if let Some(hasher) = &mut content_hasher {
+                    hasher.update(&chunk);
+                }
+                file.write_all(&chunk).await?;
+                received += chunk.len();
+                if let (Some(limit), RawBody::Remote(_)) = (self.rate_limit, &body) {
+                    limit.pace(received, started).await;
+                }
+            }
+            file.flush().await?;
+            if let Some(hasher) = &mut content_hasher {
+                let content_hash = hasher.finalize();
+                if let Some(expected) = expected {
+                    expect_content_hash(id, &content_hash, expected)?;
+                }
+                if self.names_by_hash {
+                    expect_canonical_name(id, &meta.name, &content_hash)?;
+                }
+            }
+            let (leaf, chunks) = digest.finalize_chunks();
+            if !meta.proof.verify(&root, &leaf) {
+                return Err(VerificationError(format!("Verification of file {id} failed!")).into());
+            }
+            if let (Some((content_path, etag_path)), Some(new_etag), RawBody::Remote(_)) =
+                (&cached, new_etag, &body)
+            {
+                tokio::fs::create_dir_all(content_path.parent().unwrap_or(Path::new("."))).await?;
+                tokio::fs::copy(path, content_path).await?;
+                tokio::fs::write(etag_path, new_etag).await?;
+            }
+            Ok::<_, anyhow::Error>((received, leaf, chunks))
+        }
+        .await;
+        let (received, leaf, chunks) = match written {
+            Ok(written) => written,
+            Err(err) => {
+                drop(file);
+                let _ = tokio::fs::remove_file(path).await;
+                if let (Some((content_path, etag_path)), RawBody::Cached(_)) = (&cached, &body) {
+                    let _ = tokio::fs::remove_file(etag_path).await;
+                    let _ = tokio::fs::remove_file(content_path).await;
+                    return Ok(None);
+                }
+                return Err(err);
+            }
+        };
+        let summary = VerificationSummary {
+            id,
+            epoch: meta.epoch,
+            bytes: received as u64,
+            elapsed: started.elapsed(),
+            leaf,
+            root,
+            chunks: self.chunk_summaries(received, chunks),
+        };
+        Ok(Some((meta, summary)))
+    }
+
+    /// Content type and first bytes of file, as served to browsers, without proof
+    pub async fn preview_file(
+        &self,
+        id: FileId,
+        bytes: Option<usize>,
+    ) -> anyhow::Result<(String, Vec<u8>)> {
+        let url = format!("{}/files/{}/preview", self.api_base, id);
+        let request = self
+            .request(Method::GET, &url)
+            .query(&PreviewQuery { bytes });
+        let resp = self.execute(request).await?;
+        let resp = error_for_status(resp).await?;
+        let mime = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(DEFAULT_MIME)
+            .to_string();
+        Ok((mime, resp.bytes().await?.to_vec()))
+    }
+
+    pub async fn delete_file(&self, id: FileId) -> anyhow::Result<DeletionReceipt> {
+        let url = format!("{}/files/{}", self.api_base, id);
+        let resp = self.execute(self.request(Method::DELETE, &url)).await?;
+        self.check_response(resp).await
+    }
+
+    /// Appends content of deleted file again as a new file, which is answered with its root
+    pub async fn undelete_file(&self, id: FileId) -> anyhow::Result<StoredFile> {
+        let url = format!("{}/files/{}/undelete", self.api_base, id);
+        let resp = self.execute(self.request(Method::POST, &url)).await?;
+        self.check_response(resp).await
+    }
+
+    /// Lets server pin content of the file to its IPFS node, CID is kept with the file afterwards
+    pub async fn export_to_ipfs(&self, id: FileId) -> anyhow::Result<IpfsExport> {
+        let url = format!("{}/files/{}/ipfs", self.api_base, id);
+        let resp = self.execute(self.request(Method::POST, &url)).await?;
+        self.check_response(resp).await
+    }
+
+    pub async fn fetch_deletion_receipt(&self, id: FileId) -> anyhow::Result<DeletionReceipt> {
+        let url = format!("{}/files/{}/receipt", self.api_base, id);
+        self.get(url).await
+    }
+
+    pub async fn fetch_usage(&self) -> anyhow::Result<Usage> {
+        let url = format!("{}/usage", self.api_base);
+        self.get(url).await
+    }
+
+    pub async fn fetch_all_usage(&self) -> anyhow::Result<UsageList> {
+        let url = format!("{}/admin/usage", self.api_base);
+        self.get(url).await
+    }
+
+    /// Progress of background index rebuilds, admin key required
+    pub async fn fetch_migrations(&self) -> anyhow::Result<MigrationList> {
+        let url = format!("{}/admin/migrations", self.api_base);
+        self.get(url).await
+    }
+
+    pub async fn fetch_leaves(&self) -> anyhow::Result<LeafList> {
+        let url = format!("{}/leaves", self.api_base);
+        self.get(url).await
+    }
+
+    /// Same as [`Client::fetch_leaves`] asking for at most `limit` leaves at a time, all of them
+    /// from the tree as it was when the first page was listed
+    pub async fn fetch_leaves_paged(&self, limit: usize) -> anyhow::Result<LeafList> {
+        let url = format!("{}/leaves", self.api_base);
+        let mut query = PageQuery {
+            limit: Some(limit),
+            cursor: None,
+        };
+        let mut leaves = Vec::new();
+        loop {
+            let resp = self
+                .execute(self.request(Method::GET, &url).query(&query))
+                .await?;
+            let page: LeafList = self.check_response(resp).await?;
+            leaves.extend(page.leaves);
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => {
+                    return Ok(LeafList {
+                        leaves,
+                        next_cursor: None,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Text files containing `query`, with their leaves to check against trusted ones. Fails with
+    /// `501 Not Implemented` unless server was started with search enabled.
+    pub async fn search(&self, query: &str, limit: Option<usize>) -> anyhow::Result<SearchResults> {
+        let url = format!("{}/search", self.api_base);
+        let request = self.request(Method::GET, &url).query(&SearchQuery {
+            q: query.to_string(),
+            limit,
+        });
+        let resp = self.execute(request).await?;
+        self.check_response(resp).await
+    }
+
+    /// Shards with their roots and cluster root, answered by cluster router
+    pub async fn fetch_cluster(&self) -> anyhow::Result<ClusterInfo> {
+        let url = format!("{}/cluster", self.api_base);
+        self.get(url).await
+    }
+
+    pub async fn fetch_info(&self) -> anyhow::Result<ServerInfo> {
+        let url = format!("{}/info", self.api_base);
+        self.get(url).await
+    }
+
     pub async fn fetch_root(&self) -> anyhow::Result<RootHash> {
         let url = format!("{}/root", self.api_base);
         self.get(url).await
     }
 
+    /// Proof that tree of `old_size` leaves is a prefix of tree of `new_size` leaves
+    pub async fn fetch_consistency(
+        &self,
+        old_size: TreeSize,
+        new_size: TreeSize,
+    ) -> anyhow::Result<Consistency> {
+        self.fetch_epoch_consistency(None, old_size, new_size).await
+    }
+
+    /// Same as [`Client::fetch_consistency`] within given epoch, current one if none is given
+    pub async fn fetch_epoch_consistency(
+        &self,
+        epoch: Option<u32>,
+        old_size: TreeSize,
+        new_size: TreeSize,
+    ) -> anyhow::Result<Consistency> {
+        let url = format!("{}/consistency", self.api_base);
+        let query = ConsistencyQuery {
+            old_size,
+            new_size: Some(new_size),
+            epoch,
+        };
+        let resp = self
+            .execute(self.request(Method::GET, &url).query(&query))
+            .await?;
+        self.check_response(resp).await
+    }
+
+    /// Checkpoints of sealed epochs and key they are signed with
+    pub async fn fetch_epochs(&self) -> anyhow::Result<CheckpointList> {
+        let url = format!("{}/epochs", self.api_base);
+        self.get(url).await
+    }
+
+    /// Proof of file against root of its epoch tree as it was when it had `tree_size` leaves
+    pub async fn fetch_historical_proof(
+        &self,
+        id: FileId,
+        tree_size: TreeSize,
+    ) -> anyhow::Result<HistoricalProof> {
+        let url = format!("{}/files/{id}/proof", self.api_base);
+        let request = self
+            .request(Method::GET, &url)
+            .query(&HistoricalProofQuery { tree_size });
+        let resp = self.execute(request).await?;
+        self.check_response(resp).await
+    }
+
+    /// Checks proof of file `id` against `root` of the epoch tree of `tree_size` leaves, for leaf
+    /// `leaf_of` gives at index the proof is made for. Verified proofs are kept in cache
+    /// directory keyed by file id and tree size, so checking them again against pinned roots, e.g.
+    /// by scheduled audits, works without the server. True is returned with proofs from cache.
+    /// Cached proofs which are corrupted or don't verify are dropped and fetched again, unless
+    /// proofs are offline.
+    pub async fn verify_historical_proof(
+        &self,
+        id: FileId,
+        tree_size: TreeSize,
+        leaf_of: impl Fn(LeafIndex) -> Option<Sha3Hash>,
+        root: &Sha3Hash,
+    ) -> anyhow::Result<(HistoricalProof, bool)> {
+        let cached = self
+            .cache_dir
+            .as_ref()
+            .map(|dir| dir.join("proofs").join(format!("{id}-{tree_size}.json")));
+        let verified = |proof: &HistoricalProof| {
+            (proof.id, proof.tree_size) == (id, tree_size)
+                && proof.root == *root
+                && leaf_of(proof.leaf_index).is_some_and(|leaf| proof.proof.verify(root, &leaf))
+        };
+        let from_cache = match &cached {
+            Some(path) => match tokio::fs::read(path).await {
+                Ok(bytes) => match serde_json::from_slice::<HistoricalProof>(&bytes) {
+                    Ok(proof) if self.offline_proofs || verified(&proof) => Some(proof),
+                    _ => {
+                        tokio::fs::remove_file(path).await?;
+                        None
+                    }
+                },
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            },
+            None => None,
+        };
+        let was_cached = from_cache.is_some();
+        let proof = match from_cache {
+            Some(proof) => proof,
+            None if self.offline_proofs && cached.is_none() => {
+                return Err(anyhow!("offline proofs need a cache directory"))
+            }
+            None if self.offline_proofs => {
+                return Err(anyhow!(
+                    "proof of file {id} at tree size {tree_size} is not cached"
+                ))
+            }
+            None => self.fetch_historical_proof(id, tree_size).await?,
+        };
+        if !verified(&proof) {
+            return Err(VerificationError(format!(
+                "Proof of file {id} doesn't match root of {tree_size} leaves"
+            ))
+            .into());
+        }
+        if let (Some(path), false) = (&cached, was_cached) {
+            tokio::fs::create_dir_all(path.parent().unwrap_or(Path::new("."))).await?;
+            tokio::fs::write(path, serde_json::to_vec(&proof)?).await?;
+        }
+        Ok((proof, was_cached))
+    }
+
+    pub async fn fetch_datasets(&self) -> anyhow::Result<DatasetList> {
+        let url = format!("{}/datasets", self.api_base);
+        self.get(url).await
+    }
+
+    /// Dataset with its root checked against trusted main tree root of the epoch it is proven
+    /// in, which `root_of` gives, e.g. a pinned root or a trusted checkpoint
+    pub async fn fetch_dataset(
+        &self,
+        name: &str,
+        root_of: impl Fn(u32) -> anyhow::Result<Sha3Hash>,
+    ) -> anyhow::Result<Dataset> {
+        let dataset: Dataset = self.get(self.dataset_url(name, &[])?).await?;
+        let root = root_of(dataset.epoch)?;
+        if !dataset.verify(&root) {
+            return Err(VerificationError(format!(
+                "Dataset {name} is not committed to trusted root {root}"
+            ))
+            .into());
+        }
+        Ok(dataset)
+    }
+
+    /// Downloads file and checks it against root of given dataset, which is checked against the
+    /// trusted main tree root `root_of` gives for its epoch in turn, as with
+    /// [`Client::fetch_dataset`]. Verified dataset root covers exactly the files in the dataset.
+    pub async fn download_dataset_file(
+        &self,
+        name: &str,
+        id: FileId,
+        root_of: impl Fn(u32) -> anyhow::Result<Sha3Hash>,
+    ) -> anyhow::Result<(FileContent, DatasetProof)> {
+        let url = self.dataset_url(name, &["proofs", &id.to_string()])?;
+        let (file, proof) = tokio::try_join!(self.fetch_file(id), self.get::<DatasetProof>(url))?;
+        let root = root_of(proof.dataset.epoch)?;
+        let leaf = self.leaf_hasher.leaf(&file.name, &file.content);
+        if proof.id != id || !proof.verify(&leaf, &root) {
+            return Err(VerificationError(format!(
+                "Verification of file {id} in dataset {name} failed!"
+            ))
+            .into());
+        }
+        Ok((file, proof))
+    }
+
+    /// Dataset names may have characters which have to be escaped in a path
+    fn dataset_url(&self, name: &str, rest: &[&str]) -> anyhow::Result<String> {
+        let mut url = reqwest::Url::parse(&self.api_base)?;
+        url.path_segments_mut()
+            .map_err(|()| anyhow!("{} can't be a base url", self.api_base))?
+            .pop_if_empty()
+            .extend(["datasets", name])
+            .extend(rest);
+        Ok(url.to_string())
+    }
+
+    /// Proof of the first leaf with given content hash, fails with 404 if server has none
+    pub async fn fetch_proof_by_hash(&self, hash: &Sha3Hash) -> anyhow::Result<HashProof> {
+        let url = format!("{}/proofs/by-hash/{hash}", self.api_base);
+        self.get(url).await
+    }
+
+    /// Keys checkpoints are or were signed with, active one first
+    pub async fn fetch_keys(&self) -> anyhow::Result<KeyList> {
+        let url = format!("{}/keys", self.api_base);
+        self.get(url).await
+    }
+
+    /// Makes server sign new checkpoints with key read from given file on the server, requires
+    /// admin api key
+    pub async fn rotate_key(&self, key_file: &Path) -> anyhow::Result<PublicKey> {
+        let url = format!("{}/admin/keys/rotate", self.api_base);
+        self.post(
+            url,
+            KeyRotation {
+                key_file: key_file.to_path_buf(),
+            },
+        )
+        .await
+    }
+
+    /// Sealed epoch with its leaves and file contents
+    pub async fn export_epoch(&self, epoch: u32) -> anyhow::Result<EpochArchive> {
+        let url = format!("{}/epochs/{epoch}/archive", self.api_base);
+        self.get(url).await
+    }
+
+    /// Loads exported epoch back, requires admin api key
+    pub async fn import_epoch(&self, archive: &EpochArchive) -> anyhow::Result<ImportedEpoch> {
+        let url = format!("{}/admin/epochs/import", self.api_base);
+        self.post(url, archive).await
+    }
+
+    /// Hands signed root of another server to this one, refused with `422 Unprocessable Entity`
+    /// if the signature doesn't hold. Fails with `501 Not Implemented` unless server federates.
+    pub async fn submit_attestation(
+        &self,
+        root: &SignedRoot,
+    ) -> anyhow::Result<SubmittedAttestation> {
+        let url = format!("{}/attestations", self.api_base);
+        self.post(url, root).await
+    }
+
+    /// Roots peers submitted to server and equivocations among them, only of given hex encoded
+    /// public key if any
+    pub async fn fetch_attestations(
+        &self,
+        public_key: Option<&str>,
+    ) -> anyhow::Result<AttestationList> {
+        let url = format!("{}/attestations", self.api_base);
+        let request = self.request(Method::GET, &url).query(&AttestationQuery {
+            public_key: public_key.map(str::to_string),
+        });
+        let resp = self.execute(request).await?;
+        self.check_response(resp).await
+    }
+
+    /// Decodes successful response, decompressing it first if the server compressed it. Server
+    /// compresses bodies up to [`MAX_BODY_SIZE`] only, so bodies decompressing into more than that
+    /// or than they were received in are refused.
+    async fn check_response<T: DeserializeOwned>(&self, resp: Response) -> anyhow::Result<T> {
+        let mut resp = error_for_status(resp).await?;
+        // server answers in json if it doesn't support the asked format
+        let codec = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Codec::from_mime)
+            .unwrap_or_default();
+        let content_encoding = resp
+            .headers()
+            .get(CONTENT_ENCODING)
+            .map(|value| value.to_str().unwrap_or_default().to_string());
+        let started = Instant::now();
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if let Some(limit) = self.rate_limit {
+                limit.pace(body.len(), started).await;
+            }
+        }
+        let body = self.codings.decode(
+            content_encoding.as_deref(),
+            &body,
+            MAX_BODY_SIZE.max(body.len()),
+        )?;
+        Ok(codec.decode(&body)?)
+    }
+
     async fn get<R: DeserializeOwned>(&self, url: String) -> anyhow::Result<R> {
-        let resp = self.client.get(&url).send().await?;
-        check_response(resp).await
+        let resp = self.execute(self.request(Method::GET, &url)).await?;
+        self.check_response(resp).await
     }
 
     async fn post<B: Serialize, R: DeserializeOwned>(
@@ -54,17 +1342,327 @@ impl Client {
         url: String,
         body: B,
     ) -> anyhow::Result<R> {
-        let resp = self.client.post(&url).json(&body).send().await?;
-        check_response(resp).await
+        self.send(Method::POST, url, body).await
+    }
+
+    async fn send<B: Serialize, R: DeserializeOwned>(
+        &self,
+        method: Method,
+        url: String,
+        body: B,
+    ) -> anyhow::Result<R> {
+        let body = self.codec.encode(&body)?;
+        let request = self
+            .request(method, &url)
+            .header(CONTENT_TYPE, self.codec.mime());
+        let (request, body) = match self.codings.preferred() {
+            Some(coding) if body.len() >= MIN_COMPRESSED_SIZE => (
+                request.header(CONTENT_ENCODING, coding.name()),
+                coding.encode(&body)?,
+            ),
+            _ => (request, body),
+        };
+        let request = match self.rate_limit {
+            Some(limit) => request
+                .header(CONTENT_LENGTH, body.len())
+                .body(paced_body(Cursor::new(body), limit)),
+            None => request.body(body),
+        };
+        let resp = self.execute(request).await?;
+        self.check_response(resp).await
+    }
+
+    /// Body of a streamed upload, paced when bandwidth is limited
+    fn file_body(&self, file: tokio::fs::File) -> reqwest::Body {
+        match self.rate_limit {
+            Some(limit) => paced_body(file, limit),
+            None => file.into(),
+        }
+    }
+
+    /// Sends request through registered middleware, retrying it as set by
+    /// [`Client::with_retries`]
+    async fn execute(&self, request: RequestBuilder) -> anyhow::Result<Response> {
+        let mut request = request.build()?;
+        let mut attempt = 0;
+        loop {
+            // streamed bodies can't be cloned, such requests are sent once. Copy is taken before
+            // middleware sees the request, so it doesn't get the same headers added twice.
+            let retry = match request.method() {
+                &Method::GET if attempt < self.retries => request.try_clone(),
+                _ => None,
+            };
+            for middleware in &self.middleware {
+                middleware.on_request(&mut request);
+            }
+            let method = request.method().clone();
+            let started = Instant::now();
+            let sent = match &self.unix {
+                Some(unix) => unix.send(request).await,
+                None => self.client.execute(request).await.map_err(Into::into),
+            };
+            match (sent, retry) {
+                (Ok(resp), _) => {
+                    for middleware in &self.middleware {
+                        middleware.on_response(&method, &resp, started.elapsed());
+                    }
+                    return Ok(resp);
+                }
+                (Err(err), Some(retry)) => {
+                    attempt += 1;
+                    for middleware in &self.middleware {
+                        middleware.on_retry(&retry, attempt, &err);
+                    }
+                    tokio::time::sleep(RETRY_BACKOFF * 2u32.pow((attempt - 1).min(10))).await;
+                    request = retry;
+                }
+                (Err(err), None) => return Err(err),
+            }
+        }
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, url)
+            .header(ACCEPT, self.codec.mime());
+        let request = match self.codings.accept_encoding() {
+            Some(accept_encoding) => request.header(ACCEPT_ENCODING, accept_encoding),
+            None => request,
+        };
+        let request = match &self.trace {
+            Some(trace) => request.header(TRACEPARENT, trace.child().to_string()),
+            None => request,
+        };
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+}
+
+/// Body reading `content` in [`RateLimit::chunk_size`] chunks, each of them read only once the
+/// ones before it were sent within the limit, so uploads don't go out in a burst
+fn paced_body(
+    content: impl AsyncRead + Send + Sync + Unpin + 'static,
+    limit: RateLimit,
+) -> reqwest::Body {
+    let chunk_size = limit.chunk_size();
+    let state = (Some(content), Instant::now(), 0usize);
+    reqwest::Body::wrap_stream(stream::unfold(
+        state,
+        move |(content, started, sent)| async move {
+            let mut content = content?;
+            limit.pace(sent, started).await;
+            let mut chunk = vec![0; chunk_size];
+            match content.read(&mut chunk).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    Some((Ok(chunk), (Some(content), started, sent + read)))
+                }
+                // stream ends after the error, the request fails with it
+                Err(err) => Some((Err(err), (None, started, sent))),
+            }
+        },
+    ))
+}
+
+/// Raw file content being downloaded, either from the server or from local cache the server
+/// reported to be still current
+enum RawBody {
+    Remote(Response),
+    Cached(tokio::fs::File),
+}
+
+impl RawBody {
+    async fn chunk(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        match self {
+            RawBody::Remote(resp) => Ok(resp.chunk().await?.map(|chunk| chunk.to_vec())),
+            RawBody::Cached(file) => {
+                let mut chunk = vec![0; CACHE_CHUNK_SIZE];
+                let read = file.read(&mut chunk).await?;
+                chunk.truncate(read);
+                Ok(Some(chunk).filter(|chunk| !chunk.is_empty()))
+            }
+        }
+    }
+}
+
+/// Whether trusted leaf at listed index is the leaf of listed name and content hash
+fn check_listed_name(file: &File, leaves: &[Sha3Hash]) -> NameCheck {
+    let Some(ListedLeaf {
+        leaf_index,
+        hash,
+        content_hash: Some(content_hash),
+    }) = &file.leaf
+    else {
+        return NameCheck::Unproven;
+    };
+    let derived = name_hash_leaf(&file.name, content_hash);
+    match leaves.get(leaf_index.as_usize()) {
+        Some(trusted) if *trusted == derived && *hash == derived => NameCheck::Verified,
+        Some(_) => NameCheck::Mismatch,
+        None => NameCheck::Unproven,
+    }
+}
+
+fn expect_content_hash(id: FileId, actual: &Sha3Hash, expected: &Sha3Hash) -> anyhow::Result<()> {
+    if actual != expected {
+        return Err(VerificationError(format!(
+            "Content of file {id} hashes to {actual}, {expected} expected"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn expect_canonical_name(id: FileId, name: &str, content_hash: &Sha3Hash) -> anyhow::Result<()> {
+    if *name != content_hash.to_string() {
+        return Err(VerificationError(format!(
+            "File {id} is named {name}, not by its content hash {content_hash}"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Sends requests over a unix socket, connecting to it for every request so no listener is
+/// left behind. Server answers over unix sockets with http/1.1 only.
+#[cfg(unix)]
+struct UnixTransport {
+    client: hyper::Client<UnixConnector>,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    fn new(socket: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: hyper::Client::builder().build(UnixConnector(socket.into())),
+        })
+    }
+
+    async fn send(&self, mut request: Request) -> anyhow::Result<Response> {
+        let body = match request.body_mut().take() {
+            Some(body) => match body.as_bytes() {
+                Some(bytes) => hyper::Body::from(bytes.to_vec()),
+                // reqwest doesn't give out streams of its bodies, but reads any of them as
+                // the body of a response
+                None => hyper::Body::wrap_stream(
+                    Response::from(hyper::Response::new(body)).bytes_stream(),
+                ),
+            },
+            None => hyper::Body::empty(),
+        };
+        let mut outgoing = hyper::Request::new(body);
+        *outgoing.method_mut() = request.method().clone();
+        *outgoing.uri_mut() = request.url().as_str().parse()?;
+        *outgoing.headers_mut() = std::mem::take(request.headers_mut());
+        Ok(Response::from(self.client.request(outgoing).await?))
+    }
+}
+
+/// Platforms without unix sockets never construct it
+#[cfg(not(unix))]
+enum UnixTransport {}
+
+#[cfg(not(unix))]
+impl UnixTransport {
+    fn new(_socket: &Path) -> anyhow::Result<Self> {
+        Err(anyhow!("unix sockets are not supported on this platform"))
+    }
+
+    async fn send(&self, _request: Request) -> anyhow::Result<Response> {
+        match *self {}
+    }
+}
+
+/// Connects to the socket whatever host the request is for
+#[cfg(unix)]
+#[derive(Clone)]
+struct UnixConnector(Arc<Path>);
+
+#[cfg(unix)]
+impl tower_service::Service<hyper::Uri> for UnixConnector {
+    type Response = UnixConnection;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<UnixConnection>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
+
+    fn call(&mut self, _uri: hyper::Uri) -> Self::Future {
+        let socket = self.0.clone();
+        Box::pin(async move {
+            Ok(UnixConnection(
+                tokio::net::UnixStream::connect(&*socket).await?,
+            ))
+        })
+    }
+}
+
+#[cfg(unix)]
+struct UnixConnection(tokio::net::UnixStream);
+
+#[cfg(unix)]
+impl hyper::client::connect::Connection for UnixConnection {
+    fn connected(&self) -> hyper::client::connect::Connected {
+        hyper::client::connect::Connected::new()
+    }
+}
+
+#[cfg(unix)]
+impl tokio::io::AsyncRead for UnixConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+#[cfg(unix)]
+impl tokio::io::AsyncWrite for UnixConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
+
+fn http_client(http2: bool) -> reqwest::Client {
+    let builder = reqwest::Client::builder()
+        .pool_idle_timeout(Duration::from_secs(60))
+        .pool_max_idle_per_host(16)
+        .tcp_keepalive(Duration::from_secs(30))
+        .tcp_nodelay(true);
+    let builder = if http2 {
+        builder.http2_prior_knowledge()
+    } else {
+        builder
+    };
+    builder
+        .build()
+        .expect("http client configuration should be valid")
 }
 
-async fn check_response<T: DeserializeOwned>(resp: Response) -> anyhow::Result<T> {
+async fn error_for_status(resp: Response) -> anyhow::Result<Response> {
     if !resp.status().is_success() {
-        let code = resp.status();
-        let text = resp.text().await?;
-        return Err(anyhow!("http error: {} body: {}", code, text));
+        let status = resp.status().as_u16();
+        let body = resp.text().await?;
+        return Err(HttpError { status, body }.into());
     }
-    let res = resp.json().await?;
-    Ok(res)
+    Ok(resp)
 }
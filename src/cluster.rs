@@ -0,0 +1,158 @@
+use crate::api::{shard_leaf, ClusterInfo, FileContent, FileId, RootHash, ShardInfo, StoredFile};
+use crate::client::{Client, HttpError, VerificationError};
+use crate::merkle::{Sha3Hash, Sha3Tree};
+use crate::storage::ShardRange;
+use anyhow::anyhow;
+use futures_util::future::try_join_all;
+
+/// Tree over shard roots in shard order, its root is the root of the whole cluster
+pub fn cluster_tree(roots: &[Option<RootHash>]) -> Sha3Tree {
+    Sha3Tree::from_manifest(roots.iter().map(|root| shard_leaf(root.as_ref())))
+}
+
+/// Thin router of experimental sharded cluster. It keeps no content, shards own content split
+/// evenly by the first byte of its hash (see [`ShardRange::of`]) and router only combines their
+/// roots into cluster root.
+pub struct Router {
+    shards: Vec<Client>,
+}
+
+impl Router {
+    pub fn new(shard_urls: Vec<String>) -> Self {
+        Self {
+            shards: shard_urls.into_iter().map(Client::new).collect(),
+        }
+    }
+
+    /// Fetches current roots of all shards and proves each of them against cluster root
+    pub async fn status(&self) -> anyhow::Result<ClusterInfo> {
+        let roots = try_join_all(self.shards.iter().map(fetch_shard_root)).await?;
+        let tree = cluster_tree(&roots);
+        let root = tree
+            .root()
+            .ok_or_else(|| anyhow!("cluster has no shards"))?;
+        let count = self.shards.len();
+        let shards = self
+            .shards
+            .iter()
+            .zip(roots)
+            .enumerate()
+            .map(|(index, (shard, root))| {
+                let range = ShardRange::of(index, count);
+                ShardInfo {
+                    url: shard.server_url().to_string(),
+                    first_byte: range.first,
+                    last_byte: range.last,
+                    root,
+                    proof: tree.proof_for(index).expect("every shard has a leaf"),
+                }
+            })
+            .collect();
+        Ok(ClusterInfo { shards, root })
+    }
+}
+
+/// Root of the current shard epoch, none while shard has no files
+async fn fetch_shard_root(shard: &Client) -> anyhow::Result<Option<RootHash>> {
+    match shard.fetch_root().await {
+        Ok(root) => Ok(Some(root)),
+        Err(err)
+            if err
+                .downcast_ref::<HttpError>()
+                .is_some_and(|err| err.status == 404) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Client of sharded cluster sending each upload to the shard owning its content, files are
+/// addressed by shard index and their id within the shard
+pub struct ClusterClient {
+    router: Client,
+    shards: Vec<Client>,
+}
+
+impl ClusterClient {
+    /// Learns shards from router, `configure` sets up client of each shard, e.g. its api key.
+    /// Leaf hashing of each shard is detected from its `GET /info`.
+    pub async fn connect(
+        router: Client,
+        configure: impl Fn(Client) -> Client,
+    ) -> anyhow::Result<Self> {
+        let cluster = router.fetch_cluster().await?;
+        if !cluster.verify() {
+            return Err(
+                VerificationError("shard roots don't match cluster root".to_string()).into(),
+            );
+        }
+        let shards = cluster
+            .shards
+            .into_iter()
+            .map(|shard| Client::try_new(shard.url).map(&configure))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let shards = try_join_all(shards.into_iter().map(Client::detect_leaf_hashing)).await?;
+        Ok(Self { router, shards })
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn shard_of(&self, hash: &Sha3Hash) -> usize {
+        ShardRange::shard_of(hash, self.shards.len())
+    }
+
+    pub async fn upload(
+        &self,
+        filename: &str,
+        content: &[u8],
+    ) -> anyhow::Result<(usize, StoredFile)> {
+        // shards of a cluster share leaf hashing, content is owned by shard of its leaf
+        let leaf = self.shards[0].leaf_hasher().leaf(filename, content);
+        let shard = self.shard_of(&leaf);
+        let stored = self.shards[shard]
+            .upload_new_file(filename, content)
+            .await?;
+        Ok((shard, stored))
+    }
+
+    /// Downloads file and proves it against cluster root through the root of its shard. Only
+    /// files of the current shard epochs are covered by cluster root, and the check fails if the
+    /// shard grew between both requests, so it is worth retrying.
+    pub async fn download_verified(
+        &self,
+        shard: usize,
+        id: FileId,
+    ) -> anyhow::Result<(FileContent, Sha3Hash)> {
+        let client = self
+            .shards
+            .get(shard)
+            .ok_or_else(|| anyhow!("cluster has {} shards", self.shards.len()))?;
+        let file = client.fetch_file(id).await?;
+        let cluster = self.router.fetch_cluster().await?;
+        let shard_root = cluster
+            .shards
+            .get(shard)
+            .and_then(|shard| shard.root.as_ref())
+            .filter(|root| root.epoch == file.epoch)
+            .ok_or_else(|| {
+                VerificationError(format!(
+                    "file {id} is not in the current epoch of shard {shard}"
+                ))
+            })?;
+        if !cluster.verify()
+            || !file.proof.verify(
+                &shard_root.hash,
+                &client.leaf_hasher().leaf(&file.name, &file.content),
+            )
+        {
+            return Err(VerificationError(format!(
+                "Verification of file {id} of shard {shard} against cluster root failed!"
+            ))
+            .into());
+        }
+        Ok((file, cluster.root))
+    }
+}
@@ -0,0 +1,292 @@
+use crate::compression::Codings;
+use crate::merkle::Sha3Hash;
+use actix_web::dev::Payload;
+use actix_web::http::header::{ACCEPT, CONTENT_ENCODING, CONTENT_TYPE};
+use actix_web::{error, web, FromRequest, HttpRequest, HttpResponse, HttpResponseBuilder};
+use base64::Engine;
+use futures_util::future::LocalBoxFuture;
+use futures_util::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::future::{ready, Ready};
+use std::ops::Deref;
+use std::str::FromStr;
+
+/// Biggest request body accepted by [`Decoded`], in bytes
+pub const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Header with metadata of responses whose body is raw file content
+pub const FILE_META_HEADER: &str = "x-file-meta";
+
+/// Value as base64 encoded json, which fits into http header whatever it contains
+pub fn to_header_value<T: Serialize>(value: &T) -> Result<String, CodecError> {
+    let json = Codec::Json.encode(value)?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(json))
+}
+
+pub fn from_header_value<T: DeserializeOwned>(value: &str) -> Result<T, CodecError> {
+    let json = base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map_err(codec_error)?;
+    Codec::Json.decode(&json)
+}
+
+/// Strong entity tag of raw content under given leaf hash
+pub fn etag_of(hash: &Sha3Hash) -> String {
+    format!("\"{hash}\"")
+}
+
+/// Whether `If-None-Match` header value lists given entity tag, weak tags match too
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Wire format of api types. Binary formats carry file contents as raw bytes instead of base64.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Codec {
+    #[default]
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CodecError(String);
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to encode or decode body: {}", self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+fn codec_error(err: impl Display) -> CodecError {
+    CodecError(err.to_string())
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "cbor" => Ok(Codec::Cbor),
+            "msgpack" => Ok(Codec::MessagePack),
+            _ => Err(anyhow::anyhow!(
+                "codec must be one of json, cbor or msgpack"
+            )),
+        }
+    }
+}
+
+impl Codec {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Codec::Json => "application/json",
+            Codec::Cbor => "application/cbor",
+            Codec::MessagePack => "application/msgpack",
+        }
+    }
+
+    /// Codec of given content type, parameters like charset are ignored
+    pub fn from_mime(mime: &str) -> Option<Codec> {
+        match mime.split(';').next().unwrap_or_default().trim() {
+            "application/json" => Some(Codec::Json),
+            "application/cbor" => Some(Codec::Cbor),
+            "application/msgpack" | "application/x-msgpack" => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// Supported type with the highest quality in accept header, earlier listed one wins a tie.
+    /// Json if none of the types is supported.
+    pub fn negotiate(accept: &str) -> Codec {
+        let mut best: Option<(Codec, f32)> = None;
+        for entry in accept.split(',') {
+            let Some(codec) = Codec::from_mime(entry) else {
+                continue;
+            };
+            let quality = entry
+                .split(';')
+                .skip(1)
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+                best = Some((codec, quality));
+            }
+        }
+        best.map(|(codec, _)| codec).unwrap_or_default()
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Codec::Json => serde_json::to_vec(value).map_err(codec_error),
+            Codec::Cbor => {
+                let mut encoded = Vec::new();
+                ciborium::ser::into_writer(value, &mut encoded).map_err(codec_error)?;
+                Ok(encoded)
+            }
+            // named fields keep optional and defaulted fields working the same as in json
+            Codec::MessagePack => rmp_serde::to_vec_named(value).map_err(codec_error),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Codec::Json => serde_json::from_slice(bytes).map_err(codec_error),
+            Codec::Cbor => ciborium::de::from_reader(bytes).map_err(codec_error),
+            Codec::MessagePack => rmp_serde::from_slice(bytes).map_err(codec_error),
+        }
+    }
+
+    /// Finishes response with value encoded by this codec
+    pub fn respond<T: Serialize>(
+        &self,
+        mut response: HttpResponseBuilder,
+        value: T,
+    ) -> HttpResponse {
+        match self.encode(&value) {
+            Ok(body) => response.content_type(self.mime()).body(body),
+            Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        }
+    }
+}
+
+/// Response codec negotiated from `Accept` header
+impl FromRequest for Codec {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let codec = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .map(Codec::negotiate)
+            .unwrap_or_default();
+        ready(Ok(codec))
+    }
+}
+
+/// Request body decompressed according to its `Content-Encoding` with [`Codings`] of the app,
+/// then decoded according to its `Content-Type`, json if it is missing
+pub struct Decoded<T>(pub T);
+
+impl<T> Deref for Decoded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Decoded<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let codec = match req.headers().get(CONTENT_TYPE) {
+            Some(value) => value.to_str().ok().and_then(Codec::from_mime),
+            None => Some(Codec::Json),
+        };
+        let content_encoding = req
+            .headers()
+            .get(CONTENT_ENCODING)
+            .map(|value| value.to_str().unwrap_or_default().to_string());
+        let codings = req
+            .app_data::<web::Data<Codings>>()
+            .map_or_else(Codings::default, |codings| codings.as_ref().clone());
+        // raw payload, `web::Bytes` would decode content codings actix-web knows itself
+        let mut payload = payload.take();
+        Box::pin(async move {
+            let codec = codec.ok_or_else(|| {
+                error::ErrorUnsupportedMediaType("request body must be json, cbor or msgpack")
+            })?;
+            let mut body = web::BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > MAX_BODY_SIZE {
+                    return Err(error::ErrorPayloadTooLarge(format!(
+                        "request body is bigger than {MAX_BODY_SIZE} bytes"
+                    )));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            let body = codings
+                .decode(content_encoding.as_deref(), &body, MAX_BODY_SIZE)
+                .map_err(|err| match err.kind() {
+                    std::io::ErrorKind::Unsupported => error::ErrorUnsupportedMediaType(err),
+                    _ => error::ErrorBadRequest(err),
+                })?;
+            codec
+                .decode(&body)
+                .map(Decoded)
+                .map_err(error::ErrorBadRequest)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::NewFile;
+    use crate::sha3::hash_content;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Codec::negotiate("application/cbor"), Codec::Cbor);
+        assert_eq!(
+            Codec::negotiate("text/html, application/x-msgpack"),
+            Codec::MessagePack
+        );
+        assert_eq!(
+            Codec::negotiate("application/cbor;q=0.5, application/json"),
+            Codec::Json
+        );
+        assert_eq!(
+            Codec::negotiate("application/json;q=0, application/cbor;q=0.1"),
+            Codec::Cbor
+        );
+        assert_eq!(Codec::negotiate("*/*"), Codec::Json);
+        assert_eq!(
+            Codec::from_mime("application/json; charset=utf-8"),
+            Some(Codec::Json)
+        );
+        assert_eq!(Codec::from_mime("text/plain"), None);
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let etag = etag_of(&hash_content(b"content"));
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(&format!("\"other\", W/{etag}"), &etag));
+        assert!(etag_matches("*", &etag));
+        assert!(!etag_matches("\"other\"", &etag));
+        assert!(!etag_matches(&etag, &etag_of(&hash_content(b"other"))));
+    }
+
+    #[test]
+    fn test_binary_codecs_skip_base64() {
+        let new_file = NewFile {
+            content: vec![7; 300],
+            name: "a.bin".to_string(),
+            ttl_secs: None,
+            expected_tree_size: None,
+            dataset: None,
+        };
+        let json = Codec::Json.encode(&new_file).expect("should encode");
+        for codec in [Codec::Cbor, Codec::MessagePack] {
+            let encoded = codec.encode(&new_file).expect("should encode");
+            assert!(encoded.len() < json.len(), "{codec:?}");
+            let decoded: NewFile = codec.decode(&encoded).expect("should decode");
+            assert_eq!(decoded.content, new_file.content);
+            assert_eq!(decoded.name, new_file.name);
+        }
+    }
+}
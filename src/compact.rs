@@ -0,0 +1,156 @@
+use crate::merkle::{FormatVersion, Proof, ProofNode, Sha3Hash};
+use sha3::{Digest, Sha3_256};
+
+/// Bytes of a hash in compact proofs
+pub const HASH_LEN: usize = 32;
+
+const DUPLICATE: u8 = 0;
+const LEFT: u8 = 1;
+const RIGHT: u8 = 2;
+
+/// Step of leaf proof from the leaf up, with sibling borrowed from wherever the proof is kept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProofStep<'a> {
+    /// node has no sibling and is hashed with itself
+    Duplicate,
+    Left(&'a [u8; HASH_LEN]),
+    Right(&'a [u8; HASH_LEN]),
+}
+
+/// Compact wire format of leaf proof: format version byte, then a tag byte for each step from the
+/// leaf up, 0 for node without sibling, 1 for left sibling and 2 for right one, each sibling
+/// followed by its 32 bytes
+pub fn encode(proof: &Proof<Sha3Hash>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + proof.nodes().len() * (1 + HASH_LEN));
+    bytes.push(FormatVersion::CURRENT.number() as u8);
+    for node in proof.nodes() {
+        match node {
+            ProofNode::None => bytes.push(DUPLICATE),
+            ProofNode::LeftSibling(hash) => {
+                bytes.push(LEFT);
+                bytes.extend_from_slice(hash.as_bytes());
+            }
+            ProofNode::RightSiblign(hash) => {
+                bytes.push(RIGHT);
+                bytes.extend_from_slice(hash.as_bytes());
+            }
+        }
+    }
+    bytes
+}
+
+/// Steps of proof in compact format, read in place without copying siblings
+#[derive(Debug, Clone)]
+pub struct CompactSteps<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> CompactSteps<'a> {
+    /// Checks format version and that every step is complete, none if proof is malformed
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        let (&version, steps) = bytes.split_first()?;
+        if version == 0 || u32::from(version) > FormatVersion::CURRENT.number() {
+            return None;
+        }
+        let mut rest = steps;
+        while !rest.is_empty() {
+            rest = read_step(rest)?.1;
+        }
+        Some(Self { rest: steps })
+    }
+}
+
+impl<'a> Iterator for CompactSteps<'a> {
+    type Item = ProofStep<'a>;
+
+    fn next(&mut self) -> Option<ProofStep<'a>> {
+        let (step, rest) = read_step(self.rest)?;
+        self.rest = rest;
+        Some(step)
+    }
+}
+
+fn read_step(bytes: &[u8]) -> Option<(ProofStep<'_>, &[u8])> {
+    let (&tag, rest) = bytes.split_first()?;
+    if tag == DUPLICATE {
+        return Some((ProofStep::Duplicate, rest));
+    }
+    let (sibling, rest) = rest.split_first_chunk::<HASH_LEN>()?;
+    match tag {
+        LEFT => Some((ProofStep::Left(sibling), rest)),
+        RIGHT => Some((ProofStep::Right(sibling), rest)),
+        _ => None,
+    }
+}
+
+/// Same as [`crate::merkle::Proof::verify`] on borrowed hashes, hashing one node at a time
+/// without allocating, so targets which can't hold the whole proof verify it as it is read, e.g.
+/// from [`CompactSteps`]. Relies on nothing but `core` and the `sha3` crate.
+pub fn verify_proof_streaming<'a>(
+    leaf_hash: &[u8; HASH_LEN],
+    proof: impl IntoIterator<Item = ProofStep<'a>>,
+    root: &[u8; HASH_LEN],
+) -> bool {
+    let mut hash = *leaf_hash;
+    for step in proof {
+        hash = match step {
+            ProofStep::Duplicate => node_hash(&hash, &hash),
+            ProofStep::Left(sibling) => node_hash(sibling, &hash),
+            ProofStep::Right(sibling) => node_hash(&hash, sibling),
+        };
+    }
+    hash == *root
+}
+
+fn node_hash(left: &[u8; HASH_LEN], right: &[u8; HASH_LEN]) -> [u8; HASH_LEN] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::merkle::Sha3Tree;
+    use crate::sha3::hash_content;
+
+    fn array(hash: &Sha3Hash) -> &[u8; HASH_LEN] {
+        hash.as_bytes().try_into().expect("sha3 hash has 32 bytes")
+    }
+
+    #[test]
+    fn test_verify_compact_proofs() {
+        let leaves: Vec<_> = (0..5).map(|n| hash_content(format!("{n}"))).collect();
+        let tree = Sha3Tree::from_manifest(leaves.clone());
+        let root = tree.root().expect("tree is not empty");
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = tree.proof_for(index).expect("leaf exists");
+            let bytes = encode(&proof);
+            let steps = CompactSteps::new(&bytes).expect("proof is well formed");
+            assert_eq!(steps.clone().count(), proof.nodes().len());
+            assert!(verify_proof_streaming(
+                array(leaf),
+                steps.clone(),
+                array(&root)
+            ));
+            let other = &leaves[(index + 1) % leaves.len()];
+            assert!(!verify_proof_streaming(array(other), steps, array(&root)));
+        }
+    }
+
+    #[test]
+    fn test_malformed_compact_proofs() {
+        let tree = Sha3Tree::from_manifest((0..3).map(|n| hash_content(format!("{n}"))));
+        let bytes = encode(&tree.proof_for(2).expect("leaf exists"));
+        assert_eq!(bytes.len(), 1 + 1 + 1 + HASH_LEN);
+        assert!(CompactSteps::new(&bytes[..bytes.len() - 1]).is_none());
+        assert!(CompactSteps::new(&[]).is_none());
+        assert!(CompactSteps::new(&[0]).is_none());
+        assert!(CompactSteps::new(&[1, 3]).is_none());
+        // leaf of a single leaf tree is its root
+        let steps = CompactSteps::new(&[1]).expect("empty proof is well formed");
+        let leaf = [7; HASH_LEN];
+        assert!(verify_proof_streaming(&leaf, steps, &leaf));
+    }
+}
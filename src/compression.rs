@@ -0,0 +1,298 @@
+use crate::codec::{Codec, MAX_BODY_SIZE};
+use actix_web::body::{to_bytes, BodySize, BoxBody, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, VARY};
+use anyhow::anyhow;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Smaller bodies are sent as they are, compressing them saves next to nothing
+pub const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// Content coding of request and response bodies, named by its `Content-Encoding` token
+pub trait ContentCoding: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Fails with [`io::ErrorKind::InvalidData`] once decoded body would exceed `limit` bytes
+    fn decode(&self, body: &[u8], limit: usize) -> io::Result<Vec<u8>>;
+}
+
+pub struct Gzip;
+
+impl ContentCoding for Gzip {
+    fn name(&self) -> &'static str {
+        "gzip"
+    }
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body)?;
+        encoder.finish()
+    }
+
+    fn decode(&self, body: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        read_limited(GzDecoder::new(body), limit)
+    }
+}
+
+pub struct Zstd;
+
+impl ContentCoding for Zstd {
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::stream::encode_all(body, zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    fn decode(&self, body: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        read_limited(zstd::stream::read::Decoder::new(body)?, limit)
+    }
+}
+
+/// Guards against small bodies decompressing into huge ones
+fn read_limited(decoder: impl Read, limit: usize) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    decoder
+        .take((limit as u64).saturating_add(1))
+        .read_to_end(&mut decoded)?;
+    if decoded.len() > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("decoded body is bigger than {limit} bytes"),
+        ));
+    }
+    Ok(decoded)
+}
+
+/// Codings one side supports, in order of preference. Identity is always supported and never
+/// listed. Shared by server, which decodes request bodies and compresses responses with it, and
+/// [`crate::client::Client`], so a new coding is registered once instead of in every handler.
+#[derive(Clone)]
+pub struct Codings {
+    codings: Vec<Arc<dyn ContentCoding>>,
+}
+
+impl Debug for Codings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.codings.iter().map(|coding| coding.name()))
+            .finish()
+    }
+}
+
+/// Zstd preferred over gzip
+impl Default for Codings {
+    fn default() -> Self {
+        Self::none().with(Zstd).with(Gzip)
+    }
+}
+
+/// Comma separated coding names in order of preference, `identity` or empty for none
+impl FromStr for Codings {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut codings = Codings::none();
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            codings = match name {
+                "identity" => codings,
+                "gzip" => codings.with(Gzip),
+                "zstd" => codings.with(Zstd),
+                _ => return Err(anyhow!("coding must be one of identity, gzip or zstd")),
+            };
+        }
+        Ok(codings)
+    }
+}
+
+impl Codings {
+    /// Identity only, bodies are sent as they are
+    pub fn none() -> Self {
+        Self {
+            codings: Vec::new(),
+        }
+    }
+
+    /// Registers coding as the least preferred one, replacing coding of the same name
+    pub fn with(mut self, coding: impl ContentCoding + 'static) -> Self {
+        self.codings.retain(|known| known.name() != coding.name());
+        self.codings.push(Arc::new(coding));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codings.is_empty()
+    }
+
+    /// Most preferred coding, bodies are sent with it
+    pub fn preferred(&self) -> Option<&dyn ContentCoding> {
+        self.codings.first().map(Arc::as_ref)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn ContentCoding> {
+        self.codings
+            .iter()
+            .find(|coding| coding.name().eq_ignore_ascii_case(name))
+            .map(Arc::as_ref)
+    }
+
+    /// `Accept-Encoding` value listing all codings, none if there is only identity
+    pub fn accept_encoding(&self) -> Option<String> {
+        let names: Vec<_> = self.codings.iter().map(|coding| coding.name()).collect();
+        (!names.is_empty()).then(|| names.join(", "))
+    }
+
+    /// Supported coding with the highest quality in `Accept-Encoding`, more preferred one wins a
+    /// tie. None means identity.
+    pub fn negotiate(&self, accept_encoding: &str) -> Option<&dyn ContentCoding> {
+        let mut best: Option<(usize, f32)> = None;
+        for entry in accept_encoding.split(',') {
+            let mut params = entry.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let Some(rank) = self
+                .codings
+                .iter()
+                .position(|coding| coding.name().eq_ignore_ascii_case(name))
+            else {
+                continue;
+            };
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let better = best.is_none_or(|(best_rank, best_quality)| {
+                quality > best_quality || (quality == best_quality && rank < best_rank)
+            });
+            if quality > 0.0 && better {
+                best = Some((rank, quality));
+            }
+        }
+        best.map(|(rank, _)| self.codings[rank].as_ref())
+    }
+
+    /// Body as sent with given `Content-Encoding`, unknown codings fail with
+    /// [`io::ErrorKind::Unsupported`]
+    pub fn decode(
+        &self,
+        content_encoding: Option<&str>,
+        body: &[u8],
+        limit: usize,
+    ) -> io::Result<Vec<u8>> {
+        match content_encoding.map(str::trim) {
+            None | Some("identity") => read_limited(body, limit),
+            Some(name) => self
+                .get(name)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("content coding {name} is not supported"),
+                    )
+                })?
+                .decode(body, limit),
+        }
+    }
+
+    /// Compresses api response with coding negotiated from request `Accept-Encoding`. Raw file
+    /// contents, streamed bodies and ones smaller than [`MIN_COMPRESSED_SIZE`] or bigger than
+    /// [`MAX_BODY_SIZE`] are left as they are, so clients can refuse compressed bodies decoding
+    /// into more than that.
+    pub async fn compress<B: MessageBody + 'static>(
+        &self,
+        response: ServiceResponse<B>,
+    ) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+        let coding = response
+            .request()
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|accept_encoding| self.negotiate(accept_encoding));
+        let is_api_body = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|mime| mime.to_str().ok())
+            .and_then(Codec::from_mime)
+            .is_some();
+        let compressible = matches!(
+            response.response().body().size(),
+            BodySize::Sized(size) if (MIN_COMPRESSED_SIZE as u64..=MAX_BODY_SIZE as u64).contains(&size)
+        );
+        let coding = match coding {
+            Some(coding)
+                if is_api_body
+                    && compressible
+                    && !response.headers().contains_key(CONTENT_ENCODING) =>
+            {
+                coding
+            }
+            _ => return Ok(response.map_into_boxed_body()),
+        };
+        let (request, response) = response.into_parts();
+        let (mut response, body) = response.into_parts();
+        let body = to_bytes(body).await.map_err(|err| {
+            let err: Box<dyn std::error::Error> = err.into();
+            actix_web::error::ErrorInternalServerError(err.to_string())
+        })?;
+        let encoded = coding.encode(&body)?;
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(coding.name()));
+        headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+        Ok(ServiceResponse::new(
+            request,
+            response.set_body(BoxBody::new(encoded)),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_coding() {
+        let codings = Codings::default();
+        let name = |accept: &str| codings.negotiate(accept).map(|coding| coding.name());
+        assert_eq!(name("gzip"), Some("gzip"));
+        assert_eq!(name("gzip, zstd"), Some("zstd"));
+        assert_eq!(name("gzip;q=1, zstd;q=0.5"), Some("gzip"));
+        assert_eq!(name("zstd;q=0, gzip;q=0.1"), Some("gzip"));
+        assert_eq!(name("br, identity"), None);
+        assert_eq!(codings.accept_encoding().as_deref(), Some("zstd, gzip"));
+        assert!(Codings::none().negotiate("gzip").is_none());
+        assert!(Codings::none().accept_encoding().is_none());
+
+        let only_gzip: Codings = "identity,gzip".parse().unwrap();
+        assert_eq!(only_gzip.accept_encoding().as_deref(), Some("gzip"));
+        assert!("br".parse::<Codings>().is_err());
+    }
+
+    #[test]
+    fn test_round_trip_with_limit() {
+        let body = b"leaf ".repeat(1000);
+        let codings = Codings::default();
+        for name in ["gzip", "zstd"] {
+            let coding = codings.get(name).unwrap();
+            let encoded = coding.encode(&body).unwrap();
+            assert!(encoded.len() < body.len() / 10, "{name}");
+            assert_eq!(
+                codings.decode(Some(name), &encoded, body.len()).unwrap(),
+                body
+            );
+            let err = codings
+                .decode(Some(name), &encoded, body.len() - 1)
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{name}");
+        }
+        assert_eq!(codings.decode(None, &body, body.len()).unwrap(), body);
+        let err = codings.decode(Some("br"), &body, body.len()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
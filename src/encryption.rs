@@ -0,0 +1,511 @@
+use crate::backend::{BlobCapacity, BlobStore};
+use anyhow::anyhow;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha3::{Digest, Sha3_256};
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Environment variable server reads hex encoded master key from
+pub const MASTER_KEY_ENV: &str = "SAFE_STORAGE_MASTER_KEY";
+
+/// Marks blobs written by [`EncryptedBlobs`], followed by layout version
+const MAGIC: &[u8; 4] = b"SSE1";
+/// Marks blobs [`EncryptedBlobs`] sealed segment by segment from a staged file
+const SEGMENTED_MAGIC: &[u8; 4] = b"SSE2";
+/// Content bytes sealed together in segmented blobs, so only a segment is held in memory
+const SEGMENT_LEN: usize = 64 * 1024;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Marks content sealed by [`MasterKey::encrypt`] under a salt of its own, followed by the salt
+const SALTED_MAGIC: &[u8; 4] = b"SSF1";
+const SALT_LEN: usize = 16;
+
+/// Wraps data keys blobs are encrypted with, e.g. with master key kept outside of the storage or
+/// by asking a key management service
+pub trait KeyProvider: Send {
+    fn wrap(&self, data_key: &[u8]) -> io::Result<Vec<u8>>;
+
+    fn unwrap(&self, wrapped: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// ChaCha20-Poly1305 key held in memory, wrapping data keys locally
+pub struct MasterKey {
+    key: Key,
+    cipher: ChaCha20Poly1305,
+}
+
+/// Parsed from hex encoded 32 byte key
+impl FromStr for MasterKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = parse_key(s).ok_or_else(|| anyhow!("master key must be 32 hex encoded bytes"))?;
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(&key),
+            key,
+        })
+    }
+}
+
+impl MasterKey {
+    /// Reads key from [`MASTER_KEY_ENV`]
+    pub fn from_env() -> anyhow::Result<Self> {
+        std::env::var(MASTER_KEY_ENV)
+            .map_err(|_| anyhow!("{MASTER_KEY_ENV} is not set"))?
+            .parse()
+    }
+}
+
+impl MasterKey {
+    /// Encrypts content bound to given context, e.g. on the client side so server stores
+    /// ciphertext only. Content is encrypted with a [`FileKey`] derived from the context and a
+    /// random salt kept in front of the ciphertext, so the key can be shared for this content
+    /// alone, not for other contents of the same context. Same content encrypts differently every
+    /// time.
+    pub fn encrypt(&self, content: &[u8], context: &[u8]) -> io::Result<Vec<u8>> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut sealed = Vec::with_capacity(SALTED_MAGIC.len() + SALT_LEN + NONCE_LEN);
+        sealed.extend_from_slice(SALTED_MAGIC);
+        sealed.extend_from_slice(&salt);
+        sealed.extend(seal(
+            &self.salted_key(&salt, context).cipher,
+            content,
+            context,
+        )?);
+        Ok(sealed)
+    }
+
+    /// Decrypts content encrypted by [`MasterKey::encrypt`] with the same context, including
+    /// content older clients encrypted without salt or with the master key itself
+    pub fn decrypt(&self, sealed: &[u8], context: &[u8]) -> io::Result<Vec<u8>> {
+        let salted = split_salt(sealed)
+            .ok_or_else(|| invalid("content is not salted"))
+            .and_then(|(salt, rest)| open(&self.salted_key(salt, context).cipher, rest, context));
+        salted
+            .or_else(|_| open(&self.unsalted_key(context).cipher, sealed, context))
+            .or_else(|_| open(&self.cipher, sealed, context))
+    }
+
+    /// Key given content of the context was encrypted with, which decrypts that content only.
+    /// Deriving it doesn't reveal the master key. Content encrypted before salts were added has
+    /// a single key for its whole context.
+    pub fn file_key(&self, sealed: &[u8], context: &[u8]) -> FileKey {
+        match split_salt(sealed) {
+            Some((salt, _)) => self.salted_key(salt, context),
+            None => self.unsalted_key(context),
+        }
+    }
+
+    fn salted_key(&self, salt: &[u8], context: &[u8]) -> FileKey {
+        let mut hasher = Sha3_256::new();
+        hasher.update(b"safe-storage salted file key");
+        hasher.update(self.key);
+        hasher.update(salt);
+        hasher.update(context);
+        FileKey::new(hasher.finalize())
+    }
+
+    fn unsalted_key(&self, context: &[u8]) -> FileKey {
+        let mut hasher = Sha3_256::new();
+        hasher.update(b"safe-storage file key");
+        hasher.update(self.key);
+        hasher.update(context);
+        FileKey::new(hasher.finalize())
+    }
+}
+
+/// Salt and the sealed rest of salted content
+fn split_salt(sealed: &[u8]) -> Option<(&[u8], &[u8])> {
+    sealed
+        .strip_prefix(SALTED_MAGIC.as_slice())?
+        .split_at_checked(SALT_LEN)
+}
+
+/// Key of a single file derived by [`MasterKey::file_key`], e.g. handed out in share links
+#[derive(Clone)]
+pub struct FileKey {
+    key: Key,
+    cipher: ChaCha20Poly1305,
+}
+
+impl FileKey {
+    fn new(key: Key) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&key),
+            key,
+        }
+    }
+
+    /// Decrypts content encrypted by [`MasterKey::encrypt`] this key was derived for
+    pub fn decrypt(&self, sealed: &[u8], context: &[u8]) -> io::Result<Vec<u8>> {
+        match split_salt(sealed) {
+            // unsalted content may start with the magic by chance
+            Some((_, rest)) => {
+                open(&self.cipher, rest, context).or_else(|_| open(&self.cipher, sealed, context))
+            }
+            None => open(&self.cipher, sealed, context),
+        }
+    }
+}
+
+/// Parsed from hex encoded 32 byte key
+impl FromStr for FileKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_key(s)
+            .map(FileKey::new)
+            .ok_or_else(|| anyhow!("file key must be 32 hex encoded bytes"))
+    }
+}
+
+/// Key itself is left out, so it doesn't end up in logs
+impl Debug for FileKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FileKey(..)")
+    }
+}
+
+impl Display for FileKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.key))
+    }
+}
+
+fn parse_key(s: &str) -> Option<Key> {
+    hex::decode(s.trim())
+        .ok()
+        .filter(|bytes| bytes.len() == 32)
+        .map(|bytes| *Key::from_slice(&bytes))
+}
+
+impl KeyProvider for MasterKey {
+    fn wrap(&self, data_key: &[u8]) -> io::Result<Vec<u8>> {
+        seal(&self.cipher, data_key, b"data key")
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> io::Result<Vec<u8>> {
+        open(&self.cipher, wrapped, b"data key")
+    }
+}
+
+/// Encrypts every blob of inner store with its own data key, stored wrapped next to the content.
+/// Leaf hashes are computed from plaintext before it gets here, so the tree is not affected.
+/// Blobs are bound to their id, so swapping them around fails decryption.
+pub struct EncryptedBlobs {
+    inner: Box<dyn BlobStore>,
+    keys: Box<dyn KeyProvider>,
+}
+
+impl EncryptedBlobs {
+    pub fn new(inner: Box<dyn BlobStore>, keys: Box<dyn KeyProvider>) -> Self {
+        Self { inner, keys }
+    }
+
+    /// New data key and the start of a blob sealed with it: magic, wrapped key length (u16 le)
+    /// and wrapped key
+    fn header(&self, magic: &[u8; 4]) -> io::Result<(Key, Vec<u8>)> {
+        let data_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let wrapped = self.keys.wrap(&data_key)?;
+        let wrapped_len = u16::try_from(wrapped.len())
+            .map_err(|_| io::Error::other("wrapped data key is too long"))?;
+        let mut header = Vec::with_capacity(magic.len() + 2 + wrapped.len());
+        header.extend_from_slice(magic);
+        header.extend_from_slice(&wrapped_len.to_le_bytes());
+        header.extend_from_slice(&wrapped);
+        Ok((data_key, header))
+    }
+
+    /// Seals staged content into given file segment by segment
+    fn seal_file(&self, id: usize, staged: &Path, sealed: &Path) -> io::Result<()> {
+        let mut source = File::open(staged)?;
+        let size = source.metadata()?.len();
+        let (data_key, mut header) = self.header(SEGMENTED_MAGIC)?;
+        header.extend_from_slice(&size.to_le_bytes());
+        let cipher = ChaCha20Poly1305::new(&data_key);
+        let mut target = BufWriter::new(File::create(sealed)?);
+        target.write_all(&header)?;
+        let mut segment = vec![0; SEGMENT_LEN];
+        for index in 0..segment_count(size) {
+            let len = segment_len(size, index);
+            source.read_exact(&mut segment[..len])?;
+            target.write_all(&seal(
+                &cipher,
+                &segment[..len],
+                &segment_aad(id, index, size),
+            )?)?;
+        }
+        target
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_data()
+    }
+}
+
+impl BlobStore for EncryptedBlobs {
+    /// Layout: magic, wrapped key length (u16 le), wrapped key, nonce, ciphertext
+    fn put(&mut self, id: usize, content: Vec<u8>) -> io::Result<()> {
+        let (data_key, mut blob) = self.header(MAGIC)?;
+        blob.extend(seal(&ChaCha20Poly1305::new(&data_key), &content, &aad(id))?);
+        self.inner.put(id, blob)
+    }
+
+    /// Seals staged content into a file next to it, which inner store takes over, so content is
+    /// never held in memory whole. Layout: magic, wrapped key length (u16 le), wrapped key,
+    /// content size (u64 le), then nonce and ciphertext of every [`SEGMENT_LEN`] bytes of content.
+    /// Segments are bound to their index and content size, so they can't be reordered or cut off.
+    fn put_file(&mut self, id: usize, staged: &Path) -> io::Result<()> {
+        let sealed = staged.with_extension("sealed");
+        let result = self
+            .seal_file(id, staged, &sealed)
+            .and_then(|()| self.inner.put_file(id, &sealed));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&sealed);
+        }
+        result?;
+        std::fs::remove_file(staged)
+    }
+
+    fn get(&self, id: usize) -> io::Result<Option<Vec<u8>>> {
+        let Some(blob) = self.inner.get(id)? else {
+            return Ok(None);
+        };
+        let (segmented, rest) = match blob.strip_prefix(SEGMENTED_MAGIC.as_slice()) {
+            Some(rest) => (true, rest),
+            None => blob
+                .strip_prefix(MAGIC.as_slice())
+                .map(|rest| (false, rest))
+                .ok_or_else(|| invalid(format!("blob {id} is not encrypted")))?,
+        };
+        if rest.len() < 2 {
+            return Err(invalid(format!("blob {id} is truncated")));
+        }
+        let wrapped_len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+        let (wrapped, sealed) = rest[2..]
+            .split_at_checked(wrapped_len)
+            .ok_or_else(|| invalid(format!("blob {id} is truncated")))?;
+        let data_key = self.keys.unwrap(wrapped)?;
+        let cipher = ChaCha20Poly1305::new_from_slice(&data_key)
+            .map_err(|_| invalid(format!("data key of blob {id} is malformed")))?;
+        if !segmented {
+            return open(&cipher, sealed, &aad(id)).map(Some);
+        }
+        let (size, mut sealed) = sealed
+            .split_at_checked(8)
+            .ok_or_else(|| invalid(format!("blob {id} is truncated")))?;
+        let size = u64::from_le_bytes(size.try_into().expect("should be 8 bytes"));
+        let mut content = Vec::with_capacity(sealed.len());
+        for index in 0..segment_count(size) {
+            let (segment, rest) = sealed
+                .split_at_checked(NONCE_LEN + segment_len(size, index) + TAG_LEN)
+                .ok_or_else(|| invalid(format!("blob {id} is truncated")))?;
+            content.extend(open(&cipher, segment, &segment_aad(id, index, size))?);
+            sealed = rest;
+        }
+        if !sealed.is_empty() {
+            return Err(invalid(format!("blob {id} has trailing data")));
+        }
+        Ok(Some(content))
+    }
+
+    fn remove(&mut self, id: usize) -> io::Result<()> {
+        self.inner.remove(id)
+    }
+
+    /// Capacity of the inner store, which keeps sealed blobs a bit bigger than contents
+    fn capacity(&self) -> Option<BlobCapacity> {
+        self.inner.capacity()
+    }
+
+    fn at_rest(&self) -> Option<&dyn BlobStore> {
+        Some(self.inner.as_ref())
+    }
+
+    fn at_rest_mut(&mut self) -> Option<&mut dyn BlobStore> {
+        Some(self.inner.as_mut())
+    }
+
+    fn begin(&mut self) -> io::Result<()> {
+        self.inner.begin()
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.inner.commit()
+    }
+
+    fn purge(&mut self) -> io::Result<()> {
+        self.inner.purge()
+    }
+
+    fn rollback(&mut self) -> io::Result<()> {
+        self.inner.rollback()
+    }
+}
+
+fn aad(id: usize) -> [u8; 8] {
+    (id as u64).to_le_bytes()
+}
+
+/// Empty content still has a segment, so it is authenticated as well
+fn segment_count(size: u64) -> u64 {
+    size.div_ceil(SEGMENT_LEN as u64).max(1)
+}
+
+fn segment_len(size: u64, index: u64) -> usize {
+    (size - index * SEGMENT_LEN as u64).min(SEGMENT_LEN as u64) as usize
+}
+
+fn segment_aad(id: usize, index: u64, size: u64) -> [u8; 24] {
+    let mut aad = [0; 24];
+    aad[..8].copy_from_slice(&(id as u64).to_le_bytes());
+    aad[8..16].copy_from_slice(&index.to_le_bytes());
+    aad[16..].copy_from_slice(&size.to_le_bytes());
+    aad
+}
+
+/// Nonce followed by ciphertext
+fn seal(cipher: &ChaCha20Poly1305, msg: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg, aad })
+        .map_err(|_| io::Error::other("encryption failed"))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+fn open(cipher: &ChaCha20Poly1305, sealed: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return Err(invalid("sealed data is truncated"));
+    }
+    let (nonce, msg) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| invalid("decryption failed, wrong key or tampered data"))
+}
+
+fn invalid(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::MemoryBlobs;
+
+    const KEY: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+    #[test]
+    fn test_encrypted_blobs() {
+        let mut blobs = EncryptedBlobs::new(
+            Box::<MemoryBlobs>::default(),
+            Box::new(KEY.parse::<MasterKey>().expect("should parse")),
+        );
+        blobs.put(0, b"secret".to_vec()).expect("should put");
+        blobs.put(1, b"secret".to_vec()).expect("should put");
+        assert_eq!(blobs.get(0).expect("should get"), Some(b"secret".to_vec()));
+        assert_eq!(blobs.get(2).expect("should get"), None);
+
+        let first = blobs
+            .inner
+            .get(0)
+            .expect("should get")
+            .expect("should exist");
+        let second = blobs
+            .inner
+            .get(1)
+            .expect("should get")
+            .expect("should exist");
+        assert!(!first.windows(6).any(|window| window == b"secret"));
+        assert_ne!(first, second);
+
+        // blob moved under another id doesn't decrypt
+        blobs.inner.put(1, first).expect("should put");
+        assert!(blobs.get(1).is_err());
+        blobs.inner.put(1, b"plain".to_vec()).expect("should put");
+        assert!(blobs.get(1).is_err());
+
+        let other = "02".repeat(32).parse::<MasterKey>().expect("should parse");
+        let blobs = EncryptedBlobs::new(blobs.inner, Box::new(other));
+        assert!(blobs.get(0).is_err());
+        assert!("0101".parse::<MasterKey>().is_err());
+    }
+
+    #[test]
+    fn test_encrypted_blobs_from_file() {
+        let dir = std::env::temp_dir().join("safe_storage_encrypted_file_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("should create dir");
+        let mut blobs = EncryptedBlobs::new(
+            Box::<MemoryBlobs>::default(),
+            Box::new(KEY.parse::<MasterKey>().expect("should parse")),
+        );
+        let content: Vec<u8> = (0..2 * SEGMENT_LEN + 5).map(|i| i as u8).collect();
+        for (id, content) in [content.as_slice(), b""].into_iter().enumerate() {
+            let staged = dir.join(format!("upload-{id}"));
+            std::fs::write(&staged, content).expect("should write");
+            blobs.put_file(id, &staged).expect("should put file");
+            assert!(!staged.exists());
+            assert!(!staged.with_extension("sealed").exists());
+            assert_eq!(blobs.get(id).expect("should get").as_deref(), Some(content));
+        }
+
+        let sealed = blobs
+            .inner
+            .get(0)
+            .expect("should get")
+            .expect("should exist");
+        assert!(!sealed.windows(64).any(|window| window == &content[..64]));
+        // blob cut off after a whole segment or moved under another id doesn't decrypt
+        let cut = sealed.len() - (NONCE_LEN + 5 + TAG_LEN);
+        blobs
+            .inner
+            .put(0, sealed[..cut].to_vec())
+            .expect("should put");
+        assert!(blobs.get(0).is_err());
+        blobs.inner.put(1, sealed).expect("should put");
+        assert!(blobs.get(1).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_with_context() {
+        let key = KEY.parse::<MasterKey>().expect("should parse");
+        let sealed = key.encrypt(b"secret", b"a.txt").expect("should encrypt");
+        assert_ne!(
+            sealed,
+            key.encrypt(b"secret", b"a.txt").expect("should encrypt")
+        );
+        assert_eq!(
+            key.decrypt(&sealed, b"a.txt").expect("should decrypt"),
+            b"secret"
+        );
+        assert!(key.decrypt(&sealed, b"b.txt").is_err());
+
+        // file key decrypts its own content only, not other files nor other versions of the
+        // same name
+        let file_key: FileKey = key.file_key(&sealed, b"a.txt").to_string().parse().unwrap();
+        assert_eq!(file_key.decrypt(&sealed, b"a.txt").unwrap(), b"secret");
+        let other = key.encrypt(b"secret", b"b.txt").unwrap();
+        assert!(file_key.decrypt(&other, b"b.txt").is_err());
+        let next_version = key.encrypt(b"newer secret", b"a.txt").unwrap();
+        assert!(file_key.decrypt(&next_version, b"a.txt").is_err());
+
+        // content encrypted before salts, with unsalted file key or with master key itself,
+        // still decrypts
+        let unsalted = seal(&key.unsalted_key(b"a.txt").cipher, b"secret", b"a.txt").unwrap();
+        assert_eq!(key.decrypt(&unsalted, b"a.txt").unwrap(), b"secret");
+        let unsalted_key = key.file_key(&unsalted, b"a.txt");
+        assert_eq!(
+            unsalted_key.decrypt(&unsalted, b"a.txt").unwrap(),
+            b"secret"
+        );
+        let legacy = seal(&key.cipher, b"secret", b"a.txt").unwrap();
+        assert_eq!(key.decrypt(&legacy, b"a.txt").unwrap(), b"secret");
+    }
+}
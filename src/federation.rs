@@ -0,0 +1,231 @@
+use crate::api::{AttestationList, Equivocation, SignedRoot, SubmittedAttestation};
+use crate::client::Client;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Roots kept per signing key, older ones are dropped
+pub const MAX_ROOTS_PER_KEY: usize = 1024;
+
+/// Signing keys roots are kept for, roots signed with any further key are refused
+pub const MAX_KEYS: usize = 256;
+
+/// Equivocations kept per signing key, the first ones prove it as well as any later one
+pub const MAX_EQUIVOCATIONS_PER_KEY: usize = 16;
+
+/// Environment variable with api key sent along with submitted roots, for peers requiring one
+pub const FEDERATION_API_KEY_ENV: &str = "SAFE_STORAGE_FEDERATION_API_KEY";
+
+/// Peers server submits its signed root to in federation mode, enabled with
+/// `ServerConfig::federation`
+#[derive(Debug, Clone)]
+pub struct FederationConfig {
+    /// base urls of peer servers, or of any log accepting `POST /attestations`
+    pub peers: Vec<String>,
+    /// how often current root is submitted to each peer, must not be zero
+    pub interval: Duration,
+    /// secret of api key submissions are made with, for peers requiring api keys
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AttestationError {
+    InvalidSignature,
+    /// roots of [`MAX_KEYS`] keys are kept already
+    TooManyKeys,
+}
+
+impl Display for AttestationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttestationError::InvalidSignature => write!(f, "root signature doesn't verify"),
+            AttestationError::TooManyKeys => {
+                write!(f, "roots of {MAX_KEYS} signing keys are collected already")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AttestationError {}
+
+/// Signed roots submitted by peers, checked against each other for equivocation. Memory is
+/// bounded by [`MAX_KEYS`], [`MAX_ROOTS_PER_KEY`] and [`MAX_EQUIVOCATIONS_PER_KEY`].
+#[derive(Debug, Default)]
+pub struct Attestations {
+    /// by public key, oldest first
+    roots: BTreeMap<String, VecDeque<SignedRoot>>,
+    equivocations: Vec<Equivocation>,
+}
+
+impl Attestations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records root unless the same one is known already
+    pub fn submit(&mut self, root: SignedRoot) -> Result<SubmittedAttestation, AttestationError> {
+        if !root.verify() {
+            return Err(AttestationError::InvalidSignature);
+        }
+        if self.roots.len() >= MAX_KEYS && !self.roots.contains_key(&root.public_key) {
+            return Err(AttestationError::TooManyKeys);
+        }
+        let roots = self.roots.entry(root.public_key.clone()).or_default();
+        let same_tree = |known: &&SignedRoot| {
+            (known.epoch, known.tree_size, &known.root) == (root.epoch, root.tree_size, &root.root)
+        };
+        if roots.iter().any(|known| same_tree(&known)) {
+            return Ok(SubmittedAttestation {
+                recorded: false,
+                equivocation: None,
+            });
+        }
+        let equivocation = roots
+            .iter()
+            .find(|known| known.conflicts_with(&root))
+            .map(|known| Equivocation {
+                first: known.clone(),
+                second: root.clone(),
+            });
+        let of_key = self
+            .equivocations
+            .iter()
+            .filter(|known| known.first.public_key == root.public_key)
+            .count();
+        if let Some(equivocation) = equivocation.as_ref() {
+            if of_key < MAX_EQUIVOCATIONS_PER_KEY {
+                self.equivocations.push(equivocation.clone());
+            }
+        }
+        roots.push_back(root);
+        if roots.len() > MAX_ROOTS_PER_KEY {
+            roots.pop_front();
+        }
+        Ok(SubmittedAttestation {
+            recorded: true,
+            equivocation,
+        })
+    }
+
+    /// Collected roots and equivocations, only of given key if any
+    pub fn list(&self, public_key: Option<&str>) -> AttestationList {
+        let of_key = |key: &str| public_key.is_none_or(|public_key| public_key == key);
+        AttestationList {
+            attestations: self
+                .roots
+                .iter()
+                .filter(|(key, _)| of_key(key))
+                .flat_map(|(_, roots)| roots.iter().cloned())
+                .collect(),
+            equivocations: self
+                .equivocations
+                .iter()
+                .filter(|equivocation| of_key(&equivocation.first.public_key))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Submits signed root to every peer, failures are reported and left for the next submission
+pub async fn submit_to_peers(peers: &[Client], root: &SignedRoot) {
+    for peer in peers {
+        if let Err(err) = peer.submit_attestation(root).await {
+            eprintln!("failed to submit root to {}: {err}", peer.server_url());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signing::{Keyring, ServerKey};
+    use crate::storage::Storage;
+    use std::time::SystemTime;
+
+    fn signed_root(storage: &Storage) -> SignedRoot {
+        storage.signed_root(SystemTime::now()).expect("should sign")
+    }
+
+    fn signed_by(seed: usize, content: &[u8]) -> SignedRoot {
+        let key: ServerKey = format!("{seed:064x}").parse().expect("should parse");
+        let mut storage = Storage::new().with_keyring(Keyring::new(vec![key]));
+        storage
+            .add_new_file("a.txt".to_string(), content.to_vec())
+            .expect("should add");
+        signed_root(&storage)
+    }
+
+    #[test]
+    fn test_equivocation_detected() {
+        let key = || -> ServerKey { "11".repeat(32).parse().expect("should parse") };
+        let mut honest = Storage::new().with_keyring(Keyring::new(vec![key()]));
+        let mut forked = Storage::new().with_keyring(Keyring::new(vec![key()]));
+        honest
+            .add_new_file("a.txt".to_string(), b"a".to_vec())
+            .expect("should add");
+        forked
+            .add_new_file("a.txt".to_string(), b"b".to_vec())
+            .expect("should add");
+
+        let mut attestations = Attestations::new();
+        let first = signed_root(&honest);
+        let submitted = attestations.submit(first.clone()).expect("should submit");
+        assert!(submitted.recorded);
+        let submitted = attestations.submit(first.clone()).expect("should submit");
+        assert!(!submitted.recorded);
+        let mut forged = first.clone();
+        forged.root = forked.root_hash().expect("should have root");
+        assert_eq!(
+            attestations.submit(forged),
+            Err(AttestationError::InvalidSignature)
+        );
+
+        let second = signed_root(&forked);
+        let submitted = attestations.submit(second.clone()).expect("should submit");
+        assert_eq!(
+            submitted.equivocation,
+            Some(Equivocation {
+                first: first.clone(),
+                second: second.clone(),
+            })
+        );
+        honest
+            .add_new_file("b.txt".to_string(), b"b".to_vec())
+            .expect("should add");
+        let grown = attestations
+            .submit(signed_root(&honest))
+            .expect("should submit");
+        assert!(grown.recorded && grown.equivocation.is_none());
+
+        let list = attestations.list(Some(&first.public_key));
+        assert_eq!(list.attestations.len(), 3);
+        assert_eq!(list.equivocations.len(), 1);
+        assert!(attestations.list(Some("00")).attestations.is_empty());
+    }
+
+    #[test]
+    fn test_memory_bounded() {
+        let mut attestations = Attestations::new();
+        for forks in 0..MAX_EQUIVOCATIONS_PER_KEY + 2 {
+            attestations
+                .submit(signed_by(1, &forks.to_le_bytes()))
+                .expect("should submit");
+        }
+        let list = attestations.list(None);
+        assert_eq!(list.attestations.len(), MAX_EQUIVOCATIONS_PER_KEY + 2);
+        assert_eq!(list.equivocations.len(), MAX_EQUIVOCATIONS_PER_KEY);
+
+        for seed in 2..=MAX_KEYS {
+            attestations
+                .submit(signed_by(seed, b"a"))
+                .expect("should submit");
+        }
+        assert_eq!(
+            attestations.submit(signed_by(MAX_KEYS + 1, b"a")),
+            Err(AttestationError::TooManyKeys)
+        );
+        let known = attestations.submit(signed_by(1, b"b"));
+        assert!(known.is_ok(), "keys known already are still accepted");
+    }
+}
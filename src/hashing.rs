@@ -0,0 +1,172 @@
+use crate::leaf::{LeafHasher, LeafHashing};
+use crate::sha3::{hash_content, Hash};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Payloads smaller than this are hashed inline, spawning blocking task costs more than hashing them
+pub const INLINE_HASH_LIMIT: usize = 1024 * 1024;
+
+/// Hashes big payloads on blocking threads so they don't stall request handling, at most
+/// `threads` of them at once
+pub struct HashPool {
+    threads: usize,
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    leaf_hasher: Arc<dyn LeafHasher>,
+    names_by_hash: bool,
+}
+
+impl HashPool {
+    /// Panics if `threads` is zero, server configuration is validated before pool is created
+    pub fn new(threads: usize) -> Self {
+        assert!(threads > 0, "at least one hashing thread is required");
+        Self {
+            threads,
+            permits: Arc::new(Semaphore::new(threads)),
+            queued: AtomicUsize::new(0),
+            leaf_hasher: LeafHashing::default().hasher(),
+            names_by_hash: false,
+        }
+    }
+
+    /// Leaves are derived by given hasher instead of hashing content alone
+    pub fn with_leaf_hasher(mut self, leaf_hasher: Arc<dyn LeafHasher>) -> Self {
+        self.leaf_hasher = leaf_hasher;
+        self
+    }
+
+    pub fn leaf_hasher(&self) -> &Arc<dyn LeafHasher> {
+        &self.leaf_hasher
+    }
+
+    /// Files are named by hex of their content hash instead of names they were uploaded with,
+    /// see [`HashPool::hash_named`]
+    pub fn with_names_by_hash(mut self, names_by_hash: bool) -> Self {
+        self.names_by_hash = names_by_hash;
+        self
+    }
+
+    pub fn names_by_hash(&self) -> bool {
+        self.names_by_hash
+    }
+
+    /// Whether streamed content has to be hashed alone too, for its canonical name or for
+    /// storage to keep along name-hash leaves
+    pub fn content_hashes(&self) -> bool {
+        self.names_by_hash || self.leaf_hasher.strategy() == LeafHashing::NameAndHash
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Amount of payloads waiting for a free hashing thread
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Computes leaf of file with given name, giving content back together with it
+    pub async fn hash(&self, name: &str, content: Vec<u8>) -> (Vec<u8>, Hash) {
+        let leaf_hasher = self.leaf_hasher.clone();
+        let name = name.to_string();
+        self.run(content.len(), move || {
+            let hash = leaf_hasher.leaf(&name, &content);
+            (content, hash)
+        })
+        .await
+    }
+
+    /// Like [`HashPool::hash`], but with names by hash the file is renamed to its
+    /// [`canonical_name`] first and its leaf is derived from that. Name file is stored under is
+    /// given back too.
+    pub async fn hash_named(&self, name: String, content: Vec<u8>) -> (String, Vec<u8>, Hash) {
+        let leaf_hasher = self.leaf_hasher.clone();
+        let names_by_hash = self.names_by_hash;
+        self.run(content.len(), move || {
+            let name = match names_by_hash {
+                true => canonical_name(&content),
+                false => name,
+            };
+            let hash = leaf_hasher.leaf(&name, &content);
+            (name, content, hash)
+        })
+        .await
+    }
+
+    /// Runs hashing of payload of given size inline or on a blocking thread
+    async fn run<T: Send + 'static>(
+        &self,
+        size: usize,
+        hash: impl FnOnce() -> T + Send + 'static,
+    ) -> T {
+        if size < INLINE_HASH_LIMIT {
+            return hash();
+        }
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            hash()
+        })
+        .await
+        .expect("hashing should not panic")
+    }
+}
+
+/// Name of file with given content on servers naming files by hash, hex of its content hash
+pub fn canonical_name(content: &[u8]) -> String {
+    hash_content(content).to_string()
+}
+
+impl Default for HashPool {
+    fn default() -> Self {
+        Self::new(std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sha3::hash_content;
+
+    #[tokio::test]
+    async fn test_hash_big_and_small_payloads() {
+        let pool = HashPool::new(1);
+        let small = b"small".to_vec();
+        let big = vec![7u8; INLINE_HASH_LIMIT + 1];
+
+        let (content, hash) = pool.hash("small", small.clone()).await;
+        assert_eq!((content, hash), (small.clone(), hash_content(&small)));
+        let (content, hash) = pool.hash("big", big.clone()).await;
+        assert_eq!((content, hash), (big.clone(), hash_content(&big)));
+        assert_eq!(pool.queue_depth(), 0);
+
+        let named = HashPool::new(1).with_leaf_hasher(LeafHashing::NameAndContent.hasher());
+        let (_, hash) = named.hash("big", big.clone()).await;
+        assert_eq!(hash, named.leaf_hasher().leaf("big", &big));
+        assert_ne!(hash, hash_content(&big));
+    }
+
+    #[tokio::test]
+    async fn test_names_by_hash() {
+        let pool = HashPool::new(1).with_leaf_hasher(LeafHashing::NameAndContent.hasher());
+        let (name, _, hash) = pool.hash_named("a.txt".to_string(), b"a".to_vec()).await;
+        assert_eq!(name, "a.txt");
+        assert_eq!(hash, pool.leaf_hasher().leaf("a.txt", b"a"));
+
+        let pool = pool.with_names_by_hash(true);
+        let big = vec![7u8; INLINE_HASH_LIMIT + 1];
+        for content in [b"a".to_vec(), big] {
+            let (name, _, hash) = pool.hash_named("a.txt".to_string(), content.clone()).await;
+            assert_eq!(name, hash_content(&content).to_string());
+            assert_eq!(hash, pool.leaf_hasher().leaf(&name, &content));
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use crate::trace::{TraceContext, TRACEPARENT};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Biggest content imported by default, in bytes
+pub const DEFAULT_MAX_IMPORT_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum ImportError {
+    /// url can't be parsed or has no file name to store content under
+    InvalidUrl(String),
+    SchemeNotAllowed(String),
+    /// host is or resolves to a loopback, link-local or private address and is not allowed
+    AddressNotAllowed(String),
+    TooLarge(u64),
+    /// source could not be reached or answered with error
+    Fetch(String),
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::InvalidUrl(reason) => write!(f, "invalid import url: {reason}"),
+            ImportError::SchemeNotAllowed(scheme) => {
+                write!(f, "importing from {scheme} urls is not allowed")
+            }
+            ImportError::AddressNotAllowed(host) => {
+                write!(
+                    f,
+                    "importing from non-public address of {host} is not allowed"
+                )
+            }
+            ImportError::TooLarge(limit) => {
+                write!(f, "imported content exceeds limit of {limit} bytes")
+            }
+            ImportError::Fetch(reason) => write!(f, "failed to fetch imported content: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// Fetches content hosted elsewhere on behalf of clients, only from allowed url schemes and
+/// public addresses (also when following redirects) and no more than configured amount of bytes.
+/// Hosts in `allowed_hosts` may have any address, e.g. internal mirrors, `*` allows all hosts.
+pub struct Importer {
+    client: reqwest::Client,
+    schemes: Vec<String>,
+    allowed_hosts: Arc<Vec<String>>,
+    max_size: u64,
+}
+
+impl Default for Importer {
+    fn default() -> Self {
+        Self::new(vec!["https".to_string()], vec![], DEFAULT_MAX_IMPORT_SIZE)
+    }
+}
+
+impl Importer {
+    pub fn new(schemes: Vec<String>, allowed_hosts: Vec<String>, max_size: u64) -> Self {
+        let allowed_hosts = Arc::new(allowed_hosts);
+        let allowed = schemes.clone();
+        let hosts = allowed_hosts.clone();
+        let redirects = Policy::custom(move |attempt| {
+            let scheme = attempt.url().scheme().to_string();
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if !allowed.contains(&scheme) {
+                attempt.error(ImportError::SchemeNotAllowed(scheme))
+            } else if let Err(err) = check_host(attempt.url(), &hosts) {
+                attempt.error(err)
+            } else {
+                attempt.follow()
+            }
+        });
+        // host names are checked when resolved, so redirects and rebinding are covered too
+        let client = reqwest::Client::builder()
+            .redirect(redirects)
+            .dns_resolver(Arc::new(PublicResolver(allowed_hosts.clone())))
+            .no_proxy()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(300))
+            .build()
+            .expect("http client configuration should be valid");
+        Self {
+            client,
+            schemes,
+            allowed_hosts,
+            max_size,
+        }
+    }
+
+    /// Imports bigger than this are refused
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Downloads content of given url, returns it with given name or last url path segment.
+    /// Request is traced as a child of given trace context.
+    pub async fn fetch(
+        &self,
+        url: &str,
+        name: Option<String>,
+        trace: &TraceContext,
+    ) -> Result<(String, Vec<u8>), ImportError> {
+        let url = self.check_url(url)?;
+        let name = match name {
+            Some(name) => name,
+            None => file_name(&url).ok_or_else(|| {
+                ImportError::InvalidUrl("url has no file name, name must be given".to_string())
+            })?,
+        };
+        let fetch_error = |err: reqwest::Error| ImportError::Fetch(err.to_string());
+        let mut resp = self
+            .client
+            .get(url)
+            .header(TRACEPARENT, trace.child().to_string())
+            .send()
+            .await
+            .map_err(fetch_error)?;
+        if !resp.status().is_success() {
+            return Err(ImportError::Fetch(format!(
+                "source answered with {}",
+                resp.status()
+            )));
+        }
+        if resp.content_length().is_some_and(|len| len > self.max_size) {
+            return Err(ImportError::TooLarge(self.max_size));
+        }
+        let mut content = Vec::new();
+        while let Some(chunk) = resp.chunk().await.map_err(fetch_error)? {
+            if (content.len() + chunk.len()) as u64 > self.max_size {
+                return Err(ImportError::TooLarge(self.max_size));
+            }
+            content.extend_from_slice(&chunk);
+        }
+        Ok((name, content))
+    }
+
+    fn check_url(&self, url: &str) -> Result<Url, ImportError> {
+        let url = Url::parse(url).map_err(|err| ImportError::InvalidUrl(err.to_string()))?;
+        if !self.schemes.iter().any(|scheme| scheme == url.scheme()) {
+            return Err(ImportError::SchemeNotAllowed(url.scheme().to_string()));
+        }
+        check_host(&url, &self.allowed_hosts)?;
+        Ok(url)
+    }
+}
+
+/// Refuses urls with non-public ip address as host, unless the host is allowed. Host names are
+/// checked by [`PublicResolver`] instead.
+fn check_host(url: &Url, allowed_hosts: &[String]) -> Result<(), ImportError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| ImportError::InvalidUrl("url has no host".to_string()))?;
+    let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    else {
+        return Ok(());
+    };
+    if !is_public(ip) && !allows(allowed_hosts, host) {
+        return Err(ImportError::AddressNotAllowed(host.to_string()));
+    }
+    Ok(())
+}
+
+/// Resolves host names with the system resolver, refusing names with any non-public address
+/// unless they are allowed
+struct PublicResolver(Arc<Vec<String>>);
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = allows(&self.0, name.as_str());
+        Box::pin(async move {
+            let addrs: Vec<_> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if !allowed && addrs.iter().any(|addr| !is_public(addr.ip())) {
+                return Err(ImportError::AddressNotAllowed(name.as_str().to_string()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn allows(allowed_hosts: &[String], host: &str) -> bool {
+    allowed_hosts
+        .iter()
+        .any(|allowed| allowed == "*" || allowed == host)
+}
+
+/// Address is reachable over the internet, i.e. not loopback, link-local, private, shared or
+/// otherwise reserved
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // shared address space of carrier-grade nat
+        || (a == 100 && (64..128).contains(&b))
+        // "this network" and reserved ranges
+        || a == 0
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local fc00::/7 and link-local fe80::/10
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80)
+}
+
+/// Last non empty path segment, e.g. `app.tar.gz` of `https://example.com/builds/app.tar.gz`
+fn file_name(url: &Url) -> Option<String> {
+    url.path_segments()?
+        .rfind(|segment| !segment.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_url() {
+        let importer = Importer::default();
+        assert!(importer.check_url("https://example.com/a.txt").is_ok());
+        assert_eq!(
+            importer.check_url("file:///etc/passwd"),
+            Err(ImportError::SchemeNotAllowed("file".to_string()))
+        );
+        assert_eq!(
+            importer.check_url("http://example.com/a.txt"),
+            Err(ImportError::SchemeNotAllowed("http".to_string()))
+        );
+        assert!(matches!(
+            importer.check_url("example.com/a.txt"),
+            Err(ImportError::InvalidUrl(_))
+        ));
+        for internal in [
+            "https://127.0.0.1/a.txt",
+            "https://169.254.169.254/latest/meta-data",
+            "https://10.1.2.3/a.txt",
+            "https://192.168.0.1/a.txt",
+            "https://[::1]/a.txt",
+            "https://[::ffff:127.0.0.1]/a.txt",
+            "https://[fd00::1]/a.txt",
+        ] {
+            assert!(
+                matches!(
+                    importer.check_url(internal),
+                    Err(ImportError::AddressNotAllowed(_))
+                ),
+                "{internal}"
+            );
+        }
+        assert!(importer.check_url("https://93.184.216.34/a.txt").is_ok());
+        let mirror = Importer::new(
+            vec!["https".to_string()],
+            vec!["10.1.2.3".to_string()],
+            DEFAULT_MAX_IMPORT_SIZE,
+        );
+        assert!(mirror.check_url("https://10.1.2.3/a.txt").is_ok());
+        assert!(mirror.check_url("https://10.1.2.4/a.txt").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolved_address_checked() {
+        let importer = Importer::new(vec!["http".to_string()], vec![], DEFAULT_MAX_IMPORT_SIZE);
+        let fetched = importer
+            .fetch("http://localhost:1/a.txt", None, &TraceContext::new_root())
+            .await;
+        assert!(
+            matches!(&fetched, Err(ImportError::Fetch(reason)) if reason.contains("non-public")),
+            "{fetched:?}"
+        );
+    }
+
+    #[test]
+    fn test_file_name() {
+        let name = |url: &str| file_name(&Url::parse(url).expect("should parse"));
+        assert_eq!(
+            name("https://example.com/builds/app.tar.gz"),
+            Some("app.tar.gz".to_string())
+        );
+        assert_eq!(
+            name("https://example.com/builds/"),
+            Some("builds".to_string())
+        );
+        assert_eq!(name("https://example.com"), None);
+    }
+}
@@ -0,0 +1,245 @@
+use async_trait::async_trait;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Reason why upload was not accepted
+#[derive(Debug, PartialEq)]
+pub struct Rejection {
+    pub reason: String,
+}
+
+impl Rejection {
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upload rejected: {}", self.reason)
+    }
+}
+
+/// Hook invoked for each upload before its content is committed to the tree
+#[async_trait]
+pub trait UploadInterceptor: Send + Sync {
+    async fn intercept(&self, name: &str, content: &[u8]) -> Result<(), Rejection>;
+
+    /// Same as [`UploadInterceptor::intercept`] for streamed upload staged in a file of `size`
+    /// bytes, which is read into memory unless overridden
+    async fn intercept_file(&self, name: &str, staged: &Path, _size: u64) -> Result<(), Rejection> {
+        let content = tokio::fs::read(staged)
+            .await
+            .map_err(|err| Rejection::new(format!("staged content is not readable: {err}")))?;
+        self.intercept(name, &content).await
+    }
+
+    /// Streamed uploads bigger than this are aborted before they are staged whole
+    fn max_size(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Ordered chain of interceptors, first rejection wins
+#[derive(Default)]
+pub struct UploadInterceptors {
+    interceptors: Vec<Box<dyn UploadInterceptor>>,
+}
+
+impl UploadInterceptors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, interceptor: impl UploadInterceptor + 'static) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// Checks uploads with given interceptor before all others, e.g. cheap size check before a
+    /// virus scan
+    pub fn with_first(mut self, interceptor: impl UploadInterceptor + 'static) -> Self {
+        self.interceptors.insert(0, Box::new(interceptor));
+        self
+    }
+
+    pub async fn check(&self, name: &str, content: &[u8]) -> Result<(), Rejection> {
+        for interceptor in &self.interceptors {
+            interceptor.intercept(name, content).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn check_file(&self, name: &str, staged: &Path, size: u64) -> Result<(), Rejection> {
+        for interceptor in &self.interceptors {
+            interceptor.intercept_file(name, staged, size).await?;
+        }
+        Ok(())
+    }
+
+    /// The smallest size limit of all interceptors
+    pub fn max_size(&self) -> Option<u64> {
+        self.interceptors
+            .iter()
+            .filter_map(|interceptor| interceptor.max_size())
+            .min()
+    }
+}
+
+/// Rejects content bigger than given amount of bytes
+pub struct MaxSize(pub usize);
+
+#[async_trait]
+impl UploadInterceptor for MaxSize {
+    async fn intercept(&self, name: &str, content: &[u8]) -> Result<(), Rejection> {
+        self.intercept_file(name, Path::new(""), content.len() as u64)
+            .await
+    }
+
+    async fn intercept_file(
+        &self,
+        _name: &str,
+        _staged: &Path,
+        size: u64,
+    ) -> Result<(), Rejection> {
+        if size > self.0 as u64 {
+            return Err(Rejection::new(format!(
+                "content size {} exceeds limit of {} bytes",
+                size, self.0
+            )));
+        }
+        Ok(())
+    }
+
+    fn max_size(&self) -> Option<u64> {
+        Some(self.0 as u64)
+    }
+}
+
+/// Rejects files with any of given extensions (case insensitive)
+pub struct DeniedExtensions(pub Vec<String>);
+
+#[async_trait]
+impl UploadInterceptor for DeniedExtensions {
+    async fn intercept(&self, name: &str, _content: &[u8]) -> Result<(), Rejection> {
+        let extension = Path::new(name).extension().and_then(|ext| ext.to_str());
+        match extension {
+            Some(ext)
+                if self
+                    .0
+                    .iter()
+                    .any(|denied| denied.trim_start_matches('.').eq_ignore_ascii_case(ext)) =>
+            {
+                Err(Rejection::new(format!("extension .{ext} is not allowed")))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn intercept_file(
+        &self,
+        name: &str,
+        _staged: &Path,
+        _size: u64,
+    ) -> Result<(), Rejection> {
+        self.intercept(name, &[]).await
+    }
+}
+
+/// Scans content with clamd daemon listening on given tcp address using INSTREAM command.
+/// Scanner failures reject the upload, since content cannot be considered clean.
+pub struct ClamAv {
+    pub addr: String,
+}
+
+const CLAMAV_CHUNK_SIZE: usize = 64 * 1024;
+
+impl ClamAv {
+    async fn scan(&self, mut content: impl AsyncRead + Unpin) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        let mut chunk = vec![0; CLAMAV_CHUNK_SIZE];
+        loop {
+            let read = content.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&(read as u32).to_be_bytes()).await?;
+            stream.write_all(&chunk[..read]).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok(String::from_utf8_lossy(&response)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string())
+    }
+}
+
+#[async_trait]
+impl UploadInterceptor for ClamAv {
+    async fn intercept(&self, _name: &str, content: &[u8]) -> Result<(), Rejection> {
+        verdict(self.scan(content).await)
+    }
+
+    /// Staged content is streamed to the scanner straight from the file
+    async fn intercept_file(
+        &self,
+        _name: &str,
+        staged: &Path,
+        _size: u64,
+    ) -> Result<(), Rejection> {
+        let scanned = match tokio::fs::File::open(staged).await {
+            Ok(file) => self.scan(file).await,
+            Err(err) => Err(err),
+        };
+        verdict(scanned)
+    }
+}
+
+fn verdict(response: std::io::Result<String>) -> Result<(), Rejection> {
+    let response =
+        response.map_err(|err| Rejection::new(format!("content scanner unavailable: {err}")))?;
+    if response.ends_with("OK") {
+        Ok(())
+    } else {
+        Err(Rejection::new(format!("content scanner: {response}")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_interceptor_chain() {
+        let interceptors = UploadInterceptors::new()
+            .with(MaxSize(4))
+            .with(DeniedExtensions(vec![".exe".to_string()]));
+
+        assert!(interceptors.check("notes.txt", b"1234").await.is_ok());
+        assert_eq!(
+            interceptors.check("notes.txt", b"12345").await,
+            Err(Rejection::new("content size 5 exceeds limit of 4 bytes"))
+        );
+        assert_eq!(
+            interceptors.check("setup.EXE", b"1").await,
+            Err(Rejection::new("extension .EXE is not allowed"))
+        );
+        assert_eq!(interceptors.max_size(), Some(4));
+
+        // size of staged content is known upfront, so it is not read at all
+        let missing = Path::new("missing");
+        assert!(interceptors.check_file("a.txt", missing, 4).await.is_ok());
+        assert_eq!(
+            interceptors.check_file("a.txt", missing, 5).await,
+            Err(Rejection::new("content size 5 exceeds limit of 4 bytes"))
+        );
+        assert!(interceptors.check_file("a.exe", missing, 1).await.is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use crate::trace::{TraceContext, TRACEPARENT};
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+#[derive(Debug, PartialEq)]
+pub enum IpfsError {
+    /// node could not be reached or answered with error
+    Node(String),
+    /// node answered with something else than added content
+    InvalidResponse(String),
+}
+
+impl Display for IpfsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpfsError::Node(reason) => write!(f, "ipfs node failed: {reason}"),
+            IpfsError::InvalidResponse(reason) => {
+                write!(f, "invalid response of ipfs node: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for IpfsError {}
+
+/// Entry of `/api/v0/add` response
+#[derive(Debug, Deserialize)]
+struct Added {
+    #[serde(rename = "Hash")]
+    hash: String,
+}
+
+/// Pins stored contents to an IPFS node through its Kubo compatible http api, so they can be
+/// published there as well while proofs stay with safe-storage
+pub struct IpfsNode {
+    client: reqwest::Client,
+    api_url: String,
+}
+
+impl IpfsNode {
+    /// `api_url` is base url of node rpc api, e.g. `http://localhost:5001`
+    pub fn new(api_url: impl Into<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(300))
+            .build()
+            .expect("http client configuration should be valid");
+        Self {
+            client,
+            api_url: api_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Adds content to the node and pins it, returns its CID (v1). Request is traced as a child
+    /// of given trace context.
+    pub async fn pin(
+        &self,
+        name: &str,
+        content: Vec<u8>,
+        trace: &TraceContext,
+    ) -> Result<String, IpfsError> {
+        let node_error = |err: reqwest::Error| IpfsError::Node(err.to_string());
+        let form = Form::new().part("file", Part::bytes(content).file_name(name.to_string()));
+        let resp = self
+            .client
+            .post(format!("{}/api/v0/add", self.api_url))
+            .query(&[("pin", "true"), ("cid-version", "1")])
+            .header(TRACEPARENT, trace.child().to_string())
+            .multipart(form)
+            .send()
+            .await
+            .map_err(node_error)?;
+        if !resp.status().is_success() {
+            return Err(IpfsError::Node(format!(
+                "node answered with {}",
+                resp.status()
+            )));
+        }
+        parse_added(&resp.text().await.map_err(node_error)?)
+    }
+}
+
+/// CID of added content, node may answer with an entry per line when it adds directories too
+fn parse_added(body: &str) -> Result<String, IpfsError> {
+    let last = body
+        .lines()
+        .rfind(|line| !line.trim().is_empty())
+        .ok_or_else(|| IpfsError::InvalidResponse("empty response".to_string()))?;
+    let added: Added =
+        serde_json::from_str(last).map_err(|err| IpfsError::InvalidResponse(err.to_string()))?;
+    if added.hash.is_empty() || !added.hash.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(IpfsError::InvalidResponse(format!(
+            "{} is not a cid",
+            added.hash
+        )));
+    }
+    Ok(added.hash)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_added() {
+        let cid = "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku";
+        let body = format!(r#"{{"Name":"a.txt","Hash":"{cid}","Size":"12"}}"#);
+        assert_eq!(parse_added(&body), Ok(cid.to_string()));
+        assert_eq!(parse_added(&format!("{body}\n")), Ok(cid.to_string()));
+        assert!(matches!(
+            parse_added(""),
+            Err(IpfsError::InvalidResponse(_))
+        ));
+        assert!(matches!(
+            parse_added(r#"{"Name":"a.txt","Hash":"../etc"}"#),
+            Err(IpfsError::InvalidResponse(_))
+        ));
+    }
+}
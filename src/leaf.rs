@@ -0,0 +1,373 @@
+use crate::sha3::{hash_many, Hash, Hasher};
+use crate::storage::normalize_name;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// How leaves of files are derived from their names and contents. Chosen at server startup and
+/// reported by `GET /info`, clients need the same one for their local roots to match.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum LeafHashing {
+    /// hash of the content alone
+    #[default]
+    Content,
+    /// hash of name length, name and content, so the same content stored under other name gets
+    /// other leaf
+    NameAndContent,
+    /// hash of name length, name and content hash, so names of listed files can be checked
+    /// against their leaves without downloading contents
+    NameAndHash,
+    /// hash of hashes of content split into chunks of given size
+    Chunked(usize),
+    /// hash of content prefixed with given key, keeping leaves of deployments with different keys
+    /// apart. Key is not a secret, clients learn it from `GET /info`.
+    Keyed(Vec<u8>),
+}
+
+impl LeafHashing {
+    pub fn hasher(&self) -> Arc<dyn LeafHasher> {
+        match self {
+            LeafHashing::Content => Arc::new(ContentLeaves),
+            LeafHashing::NameAndContent => Arc::new(NamedLeaves),
+            LeafHashing::NameAndHash => Arc::new(NameHashLeaves),
+            LeafHashing::Chunked(chunk_size) => Arc::new(ChunkedLeaves(*chunk_size)),
+            LeafHashing::Keyed(key) => Arc::new(KeyedLeaves(key.clone())),
+        }
+    }
+}
+
+/// `content`, `name-content`, `name-hash`, `chunked:BYTES` or `keyed:HEX`
+impl FromStr for LeafHashing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "content" => Ok(LeafHashing::Content),
+            None if s == "name-content" => Ok(LeafHashing::NameAndContent),
+            None if s == "name-hash" => Ok(LeafHashing::NameAndHash),
+            Some(("chunked", size)) => match size.parse()? {
+                0 => Err(anyhow!("chunk size must be positive")),
+                size => Ok(LeafHashing::Chunked(size)),
+            },
+            Some(("keyed", key)) => match hex::decode(key)? {
+                key if key.is_empty() => Err(anyhow!("leaf key must not be empty")),
+                key => Ok(LeafHashing::Keyed(key)),
+            },
+            _ => Err(anyhow!(
+                "leaf hashing must be one of content, name-content, name-hash, chunked:BYTES or keyed:HEX"
+            )),
+        }
+    }
+}
+
+impl Display for LeafHashing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeafHashing::Content => write!(f, "content"),
+            LeafHashing::NameAndContent => write!(f, "name-content"),
+            LeafHashing::NameAndHash => write!(f, "name-hash"),
+            LeafHashing::Chunked(size) => write!(f, "chunked:{size}"),
+            LeafHashing::Keyed(key) => write!(f, "keyed:{}", hex::encode(key)),
+        }
+    }
+}
+
+impl TryFrom<String> for LeafHashing {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<LeafHashing> for String {
+    fn from(hashing: LeafHashing) -> Self {
+        hashing.to_string()
+    }
+}
+
+/// Derives leaf of a file, see [`LeafHashing`] for available strategies
+pub trait LeafHasher: Send + Sync {
+    fn strategy(&self) -> LeafHashing;
+
+    /// Starts leaf of file with given name whose content is fed in parts, e.g. while it is
+    /// streamed to disk
+    fn start(&self, name: &str) -> Box<dyn LeafDigest>;
+
+    fn leaf(&self, name: &str, content: &[u8]) -> Hash {
+        let mut digest = self.start(name);
+        digest.update(content);
+        digest.finalize()
+    }
+}
+
+/// Leaf being computed from content fed in parts
+pub trait LeafDigest: Send {
+    fn update(&mut self, data: &[u8]);
+
+    fn finalize(self: Box<Self>) -> Hash;
+
+    /// Leaf together with hashes of chunks it was derived from, which only chunked leaves are
+    /// split into
+    fn finalize_chunks(self: Box<Self>) -> (Hash, Vec<Hash>) {
+        (self.finalize(), Vec::new())
+    }
+}
+
+impl LeafDigest for Hasher {
+    fn update(&mut self, data: &[u8]) {
+        Hasher::update(self, data);
+    }
+
+    fn finalize(mut self: Box<Self>) -> Hash {
+        Hasher::finalize(&mut self)
+    }
+}
+
+pub struct ContentLeaves;
+
+impl LeafHasher for ContentLeaves {
+    fn strategy(&self) -> LeafHashing {
+        LeafHashing::Content
+    }
+
+    fn start(&self, _name: &str) -> Box<dyn LeafDigest> {
+        Box::new(Hasher::new())
+    }
+}
+
+pub struct NamedLeaves;
+
+impl LeafHasher for NamedLeaves {
+    fn strategy(&self) -> LeafHashing {
+        LeafHashing::NameAndContent
+    }
+
+    /// Name is hashed in NFC form storage keeps it in, whichever form it was given in
+    fn start(&self, name: &str) -> Box<dyn LeafDigest> {
+        let name = normalize_name(name);
+        let mut hasher = Hasher::new();
+        hasher
+            .update(&(name.len() as u64).to_be_bytes())
+            .update(name.as_bytes());
+        Box::new(hasher)
+    }
+}
+
+pub struct NameHashLeaves;
+
+impl LeafHasher for NameHashLeaves {
+    fn strategy(&self) -> LeafHashing {
+        LeafHashing::NameAndHash
+    }
+
+    fn start(&self, name: &str) -> Box<dyn LeafDigest> {
+        Box::new(NameHashDigest {
+            name: normalize_name(name),
+            content: Hasher::new(),
+        })
+    }
+}
+
+/// Leaf of [`LeafHashing::NameAndHash`] of file with given name and content hash
+pub fn name_hash_leaf(name: &str, content_hash: &Hash) -> Hash {
+    let name = normalize_name(name);
+    let mut hasher = Hasher::new();
+    hasher
+        .update(&(name.len() as u64).to_be_bytes())
+        .update(name.as_bytes())
+        .update(content_hash.as_bytes());
+    hasher.finalize()
+}
+
+struct NameHashDigest {
+    name: String,
+    content: Hasher,
+}
+
+impl LeafDigest for NameHashDigest {
+    fn update(&mut self, data: &[u8]) {
+        self.content.update(data);
+    }
+
+    fn finalize(mut self: Box<Self>) -> Hash {
+        name_hash_leaf(&self.name, &self.content.finalize())
+    }
+}
+
+pub struct ChunkedLeaves(pub usize);
+
+impl LeafHasher for ChunkedLeaves {
+    fn strategy(&self) -> LeafHashing {
+        LeafHashing::Chunked(self.0)
+    }
+
+    fn start(&self, _name: &str) -> Box<dyn LeafDigest> {
+        Box::new(ChunkedDigest {
+            chunk_size: self.0,
+            chunk: Hasher::new(),
+            filled: 0,
+            chunks: Vec::new(),
+        })
+    }
+}
+
+struct ChunkedDigest {
+    chunk_size: usize,
+    chunk: Hasher,
+    /// bytes fed to the current chunk
+    filled: usize,
+    chunks: Vec<Hash>,
+}
+
+impl LeafDigest for ChunkedDigest {
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let taken = (self.chunk_size - self.filled).min(data.len());
+            self.chunk.update(&data[..taken]);
+            self.filled += taken;
+            data = &data[taken..];
+            if self.filled == self.chunk_size {
+                self.chunks.push(self.chunk.finalize());
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn finalize(self: Box<Self>) -> Hash {
+        self.finalize_chunks().0
+    }
+
+    fn finalize_chunks(mut self: Box<Self>) -> (Hash, Vec<Hash>) {
+        // empty content is a single empty chunk
+        if self.filled > 0 || self.chunks.is_empty() {
+            self.chunks.push(self.chunk.finalize());
+        }
+        let leaf = hash_many(&self.chunks.iter().collect::<Vec<_>>());
+        (leaf, self.chunks)
+    }
+}
+
+pub struct KeyedLeaves(pub Vec<u8>);
+
+impl LeafHasher for KeyedLeaves {
+    fn strategy(&self) -> LeafHashing {
+        LeafHashing::Keyed(self.0.clone())
+    }
+
+    fn start(&self, _name: &str) -> Box<dyn LeafDigest> {
+        let mut hasher = Hasher::new();
+        hasher.update(&self.0);
+        Box::new(hasher)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sha3::hash_content;
+
+    #[test]
+    fn test_parse_leaf_hashing() {
+        for s in [
+            "content",
+            "name-content",
+            "name-hash",
+            "chunked:1024",
+            "keyed:00ff",
+        ] {
+            let hashing: LeafHashing = s.parse().expect("should parse");
+            assert_eq!(hashing.to_string(), s);
+            assert_eq!(hashing.hasher().strategy(), hashing);
+        }
+        assert!("chunked:0".parse::<LeafHashing>().is_err());
+        assert!("keyed:".parse::<LeafHashing>().is_err());
+        assert!("keyed:xyz".parse::<LeafHashing>().is_err());
+        assert!("sha256".parse::<LeafHashing>().is_err());
+    }
+
+    #[test]
+    fn test_chunks_of_leaf() {
+        let hasher = LeafHashing::Chunked(4).hasher();
+        let mut digest = hasher.start("a.txt");
+        digest.update(b"0123456789");
+        let (leaf, chunks) = digest.finalize_chunks();
+        assert_eq!(
+            chunks,
+            [
+                hash_content(b"0123"),
+                hash_content(b"4567"),
+                hash_content(b"89")
+            ]
+        );
+        assert_eq!(leaf, hasher.leaf("a.txt", b"0123456789"));
+
+        let (leaf, chunks) = LeafHashing::Content
+            .hasher()
+            .start("a.txt")
+            .finalize_chunks();
+        assert_eq!((leaf, chunks), (hash_content(b""), Vec::new()));
+    }
+
+    #[test]
+    fn test_named_leaves_hash_normalized_name() {
+        let hasher = LeafHashing::NameAndContent.hasher();
+        assert_eq!(
+            hasher.leaf("cafe\u{301}.txt", b"content"),
+            hasher.leaf("café.txt", b"content")
+        );
+        assert_ne!(
+            hasher.leaf("cafe.txt", b"content"),
+            hasher.leaf("café.txt", b"content")
+        );
+    }
+
+    #[test]
+    fn test_leaves_of_parts_match_whole_content() {
+        let content = b"some content split into several parts";
+        for hashing in [
+            "content",
+            "name-content",
+            "name-hash",
+            "chunked:4",
+            "keyed:00ff",
+        ] {
+            let hasher = hashing.parse::<LeafHashing>().unwrap().hasher();
+            let mut digest = hasher.start("a.txt");
+            for part in content.chunks(7) {
+                digest.update(part);
+            }
+            assert_eq!(
+                digest.finalize(),
+                hasher.leaf("a.txt", content),
+                "{hashing}"
+            );
+        }
+
+        assert_eq!(ContentLeaves.leaf("a.txt", content), hash_content(content));
+        assert_ne!(
+            NamedLeaves.leaf("a.txt", content),
+            NamedLeaves.leaf("b.txt", content)
+        );
+        assert_eq!(
+            NameHashLeaves.leaf("a.txt", content),
+            name_hash_leaf("a.txt", &hash_content(content))
+        );
+        assert_ne!(
+            NameHashLeaves.leaf("a.txt", content),
+            NameHashLeaves.leaf("b.txt", content)
+        );
+        assert_ne!(
+            KeyedLeaves(vec![1]).leaf("a.txt", content),
+            KeyedLeaves(vec![2]).leaf("a.txt", content)
+        );
+        let chunks = [&b"abcd"[..], b"ef"].map(hash_content);
+        assert_eq!(
+            ChunkedLeaves(4).leaf("a.txt", b"abcdef"),
+            hash_many(&[&chunks[0], &chunks[1]])
+        );
+    }
+}
@@ -1,6 +1,35 @@
 pub mod api;
+pub mod auth;
+pub mod backend;
+pub mod backup;
+#[cfg(feature = "chaos")]
+pub mod chaos;
 pub mod client;
+pub mod cluster;
+pub mod codec;
+pub mod compact;
+pub mod compression;
+pub mod encryption;
+pub mod federation;
+pub mod hashing;
+pub mod import;
+pub mod interceptor;
+pub mod ipfs;
+pub mod leaf;
 pub mod merkle;
+pub mod metrics;
+pub mod prelude;
+pub mod search;
+pub mod server;
 pub mod service;
 pub mod sha3;
+pub mod share;
+pub mod signing;
+pub mod sparse;
+pub mod staging;
 pub mod storage;
+pub mod store;
+pub mod testvectors;
+pub mod throttle;
+pub mod trace;
+pub mod webhook;
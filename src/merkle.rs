@@ -1,7 +1,12 @@
 use crate::sha3;
 use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 type HashList<T> = Vec<T>;
 
@@ -9,20 +14,109 @@ pub trait Hash<T> {
     fn hash_of(left: &T, right: &T) -> T;
 }
 
+/// Version of serialized trees and proofs. Data written before versioning has no version field
+/// and reads as [`FormatVersion::LEGACY`], data of a newer version than this build knows is
+/// refused instead of being misread. Layout of legacy data is the same as of version 1, so it is
+/// migrated simply by writing it again: current version is always serialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FormatVersion(u32);
+
+impl FormatVersion {
+    pub const LEGACY: FormatVersion = FormatVersion(0);
+    pub const CURRENT: FormatVersion = FormatVersion(1);
+
+    pub fn number(self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for FormatVersion {
+    fn default() -> Self {
+        Self::LEGACY
+    }
+}
+
+impl Serialize for FormatVersion {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        Self::CURRENT.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for FormatVersion {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let version = FormatVersion(u32::deserialize(deserializer)?);
+        if version > Self::CURRENT {
+            return Err(serde::de::Error::custom(format!(
+                "format version {} is newer than supported {}",
+                version.0,
+                Self::CURRENT.0
+            )));
+        }
+        Ok(version)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Tree<T> {
+    #[serde(default)]
+    version: FormatVersion,
     leaves: HashList<T>,
     nodes: Vec<HashList<T>>,
+    /// amount of leading leaves dropped by [`Tree::prune`]
+    #[serde(default)]
+    pruned: usize,
+    /// amount of leading hashes dropped from leaves and every node layer above them
+    #[serde(default)]
+    offsets: Vec<usize>,
+    /// leaves node layers are allocated for when they are created, see [`Tree::with_capacity`]
+    #[serde(skip)]
+    capacity: usize,
 }
 
 impl<T> Tree<T> {
     pub fn new() -> Self {
         Self {
+            version: FormatVersion::CURRENT,
             leaves: Default::default(),
             nodes: Default::default(),
+            pruned: 0,
+            offsets: Default::default(),
+            capacity: 0,
         }
     }
 
+    /// Empty tree with room for `capacity` leaves and all nodes above them, so appending that
+    /// many leaves never grows a layer. Layers grow as usual past it.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            leaves: Vec::with_capacity(capacity),
+            nodes: Vec::with_capacity(depth_of(capacity)),
+            capacity,
+            ..Self::new()
+        }
+    }
+
+    /// Builds tree from leaf hashes in their append order, root is the same as if every hash
+    /// was appended one by one (e.g. by uploading files in that order)
+    pub fn from_manifest(ordered_hashes: impl IntoIterator<Item = T>) -> Self
+    where
+        T: Clone,
+        T: Hash<T>,
+    {
+        let ordered_hashes = ordered_hashes.into_iter();
+        let mut tree = Self::with_capacity(ordered_hashes.size_hint().0);
+        for hash in ordered_hashes {
+            tree.append(hash);
+        }
+        tree
+    }
+
     pub fn root(&self) -> Option<T>
     where
         T: Clone,
@@ -30,13 +124,89 @@ impl<T> Tree<T> {
         self.nodes.last().and_then(|top| top.last().cloned())
     }
 
+    pub fn len(&self) -> usize {
+        self.offset(0) + self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Amount of hash layers above leaves, which is also the length of every proof
+    pub fn depth(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Format version tree was read with
+    pub fn version(&self) -> FormatVersion {
+        self.version
+    }
+
+    /// Shape of the tree and how much it costs to keep and prove
+    pub fn stats(&self) -> TreeStats {
+        let layers = std::iter::once(&self.leaves).chain(&self.nodes);
+        let kept_hashes: usize = layers.clone().map(Vec::len).sum();
+        TreeStats {
+            leaves: self.len(),
+            pruned: self.pruned,
+            nodes_per_layer: layers
+                .enumerate()
+                .map(|(layer, hash_list)| self.offset(layer) + hash_list.len())
+                .collect(),
+            proof_len: self.depth(),
+            proof_bytes: self.depth() * std::mem::size_of::<T>(),
+            memory_bytes: kept_hashes * std::mem::size_of::<T>(),
+        }
+    }
+
+    /// None for leaves which are pruned
+    pub fn leaf(&self, index: usize) -> Option<&T> {
+        index
+            .checked_sub(self.pruned)
+            .and_then(|_| self.leaves.get(index - self.offset(0)))
+    }
+
+    /// Leaves which are not pruned, starting at [`Tree::pruned`]
+    pub fn leaves(&self) -> impl Iterator<Item = &T> {
+        self.leaves.iter().skip(self.pruned - self.offset(0))
+    }
+
+    /// Amount of leading leaves dropped by [`Tree::prune`], which can't be proven anymore
+    pub fn pruned(&self) -> usize {
+        self.pruned
+    }
+
+    /// Amount of hashes dropped from the start of given layer, leaves being the first one
+    fn offset(&self, layer: usize) -> usize {
+        self.offsets.get(layer).copied().unwrap_or(0)
+    }
+
+    /// Drops first `prefix` leaves together with nodes above them, e.g. once all of them are
+    /// tombstoned. Left siblings of the remaining paths (peaks of the pruned part) are kept, so
+    /// root, appends and proofs of the rest of leaves stay the same as in the whole tree.
+    pub fn prune(&mut self, prefix: usize) {
+        let prefix = prefix.min(self.len());
+        if prefix <= self.pruned {
+            return;
+        }
+        let layers = 1 + self.nodes.len();
+        self.offsets.resize(layers, 0);
+        let hash_lists = std::iter::once(&mut self.leaves).chain(self.nodes.iter_mut());
+        for (layer, (hash_list, offset)) in hash_lists.zip(self.offsets.iter_mut()).enumerate() {
+            let keep_from = (prefix >> layer) & !1;
+            hash_list.drain(..keep_from - *offset);
+            *offset = keep_from;
+        }
+        self.pruned = prefix;
+    }
+
     pub fn append(&mut self, hash: T)
     where
         T: Clone,
         T: Hash<T>,
     {
-        self.leaves.push(hash.clone());
-        let (hashed, right_child_added) = hash_of_siblings(&self.leaves);
+        self.leaves.push(hash);
+        let (hashed, right_child_added) = hash_of_siblings(&self.leaves, self.offset(0));
         self.update_next_layer(0, hashed, right_child_added);
     }
 
@@ -44,11 +214,13 @@ impl<T> Tree<T> {
     where
         T: Hash<T>,
     {
+        let offset = self.offset(layer + 1);
         let hash_list = self.nodes.get_mut(layer);
         if hash_list.is_none() {
             // special case - if we have a hash and there is no current layer, that means we reached top and
             // hash is new root hash
-            let hash_list = vec![hash];
+            let mut hash_list = Vec::with_capacity(layer_capacity(self.capacity, layer));
+            hash_list.push(hash);
             self.nodes.push(hash_list);
             return;
         }
@@ -60,47 +232,144 @@ impl<T> Tree<T> {
         } else {
             hash_list.push(hash);
         }
-        if hash_list.len() == 1 {
+        if offset + hash_list.len() == 1 {
             return;
         }
 
-        let (hashed, right_child_added) = hash_of_siblings(hash_list);
+        let (hashed, right_child_added) = hash_of_siblings(hash_list, offset);
         self.update_next_layer(layer + 1, hashed, right_child_added || update_last_hash);
     }
 
+    /// Proof of leaf at `index`, `None` if it was pruned or the tree has no such leaf, empty
+    /// trees included
     pub fn proof_for(&self, mut index: usize) -> Option<Proof<T>>
     where
         T: Clone + Debug + PartialEq + Serialize + DeserializeOwned,
     {
-        let direct_sibling = proof_node_with_sibling(&self.leaves, index);
+        if index < self.pruned || index >= self.len() {
+            return None;
+        }
+        let direct_sibling = proof_node_with_sibling(&self.leaves, self.offset(0), index);
 
         let mut proof_nodes = vec![direct_sibling];
-        for layer in &self.nodes {
-            if layer.len() == 1 {
+        for (layer, hash_list) in self.nodes.iter().enumerate() {
+            let offset = self.offset(layer + 1);
+            if offset + hash_list.len() == 1 {
                 break;
             }
 
             index /= 2;
-            proof_nodes.push(proof_node_with_sibling(layer, index));
+            proof_nodes.push(proof_node_with_sibling(hash_list, offset, index));
+        }
+
+        Some(Proof {
+            version: FormatVersion::CURRENT,
+            nodes: proof_nodes,
+        })
+    }
+
+    /// Single proof for many leaves, siblings shared by their paths or computable from given
+    /// leaves are left out. Missing indices are not proven.
+    pub fn multi_proof_for(&self, indices: &[usize]) -> Option<MultiProof<T>>
+    where
+        T: Clone,
+    {
+        let mut known: Vec<usize> = indices.to_vec();
+        known.sort_unstable();
+        known.dedup();
+        if known.last().is_none_or(|last| *last >= self.len()) || known[0] < self.pruned {
+            return None;
+        }
+        let mut nodes = Vec::new();
+        let layers = std::iter::once(&self.leaves).chain(&self.nodes);
+        for (level, layer) in layers.take(depth(self.len())).enumerate() {
+            let offset = self.offset(level);
+            let mut parents = Vec::with_capacity(known.len());
+            let mut rest = known.iter().peekable();
+            while let Some(&index) = rest.next() {
+                let sibling = index ^ 1;
+                if index % 2 == 0 && rest.peek() == Some(&&sibling) {
+                    rest.next();
+                } else if let Some(hash) = layer.get(sibling - offset) {
+                    nodes.push(hash.clone());
+                }
+                parents.push(index / 2);
+            }
+            known = parents;
         }
+        Some(MultiProof {
+            size: self.len(),
+            nodes,
+        })
+    }
 
-        Some(Proof { nodes: proof_nodes })
+    /// Proves that tree of first `old_size` leaves is a prefix of this tree. Last leaf of the old
+    /// tree with its proof in this tree is enough, since left siblings along its path are the
+    /// same in both trees, while right ones only exist in this tree.
+    pub fn consistency_proof(&self, old_size: usize) -> Option<ConsistencyProof<T>>
+    where
+        T: Clone + Debug + PartialEq + Serialize + DeserializeOwned,
+    {
+        if old_size == 0 || old_size > self.len() {
+            return None;
+        }
+        Some(ConsistencyProof {
+            old_size,
+            new_size: self.len(),
+            last_old_leaf: self.leaf(old_size - 1)?.clone(),
+            path: self.proof_for(old_size - 1)?,
+        })
     }
 }
 
+/// See [`Tree::stats`]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TreeStats {
+    pub leaves: usize,
+    /// leading leaves dropped by [`Tree::prune`]
+    pub pruned: usize,
+    /// nodes in every layer, leaves first and root last, pruned ones included
+    pub nodes_per_layer: Vec<usize>,
+    /// nodes in proof of any leaf
+    pub proof_len: usize,
+    /// hashes in proof of any leaf, without serialization overhead
+    pub proof_bytes: usize,
+    /// hashes kept in memory, without allocation overhead
+    pub memory_bytes: usize,
+}
+
 impl<T> Default for Tree<T> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-fn hash_of_siblings<T>(hash_list: &HashList<T>) -> (T, bool)
+/// Amount of node layers above `leaves` leaves
+fn depth_of(leaves: usize) -> usize {
+    match leaves {
+        0 => 0,
+        leaves => (leaves.max(2) - 1).ilog2() as usize + 1,
+    }
+}
+
+/// Hashes in node layer `layer` (0 right above leaves) of a tree of `leaves` leaves, the last
+/// one of odd layers hashed with itself
+fn layer_capacity(leaves: usize, layer: usize) -> usize {
+    leaves.div_ceil(1 << (layer + 1)).max(1)
+}
+
+/// `offset` is the amount of hashes pruned from the start of `hash_list`, which is hashed right
+/// after a hash was pushed to it, so it is never empty
+fn hash_of_siblings<T>(hash_list: &HashList<T>, offset: usize) -> (T, bool)
 where
     T: Hash<T>,
 {
-    let right_child_exists = hash_list.len() % 2 == 0;
+    let right_child_exists = (offset + hash_list.len()).is_multiple_of(2);
 
-    let last = hash_list.len() - 1;
+    let last = hash_list
+        .len()
+        .checked_sub(1)
+        .expect("hash list should have the hash just pushed");
 
     let (left, right) = if right_child_exists {
         let left = &hash_list[last - 1];
@@ -114,25 +383,27 @@ where
     (T::hash_of(left, right), right_child_exists)
 }
 
-fn proof_node_with_sibling<T>(hash_list: &HashList<T>, index: usize) -> ProofNode<T>
+fn proof_node_with_sibling<T>(hash_list: &HashList<T>, offset: usize, index: usize) -> ProofNode<T>
 where
     T: Clone + Debug + PartialEq + Serialize + DeserializeOwned,
 {
-    let sibling_is_on_the_right = index % 2 == 0;
-
-    if sibling_is_on_the_right {
-        hash_list.get(index + 1)
+    let sibling_is_on_the_right = index.is_multiple_of(2);
+    let sibling = if sibling_is_on_the_right {
+        index + 1
     } else {
-        hash_list.get(index - 1)
-    }
-    .map(|h| match sibling_is_on_the_right {
-        true => ProofNode::RightSiblign(h.clone()),
-        false => ProofNode::LeftSibling(h.clone()),
-    })
-    .unwrap_or(ProofNode::None)
+        index - 1
+    };
+
+    hash_list
+        .get(sibling - offset)
+        .map(|h| match sibling_is_on_the_right {
+            true => ProofNode::RightSiblign(h.clone()),
+            false => ProofNode::LeftSibling(h.clone()),
+        })
+        .unwrap_or(ProofNode::None)
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ProofNode<T>
 where
     T: Debug,
@@ -143,11 +414,13 @@ where
     LeftSibling(T),
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Proof<T>
 where
     T: Debug + PartialEq,
 {
+    #[serde(default)]
+    version: FormatVersion,
     nodes: Vec<ProofNode<T>>,
 }
 
@@ -155,6 +428,16 @@ impl<T> Proof<T>
 where
     T: Debug + PartialEq,
 {
+    /// Format version proof was read with
+    pub fn version(&self) -> FormatVersion {
+        self.version
+    }
+
+    /// Siblings from the leaf up
+    pub fn nodes(&self) -> &[ProofNode<T>] {
+        &self.nodes
+    }
+
     pub fn verify(&self, root_hash: &T, hash: &T) -> bool
     where
         T: Hash<T> + Clone,
@@ -168,6 +451,123 @@ where
     }
 }
 
+/// Proof of many leaves against the same root, see [`Tree::multi_proof_for`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MultiProof<T> {
+    /// amount of leaves in the tree, needed to know which nodes have no right sibling
+    size: usize,
+    /// missing siblings in the order they are needed, layer by layer from the leaves
+    nodes: Vec<T>,
+}
+
+impl<T> MultiProof<T> {
+    /// Verifies leaves given with their indices, all proven leaves have to be given
+    pub fn verify(&self, root_hash: &T, leaves: &[(usize, T)]) -> bool
+    where
+        T: Hash<T> + Clone + PartialEq,
+    {
+        let mut known: Vec<(usize, T)> = leaves.to_vec();
+        known.sort_by_key(|(index, _)| *index);
+        if known.windows(2).any(|pair| pair[0].0 == pair[1].0)
+            || known.last().is_none_or(|(last, _)| *last >= self.size)
+        {
+            return false;
+        }
+        let mut nodes = self.nodes.iter();
+        let mut layer_len = self.size;
+        for _ in 0..depth(self.size) {
+            let mut parents = Vec::with_capacity(known.len());
+            let mut rest = known.into_iter().peekable();
+            while let Some((index, hash)) = rest.next() {
+                let parent = if index % 2 == 1 {
+                    match nodes.next() {
+                        Some(left) => T::hash_of(left, &hash),
+                        None => return false,
+                    }
+                } else if let Some((_, right)) = rest.next_if(|(next, _)| *next == index + 1) {
+                    T::hash_of(&hash, &right)
+                } else if index + 1 < layer_len {
+                    match nodes.next() {
+                        Some(right) => T::hash_of(&hash, right),
+                        None => return false,
+                    }
+                } else {
+                    T::hash_of(&hash, &hash)
+                };
+                parents.push((index / 2, parent));
+            }
+            known = parents;
+            layer_len = layer_len.div_ceil(2);
+        }
+        nodes.next().is_none() && matches!(known.as_slice(), [(0, root)] if root == root_hash)
+    }
+}
+
+/// Links root of a tree to the root of the same tree after more leaves were appended
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConsistencyProof<T>
+where
+    T: Debug + PartialEq,
+{
+    pub old_size: usize,
+    pub new_size: usize,
+    last_old_leaf: T,
+    path: Proof<T>,
+}
+
+impl<T> ConsistencyProof<T>
+where
+    T: Debug + PartialEq,
+{
+    pub fn verify(&self, old_root: &T, new_root: &T) -> bool
+    where
+        T: Hash<T> + Clone,
+    {
+        if self.old_size == 0
+            || self.old_size > self.new_size
+            || self.path.nodes.len() != depth(self.new_size)
+        {
+            return false;
+        }
+        let old_depth = depth(self.old_size);
+        let (old_index, new_index) = (self.old_size - 1, self.new_size - 1);
+        let mut old_hash = self.last_old_leaf.clone();
+        let mut new_hash = self.last_old_leaf.clone();
+        for (layer, node) in self.path.nodes.iter().enumerate() {
+            let is_right_child = (old_index >> layer) % 2 == 1;
+            let has_right_sibling = (old_index >> layer) < (new_index >> layer);
+            match node {
+                ProofNode::LeftSibling(left) if is_right_child => {
+                    old_hash = T::hash_of(left, &old_hash);
+                    new_hash = T::hash_of(left, &new_hash);
+                }
+                ProofNode::RightSiblign(right) if !is_right_child && has_right_sibling => {
+                    new_hash = T::hash_of(&new_hash, right);
+                    old_hash = T::hash_of(&old_hash, &old_hash);
+                }
+                ProofNode::None if !is_right_child && !has_right_sibling => {
+                    new_hash = T::hash_of(&new_hash, &new_hash);
+                    old_hash = T::hash_of(&old_hash, &old_hash);
+                }
+                _ => return false,
+            }
+            if layer + 1 == old_depth && old_hash != *old_root {
+                return false;
+            }
+        }
+        new_hash == *new_root
+    }
+}
+
+/// Amount of layers above leaves in a tree of given size, single leaf is hashed with itself too
+fn depth(size: usize) -> usize {
+    let mut depth = 1;
+    while (1 << depth) < size {
+        depth += 1;
+    }
+    depth
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum NodeState<T> {
     PartialLeft(T),
@@ -205,7 +605,7 @@ where
             }
             NodeState::PartialRight(ref left_hash) if !new_element_stored => {
                 new_element_stored = true;
-                (NodeState::Full, T::hash_of(&left_hash, &hash))
+                (NodeState::Full, T::hash_of(left_hash, &hash))
             }
 
             NodeState::PartialRight(ref left_hash) => (
@@ -214,7 +614,7 @@ where
                 } else {
                     NodeState::PartialRight(left_hash.clone())
                 },
-                T::hash_of(&left_hash, &hash),
+                T::hash_of(left_hash, &hash),
             ),
 
             NodeState::Full if !new_element_stored => {
@@ -236,24 +636,106 @@ where
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
 pub struct LightTree<T>
 where
     T: Debug + PartialEq,
 {
+    #[serde(default)]
+    version: FormatVersion,
     nodes: Vec<LightNode<T>>,
+    /// amount of leaves appended
+    #[serde(default)]
+    size: usize,
+    /// reserved leaf slots which are not appended yet, keyed by leaf index
+    #[serde(default)]
+    pending: BTreeMap<usize, Option<T>>,
 }
 impl<T> LightTree<T>
 where
     T: Debug + PartialEq,
 {
     pub fn new() -> Self {
-        Self { nodes: vec![] }
+        Self {
+            version: FormatVersion::CURRENT,
+            nodes: vec![],
+            size: 0,
+            pending: BTreeMap::new(),
+        }
     }
 
     pub fn append(&mut self, elem: T)
     where
         T: Clone + Hash<T>,
     {
+        if self.pending.is_empty() {
+            self.append_leaf(elem);
+        } else {
+            let index = self.reserve();
+            self.fill(index, elem);
+        }
+    }
+
+    /// Reserves placeholder for the next leaf and returns its index. Root doesn't change until
+    /// this and all previously reserved placeholders are filled.
+    pub fn reserve(&mut self) -> usize {
+        let index = self.size + self.pending.len();
+        self.pending.insert(index, None);
+        index
+    }
+
+    /// Fills reserved placeholder, returns false if there is no such unfilled placeholder
+    pub fn fill(&mut self, index: usize, elem: T) -> bool
+    where
+        T: Clone + Hash<T>,
+    {
+        match self.pending.get_mut(&index) {
+            Some(slot @ None) => *slot = Some(elem),
+            _ => return false,
+        }
+        while let Some(elem) = self
+            .pending
+            .first_key_value()
+            .filter(|(_, elem)| elem.is_some())
+            .map(|(index, _)| *index)
+            .and_then(|index| self.pending.remove(&index))
+            .flatten()
+        {
+            self.append_leaf(elem);
+        }
+        true
+    }
+
+    /// Amount of leaves appended, not counting unfilled placeholders
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Amount of hash layers above leaves, the same as [`Tree::depth`] of a tree with the same
+    /// leaves. Light tree keeps no leaves, so they can't be looked up.
+    pub fn depth(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Amount of reserved placeholders waiting to be appended, filled or not
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Format version tree was read with
+    pub fn version(&self) -> FormatVersion {
+        self.version
+    }
+
+    fn append_leaf(&mut self, elem: T)
+    where
+        T: Clone + Hash<T>,
+    {
+        self.size += 1;
         if self.nodes.is_empty() {
             self.nodes.push(LightNode {
                 hash: T::hash_of(&elem, &elem),
@@ -305,8 +787,221 @@ where
     }
 }
 
-impl Hash<sha3::Hash> for sha3::Hash {
-    fn hash_of(left: &sha3::Hash, right: &sha3::Hash) -> sha3::Hash {
+/// Append-only log of leaf hashes kept outside of memory, see [`HybridTree`]
+pub trait LeafLog<T> {
+    fn append(&mut self, leaf: &T) -> io::Result<()>;
+
+    /// Leaves in append order, read again on every call
+    fn leaves(&self) -> io::Result<Box<dyn Iterator<Item = io::Result<T>> + '_>>;
+}
+
+/// Leaves kept in memory, mostly useful in tests
+impl<T: Clone> LeafLog<T> for Vec<T> {
+    fn append(&mut self, leaf: &T) -> io::Result<()> {
+        self.push(leaf.clone());
+        Ok(())
+    }
+
+    fn leaves(&self) -> io::Result<Box<dyn Iterator<Item = io::Result<T>> + '_>> {
+        Ok(Box::new(self.iter().cloned().map(Ok)))
+    }
+}
+
+/// Sha3 leaves appended to a file as raw bytes one after another
+pub struct FileLeafLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileLeafLog {
+    /// Opens log at given path, creating an empty one if file doesn't exist yet. A torn trailing
+    /// record, left by an append interrupted by a crash, is truncated away so following appends
+    /// stay aligned to whole leaves.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let torn = len % 32;
+        if torn != 0 {
+            file.set_len(len - torn)?;
+            file.sync_data()?;
+        }
+        Ok(Self { path, file })
+    }
+}
+
+impl LeafLog<Sha3Hash> for FileLeafLog {
+    fn append(&mut self, leaf: &Sha3Hash) -> io::Result<()> {
+        self.file.write_all(leaf.as_bytes())?;
+        self.file.sync_data()
+    }
+
+    fn leaves(&self) -> io::Result<Box<dyn Iterator<Item = io::Result<Sha3Hash>> + '_>> {
+        let file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        if len % 32 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("leaf log {:?} ends with a torn record", self.path),
+            ));
+        }
+        let mut reader = BufReader::new(file);
+        Ok(Box::new((0..len / 32).map(move |_| {
+            let mut bytes = [0; 32];
+            reader.read_exact(&mut bytes)?;
+            Ok(Sha3Hash::from_bytes(bytes))
+        })))
+    }
+}
+
+/// Tree keeping only [`LightTree`] frontier in memory, while leaves are appended to a
+/// [`LeafLog`]. Any proof is regenerated on demand by streaming the log once, holding a light
+/// tree per proof layer, so memory stays logarithmic in tree size at the cost of reading all
+/// leaves for every proof.
+pub struct HybridTree<T, L>
+where
+    T: Debug + PartialEq,
+{
+    frontier: LightTree<T>,
+    log: L,
+}
+
+impl<T, L> HybridTree<T, L>
+where
+    T: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Hash<T>,
+    L: LeafLog<T>,
+{
+    /// Rebuilds frontier from leaves already in the log
+    pub fn open(log: L) -> io::Result<Self> {
+        let mut frontier = LightTree::new();
+        for leaf in log.leaves()? {
+            frontier.append(leaf?);
+        }
+        Ok(Self { frontier, log })
+    }
+
+    /// Leaf is logged first, so a failed append leaves the tree unchanged
+    pub fn append(&mut self, leaf: T) -> io::Result<()> {
+        self.log.append(&leaf)?;
+        self.frontier.append(leaf);
+        Ok(())
+    }
+
+    pub fn root(&self) -> Option<T> {
+        self.frontier.root()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frontier.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frontier.is_empty()
+    }
+
+    /// Same proof as [`Tree::proof_for`] of a tree with the same leaves. Sibling of the leaf on
+    /// every layer above is the root of a subtree over a contiguous range of leaves, so each of
+    /// them is rebuilt as a light tree while the log is streamed.
+    pub fn proof_for(&self, index: usize) -> io::Result<Option<Proof<T>>> {
+        let size = self.len();
+        if index >= size {
+            return Ok(None);
+        }
+        let mut leaf_sibling = None;
+        let mut siblings: Vec<(Range<usize>, LightTree<T>)> = (0..depth(size))
+            .map(|layer| {
+                let sibling = (index >> layer) ^ 1;
+                let start = (sibling << layer).min(size);
+                let end = ((sibling + 1) << layer).min(size);
+                (start..end, LightTree::new())
+            })
+            .collect();
+        for (position, leaf) in self.log.leaves()?.enumerate().take(size) {
+            let leaf = leaf?;
+            if position == index ^ 1 {
+                leaf_sibling = Some(leaf);
+            } else if let Some((_, subtree)) = siblings[1..]
+                .iter_mut()
+                .find(|(range, _)| range.contains(&position))
+            {
+                subtree.append(leaf);
+            }
+        }
+        let nodes = siblings
+            .into_iter()
+            .enumerate()
+            .map(|(layer, (range, subtree))| {
+                let hash = match layer {
+                    0 => leaf_sibling.take(),
+                    // lone node at the end of a layer is hashed with itself on every layer up
+                    _ => subtree.root().map(|mut hash| {
+                        for _ in depth(range.len())..layer {
+                            hash = T::hash_of(&hash, &hash);
+                        }
+                        hash
+                    }),
+                };
+                match hash {
+                    None => ProofNode::None,
+                    Some(hash) if (index >> layer).is_multiple_of(2) => {
+                        ProofNode::RightSiblign(hash)
+                    }
+                    Some(hash) => ProofNode::LeftSibling(hash),
+                }
+            })
+            .collect();
+        Ok(Some(Proof {
+            version: FormatVersion::CURRENT,
+            nodes,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LeafDiff<T> {
+    /// leaf present only in the new list
+    Appended { index: usize, hash: T },
+    /// leaf present only in the old list
+    Missing { index: usize, hash: T },
+    /// leaf at the same index differs
+    Mismatched { index: usize, old: T, new: T },
+}
+
+/// Compares two ordered leaf lists index by index
+pub fn diff<T>(old_leaves: &[T], new_leaves: &[T]) -> Vec<LeafDiff<T>>
+where
+    T: Clone + PartialEq,
+{
+    let common = old_leaves.len().min(new_leaves.len());
+    let mismatched = old_leaves
+        .iter()
+        .zip(new_leaves)
+        .enumerate()
+        .filter(|(_, (old, new))| old != new)
+        .map(|(index, (old, new))| LeafDiff::Mismatched {
+            index,
+            old: old.clone(),
+            new: new.clone(),
+        });
+    let missing = old_leaves[common..]
+        .iter()
+        .enumerate()
+        .map(|(i, hash)| LeafDiff::Missing {
+            index: common + i,
+            hash: hash.clone(),
+        });
+    let appended = new_leaves[common..]
+        .iter()
+        .enumerate()
+        .map(|(i, hash)| LeafDiff::Appended {
+            index: common + i,
+            hash: hash.clone(),
+        });
+    mismatched.chain(missing).chain(appended).collect()
+}
+
+impl<A: sha3::Algorithm<N>, const N: usize> Hash<sha3::Digest<A, N>> for sha3::Digest<A, N> {
+    fn hash_of(left: &sha3::Digest<A, N>, right: &sha3::Digest<A, N>) -> sha3::Digest<A, N> {
         sha3::hash_both(left, right)
     }
 }
@@ -314,14 +1009,16 @@ impl Hash<sha3::Hash> for sha3::Hash {
 pub type Sha3Hash = sha3::Hash;
 pub type Sha3Tree = Tree<Sha3Hash>;
 pub type Sha3Proof = Proof<Sha3Hash>;
+pub type Sha3ConsistencyProof = ConsistencyProof<Sha3Hash>;
+pub type Sha3MultiProof = MultiProof<Sha3Hash>;
 
 pub type Sha3LightTree = LightTree<Sha3Hash>;
+pub type Sha3HybridTree = HybridTree<Sha3Hash, FileLeafLog>;
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::sha3::hash_content;
-    use std::u64;
 
     impl Hash<i32> for i32 {
         fn hash_of(left: &i32, right: &i32) -> i32 {
@@ -392,6 +1089,41 @@ mod test {
         assert_eq!(tree.root(), Some(204321))
     }
 
+    #[test]
+    pub fn test_from_manifest() {
+        let mut tree = Tree::new();
+        for value in [1, 20, 300, 4000, 50000] {
+            tree.append(value);
+        }
+
+        let from_manifest = Tree::from_manifest(vec![1, 20, 300, 4000, 50000]);
+        assert_eq!(from_manifest.root(), tree.root());
+        assert!(Tree::<i32>::from_manifest(vec![]).root().is_none());
+    }
+
+    #[test]
+    pub fn test_diff() {
+        assert!(diff(&[1, 2, 3], &[1, 2, 3]).is_empty());
+        assert_eq!(
+            diff(&[1, 2, 3], &[1, 5, 3, 4]),
+            vec![
+                LeafDiff::Mismatched {
+                    index: 1,
+                    old: 2,
+                    new: 5
+                },
+                LeafDiff::Appended { index: 3, hash: 4 }
+            ]
+        );
+        assert_eq!(
+            diff(&[1, 2, 3], &[1]),
+            vec![
+                LeafDiff::Missing { index: 1, hash: 2 },
+                LeafDiff::Missing { index: 2, hash: 3 }
+            ]
+        );
+    }
+
     #[test]
     pub fn test_generated_proof() {
         let mut tree = Tree::new();
@@ -406,6 +1138,22 @@ mod test {
         assert_eq!(vec![ProofNode::LeftSibling(10)], proof.nodes)
     }
 
+    #[test]
+    pub fn test_empty_tree() {
+        let mut tree = Sha3Tree::new();
+        assert!(tree.is_empty() && tree.root().is_none());
+        assert_eq!(tree.proof_for(0), None);
+        assert_eq!(tree.consistency_proof(0), None);
+        assert!(tree.multi_proof_for(&[0]).is_none());
+        assert_eq!(tree.stats().leaves, 0);
+
+        let empty = hash_content(b"");
+        tree.append(empty.clone());
+        let proof = tree.proof_for(0).expect("should exist");
+        assert!(proof.verify(&tree.root().unwrap(), &empty));
+        assert_eq!(tree.proof_for(1), None);
+    }
+
     #[test]
     pub fn test_proof_verification() {
         let mut tree = Tree::new();
@@ -427,6 +1175,87 @@ mod test {
         assert!(proof.verify(&root, &50_000))
     }
 
+    #[test]
+    pub fn test_multi_proof() {
+        let values: Vec<u64> = (0..11).map(|i| u64::pow(10, i)).collect();
+        let leaves = |indices: &[usize]| -> Vec<(usize, u64)> {
+            indices
+                .iter()
+                .map(|index| (*index, values[*index]))
+                .collect()
+        };
+        for size in 1..=values.len() {
+            let tree = Tree::from_manifest(values[..size].to_vec());
+            let root = tree.root().expect("should exist");
+            for mut indices in [
+                vec![0],
+                vec![size - 1],
+                vec![0, size - 1],
+                (0..size).collect(),
+            ] {
+                indices.dedup();
+                let proof = tree.multi_proof_for(&indices).expect("should exist");
+                assert!(
+                    proof.verify(&root, &leaves(&indices)),
+                    "{indices:?} of {size}"
+                );
+                assert!(!proof.verify(&(root + 1), &leaves(&indices)));
+            }
+        }
+
+        let tree = Tree::from_manifest(values.clone());
+        let root = tree.root().expect("should exist");
+        let proof = tree.multi_proof_for(&[7, 2, 3, 2]).expect("should exist");
+        assert!(proof.verify(&root, &leaves(&[2, 3, 7])));
+        assert!(!proof.verify(&root, &leaves(&[2, 3])), "leaf left out");
+        assert!(
+            !proof.verify(&root, &leaves(&[2, 3, 7, 7])),
+            "duplicated leaf"
+        );
+        assert!(!proof.verify(&root, &[(2, 300), (3, 7), (7, 10_000_000)]));
+        // all leaves need no siblings at all
+        assert!(tree
+            .multi_proof_for(&(0..11).collect::<Vec<_>>())
+            .unwrap()
+            .nodes
+            .is_empty());
+        assert!(tree.multi_proof_for(&[]).is_none());
+        assert!(tree.multi_proof_for(&[11]).is_none());
+    }
+
+    #[test]
+    pub fn test_consistency_proof() {
+        let values: Vec<u64> = (0..11).map(|i| u64::pow(10, i)).collect();
+        for new_size in 1..=values.len() {
+            let tree = Tree::from_manifest(values[..new_size].to_vec());
+            let new_root = tree.root().expect("should exist");
+            for old_size in 1..=new_size {
+                let old_root = Tree::from_manifest(values[..old_size].to_vec())
+                    .root()
+                    .expect("should exist");
+                let proof = tree.consistency_proof(old_size).expect("should exist");
+                assert!(
+                    proof.verify(&old_root, &new_root),
+                    "{old_size} -> {new_size}"
+                );
+                assert!(!proof.verify(&(old_root + 1), &new_root));
+                assert!(!proof.verify(&old_root, &(new_root + 1)));
+            }
+        }
+
+        let tree = Tree::from_manifest(values[..5].to_vec());
+        assert!(tree.consistency_proof(0).is_none());
+        assert!(tree.consistency_proof(6).is_none());
+
+        // leaves rewritten after old root was pinned
+        let mut rewritten = values[..5].to_vec();
+        rewritten[1] = 7;
+        let old_root = Tree::from_manifest(values[..3].to_vec()).root().unwrap();
+        let tree = Tree::from_manifest(rewritten);
+        let proof = tree.consistency_proof(3).expect("should exist");
+        assert!(!proof.verify(&old_root, &tree.root().unwrap()));
+    }
+
     #[test]
     pub fn test_lightweight_tree_proof() {
         let mut tree = Tree::new();
@@ -449,6 +1278,271 @@ mod test {
         }
     }
 
+    #[test]
+    pub fn test_stats() {
+        let mut tree = Tree::new();
+        let empty = tree.stats();
+        assert_eq!((empty.nodes_per_layer, empty.proof_len), (vec![0], 0));
+        for value in 1..=5u64 {
+            tree.append(value);
+        }
+        let stats = tree.stats();
+        assert_eq!(stats.nodes_per_layer, vec![5, 3, 2, 1]);
+        assert_eq!(
+            (stats.leaves, stats.proof_len, stats.proof_bytes),
+            (5, 3, 24)
+        );
+        assert_eq!(stats.memory_bytes, 11 * 8);
+
+        tree.prune(4);
+        let stats = tree.stats();
+        assert_eq!((stats.pruned, stats.nodes_per_layer), (4, vec![5, 3, 2, 1]));
+        assert!(stats.memory_bytes < 11 * 8);
+    }
+
+    #[test]
+    pub fn test_hybrid_tree_proofs() {
+        let mut tree = Sha3Tree::new();
+        let mut hybrid = HybridTree::open(Vec::new()).expect("should open");
+        assert_eq!(hybrid.proof_for(0).expect("should read log"), None);
+
+        for i in 0..=17 {
+            let leaf = hash_content(i.to_string());
+            tree.append(leaf.clone());
+            hybrid.append(leaf).expect("should append");
+            assert_eq!(hybrid.root(), tree.root());
+            for index in 0..=i {
+                assert_eq!(
+                    hybrid.proof_for(index).expect("should read log"),
+                    tree.proof_for(index),
+                    "proof of leaf {index} in tree of {} leaves",
+                    i + 1
+                );
+            }
+        }
+        assert_eq!(hybrid.proof_for(18).expect("should read log"), None);
+    }
+
+    #[test]
+    pub fn test_hybrid_tree_reopens_file_log() {
+        let path = std::env::temp_dir().join("safe_storage_leaf_log");
+        let _ = std::fs::remove_file(&path);
+        let leaves: Vec<_> = (0..5).map(|i| hash_content(i.to_string())).collect();
+        let mut hybrid = Sha3HybridTree::open(FileLeafLog::open(&path).expect("should open"))
+            .expect("should rebuild");
+        for leaf in &leaves[..3] {
+            hybrid.append(leaf.clone()).expect("should append");
+        }
+        drop(hybrid);
+
+        let mut hybrid = Sha3HybridTree::open(FileLeafLog::open(&path).expect("should open"))
+            .expect("should rebuild");
+        assert_eq!(hybrid.len(), 3);
+        for leaf in &leaves[3..] {
+            hybrid.append(leaf.clone()).expect("should append");
+        }
+        let tree = Sha3Tree::from_manifest(leaves.clone());
+        let root = tree.root().expect("should have root");
+        assert_eq!(hybrid.root(), Some(root.clone()));
+        let proof = hybrid
+            .proof_for(4)
+            .expect("should read log")
+            .expect("should prove");
+        assert!(proof.verify(&root, &leaves[4]));
+        std::fs::remove_file(&path).expect("should remove");
+    }
+
+    #[test]
+    pub fn test_file_log_truncates_torn_record() {
+        let path = std::env::temp_dir().join("safe_storage_torn_leaf_log");
+        let leaves: Vec<_> = (0..3).map(|i| hash_content(i.to_string())).collect();
+        let mut bytes = leaves[0].as_bytes().to_vec();
+        bytes.extend_from_slice(&leaves[1].as_bytes()[..20]);
+        std::fs::write(&path, bytes).expect("should write");
+
+        let mut hybrid = Sha3HybridTree::open(FileLeafLog::open(&path).expect("should open"))
+            .expect("should rebuild");
+        assert_eq!(hybrid.len(), 1);
+        assert_eq!(std::fs::metadata(&path).expect("should stat").len(), 32);
+        for leaf in &leaves[1..] {
+            hybrid.append(leaf.clone()).expect("should append");
+        }
+        let root = Sha3Tree::from_manifest(leaves.clone()).root();
+        assert_eq!(hybrid.root(), root);
+        let reopened = Sha3HybridTree::open(FileLeafLog::open(&path).expect("should open"))
+            .expect("should rebuild");
+        assert_eq!(reopened.root(), root);
+        std::fs::remove_file(&path).expect("should remove");
+    }
+
+    #[test]
+    pub fn test_format_version() {
+        let mut tree = Tree::new();
+        tree.append(1);
+        tree.append(2);
+        let proof = tree.proof_for(1).expect("should have proof");
+        let encoded = serde_json::to_string(&proof).expect("should serialize");
+        assert!(encoded.starts_with(r#"{"version":1,"#), "{encoded}");
+
+        let legacy = encoded.replace(r#""version":1,"#, "");
+        let legacy: Proof<i32> = serde_json::from_str(&legacy).expect("should read legacy");
+        assert_eq!(legacy.version(), FormatVersion::LEGACY);
+        assert!(legacy.verify(&tree.root().unwrap(), &2));
+        let migrated = serde_json::to_string(&legacy).expect("should serialize");
+        assert_eq!(migrated, encoded);
+
+        let newer = encoded.replace(r#""version":1,"#, r#""version":2,"#);
+        assert!(serde_json::from_str::<Proof<i32>>(&newer).is_err());
+
+        let light_tree: LightTree<i32> =
+            serde_json::from_str(r#"{"nodes": []}"#).expect("should read legacy");
+        assert_eq!(light_tree.version(), FormatVersion::LEGACY);
+        assert_eq!(LightTree::<i32>::new().version(), FormatVersion::CURRENT);
+    }
+
+    #[test]
+    pub fn test_introspection() {
+        let mut tree = Tree::new();
+        let mut light_tree = LightTree::new();
+        assert_eq!((tree.depth(), light_tree.depth()), (0, 0));
+        for value in 1..=9 {
+            tree.append(value);
+            light_tree.append(value);
+            assert_eq!(tree.depth(), light_tree.depth(), "{value} leaves");
+            assert_eq!(tree.len(), light_tree.len());
+            for index in 0..tree.len() {
+                let proof = tree.proof_for(index).expect("should have proof");
+                assert_eq!(proof.nodes.len(), tree.depth());
+            }
+        }
+        assert_eq!(tree.depth(), 4);
+        assert_eq!(tree.leaf(8), Some(&9));
+        assert_eq!(tree.leaf(9), None);
+        assert_eq!(
+            tree.leaves().copied().collect::<Vec<_>>(),
+            (1..=9).collect::<Vec<_>>()
+        );
+
+        light_tree.reserve();
+        assert_eq!((light_tree.len(), light_tree.pending()), (9, 1));
+    }
+
+    #[test]
+    pub fn test_prune() {
+        let hashes: Vec<_> = (0u32..13)
+            .map(|i| hash_content(i.to_be_bytes().as_slice()))
+            .collect();
+        for prefix in 0..=11 {
+            let whole = Sha3Tree::from_manifest(hashes[..11].to_vec());
+            let mut tree = Sha3Tree::from_manifest(hashes[..11].to_vec());
+            tree.prune(prefix / 2);
+            tree.prune(prefix);
+            assert_eq!((tree.pruned(), tree.len()), (prefix, 11), "{prefix}");
+            assert_eq!(tree.root(), whole.root());
+            assert_eq!(tree.leaves().count(), 11 - prefix);
+            for index in 0..11 {
+                let proof = tree.proof_for(index);
+                assert_eq!(proof.is_some(), index >= prefix, "{index} of {prefix}");
+                assert_eq!(tree.leaf(index).is_some(), index >= prefix);
+                if index >= prefix {
+                    assert_eq!(proof, whole.proof_for(index));
+                }
+            }
+            for old_size in 1..=11 {
+                let proof = tree.consistency_proof(old_size);
+                assert_eq!(proof.is_some(), old_size > prefix);
+                if old_size > prefix {
+                    assert_eq!(proof, whole.consistency_proof(old_size));
+                }
+            }
+            assert_eq!(
+                tree.multi_proof_for(&[prefix.min(10), 10]).is_some(),
+                prefix < 11
+            );
+
+            let mut whole = whole;
+            for hash in &hashes[11..] {
+                tree.append(hash.clone());
+                whole.append(hash.clone());
+                assert_eq!(tree.root(), whole.root(), "{prefix}");
+            }
+            for index in prefix..13 {
+                assert_eq!(tree.proof_for(index), whole.proof_for(index));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_lightweight_tree_placeholders() {
+        let mut tree = Tree::new();
+        for value in [1, 20, 300, 4000, 50000] {
+            tree.append(value);
+        }
+
+        let mut light_tree = LightTree::new();
+        light_tree.append(1);
+        let second = light_tree.reserve();
+        let third = light_tree.reserve();
+        light_tree.append(4000);
+        assert_eq!((second, third), (1, 2));
+
+        assert!(light_tree.fill(third, 300));
+        assert!(!light_tree.fill(third, 300), "already filled");
+        assert_eq!(light_tree.len(), 1);
+        assert!(light_tree.fill(second, 20));
+        assert_eq!(light_tree.len(), 4);
+        light_tree.append(50000);
+
+        assert_eq!(light_tree.root(), tree.root());
+        assert!(!light_tree.fill(10, 1), "never reserved");
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        for leaves in [0, 1, 2, 3, 4, 5, 8, 9, 1000] {
+            let mut tree = Sha3Tree::with_capacity(leaves);
+            for i in 0..leaves as u64 {
+                tree.append(hash_content(i.to_be_bytes()));
+            }
+            assert_eq!(depth_of(leaves), tree.depth(), "{leaves} leaves");
+            assert_eq!(tree.leaves.capacity(), leaves);
+            for (layer, hash_list) in tree.nodes.iter().enumerate() {
+                assert_eq!(hash_list.len(), layer_capacity(leaves, layer));
+                assert_eq!(hash_list.capacity(), hash_list.len(), "layer {layer}");
+            }
+            let manifest = Sha3Tree::from_manifest(tree.leaves.iter().cloned());
+            assert_eq!(manifest.root(), tree.root());
+        }
+        let mut tree = Sha3Tree::with_capacity(2);
+        for i in 0..5u64 {
+            tree.append(hash_content(i.to_be_bytes()));
+        }
+        assert_eq!(tree.depth(), 3, "grows past capacity");
+    }
+
+    #[test]
+    #[ignore = "appends to a million leaf tree with and without preallocated layers, run with --ignored --nocapture"]
+    fn bench_million_leaf_appends() {
+        const LEAVES: usize = 1_000_000;
+        let hashes: Vec<_> = (0..LEAVES as u64)
+            .map(|i| hash_content(i.to_be_bytes()))
+            .collect();
+        for preallocated in [false, true] {
+            let started = std::time::Instant::now();
+            let mut tree = match preallocated {
+                true => Sha3Tree::with_capacity(LEAVES),
+                false => Sha3Tree::new(),
+            };
+            for hash in &hashes {
+                tree.append(hash.clone());
+            }
+            println!(
+                "preallocated: {preallocated}, {LEAVES} appends in {:?}",
+                started.elapsed()
+            );
+        }
+    }
+
     #[test]
     #[ignore = "Super naive m tree vs light tree size comparision"]
     pub fn size_comparision() {
@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often [`LagProbe`] tasks wake up
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Measures how late probe tasks wake up on runtimes they are spawned on. Lag grows when worker
+/// threads are blocked instead of polling tasks, e.g. by hashing inline or waiting for the
+/// storage lock.
+#[derive(Debug, Default)]
+pub struct LagProbe {
+    last_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl LagProbe {
+    /// Starts probe task on current tokio runtime, does nothing outside of one
+    pub fn spawn(probe: Arc<LagProbe>) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        runtime.spawn(async move {
+            loop {
+                let started = Instant::now();
+                tokio::time::sleep(PROBE_INTERVAL).await;
+                probe.record(started.elapsed().saturating_sub(PROBE_INTERVAL));
+            }
+        });
+    }
+
+    fn record(&self, lag: Duration) {
+        let micros = lag.as_micros() as u64;
+        self.last_micros.store(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Lag of the latest wakeup and the worst one since previous call, which starts over
+    pub fn take(&self) -> (Duration, Duration) {
+        let last = self.last_micros.load(Ordering::Relaxed);
+        let max = self.max_micros.swap(0, Ordering::Relaxed);
+        (Duration::from_micros(last), Duration::from_micros(max))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_take_resets_max() {
+        let probe = LagProbe::default();
+        probe.record(Duration::from_millis(30));
+        probe.record(Duration::from_millis(2));
+        assert_eq!(
+            probe.take(),
+            (Duration::from_millis(2), Duration::from_millis(30))
+        );
+        assert_eq!(probe.take(), (Duration::from_millis(2), Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_runtime_lags() {
+        let probe = Arc::new(LagProbe::default());
+        LagProbe::spawn(probe.clone());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // blocks the only thread of the test runtime, so the probe wakes up late
+        std::thread::sleep(PROBE_INTERVAL * 2);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (_, max) = probe.take();
+        assert!(max >= PROBE_INTERVAL / 2, "{max:?}");
+    }
+}
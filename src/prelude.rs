@@ -0,0 +1,6 @@
+//! Most commonly used types for embedding safe storage into other programs
+pub use crate::api::FileId;
+pub use crate::merkle::{Hash, Sha3Hash, Sha3LightTree, Sha3Proof, Sha3Tree};
+pub use crate::sha3::hash_content;
+pub use crate::sparse::SparseProof;
+pub use crate::store::{verify, Store};
@@ -0,0 +1,168 @@
+use crate::api::{File, FileId, SearchMatch, TreeSize};
+use crate::storage::{Storage, StorageError};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// Bigger text files are not indexed, they would take memory of many small ones
+pub const MAX_INDEXED_SIZE: u64 = 1024 * 1024;
+
+/// Bytes of text kept by the index at most, files stored once it is full are not indexed
+pub const MAX_INDEX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Matches reported per file at most
+pub const MAX_MATCHES: usize = 10;
+
+/// Bytes of content shown on each side of a match
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Substring index of text files kept in memory, enabled with `ServerConfig::search`. It catches
+/// up with storage on every search instead of being updated by each of the many ways files are
+/// stored and tombstoned: only files stored and tombstoned by leaves appended since the last
+/// search are looked at, see [`Storage::list_changes`].
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    /// contents with ascii letters lowercased, so byte offsets of matches are offsets into
+    /// original contents
+    texts: BTreeMap<FileId, String>,
+    /// tree size changes were indexed up to
+    indexed: TreeSize,
+    /// bytes of all texts
+    bytes: usize,
+}
+
+/// Content types indexed besides `text/*`
+fn is_text(file: &File) -> bool {
+    let mime = file.mime.split(';').next().unwrap_or_default().trim();
+    mime.starts_with("text/")
+        || matches!(
+            mime,
+            "application/json" | "application/xml" | "application/x-yaml"
+        )
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.texts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.texts.is_empty()
+    }
+
+    /// Indexes text files stored since the last update, forgetting tombstoned ones
+    pub fn update(&mut self, storage: &Storage, now: SystemTime) -> Result<(), StorageError> {
+        let (stored, deleted, tree_size) = storage.list_changes(self.indexed, now)?;
+        for id in deleted {
+            if let Some(text) = self.texts.remove(&id) {
+                self.bytes -= text.len();
+            }
+        }
+        for file in stored {
+            let fits = self.bytes + file.size as usize <= MAX_INDEX_BYTES;
+            if !is_text(&file) || file.size > MAX_INDEXED_SIZE || !fits {
+                continue;
+            }
+            let (_, content, _) = storage.get_file_by_id(file.id)?;
+            if let Ok(text) = String::from_utf8(content) {
+                self.bytes += text.len();
+                self.texts.insert(file.id, text.to_ascii_lowercase());
+            }
+        }
+        self.indexed = tree_size;
+        Ok(())
+    }
+
+    /// Ids of files containing `query` with their first matches, at most `limit` files in
+    /// upload order. Empty query matches nothing.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<(FileId, Vec<SearchMatch>)> {
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let query = query.to_ascii_lowercase();
+        self.texts
+            .iter()
+            .filter_map(|(id, text)| {
+                let matches: Vec<_> = text
+                    .match_indices(&query)
+                    .take(MAX_MATCHES)
+                    .map(|(offset, _)| snippet(text, offset, query.len()))
+                    .collect();
+                (!matches.is_empty()).then_some((*id, matches))
+            })
+            .take(limit)
+            .collect()
+    }
+}
+
+fn snippet(text: &str, offset: usize, len: usize) -> SearchMatch {
+    let mut start = offset.saturating_sub(SNIPPET_CONTEXT);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (offset + len + SNIPPET_CONTEXT).min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+    SearchMatch {
+        offset: offset as u64,
+        snippet_start: start as u64,
+        snippet_end: end as u64,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_search_text_files() {
+        let mut storage = Storage::new();
+        let notes = storage
+            .add_new_file(
+                "notes.txt".to_string(),
+                b"Merkle trees, merkle proofs".to_vec(),
+            )
+            .expect("should add");
+        storage
+            .add_new_file("image.png".to_string(), b"\x89PNG\r\n\x1a\nmerkle".to_vec())
+            .expect("should add");
+        let long = "ą".repeat(30) + "needle" + &"ę".repeat(30);
+        let other = storage
+            .add_new_file("other.txt".to_string(), long.into_bytes())
+            .expect("should add");
+
+        let mut index = SearchIndex::new();
+        index
+            .update(&storage, SystemTime::now())
+            .expect("should index");
+        assert_eq!(index.len(), 2);
+
+        let hits = index.search("MERKLE", 10);
+        assert_eq!(hits.len(), 1);
+        let (id, matches) = &hits[0];
+        assert_eq!(*id, notes);
+        let offsets: Vec<_> = matches.iter().map(|found| found.offset).collect();
+        assert_eq!(offsets, [0, 14]);
+
+        let hits = index.search("needle", 10);
+        let found = &hits[0].1[0];
+        assert_eq!(found.offset, 60);
+        assert_eq!((found.snippet_start, found.snippet_end), (20, 106));
+        assert!(index.search("", 10).is_empty());
+
+        storage.delete_file(other).expect("should delete");
+        storage
+            .add_new_file("later.txt".to_string(), b"needle again".to_vec())
+            .expect("should add");
+        index
+            .update(&storage, SystemTime::now())
+            .expect("should index");
+        let hits = index.search("needle", 10);
+        assert_eq!((hits.len(), index.len()), (1, 2));
+        assert_eq!(index.bytes, 27 + 12);
+    }
+}
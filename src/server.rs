@@ -0,0 +1,3071 @@
+use crate::api::{
+    Divergence, ServerFeatures, ServerInfo, ServerLimits, TreeMode, HASH_ALGORITHM,
+    MAX_BATCH_FILES, PROTOCOL_VERSION,
+};
+use crate::auth::ApiKeys;
+use crate::backend::{BlobStore, MemoryBlobs, MemoryMetadata, MetadataStore};
+use crate::backup;
+use crate::client::Client;
+use crate::cluster::Router;
+use crate::codec::{FILE_META_HEADER, MAX_BODY_SIZE};
+use crate::compression::Codings;
+use crate::federation::{submit_to_peers, Attestations, FederationConfig};
+use crate::hashing::{canonical_name, HashPool};
+use crate::import::Importer;
+use crate::interceptor::{MaxSize, UploadInterceptors};
+use crate::ipfs::IpfsNode;
+use crate::leaf::LeafHashing;
+use crate::metrics::LagProbe;
+use crate::search::SearchIndex;
+use crate::service::{
+    delete_file, export_to_ipfs, get_all_usage, get_attestations, get_cluster, get_consistency,
+    get_dataset, get_dataset_proof, get_datasets, get_deletion_receipt, get_epoch_archive,
+    get_epochs, get_file_batch, get_file_changes, get_file_content, get_file_list,
+    get_file_preview, get_file_raw, get_file_status, get_health, get_historical_proof, get_info,
+    get_keys, get_leaves, get_migrations, get_proof_by_hash, get_stats, get_tree_root, get_usage,
+    import_epoch, import_file, reserve_file, rotate_key, search_files, submit_attestation,
+    undelete_file, upload_file_batch, upload_new_file, upload_quarantined, upload_reserved_file,
+    upload_stream,
+};
+use crate::signing::{Keyring, ServerKey};
+use crate::staging::Staging;
+use crate::storage::{
+    CollisionPolicy, EpochPolicy, Quota, Rehashed, ShardRange, Storage, StorageError, TreeLimit,
+    WhenFull, DEFAULT_HOT_PROOFS, MAX_SHARDS,
+};
+use crate::throttle::RateLimit;
+use crate::trace::{start_server_span, OtlpExporter, Span};
+use crate::webhook::Webhook;
+use actix_cors::Cors;
+use actix_web::dev::Service;
+use actix_web::{dev, web, App, HttpServer};
+use anyhow::anyhow;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+
+/// Leaves indexed at once by background rebuild of secondary indexes, see
+/// [`Storage::rebuild_indexes`]
+pub const DEFAULT_REBUILD_BATCH: usize = 10_000;
+
+/// Pause between batches of background index rebuild, when the storage lock is free for requests
+const REBUILD_PAUSE: Duration = Duration::from_millis(10);
+
+/// Files whose contents are read at once by periodic backups, the storage lock is released
+/// between batches
+const BACKUP_BATCH: usize = 64;
+
+/// Configuration of http service, port 0 binds to any free port
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// addresses to listen on instead of `host` and `port`, if any
+    pub listen: Vec<ListenAddr>,
+    /// bandwidth of each file download, uploads are shaped by clients sending them
+    pub limit_rate: Option<RateLimit>,
+    /// uploads bigger than this are rejected before other interceptors run, see [`MaxSize`]
+    pub max_upload_size: Option<usize>,
+    pub interceptors: UploadInterceptors,
+    /// fetches content of `POST /files/import` requests
+    pub importer: Importer,
+    pub api_keys: ApiKeys,
+    pub quota: Quota,
+    pub collision_policy: CollisionPolicy,
+    /// where file records and tree leaves are kept
+    pub metadata: Box<dyn MetadataStore>,
+    /// where file contents are kept
+    pub blobs: Box<dyn BlobStore>,
+    /// how many big uploads can be hashed in parallel
+    pub hash_threads: usize,
+    /// how long idle connections are kept open for following requests
+    pub keep_alive: Duration,
+    /// how often files with passed ttl are looked up and tombstoned, and downloads are flushed
+    pub expiry_interval: Duration,
+    /// how often stored contents and leaves are checked against persisted root chain, besides
+    /// the check at startup
+    pub integrity_interval: Duration,
+    /// when current tree is sealed and a new epoch started
+    pub epoch_policy: EpochPolicy,
+    /// bound on leaves of the in-memory epoch tree, unbounded without it
+    pub tree_limit: Option<TreeLimit>,
+    /// sign checkpoints of sealed epochs, the last one is active and earlier ones were used before
+    pub server_keys: Vec<ServerKey>,
+    /// amount of proofs computed ahead of requests and kept up to date
+    pub hot_proofs: usize,
+    /// base url of OpenTelemetry collector spans of handled requests are exported to
+    pub otlp_endpoint: Option<String>,
+    /// cross-origin access for browser clients
+    pub cors: CorsConfig,
+    /// where streamed uploads are written to before they are committed, best kept on the same
+    /// file system as blob directory so committing is a rename
+    pub staging_dir: PathBuf,
+    /// content accepted as a shard of experimental cluster, see [`spawn_router`]
+    pub shard: Option<ShardRange>,
+    /// node contents are pinned to by `POST /files/{id}/ipfs`, which is disabled without it
+    pub ipfs: Option<IpfsNode>,
+    /// how leaves are derived from file names and contents, reported by `GET /info`
+    pub leaf_hashing: LeafHashing,
+    /// contents of deleted files are kept so `POST /files/{id}/undelete` can restore them
+    pub keep_deleted: bool,
+    /// files not downloaded for this long are tombstoned as expired
+    pub idle_ttl: Option<Duration>,
+    /// reservations not filled for this long are abandoned, see [`Storage::delete_expired`]
+    pub reservation_ttl: Option<Duration>,
+    /// files ingested at startup into empty storage, see [`seed`]
+    pub seed_dir: Option<PathBuf>,
+    /// backup chain restored at startup into empty storage, see [`backup::restore`]
+    pub restore_dir: Option<PathBuf>,
+    /// directory storage is backed up to every `backup_interval` once anything changed, see
+    /// [`backup::backup`]
+    pub backup_dir: Option<PathBuf>,
+    pub backup_interval: Duration,
+    /// leaves indexed at once by background rebuild of secondary indexes after startup
+    pub rebuild_batch: usize,
+    /// codings request bodies are accepted in and api responses are compressed with
+    pub codings: Codings,
+    /// keep text files indexed in memory for `GET /search`, which is disabled without it
+    pub search: bool,
+    /// files are named by hex of their content hash instead of names they are uploaded with, see
+    /// [`HashPool::hash_named`]
+    pub name_by_hash: bool,
+    /// endpoint stored and deleted files and root changes are posted to
+    pub webhook: Option<Webhook>,
+    /// accept signed roots of peers at `POST /attestations` and submit own root to configured
+    /// ones, both disabled without it
+    pub federation: Option<FederationConfig>,
+    /// faults injected into responses
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<crate::chaos::Chaos>,
+}
+
+/// Configuration of experimental cluster router, shards are listed in their order
+pub struct RouterConfig {
+    pub host: String,
+    pub port: u16,
+    /// base urls of shard servers, each started with its [`ShardRange::of`] range
+    pub shards: Vec<String>,
+}
+
+/// Origin browser apps calling the api may be served from
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorsOrigin {
+    Any,
+    /// scheme, host and optional port, e.g. `https://app.example.com`
+    Exact(String),
+}
+
+/// `*` allows any origin
+impl FromStr for CorsOrigin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(CorsOrigin::Any);
+        }
+        let host = s
+            .strip_prefix("https://")
+            .or_else(|| s.strip_prefix("http://"))
+            .ok_or_else(|| anyhow!("origin must start with http:// or https://"))?;
+        if host.is_empty() || host.contains(['/', '?', '#', ' ']) {
+            return Err(anyhow!("origin must have host and no path"));
+        }
+        Ok(CorsOrigin::Exact(s.to_string()))
+    }
+}
+
+/// Address server listens on
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    /// host (or `[ipv6]`) and port, served with http/1.1 and http/2 with prior knowledge
+    Tcp(String, u16),
+    /// unix domain socket path, served with http/1.1
+    Unix(PathBuf),
+}
+
+/// `HOST:PORT` or `unix:PATH`
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if !cfg!(unix) {
+                return Err(anyhow!("unix sockets are not supported on this platform"));
+            }
+            if path.is_empty() {
+                return Err(anyhow!("unix socket path is missing"));
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("address must be HOST:PORT or unix:PATH"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(anyhow!("address must have host"));
+        }
+        Ok(ListenAddr::Tcp(host.to_string(), port.parse()?))
+    }
+}
+
+/// Cross-origin access for browser clients, all cross-origin requests are refused while no
+/// origin is allowed. Requests from other origins are still answered, just without CORS headers,
+/// so browsers enforce it and same-origin requests carrying `Origin` (e.g. from the web UI) work.
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    pub origins: Vec<CorsOrigin>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+    /// how long browsers may cache preflight responses, in seconds
+    pub max_age: Option<usize>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            origins: vec![],
+            methods: ["GET", "POST", "PUT", "DELETE"].map(String::from).to_vec(),
+            headers: ["authorization", "content-type", "accept", "traceparent"]
+                .map(String::from)
+                .to_vec(),
+            max_age: Some(3600),
+        }
+    }
+}
+
+impl CorsConfig {
+    fn middleware(&self) -> Cors {
+        let mut cors = Cors::default()
+            .allowed_methods(self.methods.iter().map(String::as_str))
+            .allowed_headers(self.headers.iter().map(String::as_str))
+            .expose_headers([FILE_META_HEADER, "etag"])
+            .max_age(self.max_age)
+            .block_on_origin_mismatch(false);
+        for origin in &self.origins {
+            cors = match origin {
+                CorsOrigin::Any => cors.allow_any_origin(),
+                CorsOrigin::Exact(origin) => cors.allowed_origin(origin),
+            };
+        }
+        cors
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            listen: vec![],
+            limit_rate: None,
+            max_upload_size: None,
+            interceptors: UploadInterceptors::new(),
+            importer: Importer::default(),
+            api_keys: ApiKeys::default(),
+            quota: Quota::default(),
+            collision_policy: CollisionPolicy::default(),
+            metadata: Box::<MemoryMetadata>::default(),
+            blobs: Box::<MemoryBlobs>::default(),
+            hash_threads: HashPool::default().threads(),
+            keep_alive: Duration::from_secs(75),
+            expiry_interval: Duration::from_secs(60),
+            integrity_interval: Duration::from_secs(300),
+            epoch_policy: EpochPolicy::default(),
+            tree_limit: None,
+            server_keys: Vec::new(),
+            hot_proofs: DEFAULT_HOT_PROOFS,
+            otlp_endpoint: None,
+            cors: CorsConfig::default(),
+            staging_dir: std::env::temp_dir().join("safe-storage-uploads"),
+            shard: None,
+            ipfs: None,
+            leaf_hashing: LeafHashing::default(),
+            keep_deleted: false,
+            idle_ttl: None,
+            reservation_ttl: None,
+            seed_dir: None,
+            restore_dir: None,
+            backup_dir: None,
+            backup_interval: Duration::from_secs(3600),
+            rebuild_batch: DEFAULT_REBUILD_BATCH,
+            codings: Codings::default(),
+            search: false,
+            name_by_hash: false,
+            webhook: None,
+            federation: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// What `GET /info` of server started with this configuration reports
+    pub fn info(&self) -> ServerInfo {
+        let epochs = self.epoch_policy.max_leaves.is_some()
+            || self.epoch_policy.max_age.is_some()
+            || matches!(
+                self.tree_limit,
+                Some(TreeLimit {
+                    when_full: WhenFull::Seal,
+                    ..
+                })
+            );
+        ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: PROTOCOL_VERSION,
+            hash_algorithm: HASH_ALGORITHM.to_string(),
+            leaf_hashing: self.leaf_hashing.clone(),
+            tree_mode: if epochs {
+                TreeMode::Epochs
+            } else {
+                TreeMode::Single
+            },
+            features: ServerFeatures {
+                chunked_leaves: matches!(self.leaf_hashing, LeafHashing::Chunked(_)),
+                auth: !self.api_keys.is_empty(),
+                signed_checkpoints: !self.server_keys.is_empty(),
+                ipfs_export: self.ipfs.is_some(),
+                undelete: self.keep_deleted,
+                search: self.search,
+                name_by_hash: self.name_by_hash,
+                federation: self.federation.is_some(),
+            },
+            limits: ServerLimits {
+                max_body_bytes: MAX_BODY_SIZE as u64,
+                max_batch_files: MAX_BATCH_FILES as u64,
+                max_upload_bytes: self.max_upload_size.map(|size| size as u64),
+                max_import_bytes: self.importer.max_size(),
+                quota_bytes: self.quota.max_bytes,
+                quota_files: self.quota.max_files,
+                epoch_max_leaves: self.epoch_policy.max_leaves.map(|leaves| leaves as u64),
+                max_tree_leaves: self.tree_limit.map(|limit| limit.max_leaves as u64),
+            },
+        }
+    }
+}
+
+/// Running in-process server
+pub struct ServerHandle {
+    addrs: Vec<SocketAddr>,
+    /// unix sockets listened on, removed once server stops
+    sockets: Vec<PathBuf>,
+    handle: dev::ServerHandle,
+    task: JoinHandle<io::Result<()>>,
+    /// periodic maintenance tasks, aborted once server stops
+    background: Vec<JoinHandle<()>>,
+    /// storage whose downloads counted in memory are flushed once server stops, none for routers
+    storage: Option<web::Data<Mutex<Storage>>>,
+}
+
+impl ServerHandle {
+    /// First tcp address server is actually bound to, none if it listens on unix sockets only
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addrs.first().copied()
+    }
+
+    /// Base url suitable for `Client::try_new`, `unix://` one if server listens on unix sockets only
+    pub fn url(&self) -> String {
+        match (self.addrs.first(), self.sockets.first()) {
+            (None, Some(socket)) => format!("unix://{}", socket.display()),
+            (addr, _) => format!("http://{}", addr.expect("server listens somewhere")),
+        }
+    }
+
+    /// Stops accepting new connections, with graceful stop in-flight requests are finished first
+    pub async fn stop(self, graceful: bool) -> io::Result<()> {
+        self.handle.stop(graceful).await;
+        self.wait().await
+    }
+
+    /// Waits until server is stopped (e.g. by a signal)
+    pub async fn wait(self) -> io::Result<()> {
+        let stopped = self.task.await.map_err(io::Error::other);
+        for task in self.background {
+            task.abort();
+        }
+        if let Some(storage) = self.storage {
+            if let Err(err) = storage.lock().expect("should lock").flush_downloads() {
+                eprintln!("failed to flush downloads: {err}");
+            }
+        }
+        for socket in self.sockets {
+            let _ = std::fs::remove_file(socket);
+        }
+        stopped?
+    }
+}
+
+/// Binds and starts http service on current tokio runtime
+pub fn spawn(config: ServerConfig) -> io::Result<ServerHandle> {
+    let named_leaves = matches!(
+        config.leaf_hashing,
+        LeafHashing::NameAndContent | LeafHashing::NameAndHash
+    );
+    if named_leaves && config.collision_policy == CollisionPolicy::Suffix {
+        return Err(io::Error::other(format!(
+            "{} leaf hashing can't be combined with suffix collision policy",
+            config.leaf_hashing
+        )));
+    }
+    if config.name_by_hash && config.collision_policy == CollisionPolicy::Suffix {
+        return Err(io::Error::other(
+            "naming by hash can't be combined with suffix collision policy",
+        ));
+    }
+    if config.hash_threads == 0 {
+        return Err(io::Error::other("hash threads must not be zero"));
+    }
+    if config.expiry_interval.is_zero() {
+        return Err(io::Error::other("expiry interval must not be zero"));
+    }
+    if config.integrity_interval.is_zero() {
+        return Err(io::Error::other("integrity interval must not be zero"));
+    }
+    if config.reservation_ttl.is_some_and(|ttl| ttl.is_zero()) {
+        return Err(io::Error::other("reservation ttl must not be zero"));
+    }
+    if let Some(limit) = config.tree_limit {
+        if limit.max_leaves == 0 {
+            return Err(io::Error::other("max tree leaves must not be zero"));
+        }
+        let sealed = config.epoch_policy.max_age.is_some()
+            || config
+                .epoch_policy
+                .max_leaves
+                .is_some_and(|leaves| leaves <= limit.max_leaves);
+        if limit.when_full == WhenFull::Refuse && !sealed {
+            return Err(io::Error::other(
+                "full tree can't be refused without epoch policy sealing it",
+            ));
+        }
+    }
+    let submits_roots = config
+        .federation
+        .as_ref()
+        .is_some_and(|federation| !federation.peers.is_empty());
+    if config.backup_dir.is_some() && config.backup_interval.is_zero() {
+        return Err(io::Error::other("backup interval must not be zero"));
+    }
+    if submits_roots
+        && config
+            .federation
+            .as_ref()
+            .is_some_and(|federation| federation.interval.is_zero())
+    {
+        return Err(io::Error::other("federation interval must not be zero"));
+    }
+    if submits_roots && config.server_keys.is_empty() {
+        return Err(io::Error::other(
+            "roots can't be submitted to peers without a server key to sign them",
+        ));
+    }
+    let peers = config
+        .federation
+        .iter()
+        .flat_map(|federation| {
+            federation.peers.iter().map(|peer| {
+                Client::try_new(peer.clone())
+                    .map(|client| client.with_api_key(federation.api_key.clone()))
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(io::Error::other)?;
+    let info = web::Data::new(config.info());
+    let leaf_hasher = config.leaf_hashing.hasher();
+    let (mut metadata, mut blobs) = (config.metadata, config.blobs);
+    if let Some(dir) = &config.restore_dir {
+        restore(dir, metadata.as_mut(), blobs.as_mut())?;
+    }
+    let mut storage = Storage::open(metadata, blobs)
+        .map_err(io::Error::other)?
+        .with_quota(config.quota)
+        .with_collision_policy(config.collision_policy)
+        .with_epoch_policy(config.epoch_policy)
+        .with_tree_limit(config.tree_limit)
+        .with_keyring(Keyring::new(config.server_keys))
+        .with_hot_proofs(config.hot_proofs)
+        .with_shard(config.shard)
+        .with_leaf_hasher(leaf_hasher.clone())
+        .with_keep_deleted(config.keep_deleted)
+        .with_idle_ttl(config.idle_ttl)
+        .with_reservation_ttl(config.reservation_ttl);
+    check_integrity(&mut storage);
+    if let Some(dir) = &config.seed_dir {
+        seed(&mut storage, dir, config.name_by_hash)?;
+    }
+    let storage = web::Data::new(Mutex::new(storage));
+    let rate_limit = web::Data::new(config.limit_rate);
+    let interceptors = web::Data::new(match config.max_upload_size {
+        Some(max_size) => config.interceptors.with_first(MaxSize(max_size)),
+        None => config.interceptors,
+    });
+    let importer = web::Data::new(config.importer);
+    let ipfs = web::Data::new(config.ipfs);
+    let search = web::Data::new(config.search.then(|| Mutex::new(SearchIndex::new())));
+    let attestations = web::Data::new(
+        config
+            .federation
+            .as_ref()
+            .map(|_| Mutex::new(Attestations::new())),
+    );
+    let api_keys = web::Data::new(config.api_keys);
+    let hash_pool = web::Data::new(
+        HashPool::new(config.hash_threads)
+            .with_leaf_hasher(leaf_hasher)
+            .with_names_by_hash(config.name_by_hash),
+    );
+    let staging = web::Data::new(Staging::open(config.staging_dir)?);
+    let lag_probe = web::Data::new(LagProbe::default());
+    let maintained = storage.clone();
+    let exporter = config
+        .otlp_endpoint
+        .as_deref()
+        .map(|endpoint| OtlpExporter::spawn(endpoint, "safe-storage"));
+    let cors = config.cors;
+    let codings = web::Data::new(config.codings);
+    #[cfg(feature = "chaos")]
+    let chaos = config.chaos.map(std::sync::Arc::new);
+    let mut server = HttpServer::new(move || {
+        let exporter = exporter.clone();
+        // factory runs on every worker, each gets its own probe task
+        LagProbe::spawn(lag_probe.clone().into_inner());
+        let app = App::new()
+            .wrap(cors.middleware())
+            .wrap_fn(move |req, srv| {
+                let (parent, context) = start_server_span(&req);
+                let started = SystemTime::now();
+                let response = srv.call(req);
+                let exporter = exporter.clone();
+                async move {
+                    let response = response.await?;
+                    if let Some(exporter) = exporter {
+                        exporter.export(Span::of_response(&response, parent, context, started));
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap_fn({
+                let codings = codings.clone();
+                move |req, srv| {
+                    let response = srv.call(req);
+                    let codings = codings.clone();
+                    async move { codings.compress(response.await?).await }
+                }
+            })
+            .app_data(storage.clone())
+            .app_data(rate_limit.clone())
+            .app_data(interceptors.clone())
+            .app_data(importer.clone())
+            .app_data(ipfs.clone())
+            .app_data(search.clone())
+            .app_data(attestations.clone())
+            .app_data(api_keys.clone())
+            .app_data(hash_pool.clone())
+            .app_data(staging.clone())
+            .app_data(lag_probe.clone())
+            .app_data(info.clone())
+            .app_data(codings.clone())
+            .app_data(web::PayloadConfig::new(MAX_BODY_SIZE))
+            .service(get_file_list)
+            .service(upload_new_file)
+            .service(upload_file_batch)
+            .service(upload_stream)
+            .service(upload_quarantined)
+            .service(import_file)
+            .service(reserve_file)
+            .service(upload_reserved_file)
+            .service(get_file_batch)
+            .service(get_file_changes)
+            .service(get_file_content)
+            .service(get_file_preview)
+            .service(get_file_status)
+            .service(get_file_raw)
+            .service(get_historical_proof)
+            .service(get_tree_root)
+            .service(get_consistency)
+            .service(get_proof_by_hash)
+            .service(get_datasets)
+            .service(get_dataset)
+            .service(get_dataset_proof)
+            .service(get_epochs)
+            .service(get_epoch_archive)
+            .service(get_keys)
+            .service(rotate_key)
+            .service(import_epoch)
+            .service(get_leaves)
+            .service(delete_file)
+            .service(undelete_file)
+            .service(export_to_ipfs)
+            .service(get_deletion_receipt)
+            .service(get_usage)
+            .service(get_all_usage)
+            .service(get_migrations)
+            .service(search_files)
+            .service(submit_attestation)
+            .service(get_attestations)
+            .service(get_info)
+            .service(get_stats)
+            .service(get_health)
+            .configure(web_ui);
+        // outermost, so faults hit responses the way clients see them
+        #[cfg(feature = "chaos")]
+        let app = {
+            let chaos = chaos.clone();
+            app.wrap_fn(move |req, srv| {
+                let response = srv.call(req);
+                let chaos = chaos.clone();
+                async move {
+                    let response = response.await?;
+                    match chaos {
+                        Some(chaos) => chaos.apply(response).await,
+                        None => Ok(response.map_into_boxed_body()),
+                    }
+                }
+            })
+        };
+        app
+    })
+    .keep_alive(config.keep_alive);
+    let listen = if config.listen.is_empty() {
+        vec![ListenAddr::Tcp(config.host, config.port)]
+    } else {
+        config.listen
+    };
+    let (mut addrs, mut sockets) = (vec![], vec![]);
+    for addr in listen {
+        match addr {
+            // serves both http/1.1 and http/2 with prior knowledge (h2c) on the same port
+            ListenAddr::Tcp(host, port) => {
+                // actix lists a placeholder address for unix sockets, so only new tcp ones are kept
+                let bound = server.addrs().len();
+                server = server.bind_auto_h2c((host.as_str(), port))?;
+                addrs.extend_from_slice(&server.addrs()[bound..]);
+            }
+            ListenAddr::Unix(path) => {
+                #[cfg(unix)]
+                {
+                    remove_stale_socket(&path)?;
+                    server = server.bind_uds(&path)?;
+                    sockets.push(path);
+                }
+                #[cfg(not(unix))]
+                return Err(io::Error::other(format!(
+                    "can't listen on {}, unix sockets are not supported on this platform",
+                    path.display()
+                )));
+            }
+        }
+    }
+    let server = server.run();
+    let handle = server.handle();
+    let task = tokio::spawn(server);
+    let mut background = vec![
+        tokio::spawn(rebuild_indexes(maintained.clone(), config.rebuild_batch)),
+        tokio::spawn(delete_expired(maintained.clone(), config.expiry_interval)),
+        tokio::spawn(check_integrity_periodically(
+            maintained.clone(),
+            config.integrity_interval,
+        )),
+    ];
+    if let Some(dir) = config.backup_dir {
+        background.push(tokio::spawn(back_up_periodically(
+            maintained.clone(),
+            dir,
+            config.backup_interval,
+        )));
+    }
+    if let Some(webhook) = config.webhook {
+        background.push(tokio::spawn(deliver_webhooks(maintained.clone(), webhook)));
+    }
+    if let Some(federation) = config
+        .federation
+        .filter(|federation| !federation.peers.is_empty())
+    {
+        background.push(tokio::spawn(submit_roots(
+            maintained.clone(),
+            peers,
+            federation.interval,
+        )));
+    }
+    if let Some(max_age) = config.epoch_policy.max_age {
+        // epochs are sealed at most a tenth of their age late
+        let interval = (max_age / 10).clamp(Duration::from_secs(1), Duration::from_secs(60));
+        background.push(tokio::spawn(seal_old_epochs(maintained.clone(), interval)));
+    }
+    Ok(ServerHandle {
+        addrs,
+        sockets,
+        handle,
+        task,
+        background,
+        storage: Some(maintained),
+    })
+}
+
+/// Binds and starts cluster router, which serves shards and cluster root at `GET /cluster`
+pub fn spawn_router(config: RouterConfig) -> io::Result<ServerHandle> {
+    if config.shards.is_empty() {
+        return Err(io::Error::other("router needs at least one shard"));
+    }
+    if config.shards.len() > MAX_SHARDS {
+        return Err(io::Error::other(format!(
+            "router takes at most {MAX_SHARDS} shards"
+        )));
+    }
+    let router = web::Data::new(Router::new(config.shards));
+    let server = HttpServer::new(move || App::new().app_data(router.clone()).service(get_cluster))
+        .bind((config.host.as_str(), config.port))?;
+    let addrs = server.addrs();
+    let server = server.run();
+    let handle = server.handle();
+    let task = tokio::spawn(server);
+    Ok(ServerHandle {
+        addrs,
+        sockets: Vec::new(),
+        handle,
+        task,
+        background: Vec::new(),
+        storage: None,
+    })
+}
+
+/// Removes socket left behind by a server which didn't stop cleanly, anything else at the path
+/// is left to fail binding
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Stores all files under given directory, named by their paths relative to it and appended in
+/// order of those names, so the same directory always gives the same root. With `names_by_hash`
+/// files are stored under hex of their content hash instead, see `--name-by-hash`. Storage which
+/// already has leaves is left as it is, so seeding again after restart on persisted metadata
+/// doesn't duplicate files.
+pub fn seed(storage: &mut Storage, dir: &Path, names_by_hash: bool) -> io::Result<usize> {
+    if storage.leaf_count() > 0 {
+        eprintln!(
+            "storage already has {} leaves, {} is not seeded again",
+            storage.leaf_count(),
+            dir.display()
+        );
+        return Ok(0);
+    }
+    let mut files = Vec::new();
+    collect_files(dir, dir, &mut files)?;
+    files.sort();
+    for (name, path) in &files {
+        let content = std::fs::read(path)?;
+        let name = match names_by_hash {
+            true => canonical_name(&content),
+            false => name.clone(),
+        };
+        storage
+            .add_new_file(name, content)
+            .map_err(|err| io::Error::other(format!("can't seed {}: {err}", path.display())))?;
+    }
+    Ok(files.len())
+}
+
+/// Restores backup chain unless storage has leaves already, so restarting with the same
+/// arguments on persisted metadata doesn't fail
+fn restore(
+    dir: &Path,
+    metadata: &mut dyn MetadataStore,
+    blobs: &mut dyn BlobStore,
+) -> io::Result<()> {
+    if !metadata.leaves()?.is_empty() {
+        eprintln!(
+            "storage already has leaves, backups in {} are not restored",
+            dir.display()
+        );
+        return Ok(());
+    }
+    let restored = backup::restore(dir, metadata, blobs)
+        .map_err(|err| io::Error::other(format!("can't restore {}: {err}", dir.display())))?;
+    eprintln!(
+        "restored {} files and {} leaves from {} backups in {}",
+        restored.files,
+        restored.leaves,
+        restored.backups,
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Files under `dir` with their `/` separated paths relative to `root`
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(root, &path, files)?;
+        } else {
+            let relative = path.strip_prefix(root).map_err(io::Error::other)?;
+            let name = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((name, path));
+        }
+    }
+    Ok(())
+}
+
+/// Quarantines storage if its contents or leaves don't match persisted root chain
+fn check_integrity(storage: &mut Storage) {
+    report_integrity(storage.check_integrity());
+}
+
+fn report_integrity(checked: Result<Option<Divergence>, StorageError>) {
+    match checked {
+        Ok(None) => {}
+        Ok(Some(divergence)) => eprintln!(
+            "integrity check failed: leaves {}..={} diverged from persisted root chain, storage is quarantined (read-only)",
+            divergence.first_leaf, divergence.last_leaf
+        ),
+        Err(err) => eprintln!("integrity check could not be completed: {err}"),
+    }
+}
+
+/// Files whose contents are rehashed at once by the periodic integrity check, storage is unlocked
+/// in between so requests are served while it runs
+const INTEGRITY_BATCH: usize = 64;
+
+async fn check_integrity_periodically(storage: web::Data<Mutex<Storage>>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let mut rehashed = Rehashed::default();
+        let rehashing = loop {
+            let more = storage
+                .lock()
+                .expect("should lock")
+                .rehash_files(&mut rehashed, INTEGRITY_BATCH);
+            match more {
+                Ok(true) => tokio::task::yield_now().await,
+                Ok(false) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        let checked = rehashing.and_then(|()| {
+            storage
+                .lock()
+                .expect("should lock")
+                .check_rehashed(rehashed)
+        });
+        report_integrity(checked);
+    }
+}
+
+/// Serves web ui at `/` when built with `web-ui` feature
+fn web_ui(config: &mut web::ServiceConfig) {
+    #[cfg(feature = "web-ui")]
+    config.service(crate::service::get_web_ui);
+    #[cfg(not(feature = "web-ui"))]
+    let _ = config;
+}
+
+/// Rebuilds secondary indexes of leaves persisted before startup a batch at a time, releasing the
+/// lock between batches so requests are served meanwhile
+async fn rebuild_indexes(storage: web::Data<Mutex<Storage>>, batch: usize) {
+    loop {
+        let finished = storage
+            .lock()
+            .expect("should lock")
+            .rebuild_indexes(batch, SystemTime::now());
+        if finished {
+            return;
+        }
+        tokio::time::sleep(REBUILD_PAUSE).await;
+    }
+}
+
+/// Periodically tombstones files which outlived their ttl or idle ttl, failed attempts are
+/// retried on the next tick
+async fn delete_expired(storage: web::Data<Mutex<Storage>>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let deleted = storage
+            .lock()
+            .expect("should lock")
+            .delete_expired(SystemTime::now());
+        match deleted {
+            Ok(_) | Err(StorageError::Quarantined) => {}
+            Err(err) => eprintln!("failed to delete expired files: {err}"),
+        }
+    }
+}
+
+/// Backs storage up whenever anything changed since the previous backup, incrementally once the
+/// directory has a full backup
+async fn back_up_periodically(
+    storage: web::Data<Mutex<Storage>>,
+    dir: PathBuf,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let storage = storage.clone();
+        let dir = dir.clone();
+        let backup = tokio::task::spawn_blocking(move || back_up(&storage, &dir))
+            .await
+            .expect("backup should not panic");
+        if let Err(err) = backup {
+            eprintln!("failed to back up storage: {err}");
+        }
+    }
+}
+
+/// Contents are read a batch at a time under the lock, which is released while they are written
+/// to the backup directory
+fn back_up(storage: &Mutex<Storage>, dir: &Path) -> io::Result<()> {
+    let incremental = backup::has_backups(dir);
+    let mut pending = {
+        let mut storage = storage.lock().expect("should lock");
+        storage.flush_downloads().map_err(io::Error::other)?;
+        backup::start(&storage, dir, incremental)?
+    };
+    if !pending.changed() {
+        return Ok(());
+    }
+    while !pending.is_read() {
+        let contents =
+            pending.read_contents(&storage.lock().expect("should lock"), BACKUP_BATCH)?;
+        pending.write_contents(contents)?;
+    }
+    pending.finish().map(drop)
+}
+
+/// Posts changes of storage since the stored cursor, or since startup without one, to webhook.
+/// Backlog is delivered in bounded steps right one after another, failed deliveries are retried
+/// on the next tick.
+async fn deliver_webhooks(storage: web::Data<Mutex<Storage>>, webhook: Webhook) {
+    let tree_size = storage.lock().expect("should lock").tree_size();
+    let mut since = match webhook.load_cursor().await {
+        Ok(Some(cursor)) if cursor <= tree_size => cursor,
+        Ok(Some(cursor)) => {
+            eprintln!("webhook cursor {cursor} is past the tree of {tree_size} leaves, delivering changes from now on");
+            tree_size
+        }
+        Ok(None) => tree_size,
+        Err(err) => {
+            eprintln!("failed to load webhook cursor, delivering changes from now on: {err}");
+            tree_size
+        }
+    };
+    let mut interval = tokio::time::interval(webhook.interval());
+    loop {
+        interval.tick().await;
+        loop {
+            match webhook.deliver_changes(&storage, since).await {
+                Ok(delivered) if delivered == since => break,
+                Ok(delivered) => {
+                    since = delivered;
+                    if let Err(err) = webhook.store_cursor(since).await {
+                        eprintln!("failed to store webhook cursor: {err}");
+                    }
+                }
+                Err(err) => {
+                    eprintln!("failed to deliver webhook: {err}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Submits current signed root to peers, also when it didn't change, so peers which were down or
+/// joined later learn it as well
+async fn submit_roots(storage: web::Data<Mutex<Storage>>, peers: Vec<Client>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let root = storage
+            .lock()
+            .expect("should lock")
+            .signed_root(SystemTime::now());
+        if let Some(root) = root {
+            submit_to_peers(&peers, &root).await;
+        }
+    }
+}
+
+/// Periodically seals current epoch once it gets older than allowed by epoch policy
+async fn seal_old_epochs(storage: web::Data<Mutex<Storage>>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let sealed = storage
+            .lock()
+            .expect("should lock")
+            .rotate_if_due(SystemTime::now());
+        match sealed {
+            Ok(_) | Err(StorageError::Quarantined) => {}
+            Err(err) => eprintln!("failed to seal epoch: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{FileContent, FileId, FileListQuery, IngestStatus, LeafIndex, TreeSize};
+    use crate::auth::ApiKey;
+    use crate::client::{Client, ClientMiddleware, HttpError, NameCheck, VerificationError};
+    use crate::codec::Codec;
+    use crate::interceptor::DeniedExtensions;
+    use crate::merkle::Sha3Tree;
+    use crate::sha3::hash_content;
+    use crate::signing::TrustedKey;
+    use crate::webhook::{WebhookDelivery, WebhookEvent};
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_spawned_server_roundtrip() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        assert_ne!(server.addr().expect("should listen on tcp").port(), 0);
+
+        let client = Client::new(server.url());
+        let stored = client
+            .upload_new_file("a.txt", b"content")
+            .await
+            .expect("should upload");
+        let root = client.fetch_root().await.expect("should have root").hash;
+        let reported = stored.root.expect("should be committed");
+        assert_eq!((&reported.hash, reported.size), (&root, TreeSize(1)));
+        let file = stored.file;
+        let (downloaded, summary) = client
+            .download_file(file.id, |_| Ok(root.clone()))
+            .await
+            .expect("should download and verify");
+        assert_eq!(summary.leaf, hash_content(&downloaded.content));
+        assert_eq!(summary.bytes, downloaded.content.len() as u64);
+        assert!(summary.chunks.is_empty());
+        let preview = client
+            .preview_file(file.id, Some(3))
+            .await
+            .expect("should preview");
+        assert_eq!(preview, (file.mime, b"con".to_vec()));
+
+        let other = client
+            .upload_new_file("b.txt", b"other")
+            .await
+            .expect("should upload");
+        let root = other.root.expect("should be committed").hash;
+        let other = other.file;
+        let batch = client
+            .download_batch(&[other.id, file.id])
+            .await
+            .expect("should download batch");
+        assert_eq!(batch.files.len(), 2);
+        assert!(batch.verify(&root, client.leaf_hasher().as_ref()));
+
+        let path = std::env::temp_dir().join("safe_storage_streamed_download");
+        let (meta, summary) = client
+            .download_verify_to(other.id, &path, |_| Ok(root.clone()))
+            .await
+            .expect("should download and verify");
+        assert_eq!(meta.name, "b.txt");
+        assert_eq!((summary.id, summary.bytes), (other.id, 5));
+        assert_eq!(std::fs::read(&path).expect("should read"), b"other");
+        let unrelated = hash_content(b"unrelated");
+        assert!(client
+            .download_verify_to(other.id, &path, |_| Ok(unrelated))
+            .await
+            .is_err());
+        assert!(!path.exists());
+
+        std::fs::write(&path, b"streamed").expect("should write");
+        let streamed = client
+            .upload_stream("c.txt", &path, Some(TreeSize(2)))
+            .await
+            .expect("should upload");
+        let root = client.fetch_root().await.expect("should have root").hash;
+        assert_eq!(streamed.root.map(|root| root.hash), Some(root.clone()));
+        let downloaded = client
+            .fetch_file(streamed.file.id)
+            .await
+            .expect("should download");
+        assert_eq!(downloaded.content, b"streamed");
+        assert!(downloaded.proof.verify(&root, &hash_content(b"streamed")));
+        assert!(client
+            .upload_stream("d.txt", &path, Some(TreeSize(2)))
+            .await
+            .is_err());
+        std::fs::remove_file(&path).expect("should remove");
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[test]
+    fn test_parse_cors_origin() {
+        assert_eq!("*".parse::<CorsOrigin>().unwrap(), CorsOrigin::Any);
+        assert_eq!(
+            "http://localhost:3000".parse::<CorsOrigin>().unwrap(),
+            CorsOrigin::Exact("http://localhost:3000".to_string())
+        );
+        for invalid in [
+            "localhost",
+            "https://",
+            "https://app.example.com/",
+            "ftp://a",
+        ] {
+            assert!(invalid.parse::<CorsOrigin>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!(
+            "127.0.0.1:8080".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("127.0.0.1".to_string(), 8080)
+        );
+        assert_eq!(
+            "[::1]:0".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("::1".to_string(), 0)
+        );
+        assert_eq!(
+            "unix:/run/safe-storage.sock".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Unix(PathBuf::from("/run/safe-storage.sock"))
+        );
+        for invalid in ["localhost", ":8080", "localhost:http", "unix:"] {
+            assert!(invalid.parse::<ListenAddr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listen_on_unix_socket_and_tcp() {
+        let socket = std::env::temp_dir().join("safe_storage_test.sock");
+        let server = spawn(ServerConfig {
+            listen: vec![
+                ListenAddr::Unix(socket.clone()),
+                ListenAddr::Tcp("127.0.0.1".to_string(), 0),
+            ],
+            ..Default::default()
+        })
+        .expect("should start");
+        let tcp = Client::new(server.url());
+        let unix = Client::try_new(format!("unix://{}", socket.display())).expect("should connect");
+        assert!(unix.server_url().starts_with("unix://"));
+
+        let stored = unix
+            .upload_new_file("a.txt", b"content")
+            .await
+            .expect("should upload over socket");
+        let root = tcp.fetch_root().await.expect("should have root").hash;
+        let (downloaded, _) = unix
+            .download_file(stored.file.id, |_| Ok(root.clone()))
+            .await
+            .expect("should download and verify over socket");
+        assert_eq!(downloaded.content, b"content");
+        let path = std::env::temp_dir().join("safe_storage_unix_stream_upload");
+        std::fs::write(&path, b"streamed").unwrap();
+        let streamed = unix
+            .upload_stream("b.txt", &path, None)
+            .await
+            .expect("should stream over socket");
+        assert_eq!(streamed.file.size, 8);
+        std::fs::remove_file(&path).unwrap();
+
+        drop((tcp, unix));
+        server.stop(true).await.expect("should stop");
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test]
+    async fn test_seed_dir() {
+        let dir = std::env::temp_dir().join("safe_storage_seed");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("b")).expect("should create");
+        std::fs::write(dir.join("c.txt"), b"c").expect("should write");
+        std::fs::write(dir.join("b/a.txt"), b"ba").expect("should write");
+        std::fs::write(dir.join("a.txt"), b"a").expect("should write");
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            seed_dir: Some(dir.clone()),
+            ..Default::default()
+        })
+        .expect("should start");
+
+        let client = Client::new(server.url());
+        let names: Vec<_> = client
+            .get_file_list()
+            .await
+            .expect("should list")
+            .files
+            .into_iter()
+            .map(|file| file.name)
+            .collect();
+        assert_eq!(names, vec!["a.txt", "b/a.txt", "c.txt"]);
+        let expected = Sha3Tree::from_manifest(["a", "ba", "c"].map(hash_content)).root();
+        let root = client.fetch_root().await.expect("should have root").hash;
+        assert_eq!(Some(root), expected);
+
+        server.stop(true).await.expect("should stop");
+        std::fs::remove_dir_all(&dir).expect("should remove");
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let dir = std::env::temp_dir().join("safe_storage_server_backup");
+        let _ = std::fs::remove_dir_all(&dir);
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            backup_dir: Some(dir.clone()),
+            backup_interval: Duration::from_millis(20),
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        client
+            .upload_new_file("a.txt", b"a")
+            .await
+            .expect("should upload");
+        let root = client.fetch_root().await.expect("should have root").hash;
+        let started = Instant::now();
+        // the first backup may be taken before the upload, an incremental one follows it
+        while !backed_up(&dir, 1) {
+            assert!(started.elapsed() < Duration::from_secs(5), "not backed up");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        server.stop(true).await.expect("should stop");
+
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            restore_dir: Some(dir.clone()),
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let restored = client.fetch_root().await.expect("should have root").hash;
+        assert_eq!(restored, root);
+        let files = client.get_file_list().await.expect("should list").files;
+        let content = client
+            .fetch_file(files[0].id)
+            .await
+            .expect("should download")
+            .content;
+        assert_eq!(content, b"a");
+        // keep-alive connection of the download would hold graceful shutdown back
+        drop(client);
+        server.stop(true).await.expect("should stop");
+        std::fs::remove_dir_all(&dir).expect("should remove");
+    }
+
+    /// Whether backup chain in the directory has given amount of leaves
+    fn backed_up(dir: &Path, leaves: usize) -> bool {
+        let mut metadata = MemoryMetadata::default();
+        let mut blobs = MemoryBlobs::default();
+        backup::restore(dir, &mut metadata, &mut blobs)
+            .is_ok_and(|restored| restored.leaves == leaves)
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            cors: CorsConfig {
+                origins: vec!["https://app.example.com".parse().unwrap()],
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .expect("should start");
+        let preflight = |origin: &'static str| {
+            reqwest::Client::new()
+                .request(reqwest::Method::OPTIONS, format!("{}/files", server.url()))
+                .header("origin", origin)
+                .header("access-control-request-method", "POST")
+                .send()
+        };
+
+        let allowed = preflight("https://app.example.com")
+            .await
+            .expect("should answer");
+        assert!(allowed.status().is_success());
+        assert_eq!(
+            allowed
+                .headers()
+                .get("access-control-allow-origin")
+                .and_then(|value| value.to_str().ok()),
+            Some("https://app.example.com")
+        );
+        let refused = preflight("https://evil.example.com")
+            .await
+            .expect("should answer");
+        assert!(refused
+            .headers()
+            .get("access-control-allow-origin")
+            .is_none());
+
+        // same-origin browser posts carry origin too and must not be refused
+        let upload = reqwest::Client::new()
+            .post(format!("{}/files/stream?name=a.txt", server.url()))
+            .header("origin", server.url())
+            .body("content")
+            .send()
+            .await
+            .expect("should answer");
+        assert!(upload.status().is_success(), "{}", upload.status());
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[cfg(feature = "web-ui")]
+    #[tokio::test]
+    async fn test_web_ui() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let resp = reqwest::get(format!("{}/", server.url()))
+            .await
+            .expect("should answer");
+        assert!(resp.status().is_success());
+        assert!(resp
+            .text()
+            .await
+            .expect("should have body")
+            .contains("function verifyProof"));
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_sharded_cluster() {
+        let shards: Vec<_> = (0..2)
+            .map(|index| {
+                spawn(ServerConfig {
+                    host: "127.0.0.1".to_string(),
+                    port: 0,
+                    shard: Some(ShardRange::of(index, 2)),
+                    ..Default::default()
+                })
+                .expect("should start")
+            })
+            .collect();
+        let router = spawn_router(RouterConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            shards: shards.iter().map(ServerHandle::url).collect(),
+        })
+        .expect("should start");
+        assert!(spawn_router(RouterConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            shards: vec![shards[0].url(); MAX_SHARDS + 1],
+        })
+        .is_err());
+
+        let cluster = crate::cluster::ClusterClient::connect(Client::new(router.url()), |c| c)
+            .await
+            .expect("should connect");
+        assert_eq!(cluster.shards(), 2);
+        let mut uploaded = Vec::new();
+        for i in 0..8 {
+            let content = format!("file {i}");
+            let (shard, stored) = cluster
+                .upload(&format!("{i}.txt"), content.as_bytes())
+                .await
+                .expect("should upload");
+            assert_eq!(shard, cluster.shard_of(&hash_content(&content)));
+            uploaded.push((shard, stored.file.id, content));
+        }
+        assert!(uploaded.iter().any(|(shard, ..)| *shard == 0));
+        assert!(uploaded.iter().any(|(shard, ..)| *shard == 1));
+        for (shard, id, content) in uploaded {
+            let (file, _) = cluster
+                .download_verified(shard, id)
+                .await
+                .expect("should verify");
+            assert_eq!(file.content, content.as_bytes());
+        }
+
+        // shards refuse content of other shards
+        let foreign = (0u32..)
+            .map(|i| i.to_string())
+            .find(|content| cluster.shard_of(&hash_content(content)) == 1)
+            .expect("should exist");
+        let shard = Client::new(shards[0].url());
+        assert!(shard
+            .upload_new_file("x.txt", foreign.as_bytes())
+            .await
+            .is_err());
+
+        router.stop(true).await.expect("should stop");
+        for shard in shards {
+            shard.stop(true).await.expect("should stop");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_binary_codecs() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        for codec in [Codec::Cbor, Codec::MessagePack] {
+            let client = Client::new(server.url()).with_codec(codec);
+            let file = client
+                .upload_new_file("a.bin", &[0, 1, 2, 255])
+                .await
+                .expect("should upload")
+                .file;
+            let downloaded = client.fetch_file(file.id).await.expect("should download");
+            assert_eq!(downloaded.content, vec![0, 1, 2, 255]);
+            let root = client.fetch_root().await.expect("should have root").hash;
+            assert!(downloaded
+                .proof
+                .verify(&root, &hash_content(&downloaded.content)));
+        }
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_import_from_url() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            importer: Importer::new(vec!["http".to_string()], vec!["127.0.0.1".to_string()], 4),
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let file = client
+            .upload_new_file("a.txt", b"content")
+            .await
+            .expect("should upload")
+            .file;
+
+        let source = format!("{}/files/{}/preview?bytes=3", server.url(), file.id);
+        let imported = client
+            .import_file(&source, Some("b.txt"))
+            .await
+            .expect("should import");
+        assert_eq!(imported.file.name, "b.txt");
+        assert_eq!(imported.hash, hash_content(b"con"));
+        let root = client.fetch_root().await.expect("should have root").hash;
+        assert_eq!(imported.root.as_ref(), Some(&root));
+        assert!(imported
+            .proof
+            .expect("should be committed")
+            .verify(&root, &imported.hash));
+
+        let source = format!("{}/files/{}/preview", server.url(), file.id);
+        assert!(client.import_file(&source, Some("c.txt")).await.is_err());
+        let internal = source.replace("127.0.0.1", "localhost");
+        let refused = client
+            .import_file(&internal, Some("c.txt"))
+            .await
+            .expect_err("should refuse loopback host not allowed");
+        assert!(refused.to_string().contains("non-public"), "{refused}");
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_ingestion() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            interceptors: UploadInterceptors::new().with(DeniedExtensions(vec!["exe".to_string()])),
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let path = std::env::temp_dir().join("safe_storage_quarantined_upload");
+        std::fs::write(&path, b"validated").expect("should write");
+        let poll = Duration::from_millis(10);
+
+        let held = client
+            .upload_quarantined("a.txt", &path)
+            .await
+            .expect("should upload");
+        let committed = client
+            .wait_for_file(held.id, poll)
+            .await
+            .expect("should be validated");
+        assert_eq!(committed.status, IngestStatus::Committed);
+        assert_eq!(committed.hash, Some(hash_content(b"validated")));
+        let root = client.fetch_root().await.expect("should have root");
+        assert_eq!(
+            committed.root.map(|root| root.hash),
+            Some(root.hash.clone())
+        );
+        assert!(committed
+            .proof
+            .expect("should have proof")
+            .verify(&root.hash, &hash_content(b"validated")));
+
+        let held = client
+            .upload_quarantined("b.exe", &path)
+            .await
+            .expect("should upload");
+        let rejected = client
+            .wait_for_file(held.id, poll)
+            .await
+            .expect("should be validated");
+        assert_eq!(rejected.status, IngestStatus::Rejected);
+        assert!(rejected.reason.is_some());
+        assert!(client.fetch_file(held.id).await.is_err());
+        let unchanged = client.fetch_root().await.expect("should have root");
+        assert_eq!(unchanged.hash, root.hash, "rejected file is not appended");
+        std::fs::remove_file(&path).expect("should remove");
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_ipfs_export_needs_node() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let file = client
+            .upload_new_file("a.txt", b"content")
+            .await
+            .expect("should upload")
+            .file;
+        let err = client
+            .export_to_ipfs(file.id)
+            .await
+            .expect_err("no node is configured");
+        assert_eq!(
+            err.downcast_ref::<HttpError>().map(|err| err.status),
+            Some(501)
+        );
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_historical_proof() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let first = client
+            .upload_new_file("a.txt", b"first")
+            .await
+            .expect("should upload");
+        let old_root = first.root.expect("should be committed").hash;
+        let second = client
+            .upload_new_file("b.txt", b"second")
+            .await
+            .expect("should upload");
+
+        let proof = client
+            .fetch_historical_proof(first.file.id, TreeSize(1))
+            .await
+            .expect("should prove");
+        assert_eq!(
+            (proof.leaf_index, proof.tree_size),
+            (LeafIndex(0), TreeSize(1))
+        );
+        assert_eq!(proof.root, old_root);
+        assert!(proof.proof.verify(&old_root, &hash_content(b"first")));
+        let err = client
+            .fetch_historical_proof(second.file.id, TreeSize(1))
+            .await
+            .expect_err("second file was not in the tree yet");
+        assert_eq!(
+            err.downcast_ref::<HttpError>().map(|err| err.status),
+            Some(404)
+        );
+
+        let cache_dir = std::env::temp_dir().join("safe_storage_proof_cache");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let leaves = [hash_content(b"first"), hash_content(b"second")];
+        let leaf_of = |index: LeafIndex| leaves.get(index.as_usize()).cloned();
+        let client = client.with_cache_dir(Some(cache_dir.clone()));
+        let (_, cached) = client
+            .verify_historical_proof(first.file.id, TreeSize(1), leaf_of, &old_root)
+            .await
+            .expect("should verify");
+        assert!(!cached);
+
+        // corrupted entries or proofs for something else are dropped and fetched again
+        let entry = cache_dir.join("proofs").join("0-1.json");
+        let other = client
+            .fetch_historical_proof(second.file.id, TreeSize(2))
+            .await
+            .expect("should prove");
+        let other = serde_json::to_vec(&other).expect("should serialize");
+        for corrupted in [b"{not a proof".to_vec(), other] {
+            std::fs::write(&entry, corrupted).expect("should write");
+            let (_, cached) = client
+                .verify_historical_proof(first.file.id, TreeSize(1), leaf_of, &old_root)
+                .await
+                .expect("should verify fetched proof");
+            assert!(!cached);
+        }
+        server.stop(true).await.expect("should stop");
+
+        let offline = client.with_offline_proofs(true);
+        let (proof, cached) = offline
+            .verify_historical_proof(first.file.id, TreeSize(1), leaf_of, &old_root)
+            .await
+            .expect("should verify from cache");
+        assert_eq!((proof.leaf_index, cached), (LeafIndex(0), true));
+        offline
+            .verify_historical_proof(first.file.id, TreeSize(1), leaf_of, &hash_content(b"other"))
+            .await
+            .expect_err("cached proof is for another root");
+        offline
+            .verify_historical_proof(second.file.id, TreeSize(2), leaf_of, &old_root)
+            .await
+            .expect_err("proof is not cached");
+        std::fs::remove_dir_all(&cache_dir).expect("should remove");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_uploads() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url()).with_rate_limit(Some(RateLimit::new(4000)));
+        let path = std::env::temp_dir().join("safe_storage_rate_limited_upload");
+        std::fs::write(&path, vec![7; 2000]).unwrap();
+
+        // five chunks of 400 bytes, the last one sent no sooner than 400ms in
+        let started = Instant::now();
+        let stored = client
+            .upload_stream("a.bin", &path, None)
+            .await
+            .expect("should upload");
+        assert_eq!(stored.file.size, 2000);
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        let started = Instant::now();
+        client
+            .upload_new_file("b.bin", &[7; 2000])
+            .await
+            .expect("should upload");
+        assert!(started.elapsed() >= Duration::from_millis(400));
+
+        std::fs::remove_file(&path).unwrap();
+        drop(client);
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_upload_batch() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            collision_policy: CollisionPolicy::Reject,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let files = vec![
+            ("a.txt".to_string(), b"first".to_vec()),
+            ("b.txt".to_string(), b"second".to_vec()),
+        ];
+        let stored = client
+            .upload_batch(files.clone(), Some(TreeSize(0)))
+            .await
+            .expect("should upload");
+        let indices: Vec<_> = stored.files.iter().map(|file| file.leaf_index).collect();
+        assert_eq!(indices, [LeafIndex(0), LeafIndex(1)]);
+        let root = stored.root.expect("should be committed");
+        let tree = Sha3Tree::from_manifest([&b"first"[..], b"second"].map(hash_content));
+        assert_eq!((Some(root.hash), root.size), (tree.root(), TreeSize(2)));
+
+        let err = client
+            .upload_batch(
+                vec![("c.txt".to_string(), b"third".to_vec())],
+                Some(TreeSize(0)),
+            )
+            .await
+            .expect_err("tree has grown");
+        assert_eq!(
+            err.downcast_ref::<HttpError>().map(|err| err.status),
+            Some(409)
+        );
+        client
+            .upload_batch(files, None)
+            .await
+            .expect_err("names are taken");
+        assert_eq!(
+            client.fetch_root().await.expect("should have root").size,
+            TreeSize(2)
+        );
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_upload_into_dataset() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        client
+            .upload_new_file("outside.txt", b"outside")
+            .await
+            .expect("should upload");
+        let client = client.with_dataset(Some("q3 reports".to_string()));
+        let first = client
+            .upload_new_file("a.txt", b"first")
+            .await
+            .expect("should upload");
+        assert_eq!(first.file.dataset.as_deref(), Some("q3 reports"));
+        let stored = client
+            .upload_batch(
+                vec![
+                    ("b.txt".to_string(), b"second".to_vec()),
+                    ("c.txt".to_string(), b"third".to_vec()),
+                ],
+                None,
+            )
+            .await
+            .expect("should upload");
+
+        let root = client.fetch_root().await.expect("should have root").hash;
+        let trusted = |_| Ok(root.clone());
+        let dataset = client
+            .fetch_dataset("q3 reports", trusted)
+            .await
+            .expect("should verify");
+        let err = client
+            .fetch_dataset("q3 reports", |_| Ok(hash_content(b"forged")))
+            .await
+            .expect_err("root is not trusted");
+        assert!(err.downcast_ref::<VerificationError>().is_some());
+        let expected =
+            Sha3Tree::from_manifest([&b"first"[..], b"second", b"third"].map(hash_content));
+        assert_eq!(
+            (Some(dataset.root.clone()), dataset.size),
+            (expected.root(), 3)
+        );
+        assert_eq!(dataset.tree_root, root);
+        // dataset leaves of a batch follow all of its file leaves
+        assert_eq!(dataset.leaf_index, LeafIndex(6));
+        let (file, proof) = client
+            .download_dataset_file("q3 reports", stored.files[1].file.id, trusted)
+            .await
+            .expect("should verify");
+        assert_eq!((file.content, proof.index), (b"third".to_vec(), 2));
+        let err = client
+            .download_dataset_file("q3 reports", FileId(0), trusted)
+            .await
+            .expect_err("file is outside of dataset");
+        assert_eq!(
+            err.downcast_ref::<HttpError>().map(|err| err.status),
+            Some(404)
+        );
+        let err = Client::new(server.url())
+            .with_dataset(Some("a/b".to_string()))
+            .upload_new_file("d.txt", b"nested")
+            .await
+            .expect_err("datasets are not nested");
+        assert_eq!(
+            err.downcast_ref::<HttpError>().map(|err| err.status),
+            Some(400)
+        );
+        assert_eq!(
+            client
+                .fetch_datasets()
+                .await
+                .expect("should list")
+                .datasets
+                .len(),
+            1
+        );
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_files_changed_by_owner_only() {
+        let keys = ["alice:a", "bob:b"].map(|key| key.parse::<ApiKey>().unwrap());
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            api_keys: ApiKeys::new(keys.to_vec()),
+            keep_deleted: true,
+            // nothing listens there, exports of owners fail only once they reach it
+            ipfs: Some(IpfsNode::new("http://127.0.0.1:1")),
+            ..Default::default()
+        })
+        .expect("should start");
+        let alice = Client::new(server.url()).with_api_key(Some("a".to_string()));
+        let bob = Client::new(server.url()).with_api_key(Some("b".to_string()));
+        let stored = alice
+            .upload_new_file("a.txt", b"alice's")
+            .await
+            .expect("should upload");
+        let status = |err: anyhow::Error| err.downcast_ref::<HttpError>().map(|err| err.status);
+
+        let err = bob.delete_file(stored.file.id).await.unwrap_err();
+        assert_eq!(status(err), Some(403));
+        let usage = alice.fetch_usage().await.expect("should report usage");
+        assert_eq!(usage.bytes, 7, "bob doesn't refund alice's quota");
+        alice
+            .delete_file(stored.file.id)
+            .await
+            .expect("owner should delete");
+        let err = bob.undelete_file(stored.file.id).await.unwrap_err();
+        assert_eq!(status(err), Some(403));
+        let undeleted = alice
+            .undelete_file(stored.file.id)
+            .await
+            .expect("owner should undelete");
+        let err = bob
+            .export_to_ipfs(undeleted.file.id)
+            .await
+            .expect_err("should refuse bob");
+        assert_eq!(status(err), Some(403), "refused before reaching the node");
+        let err = alice
+            .export_to_ipfs(undeleted.file.id)
+            .await
+            .expect_err("should fail to reach the node");
+        assert_eq!(status(err), Some(502), "owner reaches the node");
+
+        drop((alice, bob));
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_undelete() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            keep_deleted: true,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let stored = client
+            .upload_new_file("a.txt", b"first")
+            .await
+            .expect("should upload");
+        client
+            .delete_file(stored.file.id)
+            .await
+            .expect("should delete");
+        let deleted = client
+            .list_files_by(&FileListQuery {
+                deleted: true,
+                ..Default::default()
+            })
+            .await
+            .expect("should list");
+        assert_eq!(deleted.files.len(), 1);
+        assert!(deleted.files[0].deleted);
+
+        let undeleted = client
+            .undelete_file(stored.file.id)
+            .await
+            .expect("should undelete");
+        assert_eq!(undeleted.file.undeleted_from, Some(stored.file.id));
+        let root = undeleted.root.expect("should be committed");
+        assert_eq!(root.size, TreeSize(3));
+        let file = client
+            .fetch_file(undeleted.file.id)
+            .await
+            .expect("should fetch");
+        assert!(file.proof.verify(&root.hash, &hash_content(b"first")));
+        let listed = client.list_files(false).await.expect("should list");
+        assert_eq!(listed.files.len(), 1);
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_chunked_verification_summary() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            leaf_hashing: LeafHashing::Chunked(4),
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url())
+            .detect_leaf_hashing()
+            .await
+            .expect("should detect");
+        let file = client
+            .upload_new_file("a.txt", b"0123456789")
+            .await
+            .expect("should upload")
+            .file;
+        let root = client.fetch_root().await.expect("should have root").hash;
+
+        let path = std::env::temp_dir().join("safe_storage_chunked_download");
+        let (_, summary) = client
+            .download_verify_to(file.id, &path, |_| Ok(root.clone()))
+            .await
+            .expect("should download and verify");
+        std::fs::remove_file(&path).expect("should remove");
+        assert_eq!(summary.root, root);
+        assert_eq!(
+            summary
+                .chunks
+                .iter()
+                .map(|chunk| (chunk.offset, chunk.len))
+                .collect::<Vec<_>>(),
+            [(0, 4), (4, 4), (8, 2)]
+        );
+        assert_eq!(summary.chunks[2].hash, hash_content(b"89"));
+        let (_, in_memory) = client
+            .download_file(file.id, |_| Ok(root.clone()))
+            .await
+            .expect("should download and verify");
+        assert_eq!(in_memory.leaf, summary.leaf);
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_download_expecting_hash() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let file = client
+            .upload_new_file("a.txt", b"expected content")
+            .await
+            .expect("should upload")
+            .file;
+        let root = client.fetch_root().await.expect("should have root").hash;
+        let (good, bad) = (hash_content(b"expected content"), hash_content(b"other"));
+
+        let downloaded = client
+            .download_expecting(file.id, &good)
+            .await
+            .expect("should match");
+        client
+            .verify_file(&downloaded, Instant::now(), |_| Ok(root.clone()))
+            .expect("should verify");
+        let err = client.download_expecting(file.id, &bad).await.unwrap_err();
+        assert!(err.downcast_ref::<VerificationError>().is_some(), "{err}");
+
+        let path = std::env::temp_dir().join("safe_storage_expected_download");
+        client
+            .download_expecting_to(file.id, &path, Some(&good), |_| Ok(root.clone()))
+            .await
+            .expect("should match and verify");
+        assert_eq!(std::fs::read(&path).unwrap(), b"expected content");
+        let err = client
+            .download_expecting_to(file.id, &path, Some(&bad), |_| Ok(root.clone()))
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<VerificationError>().is_some(), "{err}");
+        assert!(!path.exists(), "mismatching content is removed");
+
+        drop(client);
+        server.stop(true).await.expect("should stop");
+    }
+
+    /// Empty contents are stored like any other, hashed as `H("")`, whichever way uploaded
+    #[tokio::test]
+    async fn test_empty_files() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        assert!(client.list_files(false).await.unwrap().files.is_empty());
+        let err = client.fetch_root().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HttpError>().map(|err| err.status),
+            Some(404)
+        );
+
+        let uploaded = client
+            .upload_new_file("empty.txt", b"")
+            .await
+            .expect("should upload empty content")
+            .file;
+        let path = std::env::temp_dir().join("safe_storage_empty_upload");
+        std::fs::write(&path, b"").unwrap();
+        let streamed = client
+            .upload_stream("streamed.txt", &path, None)
+            .await
+            .expect("should stream empty content")
+            .file;
+        assert_eq!((uploaded.size, streamed.size), (0, 0));
+        let files = client.list_files(false).await.unwrap().files;
+        assert_eq!(files.len(), 2);
+
+        let root = client.fetch_root().await.expect("should have root").hash;
+        for file in [&uploaded, &streamed] {
+            let (downloaded, _) = client
+                .download_file(file.id, |_| Ok(root.clone()))
+                .await
+                .expect("should verify empty content");
+            assert!(downloaded.content.is_empty());
+            client
+                .download_expecting_to(file.id, &path, Some(&hash_content(b"")), |_| {
+                    Ok(root.clone())
+                })
+                .await
+                .expect("should match H(\"\")");
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        }
+        client
+            .fetch_proof_by_hash(&hash_content(b""))
+            .await
+            .expect("should find content by H(\"\")");
+
+        std::fs::remove_file(&path).unwrap();
+        drop(client);
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_cached_raw_download() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let cache_dir = std::env::temp_dir().join("safe_storage_raw_cache");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let client = Client::new(server.url()).with_cache_dir(Some(cache_dir.clone()));
+        let file = client
+            .upload_new_file("a.txt", b"cached content")
+            .await
+            .expect("should upload")
+            .file;
+        let path = std::env::temp_dir().join("safe_storage_cached_download");
+        let root = client.fetch_root().await.expect("should have root").hash;
+        client
+            .download_verify_to(file.id, &path, |_| Ok(root.clone()))
+            .await
+            .expect("should download and verify");
+        let etag = std::fs::read_to_string(cache_dir.join("0.etag")).expect("should cache tag");
+
+        let resp = reqwest::Client::new()
+            .get(format!("{}/files/0/raw", server.url()))
+            .header(reqwest::header::IF_NONE_MATCH, &etag)
+            .send()
+            .await
+            .expect("should answer");
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_MODIFIED);
+        assert!(resp.headers().contains_key(FILE_META_HEADER));
+
+        // cached content is verified with proof against the grown tree
+        client
+            .upload_new_file("b.txt", b"other")
+            .await
+            .expect("should upload");
+        let root = client.fetch_root().await.expect("should have root").hash;
+        client
+            .download_verify_to(file.id, &path, |_| Ok(root.clone()))
+            .await
+            .expect("should verify cached content");
+        assert_eq!(
+            std::fs::read(&path).expect("should read"),
+            b"cached content"
+        );
+
+        // corrupted or missing cached content is dropped and downloaded again
+        std::fs::write(cache_dir.join("0"), b"tampered").expect("should write");
+        client
+            .download_verify_to(file.id, &path, |_| Ok(root.clone()))
+            .await
+            .expect("should download again");
+        assert_eq!(
+            std::fs::read(cache_dir.join("0")).expect("should cache again"),
+            b"cached content"
+        );
+        std::fs::remove_file(cache_dir.join("0")).expect("should remove");
+        client
+            .download_verify_to(file.id, &path, |_| Ok(root.clone()))
+            .await
+            .expect("should download again");
+        assert_eq!(
+            std::fs::read(&path).expect("should read"),
+            b"cached content"
+        );
+
+        // wrong root still fails when content comes from server
+        let unrelated = hash_content(b"unrelated");
+        assert!(client
+            .download_verify_to(file.id, &path, |_| Ok(unrelated))
+            .await
+            .is_err());
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&cache_dir).expect("should remove cache");
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_verified_listing() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            leaf_hashing: LeafHashing::NameAndHash,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url())
+            .detect_leaf_hashing()
+            .await
+            .expect("should detect");
+        let mut leaves = Vec::new();
+        for (name, content) in [("a.txt", &b"first"[..]), ("b.txt", b"second")] {
+            client
+                .upload_new_file(name, content)
+                .await
+                .expect("should upload");
+            leaves.push(client.leaf_hasher().leaf(name, content));
+        }
+        // content hash of streamed upload is kept from staging
+        let path = std::env::temp_dir().join("safe_storage_verified_listing.txt");
+        std::fs::write(&path, b"third").expect("should write");
+        client
+            .upload_stream("c.txt", &path, None)
+            .await
+            .expect("should upload");
+        std::fs::remove_file(&path).expect("should remove");
+        leaves.push(client.leaf_hasher().leaf("c.txt", b"third"));
+        let root = client.fetch_root().await.expect("should have root").hash;
+        let checked = client
+            .get_verified_file_list(&leaves, &root)
+            .await
+            .expect("should list");
+        let checks: Vec<_> = checked.iter().map(|(_, check)| *check).collect();
+        assert_eq!(checks, [NameCheck::Verified; 3]);
+
+        // leaves trusted for swapped names look like the server renamed both files
+        let swapped = [
+            client.leaf_hasher().leaf("a.txt", b"second"),
+            client.leaf_hasher().leaf("b.txt", b"first"),
+        ];
+        let swapped_root = Sha3Tree::from_manifest(swapped.clone())
+            .root()
+            .expect("should have root");
+        let checked = client
+            .get_verified_file_list(&swapped, &swapped_root)
+            .await
+            .expect("should list");
+        let checks: Vec<_> = checked.iter().map(|(_, check)| *check).collect();
+        assert_eq!(
+            checks,
+            [
+                NameCheck::Mismatch,
+                NameCheck::Mismatch,
+                NameCheck::Unproven
+            ]
+        );
+        let first_root = Sha3Tree::from_manifest(leaves[..1].to_vec())
+            .root()
+            .expect("should have root");
+        let checked = client
+            .get_verified_file_list(&leaves[..1], &first_root)
+            .await
+            .expect("should list");
+        assert_eq!(checked[1].1, NameCheck::Unproven);
+        assert!(client
+            .get_verified_file_list(&leaves, &swapped_root)
+            .await
+            .is_err());
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_leaf_hashing_reported_by_info() {
+        assert!(spawn(ServerConfig {
+            leaf_hashing: LeafHashing::NameAndContent,
+            collision_policy: CollisionPolicy::Suffix,
+            ..Default::default()
+        })
+        .is_err());
+
+        let leaf_hashing: LeafHashing = "keyed:0102".parse().expect("should parse");
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            leaf_hashing: leaf_hashing.clone(),
+            max_upload_size: Some(1024),
+            epoch_policy: EpochPolicy {
+                max_leaves: Some(100),
+                max_age: None,
+            },
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let info = client.server_info().await.expect("should fetch info");
+        assert_eq!(info.leaf_hashing, leaf_hashing);
+        assert_eq!(
+            (info.protocol, info.hash_algorithm.as_str()),
+            (PROTOCOL_VERSION, HASH_ALGORITHM)
+        );
+        assert_eq!(info.tree_mode, TreeMode::Epochs);
+        let limited = |when_full| ServerConfig {
+            tree_limit: Some(TreeLimit {
+                max_leaves: 100,
+                when_full,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(limited(WhenFull::Seal).info().tree_mode, TreeMode::Epochs);
+        assert_eq!(limited(WhenFull::Refuse).info().tree_mode, TreeMode::Single);
+        assert!(!info.features.auth && !info.features.chunked_leaves);
+        assert_eq!(
+            (info.limits.max_upload_bytes, info.limits.epoch_max_leaves),
+            (Some(1024), Some(100))
+        );
+        let rejected = client
+            .upload_new_file("big.bin", &[0; 2048])
+            .await
+            .expect_err("should reject upload over the limit");
+        assert!(rejected.downcast_ref::<HttpError>().is_some());
+
+        let client = client.detect_leaf_hashing().await.expect("should detect");
+        assert_eq!(client.leaf_hasher().strategy(), leaf_hashing);
+        let file = client
+            .upload_new_file("a.txt", b"content")
+            .await
+            .expect("should upload")
+            .file;
+        let root = client.fetch_root().await.expect("should have root").hash;
+        let downloaded = client.fetch_file(file.id).await.expect("should download");
+        assert!(downloaded.proof.verify(
+            &root,
+            &client.leaf_hasher().leaf("a.txt", &downloaded.content)
+        ));
+        assert!(!downloaded
+            .proof
+            .verify(&root, &hash_content(&downloaded.content)));
+
+        let path = std::env::temp_dir().join("safe_storage_keyed_download");
+        client
+            .download_verify_to(file.id, &path, |_| Ok(root.clone()))
+            .await
+            .expect("should download and verify");
+        std::fs::remove_file(&path).expect("should remove");
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    #[ignore = "http/1.1 vs http/2 bulk throughput comparison, run with --ignored --nocapture"]
+    async fn bench_bulk_transfers() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let files: Vec<(String, Vec<u8>)> = (0..1000)
+            .map(|i| (format!("{i}.bin"), vec![i as u8; 4096]))
+            .collect();
+
+        for http2 in [false, true] {
+            let client = Client::new(server.url()).with_http2(http2);
+            let started = Instant::now();
+            client.upload_many(&files).await.expect("should upload");
+            let uploaded = started.elapsed();
+            let started = Instant::now();
+            let downloaded = client.download_all().await.expect("should download");
+            println!(
+                "http2: {http2}, {} uploads in {uploaded:?}, {} downloads in {:?}",
+                files.len(),
+                downloaded.len(),
+                started.elapsed()
+            );
+        }
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_zero_settings_rejected() {
+        assert!(spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            hash_threads: 0,
+            ..Default::default()
+        })
+        .is_err());
+        assert!(spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            expiry_interval: Duration::ZERO,
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_full_tree_refused() {
+        let limited = |max_leaves, max_age| ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            tree_limit: Some(TreeLimit {
+                max_leaves,
+                when_full: WhenFull::Refuse,
+            }),
+            epoch_policy: EpochPolicy {
+                max_leaves: None,
+                max_age,
+            },
+            ..Default::default()
+        };
+        assert!(spawn(limited(1, None)).is_err(), "tree would stay full");
+        assert!(spawn(limited(0, Some(Duration::from_secs(3600)))).is_err());
+        let server = spawn(limited(1, Some(Duration::from_secs(3600)))).expect("should start");
+        let client = Client::new(server.url());
+        let info = client.fetch_info().await.expect("should get info");
+        assert_eq!(info.limits.max_tree_leaves, Some(1));
+
+        client
+            .upload_new_file("a.txt", b"a")
+            .await
+            .expect("should upload");
+        let err = client.upload_new_file("b.txt", b"b").await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HttpError>().map(|err| err.status),
+            Some(507)
+        );
+        assert_eq!(client.get_file_list().await.unwrap().files.len(), 1);
+
+        drop(client);
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_measure_proof_overhead() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        for codec in [Codec::Json, Codec::Cbor] {
+            let client = Client::new(server.url()).with_codec(codec);
+            let mut ids = Vec::new();
+            for i in 0..4 {
+                let name = format!("{codec:?}-{i}.bin");
+                let stored = client
+                    .upload_new_file(&name, &[i; 1000])
+                    .await
+                    .expect("should upload");
+                ids.push(stored.file.id);
+            }
+            let measured = client.measure(ids[0]).await.expect("should measure");
+            let file = client.fetch_file(ids[0]).await.expect("should fetch");
+            assert_eq!(measured.content_bytes, 1000);
+            assert_eq!(measured.proof_nodes, file.proof.nodes().len());
+            assert_eq!(
+                measured.content_bytes + measured.proof_bytes + measured.metadata_bytes,
+                codec.encode(&file).unwrap().len() as u64
+            );
+            assert!(measured.overhead() > 0.0);
+        }
+        let err = Client::new(server.url())
+            .measure(FileId(100))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HttpError>().map(|err| err.status),
+            Some(404)
+        );
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_signed_upload_receipts() {
+        let key: ServerKey = "01".repeat(32).parse().expect("should parse");
+        let trusted: TrustedKey = key.public_key().parse().expect("should parse");
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            server_keys: vec![key],
+            importer: Importer::new(vec!["http".to_string()], vec!["127.0.0.1".to_string()], 4),
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+
+        let stored = client
+            .upload_new_file("a.txt", b"content")
+            .await
+            .expect("should upload");
+        let receipt = stored.receipt.expect("should be signed");
+        assert_eq!(
+            (receipt.id, receipt.leaf_index),
+            (stored.file.id, LeafIndex(0))
+        );
+        assert_eq!(receipt.leaf_hash, hash_content(b"content"));
+        assert!(trusted.accepts_receipt(&receipt));
+
+        let batch = client
+            .upload_batch(
+                vec![
+                    ("b.txt".to_string(), b"b".to_vec()),
+                    ("c.txt".to_string(), b"c".to_vec()),
+                ],
+                None,
+            )
+            .await
+            .expect("should upload");
+        for file in &batch.files {
+            let receipt = file.receipt.as_ref().expect("should be signed");
+            assert_eq!(receipt.leaf_index, file.leaf_index);
+            assert!(trusted.accepts_receipt(receipt));
+        }
+
+        let first = client.reserve().await.expect("should reserve");
+        let second = client.reserve().await.expect("should reserve");
+        let waiting = client
+            .upload_reserved_file(second.id, "e.txt", b"e")
+            .await
+            .expect("should fill");
+        assert!(waiting.receipt.is_none(), "earlier slot is not filled");
+        let filled = client
+            .upload_reserved_file(first.id, "d.txt", b"d")
+            .await
+            .expect("should fill");
+        let receipt = filled.receipt.expect("should be signed");
+        assert_eq!(receipt.leaf_index, first.leaf_index);
+        assert!(trusted.accepts_receipt(&receipt));
+        let status = client
+            .fetch_file_status(second.id)
+            .await
+            .expect("should get status");
+        let receipt = status.receipt.expect("should be signed once committed");
+        assert_eq!(receipt.leaf_index, second.leaf_index);
+        assert!(trusted.accepts_receipt(&receipt));
+
+        let path = std::env::temp_dir().join("safe_storage_quarantined_receipt");
+        std::fs::write(&path, b"quarantined").expect("should write");
+        let held = client
+            .upload_quarantined("f.txt", &path)
+            .await
+            .expect("should upload");
+        assert!(held.receipt.is_none());
+        let status = client
+            .wait_for_file(held.id, Duration::from_millis(10))
+            .await
+            .expect("should be validated");
+        let receipt = status.receipt.expect("should be signed");
+        assert_eq!(receipt.leaf_hash, hash_content(b"quarantined"));
+        assert!(trusted.accepts_receipt(&receipt));
+        std::fs::remove_file(&path).expect("should remove");
+
+        let source = format!("{}/files/{}/preview?bytes=3", server.url(), stored.file.id);
+        let imported = client
+            .import_file(&source, Some("g.txt"))
+            .await
+            .expect("should import");
+        let receipt = imported.receipt.expect("should be signed");
+        assert_eq!(
+            (receipt.id, &receipt.leaf_hash),
+            (imported.file.id, &imported.hash)
+        );
+        assert!(trusted.accepts_receipt(&receipt));
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key_from_file() {
+        let admin = ApiKey {
+            name: "ops".to_string(),
+            secret: "secret".to_string(),
+            admin: true,
+        };
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            api_keys: ApiKeys::new(vec![admin]),
+            server_keys: vec!["01".repeat(32).parse().expect("should parse")],
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url()).with_api_key(Some("secret".to_string()));
+        let key_file = std::env::temp_dir().join("safe_storage_rotated_key");
+        std::fs::write(&key_file, "not a key").expect("should write");
+        let err = client.rotate_key(&key_file).await.unwrap_err();
+        let err = err
+            .downcast_ref::<HttpError>()
+            .expect("should be http error");
+        assert_eq!(err.status, 400);
+        assert!(!err.to_string().contains("not a key"), "{err}");
+
+        let key: ServerKey = "02".repeat(32).parse().expect("should parse");
+        std::fs::write(&key_file, "02".repeat(32)).expect("should write");
+        let rotated = client.rotate_key(&key_file).await.expect("should rotate");
+        assert_eq!(rotated.public_key, key.public_key());
+        let keys = client.fetch_keys().await.expect("should list keys").keys;
+        assert_eq!(keys.len(), 2);
+        std::fs::remove_file(&key_file).expect("should remove");
+
+        drop(client);
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[tokio::test]
+    async fn test_compressed_bodies() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let content = b"compressible ".repeat(1000);
+        let mut id = FileId(0);
+        for codings in ["zstd", "gzip"] {
+            let client = Client::new(server.url()).with_codings(codings.parse().unwrap());
+            let stored = client
+                .upload_new_file(&format!("{codings}.txt"), &content)
+                .await
+                .expect("should upload");
+            let file = client
+                .fetch_file(stored.file.id)
+                .await
+                .expect("should download");
+            assert_eq!(file.content, content);
+            id = stored.file.id;
+        }
+
+        let resp = reqwest::Client::new()
+            .get(format!("{}/files/{id}", server.url()))
+            .header("accept-encoding", "br, gzip")
+            .send()
+            .await
+            .expect("should send");
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+
+        // bodies bigger than clients decompress are sent as they are
+        let path = std::env::temp_dir().join("safe_storage_uncompressed_body");
+        let big = b"compressible ".repeat(MAX_BODY_SIZE / 10);
+        std::fs::write(&path, &big).expect("should write");
+        let client = Client::new(server.url()).with_codings("zstd".parse().expect("should parse"));
+        let stored = client
+            .upload_stream("big.txt", &path, None)
+            .await
+            .expect("should upload");
+        let file = client
+            .fetch_file(stored.file.id)
+            .await
+            .expect("should download");
+        assert_eq!(file.content, big);
+        let resp = reqwest::Client::new()
+            .get(format!("{}/files/{}", server.url(), stored.file.id))
+            .header("accept-encoding", "zstd")
+            .send()
+            .await
+            .expect("should send");
+        assert!(resp.headers().get("content-encoding").is_none());
+        std::fs::remove_file(&path).expect("should remove");
+
+        let resp = reqwest::Client::new()
+            .post(format!("{}/files", server.url()))
+            .header("content-encoding", "br")
+            .body("{}")
+            .send()
+            .await
+            .expect("should send");
+        assert_eq!(resp.status(), 415);
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[actix_web::test]
+    async fn test_search_text_files() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            search: true,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        client
+            .upload_new_file("a.txt", b"nothing here")
+            .await
+            .expect("should upload");
+        let stored = client
+            .upload_new_file("b.txt", b"find the Needle")
+            .await
+            .expect("should upload");
+
+        let hits = client
+            .search("needle", None)
+            .await
+            .expect("should search")
+            .hits;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(
+            (hits[0].id, hits[0].name.as_str()),
+            (stored.file.id, "b.txt")
+        );
+        assert_eq!(hits[0].leaf.hash, hash_content(b"find the Needle"));
+        assert_eq!(hits[0].matches[0].offset, 9);
+        server.stop(true).await.expect("should stop");
+
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let err = Client::new(server.url())
+            .search("needle", None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<HttpError>().unwrap().status, 501);
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[actix_web::test]
+    async fn test_name_by_hash() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            leaf_hashing: LeafHashing::NameAndContent,
+            name_by_hash: true,
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url())
+            .detect_leaf_hashing()
+            .await
+            .expect("should detect");
+        let info = client.fetch_info().await.expect("should fetch info");
+        assert!(info.features.name_by_hash);
+
+        let stored = client
+            .upload_new_file("a.txt", b"content")
+            .await
+            .expect("should upload");
+        let name = canonical_name(b"content");
+        assert_eq!(stored.file.name, name);
+        let root = client.fetch_root().await.expect("should have root").hash;
+        let downloaded = client
+            .fetch_file(stored.file.id)
+            .await
+            .expect("should download");
+        let leaf = client.leaf_hasher().leaf(&name, b"content");
+        assert!(downloaded.proof.verify(&root, &leaf));
+        client
+            .verify_file(&downloaded, Instant::now(), |_| Ok(root.clone()))
+            .expect("should verify");
+        let renamed = FileContent {
+            name: "a.txt".to_string(),
+            ..downloaded
+        };
+        let err = client
+            .verify_file(&renamed, Instant::now(), |_| Ok(root.clone()))
+            .unwrap_err();
+        assert!(err.to_string().contains("not by its content hash"), "{err}");
+
+        let batch = client
+            .upload_batch(
+                vec![
+                    ("b.txt".to_string(), b"first".to_vec()),
+                    ("b.txt".to_string(), b"second".to_vec()),
+                ],
+                None,
+            )
+            .await
+            .expect("should upload");
+        let names: Vec<_> = batch.files.iter().map(|file| &file.file.name).collect();
+        assert_eq!(
+            names,
+            [&canonical_name(b"first"), &canonical_name(b"second")]
+        );
+
+        let path = std::env::temp_dir().join("safe_storage_name_by_hash_test");
+        std::fs::write(&path, b"streamed").expect("should write");
+        let err = client
+            .upload_stream("c.txt", &path, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<HttpError>().unwrap().status, 422);
+        let streamed = client
+            .upload_stream(&canonical_name(b"streamed"), &path, None)
+            .await
+            .expect("should upload");
+        assert_eq!(streamed.file.name, canonical_name(b"streamed"));
+        std::fs::remove_file(&path).expect("should remove");
+
+        server.stop(true).await.expect("should stop");
+    }
+
+    #[actix_web::test]
+    async fn test_webhook_deliveries() {
+        type Received = std::sync::Mutex<Vec<(String, web::Bytes)>>;
+        let received = web::Data::new(Received::default());
+        let receiving = received.clone();
+        let receiver = HttpServer::new(move || {
+            App::new().app_data(receiving.clone()).route(
+                "/hook",
+                web::post().to(
+                    |request: actix_web::HttpRequest,
+                     body: web::Bytes,
+                     received: web::Data<Received>| async move {
+                        let signature = request
+                            .headers()
+                            .get(crate::webhook::SIGNATURE_HEADER)
+                            .expect("should be signed")
+                            .to_str()
+                            .expect("should be text")
+                            .to_string();
+                        received
+                            .lock()
+                            .expect("should lock")
+                            .push((signature, body));
+                        actix_web::HttpResponse::Ok().finish()
+                    },
+                ),
+            )
+        })
+        .bind(("127.0.0.1", 0))
+        .expect("should bind");
+        let hook_url = format!("http://{}/hook", receiver.addrs()[0]);
+        let receiver = receiver.run();
+        let receiver_handle = receiver.handle();
+        tokio::spawn(receiver);
+
+        let cursor = std::env::temp_dir().join("safe_storage_webhook_cursor");
+        let _ = std::fs::remove_file(&cursor);
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            webhook: Some(
+                Webhook::new(hook_url, "secret")
+                    .with_interval(Duration::from_millis(20))
+                    .with_cursor_file(&cursor),
+            ),
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let stored = client
+            .upload_new_file("a.txt", b"content")
+            .await
+            .expect("should upload");
+        let root = client.fetch_root().await.expect("should have root");
+        let started = Instant::now();
+        while std::fs::read_to_string(&cursor).ok().as_deref() != Some("1") {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "webhook not delivered"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let (signature, body) = received.lock().expect("should lock").remove(0);
+        crate::webhook::verify_signature(&body, &signature, b"secret").expect("should be signed");
+        assert!(crate::webhook::verify_signature(&body, &signature, b"other").is_err());
+        let delivery: WebhookDelivery = serde_json::from_slice(&body).expect("should parse");
+        match &delivery.events[..] {
+            [WebhookEvent::FileStored { file }, WebhookEvent::RootChanged { root: changed }] => {
+                assert_eq!((file.id, file.name.as_str()), (stored.file.id, "a.txt"));
+                assert_eq!((&changed.hash, changed.size), (&root.hash, root.size));
+            }
+            events => panic!("unexpected events {events:?}"),
+        }
+        server.stop(true).await.expect("should stop");
+        receiver_handle.stop(true).await;
+        std::fs::remove_file(&cursor).expect("should remove");
+    }
+
+    #[tokio::test]
+    async fn test_federation() {
+        let log = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            api_keys: ApiKeys::new(vec!["peer:p".parse().expect("should parse")]),
+            federation: Some(FederationConfig {
+                peers: Vec::new(),
+                interval: Duration::from_secs(60),
+                api_key: None,
+            }),
+            ..Default::default()
+        })
+        .expect("should start");
+        let federation = FederationConfig {
+            peers: vec![log.url()],
+            interval: Duration::from_millis(20),
+            api_key: Some("p".to_string()),
+        };
+        assert!(spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            federation: Some(federation.clone()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            server_keys: vec!["03".repeat(32).parse().expect("should parse")],
+            federation: Some(FederationConfig {
+                interval: Duration::ZERO,
+                ..federation.clone()
+            }),
+            ..Default::default()
+        })
+        .is_err());
+        let key: ServerKey = "02".repeat(32).parse().expect("should parse");
+        let public_key = key.public_key();
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            server_keys: vec![key],
+            federation: Some(federation),
+            ..Default::default()
+        })
+        .expect("should start");
+        let client = Client::new(server.url());
+        let log_client = Client::new(log.url());
+        let info = log_client.fetch_info().await.expect("should answer");
+        assert!(info.features.federation);
+
+        client
+            .upload_new_file("a.txt", b"content")
+            .await
+            .expect("should upload");
+        let root = client.fetch_root().await.expect("should have root");
+        let started = Instant::now();
+        let attested = loop {
+            let list = log_client
+                .fetch_attestations(Some(&public_key))
+                .await
+                .expect("should list");
+            if let Some(attested) = list.attestations.into_iter().last() {
+                break attested;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "not submitted");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        assert!(attested.verify());
+        assert_eq!(
+            (&attested.root, attested.tree_size),
+            (&root.hash, root.size)
+        );
+
+        let status = |err: anyhow::Error| err.downcast_ref::<HttpError>().map(|err| err.status);
+        let err = log_client
+            .submit_attestation(&attested)
+            .await
+            .expect_err("should require api key");
+        assert_eq!(status(err), Some(401));
+        let log_client = log_client.with_api_key(Some("p".to_string()));
+        let mut forged = attested.clone();
+        forged.tree_size = TreeSize(7);
+        let err = log_client
+            .submit_attestation(&forged)
+            .await
+            .expect_err("should refuse forged root");
+        assert_eq!(status(err), Some(422));
+        let own = client.fetch_attestations(None).await.expect("should list");
+        assert!(own.attestations.is_empty());
+        let list = log_client
+            .fetch_attestations(None)
+            .await
+            .expect("should list");
+        assert!(list.equivocations.is_empty());
+
+        drop((client, log_client));
+        server.stop(true).await.expect("should stop");
+        log.stop(true).await.expect("should stop");
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        events: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl ClientMiddleware for std::sync::Arc<Recorder> {
+        fn on_request(&self, request: &mut reqwest::Request) {
+            request
+                .headers_mut()
+                .insert("x-recorded", "yes".parse().unwrap());
+            let event = format!("request {}", request.url().path());
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_response(&self, method: &reqwest::Method, response: &reqwest::Response, _: Duration) {
+            let event = format!("{method} {}", response.status().as_u16());
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_retry(&self, _: &reqwest::Request, attempt: u32, _: &anyhow::Error) {
+            self.events.lock().unwrap().push(format!("retry {attempt}"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_client_middleware() {
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let recorder = std::sync::Arc::new(Recorder::default());
+        let client = Client::new(server.url())
+            .with_middleware(recorder.clone())
+            .with_retries(2);
+        client
+            .upload_new_file("a.txt", b"hello")
+            .await
+            .expect("should upload");
+        assert!(client.fetch_file(FileId(7)).await.is_err());
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            ["request /files", "POST 201", "request /files/7", "GET 404"]
+        );
+        let url = server.url();
+        server.stop(true).await.expect("should stop");
+
+        recorder.events.lock().unwrap().clear();
+        let client = Client::new(url)
+            .with_middleware(recorder.clone())
+            .with_retries(2);
+        assert!(client.fetch_root().await.is_err());
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [
+                "request /root",
+                "retry 1",
+                "request /root",
+                "retry 2",
+                "request /root"
+            ]
+        );
+    }
+
+    /// Uploads, downloads and root fetches racing each other against one server: leaves are
+    /// appended in some order each writer saw its own leaf last in, roots seen by readers are of
+    /// complete prefixes of that order and grow, and every served proof holds for one of them
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_uploads_downloads_and_roots() {
+        const UPLOADS: usize = 24;
+        let server = spawn(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .expect("should start");
+        let url = server.url();
+        let first = Client::new(url.clone())
+            .upload_new_file("first.txt", b"first")
+            .await
+            .expect("should upload");
+
+        let uploads: Vec<_> = (0..UPLOADS)
+            .map(|n| {
+                let client = Client::new(url.clone());
+                tokio::spawn(async move {
+                    let content = format!("content {n}");
+                    let stored = client
+                        .upload_new_file(&format!("{n}.txt"), content.as_bytes())
+                        .await
+                        .expect("should upload");
+                    (content, stored.root.expect("no reservations are made"))
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let client = Client::new(url.clone());
+                tokio::spawn(async move {
+                    let mut seen = Vec::new();
+                    for _ in 0..UPLOADS / 4 {
+                        let root = client.fetch_root().await.expect("should have root");
+                        let file = client.fetch_file(first.file.id).await.expect("should get");
+                        seen.push((root, file.proof));
+                    }
+                    seen
+                })
+            })
+            .collect();
+
+        let mut leaves = vec![None; UPLOADS + 1];
+        leaves[0] = Some(hash_content(b"first"));
+        let mut reported = Vec::new();
+        for upload in uploads {
+            let (content, root) = upload.await.expect("should join");
+            let index = root.size.as_usize() - 1;
+            assert!(leaves[index].is_none(), "leaf {index} appended twice");
+            leaves[index] = Some(hash_content(content));
+            reported.push(root);
+        }
+        let leaves: Vec<_> = leaves
+            .into_iter()
+            .map(|leaf| leaf.expect("no gaps"))
+            .collect();
+        let roots: Vec<_> = (1..=leaves.len())
+            .map(|size| {
+                Sha3Tree::from_manifest(leaves[..size].iter().cloned())
+                    .root()
+                    .expect("tree is not empty")
+            })
+            .collect();
+        for root in reported {
+            assert_eq!(root.hash, roots[root.size.as_usize() - 1]);
+        }
+        for reader in readers {
+            let seen = reader.await.expect("should join");
+            let sizes: Vec<_> = seen.iter().map(|(root, _)| root.size).collect();
+            assert!(sizes.windows(2).all(|pair| pair[0] <= pair[1]), "{sizes:?}");
+            for (root, proof) in seen {
+                assert_eq!(root.hash, roots[root.size.as_usize() - 1]);
+                assert!(roots.iter().any(|root| proof.verify(root, &leaves[0])));
+            }
+        }
+        let root = Client::new(url)
+            .fetch_root()
+            .await
+            .expect("should have root");
+        assert_eq!(
+            (root.hash, root.size),
+            (roots[UPLOADS].clone(), TreeSize(UPLOADS as u64 + 1))
+        );
+    }
+}
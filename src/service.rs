@@ -1,65 +1,1334 @@
-use crate::api::{File, FileContent, FileList, NewFile, RootHash};
-use crate::storage::Storage;
-use actix_web::{get, post, web, HttpResponse, Responder};
+use crate::api::{
+    AttestationQuery, BatchRequest, BlobUsage, CheckpointList, Consistency, ConsistencyQuery,
+    DatasetList, EpochArchive, File, FileChanges, FileChangesQuery, FileContent, FileGroup,
+    FileGrouping, FileId, FileList, FileListQuery, HashProof, Health, HealthStatus,
+    HistoricalProof, HistoricalProofQuery, ImportFile, ImportedFile, IpfsExport, KeyList,
+    KeyRotation, LeafList, MigrationList, NewFile, NewFileBatch, PageQuery, PreviewQuery,
+    QuarantineQuery, RawFileMeta, Reservation, RootHash, RuntimeStats, SearchHit, SearchQuery,
+    SearchResults, ServerInfo, SignedRoot, Stats, StoredBatch, StoredBatchFile, StoredFile,
+    StreamQuery, Usage, UsageList, MAX_BATCH_FILES, MAX_PAGE_LIMIT,
+};
+use crate::auth::Caller;
+use crate::cluster::Router;
+use crate::codec::{etag_matches, etag_of, to_header_value, Codec, Decoded, FILE_META_HEADER};
+use crate::federation::{AttestationError, Attestations};
+use crate::hashing::HashPool;
+use crate::import::{ImportError, Importer};
+use crate::interceptor::UploadInterceptors;
+use crate::ipfs::IpfsNode;
+use crate::merkle::Sha3Hash;
+use crate::metrics::LagProbe;
+use crate::search::SearchIndex;
+use crate::signing::ServerKey;
+use crate::staging::{StagedFile, Staging};
+use crate::storage::{self, Storage, StorageError};
+use crate::throttle::RateLimit;
+use crate::trace::TraceContext;
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Responder};
+use futures_util::stream::{self, Stream};
+use std::convert::Infallible;
 use std::ops::Deref;
 use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Bytes returned by preview when query doesn't ask for specific amount
+const DEFAULT_PREVIEW_BYTES: usize = 1024;
+/// Preview is meant for quick inspection, whole files are downloaded with proof
+const MAX_PREVIEW_BYTES: usize = 64 * 1024;
+
+/// Single page ui listing, uploading and verifying files in the browser
+#[cfg(feature = "web-ui")]
+#[get("/")]
+pub async fn get_web_ui() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("web/index.html"))
+}
 
 #[get("/files")]
-pub async fn get_file_list(storage: web::Data<Mutex<Storage>>) -> impl Responder {
-    let files = storage
+pub async fn get_file_list(
+    storage: web::Data<Mutex<Storage>>,
+    query: web::Query<FileListQuery>,
+    codec: Codec,
+) -> impl Responder {
+    let files = {
+        let storage = storage.lock().expect("should lock");
+        let files = match (query.limit, query.cursor) {
+            (None, None) => match query.deleted {
+                true => storage.list_deleted_by(query.sort, query.order),
+                false => storage.list_files_by(
+                    SystemTime::now(),
+                    query.include_expired,
+                    query.sort,
+                    query.order,
+                ),
+            }
+            .map(|files| (files, None)),
+            (limit, _) => storage.list_files_page(&query, page_limit(limit)),
+        };
+        files.and_then(|(mut files, next_cursor)| {
+            if query.leaves {
+                for file in &mut files {
+                    file.leaf = Some(storage.listed_leaf(file.id)?);
+                }
+            }
+            Ok((files, next_cursor))
+        })
+    };
+    let list = match (files, query.group) {
+        (Ok((files, next_cursor)), None) => FileList {
+            files,
+            groups: Vec::new(),
+            next_cursor,
+        },
+        (Ok((files, next_cursor)), Some(FileGrouping::Prefix)) => FileList {
+            files: Vec::new(),
+            groups: FileGroup::by_prefix(files),
+            next_cursor,
+        },
+        (Err(err), _) => return storage_error(err),
+    };
+    codec.respond(HttpResponse::Ok(), list)
+}
+
+#[get("/files/changes")]
+pub async fn get_file_changes(
+    storage: web::Data<Mutex<Storage>>,
+    query: web::Query<FileChangesQuery>,
+    codec: Codec,
+) -> impl Responder {
+    let changes = storage
         .lock()
         .expect("should lock")
-        .list_all_files()
-        .into_iter()
-        .map(|(i, name, _)| File { id: i as u32, name })
-        .collect();
-    HttpResponse::Ok().json(FileList { files })
+        .list_changes(query.since_tree_size, SystemTime::now());
+    match changes {
+        Ok((files, deleted, tree_size)) => codec.respond(
+            HttpResponse::Ok(),
+            FileChanges {
+                files,
+                deleted,
+                tree_size,
+            },
+        ),
+        Err(err) => storage_error(err),
+    }
 }
 
 #[post("/files")]
 pub async fn upload_new_file(
     storage: web::Data<Mutex<Storage>>,
-    new_file: web::Json<NewFile>,
+    interceptors: web::Data<UploadInterceptors>,
+    hash_pool: web::Data<HashPool>,
+    caller: Caller,
+    new_file: Decoded<NewFile>,
+    codec: Codec,
+) -> impl Responder {
+    let NewFile {
+        name,
+        content,
+        ttl_secs,
+        expected_tree_size,
+        dataset,
+    } = new_file.0;
+    if let Err(rejection) = interceptors.check(&name, &content).await {
+        return HttpResponse::UnprocessableEntity().body(rejection.to_string());
+    }
+    let (name, content, hash) = hash_pool.hash_named(name, content).await;
+    let mut storage = storage.lock().expect("should lock");
+    let stored = expected_tree_size
+        .map_or(Ok(()), |expected| storage.expect_tree_size(expected))
+        .and_then(|()| check_dataset(&storage, dataset.as_deref()))
+        .and_then(|()| {
+            storage.atomically(|storage| {
+                let id = storage.add_hashed_file_as(&caller.name, name, content, hash)?;
+                join_dataset(storage, id, dataset.as_deref())?;
+                Ok(id)
+            })
+        })
+        .and_then(|id| {
+            Ok(StoredFile {
+                file: stored_file(&mut storage, id, ttl_secs)?,
+                root: storage.root_of_file(id)?,
+                receipt: storage.upload_receipt(id, SystemTime::now())?,
+            })
+        });
+    match stored {
+        Ok(stored) => codec.respond(HttpResponse::Created(), stored),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Stores all files of the batch with consecutive leaves or none of them, so a client can't end
+/// up with only some of its leaves in the tree
+#[post("/files/batch/upload")]
+pub async fn upload_file_batch(
+    storage: web::Data<Mutex<Storage>>,
+    interceptors: web::Data<UploadInterceptors>,
+    hash_pool: web::Data<HashPool>,
+    caller: Caller,
+    batch: Decoded<NewFileBatch>,
+    codec: Codec,
+) -> impl Responder {
+    let NewFileBatch {
+        files,
+        ttl_secs,
+        expected_tree_size,
+        dataset,
+    } = batch.0;
+    if files.is_empty() || files.len() > MAX_BATCH_FILES {
+        return HttpResponse::BadRequest()
+            .body(format!("batch must have 1 to {MAX_BATCH_FILES} files"));
+    }
+    let mut hashed = Vec::with_capacity(files.len());
+    for file in files {
+        if let Err(rejection) = interceptors.check(&file.name, &file.content).await {
+            return HttpResponse::UnprocessableEntity().body(format!("{}: {rejection}", file.name));
+        }
+        hashed.push(hash_pool.hash_named(file.name, file.content).await);
+    }
+    let mut storage = storage.lock().expect("should lock");
+    let stored = expected_tree_size
+        .map_or(Ok(()), |expected| storage.expect_tree_size(expected))
+        .and_then(|()| check_dataset(&storage, dataset.as_deref()))
+        .and_then(|()| {
+            storage.atomically(|storage| {
+                let stored = storage.add_hashed_files_as(&caller.name, hashed)?;
+                for (id, _) in &stored {
+                    join_dataset(storage, *id, dataset.as_deref())?;
+                }
+                Ok(stored)
+            })
+        })
+        .and_then(|stored| {
+            let files = stored
+                .iter()
+                .map(|(id, leaf_index)| {
+                    Ok(StoredBatchFile {
+                        file: stored_file(&mut storage, *id, ttl_secs)?,
+                        leaf_index: *leaf_index,
+                        receipt: storage.upload_receipt(*id, SystemTime::now())?,
+                    })
+                })
+                .collect::<Result<Vec<_>, StorageError>>()?;
+            let last = stored.last().expect("batch is not empty").0;
+            Ok(StoredBatch {
+                files,
+                root: storage.root_of_file(last)?,
+            })
+        });
+    match stored {
+        Ok(stored) => codec.respond(HttpResponse::Created(), stored),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Raw upload streamed into hasher and staging file at once, so memory taken by an upload is
+/// bounded by a single chunk. Staged file is moved into blob store only once it is accepted.
+#[post("/files/stream")]
+pub async fn upload_stream(
+    storage: web::Data<Mutex<Storage>>,
+    interceptors: web::Data<UploadInterceptors>,
+    (staging, hash_pool): (web::Data<Staging>, web::Data<HashPool>),
+    caller: Caller,
+    query: web::Query<StreamQuery>,
+    payload: web::Payload,
+    codec: Codec,
+) -> impl Responder {
+    let StreamQuery {
+        name,
+        ttl_secs,
+        expected_tree_size,
+        dataset,
+    } = query.into_inner();
+    let staged = match staging
+        .stage(
+            payload,
+            hash_pool.leaf_hasher().start(&name),
+            hash_pool.content_hashes(),
+            interceptors.max_size(),
+        )
+        .await
+    {
+        Ok(staged) => staged,
+        Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+            return HttpResponse::PayloadTooLarge().body(err.to_string())
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::InvalidInput => {
+            return HttpResponse::BadRequest().body(err.to_string())
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    if let Err(response) = check_canonical_name(&name, &staged, hash_pool.names_by_hash()) {
+        return response;
+    }
+    let checked = interceptors
+        .check_file(&name, staged.path(), staged.size())
+        .await;
+    if let Err(rejection) = checked {
+        return HttpResponse::UnprocessableEntity().body(rejection.to_string());
+    }
+    let mut storage = storage.lock().expect("should lock");
+    let stored = expected_tree_size
+        .map_or(Ok(()), |expected| storage.expect_tree_size(expected))
+        .and_then(|()| check_dataset(&storage, dataset.as_deref()))
+        .and_then(|()| {
+            storage.atomically(|storage| {
+                let id = storage.add_staged_file_as(
+                    &caller.name,
+                    name,
+                    staged.path(),
+                    staged.size(),
+                    storage::detect_mime_of_head(staged.head()),
+                    staged.hash().clone(),
+                )?;
+                if let Some(content_hash) = staged.content_hash() {
+                    storage.record_content_hash(id, content_hash.clone())?;
+                }
+                join_dataset(storage, id, dataset.as_deref())?;
+                Ok(id)
+            })
+        })
+        .and_then(|id| {
+            Ok(StoredFile {
+                file: stored_file(&mut storage, id, ttl_secs)?,
+                root: storage.root_of_file(id)?,
+                receipt: storage.upload_receipt(id, SystemTime::now())?,
+            })
+        });
+    match stored {
+        Ok(stored) => codec.respond(HttpResponse::Created(), stored),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// First phase of two-phase ingestion: raw upload is staged and recorded out of the tree right
+/// away, upload checks run in background and either commit the file or reject it. Progress is
+/// followed with `GET /files/{id}/status`.
+#[post("/files/quarantine")]
+pub async fn upload_quarantined(
+    storage: web::Data<Mutex<Storage>>,
+    interceptors: web::Data<UploadInterceptors>,
+    (staging, hash_pool): (web::Data<Staging>, web::Data<HashPool>),
+    caller: Caller,
+    query: web::Query<QuarantineQuery>,
+    payload: web::Payload,
+    codec: Codec,
 ) -> impl Responder {
-    let NewFile { name, content } = new_file.0;
-    let id = storage
+    let QuarantineQuery { name, ttl_secs } = query.into_inner();
+    let staged = match staging
+        .stage(
+            payload,
+            hash_pool.leaf_hasher().start(&name),
+            hash_pool.content_hashes(),
+            interceptors.max_size(),
+        )
+        .await
+    {
+        Ok(staged) => staged,
+        Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+            return HttpResponse::PayloadTooLarge().body(err.to_string())
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::InvalidInput => {
+            return HttpResponse::BadRequest().body(err.to_string())
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    if let Err(response) = check_canonical_name(&name, &staged, hash_pool.names_by_hash()) {
+        return response;
+    }
+    let status = {
+        let mut storage = storage.lock().expect("should lock");
+        storage
+            .atomically(|storage| {
+                let id = storage.hold_file_as(
+                    &caller.name,
+                    name.clone(),
+                    staged.size(),
+                    storage::detect_mime_of_head(staged.head()),
+                    staged.hash().clone(),
+                )?;
+                if let Some(content_hash) = staged.content_hash() {
+                    storage.record_content_hash(id, content_hash.clone())?;
+                }
+                Ok(id)
+            })
+            .and_then(|id| storage.file_status(id, SystemTime::now()))
+    };
+    let status = match status {
+        Ok(status) => status,
+        Err(err) => return storage_error(err),
+    };
+    let id = status.id;
+    let held = storage.clone();
+    tokio::spawn(async move {
+        let checked = interceptors
+            .check_file(&name, staged.path(), staged.size())
+            .await;
+        let mut storage = held.lock().expect("should lock");
+        let released = match checked {
+            Ok(()) => storage
+                .release_file(id, staged.path())
+                .and_then(|_| stored_file(&mut storage, id, ttl_secs))
+                .map_err(|err| err.to_string()),
+            Err(rejection) => Err(rejection.to_string()),
+        };
+        if let Err(reason) = released {
+            // release fails before anything is changed, so the file is still held
+            let _ = storage.reject_file(id, reason);
+        }
+    });
+    codec.respond(HttpResponse::Accepted(), status)
+}
+
+/// Streamed content is hashed as it arrives, before it is known which name would be canonical,
+/// so on servers naming files by hash it must be uploaded under that name already
+fn check_canonical_name(
+    name: &str,
+    staged: &StagedFile,
+    names_by_hash: bool,
+) -> Result<(), HttpResponse> {
+    match staged.content_hash().filter(|_| names_by_hash) {
+        Some(content_hash) if content_hash.to_string() != name => {
+            Err(HttpResponse::UnprocessableEntity().body(format!(
+                "files are named by content hash, upload this one as {content_hash}"
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether file is still validated, committed or rejected, with proof once it is committed
+#[get("/files/{id}/status")]
+pub async fn get_file_status(
+    storage: web::Data<Mutex<Storage>>,
+    id: web::Path<FileId>,
+    codec: Codec,
+) -> impl Responder {
+    let id = *id.deref();
+    let status = storage
         .lock()
         .expect("should lock")
-        .add_new_file(name.clone(), content);
-    HttpResponse::Created().json(File {
+        .file_status(id, SystemTime::now());
+    match status {
+        Ok(status) => codec.respond(HttpResponse::Ok(), status),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Shards with their current roots proven against cluster root, served by cluster router
+#[get("/cluster")]
+pub async fn get_cluster(router: web::Data<Router>, codec: Codec) -> impl Responder {
+    match router.status().await {
+        Ok(cluster) => codec.respond(HttpResponse::Ok(), cluster),
+        Err(err) => HttpResponse::BadGateway().body(err.to_string()),
+    }
+}
+
+/// Fetches content from given url on the server side and stores it like a regular upload
+#[post("/files/import")]
+pub async fn import_file(
+    storage: web::Data<Mutex<Storage>>,
+    interceptors: web::Data<UploadInterceptors>,
+    hash_pool: web::Data<HashPool>,
+    importer: web::Data<Importer>,
+    (caller, trace): (Caller, TraceContext),
+    import: Decoded<ImportFile>,
+    codec: Codec,
+) -> impl Responder {
+    let ImportFile {
+        url,
         name,
-        id: id as u32,
-    })
+        ttl_secs,
+    } = import.0;
+    let (name, content) = match importer.fetch(&url, name, &trace).await {
+        Ok(fetched) => fetched,
+        Err(err) => return import_error(err),
+    };
+    if let Err(rejection) = interceptors.check(&name, &content).await {
+        return HttpResponse::UnprocessableEntity().body(rejection.to_string());
+    }
+    let (name, content, hash) = hash_pool.hash_named(name, content).await;
+    let mut storage = storage.lock().expect("should lock");
+    let stored = storage
+        .add_hashed_file_as(&caller.name, name, content, hash.clone())
+        .and_then(|id| stored_file(&mut storage, id, ttl_secs));
+    let imported = stored.and_then(|file| {
+        let proof = storage.proof(file.id).ok();
+        Ok(ImportedFile {
+            receipt: storage.upload_receipt(file.id, SystemTime::now())?,
+            file,
+            hash,
+            root: proof.as_ref().map(|proof| proof.root.clone()),
+            proof: proof.map(|proof| proof.proof),
+        })
+    });
+    match imported {
+        Ok(imported) => codec.respond(HttpResponse::Created(), imported),
+        Err(err) => storage_error(err),
+    }
+}
+
+#[post("/files/reserve")]
+pub async fn reserve_file(
+    storage: web::Data<Mutex<Storage>>,
+    caller: Caller,
+    codec: Codec,
+) -> impl Responder {
+    let reserved = storage.lock().expect("should lock").reserve(&caller.name);
+    match reserved {
+        Ok((id, leaf_index)) => {
+            codec.respond(HttpResponse::Created(), Reservation { id, leaf_index })
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+#[put("/files/{id}")]
+pub async fn upload_reserved_file(
+    storage: web::Data<Mutex<Storage>>,
+    interceptors: web::Data<UploadInterceptors>,
+    hash_pool: web::Data<HashPool>,
+    caller: Caller,
+    id: web::Path<FileId>,
+    new_file: Decoded<NewFile>,
+    codec: Codec,
+) -> impl Responder {
+    let id = *id.deref();
+    let NewFile {
+        name,
+        content,
+        ttl_secs,
+        dataset,
+        ..
+    } = new_file.0;
+    if dataset.is_some() {
+        return HttpResponse::BadRequest().body("reserved files can't join datasets");
+    }
+    if let Err(rejection) = interceptors.check(&name, &content).await {
+        return HttpResponse::UnprocessableEntity().body(rejection.to_string());
+    }
+    let (name, content, hash) = hash_pool.hash_named(name, content).await;
+    let mut storage = storage.lock().expect("should lock");
+    let stored = storage
+        .fill_reservation(id, &caller.name, name, content, hash)
+        .and_then(|_| {
+            Ok(StoredFile {
+                file: stored_file(&mut storage, id, ttl_secs)?,
+                root: storage.root_of_file(id)?,
+                receipt: storage.upload_receipt(id, SystemTime::now())?,
+            })
+        });
+    match stored {
+        Ok(stored) => codec.respond(HttpResponse::Ok(), stored),
+        Err(err) => storage_error(err),
+    }
 }
 
 #[get("/files/{id}")]
 pub async fn get_file_content(
     storage: web::Data<Mutex<Storage>>,
-    id: web::Path<u32>,
+    rate_limit: web::Data<Option<RateLimit>>,
+    id: web::Path<FileId>,
+    codec: Codec,
 ) -> impl Responder {
     let id = *id.deref();
-    let content = storage
-        .lock()
-        .expect("should lock")
-        .get_file_by_id(id as usize);
-    match content {
-        Some((name, content, proof)) => HttpResponse::Ok().json(FileContent {
+    let content = {
+        let mut storage = storage.lock().expect("should lock");
+        storage.get_file_by_id(id).inspect(|_| {
+            storage.record_download(&[id], SystemTime::now());
+        })
+    };
+    let file_content = match content {
+        Ok((name, content, proof)) => FileContent {
             id,
             name,
             content,
-            proof,
-        }),
-        None => HttpResponse::NotFound().body(format!("file {} not found", id)),
+            proof: proof.proof,
+            epoch: proof.epoch,
+        },
+        Err(StorageError::NotFound) => return file_not_found(&storage, id),
+        Err(err) => return storage_error(err),
+    };
+    match **rate_limit {
+        Some(limit) => match codec.encode(&file_content) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type(codec.mime())
+                .streaming(throttled(body, limit)),
+            Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        },
+        None => codec.respond(HttpResponse::Ok(), file_content),
+    }
+}
+
+/// Many files with a single multi-proof, saving round trips when restoring lots of small files
+#[post("/files/batch")]
+pub async fn get_file_batch(
+    storage: web::Data<Mutex<Storage>>,
+    rate_limit: web::Data<Option<RateLimit>>,
+    request: Decoded<BatchRequest>,
+    codec: Codec,
+) -> impl Responder {
+    if request.ids.is_empty() || request.ids.len() > MAX_BATCH_FILES {
+        return HttpResponse::BadRequest()
+            .body(format!("batch must have 1 to {MAX_BATCH_FILES} file ids"));
+    }
+    let batch = {
+        let mut storage = storage.lock().expect("should lock");
+        storage.get_files_by_ids(&request.ids).inspect(|batch| {
+            let ids: Vec<_> = batch.files.iter().map(|file| file.id).collect();
+            storage.record_download(&ids, SystemTime::now());
+        })
+    };
+    let batch = match batch {
+        Ok(batch) => batch,
+        Err(StorageError::NotFound) => {
+            return HttpResponse::NotFound().body("some of requested files are not found")
+        }
+        Err(err) => return storage_error(err),
+    };
+    match **rate_limit {
+        Some(limit) => match codec.encode(&batch) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type(codec.mime())
+                .streaming(throttled(body, limit)),
+            Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        },
+        None => codec.respond(HttpResponse::Ok(), batch),
+    }
+}
+
+/// Raw file content with metadata and proof in [`FILE_META_HEADER`], so clients can hash content
+/// while streaming it to disk instead of buffering it whole. Content under a leaf never changes, so
+/// it is tagged with leaf hash and `If-None-Match` of that tag is answered with `304 Not Modified`
+/// carrying fresh proof only. Proof changes as the tree grows, so caches have to revalidate.
+#[get("/files/{id}/raw")]
+pub async fn get_file_raw(
+    storage: web::Data<Mutex<Storage>>,
+    rate_limit: web::Data<Option<RateLimit>>,
+    id: web::Path<FileId>,
+    req: HttpRequest,
+) -> impl Responder {
+    let id = *id.deref();
+    let if_none_match = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+    let file = {
+        let mut storage = storage.lock().expect("should lock");
+        storage
+            .get_file_unless_cached(id, |hash| {
+                if_none_match.is_some_and(|tags| etag_matches(tags, &etag_of(hash)))
+            })
+            .inspect(|file| {
+                // revalidation of cached content is not a download
+                if file.content.is_some() {
+                    storage.record_download(&[id], SystemTime::now());
+                }
+            })
+    };
+    let file = match file {
+        Ok(file) => file,
+        Err(StorageError::NotFound) => return file_not_found(&storage, id),
+        Err(err) => return storage_error(err),
+    };
+    let meta = RawFileMeta {
+        id,
+        name: file.name,
+        epoch: file.proof.epoch,
+        proof: file.proof.proof,
+    };
+    let meta = match to_header_value(&meta) {
+        Ok(meta) => meta,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let mut response = match file.content {
+        Some(_) => HttpResponse::Ok(),
+        None => HttpResponse::NotModified(),
+    };
+    response
+        .insert_header((FILE_META_HEADER, meta))
+        .insert_header((header::ETAG, etag_of(&file.hash)))
+        .insert_header((header::CACHE_CONTROL, "no-cache"));
+    match (file.content, **rate_limit) {
+        (None, _) => response.finish(),
+        (Some(content), Some(limit)) => response
+            .content_type(storage::DEFAULT_MIME)
+            .streaming(throttled(content, limit)),
+        (Some(content), None) => response.content_type(storage::DEFAULT_MIME).body(content),
+    }
+}
+
+/// First bytes of file served with detected content type, so browsers can render it. Uploaded
+/// content is untrusted, so it is sandboxed and never sniffed into something else.
+#[get("/files/{id}/preview")]
+pub async fn get_file_preview(
+    storage: web::Data<Mutex<Storage>>,
+    id: web::Path<FileId>,
+    query: web::Query<PreviewQuery>,
+) -> impl Responder {
+    let id = *id.deref();
+    let len = query
+        .bytes
+        .unwrap_or(DEFAULT_PREVIEW_BYTES)
+        .min(MAX_PREVIEW_BYTES);
+    let preview = storage.lock().expect("should lock").preview(id, len);
+    match preview {
+        Ok((mime, content)) => HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, mime))
+            .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .insert_header((header::CONTENT_SECURITY_POLICY, "sandbox"))
+            .body(content),
+        Err(StorageError::NotFound) => file_not_found(&storage, id),
+        Err(err) => storage_error(err),
+    }
+}
+
+#[delete("/files/{id}")]
+pub async fn delete_file(
+    storage: web::Data<Mutex<Storage>>,
+    caller: Caller,
+    id: web::Path<FileId>,
+    codec: Codec,
+) -> impl Responder {
+    let id = *id.deref();
+    let receipt = storage
+        .lock()
+        .expect("should lock")
+        .delete_file_as(&caller.name, id);
+    match receipt {
+        Ok(receipt) => codec.respond(HttpResponse::Ok(), receipt),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Appends content of deleted file again as a new file referencing the deleted one
+#[post("/files/{id}/undelete")]
+pub async fn undelete_file(
+    storage: web::Data<Mutex<Storage>>,
+    caller: Caller,
+    id: web::Path<FileId>,
+    codec: Codec,
+) -> impl Responder {
+    let id = *id.deref();
+    let mut storage = storage.lock().expect("should lock");
+    let stored = storage.undelete_file_as(&caller.name, id).and_then(|id| {
+        Ok(StoredFile {
+            file: storage.describe(id)?,
+            root: storage.root_of_file(id)?,
+            receipt: storage.upload_receipt(id, SystemTime::now())?,
+        })
+    });
+    match stored {
+        Ok(stored) => codec.respond(HttpResponse::Created(), stored),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Pins content of the file to configured IPFS node and records its CID with the file, only
+/// files of the caller are published
+#[post("/files/{id}/ipfs")]
+pub async fn export_to_ipfs(
+    storage: web::Data<Mutex<Storage>>,
+    ipfs: web::Data<Option<IpfsNode>>,
+    (caller, trace): (Caller, TraceContext),
+    id: web::Path<FileId>,
+    codec: Codec,
+) -> impl Responder {
+    let Some(ipfs) = ipfs.as_ref() else {
+        return HttpResponse::NotImplemented().body("no ipfs node configured");
+    };
+    let id = *id.deref();
+    let (file, leaf_hasher) = {
+        let storage = storage.lock().expect("should lock");
+        let file = storage
+            .check_owner(&caller.name, id)
+            .and_then(|()| storage.get_file_by_id(id));
+        (file, storage.leaf_hasher().clone())
+    };
+    let (name, content) = match file {
+        Ok((name, content, _)) => (name, content),
+        Err(StorageError::NotFound) => return file_not_found(&storage, id),
+        Err(err) => return storage_error(err),
+    };
+    let hash = leaf_hasher.leaf(&name, &content);
+    let cid = match ipfs.pin(&name, content, &trace).await {
+        Ok(cid) => cid,
+        Err(err) => return HttpResponse::BadGateway().body(err.to_string()),
+    };
+    let recorded =
+        storage
+            .lock()
+            .expect("should lock")
+            .record_ipfs_cid_as(&caller.name, id, cid.clone());
+    match recorded {
+        Ok(()) => codec.respond(HttpResponse::Ok(), IpfsExport { id, cid, hash }),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Proof of file against root of its epoch tree as it was at given size, so files can be
+/// verified against roots pinned long ago
+#[get("/files/{id}/proof")]
+pub async fn get_historical_proof(
+    storage: web::Data<Mutex<Storage>>,
+    id: web::Path<FileId>,
+    query: web::Query<HistoricalProofQuery>,
+    codec: Codec,
+) -> impl Responder {
+    let id = *id.deref();
+    let tree_size = query.tree_size;
+    let proof = storage
+        .lock()
+        .expect("should lock")
+        .historical_proof(id, tree_size);
+    match proof {
+        Ok((leaf_index, proof)) => codec.respond(
+            HttpResponse::Ok(),
+            HistoricalProof {
+                id,
+                epoch: proof.epoch,
+                leaf_index,
+                tree_size,
+                proof: proof.proof,
+                root: proof.root,
+            },
+        ),
+        Err(StorageError::NotFound) => {
+            HttpResponse::NotFound().body(format!("file {id} is not in tree of {tree_size} leaves"))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+#[get("/files/{id}/receipt")]
+pub async fn get_deletion_receipt(
+    storage: web::Data<Mutex<Storage>>,
+    id: web::Path<FileId>,
+    codec: Codec,
+) -> impl Responder {
+    let id = *id.deref();
+    let receipt = storage.lock().expect("should lock").deletion_receipt(id);
+    match receipt {
+        Ok(Some(receipt)) => codec.respond(HttpResponse::Ok(), receipt),
+        Ok(None) => HttpResponse::NotFound().body(format!("no deletion receipt for file {}", id)),
+        Err(err) => storage_error(err),
+    }
+}
+
+#[get("/usage")]
+pub async fn get_usage(
+    storage: web::Data<Mutex<Storage>>,
+    caller: Caller,
+    codec: Codec,
+) -> impl Responder {
+    let usage = storage.lock().expect("should lock").usage_of(&caller.name);
+    codec.respond(HttpResponse::Ok(), usage_of(caller.name, usage))
+}
+
+#[get("/admin/usage")]
+pub async fn get_all_usage(
+    storage: web::Data<Mutex<Storage>>,
+    caller: Caller,
+    codec: Codec,
+) -> impl Responder {
+    if !caller.admin {
+        return HttpResponse::Forbidden().body("admin api key required");
+    }
+    let usage = storage
+        .lock()
+        .expect("should lock")
+        .all_usage()
+        .into_iter()
+        .map(|(name, usage)| usage_of(name, usage))
+        .collect();
+    codec.respond(HttpResponse::Ok(), UsageList { usage })
+}
+
+/// Progress of secondary indexes rebuilt in background since startup
+#[get("/admin/migrations")]
+pub async fn get_migrations(
+    storage: web::Data<Mutex<Storage>>,
+    caller: Caller,
+    codec: Codec,
+) -> impl Responder {
+    if !caller.admin {
+        return HttpResponse::Forbidden().body("admin api key required");
+    }
+    let migrations = storage.lock().expect("should lock").migrations();
+    codec.respond(HttpResponse::Ok(), MigrationList { migrations })
+}
+
+/// Text files containing the query, with leaves to verify them by. Index catches up with
+/// storage under its lock, so searches wait for files stored since the last one to be indexed.
+#[get("/search")]
+pub async fn search_files(
+    storage: web::Data<Mutex<Storage>>,
+    index: web::Data<Option<Mutex<SearchIndex>>>,
+    query: web::Query<SearchQuery>,
+    codec: Codec,
+) -> impl Responder {
+    let Some(index) = index.as_ref() else {
+        return HttpResponse::NotImplemented().body("search is not enabled");
+    };
+    if query.q.is_empty() {
+        return HttpResponse::BadRequest().body("query must not be empty");
+    }
+    let storage = storage.lock().expect("should lock");
+    let mut index = index.lock().expect("should lock");
+    let hits = index.update(&storage, SystemTime::now()).and_then(|()| {
+        index
+            .search(&query.q, page_limit(query.limit))
+            .into_iter()
+            .map(|(id, matches)| {
+                Ok(SearchHit {
+                    id,
+                    name: storage.describe(id)?.name,
+                    leaf: storage.listed_leaf(id)?,
+                    matches,
+                })
+            })
+            .collect::<Result<Vec<_>, StorageError>>()
+    });
+    match hits {
+        Ok(hits) => codec.respond(HttpResponse::Ok(), SearchResults { hits }),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Records signed root of a peer server, answering with the earlier root it conflicts with if any.
+/// Api key is required if server has any.
+#[post("/attestations")]
+pub async fn submit_attestation(
+    attestations: web::Data<Option<Mutex<Attestations>>>,
+    _caller: Caller,
+    root: Decoded<SignedRoot>,
+    codec: Codec,
+) -> impl Responder {
+    let Some(attestations) = attestations.as_ref() else {
+        return HttpResponse::NotImplemented().body("federation is not enabled");
+    };
+    let submitted = attestations.lock().expect("should lock").submit(root.0);
+    match submitted {
+        Ok(submitted) => codec.respond(HttpResponse::Ok(), submitted),
+        Err(err @ AttestationError::InvalidSignature) => {
+            HttpResponse::UnprocessableEntity().body(err.to_string())
+        }
+        Err(err @ AttestationError::TooManyKeys) => {
+            HttpResponse::InsufficientStorage().body(err.to_string())
+        }
+    }
+}
+
+/// Roots peers submitted and equivocations among them, so anyone can spot a server which showed
+/// different trees to different peers
+#[get("/attestations")]
+pub async fn get_attestations(
+    attestations: web::Data<Option<Mutex<Attestations>>>,
+    query: web::Query<AttestationQuery>,
+    codec: Codec,
+) -> impl Responder {
+    let Some(attestations) = attestations.as_ref() else {
+        return HttpResponse::NotImplemented().body("federation is not enabled");
+    };
+    let list = attestations
+        .lock()
+        .expect("should lock")
+        .list(query.public_key.as_deref());
+    codec.respond(HttpResponse::Ok(), list)
+}
+
+fn usage_of(name: String, usage: storage::Usage) -> Usage {
+    Usage {
+        name,
+        bytes: usage.bytes,
+        files: usage.files,
+    }
+}
+
+/// Server version, how it derives leaves, its features and limits, so clients can compute
+/// matching roots and refuse servers they can't verify
+#[get("/info")]
+pub async fn get_info(info: web::Data<ServerInfo>, codec: Codec) -> impl Responder {
+    codec.respond(HttpResponse::Ok(), info.get_ref())
+}
+
+#[get("/stats")]
+pub async fn get_stats(
+    storage: web::Data<Mutex<Storage>>,
+    hash_pool: web::Data<HashPool>,
+    lag_probe: web::Data<LagProbe>,
+    codec: Codec,
+) -> impl Responder {
+    let waiting = Instant::now();
+    let storage = storage.lock().expect("should lock");
+    let storage_lock_wait = waiting.elapsed();
+    let capacity = storage.blob_capacity();
+    let downloads = storage.downloads();
+    let files = match storage.file_count() {
+        Ok(files) => files,
+        Err(err) => return storage_error(err),
+    };
+    let tree = storage.tree_stats();
+    drop(storage);
+    let (poll_lag, max_poll_lag) = lag_probe.take();
+    codec.respond(
+        HttpResponse::Ok(),
+        Stats {
+            hash_threads: hash_pool.threads(),
+            hash_queue_depth: hash_pool.queue_depth(),
+            blobs: capacity.map(|capacity| BlobUsage {
+                used_bytes: capacity.used,
+                max_bytes: capacity.max,
+            }),
+            runtime: RuntimeStats {
+                poll_lag_micros: poll_lag.as_micros() as u64,
+                max_poll_lag_micros: max_poll_lag.as_micros() as u64,
+                storage_lock_wait_micros: storage_lock_wait.as_micros() as u64,
+            },
+            downloads,
+            files,
+            tree,
+        },
+    )
+}
+
+/// Reports tamper-detection alarm, storage stays read-only once integrity check failed
+#[get("/healthz")]
+pub async fn get_health(storage: web::Data<Mutex<Storage>>, codec: Codec) -> impl Responder {
+    let divergence = storage.lock().expect("should lock").quarantine().cloned();
+    match divergence {
+        Some(divergence) => codec.respond(
+            HttpResponse::ServiceUnavailable(),
+            Health {
+                status: HealthStatus::Quarantined,
+                divergence: Some(divergence),
+            },
+        ),
+        None => codec.respond(
+            HttpResponse::Ok(),
+            Health {
+                status: HealthStatus::Ok,
+                divergence: None,
+            },
+        ),
+    }
+}
+
+#[get("/leaves")]
+pub async fn get_leaves(
+    storage: web::Data<Mutex<Storage>>,
+    query: web::Query<PageQuery>,
+    codec: Codec,
+) -> impl Responder {
+    let leaves = {
+        let storage = storage.lock().expect("should lock");
+        match (query.limit, query.cursor) {
+            (None, None) => storage.leaves().map(|leaves| (leaves, None)),
+            (limit, cursor) => storage.leaves_page(cursor, page_limit(limit)),
+        }
+    };
+    match leaves {
+        Ok((leaves, next_cursor)) => codec.respond(
+            HttpResponse::Ok(),
+            LeafList {
+                leaves,
+                next_cursor,
+            },
+        ),
+        Err(err) => storage_error(err),
+    }
+}
+
+#[get("/datasets")]
+pub async fn get_datasets(storage: web::Data<Mutex<Storage>>, codec: Codec) -> impl Responder {
+    match storage.lock().expect("should lock").datasets() {
+        Ok(datasets) => codec.respond(HttpResponse::Ok(), DatasetList { datasets }),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Dataset root with proof of the latest dataset leaf against the main tree
+#[get("/datasets/{name}")]
+pub async fn get_dataset(
+    storage: web::Data<Mutex<Storage>>,
+    name: web::Path<String>,
+    codec: Codec,
+) -> impl Responder {
+    match storage.lock().expect("should lock").dataset(&name) {
+        Ok(Some(dataset)) => codec.respond(HttpResponse::Ok(), dataset),
+        Ok(None) => HttpResponse::NotFound().body(format!("dataset {name} not found")),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Proof of file against dataset root together with the dataset proof, see
+/// [`crate::api::DatasetProof`]
+#[get("/datasets/{name}/proofs/{id}")]
+pub async fn get_dataset_proof(
+    storage: web::Data<Mutex<Storage>>,
+    path: web::Path<(String, FileId)>,
+    codec: Codec,
+) -> impl Responder {
+    let (name, id) = path.into_inner();
+    match storage
+        .lock()
+        .expect("should lock")
+        .dataset_proof(&name, id)
+    {
+        Ok(Some(proof)) => codec.respond(HttpResponse::Ok(), proof),
+        Ok(None) => HttpResponse::NotFound().body(format!("file {id} is not in dataset {name}")),
+        Err(err) => storage_error(err),
+    }
+}
+
+#[get("/epochs")]
+pub async fn get_epochs(storage: web::Data<Mutex<Storage>>, codec: Codec) -> impl Responder {
+    let storage = storage.lock().expect("should lock");
+    codec.respond(
+        HttpResponse::Ok(),
+        CheckpointList {
+            checkpoints: storage.checkpoints().to_vec(),
+            current_epoch: storage.current_epoch(),
+            public_key: storage.public_key(),
+        },
+    )
+}
+
+#[get("/epochs/{epoch}/archive")]
+pub async fn get_epoch_archive(
+    storage: web::Data<Mutex<Storage>>,
+    epoch: web::Path<u32>,
+    codec: Codec,
+) -> impl Responder {
+    let archive = storage.lock().expect("should lock").export_epoch(*epoch);
+    match archive {
+        Ok(archive) => codec.respond(HttpResponse::Ok(), archive),
+        Err(StorageError::NotFound) => HttpResponse::NotFound().body("epoch is not sealed"),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Keys checkpoints are or were signed with, active one first
+#[get("/keys")]
+pub async fn get_keys(storage: web::Data<Mutex<Storage>>, codec: Codec) -> impl Responder {
+    let keys = storage.lock().expect("should lock").public_keys();
+    codec.respond(HttpResponse::Ok(), KeyList { keys })
+}
+
+/// Makes key read from given file sign checkpoints sealed from now on, the previous one stays
+/// published. Contents of the file are never echoed back, it may be any file the server can read.
+#[post("/admin/keys/rotate")]
+pub async fn rotate_key(
+    storage: web::Data<Mutex<Storage>>,
+    caller: Caller,
+    rotation: Decoded<KeyRotation>,
+    codec: Codec,
+) -> impl Responder {
+    if !caller.admin {
+        return HttpResponse::Forbidden().body("admin api key required");
+    }
+    let key_file = &rotation.key_file;
+    let key = match tokio::fs::read_to_string(key_file).await {
+        Ok(seed) => seed.parse::<ServerKey>(),
+        Err(err) => {
+            return HttpResponse::BadRequest()
+                .body(format!("can't read {}: {err}", key_file.display()))
+        }
+    };
+    let key = match key {
+        Ok(key) => key,
+        Err(_) => {
+            return HttpResponse::BadRequest().body(format!(
+                "{} doesn't hold a hex encoded signing key",
+                key_file.display()
+            ))
+        }
+    };
+    let rotated = storage
+        .lock()
+        .expect("should lock")
+        .rotate_key(key, SystemTime::now());
+    match rotated {
+        Ok(public_key) => codec.respond(HttpResponse::Ok(), public_key),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Loads exported sealed epoch, e.g. to restore its contents from cold storage
+#[post("/admin/epochs/import")]
+pub async fn import_epoch(
+    storage: web::Data<Mutex<Storage>>,
+    caller: Caller,
+    archive: Decoded<EpochArchive>,
+    codec: Codec,
+) -> impl Responder {
+    if !caller.admin {
+        return HttpResponse::Forbidden().body("admin api key required");
+    }
+    let imported = storage.lock().expect("should lock").import_epoch(archive.0);
+    match imported {
+        Ok(imported) => codec.respond(HttpResponse::Ok(), imported),
+        Err(err) => storage_error(err),
     }
 }
 
 #[get("/root")]
-pub async fn get_tree_root(storage: web::Data<Mutex<Storage>>) -> impl Responder {
-    let maybe_root = storage.lock().expect("should lock").root_hash();
-    match maybe_root {
-        Some(hash) => HttpResponse::Ok().json(RootHash { hash }),
+pub async fn get_tree_root(storage: web::Data<Mutex<Storage>>, codec: Codec) -> impl Responder {
+    let storage = storage.lock().expect("should lock");
+    match storage.root_hash() {
+        Some(hash) => codec.respond(
+            HttpResponse::Ok(),
+            RootHash {
+                hash,
+                size: storage.epoch_size().into(),
+                epoch: storage.current_epoch(),
+            },
+        ),
         None => {
             HttpResponse::NotFound().body("root is not available yet - try uploading some files")
         }
     }
 }
+
+#[get("/consistency")]
+pub async fn get_consistency(
+    storage: web::Data<Mutex<Storage>>,
+    query: web::Query<ConsistencyQuery>,
+    codec: Codec,
+) -> impl Responder {
+    let maybe_proof = storage.lock().expect("should lock").consistency_proof(
+        query.epoch,
+        query.old_size,
+        query.new_size,
+    );
+    match maybe_proof {
+        Ok(Some((proof, root))) => codec.respond(HttpResponse::Ok(), Consistency { proof, root }),
+        Ok(None) => HttpResponse::NotFound().body("no consistency proof for requested tree sizes"),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Proof for verifiers holding only a digest that server has stored content with it
+#[get("/proofs/by-hash/{hash}")]
+pub async fn get_proof_by_hash(
+    storage: web::Data<Mutex<Storage>>,
+    hash: web::Path<String>,
+    codec: Codec,
+) -> impl Responder {
+    let hash = match hash.parse::<Sha3Hash>() {
+        Ok(hash) => hash,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let found = storage.lock().expect("should lock").proof_by_hash(&hash);
+    match found {
+        Ok((leaf_index, proof)) => codec.respond(
+            HttpResponse::Ok(),
+            HashProof {
+                leaf_index,
+                proof: proof.proof,
+                epoch: proof.epoch,
+                root: proof.root,
+            },
+        ),
+        Err(StorageError::NotFound) => HttpResponse::NotFound().body("no leaf with given hash"),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Applies ttl of just stored file and describes it with name and version it was actually stored
+/// with, which may differ from requested name. TTL too big to be represented means no expiry.
+fn stored_file(
+    storage: &mut Storage,
+    id: FileId,
+    ttl_secs: Option<u64>,
+) -> Result<File, StorageError> {
+    let expires_at =
+        ttl_secs.and_then(|secs| SystemTime::now().checked_add(Duration::from_secs(secs)));
+    if let Some(expires_at) = expires_at {
+        storage.expire_at(id, expires_at)?;
+    }
+    storage.describe(id)
+}
+
+/// Pages continued without a limit are as long as they can be
+fn page_limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(MAX_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT)
+}
+
+/// Uploads into a dataset are refused before the file is stored if it couldn't join it
+fn check_dataset(storage: &Storage, dataset: Option<&str>) -> Result<(), StorageError> {
+    dataset.map_or(Ok(()), |dataset| storage.check_dataset(dataset))
+}
+
+fn join_dataset(
+    storage: &mut Storage,
+    id: FileId,
+    dataset: Option<&str>,
+) -> Result<(), StorageError> {
+    dataset.map_or(Ok(()), |dataset| storage.join_dataset(id, dataset))
+}
+
+fn storage_error(err: StorageError) -> HttpResponse {
+    match err {
+        StorageError::NotFound => HttpResponse::NotFound().body(err.to_string()),
+        StorageError::QuotaExceeded(_) => HttpResponse::TooManyRequests().body(err.to_string()),
+        StorageError::Conflict(_) | StorageError::NameTaken(_) => {
+            HttpResponse::Conflict().body(err.to_string())
+        }
+        StorageError::InvalidName(_) => HttpResponse::BadRequest().body(err.to_string()),
+        StorageError::Backend(_) => HttpResponse::InternalServerError().body(err.to_string()),
+        StorageError::Quarantined => HttpResponse::ServiceUnavailable().body(err.to_string()),
+        StorageError::InvalidArchive(_) => {
+            HttpResponse::UnprocessableEntity().body(err.to_string())
+        }
+        StorageError::OutOfSpace(..) | StorageError::TreeFull(_) => {
+            HttpResponse::InsufficientStorage().body(err.to_string())
+        }
+        StorageError::NotOwner(_) => HttpResponse::Forbidden().body(err.to_string()),
+        StorageError::BatchTooLarge(_) => HttpResponse::PayloadTooLarge().body(err.to_string()),
+    }
+}
+
+fn import_error(err: ImportError) -> HttpResponse {
+    match err {
+        ImportError::InvalidUrl(_) | ImportError::SchemeNotAllowed(_) => {
+            HttpResponse::BadRequest().body(err.to_string())
+        }
+        ImportError::AddressNotAllowed(_) => HttpResponse::Forbidden().body(err.to_string()),
+        ImportError::TooLarge(_) => HttpResponse::PayloadTooLarge().body(err.to_string()),
+        ImportError::Fetch(_) => HttpResponse::BadGateway().body(err.to_string()),
+    }
+}
+
+fn file_not_found(storage: &Mutex<Storage>, id: FileId) -> HttpResponse {
+    let deleted = storage
+        .lock()
+        .expect("should lock")
+        .deletion_receipt(id)
+        .is_ok_and(|receipt| receipt.is_some());
+    if deleted {
+        HttpResponse::Gone().body(format!("file {} was deleted", id))
+    } else {
+        HttpResponse::NotFound().body(format!("file {} not found", id))
+    }
+}
+
+/// Splits response body into chunks which are sent no faster than given rate limit allows
+fn throttled(
+    body: Vec<u8>,
+    limit: RateLimit,
+) -> impl Stream<Item = Result<Bytes, Infallible>> + 'static {
+    let chunk_size = limit.chunk_size();
+    let state = (Bytes::from(body), Instant::now(), 0usize);
+    stream::unfold(state, move |(mut rest, started, sent)| async move {
+        if rest.is_empty() {
+            return None;
+        }
+        let chunk = rest.split_to(chunk_size.min(rest.len()));
+        let sent = sent + chunk.len();
+        limit.pace(sent, started).await;
+        Some((Ok(chunk), (rest, started, sent)))
+    })
+}
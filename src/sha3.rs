@@ -1,59 +1,179 @@
+use anyhow::anyhow;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use sha3::digest::{FixedOutput, Output};
-use sha3::{Digest, Sha3_256};
+use sha3::digest::FixedOutput;
+use sha3::{Sha3_256, Sha3_512};
 use std::fmt::{Debug, Display, Formatter};
+use std::marker::PhantomData;
 use std::str;
 use std::str::FromStr;
 
-#[derive(Clone, PartialEq)]
-pub struct Hash(Output<Sha3_256>);
+/// Hash function producing digests of `N` bytes, which [`Digest`], [`DigestHasher`] and trees
+/// of its digests are generic over
+pub trait Algorithm<const N: usize>: Default {
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns digest of everything fed so far and resets the state
+    fn finalize_reset(&mut self) -> [u8; N];
+}
+
+impl Algorithm<32> for Sha3_256 {
+    fn update(&mut self, data: &[u8]) {
+        sha3::Digest::update(self, data);
+    }
+
+    fn finalize_reset(&mut self) -> [u8; 32] {
+        to_array(&std::mem::take(self).finalize_fixed())
+    }
+}
+
+impl Algorithm<64> for Sha3_512 {
+    fn update(&mut self, data: &[u8]) {
+        sha3::Digest::update(self, data);
+    }
+
+    fn finalize_reset(&mut self) -> [u8; 64] {
+        to_array(&std::mem::take(self).finalize_fixed())
+    }
+}
+
+fn to_array<const N: usize>(output: &[u8]) -> [u8; N] {
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(output);
+    bytes
+}
+
+/// Digest of `N` bytes produced by algorithm `A`, hex encoded when displayed or serialized.
+/// Digests of different algorithms are different types even when their sizes match.
+pub struct Digest<A, const N: usize>([u8; N], PhantomData<fn() -> A>);
+
+/// SHA3-256 digest, the one leaves and trees are built of
+pub type Hash = Digest<Sha3_256, 32>;
+
+pub type Sha3_512Hash = Digest<Sha3_512, 64>;
+
+impl<A, const N: usize> Digest<A, N> {
+    pub fn from_bytes(bytes: [u8; N]) -> Self {
+        Self(bytes, PhantomData)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<A, const N: usize> Clone for Digest<A, N> {
+    fn clone(&self) -> Self {
+        Self::from_bytes(self.0)
+    }
+}
+
+impl<A, const N: usize> PartialEq for Digest<A, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<A, const N: usize> Eq for Digest<A, N> {}
+
+impl<A, const N: usize> std::hash::Hash for Digest<A, N> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
 
 pub fn hash_content(content: impl AsRef<[u8]>) -> Hash {
-    Hash(Sha3_256::digest(content))
+    digest_of(content)
+}
+
+/// Same as [`hash_content`] with any algorithm
+pub fn digest_of<A: Algorithm<N>, const N: usize>(content: impl AsRef<[u8]>) -> Digest<A, N> {
+    DigestHasher::new().update(content.as_ref()).finalize()
 }
 
-pub fn hash_both(hash1: &Hash, hash2: &Hash) -> Hash {
-    let mut hasher = Sha3_256::new();
-    hasher.update(hash1.0.as_slice());
-    hasher.update(hash2.0.as_slice());
-    Hash(hasher.finalize_fixed())
+pub fn hash_both<A: Algorithm<N>, const N: usize>(
+    hash1: &Digest<A, N>,
+    hash2: &Digest<A, N>,
+) -> Digest<A, N> {
+    hash_many(&[hash1, hash2])
 }
 
-impl FromStr for Hash {
+/// Hash of given hashes joined in order
+pub fn hash_many<A: Algorithm<N>, const N: usize>(hashes: &[&Digest<A, N>]) -> Digest<A, N> {
+    let mut hasher = DigestHasher::new();
+    for hash in hashes {
+        hasher.update(&hash.0);
+    }
+    hasher.finalize()
+}
+
+/// Hashes content fed in parts, e.g. name and content of a leaf or chunks streamed to disk,
+/// without joining them into one buffer. Result is the same as [`hash_content`] of all parts
+/// joined.
+pub type Hasher = DigestHasher<Sha3_256, 32>;
+
+/// [`Hasher`] of any algorithm
+#[derive(Default)]
+pub struct DigestHasher<A, const N: usize> {
+    hasher: A,
+}
+
+impl<A: Algorithm<N>, const N: usize> DigestHasher<A, N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> &mut Self {
+        self.hasher.update(data);
+        self
+    }
+
+    /// Returns hash of everything fed so far and resets hasher
+    pub fn finalize(&mut self) -> Digest<A, N> {
+        Digest::from_bytes(self.hasher.finalize_reset())
+    }
+}
+
+/// Leaf hash appended to the tree when file with given leaf hash is deleted
+pub fn tombstone_of(hash: &Hash) -> Hash {
+    hash_both(&hash_content(b"tombstone"), hash)
+}
+
+impl<A, const N: usize> FromStr for Digest<A, N> {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let bytes = hex::decode(s)?;
-
-        let output = Output::<Sha3_256>::clone_from_slice(&bytes);
-        Ok(Hash(output))
+        let bytes = bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| anyhow!("hash has {} bytes, {N} expected", bytes.len()))?;
+        Ok(Self::from_bytes(bytes))
     }
 }
 
-impl Display for Hash {
+impl<A, const N: usize> Display for Digest<A, N> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let str = hex::encode(self.0);
         f.serialize_str(&str)
     }
 }
 
-impl Debug for Hash {
+impl<A, const N: usize> Debug for Digest<A, N> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.serialize_str(&self.to_string())
     }
 }
 
-impl<'de> Deserialize<'de> for Hash {
+impl<'de, A, const N: usize> Deserialize<'de> for Digest<A, N> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         let str = String::deserialize(deserializer).map_err(serde::de::Error::custom)?;
-        Hash::from_str(&str).map_err(serde::de::Error::custom)
+        Digest::from_str(&str).map_err(serde::de::Error::custom)
     }
 }
 
-impl Serialize for Hash {
+impl<A, const N: usize> Serialize for Digest<A, N> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
@@ -75,6 +195,59 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_hasher() {
+        let mut hasher = Hasher::new();
+        for chunk in [&b"first "[..], b"", b"second"] {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finalize(), hash_content(b"first second"));
+        assert_eq!(
+            Hasher::new().update(b"12").update(b"3").finalize(),
+            hash_content(b"123")
+        );
+    }
+
+    #[test]
+    fn test_hash_many() {
+        let (first, second) = (hash_content(b"1"), hash_content(b"2"));
+        assert_eq!(hash_many(&[&first, &second]), hash_both(&first, &second));
+        assert_eq!(hash_many(&[]), hash_content(b""));
+        let joined = [first.0.as_slice(), second.0.as_slice()].concat();
+        assert_eq!(hash_many(&[&first, &second]), hash_content(joined));
+    }
+
+    #[test]
+    fn test_sha3_512() {
+        let empty: Sha3_512Hash = digest_of(b"");
+        assert_eq!(
+            empty.to_string(),
+            "a69f73cca23a9ac5c8b567dc185a756e97c982164fe25859e0d1dcc1475c80a6\
+             15b2123af1f5f94c11e3e9402c3ac558f500199d95b6d3e301758586281dcd26"
+        );
+        assert_eq!(
+            empty
+                .to_string()
+                .parse::<Sha3_512Hash>()
+                .expect("should parse"),
+            empty
+        );
+
+        let mut tree = crate::merkle::Tree::new();
+        let leaves: Vec<Sha3_512Hash> = vec![digest_of(b"1"), digest_of(b"2")];
+        for leaf in &leaves {
+            tree.append(leaf.clone());
+        }
+        assert_eq!(tree.root(), Some(hash_both(&leaves[0], &leaves[1])));
+    }
+
+    #[test]
+    fn test_parse_wrong_size() {
+        let hex = hash_content(b"123").to_string();
+        assert!(hex.parse::<Sha3_512Hash>().is_err());
+        assert!(hex[..62].parse::<Hash>().is_err());
+    }
+
     #[test]
     fn test_parse() {
         let parsed_hash =
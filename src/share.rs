@@ -0,0 +1,110 @@
+use crate::api::{FileId, TreeSize};
+use crate::encryption::FileKey;
+use crate::merkle::Sha3Hash;
+use anyhow::anyhow;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Link to a single file, which recipient downloads and verifies against root pinned by whoever
+/// shared it, e.g. `http://localhost:8080/files/7#size=12&root=HEX&key=HEX`. Root and key of
+/// encrypted content are in the fragment, which is never sent to the server.
+#[derive(Debug, Clone)]
+pub struct ShareLink {
+    pub server_url: String,
+    pub id: FileId,
+    /// size of the tree `root` is of
+    pub tree_size: TreeSize,
+    pub root: Sha3Hash,
+    /// key content is decrypted with, none for contents uploaded in plain
+    pub key: Option<FileKey>,
+}
+
+impl Display for ShareLink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/files/{}#size={}&root={}",
+            self.server_url.trim_end_matches('/'),
+            self.id,
+            self.tree_size,
+            self.root
+        )?;
+        match &self.key {
+            Some(key) => write!(f, "&key={key}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for ShareLink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (url, fragment) = s
+            .split_once('#')
+            .ok_or_else(|| anyhow!("share link has no root to verify against"))?;
+        let (server_url, id) = url
+            .rsplit_once("/files/")
+            .ok_or_else(|| anyhow!("share link doesn't point to a file"))?;
+        let (mut tree_size, mut root, mut key) = (None, None, None);
+        for part in fragment.split('&').filter(|part| !part.is_empty()) {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected NAME=VALUE, got {part}"))?;
+            match name {
+                "size" => tree_size = Some(value.parse()?),
+                "root" => root = Some(value.parse()?),
+                "key" => key = Some(value.parse()?),
+                _ => return Err(anyhow!("unknown share link parameter {name}")),
+            }
+        }
+        Ok(ShareLink {
+            server_url: server_url.to_string(),
+            id: id.parse()?,
+            tree_size: tree_size.ok_or_else(|| anyhow!("share link has no tree size"))?,
+            root: root.ok_or_else(|| anyhow!("share link has no root"))?,
+            key,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encryption::MasterKey;
+    use crate::sha3::hash_content;
+
+    #[test]
+    fn test_share_link_round_trip() {
+        let key: MasterKey = "01".repeat(32).parse().unwrap();
+        let sealed = key.encrypt(b"secret", b"a.txt").unwrap();
+        let link = ShareLink {
+            server_url: "http://localhost:8080/".to_string(),
+            id: FileId(7),
+            tree_size: TreeSize(12),
+            root: hash_content(b"root"),
+            key: Some(key.file_key(&sealed, b"a.txt")),
+        };
+        let text = link.to_string();
+        assert!(text.starts_with("http://localhost:8080/files/7#size=12&root="));
+        let parsed: ShareLink = text.parse().unwrap();
+        assert_eq!(parsed.server_url, "http://localhost:8080");
+        assert_eq!((parsed.id, parsed.tree_size), (FileId(7), TreeSize(12)));
+        assert_eq!(parsed.root, link.root);
+        assert_eq!(
+            parsed.key.unwrap().decrypt(&sealed, b"a.txt").unwrap(),
+            b"secret"
+        );
+
+        let plain = ShareLink { key: None, ..link }.to_string();
+        assert!(plain.parse::<ShareLink>().unwrap().key.is_none());
+        for invalid in [
+            "http://localhost:8080/files/7",
+            "http://localhost:8080/files/7#size=12",
+            "http://localhost:8080/7#size=12&root=00",
+            "http://localhost:8080/files/x#size=12&root=00",
+        ] {
+            assert!(invalid.parse::<ShareLink>().is_err(), "{invalid}");
+        }
+    }
+}
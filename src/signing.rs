@@ -0,0 +1,280 @@
+use crate::api::{Checkpoint, PublicKey, UploadReceipt};
+use anyhow::anyhow;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::str::FromStr;
+
+/// Ed25519 key server signs checkpoints of sealed epochs with
+pub struct ServerKey {
+    key: SigningKey,
+}
+
+/// Parsed from hex encoded 32 byte secret seed
+impl FromStr for ServerKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let seed: [u8; 32] = hex::decode(s.trim())?
+            .try_into()
+            .map_err(|_| anyhow!("signing key must be 32 bytes long"))?;
+        Ok(Self {
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+}
+
+impl ServerKey {
+    /// Hex encoded public key clients verify signatures with
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().to_bytes())
+    }
+
+    /// Short id checkpoints name the key they are signed with by
+    pub fn key_id(&self) -> String {
+        key_id(&self.public_key())
+    }
+
+    /// Hex encoded signature of given message
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex::encode(self.key.sign(message).to_bytes())
+    }
+}
+
+/// First 8 bytes of hex encoded public key
+pub fn key_id(public_key: &str) -> String {
+    public_key.chars().take(16).collect()
+}
+
+/// Keys server has signed checkpoints with, the last one signs new checkpoints. Retired keys stay
+/// published, so checkpoints sealed before rotation keep verifying.
+#[derive(Default)]
+pub struct Keyring {
+    keys: Vec<ServerKey>,
+    published: Vec<PublicKey>,
+}
+
+impl Keyring {
+    /// Keys in order they were used in, the last one is active
+    pub fn new(keys: Vec<ServerKey>) -> Self {
+        let last = keys.len().saturating_sub(1);
+        let published = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| PublicKey {
+                key_id: key.key_id(),
+                public_key: key.public_key(),
+                active: i == last,
+                active_from: None,
+                retired_at: None,
+            })
+            .collect();
+        Self { keys, published }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Key new checkpoints are signed with
+    pub fn active(&self) -> Option<&ServerKey> {
+        self.keys.last()
+    }
+
+    pub fn public_keys(&self) -> &[PublicKey] {
+        &self.published
+    }
+
+    /// Makes `key` sign checkpoints from `now` (unix time in seconds) on and retires the active one
+    pub fn rotate(&mut self, key: ServerKey, now: u64) -> anyhow::Result<PublicKey> {
+        let public_key = key.public_key();
+        if self.published.iter().any(|p| p.public_key == public_key) {
+            return Err(anyhow!("key {} was already used", key.key_id()));
+        }
+        if let Some(retired) = self.published.last_mut() {
+            retired.active = false;
+            retired.retired_at = Some(now);
+        }
+        let published = PublicKey {
+            key_id: key.key_id(),
+            public_key,
+            active: true,
+            active_from: Some(now),
+            retired_at: None,
+        };
+        self.keys.push(key);
+        self.published.push(published.clone());
+        Ok(published)
+    }
+
+    /// Checkpoint is signed with any key of the ring
+    pub fn verifies(&self, checkpoint: &Checkpoint) -> bool {
+        self.published
+            .iter()
+            .any(|key| checkpoint.verify(&key.public_key))
+    }
+}
+
+/// Server key client accepts checkpoints sealed within its validity window signed with
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrustedKey {
+    pub public_key: String,
+    /// unix time in seconds
+    pub valid_from: Option<u64>,
+    /// unix time in seconds
+    pub valid_until: Option<u64>,
+}
+
+/// Parsed from hex encoded public key, optionally followed by `@FROM..UNTIL` window of unix times,
+/// either end of it may be left open
+impl FromStr for TrustedKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (public_key, window) = match s.split_once('@') {
+            Some((public_key, window)) => (public_key, Some(window)),
+            None => (s, None),
+        };
+        if !matches!(hex::decode(public_key), Ok(key) if key.len() == 32) {
+            return Err(anyhow!("public key must be 32 hex encoded bytes"));
+        }
+        let bound = |bound: &str| match bound {
+            "" => Ok(None),
+            bound => bound.parse().map(Some),
+        };
+        let (valid_from, valid_until) = match window {
+            Some(window) => {
+                let (from, until) = window
+                    .split_once("..")
+                    .ok_or_else(|| anyhow!("validity window must be given as FROM..UNTIL"))?;
+                (bound(from)?, bound(until)?)
+            }
+            None => (None, None),
+        };
+        Ok(Self {
+            public_key: public_key.to_string(),
+            valid_from,
+            valid_until,
+        })
+    }
+}
+
+impl TrustedKey {
+    /// Checkpoint is signed with this key and sealed within its validity window. Checkpoints
+    /// naming another key are refused without checking the signature.
+    pub fn accepts(&self, checkpoint: &Checkpoint) -> bool {
+        self.covers(checkpoint.key_id.as_deref(), checkpoint.sealed_at)
+            && checkpoint.verify(&self.public_key)
+    }
+
+    /// Upload receipt is signed with this key and issued within its validity window
+    pub fn accepts_receipt(&self, receipt: &UploadReceipt) -> bool {
+        self.covers(Some(&receipt.key_id), receipt.issued_at) && receipt.verify(&self.public_key)
+    }
+
+    /// Signature naming given key id, if any, made at unix time `at` may be of this key
+    fn covers(&self, signed_with: Option<&str>, at: u64) -> bool {
+        signed_with.is_none_or(|id| id == key_id(&self.public_key))
+            && self.valid_from.is_none_or(|from| at >= from)
+            && self.valid_until.is_none_or(|until| at < until)
+    }
+}
+
+/// Checks hex encoded signature of message against hex encoded public key, malformed key or
+/// signature never verifies
+pub fn verify(public_key: &str, message: &[u8], signature: &str) -> bool {
+    let Some(public_key) = hex::decode(public_key)
+        .ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+    else {
+        return false;
+    };
+    let Some(signature) = hex::decode(signature)
+        .ok()
+        .and_then(|signature| <[u8; 64]>::try_from(signature).ok())
+    else {
+        return false;
+    };
+    public_key
+        .verify(message, &Signature::from_bytes(&signature))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key: ServerKey = "0101010101010101010101010101010101010101010101010101010101010101"
+            .parse()
+            .expect("should parse");
+        let signature = key.sign(b"checkpoint");
+        assert!(verify(&key.public_key(), b"checkpoint", &signature));
+        assert!(!verify(&key.public_key(), b"tampered", &signature));
+        assert!(!verify("not hex", b"checkpoint", &signature));
+        assert!("0101".parse::<ServerKey>().is_err());
+    }
+
+    fn signed(key: &ServerKey, sealed_at: u64) -> Checkpoint {
+        let mut checkpoint = Checkpoint {
+            epoch: 0,
+            first_leaf: 0,
+            size: 1,
+            root: crate::sha3::hash_content(b"leaf"),
+            sealed_at,
+            key_id: Some(key.key_id()),
+            signature: None,
+        };
+        checkpoint.signature = Some(key.sign(&checkpoint.signed_message()));
+        checkpoint
+    }
+
+    #[test]
+    fn test_rotate_keyring() {
+        let old: ServerKey = "01".repeat(32).parse().expect("should parse");
+        let new: ServerKey = "02".repeat(32).parse().expect("should parse");
+        let before = signed(&old, 100);
+        let after = signed(&new, 200);
+
+        let mut keyring = Keyring::new(vec![old]);
+        assert!(keyring.verifies(&before));
+        assert!(!keyring.verifies(&after));
+        let published = keyring
+            .rotate("02".repeat(32).parse().expect("should parse"), 150)
+            .expect("should rotate");
+        assert_eq!(published.key_id, new.key_id());
+        assert_eq!(keyring.active().map(ServerKey::key_id), Some(new.key_id()));
+        assert!(keyring.verifies(&before) && keyring.verifies(&after));
+        let retired = &keyring.public_keys()[0];
+        assert_eq!((retired.active, retired.retired_at), (false, Some(150)));
+        assert!(keyring.rotate(new, 300).is_err());
+    }
+
+    #[test]
+    fn test_trusted_key_window() {
+        let key: ServerKey = "01".repeat(32).parse().expect("should parse");
+        let trusted: TrustedKey = format!("{}@100..200", key.public_key())
+            .parse()
+            .expect("should parse");
+        assert_eq!(
+            (trusted.valid_from, trusted.valid_until),
+            (Some(100), Some(200))
+        );
+        assert!(trusted.accepts(&signed(&key, 100)));
+        assert!(!trusted.accepts(&signed(&key, 200)));
+        assert!(!trusted.accepts(&signed(&key, 99)));
+
+        let open: TrustedKey = format!("{}@150..", key.public_key())
+            .parse()
+            .expect("should parse");
+        assert!(open.accepts(&signed(&key, u64::MAX)));
+        let mut other_id = signed(&key, 150);
+        other_id.key_id = Some("0202020202020202".to_string());
+        assert!(!open.accepts(&other_id));
+
+        assert!("0101".parse::<TrustedKey>().is_err());
+        assert!(format!("{}@100", key.public_key())
+            .parse::<TrustedKey>()
+            .is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::merkle::FormatVersion;
+use crate::sha3::{hash_both, hash_content, hash_many, Hash};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Bits of a key path, which is the hash of the key, so the tree is this many layers deep
+pub const DEPTH: usize = 256;
+
+/// Sparse Merkle tree of key-value pairs, each leaf sitting at the path given by its key hash.
+/// Empty subtrees hash to zeros at any height, so only the paths of present keys are hashed and
+/// absence of a key is proven the same way as its presence.
+#[derive(Debug, Clone, Default)]
+pub struct SparseTree {
+    leaves: BTreeMap<[u8; 32], Hash>,
+}
+
+/// Proof of value stored under a key, or of the key not being stored, see [`SparseTree::proof`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SparseProof {
+    #[serde(default)]
+    version: FormatVersion,
+    /// siblings on the path which are not empty subtrees, from the leaf up
+    siblings: Vec<SparseSibling>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SparseSibling {
+    /// depth of the sibling, 1 below the root to [`DEPTH`] for the leaf layer
+    depth: u16,
+    hash: Hash,
+}
+
+fn empty() -> Hash {
+    Hash::from_bytes([0; 32])
+}
+
+fn path_of(key: &[u8]) -> [u8; 32] {
+    let mut path = [0; 32];
+    path.copy_from_slice(hash_content(key).as_bytes());
+    path
+}
+
+/// Bit at given depth decides whether path goes left or right below the node at that depth
+fn goes_right(path: &[u8; 32], depth: usize) -> bool {
+    path[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let empty = empty();
+    if *left == empty && *right == empty {
+        return empty;
+    }
+    hash_both(left, right)
+}
+
+/// Leaf commits to the key path as well, so a proof of one key can't be passed off as of another
+pub fn leaf_hash(key: &[u8], value: &[u8]) -> Hash {
+    hash_many(&[
+        &hash_content(b"safe-storage sparse leaf"),
+        &hash_content(key),
+        &hash_content(value),
+    ])
+}
+
+/// Hash of the subtree at given depth holding given leaves, all sharing the path above it
+fn subtree_hash(leaves: &[([u8; 32], Hash)], depth: usize) -> Hash {
+    match leaves {
+        [] => empty(),
+        [(_, leaf)] if depth == DEPTH => leaf.clone(),
+        _ => {
+            let split = leaves.partition_point(|(path, _)| !goes_right(path, depth));
+            node_hash(
+                &subtree_hash(&leaves[..split], depth + 1),
+                &subtree_hash(&leaves[split..], depth + 1),
+            )
+        }
+    }
+}
+
+impl SparseTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// Puts value under the key, replacing the previous one
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.leaves.insert(path_of(key), leaf_hash(key, value));
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        self.leaves.remove(&path_of(key)).is_some()
+    }
+
+    /// Root of an empty tree is all zeros. Computed from the leaves on every call, which hashes
+    /// each of them up through all [`DEPTH`] layers.
+    pub fn root(&self) -> Hash {
+        subtree_hash(&self.sorted_leaves(), 0)
+    }
+
+    /// Proof of whatever is stored under the key, including nothing
+    pub fn proof(&self, key: &[u8]) -> SparseProof {
+        let path = path_of(key);
+        let mut leaves = &self.sorted_leaves()[..];
+        let mut siblings = Vec::new();
+        for depth in 0..DEPTH {
+            let split = leaves.partition_point(|(path, _)| !goes_right(path, depth));
+            let (left, right) = leaves.split_at(split);
+            let (next, sibling) = match goes_right(&path, depth) {
+                true => (right, left),
+                false => (left, right),
+            };
+            let hash = subtree_hash(sibling, depth + 1);
+            if hash != empty() {
+                siblings.push(SparseSibling {
+                    depth: depth as u16 + 1,
+                    hash,
+                });
+            }
+            leaves = next;
+        }
+        siblings.reverse();
+        SparseProof {
+            version: FormatVersion::CURRENT,
+            siblings,
+        }
+    }
+
+    fn sorted_leaves(&self) -> Vec<([u8; 32], Hash)> {
+        self.leaves
+            .iter()
+            .map(|(path, leaf)| (*path, leaf.clone()))
+            .collect()
+    }
+}
+
+impl SparseProof {
+    /// Format version proof was read with
+    pub fn version(&self) -> FormatVersion {
+        self.version
+    }
+
+    /// Checks that the key holds given value in the tree with given root, `None` checks that the
+    /// key is not there
+    pub fn verify(&self, root: &Hash, key: &[u8], value: Option<&[u8]>) -> bool {
+        let path = path_of(key);
+        let mut hash = match value {
+            Some(value) => leaf_hash(key, value),
+            None => empty(),
+        };
+        let mut siblings = self.siblings.iter().peekable();
+        for depth in (1..=DEPTH).rev() {
+            let sibling = match siblings.next_if(|sibling| sibling.depth as usize == depth) {
+                Some(sibling) => sibling.hash.clone(),
+                None => empty(),
+            };
+            hash = match goes_right(&path, depth - 1) {
+                true => node_hash(&sibling, &hash),
+                false => node_hash(&hash, &sibling),
+            };
+        }
+        // siblings out of order or below the leaf layer are left over
+        siblings.next().is_none() && hash == *root
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_presence_and_absence() {
+        let mut tree = SparseTree::new();
+        assert_eq!(tree.root(), empty());
+        assert!(tree.proof(b"a").verify(&tree.root(), b"a", None));
+
+        tree.insert(b"a", b"1");
+        tree.insert(b"b", b"2");
+        tree.insert(b"c", b"3");
+        let root = tree.root();
+        let proof = tree.proof(b"b");
+        assert!(proof.verify(&root, b"b", Some(b"2")));
+        assert!(!proof.verify(&root, b"b", Some(b"3")));
+        assert!(!proof.verify(&root, b"b", None));
+        assert!(!proof.verify(&root, b"a", Some(b"2")));
+        assert!(tree.proof(b"d").verify(&root, b"d", None));
+        assert!(!tree.proof(b"d").verify(&root, b"d", Some(b"")));
+
+        tree.insert(b"b", b"4");
+        assert_ne!(tree.root(), root);
+        assert!(tree.proof(b"b").verify(&tree.root(), b"b", Some(b"4")));
+        assert!(tree.remove(b"b"));
+        assert!(tree.proof(b"b").verify(&tree.root(), b"b", None));
+    }
+
+    #[test]
+    fn test_root_independent_of_order() {
+        let mut first = SparseTree::new();
+        let mut second = SparseTree::new();
+        for key in ["x", "y", "z"] {
+            first.insert(key.as_bytes(), b"value");
+        }
+        for key in ["z", "x", "y"] {
+            second.insert(key.as_bytes(), b"value");
+        }
+        assert_eq!(first.root(), second.root());
+        second.remove(b"z");
+        assert_ne!(first.root(), second.root());
+    }
+
+    #[test]
+    fn test_proof_format() {
+        let mut tree = SparseTree::new();
+        tree.insert(b"a", b"1");
+        tree.insert(b"b", b"2");
+        let proof = tree.proof(b"a");
+        let json = serde_json::to_value(&proof).unwrap();
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["siblings"].as_array().unwrap().len(), 1);
+        let read: SparseProof = serde_json::from_value(json).unwrap();
+        assert_eq!(read, proof);
+        assert!(read.verify(&tree.root(), b"a", Some(b"1")));
+    }
+}
@@ -0,0 +1,200 @@
+use crate::leaf::LeafDigest;
+use crate::sha3::{hash_content, Hash, Hasher};
+use futures_util::{Stream, StreamExt};
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+
+/// First bytes of staged content kept in memory, enough to detect its mime type
+const HEAD_LEN: usize = 8 * 1024;
+
+/// Directory streamed uploads are written to until they are committed to storage
+pub struct Staging {
+    dir: PathBuf,
+    uploads: AtomicU64,
+}
+
+impl Staging {
+    /// Creates directory if it doesn't exist yet
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            uploads: AtomicU64::new(0),
+        })
+    }
+
+    /// Writes chunks to a new staged file while feeding them to `digest` of its leaf, so only a
+    /// single chunk of the upload is held in memory. Content alone is hashed as well if
+    /// `with_content_hash` is set. Fails with `InvalidData` once content grows over `max_size`
+    /// and with `InvalidInput` if chunks fail, e.g. on a malformed payload, any other error is
+    /// one of staging itself.
+    pub async fn stage<B, E>(
+        &self,
+        mut chunks: impl Stream<Item = Result<B, E>> + Unpin,
+        mut digest: Box<dyn LeafDigest>,
+        with_content_hash: bool,
+        max_size: Option<u64>,
+    ) -> io::Result<StagedFile>
+    where
+        B: AsRef<[u8]>,
+        E: Display,
+    {
+        let upload = self.uploads.fetch_add(1, Ordering::Relaxed);
+        let path = self
+            .dir
+            .join(format!("upload-{}-{upload}", std::process::id()));
+        let mut file = tokio::fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        // removes partial file if upload fails on the way
+        let mut staged = StagedFile {
+            path,
+            size: 0,
+            hash: hash_content(b""),
+            content_hash: None,
+            head: Vec::new(),
+        };
+        let mut content = with_content_hash.then(Hasher::new);
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+            let chunk = chunk.as_ref();
+            staged.size += chunk.len() as u64;
+            if let Some(max_size) = max_size.filter(|max_size| staged.size > *max_size) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("content exceeds limit of {max_size} bytes"),
+                ));
+            }
+            let missing = HEAD_LEN.saturating_sub(staged.head.len()).min(chunk.len());
+            staged.head.extend_from_slice(&chunk[..missing]);
+            digest.update(chunk);
+            if let Some(content) = &mut content {
+                content.update(chunk);
+            }
+            file.write_all(chunk).await?;
+        }
+        file.flush().await?;
+        staged.hash = digest.finalize();
+        staged.content_hash = content.map(|mut content| content.finalize());
+        Ok(staged)
+    }
+}
+
+/// Content of a streamed upload, removed on drop unless it was moved away by then
+pub struct StagedFile {
+    path: PathBuf,
+    size: u64,
+    hash: Hash,
+    content_hash: Option<Hash>,
+    head: Vec<u8>,
+}
+
+impl StagedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn hash(&self) -> &Hash {
+        &self.hash
+    }
+
+    /// Hash of the content alone, if it was staged `with_content_hash`
+    pub fn content_hash(&self) -> Option<&Hash> {
+        self.content_hash.as_ref()
+    }
+
+    /// First bytes of the content, at most 8 KiB
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::leaf::{ContentLeaves, LeafHasher, NamedLeaves};
+    use futures_util::stream;
+
+    #[tokio::test]
+    async fn test_stage_chunks() {
+        let dir = std::env::temp_dir().join("safe_storage_staging_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let staging = Staging::open(&dir).expect("should open");
+
+        let chunks = stream::iter(["first ", "second"].map(Ok::<_, io::Error>));
+        let staged = staging
+            .stage(chunks, NamedLeaves.start("a"), true, None)
+            .await
+            .expect("should stage");
+        assert_eq!(staged.size(), 12);
+        assert_eq!(staged.hash(), &NamedLeaves.leaf("a", b"first second"));
+        assert_eq!(staged.content_hash(), Some(&hash_content(b"first second")));
+        drop(staged);
+
+        let chunks = stream::iter(["first ", "second"].map(Ok::<_, io::Error>));
+        let staged = staging
+            .stage(chunks, ContentLeaves.start(""), false, None)
+            .await
+            .expect("should stage");
+        assert_eq!(staged.hash(), &hash_content(b"first second"));
+        assert_eq!(staged.content_hash(), None);
+        assert_eq!(staged.head(), b"first second");
+        assert_eq!(
+            std::fs::read(staged.path()).expect("should read"),
+            b"first second"
+        );
+        let path = staged.path().to_path_buf();
+        drop(staged);
+        assert!(!path.exists());
+
+        let chunks = stream::iter(["first ", "second"].map(Ok::<_, io::Error>));
+        let too_big = staging
+            .stage(chunks, ContentLeaves.start(""), false, Some(8))
+            .await;
+        assert_eq!(
+            too_big.err().map(|err| err.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+        let failing = stream::iter([Ok("first"), Err("connection reset")]);
+        let failed = staging
+            .stage(failing, ContentLeaves.start(""), false, None)
+            .await;
+        assert_eq!(
+            failed.err().map(|err| err.kind()),
+            Some(io::ErrorKind::InvalidInput)
+        );
+        assert_eq!(
+            std::fs::read_dir(&dir).expect("should list").count(),
+            0,
+            "partial uploads are removed"
+        );
+        std::fs::remove_dir_all(&dir).expect("should remove dir");
+
+        // failing staging itself is not blamed on the payload
+        let chunks = stream::iter(["first"].map(Ok::<_, io::Error>));
+        let failed = staging
+            .stage(chunks, ContentLeaves.start(""), false, None)
+            .await;
+        let kind = failed.err().map(|err| err.kind());
+        assert!(kind.is_some());
+        assert_ne!(kind, Some(io::ErrorKind::InvalidInput));
+        assert_ne!(kind, Some(io::ErrorKind::InvalidData));
+    }
+}
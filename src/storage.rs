@@ -1,52 +1,5381 @@
+use crate::api::{
+    dataset_leaf, ArchivedFile, BatchFile, Checkpoint, Cursor, Dataset, DatasetProof,
+    DeletionReceipt, Divergence, EpochArchive, File, FileBatch, FileId, FileListQuery, FileSort,
+    FileStatus, ImportedEpoch, IngestStatus, LeafIndex, ListedLeaf, Migration, PublicKey, RootHash,
+    SignedRoot, SortOrder, TreeSize, UploadReceipt, MAX_BATCH_BYTES,
+};
+use crate::auth::ANONYMOUS;
+use crate::backend::{
+    BlobCapacity, BlobStore, FileMeta, Hold, MemoryBlobs, MemoryMetadata, MetadataStore,
+};
+use crate::leaf::{LeafHasher, LeafHashing};
 use crate::merkle;
-use crate::sha3::hash_content;
+use crate::sha3::{hash_content, tombstone_of};
+use crate::signing::{Keyring, ServerKey};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use unicode_normalization::UnicodeNormalization;
 
-pub struct Content {
-    name: String,
-    content: Vec<u8>,
+/// Longest file name accepted, in bytes of its utf-8 NFC form
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Characters Windows doesn't allow in file names, refused so any name can be downloaded anywhere
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names Windows reserves regardless of extension and case
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Content type of files which are neither recognized by signature nor valid utf-8 text
+pub const DEFAULT_MIME: &str = "application/octet-stream";
+
+/// Limits applied to each credential separately
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+/// Bytes currently stored and files uploaded in total by single credential
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QuotaExceeded {
+    Bytes(u64),
+    Files(u64),
+}
+
+impl Display for QuotaExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaExceeded::Bytes(limit) => write!(f, "storage quota of {limit} bytes exceeded"),
+            QuotaExceeded::Files(limit) => write!(f, "quota of {limit} uploaded files exceeded"),
+        }
+    }
+}
+
+/// When current epoch is sealed and a new tree is started, epochs are never sealed by default
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct EpochPolicy {
+    /// seal once the epoch tree has this many leaves
+    pub max_leaves: Option<usize>,
+    /// seal non-empty epoch this long after it was started
+    pub max_age: Option<Duration>,
+}
+
+/// Leaves recomputed from contents so far by [`Storage::rehash_files`]
+#[derive(Debug, Default)]
+pub struct Rehashed {
+    /// id of the next file to rehash
+    next: usize,
+    /// recomputed leaves by leaf index
+    leaves: Vec<(usize, merkle::Sha3Hash)>,
+    /// leaf indexes of files whose recomputed leaf differs from their stored one
+    diverged: Vec<usize>,
+}
+
+/// Guardrail on leaves of the current epoch tree, which is kept in memory whole
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TreeLimit {
+    pub max_leaves: usize,
+    pub when_full: WhenFull,
+}
+
+/// What happens once the epoch tree reaches [`TreeLimit::max_leaves`]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum WhenFull {
+    /// anything taking a leaf is refused with [`StorageError::TreeFull`] until the epoch is
+    /// sealed by epoch policy
+    #[default]
+    Refuse,
+    /// epoch is sealed and a new tree started, as if epoch policy said so
+    Seal,
+}
+
+impl FromStr for WhenFull {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refuse" => Ok(WhenFull::Refuse),
+            "seal" => Ok(WhenFull::Seal),
+            _ => Err(anyhow::anyhow!(
+                "full tree policy must be one of refuse or seal"
+            )),
+        }
+    }
+}
+
+/// Proof of a leaf against root of the epoch tree it was appended to
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafProof {
+    pub epoch: u32,
+    pub proof: merkle::Sha3Proof,
+    pub root: merkle::Sha3Hash,
+}
+
+/// Committed file with proof of its leaf, content is left out when the caller has it already
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProvenFile {
+    pub name: String,
+    /// leaf hash of the content, content under a leaf never changes
+    pub hash: merkle::Sha3Hash,
+    pub content: Option<Vec<u8>>,
+    pub proof: LeafProof,
+}
+
+/// Committed file which is not deleted, without its content, see [`Storage::list_all_files`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    pub id: FileId,
+    pub name: String,
+    pub size: u64,
+    /// leaf hash of the content
+    pub hash: merkle::Sha3Hash,
+}
+
+/// Amount of proofs kept ready by default, see [`Storage::with_hot_proofs`]
+pub const DEFAULT_HOT_PROOFS: usize = 64;
+
+/// Proofs computed ahead of requests, served without walking the tree or, for sealed epochs,
+/// rebuilding it from metadata leaves. Least recently used proof is evicted once capacity is
+/// reached.
+#[derive(Default)]
+struct ProofCache {
+    capacity: usize,
+    /// proofs by leaf index, with the tick they were last used at
+    proofs: HashMap<usize, (u64, LeafProof)>,
+    tick: u64,
+}
+
+impl ProofCache {
+    fn get(&mut self, leaf_index: usize) -> Option<LeafProof> {
+        self.tick += 1;
+        let (used, proof) = self.proofs.get_mut(&leaf_index)?;
+        *used = self.tick;
+        Some(proof.clone())
+    }
+
+    fn insert(&mut self, leaf_index: usize, proof: LeafProof) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        self.proofs.insert(leaf_index, (self.tick, proof));
+        if self.proofs.len() > self.capacity {
+            let least_used = self
+                .proofs
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(leaf_index, _)| *leaf_index);
+            if let Some(least_used) = least_used {
+                self.proofs.remove(&least_used);
+            }
+        }
+    }
+
+    /// Recomputes cached proofs of the current epoch against the new root of its tree, proofs of
+    /// sealed epochs never change
+    fn refresh(&mut self, epoch: u32, first_leaf: usize, tree: &merkle::Sha3Tree) {
+        let Some(root) = tree.root() else {
+            return;
+        };
+        for (leaf_index, (_, proof)) in self.proofs.iter_mut() {
+            if proof.epoch != epoch {
+                continue;
+            }
+            if let Some(fresh) = tree.proof_for(leaf_index - first_leaf) {
+                proof.proof = fresh;
+                proof.root = root.clone();
+            }
+        }
+        self.forget_pruned(epoch, first_leaf, tree.pruned());
+    }
+
+    /// Drops proofs of leaves pruned from the current epoch tree, they can't be refreshed anymore
+    fn forget_pruned(&mut self, epoch: u32, first_leaf: usize, pruned: usize) {
+        self.proofs.retain(|leaf_index, (_, proof)| {
+            proof.epoch != epoch || leaf_index - first_leaf >= pruned
+        });
+    }
+}
+
+/// Content of a file being stored
+enum NewContent<'a> {
+    Memory(Vec<u8>),
+    /// staged in a file, moved into blob store as is
+    Staged {
+        path: &'a Path,
+        size: u64,
+        mime: String,
+    },
+}
+
+/// What happens when uploaded file has the same name as one already stored
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum CollisionPolicy {
+    /// upload is refused
+    Reject,
+    /// file is stored under the first free name with `-N` suffix before extension
+    Suffix,
+    /// file is stored under the same name as the next version
+    #[default]
+    Version,
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(CollisionPolicy::Reject),
+            "suffix" => Ok(CollisionPolicy::Suffix),
+            "version" => Ok(CollisionPolicy::Version),
+            _ => Err(anyhow::anyhow!(
+                "collision policy must be one of reject, suffix or version"
+            )),
+        }
+    }
+}
+
+/// Most shards a cluster can have, each owns at least one first byte value
+pub const MAX_SHARDS: usize = 256;
+
+/// Content owned by a shard of a cluster, by the first byte of its hash
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShardRange {
+    pub first: u8,
+    pub last: u8,
+}
+
+impl ShardRange {
+    /// Range of shard `index` out of `count`, first byte values are split evenly.
+    ///
+    /// Panics unless `index < count <= MAX_SHARDS`, as some shards would own nothing.
+    pub fn of(index: usize, count: usize) -> Self {
+        assert!(
+            index < count && count <= MAX_SHARDS,
+            "shard {index} out of {count} doesn't exist"
+        );
+        Self {
+            first: (index * 256).div_ceil(count) as u8,
+            last: (((index + 1) * 256).div_ceil(count) - 1) as u8,
+        }
+    }
+
+    /// Index of the shard out of `count` owning content with given hash
+    pub fn shard_of(hash: &merkle::Sha3Hash, count: usize) -> usize {
+        hash.as_bytes()[0] as usize * count / 256
+    }
+
+    pub fn contains(&self, hash: &merkle::Sha3Hash) -> bool {
+        (self.first..=self.last).contains(&hash.as_bytes()[0])
+    }
+}
+
+/// Parsed from hex bytes `FIRST-LAST`, e.g. `00-7f`
+impl FromStr for ShardRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("shard range must look like 00-7f"))?;
+        let range = Self {
+            first: u8::from_str_radix(first, 16)?,
+            last: u8::from_str_radix(last, 16)?,
+        };
+        if range.first > range.last {
+            return Err(anyhow::anyhow!("shard range {s} is empty"));
+        }
+        Ok(range)
+    }
+}
+
+impl Display for ShardRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02x}-{:02x}", self.first, self.last)
+    }
+}
+
+/// Reason why file name can't be stored as is
+#[derive(Debug, PartialEq)]
+pub enum InvalidName {
+    Empty,
+    TooLong,
+    ControlCharacter,
+    /// absolute path, backslash, empty, `.` or `..` component
+    Traversal,
+    /// not in NFC form, see [`normalize_name`]
+    NotNormalized,
+    /// character or component name not portable to Windows
+    Reserved(String),
+    /// dataset name with `/`, datasets are not nested
+    Separator,
+}
+
+impl Display for InvalidName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidName::Empty => write!(f, "file name must not be empty"),
+            InvalidName::TooLong => write!(f, "file name is longer than {MAX_NAME_LEN} bytes"),
+            InvalidName::ControlCharacter => write!(f, "file name contains control characters"),
+            InvalidName::Traversal => write!(
+                f,
+                "file name must be a relative path without empty, . or .. components"
+            ),
+            InvalidName::NotNormalized => write!(f, "file name is not in unicode NFC form"),
+            InvalidName::Reserved(reserved) => write!(
+                f,
+                "file name contains {reserved:?}, which is reserved on Windows"
+            ),
+            InvalidName::Separator => write!(f, "dataset name must not contain /"),
+        }
+    }
+}
+
+/// Checks that name can be safely used as relative path on the client side, names may contain
+/// directories separated by `/`
+pub fn validate_name(name: &str) -> Result<(), InvalidName> {
+    if name.is_empty() {
+        return Err(InvalidName::Empty);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(InvalidName::TooLong);
+    }
+    if name.chars().any(char::is_control) {
+        return Err(InvalidName::ControlCharacter);
+    }
+    if name.contains('\\')
+        || name
+            .split('/')
+            .any(|component| matches!(component, "" | "." | ".."))
+    {
+        return Err(InvalidName::Traversal);
+    }
+    if !unicode_normalization::is_nfc(name) {
+        return Err(InvalidName::NotNormalized);
+    }
+    if let Some(c) = name.chars().find(|c| RESERVED_CHARS.contains(c)) {
+        return Err(InvalidName::Reserved(c.to_string()));
+    }
+    for component in name.split('/') {
+        let stem = component.split('.').next().unwrap_or_default().trim_end();
+        if component.ends_with(['.', ' '])
+            || RESERVED_NAMES
+                .iter()
+                .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+        {
+            return Err(InvalidName::Reserved(component.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Names are stored, compared and hashed into name bound leaves in NFC form, so the same name
+/// typed on systems composing characters differently refers to the same file
+pub fn normalize_name(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// Detects content type from file signature, falling back to plain text for valid utf-8
+pub fn detect_mime(content: &[u8]) -> String {
+    match infer::get(content) {
+        Some(kind) => kind.mime_type().to_string(),
+        None if std::str::from_utf8(content).is_ok() => "text/plain; charset=utf-8".to_string(),
+        None => DEFAULT_MIME.to_string(),
+    }
+}
+
+/// Same as [`detect_mime`] for the first bytes of longer content, which may end in the middle of
+/// a character
+pub fn detect_mime_of_head(head: &[u8]) -> String {
+    match std::str::from_utf8(head) {
+        Err(err) if err.error_len().is_none() => detect_mime(&head[..err.valid_up_to()]),
+        _ => detect_mime(head),
+    }
+}
+
+fn is_expired(file: &FileMeta, now: SystemTime) -> bool {
+    file.expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+fn describe(id: usize, file: &FileMeta) -> File {
+    File {
+        id: FileId::from_usize(id),
+        name: file.name.clone(),
+        version: file.version,
+        mime: file.mime.clone(),
+        size: file.size,
+        expires_at: file.expires_at.map(|expires_at| {
+            expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        }),
+        expired: file.expired,
+        ipfs_cid: file.ipfs_cid.clone(),
+        deleted: file.deleted.is_some(),
+        undeleted_from: file.undeleted_from.map(FileId::from_usize),
+        leaf: None,
+        downloads: file.downloads,
+        last_access: file.last_access.map(|last_access| {
+            last_access
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        }),
+        dataset: file.dataset.clone(),
+    }
+}
+
+/// `dir/report.txt` becomes `dir/report-N.txt`, names without extension just get `-N` appended
+fn with_suffix(name: &str, n: u32) -> String {
+    let (dir, file) = match name.rsplit_once('/') {
+        Some((dir, file)) => (format!("{dir}/"), file),
+        None => (String::new(), name),
+    };
+    match file.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{dir}{stem}-{n}.{ext}"),
+        _ => format!("{dir}{file}-{n}"),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StorageError {
+    NotFound,
+    QuotaExceeded(QuotaExceeded),
+    /// operation is not possible in the current state of the file or tree
+    Conflict(String),
+    InvalidName(InvalidName),
+    /// file with the same name is already stored and collision policy is reject
+    NameTaken(String),
+    /// metadata or blob store failed
+    Backend(String),
+    /// integrity check failed, nothing can be changed anymore
+    Quarantined,
+    /// epoch archive doesn't match its checkpoint
+    InvalidArchive(String),
+    /// blob store can't keep given amount of bytes more
+    OutOfSpace(u64, BlobCapacity),
+    /// epoch tree has as many leaves as its limit allows
+    TreeFull(usize),
+    /// file belongs to other credential than the one changing it
+    NotOwner(FileId),
+    /// requested files have more content than given bytes in total
+    BatchTooLarge(u64),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound => write!(f, "file not found"),
+            StorageError::QuotaExceeded(exceeded) => exceeded.fmt(f),
+            StorageError::Conflict(reason) => write!(f, "{reason}"),
+            StorageError::InvalidName(invalid) => invalid.fmt(f),
+            StorageError::NameTaken(name) => write!(f, "file named {name} already exists"),
+            StorageError::Backend(reason) => write!(f, "storage backend failed: {reason}"),
+            StorageError::Quarantined => write!(
+                f,
+                "storage is read-only, integrity check found tampered leaves"
+            ),
+            StorageError::InvalidArchive(reason) => write!(f, "invalid epoch archive: {reason}"),
+            StorageError::OutOfSpace(bytes, capacity) => write!(
+                f,
+                "storage is full: {bytes} more bytes don't fit, {} of {} bytes used",
+                capacity.used, capacity.max
+            ),
+            StorageError::TreeFull(max_leaves) => write!(
+                f,
+                "tree is full: current epoch can't have more than {max_leaves} leaves"
+            ),
+            StorageError::NotOwner(id) => {
+                write!(f, "file {id} belongs to other credential")
+            }
+            StorageError::BatchTooLarge(max_bytes) => write!(
+                f,
+                "batch has more than {max_bytes} bytes of content, request fewer files"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<QuotaExceeded> for StorageError {
+    fn from(exceeded: QuotaExceeded) -> Self {
+        StorageError::QuotaExceeded(exceeded)
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+impl From<InvalidName> for StorageError {
+    fn from(invalid: InvalidName) -> Self {
+        StorageError::InvalidName(invalid)
+    }
+}
+
+/// Files stored under single name
+#[derive(Debug, Default)]
+struct NameUsage {
+    /// files which are not deleted
+    live: usize,
+    /// versions handed out so far, including deleted ones
+    versions: u32,
+}
+
+/// Hash index entries of leaves persisted before storage was opened, built in batches by
+/// [`Storage::rebuild_indexes`] so opening doesn't take time proportional to the store size
+struct HashIndexRebuild {
+    /// leaves at open, indexed ones are dropped once all of them are
+    leaves: Vec<merkle::Sha3Hash>,
+    indexed: usize,
+    total: usize,
+    started_at: SystemTime,
+    finished_at: Option<SystemTime>,
 }
 
+impl HashIndexRebuild {
+    fn new(leaves: Vec<merkle::Sha3Hash>, now: SystemTime) -> Self {
+        let total = leaves.len();
+        Self {
+            leaves,
+            indexed: 0,
+            total,
+            started_at: now,
+            finished_at: (total == 0).then_some(now),
+        }
+    }
+}
+
+/// Tree of leaves of files which joined the same dataset, in the order they joined
+#[derive(Default)]
+struct DatasetTree {
+    files: Vec<usize>,
+    tree: merkle::Sha3Tree,
+    /// index of the latest dataset leaf in the main tree
+    leaf_index: usize,
+}
+
+/// What a leaf did to a file, see [`Storage::list_changes`]
+#[derive(Debug, Clone, Copy)]
+enum LeafChange {
+    Stored(usize),
+    Tombstoned(usize),
+}
+
+/// Ids of named files, deleted ones included, in orders files can be listed in
 #[derive(Default)]
+struct ListingIndex {
+    created: BTreeSet<usize>,
+    by_name: BTreeSet<(String, usize)>,
+    by_size: BTreeSet<(u64, usize)>,
+}
+
+impl ListingIndex {
+    fn insert(&mut self, id: usize, file: &FileMeta) {
+        self.created.insert(id);
+        self.by_name.insert((file.name.clone(), id));
+        self.by_size.insert((file.size, id));
+    }
+
+    fn ids(&self, sort: FileSort, order: SortOrder) -> Box<dyn Iterator<Item = usize> + '_> {
+        self.ids_after(sort, order, None)
+    }
+
+    /// Ids in given order following the given file, which is skipped as well
+    fn ids_after(
+        &self,
+        sort: FileSort,
+        order: SortOrder,
+        after: Option<(usize, &FileMeta)>,
+    ) -> Box<dyn Iterator<Item = usize> + '_> {
+        fn seek<'a, K: Ord + Clone>(
+            set: &'a BTreeSet<K>,
+            key: Option<K>,
+            order: SortOrder,
+        ) -> Box<dyn Iterator<Item = &'a K> + 'a> {
+            use std::ops::Bound::{Excluded, Unbounded};
+            match (order, key) {
+                (SortOrder::Asc, None) => Box::new(set.iter()),
+                (SortOrder::Desc, None) => Box::new(set.iter().rev()),
+                (SortOrder::Asc, Some(key)) => Box::new(set.range((Excluded(key), Unbounded))),
+                (SortOrder::Desc, Some(key)) => {
+                    Box::new(set.range((Unbounded, Excluded(key))).rev())
+                }
+            }
+        }
+        match sort {
+            FileSort::Created => {
+                Box::new(seek(&self.created, after.map(|(id, _)| id), order).copied())
+            }
+            FileSort::Name => {
+                let key = after.map(|(id, file)| (file.name.clone(), id));
+                Box::new(seek(&self.by_name, key, order).map(|(_, id)| *id))
+            }
+            FileSort::Size => {
+                let key = after.map(|(id, file)| (file.size, id));
+                Box::new(seek(&self.by_size, key, order).map(|(_, id)| *id))
+            }
+        }
+    }
+}
+
 pub struct Storage {
+    metadata: Box<dyn MetadataStore>,
+    blobs: Box<dyn BlobStore>,
+    /// current epoch only, rebuilt from metadata leaves after the last checkpoint when storage
+    /// is opened
     tree: merkle::Sha3Tree,
-    files: Vec<Content>,
+    /// index of the first leaf of the current epoch
+    epoch_start: usize,
+    checkpoints: Vec<Checkpoint>,
+    epoch_started_at: SystemTime,
+    epoch_policy: EpochPolicy,
+    tree_limit: Option<TreeLimit>,
+    keyring: Keyring,
+    proof_cache: RefCell<ProofCache>,
+    quota: Quota,
+    usage: BTreeMap<String, Usage>,
+    collision_policy: CollisionPolicy,
+    names: BTreeMap<String, NameUsage>,
+    /// ids of files in leaf order which are not appended to the tree yet, because some reserved
+    /// slot before them is still not filled
+    pending: VecDeque<usize>,
+    /// index of the first committed leaf with given hash, complete once `hash_rebuild` finishes
+    hashes: HashMap<merkle::Sha3Hash, usize>,
+    hash_rebuild: HashIndexRebuild,
+    listing: ListingIndex,
+    /// current epoch leaves after the pruned prefix of its tree which belong to deleted files or
+    /// are their tombstones
+    dead: BTreeSet<usize>,
+    /// set once integrity check fails, storage stays read-only afterwards
+    quarantine: Option<Divergence>,
+    /// content this storage accepts when it is a shard of a cluster
+    shard: Option<ShardRange>,
+    leaf_hasher: Arc<dyn LeafHasher>,
+    /// contents of deleted files stay in blob store so they can be undeleted
+    keep_deleted: bool,
+    /// files not downloaded for this long are tombstoned as expired
+    idle_ttl: Option<Duration>,
+    /// reservations not filled for this long are abandoned and tombstoned as expired
+    reservation_ttl: Option<Duration>,
+    /// content downloads of all files, deleted ones included
+    downloads: u64,
+    /// downloads and the last access by file id not written to metadata yet, see
+    /// [`Storage::flush_downloads`]
+    pending_downloads: BTreeMap<usize, (u64, SystemTime)>,
+    datasets: BTreeMap<String, DatasetTree>,
+    /// files stored and tombstoned by leaves, by leaf index
+    changes: BTreeMap<usize, LeafChange>,
+    /// set while [`Storage::transaction`] runs, so mutations made of other ones share it
+    in_transaction: bool,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Storage {
+    /// Storage keeping both metadata and contents in memory
     pub fn new() -> Self {
+        Self::open(
+            Box::<MemoryMetadata>::default(),
+            Box::<MemoryBlobs>::default(),
+        )
+        .expect("empty in-memory stores can't fail")
+    }
+
+    /// Opens storage on top of given stores, rebuilding tree, name and usage indexes from
+    /// metadata already kept there. Hash index is rebuilt later by [`Storage::rebuild_indexes`].
+    /// Current epoch is considered started when the last one was sealed, or now if there is none.
+    pub fn open(
+        metadata: Box<dyn MetadataStore>,
+        blobs: Box<dyn BlobStore>,
+    ) -> Result<Self, StorageError> {
+        Self::load(metadata, blobs, true)
+    }
+
+    /// Same as [`Storage::open`], uploads waiting for validation are rejected only if the
+    /// process `restarted`, as their staged contents are gone then
+    fn load(
+        metadata: Box<dyn MetadataStore>,
+        blobs: Box<dyn BlobStore>,
+        restarted: bool,
+    ) -> Result<Self, StorageError> {
+        let mut storage = Self::on_stores(metadata, blobs);
+        storage.index_stores(restarted)?;
+        Ok(storage)
+    }
+
+    /// Storage on top of given stores with empty indexes, which [`Storage::index_stores`] builds
+    fn on_stores(metadata: Box<dyn MetadataStore>, blobs: Box<dyn BlobStore>) -> Self {
         Self {
-            files: Default::default(),
             tree: merkle::Sha3Tree::new(),
+            epoch_start: 0,
+            checkpoints: Vec::new(),
+            epoch_started_at: SystemTime::now(),
+            epoch_policy: Default::default(),
+            tree_limit: None,
+            keyring: Keyring::default(),
+            proof_cache: RefCell::new(ProofCache {
+                capacity: DEFAULT_HOT_PROOFS,
+                ..Default::default()
+            }),
+            metadata,
+            blobs,
+            quota: Default::default(),
+            usage: Default::default(),
+            collision_policy: Default::default(),
+            names: Default::default(),
+            pending: Default::default(),
+            hashes: Default::default(),
+            hash_rebuild: HashIndexRebuild::new(Vec::new(), SystemTime::now()),
+            listing: Default::default(),
+            dead: Default::default(),
+            quarantine: None,
+            shard: None,
+            leaf_hasher: LeafHashing::default().hasher(),
+            keep_deleted: false,
+            idle_ttl: None,
+            reservation_ttl: None,
+            downloads: 0,
+            pending_downloads: Default::default(),
+            datasets: Default::default(),
+            changes: Default::default(),
+            in_transaction: false,
+        }
+    }
+
+    /// Builds tree, name, usage and dataset indexes from metadata of the stores, as described in
+    /// [`Storage::open`]
+    fn index_stores(&mut self, restarted: bool) -> Result<(), StorageError> {
+        self.checkpoints = self.metadata.checkpoints()?;
+        self.epoch_start = self
+            .checkpoints
+            .last()
+            .map_or(0, |c| (c.first_leaf + c.size) as usize);
+        self.epoch_started_at = self.checkpoints.last().map_or_else(SystemTime::now, |c| {
+            UNIX_EPOCH + Duration::from_secs(c.sealed_at)
+        });
+        let leaves = self.metadata.leaves()?;
+        self.tree = merkle::Sha3Tree::from_manifest(leaves.iter().skip(self.epoch_start).cloned());
+        self.hash_rebuild = HashIndexRebuild::new(leaves, SystemTime::now());
+        // ids of held files released later are greater than ids of reservations made meanwhile,
+        // so pending files are ordered by their leaf index
+        let mut pending = Vec::new();
+        let mut joined = Vec::new();
+        for (id, mut file) in self.metadata.all()?.into_iter().enumerate() {
+            if let (true, Some(Hold::Pending(_))) = (restarted, &file.held) {
+                // staged content doesn't survive restart, so validation can't finish anymore
+                file.held = Some(Hold::Rejected(
+                    "server restarted before validation finished".to_string(),
+                ));
+                self.metadata.update(id, file.clone())?;
+            }
+            self.downloads += file.downloads;
+            let usage = self.usage.entry(file.owner.clone()).or_default();
+            usage.files += 1;
+            if file.deleted.is_none() && file.held.is_none() {
+                usage.bytes += file.size;
+            }
+            // abandoned reservations stay unnamed
+            if file.hash.is_some() && !file.name.is_empty() {
+                let name = self.names.entry(file.name.clone()).or_default();
+                name.versions = name.versions.max(file.version);
+                if file.deleted.is_none() {
+                    name.live += 1;
+                }
+                self.listing.insert(id, &file);
+            }
+            if file.held.is_none() && !self.is_committed(&file) {
+                pending.push((file.leaf_index, id));
+            } else if file.held.is_none() && file.hash.is_some() {
+                self.changes.insert(file.leaf_index, LeafChange::Stored(id));
+            }
+            if let Some(tombstone_index) = file.tombstone_index {
+                self.changes
+                    .insert(tombstone_index, LeafChange::Tombstoned(id));
+            }
+            if file.deleted.is_some() {
+                self.mark_dead(file.leaf_index);
+                if let Some(tombstone_index) = file.tombstone_index {
+                    self.mark_dead(tombstone_index);
+                }
+            }
+            if let (Some(dataset), Some(leaf_index), Some(hash)) =
+                (file.dataset, file.dataset_leaf_index, file.hash)
+            {
+                joined.push((leaf_index, id, dataset, hash));
+            }
+        }
+        // dataset trees grow in the order their leaves were appended
+        joined.sort_by_key(|(leaf_index, ..)| *leaf_index);
+        for (leaf_index, id, dataset, hash) in joined {
+            let dataset = self.datasets.entry(dataset).or_default();
+            dataset.tree.append(hash);
+            dataset.files.push(id);
+            dataset.leaf_index = leaf_index;
+        }
+        pending.sort();
+        self.pending = pending.into_iter().map(|(_, id)| id).collect();
+        self.prune_dead();
+        Ok(())
+    }
+
+    /// Hands stores back, e.g. to open storage on top of them again
+    pub fn into_stores(self) -> (Box<dyn MetadataStore>, Box<dyn BlobStore>) {
+        (self.metadata, self.blobs)
+    }
+
+    pub fn with_quota(self, quota: Quota) -> Self {
+        Self { quota, ..self }
+    }
+
+    /// Accepts only content in given range, experimental sharded cluster mode
+    pub fn with_shard(self, shard: Option<ShardRange>) -> Self {
+        Self { shard, ..self }
+    }
+
+    /// How leaves of added files are derived from their names and contents. Leaves are hashed
+    /// with the name given on upload, so name based hashing doesn't go with suffix collision
+    /// policy.
+    pub fn with_leaf_hasher(self, leaf_hasher: Arc<dyn LeafHasher>) -> Self {
+        Self {
+            leaf_hasher,
+            ..self
+        }
+    }
+
+    pub fn leaf_hasher(&self) -> &Arc<dyn LeafHasher> {
+        &self.leaf_hasher
+    }
+
+    /// Keeps contents of deleted files instead of dropping them, so [`Storage::undelete_file`]
+    /// can append them again. Kept contents are not charged to owners.
+    pub fn with_keep_deleted(self, keep_deleted: bool) -> Self {
+        Self {
+            keep_deleted,
+            ..self
+        }
+    }
+
+    /// Tombstones files nobody downloaded for given time, counted from upload until the first
+    /// download, by [`Storage::delete_expired`]
+    pub fn with_idle_ttl(self, idle_ttl: Option<Duration>) -> Self {
+        Self { idle_ttl, ..self }
+    }
+
+    /// Abandons reservations not filled for given time since they were made, by
+    /// [`Storage::delete_expired`]
+    pub fn with_reservation_ttl(self, reservation_ttl: Option<Duration>) -> Self {
+        Self {
+            reservation_ttl,
+            ..self
+        }
+    }
+
+    pub fn with_collision_policy(self, collision_policy: CollisionPolicy) -> Self {
+        Self {
+            collision_policy,
+            ..self
+        }
+    }
+
+    pub fn with_epoch_policy(self, epoch_policy: EpochPolicy) -> Self {
+        Self {
+            epoch_policy,
+            ..self
         }
     }
 
-    pub fn add_new_file(&mut self, name: String, content: Vec<u8>) -> usize {
-        self.tree.append(hash_content(&content));
-        self.files.push(Content { name, content });
-        self.files.len() - 1
+    pub fn with_tree_limit(self, tree_limit: Option<TreeLimit>) -> Self {
+        Self { tree_limit, ..self }
+    }
+
+    /// Key checkpoints of sealed epochs are signed with, they are left unsigned without it
+    pub fn with_server_key(self, server_key: Option<ServerKey>) -> Self {
+        self.with_keyring(Keyring::new(server_key.into_iter().collect()))
+    }
+
+    /// Same as [`Storage::with_server_key`] with keys used before the active one, checkpoints
+    /// signed with them are still accepted on epoch import
+    pub fn with_keyring(self, keyring: Keyring) -> Self {
+        Self { keyring, ..self }
+    }
+
+    /// Amount of proofs kept ready: proof of every appended leaf is computed right away and kept
+    /// up to date until it is evicted by more recently appended or requested ones. Zero computes
+    /// every proof on request.
+    pub fn with_hot_proofs(self, capacity: usize) -> Self {
+        self.proof_cache.borrow_mut().capacity = capacity;
+        self
+    }
+
+    pub fn add_new_file(&mut self, name: String, content: Vec<u8>) -> Result<FileId, StorageError> {
+        self.writable()?;
+        let hash = self.leaf_hasher.leaf(&name, &content);
+        self.owns(&hash)?;
+        let (name, version) = self.resolve_name(name)?;
+        self.fits(content.len() as u64)?;
+        let usage = self.usage.entry(ANONYMOUS.to_string()).or_default();
+        usage.bytes += content.len() as u64;
+        usage.files += 1;
+        self.push_file(
+            ANONYMOUS,
+            Some((name, version)),
+            NewContent::Memory(content),
+            Some(hash),
+        )
+    }
+
+    /// Adds file on behalf of given credential, enforcing configured quota
+    pub fn add_new_file_as(
+        &mut self,
+        owner: &str,
+        name: String,
+        content: Vec<u8>,
+    ) -> Result<FileId, StorageError> {
+        let hash = self.leaf_hasher.leaf(&name, &content);
+        self.add_hashed_file_as(owner, name, content, hash)
+    }
+
+    /// Same as [`Storage::add_new_file_as`], for content already hashed outside of storage lock
+    pub fn add_hashed_file_as(
+        &mut self,
+        owner: &str,
+        name: String,
+        content: Vec<u8>,
+        hash: merkle::Sha3Hash,
+    ) -> Result<FileId, StorageError> {
+        self.writable()?;
+        self.has_room(1)?;
+        self.owns(&hash)?;
+        let (name, version) = self.resolve_name(name)?;
+        self.charge(owner, content.len() as u64, true)?;
+        self.push_file(
+            owner,
+            Some((name, version)),
+            NewContent::Memory(content),
+            Some(hash),
+        )
     }
 
-    pub fn list_all_files(&self) -> Vec<(usize, String, Vec<u8>)> {
-        self.files
+    /// Stores files with their hashes together: all of them are checked first and their leaves
+    /// are appended consecutively, or nothing is stored. Returns ids and leaf indices in order.
+    pub fn add_hashed_files_as(
+        &mut self,
+        owner: &str,
+        files: Vec<(String, Vec<u8>, merkle::Sha3Hash)>,
+    ) -> Result<Vec<(FileId, LeafIndex)>, StorageError> {
+        self.writable()?;
+        self.has_room(files.len())?;
+        for (_, _, hash) in &files {
+            self.owns(hash)?;
+        }
+        let names = self.resolve_names(files.iter().map(|(name, ..)| name.clone()).collect())?;
+        let bytes = files
             .iter()
-            .enumerate()
-            .map(|(i, v)| (i, v.name.clone(), v.content.clone()))
-            .collect()
+            .map(|(_, content, _)| content.len() as u64)
+            .sum();
+        self.charge_files(owner, bytes, files.len() as u64)?;
+        self.transaction(|storage| {
+            let mut stored = Vec::with_capacity(files.len());
+            for ((_, content, hash), name) in files.into_iter().zip(names) {
+                let leaf_index = storage.leaf_count();
+                let content = NewContent::Memory(content);
+                let id = storage.queue_file(owner, Some(name), content, Some(hash))?;
+                stored.push((FileId::from_usize(id), leaf_index.into()));
+            }
+            storage.commit_pending()?;
+            Ok(stored)
+        })
     }
 
-    pub fn get_file_by_id(&self, id: usize) -> Option<(String, Vec<u8>, merkle::Sha3Proof)> {
-        self.files.get(id).map(|c| {
-            (
-                c.name.clone(),
-                c.content.clone(),
-                self.tree
-                    .proof_for(id)
-                    .expect("should be present since we found file with same id"),
-            )
+    /// Same as [`Storage::add_hashed_file_as`] for content staged in a file, which is moved into
+    /// blob store only once the file is accepted. `mime` is detected from the first bytes.
+    pub fn add_staged_file_as(
+        &mut self,
+        owner: &str,
+        name: String,
+        staged: &Path,
+        size: u64,
+        mime: String,
+        hash: merkle::Sha3Hash,
+    ) -> Result<FileId, StorageError> {
+        self.writable()?;
+        self.has_room(1)?;
+        self.owns(&hash)?;
+        let (name, version) = self.resolve_name(name)?;
+        self.charge(owner, size, true)?;
+        let content = NewContent::Staged {
+            path: staged,
+            size,
+            mime,
+        };
+        self.push_file(owner, Some((name, version)), content, Some(hash))
+    }
+
+    /// First phase of two-phase ingestion: records upload of content staged outside of storage
+    /// without taking a leaf slot, so files uploaded meanwhile are committed regardless of how
+    /// long validation takes. Collision policy is applied again on release.
+    pub fn hold_file_as(
+        &mut self,
+        owner: &str,
+        name: String,
+        size: u64,
+        mime: String,
+        hash: merkle::Sha3Hash,
+    ) -> Result<FileId, StorageError> {
+        self.writable()?;
+        self.owns(&hash)?;
+        let name = normalize_name(&name);
+        self.resolve_name(name.clone())?;
+        self.charge(owner, size, true)?;
+        let id = self.metadata.insert(FileMeta {
+            name,
+            owner: owner.to_string(),
+            version: 0,
+            mime,
+            size,
+            // assigned on release
+            leaf_index: usize::MAX,
+            hash: None,
+            expires_at: None,
+            expired: false,
+            deleted: None,
+            tombstone_index: None,
+            ipfs_cid: None,
+            undeleted_from: None,
+            undeleted_to: None,
+            held: Some(Hold::Pending(hash)),
+            stored_at: SystemTime::now(),
+            downloads: 0,
+            last_access: None,
+            dataset: None,
+            dataset_leaf_index: None,
+            content_hash: None,
+        })?;
+        Ok(FileId::from_usize(id))
+    }
+
+    /// Second phase of two-phase ingestion once validation approved held file: staged content
+    /// is moved into blob store and the leaf is appended, or queued behind unfilled
+    /// reservations. Returns leaf index of the file.
+    pub fn release_file(&mut self, id: FileId, staged: &Path) -> Result<LeafIndex, StorageError> {
+        let id = id.as_usize();
+        self.writable()?;
+        let mut file = self.metadata.get(id)?.ok_or(StorageError::NotFound)?;
+        let Some(Hold::Pending(hash)) = file.held.clone() else {
+            return Err(StorageError::Conflict(format!(
+                "file {id} is not waiting for validation"
+            )));
+        };
+        let (name, version) = self.resolve_name(file.name.clone())?;
+        self.has_room(1)?;
+        self.transaction(|storage| {
+            storage.blobs.put_file(id, staged)?;
+            file.hash = Some(hash);
+            file.held = None;
+            file.leaf_index = storage.leaf_count();
+            storage.name_file(&mut file, name, version);
+            let leaf_index = file.leaf_index;
+            storage.listing.insert(id, &file);
+            storage.metadata.update(id, file)?;
+            storage.pending.push_back(id);
+            storage.commit_pending()?;
+            Ok(leaf_index.into())
         })
     }
 
-    pub fn root_hash(&self) -> Option<merkle::Sha3Hash> {
-        self.tree.root()
+    /// Second phase of two-phase ingestion once validation refused held file, nothing is
+    /// appended and its bytes are not charged to the owner anymore
+    pub fn reject_file(&mut self, id: FileId, reason: String) -> Result<(), StorageError> {
+        let id = id.as_usize();
+        let mut file = self.metadata.get(id)?.ok_or(StorageError::NotFound)?;
+        if !matches!(file.held, Some(Hold::Pending(_))) {
+            return Err(StorageError::Conflict(format!(
+                "file {id} is not waiting for validation"
+            )));
+        }
+        if let Some(usage) = self.usage.get_mut(&file.owner) {
+            usage.bytes -= file.size;
+        }
+        file.held = Some(Hold::Rejected(reason));
+        Ok(self.metadata.update(id, file)?)
+    }
+
+    /// Ingestion stage of the file, with proof of its leaf once it is committed. Reserved slots
+    /// and files queued behind them are pending as well.
+    pub fn file_status(&self, id: FileId, now: SystemTime) -> Result<FileStatus, StorageError> {
+        let id = id.as_usize();
+        let file = self.metadata.get(id)?.ok_or(StorageError::NotFound)?;
+        let mut status = FileStatus {
+            id: FileId::from_usize(id),
+            status: IngestStatus::Pending,
+            reason: None,
+            hash: None,
+            proof: None,
+            root: None,
+            receipt: None,
+        };
+        match file.held {
+            Some(Hold::Pending(_)) => {}
+            Some(Hold::Rejected(reason)) => {
+                status.status = IngestStatus::Rejected;
+                status.reason = Some(reason);
+            }
+            None if !self.is_committed(&file) => {}
+            None => {
+                status.status = IngestStatus::Committed;
+                status.proof = Some(self.leaf_proof(file.leaf_index)?.proof);
+                status.root = self.root_of_file(FileId::from_usize(id))?;
+                status.hash = file.hash;
+                status.receipt = self.upload_receipt(FileId::from_usize(id), now)?;
+            }
+        }
+        Ok(status)
+    }
+
+    /// Fails with conflict if content with given hash belongs to another shard
+    fn owns(&self, hash: &merkle::Sha3Hash) -> Result<(), StorageError> {
+        match self.shard {
+            Some(shard) if !shard.contains(hash) => Err(StorageError::Conflict(format!(
+                "content {hash} belongs to another shard than {shard}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reserves next leaf slot for a file which will be uploaded later, returns file id and leaf
+    /// index the file will land at. Files added after the reservation are committed to the tree
+    /// only once the reserved slot is filled.
+    pub fn reserve(&mut self, owner: &str) -> Result<(FileId, LeafIndex), StorageError> {
+        self.writable()?;
+        self.has_room(1)?;
+        self.charge(owner, 0, true)?;
+        let leaf_index = self.leaf_count();
+        let id = self.push_file(owner, None, NewContent::Memory(Vec::new()), None)?;
+        Ok((id, leaf_index.into()))
+    }
+
+    /// Fills previously reserved slot with file content, returns leaf index of the file
+    pub fn fill_reservation(
+        &mut self,
+        id: FileId,
+        owner: &str,
+        name: String,
+        content: Vec<u8>,
+        hash: merkle::Sha3Hash,
+    ) -> Result<LeafIndex, StorageError> {
+        let id = id.as_usize();
+        self.writable()?;
+        let mut file = self.metadata.get(id)?.ok_or(StorageError::NotFound)?;
+        // held files have no hash either until they are released
+        if file.hash.is_some() || file.held.is_some() {
+            return Err(StorageError::Conflict(format!(
+                "file {id} is not a reservation or is already filled"
+            )));
+        }
+        if file.owner != owner {
+            return Err(StorageError::Conflict(format!(
+                "file {id} is reserved by other credential"
+            )));
+        }
+        self.owns(&hash)?;
+        let (name, version) = self.resolve_name(name)?;
+        self.charge(owner, content.len() as u64, false)?;
+        file.mime = detect_mime(&content);
+        file.size = content.len() as u64;
+        file.hash = Some(hash);
+        file.content_hash = self.content_hash_of(&content);
+        self.transaction(|storage| {
+            storage.blobs.put(id, content)?;
+            storage.name_file(&mut file, name, version);
+            let leaf_index = file.leaf_index;
+            storage.listing.insert(id, &file);
+            storage.metadata.update(id, file)?;
+            storage.commit_pending()?;
+            Ok(leaf_index.into())
+        })
+    }
+
+    /// Total amount of leaves including reserved ones which are not in the tree yet
+    pub fn leaf_count(&self) -> usize {
+        self.epoch_start + self.tree.len() + self.pending.len()
+    }
+
+    /// Fails with conflict unless the next leaf lands at index `expected`, i.e. nobody appended or
+    /// reserved leaves since the caller last saw the tree
+    pub fn expect_tree_size(&self, expected: TreeSize) -> Result<(), StorageError> {
+        match self.leaf_count() {
+            size if size == expected.as_usize() => Ok(()),
+            size => Err(StorageError::Conflict(format!(
+                "tree has {size} leaves, {expected} expected - another writer appended to it"
+            ))),
+        }
+    }
+
+    /// Normalizes and validates name and applies collision policy, returns name and version file will be stored
+    /// with. Nothing is changed until [`Storage::name_file`] is called.
+    fn resolve_name(&self, name: String) -> Result<(String, u32), StorageError> {
+        self.resolve_name_among(name, &BTreeMap::new())
+    }
+
+    /// Same as [`Storage::resolve_name`] for names of files stored together, each one resolved
+    /// as if the ones before it were already stored
+    fn resolve_names(&self, names: Vec<String>) -> Result<Vec<(String, u32)>, StorageError> {
+        let mut batch = BTreeMap::new();
+        let mut resolved = Vec::with_capacity(names.len());
+        for name in names {
+            let (name, version) = self.resolve_name_among(name, &batch)?;
+            batch.insert(name.clone(), version);
+            resolved.push((name, version));
+        }
+        Ok(resolved)
+    }
+
+    /// `batch` has names taken by files not stored yet with their latest versions
+    fn resolve_name_among(
+        &self,
+        name: String,
+        batch: &BTreeMap<String, u32>,
+    ) -> Result<(String, u32), StorageError> {
+        let name = normalize_name(&name);
+        validate_name(&name)?;
+        let taken = |name: &str| {
+            batch.contains_key(name) || self.names.get(name).is_some_and(|usage| usage.live > 0)
+        };
+        let name = match self.collision_policy {
+            CollisionPolicy::Reject if taken(&name) => return Err(StorageError::NameTaken(name)),
+            CollisionPolicy::Suffix if taken(&name) => {
+                let name = (1..)
+                    .map(|n| with_suffix(&name, n))
+                    .find(|candidate| !taken(candidate))
+                    .expect("some suffix should be free");
+                validate_name(&name)?;
+                name
+            }
+            _ => name,
+        };
+        let version = match batch.get(&name) {
+            Some(version) => *version,
+            None => self.names.get(&name).map_or(0, |usage| usage.versions),
+        } + 1;
+        Ok((name, version))
+    }
+
+    fn name_file(&mut self, file: &mut FileMeta, name: String, version: u32) {
+        let usage = self.names.entry(name.clone()).or_default();
+        usage.live += 1;
+        usage.versions = version;
+        file.name = name;
+        file.version = version;
+    }
+
+    fn charge(&mut self, owner: &str, bytes: u64, new_file: bool) -> Result<(), StorageError> {
+        self.charge_files(owner, bytes, new_file as u64)
+    }
+
+    /// Charges `bytes` of `files` new files at once, nothing is charged if they don't fit into
+    /// quota of the owner or into blob store
+    fn charge_files(&mut self, owner: &str, bytes: u64, files: u64) -> Result<(), StorageError> {
+        self.fits(bytes)?;
+        let usage = self.usage.entry(owner.to_string()).or_default();
+        let bytes = usage.bytes + bytes;
+        if let Some(max_files) = self
+            .quota
+            .max_files
+            .filter(|max| files > 0 && usage.files + files > *max)
+        {
+            return Err(QuotaExceeded::Files(max_files).into());
+        }
+        if let Some(max_bytes) = self.quota.max_bytes.filter(|max| bytes > *max) {
+            return Err(QuotaExceeded::Bytes(max_bytes).into());
+        }
+        usage.bytes = bytes;
+        usage.files += files;
+        Ok(())
+    }
+
+    /// Checks that `bytes` more content fit into blob store, if it has limited capacity
+    fn fits(&self, bytes: u64) -> Result<(), StorageError> {
+        match self.blobs.capacity() {
+            Some(capacity) if capacity.used + bytes > capacity.max => {
+                Err(StorageError::OutOfSpace(bytes, capacity))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs mutation made of several writes in one transaction of both stores, which mutations
+    /// run by `f` join. Blobs commit before metadata referring to them, and blobs removed by the
+    /// mutation are dropped only once metadata committed. If any step fails, writes of all of
+    /// them are rolled back and indexes are rebuilt from the stores, as if storage was opened on
+    /// top of them again.
+    fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, StorageError>,
+    ) -> Result<T, StorageError> {
+        if self.in_transaction {
+            return f(self);
+        }
+        self.metadata.begin()?;
+        if let Err(err) = self.blobs.begin() {
+            self.metadata.rollback()?;
+            return Err(err.into());
+        }
+        self.in_transaction = true;
+        let result = f(self).and_then(|value| {
+            self.blobs.commit()?;
+            self.metadata.commit()?;
+            Ok(value)
+        });
+        self.in_transaction = false;
+        if result.is_ok() {
+            // metadata doesn't refer to them anymore, a blob left behind is only wasted space
+            if let Err(err) = self.blobs.purge() {
+                eprintln!("removed blobs could not be dropped: {err}");
+            }
+            return result;
+        }
+        // both stores are rolled back even if one of them fails to, so neither of them is left
+        // with a transaction open
+        let metadata = self.metadata.rollback();
+        let blobs = self.blobs.rollback();
+        metadata?;
+        blobs?;
+        self.reload()?;
+        result
+    }
+
+    /// Runs several mutations, e.g. upload of a file and its dataset join, so that either all of
+    /// them are stored or none
+    pub fn atomically<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, StorageError>,
+    ) -> Result<T, StorageError> {
+        self.transaction(f)
+    }
+
+    /// Rebuilds indexes from stores, keeping configuration. Hash index is rebuilt right away.
+    /// Stores stay with storage if they can't be read.
+    fn reload(&mut self) -> Result<(), StorageError> {
+        let metadata = std::mem::replace(&mut self.metadata, Box::new(MemoryMetadata::default()));
+        let blobs = std::mem::replace(&mut self.blobs, Box::new(MemoryBlobs::default()));
+        let mut fresh = Self::on_stores(metadata, blobs);
+        if let Err(err) = fresh.index_stores(false) {
+            (self.metadata, self.blobs) = fresh.into_stores();
+            return Err(err);
+        }
+        let stale = std::mem::replace(self, fresh);
+        // rolled back seal restarts the epoch sealed before it, as it does on open
+        if self.checkpoints.len() == stale.checkpoints.len() {
+            self.epoch_started_at = stale.epoch_started_at;
+        }
+        self.epoch_policy = stale.epoch_policy;
+        self.tree_limit = stale.tree_limit;
+        self.keyring = stale.keyring;
+        self.proof_cache.get_mut().capacity = stale.proof_cache.into_inner().capacity;
+        self.quota = stale.quota;
+        self.collision_policy = stale.collision_policy;
+        self.quarantine = stale.quarantine;
+        self.shard = stale.shard;
+        self.leaf_hasher = stale.leaf_hasher;
+        self.keep_deleted = stale.keep_deleted;
+        self.idle_ttl = stale.idle_ttl;
+        self.reservation_ttl = stale.reservation_ttl;
+        self.rebuild_indexes(usize::MAX, SystemTime::now());
+        Ok(())
+    }
+
+    /// Stores new file, reservations come without name, content and hash
+    fn push_file(
+        &mut self,
+        owner: &str,
+        name: Option<(String, u32)>,
+        content: NewContent,
+        hash: Option<merkle::Sha3Hash>,
+    ) -> Result<FileId, StorageError> {
+        self.transaction(|storage| {
+            let id = storage.queue_file(owner, name, content, hash)?;
+            storage.commit_pending()?;
+            Ok(FileId::from_usize(id))
+        })
+    }
+
+    /// Same as [`Storage::push_file`] without appending the leaf, which waits in pending files
+    /// for the next [`Storage::commit_pending`]
+    fn queue_file(
+        &mut self,
+        owner: &str,
+        name: Option<(String, u32)>,
+        content: NewContent,
+        hash: Option<merkle::Sha3Hash>,
+    ) -> Result<usize, StorageError> {
+        self.has_room(1)?;
+        let (size, mime) = match &content {
+            NewContent::Memory(_) if hash.is_none() => (0, String::new()),
+            NewContent::Memory(content) => (content.len() as u64, detect_mime(content)),
+            NewContent::Staged { size, mime, .. } => (*size, mime.clone()),
+        };
+        let content_hash = match &content {
+            NewContent::Memory(content) if hash.is_some() => self.content_hash_of(content),
+            // recorded by the caller, which hashed it while staging
+            _ => None,
+        };
+        let mut file = FileMeta {
+            name: String::new(),
+            owner: owner.to_string(),
+            version: 0,
+            mime,
+            size,
+            leaf_index: self.leaf_count(),
+            hash,
+            expires_at: None,
+            expired: false,
+            deleted: None,
+            tombstone_index: None,
+            ipfs_cid: None,
+            undeleted_from: None,
+            undeleted_to: None,
+            held: None,
+            stored_at: SystemTime::now(),
+            downloads: 0,
+            last_access: None,
+            dataset: None,
+            dataset_leaf_index: None,
+            content_hash,
+        };
+        let named = name.is_some();
+        if let Some((name, version)) = name {
+            self.name_file(&mut file, name, version);
+        }
+        let id = self.metadata.insert(file.clone())?;
+        if named {
+            self.listing.insert(id, &file);
+        }
+        match content {
+            NewContent::Memory(content) if content.is_empty() => {}
+            NewContent::Memory(content) => self.blobs.put(id, content)?,
+            NewContent::Staged { path, .. } => self.blobs.put_file(id, path)?,
+        }
+        self.pending.push_back(id);
+        Ok(id)
+    }
+
+    /// Appends leaves of pending files to the tree until first unfilled reservation
+    fn commit_pending(&mut self) -> Result<(), StorageError> {
+        while let Some(id) = self.pending.front() {
+            let Some(hash) = self.metadata.get(*id)?.and_then(|file| file.hash) else {
+                break;
+            };
+            let change = LeafChange::Stored(*id);
+            self.changes
+                .insert(self.epoch_start + self.tree.len(), change);
+            self.append_leaf(hash)?;
+            self.pending.pop_front();
+        }
+        Ok(())
+    }
+
+    fn append_leaf(&mut self, hash: merkle::Sha3Hash) -> Result<(), StorageError> {
+        self.tree.append(hash.clone());
+        let root = self.tree.root().expect("leaf was just appended");
+        let (epoch, index) = (self.current_epoch(), self.tree.len() - 1);
+        self.hashes
+            .entry(hash.clone())
+            .or_insert(self.epoch_start + index);
+        self.metadata.append_leaf(hash, root.clone())?;
+        let cache = self.proof_cache.get_mut();
+        cache.refresh(epoch, self.epoch_start, &self.tree);
+        if let Some(proof) = self.tree.proof_for(index) {
+            cache.insert(self.epoch_start + index, LeafProof { epoch, proof, root });
+        }
+        let full = self.tree_limit.is_some_and(|limit| {
+            limit.when_full == WhenFull::Seal && self.tree.len() >= limit.max_leaves
+        });
+        if full
+            || self
+                .epoch_policy
+                .max_leaves
+                .is_some_and(|max_leaves| self.tree.len() >= max_leaves)
+        {
+            self.seal_epoch(SystemTime::now())?;
+        }
+        Ok(())
+    }
+
+    /// Fails unless `leaves` more fit into the current epoch tree, counting reserved slots and
+    /// files queued behind them. Full trees are sealed instead with [`WhenFull::Seal`].
+    fn has_room(&self, leaves: usize) -> Result<(), StorageError> {
+        match self.tree_limit {
+            Some(TreeLimit {
+                max_leaves,
+                when_full: WhenFull::Refuse,
+            }) if self.leaf_count() - self.epoch_start + leaves > max_leaves => {
+                Err(StorageError::TreeFull(max_leaves))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Persists signed checkpoint of the current epoch and starts a new empty tree
+    fn seal_epoch(&mut self, now: SystemTime) -> Result<Checkpoint, StorageError> {
+        let mut checkpoint = Checkpoint {
+            epoch: self.current_epoch(),
+            first_leaf: self.epoch_start as u64,
+            size: self.tree.len() as u64,
+            root: self.tree.root().expect("only non-empty epochs are sealed"),
+            sealed_at: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            key_id: self.keyring.active().map(ServerKey::key_id),
+            signature: None,
+        };
+        checkpoint.signature = self
+            .keyring
+            .active()
+            .map(|key| key.sign(&checkpoint.signed_message()));
+        self.push_checkpoint(checkpoint.clone(), now)?;
+        Ok(checkpoint)
+    }
+
+    /// Persists checkpoint of leaves after the last one and starts a new empty tree
+    fn push_checkpoint(
+        &mut self,
+        checkpoint: Checkpoint,
+        now: SystemTime,
+    ) -> Result<(), StorageError> {
+        self.metadata.seal_epoch(checkpoint.clone())?;
+        self.epoch_start += checkpoint.size as usize;
+        self.tree = merkle::Sha3Tree::new();
+        self.dead.clear();
+        self.epoch_started_at = now;
+        self.checkpoints.push(checkpoint);
+        Ok(())
+    }
+
+    /// Seals current epoch if it is older than allowed by epoch policy at `now`
+    pub fn rotate_if_due(&mut self, now: SystemTime) -> Result<Option<Checkpoint>, StorageError> {
+        self.writable()?;
+        let due = self.epoch_policy.max_age.is_some_and(|max_age| {
+            now.duration_since(self.epoch_started_at)
+                .is_ok_and(|age| age >= max_age)
+        });
+        if !due || self.tree.is_empty() {
+            return Ok(None);
+        }
+        self.seal_epoch(now).map(Some)
+    }
+
+    /// Epoch leaf was appended to together with index of its first leaf
+    fn epoch_of(&self, leaf_index: usize) -> (u32, usize) {
+        self.checkpoints
+            .iter()
+            .find(|c| leaf_index < (c.first_leaf + c.size) as usize)
+            .map_or((self.current_epoch(), self.epoch_start), |c| {
+                (c.epoch, c.first_leaf as usize)
+            })
+    }
+
+    /// Runs `f` with tree of given epoch holding leaves from epoch relative index `from`, sealed
+    /// ones (and the current one if those leaves are pruned) are rebuilt from persisted leaves
+    fn with_epoch_tree<R>(
+        &self,
+        epoch: u32,
+        from: usize,
+        f: impl FnOnce(&merkle::Sha3Tree) -> R,
+    ) -> Result<R, StorageError> {
+        let (first_leaf, size) = match self.checkpoints.get(epoch as usize) {
+            Some(checkpoint) => (checkpoint.first_leaf as usize, checkpoint.size as usize),
+            None if from >= self.tree.pruned() => return Ok(f(&self.tree)),
+            None => (self.epoch_start, self.tree.len()),
+        };
+        let leaves = self.metadata.leaves()?.into_iter();
+        let tree = merkle::Sha3Tree::from_manifest(leaves.skip(first_leaf).take(size));
+        Ok(f(&tree))
+    }
+
+    /// Remembers leaf of the current epoch as dead, see [`Storage::prune_dead`]
+    fn mark_dead(&mut self, leaf_index: usize) {
+        if leaf_index >= self.epoch_start + self.tree.pruned() {
+            self.dead.insert(leaf_index);
+        }
+    }
+
+    /// Prunes the longest prefix of the current epoch tree made of dead leaves only, so memory of
+    /// long-running servers doesn't grow with files deleted long ago. Proofs of those leaves are
+    /// still served from persisted leaves.
+    fn prune_dead(&mut self) {
+        let mut prefix = self.tree.pruned();
+        while self.dead.remove(&(self.epoch_start + prefix)) {
+            prefix += 1;
+        }
+        self.tree.prune(prefix);
+        self.proof_cache
+            .borrow_mut()
+            .forget_pruned(self.current_epoch(), self.epoch_start, prefix);
+    }
+
+    /// Amount of leading leaves pruned from the current epoch tree
+    pub fn pruned_leaves(&self) -> usize {
+        self.tree.pruned()
+    }
+
+    /// Proof of committed leaf against root of its epoch
+    fn leaf_proof(&self, leaf_index: usize) -> Result<LeafProof, StorageError> {
+        if let Some(proof) = self.proof_cache.borrow_mut().get(leaf_index) {
+            return Ok(proof);
+        }
+        let (epoch, first_leaf) = self.epoch_of(leaf_index);
+        let proof = self
+            .with_epoch_tree(epoch, leaf_index - first_leaf, |tree| {
+                Some(LeafProof {
+                    epoch,
+                    proof: tree.proof_for(leaf_index - first_leaf)?,
+                    root: tree.root()?,
+                })
+            })?
+            .ok_or(StorageError::NotFound)?;
+        self.proof_cache
+            .borrow_mut()
+            .insert(leaf_index, proof.clone());
+        Ok(proof)
+    }
+
+    /// Root, size and epoch of the tree holding leaf of file `id`, which right after the file is
+    /// stored is the root its leaf produced. None while the leaf waits for earlier reservations.
+    pub fn root_of_file(&self, id: FileId) -> Result<Option<RootHash>, StorageError> {
+        let id = id.as_usize();
+        let file = self.metadata.get(id)?.ok_or(StorageError::NotFound)?;
+        if file.hash.is_none() || !self.is_committed(&file) {
+            return Ok(None);
+        }
+        let proof = self.leaf_proof(file.leaf_index)?;
+        let size = match self.checkpoints.get(proof.epoch as usize) {
+            Some(checkpoint) => TreeSize(checkpoint.size),
+            None => self.tree.len().into(),
+        };
+        Ok(Some(RootHash {
+            hash: proof.root,
+            size,
+            epoch: proof.epoch,
+        }))
+    }
+
+    /// Receipt of committed file `id` signed with the active key at `now`, taken right after the
+    /// upload under the same lock. None if server has no key or the leaf waits for earlier
+    /// reservations.
+    pub fn upload_receipt(
+        &self,
+        id: FileId,
+        now: SystemTime,
+    ) -> Result<Option<UploadReceipt>, StorageError> {
+        let id = id.as_usize();
+        let Some(key) = self.keyring.active() else {
+            return Ok(None);
+        };
+        let Some(root) = self.root_of_file(FileId::from_usize(id))? else {
+            return Ok(None);
+        };
+        let file = self.metadata.get(id)?.ok_or(StorageError::NotFound)?;
+        let LeafProof { proof, .. } = self.leaf_proof(file.leaf_index)?;
+        let mut receipt = UploadReceipt {
+            id: FileId::from_usize(id),
+            leaf_index: file.leaf_index.into(),
+            leaf_hash: file.hash.ok_or(StorageError::NotFound)?,
+            proof,
+            epoch: root.epoch,
+            tree_size: root.size,
+            root: root.hash,
+            issued_at: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            key_id: key.key_id(),
+            signature: String::new(),
+        };
+        receipt.signature = key.sign(&receipt.signed_message());
+        Ok(Some(receipt))
+    }
+
+    /// Current root signed with the active server key, none without a key or leaves
+    pub fn signed_root(&self, now: SystemTime) -> Option<SignedRoot> {
+        let key = self.keyring.active()?;
+        let mut signed = SignedRoot {
+            epoch: self.current_epoch(),
+            tree_size: self.epoch_size().into(),
+            root: self.root_hash()?,
+            issued_at: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            public_key: key.public_key(),
+            signature: String::new(),
+        };
+        signed.signature = key.sign(&signed.signed_message());
+        Some(signed)
+    }
+
+    /// Index and proof of the first committed leaf with given hash, whether the file it belongs to
+    /// is still stored or not. Leaves not indexed yet are scanned.
+    pub fn proof_by_hash(
+        &self,
+        hash: &merkle::Sha3Hash,
+    ) -> Result<(LeafIndex, LeafProof), StorageError> {
+        let rebuild = &self.hash_rebuild;
+        let indexed = self.hashes.get(hash).copied();
+        let index = match indexed {
+            Some(index) if index < rebuild.indexed => Some(index),
+            // leaves appended since open come after all leaves waiting for the rebuild
+            _ => rebuild.leaves[rebuild.indexed.min(rebuild.leaves.len())..]
+                .iter()
+                .position(|leaf| leaf == hash)
+                .map(|position| rebuild.indexed + position)
+                .or(indexed),
+        }
+        .ok_or(StorageError::NotFound)?;
+        Ok((index.into(), self.leaf_proof(index)?))
+    }
+
+    /// Indexes next `batch` leaves persisted before storage was opened, returning whether the
+    /// rebuild is finished. Meant to be called in background with pauses in between.
+    pub fn rebuild_indexes(&mut self, batch: usize, now: SystemTime) -> bool {
+        let rebuild = &mut self.hash_rebuild;
+        if rebuild.finished_at.is_some() {
+            return true;
+        }
+        let end = rebuild.total.min(rebuild.indexed + batch.max(1));
+        for (index, leaf) in rebuild.leaves[rebuild.indexed..end].iter().enumerate() {
+            let index = rebuild.indexed + index;
+            self.hashes
+                .entry(leaf.clone())
+                .and_modify(|first| *first = index.min(*first))
+                .or_insert(index);
+        }
+        rebuild.indexed = end;
+        if end == rebuild.total {
+            rebuild.leaves = Vec::new();
+            rebuild.finished_at = Some(now);
+        }
+        rebuild.finished_at.is_some()
+    }
+
+    /// Progress of background index rebuilds
+    pub fn migrations(&self) -> Vec<Migration> {
+        let secs = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        let rebuild = &self.hash_rebuild;
+        vec![Migration {
+            name: "hash-index".to_string(),
+            done: rebuild.indexed as u64,
+            total: rebuild.total as u64,
+            started_at: secs(rebuild.started_at),
+            finished_at: rebuild.finished_at.map(secs),
+        }]
+    }
+
+    /// Sealed epoch with its leaves and contents of files which are not deleted, e.g. to move it to
+    /// cold storage
+    pub fn export_epoch(&self, epoch: u32) -> Result<EpochArchive, StorageError> {
+        let checkpoint = self
+            .checkpoints
+            .get(epoch as usize)
+            .cloned()
+            .ok_or(StorageError::NotFound)?;
+        let range =
+            checkpoint.first_leaf as usize..(checkpoint.first_leaf + checkpoint.size) as usize;
+        let leaves = self
+            .metadata
+            .leaves()?
+            .get(range.clone())
+            .map(<[_]>::to_vec)
+            .ok_or_else(|| StorageError::Backend(format!("leaves of epoch {epoch} are missing")))?;
+        let mut files = Vec::new();
+        for (id, file) in self.metadata.all()?.into_iter().enumerate() {
+            if file.deleted.is_none() && file.hash.is_some() && range.contains(&file.leaf_index) {
+                files.push(ArchivedFile {
+                    id: FileId::from_usize(id),
+                    content: self.content_of(id, &file)?,
+                    name: file.name,
+                    version: file.version,
+                    leaf_index: file.leaf_index.into(),
+                });
+            }
+        }
+        Ok(EpochArchive {
+            checkpoint,
+            leaves,
+            files,
+        })
+    }
+
+    /// Loads exported epoch after checking its leaves against checkpoint root, checkpoint
+    /// signature against server key if storage has one and every content against its leaf hash.
+    /// Epoch already sealed here must have the same checkpoint and only gets missing contents
+    /// restored. The next epoch is merged only while current epoch is empty, its files get new ids
+    /// and are owned by anonymous.
+    pub fn import_epoch(&mut self, archive: EpochArchive) -> Result<ImportedEpoch, StorageError> {
+        self.writable()?;
+        let EpochArchive {
+            checkpoint,
+            leaves,
+            files,
+        } = archive;
+        let invalid = |reason: &str| StorageError::InvalidArchive(reason.to_string());
+        if leaves.is_empty() || leaves.len() as u64 != checkpoint.size {
+            return Err(invalid("leaf count doesn't match checkpoint size"));
+        }
+        let root = merkle::Sha3Tree::from_manifest(leaves.iter().cloned()).root();
+        if root.as_ref() != Some(&checkpoint.root) {
+            return Err(invalid("leaves don't match checkpoint root"));
+        }
+        if !self.keyring.is_empty() && !self.keyring.verifies(&checkpoint) {
+            return Err(invalid("checkpoint is not signed with server key"));
+        }
+        let first_leaf = checkpoint.first_leaf as usize;
+        let mut seen = HashSet::new();
+        for file in &files {
+            let leaf = file
+                .leaf_index
+                .as_usize()
+                .checked_sub(first_leaf)
+                .and_then(|index| leaves.get(index));
+            let hash = self.leaf_hasher.leaf(&file.name, &file.content);
+            if leaf != Some(&hash) || !seen.insert(file.leaf_index) {
+                return Err(StorageError::InvalidArchive(format!(
+                    "content of file {} doesn't match its leaf",
+                    file.id
+                )));
+            }
+        }
+
+        match self.checkpoints.get(checkpoint.epoch as usize) {
+            Some(sealed) => {
+                if (sealed.first_leaf, sealed.size, &sealed.root)
+                    != (checkpoint.first_leaf, checkpoint.size, &checkpoint.root)
+                {
+                    return Err(invalid("checkpoint differs from the one sealed here"));
+                }
+                let sealed = sealed.clone();
+                let by_leaf: HashMap<usize, usize> = self
+                    .metadata
+                    .all()?
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, file)| file.deleted.is_none() && file.hash.is_some())
+                    .map(|(id, file)| (file.leaf_index, id))
+                    .collect();
+                let mut restored = 0;
+                for file in files {
+                    let Some(id) = by_leaf.get(&(file.leaf_index.as_usize())) else {
+                        continue;
+                    };
+                    if !file.content.is_empty() && self.blobs.get(*id)?.is_none() {
+                        self.blobs.put(*id, file.content)?;
+                        restored += 1;
+                    }
+                }
+                Ok(ImportedEpoch {
+                    checkpoint: sealed,
+                    files: restored,
+                    restored: true,
+                })
+            }
+            None if checkpoint.epoch == self.current_epoch() => {
+                if !self.tree.is_empty()
+                    || !self.pending.is_empty()
+                    || first_leaf != self.epoch_start
+                {
+                    return Err(StorageError::Conflict(
+                        "epoch can be merged only while current epoch is empty".to_string(),
+                    ));
+                }
+                for file in &files {
+                    validate_name(&file.name)?;
+                }
+                self.transaction(|storage| {
+                    let mut tree = merkle::Sha3Tree::new();
+                    for leaf in &leaves {
+                        tree.append(leaf.clone());
+                        let root = tree.root().expect("leaf was just appended");
+                        storage
+                            .hashes
+                            .entry(leaf.clone())
+                            .or_insert(first_leaf + tree.len() - 1);
+                        storage.metadata.append_leaf(leaf.clone(), root)?;
+                    }
+                    let count = files.len() as u32;
+                    for file in files {
+                        let usage = storage.usage.entry(ANONYMOUS.to_string()).or_default();
+                        usage.bytes += file.content.len() as u64;
+                        usage.files += 1;
+                        let name = storage.names.entry(file.name.clone()).or_default();
+                        name.live += 1;
+                        name.versions = name.versions.max(file.version);
+                        let meta = FileMeta {
+                            name: file.name,
+                            owner: ANONYMOUS.to_string(),
+                            version: file.version,
+                            mime: detect_mime(&file.content),
+                            size: file.content.len() as u64,
+                            leaf_index: file.leaf_index.as_usize(),
+                            hash: Some(leaves[file.leaf_index.as_usize() - first_leaf].clone()),
+                            expires_at: None,
+                            expired: false,
+                            deleted: None,
+                            tombstone_index: None,
+                            ipfs_cid: None,
+                            undeleted_from: None,
+                            undeleted_to: None,
+                            held: None,
+                            stored_at: SystemTime::now(),
+                            downloads: 0,
+                            last_access: None,
+                            dataset: None,
+                            dataset_leaf_index: None,
+                            content_hash: storage.content_hash_of(&file.content),
+                        };
+                        let id = storage.metadata.insert(meta.clone())?;
+                        storage.listing.insert(id, &meta);
+                        storage
+                            .changes
+                            .insert(meta.leaf_index, LeafChange::Stored(id));
+                        if !file.content.is_empty() {
+                            storage.blobs.put(id, file.content)?;
+                        }
+                    }
+                    storage.push_checkpoint(checkpoint.clone(), SystemTime::now())?;
+                    Ok(ImportedEpoch {
+                        checkpoint,
+                        files: count,
+                        restored: false,
+                    })
+                })
+            }
+            None => Err(StorageError::Conflict(format!(
+                "epoch {} can't be imported before epoch {}",
+                checkpoint.epoch,
+                self.current_epoch()
+            ))),
+        }
+    }
+
+    /// Checkpoints of sealed epochs, in order
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+
+    /// Epoch new leaves are appended to
+    pub fn current_epoch(&self) -> u32 {
+        self.checkpoints.len() as u32
+    }
+
+    /// Hex encoded key checkpoints are signed with
+    pub fn public_key(&self) -> Option<String> {
+        self.keyring.active().map(ServerKey::public_key)
+    }
+
+    /// Active key followed by retired ones
+    pub fn public_keys(&self) -> Vec<PublicKey> {
+        self.keyring.public_keys().iter().rev().cloned().collect()
+    }
+
+    /// Signs checkpoints sealed from `now` on with `key`, keeping the previous one published
+    pub fn rotate_key(
+        &mut self,
+        key: ServerKey,
+        now: SystemTime,
+    ) -> Result<PublicKey, StorageError> {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.keyring
+            .rotate(key, now)
+            .map_err(|err| StorageError::Conflict(err.to_string()))
+    }
+
+    fn writable(&self) -> Result<(), StorageError> {
+        match self.quarantine {
+            Some(_) => Err(StorageError::Quarantined),
+            None => Ok(()),
+        }
+    }
+
+    /// Bytes kept by blob store and its limit, if it has one
+    pub fn blob_capacity(&self) -> Option<BlobCapacity> {
+        self.blobs.capacity()
+    }
+
+    /// Shape of the current epoch tree, leaves of sealed epochs are not kept in it
+    pub fn tree_stats(&self) -> merkle::TreeStats {
+        self.tree.stats()
+    }
+
+    /// Divergence found by the last failed integrity check, if any
+    pub fn quarantine(&self) -> Option<&Divergence> {
+        self.quarantine.as_ref()
+    }
+
+    /// Recomputes leaves from stored contents (deleted files from their receipts) and roots from
+    /// those leaves, comparing both with persisted leaves, root chain and roots of sealed epochs.
+    /// Any mismatch puts storage into read-only quarantine, which is kept until restart.
+    pub fn check_integrity(&mut self) -> Result<Option<Divergence>, StorageError> {
+        let mut rehashed = Rehashed::default();
+        while self.rehash_files(&mut rehashed, usize::MAX)? {}
+        self.check_rehashed(rehashed)
+    }
+
+    /// Recomputes leaves of at most `count` more files for [`Storage::check_rehashed`], so
+    /// contents can be hashed in batches while storage keeps serving in between. Files stored
+    /// meanwhile are compared by their persisted leaves. False once all files are rehashed.
+    pub fn rehash_files(
+        &self,
+        rehashed: &mut Rehashed,
+        count: usize,
+    ) -> Result<bool, StorageError> {
+        for _ in 0..count {
+            let id = rehashed.next;
+            let Some(file) = self.metadata.get(id)? else {
+                return Ok(false);
+            };
+            rehashed.next += 1;
+            let Some(hash) = &file.hash else {
+                continue;
+            };
+            let leaf = match &file.deleted {
+                Some(receipt) => receipt.leaf_hash.clone(),
+                None => self
+                    .leaf_hasher
+                    .leaf(&file.name, &self.content_of(id, &file)?),
+            };
+            if &leaf != hash {
+                rehashed.diverged.push(file.leaf_index);
+            }
+            rehashed.leaves.push((file.leaf_index, leaf));
+        }
+        Ok(true)
+    }
+
+    /// Second half of [`Storage::check_integrity`], comparing leaves of [`Storage::rehash_files`]
+    /// and roots computed from them with persisted ones
+    pub fn check_rehashed(
+        &mut self,
+        rehashed: Rehashed,
+    ) -> Result<Option<Divergence>, StorageError> {
+        let leaves = self.metadata.leaves()?;
+        let roots = self.metadata.roots()?;
+        let mut recomputed = leaves.clone();
+        // every root after tampered leaf differs too, so roots only matter if leaves look fine
+        let mut diverged = rehashed.diverged;
+        let mut diverged_roots = Vec::new();
+        for (leaf_index, leaf) in rehashed.leaves {
+            if let Some(slot) = recomputed.get_mut(leaf_index) {
+                *slot = leaf;
+            }
+        }
+        let mut tree = merkle::Sha3Tree::new();
+        let mut checkpoints = self.checkpoints.iter().peekable();
+        for (index, leaf) in recomputed.into_iter().enumerate() {
+            let in_tree = match index.checked_sub(self.epoch_start) {
+                Some(index) if index >= self.tree.pruned() => Some(&leaf) == self.tree.leaf(index),
+                _ => true,
+            };
+            if Some(&leaf) != leaves.get(index) || !in_tree {
+                diverged.push(index);
+            }
+            tree.append(leaf);
+            if tree.root().as_ref() != roots.get(index) {
+                diverged_roots.push(index);
+            }
+            if let Some(checkpoint) =
+                checkpoints.next_if(|c| index + 1 == (c.first_leaf + c.size) as usize)
+            {
+                if tree.root().as_ref() != Some(&checkpoint.root) {
+                    diverged_roots.push(index);
+                }
+                tree = merkle::Sha3Tree::new();
+            }
+        }
+        let committed = self.epoch_start + self.tree.len();
+        if roots.len() != leaves.len() || committed != leaves.len() || checkpoints.next().is_some()
+        {
+            diverged_roots.push(leaves.len().min(roots.len()).min(committed));
+        }
+        if diverged.is_empty() {
+            diverged = diverged_roots;
+        }
+        let divergence = diverged
+            .iter()
+            .min()
+            .zip(diverged.iter().max())
+            .map(|(first, last)| Divergence {
+                first_leaf: (*first).into(),
+                last_leaf: (*last).into(),
+            });
+        if divergence.is_some() && self.quarantine.is_none() {
+            self.quarantine = divergence.clone();
+        }
+        Ok(divergence)
+    }
+
+    fn is_committed(&self, file: &FileMeta) -> bool {
+        file.leaf_index < self.epoch_start + self.tree.len()
+    }
+
+    fn content_of(&self, id: usize, file: &FileMeta) -> Result<Vec<u8>, StorageError> {
+        match self.blobs.get(id)? {
+            Some(content) => Ok(content),
+            None if file.size == 0 => Ok(Vec::new()),
+            None => Err(StorageError::Backend(format!(
+                "content of file {id} is missing"
+            ))),
+        }
+    }
+
+    /// Committed files which are not deleted in the order they were stored, expired ones
+    /// included. Records are read from metadata store one at a time as the iterator advances and
+    /// contents are not read at all, see [`Storage::file_content`].
+    pub fn list_all_files(&self) -> impl Iterator<Item = Result<FileInfo, StorageError>> + '_ {
+        self.listing
+            .ids(FileSort::Created, SortOrder::Asc)
+            .filter_map(|id| match self.metadata.get(id) {
+                Ok(Some(file)) if file.deleted.is_none() && self.is_committed(&file) => {
+                    Some(Ok(FileInfo {
+                        id: FileId::from_usize(id),
+                        name: file.name,
+                        size: file.size,
+                        hash: file.hash?,
+                    }))
+                }
+                Ok(_) => None,
+                Err(err) => Some(Err(err.into())),
+            })
+    }
+
+    /// Amount of committed files which are not deleted, the ones [`Storage::list_all_files`]
+    /// lists. Only files queued behind unfilled reservations are read to tell.
+    pub fn file_count(&self) -> Result<usize, StorageError> {
+        let live: usize = self.names.values().map(|usage| usage.live).sum();
+        let mut queued = 0;
+        for id in &self.pending {
+            // reserved slots get their name once filled
+            if self
+                .metadata
+                .get(*id)?
+                .is_some_and(|file| file.hash.is_some())
+            {
+                queued += 1;
+            }
+        }
+        Ok(live - queued)
+    }
+
+    /// Content of a committed file which is not deleted, without its proof
+    pub fn file_content(&self, id: FileId) -> Result<Vec<u8>, StorageError> {
+        let id = id.as_usize();
+        let file = self
+            .metadata
+            .get(id)?
+            .filter(|file| file.deleted.is_none() && self.is_committed(file))
+            .ok_or(StorageError::NotFound)?;
+        self.content_of(id, &file)
+    }
+
+    /// Same as [`Storage::list_all_files`] described for the api. Files expired at `now` are
+    /// hidden unless `include_expired` is set, which also lists ones already tombstoned by expiry.
+    pub fn list_files(
+        &self,
+        now: SystemTime,
+        include_expired: bool,
+    ) -> Result<Vec<File>, StorageError> {
+        self.list_files_by(now, include_expired, FileSort::Created, SortOrder::Asc)
+    }
+
+    /// Same as [`Storage::list_files`] in given order, walking index kept for it instead of
+    /// sorting the listing
+    pub fn list_files_by(
+        &self,
+        now: SystemTime,
+        include_expired: bool,
+        sort: FileSort,
+        order: SortOrder,
+    ) -> Result<Vec<File>, StorageError> {
+        let mut files = Vec::new();
+        for id in self.listing.ids(sort, order) {
+            let Some(file) = self.metadata.get(id)? else {
+                continue;
+            };
+            let listed = match file.deleted {
+                Some(_) => include_expired && file.expired,
+                None => include_expired || !is_expired(&file, now),
+            };
+            if listed && self.is_committed(&file) {
+                files.push(self.describe_file(id, &file));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Page of at most `limit` files listed by `query` in the tree as it was at its cursor, or now
+    /// if it has none. Files appended later are left out, files tombstoned later are still listed
+    /// as they were, so the listing stays the same snapshot from page to page. Expiry counts only
+    /// once the file is tombstoned.
+    pub fn list_files_page(
+        &self,
+        query: &FileListQuery,
+        limit: usize,
+    ) -> Result<(Vec<File>, Option<Cursor>), StorageError> {
+        let cursor = self.cursor_or_now(query.cursor)?;
+        let tree_size = cursor.tree_size as usize;
+        let after = match cursor.after {
+            Some(after) => {
+                let id = after as usize;
+                let file = self.metadata.get(id)?.ok_or_else(|| {
+                    StorageError::Conflict(format!("cursor points to unknown file {id}"))
+                })?;
+                Some((id, file))
+            }
+            None => None,
+        };
+        // cursors without the last listed file skip entries listed already
+        let skip = match after {
+            Some(_) => 0,
+            None => cursor.offset as usize,
+        };
+        let ids = self.listing.ids_after(
+            query.sort,
+            query.order,
+            after.as_ref().map(|(id, file)| (*id, file)),
+        );
+        let mut files: Vec<File> = Vec::new();
+        let mut listed = 0;
+        for id in ids {
+            let Some(file) = self.metadata.get(id)? else {
+                continue;
+            };
+            if file.held.is_some() || file.leaf_index >= tree_size {
+                continue;
+            }
+            let tombstoned = file
+                .tombstone_index
+                .is_some_and(|tombstone| tombstone < tree_size);
+            let in_snapshot = match query.deleted {
+                true => tombstoned,
+                false => !tombstoned || (query.include_expired && file.expired),
+            };
+            if !in_snapshot {
+                continue;
+            }
+            listed += 1;
+            if listed <= skip {
+                continue;
+            }
+            if files.len() == limit {
+                let next = Cursor {
+                    offset: cursor.offset + files.len() as u64,
+                    after: files.last().map(|file| file.id.as_usize() as u64),
+                    ..cursor
+                };
+                return Ok((files, Some(next)));
+            }
+            files.push(self.describe_file(id, &file));
+        }
+        Ok((files, None))
+    }
+
+    /// Page of at most `limit` leaves of the tree as it was at given cursor, or now
+    pub fn leaves_page(
+        &self,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<(Vec<merkle::Sha3Hash>, Option<Cursor>), StorageError> {
+        let cursor = self.cursor_or_now(cursor)?;
+        let (offset, tree_size) = (cursor.offset as usize, cursor.tree_size as usize);
+        let end = tree_size.min(offset.saturating_add(limit));
+        let leaves = self.metadata.leaves_in(offset.min(end)..end)?;
+        let next = (end < tree_size).then_some(Cursor {
+            offset: end as u64,
+            after: None,
+            ..cursor
+        });
+        Ok((leaves, next))
+    }
+
+    /// Cursors can't point past committed leaves, they would list leaves nobody committed yet
+    fn cursor_or_now(&self, cursor: Option<Cursor>) -> Result<Cursor, StorageError> {
+        let committed = (self.epoch_start + self.tree.len()) as u64;
+        match cursor {
+            Some(cursor) if cursor.tree_size > committed => Err(StorageError::Conflict(format!(
+                "cursor is past the {committed} committed leaves"
+            ))),
+            Some(cursor) => Ok(cursor),
+            None => Ok(Cursor {
+                tree_size: committed,
+                offset: 0,
+                after: None,
+            }),
+        }
+    }
+
+    /// Leaf of committed file. Content hash is only listed with name-hash leaves, it is kept
+    /// since upload, files stored before it was have their content read.
+    pub fn listed_leaf(&self, id: FileId) -> Result<ListedLeaf, StorageError> {
+        let id = id.as_usize();
+        let file = self
+            .metadata
+            .get(id)?
+            .filter(|c| self.is_committed(c))
+            .ok_or(StorageError::NotFound)?;
+        let hash = file.hash.clone().ok_or(StorageError::NotFound)?;
+        let content_hash = match (self.leaf_hasher.strategy(), &file.content_hash) {
+            (LeafHashing::NameAndHash, _) if file.deleted.is_some() => None,
+            (LeafHashing::NameAndHash, Some(content_hash)) => Some(content_hash.clone()),
+            (LeafHashing::NameAndHash, None) => Some(hash_content(self.content_of(id, &file)?)),
+            _ => None,
+        };
+        Ok(ListedLeaf {
+            leaf_index: file.leaf_index.into(),
+            hash,
+            content_hash,
+        })
+    }
+
+    /// Tombstoned files, both deleted and expired ones, in given order
+    pub fn list_deleted_by(
+        &self,
+        sort: FileSort,
+        order: SortOrder,
+    ) -> Result<Vec<File>, StorageError> {
+        let mut files = Vec::new();
+        for id in self.listing.ids(sort, order) {
+            if let Some(file) = self.metadata.get(id)?.filter(|file| file.deleted.is_some()) {
+                files.push(self.describe_file(id, &file));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Files committed at or after leaf `since` which are still listed by [`Storage::list_files`],
+    /// ids of files whose tombstones were committed since then and the committed tree size to
+    /// poll from next time. Only files of leaves appended since are read.
+    pub fn list_changes(
+        &self,
+        since: TreeSize,
+        now: SystemTime,
+    ) -> Result<(Vec<File>, Vec<FileId>, TreeSize), StorageError> {
+        self.list_changes_until(since, self.tree_size(), now)
+    }
+
+    /// [`Storage::list_changes`] of leaves before `until` only, so changes can be read in bounded
+    /// steps. Returns tree size to poll from next time, `until` unless the tree is smaller.
+    pub fn list_changes_until(
+        &self,
+        since: TreeSize,
+        until: TreeSize,
+        now: SystemTime,
+    ) -> Result<(Vec<File>, Vec<FileId>, TreeSize), StorageError> {
+        let until = until.min(self.tree_size());
+        let mut files = Vec::new();
+        let mut deleted = Vec::new();
+        for change in self
+            .changes
+            .range(since.as_usize()..until.as_usize().max(since.as_usize()))
+            .map(|(_, change)| *change)
+        {
+            match change {
+                LeafChange::Stored(id) => {
+                    let file = self.metadata.get(id)?.ok_or(StorageError::NotFound)?;
+                    if file.tombstone_index.is_none() && !is_expired(&file, now) {
+                        files.push(self.describe_file(id, &file));
+                    }
+                }
+                LeafChange::Tombstoned(id) => deleted.push(FileId::from_usize(id)),
+            }
+        }
+        files.sort_by_key(|file| file.id);
+        deleted.sort();
+        Ok((files, deleted, until))
+    }
+
+    /// Name and version of stored file, including ones waiting for reservations before them
+    pub fn describe(&self, id: FileId) -> Result<File, StorageError> {
+        let id = id.as_usize();
+        self.metadata
+            .get(id)?
+            .filter(|c| c.hash.is_some() && c.deleted.is_none())
+            .map(|c| self.describe_file(id, &c))
+            .ok_or(StorageError::NotFound)
+    }
+
+    /// Sets time after which file is tombstoned by [`Storage::delete_expired`]
+    pub fn expire_at(&mut self, id: FileId, expires_at: SystemTime) -> Result<(), StorageError> {
+        let id = id.as_usize();
+        self.writable()?;
+        let mut file = self
+            .metadata
+            .get(id)?
+            .filter(|c| c.deleted.is_none())
+            .ok_or(StorageError::NotFound)?;
+        file.expires_at = Some(expires_at);
+        Ok(self.metadata.update(id, file)?)
+    }
+
+    /// Tombstones files expired at `now`, or idle for longer than idle ttl, the same way as
+    /// [`Storage::delete_file`] does. Reservations older than reservation ttl are abandoned first:
+    /// their slot is filled with an unnamed empty file, which expires right away. Expired files are
+    /// kept while other leaf reservations are not filled and picked up by a later call. Downloads
+    /// are flushed first, so idle files are told by their last access.
+    pub fn delete_expired(
+        &mut self,
+        now: SystemTime,
+    ) -> Result<Vec<DeletionReceipt>, StorageError> {
+        self.flush_downloads()?;
+        self.writable()?;
+        self.abandon_reservations(now)?;
+        let expired: Vec<usize> = self
+            .metadata
+            .all()?
+            .into_iter()
+            .enumerate()
+            .filter(|(_, c)| {
+                c.deleted.is_none()
+                    && c.hash.is_some()
+                    && (is_expired(c, now) || self.is_idle(c, now))
+            })
+            .map(|(id, _)| id)
+            .collect();
+        let mut receipts = Vec::with_capacity(expired.len());
+        for id in expired {
+            let deleted = self.transaction(|storage| {
+                let receipt = storage.delete_file(FileId::from_usize(id))?;
+                let mut file = storage.metadata.get(id)?.ok_or(StorageError::NotFound)?;
+                file.expired = true;
+                storage.metadata.update(id, file)?;
+                Ok(receipt)
+            });
+            match deleted {
+                Ok(receipt) => receipts.push(receipt),
+                Err(StorageError::Conflict(_)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(receipts)
+    }
+
+    /// Fills reservations made longer than reservation ttl before `now` with an empty unnamed file
+    /// expiring at `now`, so leaves queued behind them get committed
+    fn abandon_reservations(&mut self, now: SystemTime) -> Result<(), StorageError> {
+        let Some(ttl) = self.reservation_ttl else {
+            return Ok(());
+        };
+        let mut abandoned = Vec::new();
+        for &id in &self.pending {
+            let Some(file) = self.metadata.get(id)? else {
+                continue;
+            };
+            let stale = now.duration_since(file.stored_at).unwrap_or_default() >= ttl;
+            if file.hash.is_none() && file.held.is_none() && stale {
+                abandoned.push((id, file));
+            }
+        }
+        if abandoned.is_empty() {
+            return Ok(());
+        }
+        let hash = self.leaf_hasher.leaf("", &[]);
+        self.transaction(|storage| {
+            for (id, mut file) in abandoned {
+                file.hash = Some(hash.clone());
+                file.expires_at = Some(now);
+                storage.metadata.update(id, file)?;
+            }
+            storage.commit_pending()
+        })
+    }
+
+    fn is_idle(&self, file: &FileMeta, now: SystemTime) -> bool {
+        self.idle_ttl.is_some_and(|idle_ttl| {
+            let accessed = file.last_access.unwrap_or(file.stored_at);
+            now.duration_since(accessed).unwrap_or_default() >= idle_ttl
+        })
+    }
+
+    /// Counts download of content of given files at `now` in memory, so serving content doesn't
+    /// write metadata. Listings count them in right away, metadata gets them by
+    /// [`Storage::flush_downloads`].
+    pub fn record_download(&mut self, ids: &[FileId], now: SystemTime) {
+        for id in ids.iter().map(|id| id.as_usize()) {
+            let (downloads, last_access) = self.pending_downloads.entry(id).or_insert((0, now));
+            *downloads += 1;
+            *last_access = now.max(*last_access);
+            self.downloads += 1;
+        }
+    }
+
+    /// Writes downloads counted since the last flush to metadata, e.g. periodically and once
+    /// server stops. Counters are kept next to the leaves, so they are written even while storage
+    /// is quarantined.
+    pub fn flush_downloads(&mut self) -> Result<(), StorageError> {
+        while let Some((id, (downloads, last_access))) = self.pending_downloads.pop_first() {
+            let written = self.metadata.get(id).and_then(|file| match file {
+                Some(mut file) => {
+                    file.downloads += downloads;
+                    file.last_access = file.last_access.max(Some(last_access));
+                    self.metadata.update(id, file)
+                }
+                None => Ok(()),
+            });
+            if let Err(err) = written {
+                self.pending_downloads.insert(id, (downloads, last_access));
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as [`describe`] with downloads not flushed yet counted in
+    fn describe_file(&self, id: usize, file: &FileMeta) -> File {
+        let mut described = describe(id, file);
+        if let Some((downloads, last_access)) = self.pending_downloads.get(&id) {
+            described.downloads += downloads;
+            described.last_access = Some(
+                last_access
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            );
+        }
+        described
+    }
+
+    /// Content downloads of all files so far
+    pub fn downloads(&self) -> u64 {
+        self.downloads
+    }
+
+    pub fn get_file_by_id(&self, id: FileId) -> Result<(String, Vec<u8>, LeafProof), StorageError> {
+        let id = id.as_usize();
+        let file = self.get_file_unless_cached(FileId::from_usize(id), |_| false)?;
+        Ok((file.name, file.content.unwrap_or_default(), file.proof))
+    }
+
+    /// Name, leaf hash and proof of committed file, with content only if `cached` says the caller
+    /// doesn't have content of that leaf already. Content under a leaf never changes.
+    pub fn get_file_unless_cached(
+        &self,
+        id: FileId,
+        cached: impl FnOnce(&merkle::Sha3Hash) -> bool,
+    ) -> Result<ProvenFile, StorageError> {
+        let id = id.as_usize();
+        let file = self
+            .metadata
+            .get(id)?
+            .filter(|c| c.deleted.is_none() && self.is_committed(c))
+            .ok_or(StorageError::NotFound)?;
+        let hash = file.hash.clone().ok_or(StorageError::NotFound)?;
+        let proof = self.leaf_proof(file.leaf_index)?;
+        let content = if cached(&hash) {
+            None
+        } else {
+            Some(self.content_of(id, &file)?)
+        };
+        Ok(ProvenFile {
+            name: file.name,
+            hash,
+            content,
+            proof,
+        })
+    }
+
+    /// Committed files ordered by id with single proof of all their leaves, duplicated ids are
+    /// returned once. Fails if any of the files is not found or files are from different epochs.
+    pub fn get_files_by_ids(&self, ids: &[FileId]) -> Result<FileBatch, StorageError> {
+        let mut ids: Vec<_> = ids.iter().map(|id| id.as_usize()).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let metas = ids
+            .into_iter()
+            .map(|id| {
+                let file = self
+                    .metadata
+                    .get(id)?
+                    .filter(|c| c.deleted.is_none() && self.is_committed(c))
+                    .ok_or(StorageError::NotFound)?;
+                Ok((id, file))
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        // sizes are checked before any content is read
+        if metas.iter().map(|(_, file)| file.size).sum::<u64>() > MAX_BATCH_BYTES {
+            return Err(StorageError::BatchTooLarge(MAX_BATCH_BYTES));
+        }
+        let mut files = Vec::with_capacity(metas.len());
+        for (id, file) in metas {
+            files.push(BatchFile {
+                id: FileId::from_usize(id),
+                content: self.content_of(id, &file)?,
+                name: file.name,
+                leaf_index: file.leaf_index.into(),
+            });
+        }
+        let Some(first) = files.first() else {
+            return Err(StorageError::Conflict("no files requested".to_string()));
+        };
+        let (epoch, first_leaf) = self.epoch_of(first.leaf_index.as_usize());
+        if files
+            .iter()
+            .any(|f| self.epoch_of(f.leaf_index.as_usize()).0 != epoch)
+        {
+            return Err(StorageError::Conflict(
+                "files of a batch must belong to the same epoch".to_string(),
+            ));
+        }
+        let leaf_indices: Vec<_> = files
+            .iter()
+            .map(|f| f.leaf_index.as_usize() - first_leaf)
+            .collect();
+        let (proof, root) = self
+            .with_epoch_tree(
+                epoch,
+                leaf_indices.iter().min().copied().unwrap_or(0),
+                |tree| Some((tree.multi_proof_for(&leaf_indices)?, tree.root()?)),
+            )?
+            .ok_or(StorageError::NotFound)?;
+        Ok(FileBatch {
+            files,
+            proof,
+            root,
+            epoch,
+            first_leaf: first_leaf.into(),
+        })
+    }
+
+    /// Proof of committed file leaf against root of its epoch
+    pub fn proof(&self, id: FileId) -> Result<LeafProof, StorageError> {
+        let id = id.as_usize();
+        let file = self
+            .metadata
+            .get(id)?
+            .filter(|c| c.deleted.is_none() && self.is_committed(c))
+            .ok_or(StorageError::NotFound)?;
+        self.leaf_proof(file.leaf_index)
+    }
+
+    /// Proof of committed file leaf against root of the first `tree_size` leaves of its epoch,
+    /// i.e. of the tree as it was back then. Not found if the leaf was not in it yet. Leaf index
+    /// within the epoch is returned with the proof.
+    pub fn historical_proof(
+        &self,
+        id: FileId,
+        tree_size: TreeSize,
+    ) -> Result<(LeafIndex, LeafProof), StorageError> {
+        let id = id.as_usize();
+        let tree_size = tree_size.as_usize();
+        let file = self
+            .metadata
+            .get(id)?
+            .filter(|c| c.held.is_none() && self.is_committed(c))
+            .ok_or(StorageError::NotFound)?;
+        let (epoch, first_leaf) = self.epoch_of(file.leaf_index);
+        let index = file.leaf_index - first_leaf;
+        if index >= tree_size {
+            return Err(StorageError::NotFound);
+        }
+        let proof = self
+            .with_epoch_tree(epoch, 0, |tree| {
+                if tree_size > tree.len() {
+                    return None;
+                }
+                let tree = merkle::Sha3Tree::from_manifest(tree.leaves().take(tree_size).cloned());
+                Some(LeafProof {
+                    epoch,
+                    proof: tree.proof_for(index)?,
+                    root: tree.root()?,
+                })
+            })?
+            .ok_or(StorageError::NotFound)?;
+        Ok((index.into(), proof))
+    }
+
+    /// Content type and at most `len` first bytes of committed file
+    pub fn preview(&self, id: FileId, len: usize) -> Result<(String, Vec<u8>), StorageError> {
+        let id = id.as_usize();
+        let file = self
+            .metadata
+            .get(id)?
+            .filter(|c| c.deleted.is_none() && self.is_committed(c))
+            .ok_or(StorageError::NotFound)?;
+        let mut content = self.content_of(id, &file)?;
+        content.truncate(len);
+        Ok((file.mime, content))
+    }
+
+    /// Same as [`Storage::delete_file`], refused unless the file belongs to `owner`
+    pub fn delete_file_as(
+        &mut self,
+        owner: &str,
+        id: FileId,
+    ) -> Result<DeletionReceipt, StorageError> {
+        self.check_owner(owner, id)?;
+        self.delete_file(id)
+    }
+
+    /// Refuses files uploaded with other credentials than `owner` with [`StorageError::NotOwner`]
+    pub fn check_owner(&self, owner: &str, id: FileId) -> Result<(), StorageError> {
+        let file = self
+            .metadata
+            .get(id.as_usize())?
+            .ok_or(StorageError::NotFound)?;
+        if file.owner != owner {
+            return Err(StorageError::NotOwner(id));
+        }
+        Ok(())
+    }
+
+    /// Drops file content, unless deleted contents are kept, and appends tombstone leaf for it.
+    /// Deleting already deleted file returns the original receipt.
+    pub fn delete_file(&mut self, id: FileId) -> Result<DeletionReceipt, StorageError> {
+        let id = id.as_usize();
+        self.writable()?;
+        let mut file = self.metadata.get(id)?.ok_or(StorageError::NotFound)?;
+        if let Some(receipt) = &file.deleted {
+            return Ok(receipt.clone());
+        }
+        if file.held.is_some() {
+            return Err(StorageError::Conflict(format!(
+                "file {id} was not committed by validation"
+            )));
+        }
+        if !self.pending.is_empty() {
+            return Err(StorageError::Conflict(
+                "files can't be deleted while leaf reservations are not filled".to_string(),
+            ));
+        }
+        let leaf_hash = file
+            .hash
+            .clone()
+            .expect("leaf hash should be present for committed file");
+        let leaf = self.leaf_proof(file.leaf_index)?;
+
+        self.has_room(1)?;
+        // tombstone may seal the epoch, so its proof is taken from whichever tree holds it
+        self.transaction(|storage| {
+            let tombstone_index = storage.leaf_count();
+            let tombstone_hash = tombstone_of(&leaf_hash);
+            storage
+                .changes
+                .insert(tombstone_index, LeafChange::Tombstoned(id));
+            storage.append_leaf(tombstone_hash.clone())?;
+            let tombstone = storage.leaf_proof(tombstone_index)?;
+            let receipt = DeletionReceipt {
+                id: FileId::from_usize(id),
+                leaf_hash,
+                leaf_proof: leaf.proof,
+                root_before: leaf.root,
+                tombstone_proof: tombstone.proof,
+                tombstone_hash,
+                root_after: tombstone.root,
+            };
+
+            if let Some(usage) = storage.usage.get_mut(&file.owner) {
+                usage.bytes -= file.size;
+            }
+            if let Some(usage) = storage.names.get_mut(&file.name) {
+                usage.live -= 1;
+            }
+            let leaf_index = file.leaf_index;
+            file.deleted = Some(receipt.clone());
+            file.tombstone_index = Some(tombstone_index);
+            storage.metadata.update(id, file)?;
+            if !storage.keep_deleted {
+                storage.blobs.remove(id)?;
+            }
+            storage.mark_dead(leaf_index);
+            storage.mark_dead(tombstone_index);
+            storage.prune_dead();
+            Ok(receipt)
+        })
+    }
+
+    /// Same as [`Storage::undelete_file`], refused unless the deleted file belonged to `owner`
+    pub fn undelete_file_as(&mut self, owner: &str, id: FileId) -> Result<FileId, StorageError> {
+        self.check_owner(owner, id)?;
+        self.undelete_file(id)
+    }
+
+    /// Appends content of deleted file again as a new file of the same owner, named and charged
+    /// as any other upload and referencing the deleted one. Deleted file keeps its tombstone, its
+    /// content moves to the new file, so it can be undeleted only once. Refused unless contents of
+    /// deleted files are kept, or while a live file has its name.
+    pub fn undelete_file(&mut self, id: FileId) -> Result<FileId, StorageError> {
+        let id = id.as_usize();
+        self.writable()?;
+        let mut deleted = self
+            .metadata
+            .get(id)?
+            // abandoned reservations have nothing to undelete
+            .filter(|c| c.deleted.is_some() && !c.name.is_empty())
+            .ok_or(StorageError::NotFound)?;
+        if !self.keep_deleted {
+            return Err(StorageError::Conflict(
+                "contents of deleted files are not kept, nothing can be undeleted".to_string(),
+            ));
+        }
+        if let Some(undeleted) = deleted.undeleted_to {
+            return Err(StorageError::Conflict(format!(
+                "file {id} was undeleted as file {undeleted} already"
+            )));
+        }
+        if self
+            .names
+            .get(&deleted.name)
+            .is_some_and(|usage| usage.live > 0)
+        {
+            return Err(StorageError::NameTaken(deleted.name));
+        }
+        let file = deleted.clone();
+        let content = match self.blobs.get(id)? {
+            Some(content) => content,
+            None if file.size == 0 => Vec::new(),
+            None => {
+                return Err(StorageError::Conflict(format!(
+                    "content of deleted file {id} is not kept anymore"
+                )))
+            }
+        };
+        let (name, version) = self.resolve_name(file.name)?;
+        let hash = self.leaf_hasher.leaf(&name, &content);
+        self.owns(&hash)?;
+        self.has_room(1)?;
+        self.charge(&file.owner, content.len() as u64, true)?;
+        self.transaction(|storage| {
+            let undeleted = storage.push_file(
+                &file.owner,
+                Some((name, version)),
+                NewContent::Memory(content),
+                Some(hash),
+            )?;
+            let mut restored = storage
+                .metadata
+                .get(undeleted.as_usize())?
+                .ok_or(StorageError::NotFound)?;
+            restored.undeleted_from = Some(id);
+            storage.metadata.update(undeleted.as_usize(), restored)?;
+            deleted.undeleted_to = Some(undeleted.as_usize());
+            storage.metadata.update(id, deleted)?;
+            storage.blobs.remove(id)?;
+            Ok(undeleted)
+        })
+    }
+
+    /// Same as [`Storage::record_ipfs_cid`], refused unless the file belongs to `owner`
+    pub fn record_ipfs_cid_as(
+        &mut self,
+        owner: &str,
+        id: FileId,
+        cid: String,
+    ) -> Result<(), StorageError> {
+        self.check_owner(owner, id)?;
+        self.record_ipfs_cid(id, cid)
+    }
+
+    /// Records CID the content of committed file was published to IPFS under, replacing earlier
+    /// one. Nothing in the tree changes, CID is kept next to the leaf of the file.
+    pub fn record_ipfs_cid(&mut self, id: FileId, cid: String) -> Result<(), StorageError> {
+        let id = id.as_usize();
+        self.writable()?;
+        let mut file = self
+            .metadata
+            .get(id)?
+            .filter(|c| c.deleted.is_none() && self.is_committed(c))
+            .ok_or(StorageError::NotFound)?;
+        file.ipfs_cid = Some(cid);
+        self.metadata.update(id, file)?;
+        Ok(())
+    }
+
+    /// Hash of content alone kept with files whose leaves are derived from it, see
+    /// [`Storage::listed_leaf`]
+    fn content_hash_of(&self, content: &[u8]) -> Option<merkle::Sha3Hash> {
+        (self.leaf_hasher.strategy() == LeafHashing::NameAndHash).then(|| hash_content(content))
+    }
+
+    /// Records hash of content of file stored from a staged file, which was hashed while it was
+    /// staged. Kept only with name-hash leaves, like the one of files stored from memory.
+    pub fn record_content_hash(
+        &mut self,
+        id: FileId,
+        content_hash: merkle::Sha3Hash,
+    ) -> Result<(), StorageError> {
+        if self.leaf_hasher.strategy() != LeafHashing::NameAndHash {
+            return Ok(());
+        }
+        let id = id.as_usize();
+        let mut file = self.metadata.get(id)?.ok_or(StorageError::NotFound)?;
+        file.content_hash = Some(content_hash);
+        Ok(self.metadata.update(id, file)?)
+    }
+
+    /// Checks that files can join dataset of given name, so uploads into a dataset are refused
+    /// before they are stored
+    pub fn check_dataset(&self, name: &str) -> Result<(), StorageError> {
+        validate_name(name)?;
+        if name.contains('/') {
+            return Err(InvalidName::Separator.into());
+        }
+        if !self.pending.is_empty() {
+            return Err(StorageError::Conflict(
+                "files can't join datasets while leaf reservations are not filled".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Appends leaf of committed file to the tree of given dataset, which its first file creates,
+    /// and dataset leaf of the grown dataset tree to the main tree. File joins one dataset at most
+    /// and stays in it once deleted, as its leaf does in the main tree.
+    pub fn join_dataset(&mut self, id: FileId, name: &str) -> Result<(), StorageError> {
+        let id = id.as_usize();
+        self.writable()?;
+        self.check_dataset(name)?;
+        let mut file = self
+            .metadata
+            .get(id)?
+            .filter(|c| c.deleted.is_none() && c.held.is_none() && self.is_committed(c))
+            .ok_or(StorageError::NotFound)?;
+        if let Some(dataset) = &file.dataset {
+            return Err(StorageError::Conflict(format!(
+                "file {id} already joined dataset {dataset}"
+            )));
+        }
+        let hash = file
+            .hash
+            .clone()
+            .expect("leaf hash should be present for committed file");
+        self.has_room(1)?;
+        self.transaction(|storage| {
+            let leaf_index = storage.leaf_count();
+            let dataset = storage.datasets.entry(name.to_string()).or_default();
+            dataset.tree.append(hash);
+            dataset.files.push(id);
+            dataset.leaf_index = leaf_index;
+            let root = dataset.tree.root().expect("leaf was just appended");
+            let leaf = dataset_leaf(name, &root, dataset.tree.len() as u64);
+            storage.append_leaf(leaf)?;
+            file.dataset = Some(name.to_string());
+            file.dataset_leaf_index = Some(leaf_index);
+            Ok(storage.metadata.update(id, file)?)
+        })
+    }
+
+    /// Dataset with its root proven by the latest dataset leaf, none if no file joined it
+    pub fn dataset(&self, name: &str) -> Result<Option<Dataset>, StorageError> {
+        let Some(dataset) = self.datasets.get(name) else {
+            return Ok(None);
+        };
+        let leaf = self.leaf_proof(dataset.leaf_index)?;
+        Ok(Some(Dataset {
+            name: name.to_string(),
+            size: dataset.tree.len() as u64,
+            root: dataset
+                .tree
+                .root()
+                .expect("datasets are created by their first file"),
+            leaf_index: dataset.leaf_index.into(),
+            proof: leaf.proof,
+            epoch: leaf.epoch,
+            tree_root: leaf.root,
+        }))
+    }
+
+    /// All datasets ordered by name
+    pub fn datasets(&self) -> Result<Vec<Dataset>, StorageError> {
+        self.datasets
+            .keys()
+            .filter_map(|name| self.dataset(name).transpose())
+            .collect()
+    }
+
+    /// Proof of file leaf against root of given dataset, none if the file didn't join it
+    pub fn dataset_proof(
+        &self,
+        name: &str,
+        id: FileId,
+    ) -> Result<Option<DatasetProof>, StorageError> {
+        let id = id.as_usize();
+        let Some((dataset, index)) = self.datasets.get(name).and_then(|dataset| {
+            let index = dataset.files.iter().position(|file| *file == id)?;
+            Some((dataset, index))
+        }) else {
+            return Ok(None);
+        };
+        let proof = dataset
+            .tree
+            .proof_for(index)
+            .expect("file leaf is in the dataset tree");
+        Ok(self.dataset(name)?.map(|dataset| DatasetProof {
+            id: FileId::from_usize(id),
+            index: index as u64,
+            proof,
+            dataset,
+        }))
+    }
+
+    pub fn deletion_receipt(&self, id: FileId) -> Result<Option<DeletionReceipt>, StorageError> {
+        let id = id.as_usize();
+        Ok(self.metadata.get(id)?.and_then(|c| c.deleted))
+    }
+
+    pub fn usage_of(&self, owner: &str) -> Usage {
+        self.usage.get(owner).cloned().unwrap_or_default()
+    }
+
+    pub fn all_usage(&self) -> Vec<(String, Usage)> {
+        self.usage
+            .iter()
+            .map(|(owner, usage)| (owner.clone(), usage.clone()))
+            .collect()
+    }
+
+    /// Hashes of all leaves committed to the tree, in order, including sealed epochs
+    pub fn leaves(&self) -> Result<Vec<merkle::Sha3Hash>, StorageError> {
+        Ok(self.metadata.leaves()?)
+    }
+
+    /// Root of the current epoch tree
+    pub fn root_hash(&self) -> Option<merkle::Sha3Hash> {
+        self.tree.root()
+    }
+
+    /// Consistency proof of epoch tree prefixes of given sizes together with root of the bigger
+    /// one (whole tree if its size is not given), none if sizes are out of its bounds or epoch
+    /// doesn't exist yet. Current epoch is used if none is given.
+    pub fn consistency_proof(
+        &self,
+        epoch: Option<u32>,
+        old_size: TreeSize,
+        new_size: Option<TreeSize>,
+    ) -> Result<Option<(merkle::Sha3ConsistencyProof, merkle::Sha3Hash)>, StorageError> {
+        let (old_size, new_size) = (old_size.as_usize(), new_size.map(TreeSize::as_usize));
+        let epoch = epoch.unwrap_or(self.current_epoch());
+        if epoch > self.current_epoch() {
+            return Ok(None);
+        }
+        let proof_of =
+            |tree: &merkle::Sha3Tree| Some((tree.consistency_proof(old_size)?, tree.root()?));
+        let from = match new_size {
+            Some(_) => 0,
+            None => old_size.saturating_sub(1),
+        };
+        self.with_epoch_tree(epoch, from, |tree| match new_size {
+            None => proof_of(tree),
+            Some(new_size) if new_size == tree.len() => proof_of(tree),
+            Some(new_size) if new_size < tree.len() => proof_of(&merkle::Sha3Tree::from_manifest(
+                tree.leaves().take(new_size).cloned(),
+            )),
+            Some(_) => None,
+        })
+    }
+
+    /// Amount of leaves in the current epoch tree
+    pub fn epoch_size(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Amount of leaves appended across all epochs, without pending reservations
+    pub fn tree_size(&self) -> TreeSize {
+        (self.epoch_start + self.tree.len()).into()
+    }
+
+    /// Stores state is kept in, read by [`crate::backup::start`]
+    pub fn stores(&self) -> (&dyn MetadataStore, &dyn BlobStore) {
+        (self.metadata.as_ref(), self.blobs.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::FileGroup;
+    use crate::leaf::name_hash_leaf;
+    use crate::signing::TrustedKey;
+    use std::time::Duration;
+
+    #[test]
+    fn test_delete_file_receipt() {
+        let mut storage = Storage::new();
+        storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        let id = storage
+            .add_new_file("b.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+        let root_before = storage.root_hash();
+
+        let receipt = storage.delete_file(id).expect("should delete");
+        assert!(receipt.verify());
+        assert_eq!(Some(receipt.root_before.clone()), root_before);
+        assert_eq!(Some(receipt.root_after.clone()), storage.root_hash());
+
+        assert!(storage.get_file_by_id(id).is_err());
+        let listed: Vec<_> = storage.list_all_files().map(Result::unwrap).collect();
+        assert_eq!(
+            listed,
+            [FileInfo {
+                id: FileId(0),
+                name: "a.txt".to_string(),
+                size: 5,
+                hash: hash_content(b"first"),
+            }]
+        );
+        assert_eq!(storage.file_count(), Ok(1));
+        assert_eq!(storage.file_content(FileId(0)), Ok(b"first".to_vec()));
+        assert_eq!(storage.file_content(id), Err(StorageError::NotFound));
+        // deleting again returns the same receipt and doesn't touch the tree
+        let again = storage.delete_file(id).expect("should return receipt");
+        assert_eq!(again.root_after, receipt.root_after);
+        assert_eq!(Some(receipt.root_after), storage.root_hash());
+
+        // files added after deletion are still provable
+        let id = storage
+            .add_new_file("c.txt".to_string(), b"third".to_vec())
+            .expect("should add");
+        let (_, content, proof) = storage.get_file_by_id(id).expect("should exist");
+        assert!(proof.proof.verify(
+            &storage.root_hash().expect("root exists"),
+            &hash_content(content)
+        ));
+    }
+
+    #[test]
+    fn test_empty_storage_and_content() {
+        let mut storage = Storage::new();
+        assert!(storage.root_hash().is_none());
+        assert!(storage
+            .list_files(SystemTime::now(), true)
+            .unwrap()
+            .is_empty());
+        assert_eq!(storage.list_all_files().count(), 0);
+        assert_eq!(storage.file_count(), Ok(0));
+        assert_eq!(
+            storage.get_file_by_id(FileId(0)).unwrap_err(),
+            StorageError::NotFound
+        );
+        assert_eq!(
+            storage.proof_by_hash(&hash_content(b"")).unwrap_err(),
+            StorageError::NotFound
+        );
+
+        let id = storage
+            .add_new_file("empty.txt".to_string(), Vec::new())
+            .expect("empty content is allowed");
+        let (_, content, proof) = storage.get_file_by_id(id).expect("should exist");
+        assert!(content.is_empty());
+        assert!(proof.proof.verify(
+            &storage.root_hash().expect("root exists"),
+            &hash_content(b"")
+        ));
+        assert_eq!(storage.file_content(id), Ok(Vec::new()));
+        let listed: Vec<_> = storage.list_all_files().map(Result::unwrap).collect();
+        assert_eq!((listed[0].size, &listed[0].hash), (0, &hash_content(b"")));
+        assert!(storage.proof_by_hash(&hash_content(b"")).is_ok());
+    }
+
+    #[test]
+    fn test_undelete_file() {
+        let mut storage = Storage::new().with_keep_deleted(true);
+        let id = storage
+            .add_new_file_as("alice", "a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        storage.delete_file(id).expect("should delete");
+        let deleted = storage
+            .list_deleted_by(FileSort::Created, SortOrder::Asc)
+            .expect("should list");
+        assert_eq!(deleted.len(), 1);
+        assert!(deleted[0].deleted);
+
+        let undeleted = storage.undelete_file(id).expect("should undelete");
+        assert_eq!(storage.leaf_count(), 3, "leaf, tombstone and new leaf");
+        let (name, content, proof) = storage.get_file_by_id(undeleted).expect("should exist");
+        assert_eq!(
+            (name.as_str(), content.as_slice()),
+            ("a.txt", &b"first"[..])
+        );
+        assert!(proof.proof.verify(
+            &storage.root_hash().expect("root exists"),
+            &hash_content(b"first")
+        ));
+        let file = storage.describe(undeleted).expect("should describe");
+        assert_eq!((file.version, file.undeleted_from), (2, Some(id)));
+        assert!(storage.deletion_receipt(id).expect("should get").is_some());
+        assert_eq!(storage.usage_of("alice").bytes, 5);
+
+        // content moved to the undeleted file
+        assert!(matches!(
+            storage.undelete_file(id),
+            Err(StorageError::Conflict(_))
+        ));
+        assert_eq!(
+            storage.undelete_file(undeleted),
+            Err(StorageError::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_undelete_refused_for_live_name_and_twice() {
+        let mut storage = Storage::new().with_keep_deleted(true);
+        let id = storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        storage.delete_file(id).expect("should delete");
+        let live = storage
+            .add_new_file("a.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+        assert_eq!(
+            storage.undelete_file(id),
+            Err(StorageError::NameTaken("a.txt".to_string()))
+        );
+        storage.delete_file(live).expect("should delete");
+        storage.undelete_file(id).expect("should undelete");
+
+        let empty = storage
+            .add_new_file("empty.txt".to_string(), Vec::new())
+            .expect("should add");
+        storage.delete_file(empty).expect("should delete");
+        let undeleted = storage.undelete_file(empty).expect("should undelete");
+        storage.delete_file(undeleted).expect("should delete");
+        assert!(matches!(
+            storage.undelete_file(empty),
+            Err(StorageError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_undelete_needs_kept_content() {
+        let mut storage = Storage::new();
+        let id = storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        storage.delete_file(id).expect("should delete");
+        assert!(matches!(
+            storage.undelete_file(id),
+            Err(StorageError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_record_ipfs_cid() {
+        let mut storage = Storage::new();
+        let id = storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        let root = storage.root_hash();
+        storage
+            .record_ipfs_cid(id, "bafkreiexample".to_string())
+            .expect("should record");
+        assert_eq!(storage.root_hash(), root, "tree is not touched");
+        let files = storage
+            .list_files(SystemTime::now(), false)
+            .expect("should list");
+        assert_eq!(files[0].ipfs_cid.as_deref(), Some("bafkreiexample"));
+
+        storage.delete_file(id).expect("should delete");
+        assert_eq!(
+            storage.record_ipfs_cid(id, "bafkreiexample".to_string()),
+            Err(StorageError::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_add_files_together() {
+        let batch = |names: &[&str]| -> Vec<_> {
+            names
+                .iter()
+                .map(|name| {
+                    (
+                        name.to_string(),
+                        name.as_bytes().to_vec(),
+                        hash_content(name),
+                    )
+                })
+                .collect()
+        };
+        let mut storage = Storage::new()
+            .with_collision_policy(CollisionPolicy::Reject)
+            .with_quota(Quota {
+                max_bytes: None,
+                max_files: Some(4),
+            });
+        storage
+            .add_new_file_as("ci", "a.txt".to_string(), b"a".to_vec())
+            .expect("should add");
+
+        // nothing is stored when any file of the batch is refused
+        assert_eq!(
+            storage.add_hashed_files_as("ci", batch(&["b.txt", "a.txt"])),
+            Err(StorageError::NameTaken("a.txt".to_string()))
+        );
+        assert_eq!(
+            storage.add_hashed_files_as("ci", batch(&["b.txt", "b.txt"])),
+            Err(StorageError::NameTaken("b.txt".to_string()))
+        );
+        assert_eq!(
+            storage.add_hashed_files_as("ci", batch(&["b.txt", "c.txt", "d.txt", "e.txt"])),
+            Err(QuotaExceeded::Files(4).into())
+        );
+        assert_eq!(storage.leaf_count(), 1);
+        assert_eq!(storage.usage_of("ci").files, 1);
+
+        let stored = storage
+            .add_hashed_files_as("ci", batch(&["b.txt", "c.txt"]))
+            .expect("should add");
+        assert_eq!(
+            stored,
+            [(FileId(1), LeafIndex(1)), (FileId(2), LeafIndex(2))]
+        );
+        let files = storage
+            .list_files(SystemTime::now(), false)
+            .expect("should list");
+        let names: Vec<_> = files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["a.txt", "b.txt", "c.txt"]);
+        let tree = merkle::Sha3Tree::from_manifest(
+            [b"a".to_vec(), b"b.txt".to_vec(), b"c.txt".to_vec()].map(hash_content),
+        );
+        assert_eq!(storage.root_hash(), tree.root());
+    }
+
+    #[test]
+    fn test_add_files_together_versions_names() {
+        let mut storage = Storage::new();
+        let files = ["a.txt", "a.txt"]
+            .map(|name| (name.to_string(), b"same".to_vec(), hash_content(b"same")));
+        let stored = storage
+            .add_hashed_files_as("ci", files.to_vec())
+            .expect("should add");
+        let versions: Vec<_> = stored
+            .iter()
+            .map(|(id, _)| storage.describe(*id).expect("should describe").version)
+            .collect();
+        assert_eq!(versions, [1, 2]);
+    }
+
+    #[test]
+    fn test_quota() {
+        let mut storage = Storage::new().with_quota(Quota {
+            max_bytes: Some(10),
+            max_files: Some(3),
+        });
+        let id = storage
+            .add_new_file_as("ci", "a.txt".to_string(), b"12345678".to_vec())
+            .expect("within quota");
+        assert_eq!(
+            storage.add_new_file_as("ci", "b.txt".to_string(), b"123".to_vec()),
+            Err(QuotaExceeded::Bytes(10).into())
+        );
+        // other credentials have their own quota
+        assert!(storage
+            .add_new_file_as("dev", "b.txt".to_string(), b"123".to_vec())
+            .is_ok());
+
+        // deleting file frees up space, but counts as uploaded file
+        storage.delete_file(id).expect("should delete");
+        assert_eq!(storage.usage_of("ci"), Usage { bytes: 0, files: 1 });
+        storage
+            .add_new_file_as("ci", "b.txt".to_string(), b"123".to_vec())
+            .expect("within quota");
+        storage
+            .add_new_file_as("ci", "c.txt".to_string(), b"123".to_vec())
+            .expect("within quota");
+        assert_eq!(
+            storage.add_new_file_as("ci", "d.txt".to_string(), b"1".to_vec()),
+            Err(QuotaExceeded::Files(3).into())
+        );
+    }
+
+    #[test]
+    fn test_get_file_unless_cached() {
+        let mut storage = Storage::new();
+        let id = storage
+            .add_new_file("a.txt".to_string(), b"content".to_vec())
+            .expect("should add");
+        let file = storage
+            .get_file_unless_cached(id, |_| false)
+            .expect("should get");
+        assert_eq!(file.content, Some(b"content".to_vec()));
+        assert_eq!(file.hash, hash_content(b"content"));
+        let cached = storage
+            .get_file_unless_cached(id, |hash| *hash == file.hash)
+            .expect("should get");
+        assert_eq!(cached.content, None);
+        assert_eq!(cached.proof, file.proof);
+    }
+
+    #[test]
+    fn test_blob_budget() {
+        let mut storage = Storage::open(
+            Box::<MemoryMetadata>::default(),
+            Box::new(MemoryBlobs::with_budget(10)),
+        )
+        .expect("should open");
+        storage
+            .add_new_file_as("ci", "a.txt".to_string(), b"12345678".to_vec())
+            .expect("should fit");
+        assert_eq!(
+            storage.add_new_file_as("dev", "b.txt".to_string(), b"123".to_vec()),
+            Err(StorageError::OutOfSpace(
+                3,
+                BlobCapacity { used: 8, max: 10 }
+            ))
+        );
+        assert!(matches!(
+            storage.add_new_file("b.txt".to_string(), b"123".to_vec()),
+            Err(StorageError::OutOfSpace(..))
+        ));
+        assert_eq!(storage.leaf_count(), 1);
+        assert_eq!(storage.usage_of("dev"), Usage::default());
+        assert_eq!(
+            storage.blob_capacity(),
+            Some(BlobCapacity { used: 8, max: 10 })
+        );
+    }
+
+    #[test]
+    fn test_reservations_commit_in_leaf_order() {
+        let mut storage = Storage::new();
+        let (first, first_leaf) = storage.reserve("ci").expect("should reserve");
+        let (second, second_leaf) = storage.reserve("ci").expect("should reserve");
+        assert_eq!((first_leaf, second_leaf), (LeafIndex(0), LeafIndex(1)));
+        let third = storage
+            .add_new_file("c.txt".to_string(), b"third".to_vec())
+            .expect("should add");
+        assert_eq!(storage.leaf_count(), 3);
+
+        // nothing is committed until first slot is filled
+        storage
+            .fill_reservation(
+                second,
+                "ci",
+                "b.txt".to_string(),
+                b"second".to_vec(),
+                hash_content(b"second"),
+            )
+            .expect("should fill");
+        assert!(storage.root_hash().is_none());
+        assert_eq!(storage.list_all_files().count(), 0);
+        assert_eq!(storage.file_count(), Ok(0), "queued files are not counted");
+        assert!(storage.get_file_by_id(third).is_err());
+        assert!(storage.delete_file(third).is_err());
+        assert_eq!(
+            storage.fill_reservation(
+                first,
+                "dev",
+                "a.txt".to_string(),
+                b"first".to_vec(),
+                hash_content(b"first"),
+            ),
+            Err(StorageError::Conflict(
+                "file 0 is reserved by other credential".to_string()
+            ))
+        );
+        storage
+            .fill_reservation(
+                first,
+                "ci",
+                "a.txt".to_string(),
+                b"first".to_vec(),
+                hash_content(b"first"),
+            )
+            .expect("should fill");
+
+        let expected = merkle::Sha3Tree::from_manifest(vec![
+            hash_content(b"first"),
+            hash_content(b"second"),
+            hash_content(b"third"),
+        ]);
+        assert_eq!(storage.root_hash(), expected.root());
+        assert_eq!(storage.list_all_files().count(), 3);
+        assert_eq!(storage.file_count(), Ok(3));
+    }
+
+    #[test]
+    fn test_unfilled_reservations_expire() {
+        let mut storage = Storage::new().with_reservation_ttl(Some(Duration::from_secs(60)));
+        let (abandoned, _) = storage.reserve("ci").expect("should reserve");
+        let queued = storage
+            .add_new_file("a.txt".to_string(), b"queued".to_vec())
+            .expect("should add");
+        let now = SystemTime::now();
+        assert!(storage
+            .delete_expired(now)
+            .expect("should expire")
+            .is_empty());
+        assert!(storage.root_hash().is_none());
+
+        let receipts = storage
+            .delete_expired(now + Duration::from_secs(61))
+            .expect("should expire");
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].id, abandoned);
+        assert_eq!(receipts[0].leaf_hash, hash_content(b""));
+        assert_eq!(
+            storage.tree_size().as_usize(),
+            3,
+            "slot, queued file, tombstone"
+        );
+        let names: Vec<_> = storage
+            .list_all_files()
+            .map(|file| file.expect("should read").name)
+            .collect();
+        assert_eq!(names, ["a.txt"]);
+        assert!(storage.get_file_by_id(queued).is_ok());
+        assert!(matches!(
+            storage.fill_reservation(
+                abandoned,
+                "ci",
+                "b.txt".to_string(),
+                b"late".to_vec(),
+                hash_content(b"late"),
+            ),
+            Err(StorageError::Conflict(_))
+        ));
+        assert_eq!(
+            storage.undelete_file(abandoned),
+            Err(StorageError::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_two_phase_ingestion() {
+        let dir = std::env::temp_dir().join("safe_storage_held_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("should create dir");
+        let staged = |name: &str, content: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).expect("should write");
+            path
+        };
+        let hold = |storage: &mut Storage, name: &str, content: &[u8]| {
+            let mime = detect_mime(content);
+            storage
+                .hold_file_as("ci", name.to_string(), 5, mime, hash_content(content))
+                .expect("should hold")
+        };
+        let status = |storage: &Storage, id| {
+            storage
+                .file_status(id, SystemTime::now())
+                .expect("should exist")
+        };
+
+        let mut storage = Storage::new();
+        let held = hold(&mut storage, "a.txt", b"first");
+        let other = storage
+            .add_new_file("b.txt".to_string(), b"other".to_vec())
+            .expect("should add");
+        assert_eq!(status(&storage, held).status, IngestStatus::Pending);
+        assert_eq!(status(&storage, other).status, IngestStatus::Committed);
+        assert_eq!(storage.leaf_count(), 1, "held file takes no leaf slot");
+        assert!(storage.get_file_by_id(held).is_err());
+        assert!(storage.delete_file(held).is_err());
+        assert!(matches!(
+            storage.fill_reservation(
+                held,
+                "ci",
+                "a.txt".to_string(),
+                b"forged".to_vec(),
+                hash_content(b"forged")
+            ),
+            Err(StorageError::Conflict(_))
+        ));
+
+        let leaf_index = storage
+            .release_file(held, &staged("a", b"first"))
+            .expect("should release");
+        assert_eq!(leaf_index, LeafIndex(1));
+        let released = status(&storage, held);
+        assert_eq!(released.status, IngestStatus::Committed);
+        assert_eq!(released.hash, Some(hash_content(b"first")));
+        let root = released.root.expect("should have root");
+        assert!(released
+            .proof
+            .expect("should have proof")
+            .verify(&root.hash, &hash_content(b"first")));
+        assert_eq!(
+            storage.get_file_by_id(held).expect("should get").1,
+            b"first"
+        );
+
+        let rejected = hold(&mut storage, "c.txt", b"virus");
+        storage
+            .reject_file(rejected, "infected".to_string())
+            .expect("should reject");
+        let rejected = status(&storage, rejected);
+        assert_eq!(rejected.status, IngestStatus::Rejected);
+        assert_eq!(rejected.reason.as_deref(), Some("infected"));
+        assert_eq!(storage.usage_of("ci").bytes, 5);
+
+        // files released after a reservation was made are committed after it
+        let late = hold(&mut storage, "d.txt", b"later");
+        let (reserved, _) = storage.reserve("ci").expect("should reserve");
+        storage
+            .release_file(late, &staged("d", b"later"))
+            .expect("should release");
+        let interrupted = hold(&mut storage, "e.txt", b"never");
+        let (metadata, blobs) = storage.into_stores();
+        let mut storage = Storage::open(metadata, blobs).expect("should reopen");
+        assert_eq!(status(&storage, interrupted).status, IngestStatus::Rejected);
+        storage
+            .fill_reservation(
+                reserved,
+                "ci",
+                "f.txt".to_string(),
+                b"filled".to_vec(),
+                hash_content(b"filled"),
+            )
+            .expect("should fill");
+        let expected = merkle::Sha3Tree::from_manifest(
+            ["other", "first", "filled", "later"].map(hash_content),
+        );
+        assert_eq!(storage.root_hash(), expected.root());
+        std::fs::remove_dir_all(&dir).expect("should remove dir");
+    }
+
+    #[test]
+    fn test_historical_proof() {
+        let mut storage = Storage::new();
+        let first = storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        let root_of_one = storage.root_hash().expect("should have root");
+        let second = storage
+            .add_new_file("b.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+        storage
+            .add_new_file("c.txt".to_string(), b"third".to_vec())
+            .expect("should add");
+        storage.delete_file(first).expect("should delete");
+
+        let (index, proof) = storage
+            .historical_proof(first, TreeSize(1))
+            .expect("should prove");
+        assert_eq!((index, proof.epoch), (LeafIndex(0), 0));
+        assert_eq!(proof.root, root_of_one);
+        assert!(proof.proof.verify(&root_of_one, &hash_content(b"first")));
+        let (index, proof) = storage
+            .historical_proof(second, TreeSize(3))
+            .expect("should prove");
+        assert_eq!(index, LeafIndex(1));
+        assert!(proof.proof.verify(&proof.root, &hash_content(b"second")));
+        assert_ne!(Some(proof.root), storage.root_hash());
+
+        assert_eq!(
+            storage.historical_proof(second, TreeSize(1)).err(),
+            Some(StorageError::NotFound)
+        );
+        assert_eq!(
+            storage.historical_proof(second, TreeSize(5)).err(),
+            Some(StorageError::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_listed_leaf() {
+        let mut storage = Storage::new();
+        let id = storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        let leaf = storage.listed_leaf(id).expect("should list leaf");
+        assert_eq!((leaf.leaf_index, leaf.content_hash), (LeafIndex(0), None));
+        assert_eq!(leaf.hash, hash_content(b"first"));
+
+        let mut storage = Storage::new().with_leaf_hasher(LeafHashing::NameAndHash.hasher());
+        storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        let id = storage
+            .add_new_file("b.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+        let leaf = storage.listed_leaf(id).expect("should list leaf");
+        let content_hash = hash_content(b"second");
+        assert_eq!(leaf.leaf_index, LeafIndex(1));
+        assert_eq!(leaf.hash, name_hash_leaf("b.txt", &content_hash));
+        assert_eq!(leaf.content_hash, Some(content_hash.clone()));
+        assert_eq!(storage.listed_leaf(FileId(5)), Err(StorageError::NotFound));
+        // content hash kept since upload is listed without reading the content
+        storage.blobs.remove(id.as_usize()).expect("should remove");
+        let leaf = storage.listed_leaf(id).expect("should list leaf");
+        assert_eq!(leaf.content_hash, Some(content_hash));
+    }
+
+    #[test]
+    fn test_leaf_hasher() {
+        let hashing = LeafHashing::NameAndContent;
+        let mut storage = Storage::new().with_leaf_hasher(hashing.hasher());
+        let first = storage
+            .add_new_file("a.txt".to_string(), b"same".to_vec())
+            .expect("should add");
+        storage
+            .add_new_file("b.txt".to_string(), b"same".to_vec())
+            .expect("should add");
+        let expected = merkle::Sha3Tree::from_manifest(vec![
+            hashing.hasher().leaf("a.txt", b"same"),
+            hashing.hasher().leaf("b.txt", b"same"),
+        ]);
+        assert_eq!(storage.root_hash(), expected.root());
+        let (_, content, proof) = storage.get_file_by_id(first).expect("should get");
+        assert!(!proof.proof.verify(&proof.root, &hash_content(&content)));
+        assert_eq!(storage.check_integrity(), Ok(None));
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("a.txt").is_ok());
+        assert!(validate_name("docs/a.txt").is_ok());
+        assert_eq!(validate_name(""), Err(InvalidName::Empty));
+        assert_eq!(validate_name(&"a".repeat(256)), Err(InvalidName::TooLong));
+        assert_eq!(validate_name("a\nb"), Err(InvalidName::ControlCharacter));
+        for name in [
+            "../../etc/passwd",
+            "/etc/passwd",
+            "a/./b",
+            "a//b",
+            "..\\a",
+            "docs/",
+        ] {
+            assert_eq!(validate_name(name), Err(InvalidName::Traversal), "{name}");
+        }
+        for name in [
+            "😀.txt",
+            "報告/年度.txt",
+            "café.txt",
+            "docs/conf/a",
+            "console.txt",
+        ] {
+            assert_eq!(validate_name(name), Ok(()), "{name}");
+        }
+        assert_eq!(
+            validate_name("cafe\u{301}.txt"),
+            Err(InvalidName::NotNormalized)
+        );
+        for name in [
+            "CON",
+            "docs/nul.txt",
+            "Com1.log",
+            "lpt9",
+            "a.",
+            "a ",
+            "a?b",
+            "a:b",
+        ] {
+            assert!(
+                matches!(validate_name(name), Err(InvalidName::Reserved(_))),
+                "{name}"
+            );
+        }
+        // limit counts bytes, 63 emoji fit while 64 don't
+        assert!(validate_name(&"😀".repeat(63)).is_ok());
+        assert_eq!(validate_name(&"😀".repeat(64)), Err(InvalidName::TooLong));
+
+        let mut storage = Storage::new();
+        assert_eq!(
+            storage.add_new_file("../../etc/passwd".to_string(), b"root".to_vec()),
+            Err(StorageError::InvalidName(InvalidName::Traversal))
+        );
+        assert!(storage.root_hash().is_none());
+    }
+
+    #[test]
+    fn test_names_are_normalized() {
+        let mut storage = Storage::new().with_collision_policy(CollisionPolicy::Reject);
+        let id = storage
+            .add_new_file("cafe\u{301}.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        assert_eq!(
+            storage.describe(id).expect("should describe").name,
+            "café.txt"
+        );
+        assert_eq!(
+            storage.add_new_file("café.txt".to_string(), b"second".to_vec()),
+            Err(StorageError::NameTaken("café.txt".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_collision_policies() {
+        let mut storage = Storage::new().with_collision_policy(CollisionPolicy::Reject);
+        storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        assert_eq!(
+            storage.add_new_file("a.txt".to_string(), b"second".to_vec()),
+            Err(StorageError::NameTaken("a.txt".to_string()))
+        );
+
+        let mut storage = Storage::new().with_collision_policy(CollisionPolicy::Suffix);
+        for content in [b"1", b"2", b"3"] {
+            storage
+                .add_new_file("docs/a.txt".to_string(), content.to_vec())
+                .expect("should add");
+        }
+        let names: Vec<String> = storage
+            .list_files(SystemTime::now(), false)
+            .expect("should list")
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, vec!["docs/a.txt", "docs/a-1.txt", "docs/a-2.txt"]);
+
+        let mut storage = Storage::new();
+        let first = storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        storage.delete_file(first).expect("should delete");
+        let second = storage
+            .add_new_file("a.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+        let third = storage
+            .add_new_file("a.txt".to_string(), b"third".to_vec())
+            .expect("should add");
+        assert_eq!(storage.describe(second).map(|f| f.version), Ok(2));
+        assert_eq!(storage.describe(third).map(|f| f.version), Ok(3));
+    }
+
+    #[test]
+    fn test_mime_and_preview() {
+        let mut storage = Storage::new();
+        let png = storage
+            .add_new_file(
+                "a.png".to_string(),
+                b"\x89PNG\r\n\x1a\nrest of image".to_vec(),
+            )
+            .expect("should add");
+        let text = storage
+            .add_new_file("a.txt".to_string(), b"hello".to_vec())
+            .expect("should add");
+        let binary = storage
+            .add_new_file("a.bin".to_string(), vec![0xff, 0xfe, 0x00])
+            .expect("should add");
+
+        assert_eq!(
+            storage.preview(png, 8),
+            Ok(("image/png".to_string(), b"\x89PNG\r\n\x1a\n".to_vec()))
+        );
+        assert_eq!(
+            storage.preview(text, 1024),
+            Ok(("text/plain; charset=utf-8".to_string(), b"hello".to_vec()))
+        );
+        assert_eq!(
+            storage.describe(binary).map(|f| f.mime),
+            Ok(DEFAULT_MIME.to_string())
+        );
+        storage.delete_file(text).expect("should delete");
+        assert_eq!(storage.preview(text, 1024), Err(StorageError::NotFound));
+    }
+
+    #[test]
+    fn test_add_staged_file() {
+        let dir = std::env::temp_dir().join("safe_storage_staged_file_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("should create dir");
+        let staged = dir.join("upload");
+        std::fs::write(&staged, "ąčę").expect("should write");
+
+        let mut storage = Storage::new().with_quota(Quota {
+            max_files: None,
+            max_bytes: Some(6),
+        });
+        let id = storage
+            .add_staged_file_as(
+                "alice",
+                "a.txt".to_string(),
+                &staged,
+                6,
+                detect_mime_of_head(&"ąčę".as_bytes()[..3]),
+                hash_content("ąčę"),
+            )
+            .expect("should add");
+        assert!(!staged.exists(), "staged file is moved into blob store");
+        let (name, content, proof) = storage.get_file_by_id(id).expect("should get");
+        assert_eq!(
+            (name.as_str(), content),
+            ("a.txt", "ąčę".as_bytes().to_vec())
+        );
+        assert!(proof.proof.verify(&proof.root, &hash_content("ąčę")));
+        assert_eq!(
+            storage.describe(id).map(|f| f.mime),
+            Ok("text/plain; charset=utf-8".to_string())
+        );
+
+        // refused file leaves staged content in place for the caller to clean up
+        std::fs::write(&staged, b"more").expect("should write");
+        let refused = storage.add_staged_file_as(
+            "alice",
+            "b.txt".to_string(),
+            &staged,
+            4,
+            DEFAULT_MIME.to_string(),
+            hash_content(b"more"),
+        );
+        assert!(matches!(refused, Err(StorageError::QuotaExceeded(_))));
+        assert!(staged.exists());
+        std::fs::remove_dir_all(&dir).expect("should remove dir");
+    }
+
+    #[test]
+    fn test_shard_range() {
+        assert_eq!(ShardRange::of(0, 1).to_string(), "00-ff");
+        assert_eq!(ShardRange::of(1, 2).to_string(), "80-ff");
+        assert_eq!(ShardRange::of(1, 3).to_string(), "56-aa");
+        assert_eq!(ShardRange::of(0, MAX_SHARDS).to_string(), "00-00");
+        assert_eq!(
+            ShardRange::of(MAX_SHARDS - 1, MAX_SHARDS).to_string(),
+            "ff-ff"
+        );
+        assert_eq!(
+            "56-aa".parse::<ShardRange>().ok(),
+            Some(ShardRange::of(1, 3))
+        );
+        assert!("aa-56".parse::<ShardRange>().is_err());
+        assert!("56".parse::<ShardRange>().is_err());
+        for i in 0u32..256 {
+            let hash = hash_content(i.to_be_bytes());
+            for count in (1..=5).chain([MAX_SHARDS]) {
+                let shard = ShardRange::shard_of(&hash, count);
+                let owners: Vec<_> = (0..count)
+                    .filter(|index| ShardRange::of(*index, count).contains(&hash))
+                    .collect();
+                assert_eq!(owners, vec![shard]);
+            }
+        }
+
+        let (owned, foreign): (Vec<_>, Vec<_>) = (0u32..64)
+            .map(|i| i.to_string())
+            .partition(|content| ShardRange::of(0, 2).contains(&hash_content(content)));
+        let mut storage = Storage::new().with_shard(Some(ShardRange::of(0, 2)));
+        storage
+            .add_new_file("a.txt".to_string(), owned[0].clone().into_bytes())
+            .expect("should add");
+        assert!(matches!(
+            storage.add_new_file("b.txt".to_string(), foreign[0].clone().into_bytes()),
+            Err(StorageError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_expect_tree_size() {
+        let mut storage = Storage::new();
+        assert_eq!(storage.expect_tree_size(TreeSize(0)), Ok(()));
+        storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        assert!(matches!(
+            storage.expect_tree_size(TreeSize(0)),
+            Err(StorageError::Conflict(_))
+        ));
+        storage.reserve(ANONYMOUS).expect("should reserve");
+        assert_eq!(storage.expect_tree_size(TreeSize(2)), Ok(()));
+    }
+
+    #[test]
+    fn test_root_of_file() {
+        let mut storage = Storage::new().with_epoch_policy(EpochPolicy {
+            max_leaves: Some(2),
+            max_age: None,
+        });
+        let first = storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        let root = storage.root_of_file(first).expect("should get");
+        assert_eq!(
+            root.map(|r| (r.hash, r.size, r.epoch)),
+            Some((
+                storage.root_hash().expect("should have root"),
+                TreeSize(1),
+                0
+            ))
+        );
+
+        // leaf sealing the epoch gets root of its checkpoint
+        let second = storage
+            .add_new_file("b.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+        let root = storage
+            .root_of_file(second)
+            .expect("should get")
+            .expect("should be committed");
+        let checkpoint = &storage.checkpoints()[0];
+        assert_eq!(
+            (&root.hash, root.size, root.epoch),
+            (&checkpoint.root, TreeSize(2), 0)
+        );
+
+        let (reserved, _) = storage.reserve(ANONYMOUS).expect("should reserve");
+        let waiting = storage
+            .add_new_file("c.txt".to_string(), b"third".to_vec())
+            .expect("should add");
+        assert!(storage.root_of_file(waiting).expect("should get").is_none());
+        assert!(storage
+            .root_of_file(reserved)
+            .expect("should get")
+            .is_none());
+    }
+
+    #[test]
+    fn test_upload_receipt() {
+        let key: ServerKey = "01".repeat(32).parse().expect("should parse");
+        let trusted: TrustedKey = key.public_key().parse().expect("should parse");
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut storage = Storage::new();
+        let id = storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        assert!(storage
+            .upload_receipt(id, now)
+            .expect("should get")
+            .is_none());
+
+        let mut storage = storage.with_server_key(Some(key));
+        let id = storage
+            .add_new_file("b.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+        let receipt = storage
+            .upload_receipt(id, now)
+            .expect("should get")
+            .expect("should be signed");
+        assert_eq!(
+            (
+                receipt.id,
+                receipt.leaf_index,
+                receipt.tree_size,
+                receipt.issued_at
+            ),
+            (id, LeafIndex(1), TreeSize(2), 1000)
+        );
+        assert_eq!(receipt.leaf_hash, hash_content(b"second"));
+        assert_eq!(Some(&receipt.root), storage.root_hash().as_ref());
+        assert!(trusted.accepts_receipt(&receipt));
+
+        let mut moved = receipt.clone();
+        moved.leaf_index = LeafIndex(0);
+        assert!(!trusted.accepts_receipt(&moved));
+        let expired: TrustedKey = format!("{}@..1000", trusted.public_key)
+            .parse()
+            .expect("should parse");
+        assert!(!expired.accepts_receipt(&receipt));
+
+        storage.reserve(ANONYMOUS).expect("should reserve");
+        let waiting = storage
+            .add_new_file("c.txt".to_string(), b"third".to_vec())
+            .expect("should add");
+        assert!(storage
+            .upload_receipt(waiting, now)
+            .expect("should get")
+            .is_none());
+    }
+
+    #[test]
+    fn test_prune_dead_prefix() {
+        let mut storage = Storage::new();
+        let ids: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                storage
+                    .add_new_file(name.to_string(), name.as_bytes().to_vec())
+                    .expect("should add")
+            })
+            .collect();
+        let (_, _, proof_of_c) = storage.get_file_by_id(ids[2]).expect("should get");
+
+        storage.delete_file(ids[0]).expect("should delete");
+        assert_eq!(storage.pruned_leaves(), 1);
+        // tombstone of "a" is dead too, but "c" after "b" is still alive
+        storage.delete_file(ids[1]).expect("should delete");
+        assert_eq!(storage.pruned_leaves(), 2);
+        assert_eq!(storage.epoch_size(), 5);
+
+        let root = storage.root_hash().expect("should have root");
+        let (_, _, proof) = storage.get_file_by_id(ids[2]).expect("should get");
+        assert_eq!(proof.root, root);
+        assert!(proof.proof.verify(&root, &hash_content(b"c")));
+        assert_ne!(proof, proof_of_c);
+
+        // pruned leaves are still proven from persisted ones
+        let (index, proof) = storage
+            .proof_by_hash(&hash_content(b"a"))
+            .expect("should find");
+        assert_eq!((index, &proof.root), (LeafIndex(0), &root));
+        assert!(proof.proof.verify(&root, &hash_content(b"a")));
+        let (consistency, new_root) = storage
+            .consistency_proof(None, TreeSize(1), None)
+            .expect("should prove")
+            .expect("should exist");
+        assert_eq!(new_root, root);
+        assert_eq!(consistency.old_size, 1);
+        assert_eq!(storage.check_integrity(), Ok(None));
+
+        let (metadata, blobs) = storage.into_stores();
+        let storage = Storage::open(metadata, blobs).expect("should open");
+        assert_eq!(storage.pruned_leaves(), 2);
+        assert_eq!(storage.root_hash(), Some(root));
+    }
+
+    #[test]
+    fn test_proof_by_hash() {
+        let mut storage = Storage::new().with_epoch_policy(EpochPolicy {
+            max_leaves: Some(2),
+            max_age: None,
+        });
+        for content in ["first", "second", "third"] {
+            storage
+                .add_new_file(content.to_string(), content.as_bytes().to_vec())
+                .expect("should add");
+        }
+        let hash = hash_content(b"second");
+        let (index, proof) = storage.proof_by_hash(&hash).expect("should find");
+        assert_eq!((index, proof.epoch), (LeafIndex(1), 0));
+        assert_eq!(proof.root, storage.checkpoints()[0].root);
+        assert!(proof.proof.verify(&proof.root, &hash));
+        assert_eq!(
+            storage.proof_by_hash(&hash_content(b"missing")).map(|_| ()),
+            Err(StorageError::NotFound)
+        );
+
+        let (metadata, blobs) = storage.into_stores();
+        let storage = Storage::open(metadata, blobs).expect("should open");
+        let hash = hash_content(b"third");
+        let (index, proof) = storage.proof_by_hash(&hash).expect("should find");
+        assert_eq!((index, proof.epoch), (LeafIndex(2), 1));
+        assert!(proof.proof.verify(&proof.root, &hash));
+    }
+
+    #[test]
+    fn test_rebuild_hash_index() {
+        let mut storage = Storage::new();
+        assert!(storage.migrations()[0].finished_at.is_some());
+        for content in ["a", "b", "a", "c"] {
+            storage
+                .add_new_file(format!("{content}.txt"), content.as_bytes().to_vec())
+                .expect("should add");
+        }
+        let (metadata, blobs) = storage.into_stores();
+        let mut storage = Storage::open(metadata, blobs).expect("should open");
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        assert_eq!(storage.migrations()[0].done, 0);
+        assert_eq!(storage.migrations()[0].total, 4);
+        // leaves not indexed yet are still found, and leaves appended since open too
+        storage
+            .add_new_file("d.txt".to_string(), b"d".to_vec())
+            .expect("should add");
+        storage
+            .add_new_file("a.txt".to_string(), b"a".to_vec())
+            .expect("should add");
+        let index_of = |storage: &Storage, content: &[u8]| {
+            storage
+                .proof_by_hash(&hash_content(content))
+                .map(|(index, _)| index)
+        };
+        assert_eq!(index_of(&storage, b"a"), Ok(LeafIndex(0)));
+        assert_eq!(index_of(&storage, b"c"), Ok(LeafIndex(3)));
+        assert_eq!(index_of(&storage, b"d"), Ok(LeafIndex(4)));
+
+        assert!(!storage.rebuild_indexes(3, now));
+        assert_eq!(storage.migrations()[0].done, 3);
+        assert_eq!(index_of(&storage, b"a"), Ok(LeafIndex(0)));
+        assert_eq!(index_of(&storage, b"c"), Ok(LeafIndex(3)));
+        assert!(storage.rebuild_indexes(3, now));
+        let migration = &storage.migrations()[0];
+        assert_eq!((migration.done, migration.finished_at), (4, Some(100)));
+        assert_eq!(index_of(&storage, b"a"), Ok(LeafIndex(0)));
+        assert_eq!(index_of(&storage, b"c"), Ok(LeafIndex(3)));
+        assert_eq!(index_of(&storage, b"d"), Ok(LeafIndex(4)));
+        assert_eq!(index_of(&storage, b"e"), Err(StorageError::NotFound));
+    }
+
+    #[test]
+    fn test_list_changes() {
+        let mut storage = Storage::new();
+        let now = SystemTime::now();
+        let first = storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        storage
+            .add_new_file("b.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+        let (files, deleted, size) = storage.list_changes(TreeSize(0), now).expect("should list");
+        assert_eq!(files.len(), 2);
+        assert!(deleted.is_empty());
+        assert_eq!(size, TreeSize(2));
+
+        let third = storage
+            .add_new_file("c.txt".to_string(), b"third".to_vec())
+            .expect("should add");
+        storage.delete_file(first).expect("should delete");
+        let (files, deleted, size) = storage.list_changes(TreeSize(2), now).expect("should list");
+        assert_eq!(files.iter().map(|f| f.id).collect::<Vec<_>>(), vec![third]);
+        assert_eq!(deleted, vec![first]);
+        assert_eq!(size, TreeSize(4));
+
+        let (files, deleted, _) = storage.list_changes(size, now).expect("should list");
+        assert!(files.is_empty() && deleted.is_empty());
+    }
+
+    #[test]
+    fn test_list_files_by() {
+        let mut storage = Storage::new();
+        let now = SystemTime::now();
+        for (name, content) in [("docs/b.txt", "12"), ("a.txt", "123"), ("docs/a.txt", "1")] {
+            storage
+                .add_new_file(name.to_string(), content.as_bytes().to_vec())
+                .expect("should add");
+        }
+        let deleted = storage
+            .add_new_file("c.txt".to_string(), b"1234".to_vec())
+            .expect("should add");
+        storage.delete_file(deleted).expect("should delete");
+        let names = |sort, order| {
+            storage
+                .list_files_by(now, false, sort, order)
+                .expect("should list")
+                .into_iter()
+                .map(|file| file.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(FileSort::Created, SortOrder::Asc),
+            ["docs/b.txt", "a.txt", "docs/a.txt"]
+        );
+        assert_eq!(
+            names(FileSort::Name, SortOrder::Asc),
+            ["a.txt", "docs/a.txt", "docs/b.txt"]
+        );
+        assert_eq!(
+            names(FileSort::Size, SortOrder::Desc),
+            ["a.txt", "docs/b.txt", "docs/a.txt"]
+        );
+
+        let files = storage
+            .list_files_by(now, false, FileSort::Name, SortOrder::Desc)
+            .expect("should list");
+        let groups = FileGroup::by_prefix(files);
+        assert_eq!(
+            groups
+                .iter()
+                .map(|group| (group.prefix.as_str(), group.files.len()))
+                .collect::<Vec<_>>(),
+            [("docs/", 2), ("", 1)]
+        );
+    }
+
+    #[test]
+    fn test_delete_expired() {
+        let mut storage = Storage::new();
+        let now = SystemTime::now();
+        let temporary = storage
+            .add_new_file("build.log".to_string(), b"log".to_vec())
+            .expect("should add");
+        let kept = storage
+            .add_new_file("a.txt".to_string(), b"kept".to_vec())
+            .expect("should add");
+        storage
+            .expire_at(temporary, now + Duration::from_secs(60))
+            .expect("should set expiry");
+
+        assert!(storage
+            .delete_expired(now)
+            .expect("should expire")
+            .is_empty());
+        assert_eq!(
+            storage.list_files(now, false).expect("should list").len(),
+            2
+        );
+
+        let later = now + Duration::from_secs(61);
+        let ids = |files: Vec<File>| files.into_iter().map(|f| f.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(storage.list_files(later, false).expect("should list")),
+            vec![kept]
+        );
+
+        let root_before = storage.root_hash();
+        let receipts = storage.delete_expired(later).expect("should expire");
+        assert_eq!(receipts.len(), 1);
+        assert!(receipts[0].verify());
+        assert_eq!(
+            receipts[0].root_before,
+            root_before.expect("should have root")
+        );
+        assert!(storage.get_file_by_id(temporary).is_err());
+        assert!(storage
+            .delete_expired(later)
+            .expect("should expire")
+            .is_empty());
+
+        assert_eq!(
+            ids(storage.list_files(later, false).expect("should list")),
+            vec![kept]
+        );
+        let all = storage.list_files(later, true).expect("should list");
+        assert!(all[0].expired);
+        assert_eq!(ids(all), vec![temporary, kept]);
+    }
+
+    #[test]
+    fn test_idle_files_expire() {
+        let mut storage = Storage::new().with_idle_ttl(Some(Duration::from_secs(60)));
+        let now = SystemTime::now();
+        let idle = storage
+            .add_new_file("idle.txt".to_string(), b"idle".to_vec())
+            .expect("should add");
+        let read = storage
+            .add_new_file("read.txt".to_string(), b"read".to_vec())
+            .expect("should add");
+        let later = now + Duration::from_secs(61);
+        storage.record_download(&[read, read], later);
+        assert_eq!(storage.downloads(), 2);
+        let listed = storage.list_files(later, false).expect("should list");
+        assert_eq!((listed[0].downloads, listed[0].last_access), (0, None));
+        assert_eq!(listed[1].downloads, 2);
+        assert!(listed[1].last_access.is_some());
+        // counted in memory until flushed
+        assert_eq!(
+            storage
+                .metadata
+                .get(read.as_usize())
+                .expect("should get")
+                .map(|file| file.downloads),
+            Some(0)
+        );
+        storage.flush_downloads().expect("should flush");
+        storage.record_download(&[read], later);
+        assert_eq!(
+            storage
+                .metadata
+                .get(read.as_usize())
+                .expect("should get")
+                .map(|file| file.downloads),
+            Some(2)
+        );
+        assert_eq!(storage.describe(read).map(|file| file.downloads), Ok(3));
+
+        let receipts = storage.delete_expired(later).expect("should expire");
+        assert_eq!(receipts.len(), 1);
+        assert!(storage.get_file_by_id(idle).is_err());
+        assert!(storage.get_file_by_id(read).is_ok());
+        assert!(storage
+            .list_files(later, true)
+            .expect("should list")
+            .iter()
+            .any(|file| file.id == idle && file.expired));
+        assert_eq!(
+            storage
+                .delete_expired(later + Duration::from_secs(61))
+                .expect("should expire")
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_paged_listing_is_a_snapshot() {
+        let mut storage = Storage::new();
+        for name in ["c.txt", "a.txt", "d.txt", "b.txt"] {
+            storage
+                .add_new_file(name.to_string(), name.as_bytes().to_vec())
+                .expect("should add");
+        }
+        let query = FileListQuery {
+            sort: FileSort::Name,
+            ..Default::default()
+        };
+        let (first, cursor) = storage.list_files_page(&query, 2).expect("should list");
+        let cursor = cursor.expect("should have more");
+        assert_eq!(cursor.to_string().parse::<Cursor>().ok(), Some(cursor));
+        // sorted before the rest of the snapshot, but appended after it
+        storage
+            .add_new_file("0.txt".to_string(), b"0".to_vec())
+            .expect("should add");
+        storage.delete_file(FileId(0)).expect("should delete");
+        let query = FileListQuery {
+            cursor: Some(cursor),
+            ..query
+        };
+        let (second, next) = storage.list_files_page(&query, 2).expect("should list");
+        assert_eq!(next, None);
+        let names: Vec<_> = first
+            .iter()
+            .chain(&second)
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(names, ["a.txt", "b.txt", "c.txt", "d.txt"]);
+
+        let mut query = FileListQuery {
+            sort: FileSort::Size,
+            order: SortOrder::Desc,
+            ..Default::default()
+        };
+        let mut names = Vec::new();
+        loop {
+            let (page, next) = storage.list_files_page(&query, 1).expect("should list");
+            names.extend(page.into_iter().map(|f| f.name));
+            match next {
+                Some(next) => query.cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(names, ["b.txt", "d.txt", "a.txt", "0.txt"]);
+
+        let (leaves, next) = storage.leaves_page(None, 5).expect("should list");
+        assert_eq!(leaves.len(), 5);
+        let (rest, next) = storage.leaves_page(next, 5).expect("should list");
+        assert_eq!((rest.len(), next), (1, None));
+        assert!(matches!(
+            storage.leaves_page(
+                Some(Cursor {
+                    tree_size: 7,
+                    offset: 0,
+                    after: None,
+                }),
+                5
+            ),
+            Err(StorageError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_datasets() {
+        let mut storage = Storage::new();
+        let outside = storage
+            .add_new_file("outside.txt".to_string(), b"outside".to_vec())
+            .expect("should add");
+        let mut joined = Vec::new();
+        for content in ["first", "second", "third"] {
+            let id = storage
+                .add_new_file(format!("{content}.txt"), content.as_bytes().to_vec())
+                .expect("should add");
+            storage.join_dataset(id, "reports").expect("should join");
+            joined.push(id);
+        }
+        // every file is followed by dataset leaf
+        assert_eq!(storage.leaf_count(), 7);
+        assert!(matches!(
+            storage.join_dataset(joined[0], "other"),
+            Err(StorageError::Conflict(_))
+        ));
+        assert_eq!(
+            storage.check_dataset("a/b"),
+            Err(StorageError::InvalidName(InvalidName::Separator))
+        );
+
+        let dataset = storage
+            .dataset("reports")
+            .expect("should get")
+            .expect("should exist");
+        assert_eq!((dataset.size, dataset.leaf_index), (3, LeafIndex(6)));
+        let root = storage.root_hash().expect("should exist");
+        assert_eq!(dataset.tree_root, root);
+        assert!(dataset.verify(&root));
+        assert!(!dataset.verify(&storage.leaf_hasher().leaf("a.txt", b"forged")));
+        for (index, id) in joined.iter().enumerate() {
+            let (name, content, _) = storage.get_file_by_id(*id).expect("should get");
+            let proof = storage
+                .dataset_proof("reports", *id)
+                .expect("should prove")
+                .expect("file joined dataset");
+            assert_eq!(proof.index, index as u64);
+            assert!(proof.verify(&storage.leaf_hasher().leaf(&name, &content), &root));
+        }
+        assert!(storage
+            .dataset_proof("reports", outside)
+            .expect("should prove")
+            .is_none());
+        assert!(storage.dataset("missing").expect("should get").is_none());
+        assert_eq!(
+            storage
+                .describe(joined[1])
+                .expect("should describe")
+                .dataset,
+            Some("reports".to_string())
+        );
+
+        let (metadata, blobs) = storage.into_stores();
+        let reopened = Storage::open(metadata, blobs).expect("should open");
+        let datasets = reopened.datasets().expect("should list");
+        assert_eq!(datasets.len(), 1);
+        assert_eq!(datasets[0].root, dataset.root);
+        assert_eq!(datasets[0].leaf_index, dataset.leaf_index);
+    }
+
+    #[test]
+    fn test_dataset_join_is_atomic() {
+        let mut storage = Storage::new().with_tree_limit(Some(TreeLimit {
+            max_leaves: 2,
+            when_full: WhenFull::Refuse,
+        }));
+        storage
+            .add_new_file("a.txt".to_string(), b"a".to_vec())
+            .expect("should add");
+        let joined = storage.atomically(|storage| {
+            let id = storage.add_new_file("b.txt".to_string(), b"b".to_vec())?;
+            storage.join_dataset(id, "reports")
+        });
+        assert_eq!(joined, Err(StorageError::TreeFull(2)));
+        assert_eq!(storage.leaf_count(), 1);
+        assert_eq!(storage.file_count(), Ok(1));
+        assert!(storage.dataset("reports").expect("should get").is_none());
+    }
+
+    #[test]
+    fn test_get_files_by_ids() {
+        let mut storage = Storage::new();
+        for content in ["first", "second", "third", "fourth"] {
+            storage
+                .add_new_file(format!("{content}.txt"), content.as_bytes().to_vec())
+                .expect("should add");
+        }
+        storage.delete_file(FileId(1)).expect("should delete");
+        let root = storage.root_hash().expect("should exist");
+
+        let batch = storage
+            .get_files_by_ids(&[FileId(3), FileId(0), FileId(3)])
+            .expect("should get");
+        let ids: Vec<_> = batch.files.iter().map(|f| f.id).collect();
+        assert_eq!(ids, vec![FileId(0), FileId(3)]);
+        assert_eq!(batch.files[1].content, b"fourth".to_vec());
+        assert!(batch.verify(&root, storage.leaf_hasher().as_ref()));
+
+        assert_eq!(
+            storage
+                .get_files_by_ids(&[FileId(0), FileId(1)])
+                .map(|_| ()),
+            Err(StorageError::NotFound)
+        );
+        assert!(storage.get_files_by_ids(&[]).is_err());
+
+        let half = vec![0; MAX_BATCH_BYTES as usize / 2];
+        for name in ["big.bin", "bigger.bin"] {
+            storage
+                .add_new_file(name.to_string(), half.clone())
+                .expect("should add");
+        }
+        storage
+            .get_files_by_ids(&[FileId(4), FileId(5)])
+            .expect("should get batch of limit size");
+        assert_eq!(
+            storage
+                .get_files_by_ids(&[FileId(0), FileId(4), FileId(5)])
+                .map(|_| ()),
+            Err(StorageError::BatchTooLarge(MAX_BATCH_BYTES))
+        );
+    }
+
+    #[test]
+    fn test_consistency_proof() {
+        let mut storage = Storage::new();
+        let mut roots = Vec::new();
+        for content in ["first", "second", "third"] {
+            storage
+                .add_new_file(content.to_string(), content.as_bytes().to_vec())
+                .expect("should add");
+            roots.push(storage.root_hash().expect("should exist"));
+        }
+        let proof = |storage: &Storage, epoch, old_size, new_size: Option<u64>| {
+            storage
+                .consistency_proof(epoch, TreeSize(old_size), new_size.map(TreeSize))
+                .expect("should not fail")
+        };
+
+        let (consistency, root) = proof(&storage, None, 1, None).expect("should exist");
+        assert_eq!(root, roots[2]);
+        assert!(consistency.verify(&roots[0], &root));
+        let (consistency, root) = proof(&storage, None, 1, Some(2)).expect("should exist");
+        assert_eq!(root, roots[1]);
+        assert!(consistency.verify(&roots[0], &root));
+        assert!(!consistency.verify(&roots[1], &root));
+
+        assert!(proof(&storage, None, 0, None).is_none());
+        assert!(proof(&storage, None, 2, Some(1)).is_none());
+        assert!(proof(&storage, None, 1, Some(4)).is_none());
+        assert!(proof(&storage, Some(1), 1, None).is_none());
+
+        // sealed epoch stays provable after new tree is started
+        storage.epoch_policy.max_age = Some(Duration::ZERO);
+        storage
+            .rotate_if_due(SystemTime::now() + Duration::from_secs(1))
+            .expect("should seal");
+        assert!(proof(&storage, None, 1, None).is_none());
+        let (consistency, root) = proof(&storage, Some(0), 2, None).expect("should exist");
+        assert_eq!(root, roots[2]);
+        assert!(consistency.verify(&roots[1], &root));
+    }
+
+    #[test]
+    fn test_epochs_sealed_by_size() {
+        let key: ServerKey = "0101010101010101010101010101010101010101010101010101010101010101"
+            .parse()
+            .expect("should parse");
+        let public_key = key.public_key();
+        let mut storage = Storage::new()
+            .with_epoch_policy(EpochPolicy {
+                max_leaves: Some(2),
+                max_age: None,
+            })
+            .with_server_key(Some(key));
+        for content in ["first", "second", "third"] {
+            storage
+                .add_new_file(content.to_string(), content.as_bytes().to_vec())
+                .expect("should add");
+        }
+        assert_eq!(storage.current_epoch(), 1);
+        let checkpoint = storage.checkpoints()[0].clone();
+        assert_eq!((checkpoint.first_leaf, checkpoint.size), (0, 2));
+        assert!(checkpoint.verify(&public_key));
+
+        let (_, content, proof) = storage.get_file_by_id(FileId(1)).expect("should get");
+        assert_eq!((proof.epoch, &proof.root), (0, &checkpoint.root));
+        assert!(proof.proof.verify(&proof.root, &hash_content(content)));
+        let (_, content, proof) = storage.get_file_by_id(FileId(2)).expect("should get");
+        assert_eq!(proof.epoch, 1);
+        assert_eq!(Some(&proof.root), storage.root_hash().as_ref());
+        assert!(proof.proof.verify(&proof.root, &hash_content(content)));
+
+        // tombstone of sealed leaf lands in the current epoch and seals it
+        let receipt = storage.delete_file(FileId(0)).expect("should delete");
+        assert!(receipt.verify());
+        assert_eq!(receipt.root_before, checkpoint.root);
+        assert_eq!(receipt.root_after, storage.checkpoints()[1].root);
+        assert_eq!(storage.root_hash(), None);
+
+        assert!(matches!(
+            storage.get_files_by_ids(&[FileId(1), FileId(2)]),
+            Err(StorageError::Conflict(_))
+        ));
+        let batch = storage.get_files_by_ids(&[FileId(2)]).expect("should get");
+        assert!(batch.verify(
+            &storage.checkpoints()[1].root,
+            storage.leaf_hasher().as_ref()
+        ));
+        assert_eq!(storage.check_integrity(), Ok(None));
+
+        let (metadata, blobs) = storage.into_stores();
+        let mut storage = Storage::open(metadata, blobs).expect("should open");
+        assert_eq!((storage.current_epoch(), storage.leaf_count()), (2, 4));
+        assert_eq!(
+            storage
+                .get_file_by_id(FileId(1))
+                .map(|(_, _, proof)| proof.root),
+            Ok(checkpoint.root)
+        );
+        assert_eq!(storage.check_integrity(), Ok(None));
+    }
+
+    #[test]
+    fn test_tree_limit() {
+        let limit = |when_full| {
+            Some(TreeLimit {
+                max_leaves: 2,
+                when_full,
+            })
+        };
+        let mut storage = Storage::new().with_tree_limit(limit(WhenFull::Refuse));
+        let (reserved, _) = storage.reserve(ANONYMOUS).expect("should reserve");
+        storage
+            .add_new_file("a.txt".to_string(), b"a".to_vec())
+            .expect("should add");
+        assert_eq!(
+            storage.add_new_file("b.txt".to_string(), b"b".to_vec()),
+            Err(StorageError::TreeFull(2)),
+            "reserved slot and queued file take the room"
+        );
+        assert!(matches!(
+            storage.add_hashed_files_as(
+                ANONYMOUS,
+                vec![("c.txt".to_string(), b"c".to_vec(), hash_content(b"c"))]
+            ),
+            Err(StorageError::TreeFull(2))
+        ));
+        assert_eq!(
+            storage.reserve(ANONYMOUS).map(|_| ()),
+            Err(StorageError::TreeFull(2))
+        );
+        storage
+            .fill_reservation(
+                reserved,
+                ANONYMOUS,
+                "r.txt".to_string(),
+                b"r".to_vec(),
+                hash_content(b"r"),
+            )
+            .expect("should fill");
+        assert_eq!(
+            storage.delete_file(reserved).map(|_| ()),
+            Err(StorageError::TreeFull(2)),
+            "tombstone takes a leaf as well"
+        );
+        assert_eq!(storage.leaf_count(), 2);
+
+        let mut storage = Storage::new().with_tree_limit(limit(WhenFull::Seal));
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            storage
+                .add_new_file(name.to_string(), name.as_bytes().to_vec())
+                .expect("should add");
+        }
+        assert_eq!((storage.current_epoch(), storage.epoch_size()), (1, 1));
+    }
+
+    #[test]
+    fn test_rotate_signing_key() {
+        let old: ServerKey = "01".repeat(32).parse().expect("should parse");
+        let (old_key, old_id) = (old.public_key(), old.key_id());
+        let mut storage = Storage::new()
+            .with_epoch_policy(EpochPolicy {
+                max_leaves: Some(1),
+                max_age: None,
+            })
+            .with_server_key(Some(old));
+        storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        let new: ServerKey = "02".repeat(32).parse().expect("should parse");
+        let new_key = new.public_key();
+        let rotated = storage
+            .rotate_key(new, SystemTime::now())
+            .expect("should rotate");
+        assert!(rotated.active);
+        storage
+            .add_new_file("b.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+
+        let checkpoints = storage.checkpoints();
+        assert_eq!(checkpoints[0].key_id, Some(old_id));
+        assert!(checkpoints[0].verify(&old_key));
+        assert_eq!(checkpoints[1].key_id, Some(rotated.key_id));
+        assert!(checkpoints[1].verify(&new_key));
+        let keys: Vec<_> = storage
+            .public_keys()
+            .into_iter()
+            .map(|k| k.public_key)
+            .collect();
+        assert_eq!(keys, vec![new_key.clone(), old_key]);
+        assert_eq!(storage.public_key(), Some(new_key));
+        let again: ServerKey = "02".repeat(32).parse().expect("should parse");
+        assert!(matches!(
+            storage.rotate_key(again, SystemTime::now()),
+            Err(StorageError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_export_and_import_epoch() {
+        let key = "0202020202020202020202020202020202020202020202020202020202020202";
+        let epochs = EpochPolicy {
+            max_leaves: Some(2),
+            max_age: None,
+        };
+        let mut storage = Storage::new()
+            .with_epoch_policy(epochs)
+            .with_server_key(Some(key.parse().expect("should parse")));
+        for content in ["first", "second", "third"] {
+            storage
+                .add_new_file(content.to_string(), content.as_bytes().to_vec())
+                .expect("should add");
+        }
+        assert_eq!(
+            storage.export_epoch(1).map(|_| ()),
+            Err(StorageError::NotFound)
+        );
+        let archive = storage.export_epoch(0).expect("should export");
+        assert_eq!((archive.leaves.len(), archive.files.len()), (2, 2));
+
+        let mut merged = Storage::new().with_server_key(Some(key.parse().expect("should parse")));
+        let imported = merged.import_epoch(archive).expect("should import");
+        assert_eq!((imported.files, imported.restored), (2, false));
+        assert_eq!(merged.current_epoch(), 1);
+        let (name, _, proof) = merged.get_file_by_id(FileId(1)).expect("should get");
+        assert_eq!(name, "second");
+        assert_eq!(proof.root, imported.checkpoint.root);
+        assert_eq!(merged.check_integrity(), Ok(None));
+
+        // contents moved to cold storage are restored into already sealed epoch
+        let (metadata, mut blobs) = storage.into_stores();
+        blobs.remove(1).expect("should remove");
+        let mut storage = Storage::open(metadata, blobs)
+            .expect("should open")
+            .with_server_key(Some(key.parse().expect("should parse")));
+        assert!(storage.get_file_by_id(FileId(1)).is_err());
+        let archive = merged.export_epoch(0).expect("should export");
+        let imported = storage.import_epoch(archive).expect("should restore");
+        assert_eq!((imported.files, imported.restored), (1, true));
+        assert!(storage.get_file_by_id(FileId(1)).is_ok());
+
+        let mut tampered = merged.export_epoch(0).expect("should export");
+        tampered.files[0].content = b"tampered".to_vec();
+        assert!(matches!(
+            storage.import_epoch(tampered),
+            Err(StorageError::InvalidArchive(_))
+        ));
+        let mut unsigned = merged.export_epoch(0).expect("should export");
+        unsigned.checkpoint.signature = None;
+        assert!(matches!(
+            storage.import_epoch(unsigned),
+            Err(StorageError::InvalidArchive(_))
+        ));
+    }
+
+    #[test]
+    fn test_hot_proofs() {
+        let epoch_policy = EpochPolicy {
+            max_leaves: Some(3),
+            max_age: None,
+        };
+        let mut cached = Storage::new()
+            .with_epoch_policy(epoch_policy)
+            .with_hot_proofs(2);
+        let mut uncached = Storage::new()
+            .with_epoch_policy(epoch_policy)
+            .with_hot_proofs(0);
+        for i in 0..5 {
+            for storage in [&mut cached, &mut uncached] {
+                storage
+                    .add_new_file(format!("{i}.txt"), vec![i])
+                    .expect("should add");
+            }
+            for id in 0..=i as u32 {
+                let proof = cached.proof(FileId(id)).expect("should prove");
+                assert_eq!(
+                    proof,
+                    uncached.proof(FileId(id)).expect("should prove"),
+                    "{id}"
+                );
+                assert!(proof.proof.verify(&proof.root, &hash_content([id as u8])));
+            }
+        }
+        assert_eq!(cached.proof_cache.borrow().proofs.len(), 2);
+        assert!(cached.proof(FileId(5)).is_err());
+    }
+
+    #[test]
+    #[ignore = "proof lookup timings with and without hot proofs, run with --ignored --nocapture"]
+    fn bench_hot_proofs() {
+        for hot_proofs in [0, DEFAULT_HOT_PROOFS] {
+            let mut storage = Storage::new()
+                .with_epoch_policy(EpochPolicy {
+                    max_leaves: Some(10_000),
+                    max_age: None,
+                })
+                .with_hot_proofs(hot_proofs);
+            let started = std::time::Instant::now();
+            for i in 0..20_000u32 {
+                storage
+                    .add_new_file(format!("{i}.bin"), i.to_be_bytes().to_vec())
+                    .expect("should add");
+            }
+            let uploaded = started.elapsed();
+            let started = std::time::Instant::now();
+            for _ in 0..100 {
+                for id in 19_990..20_000 {
+                    storage.proof(FileId(id)).expect("should prove");
+                }
+                for id in 0..10 {
+                    storage.proof(FileId(id)).expect("should prove");
+                }
+            }
+            println!(
+                "hot proofs: {hot_proofs}, 20000 uploads in {uploaded:?}, 2000 proofs in {:?}",
+                started.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn test_rotate_if_due() {
+        let now = SystemTime::now();
+        let mut storage = Storage::new().with_epoch_policy(EpochPolicy {
+            max_leaves: None,
+            max_age: Some(Duration::from_secs(60)),
+        });
+        assert_eq!(
+            storage.rotate_if_due(now + Duration::from_secs(120)),
+            Ok(None)
+        );
+        storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        assert_eq!(storage.rotate_if_due(now), Ok(None));
+
+        let sealed_at = now + Duration::from_secs(120);
+        let checkpoint = storage
+            .rotate_if_due(sealed_at)
+            .expect("should seal")
+            .expect("should be due");
+        assert_eq!((checkpoint.epoch, checkpoint.size), (0, 1));
+        assert!(checkpoint.signature.is_none());
+        assert_eq!(storage.rotate_if_due(sealed_at), Ok(None));
+        assert_eq!(
+            storage.get_file_by_id(FileId(0)).map(|(_, _, p)| p.epoch),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn test_open_rebuilds_indexes() {
+        let mut storage = Storage::new()
+            .with_collision_policy(CollisionPolicy::Reject)
+            .with_quota(Quota {
+                max_bytes: None,
+                max_files: Some(4),
+            });
+        storage
+            .add_new_file_as("ci", "a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+        let deleted = storage
+            .add_new_file_as("ci", "b.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+        storage.delete_file(deleted).expect("should delete");
+        let (reserved, _) = storage.reserve("ci").expect("should reserve");
+        let pending = storage
+            .add_new_file_as("ci", "c.txt".to_string(), b"third".to_vec())
+            .expect("should add");
+        let root = storage.root_hash();
+        let usage = storage.usage_of("ci");
+
+        let (metadata, blobs) = storage.into_stores();
+        let mut storage = Storage::open(metadata, blobs)
+            .expect("should open")
+            .with_collision_policy(CollisionPolicy::Reject)
+            .with_quota(Quota {
+                max_bytes: None,
+                max_files: Some(4),
+            });
+        assert_eq!(storage.root_hash(), root);
+        assert_eq!(storage.usage_of("ci"), usage);
+        assert_eq!(
+            storage.add_new_file_as("ci", "d.txt".to_string(), b"fifth".to_vec()),
+            Err(QuotaExceeded::Files(4).into())
+        );
+        assert!(storage.get_file_by_id(pending).is_err());
+
+        let hash = hash_content(b"fourth");
+        assert_eq!(
+            storage.fill_reservation(
+                reserved,
+                "ci",
+                "a.txt".to_string(),
+                b"fourth".to_vec(),
+                hash.clone()
+            ),
+            Err(StorageError::NameTaken("a.txt".to_string()))
+        );
+        storage
+            .fill_reservation(
+                reserved,
+                "ci",
+                "b.txt".to_string(),
+                b"fourth".to_vec(),
+                hash,
+            )
+            .expect("should fill");
+        assert_eq!(storage.describe(reserved).map(|f| f.version), Ok(2));
+        let (_, content, _) = storage
+            .get_file_by_id(pending)
+            .expect("should be committed");
+        assert_eq!(content, b"third");
+    }
+
+    #[test]
+    fn test_integrity_check_quarantines_tampered_storage() {
+        let mut storage = Storage::new();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            storage
+                .add_new_file(name.to_string(), name.as_bytes().to_vec())
+                .expect("should add");
+        }
+        storage.delete_file(FileId(1)).expect("should delete");
+        assert_eq!(storage.check_integrity(), Ok(None));
+
+        // files stored between batches are compared by their persisted leaves
+        let mut rehashed = Rehashed::default();
+        assert_eq!(storage.rehash_files(&mut rehashed, 2), Ok(true));
+        storage
+            .add_new_file("d.txt".to_string(), b"d".to_vec())
+            .expect("should add");
+        while storage
+            .rehash_files(&mut rehashed, 1)
+            .expect("should rehash")
+        {}
+        assert_eq!(storage.check_rehashed(rehashed), Ok(None));
+
+        let (metadata, mut blobs) = storage.into_stores();
+        blobs.put(2, b"tampered".to_vec()).expect("should put");
+        let mut storage = Storage::open(metadata, blobs).expect("should open");
+        let divergence = Divergence {
+            first_leaf: LeafIndex(2),
+            last_leaf: LeafIndex(2),
+        };
+        let mut rehashed = Rehashed::default();
+        while storage
+            .rehash_files(&mut rehashed, 1)
+            .expect("should rehash")
+        {}
+        assert_eq!(
+            storage.check_rehashed(rehashed),
+            Ok(Some(divergence.clone()))
+        );
+        assert_eq!(storage.quarantine(), Some(&divergence));
+        assert_eq!(storage.check_integrity(), Ok(Some(divergence.clone())));
+
+        assert_eq!(
+            storage.add_new_file("e.txt".to_string(), b"e".to_vec()),
+            Err(StorageError::Quarantined)
+        );
+        assert!(matches!(
+            storage.delete_file(FileId(0)),
+            Err(StorageError::Quarantined)
+        ));
+        assert!(storage.get_file_by_id(FileId(0)).is_ok());
+    }
+
+    /// Store which fails every write once `writes` run out, as if the process was killed
+    struct Killed<S> {
+        inner: S,
+        writes: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl<S> Killed<S> {
+        fn write(&self) -> std::io::Result<()> {
+            use std::sync::atomic::Ordering;
+            self.writes
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |writes| {
+                    writes.checked_sub(1)
+                })
+                .map(|_| ())
+                .map_err(|_| std::io::Error::other("process killed"))
+        }
+    }
+
+    impl MetadataStore for Killed<MemoryMetadata> {
+        fn insert(&mut self, file: FileMeta) -> std::io::Result<usize> {
+            self.write()?;
+            self.inner.insert(file)
+        }
+
+        fn update(&mut self, id: usize, file: FileMeta) -> std::io::Result<()> {
+            self.write()?;
+            self.inner.update(id, file)
+        }
+
+        fn get(&self, id: usize) -> std::io::Result<Option<FileMeta>> {
+            self.inner.get(id)
+        }
+
+        fn all(&self) -> std::io::Result<Vec<FileMeta>> {
+            self.inner.all()
+        }
+
+        fn append_leaf(
+            &mut self,
+            hash: merkle::Sha3Hash,
+            root: merkle::Sha3Hash,
+        ) -> std::io::Result<()> {
+            self.write()?;
+            self.inner.append_leaf(hash, root)
+        }
+
+        fn leaves(&self) -> std::io::Result<Vec<merkle::Sha3Hash>> {
+            self.inner.leaves()
+        }
+
+        fn roots(&self) -> std::io::Result<Vec<merkle::Sha3Hash>> {
+            self.inner.roots()
+        }
+
+        fn seal_epoch(&mut self, checkpoint: Checkpoint) -> std::io::Result<()> {
+            self.write()?;
+            self.inner.seal_epoch(checkpoint)
+        }
+
+        fn checkpoints(&self) -> std::io::Result<Vec<Checkpoint>> {
+            self.inner.checkpoints()
+        }
+
+        fn begin(&mut self) -> std::io::Result<()> {
+            self.inner.begin()
+        }
+
+        fn commit(&mut self) -> std::io::Result<()> {
+            self.inner.commit()
+        }
+
+        fn rollback(&mut self) -> std::io::Result<()> {
+            self.inner.rollback()
+        }
+    }
+
+    impl BlobStore for Killed<MemoryBlobs> {
+        fn put(&mut self, id: usize, content: Vec<u8>) -> std::io::Result<()> {
+            self.write()?;
+            self.inner.put(id, content)
+        }
+
+        fn get(&self, id: usize) -> std::io::Result<Option<Vec<u8>>> {
+            self.inner.get(id)
+        }
+
+        fn remove(&mut self, id: usize) -> std::io::Result<()> {
+            self.write()?;
+            self.inner.remove(id)
+        }
+
+        fn begin(&mut self) -> std::io::Result<()> {
+            self.inner.begin()
+        }
+
+        fn commit(&mut self) -> std::io::Result<()> {
+            self.inner.commit()
+        }
+
+        fn rollback(&mut self) -> std::io::Result<()> {
+            self.inner.rollback()
+        }
+    }
+
+    /// Root, files and usage, which must be the same before and after a killed mutation
+    fn snapshot(storage: &Storage) -> impl PartialEq + std::fmt::Debug {
+        let files: Vec<_> = storage
+            .list_files(SystemTime::now(), true)
+            .expect("should list")
+            .into_iter()
+            .map(|file| (file.id, file.name, file.version))
+            .collect();
+        let contents: Vec<_> = files
+            .iter()
+            .map(|(id, ..)| storage.get_file_by_id(*id).map(|(_, content, _)| content))
+            .collect();
+        (
+            storage.root_hash(),
+            storage.leaf_count(),
+            storage.checkpoints().len(),
+            files,
+            contents,
+            storage.usage_of(ANONYMOUS),
+        )
+    }
+
+    #[test]
+    fn test_mutations_killed_between_steps() {
+        type Mutation = fn(&mut Storage) -> Result<(), StorageError>;
+        let mutations: [(&str, Mutation); 5] = [
+            ("add", |storage| {
+                storage.add_new_file("c.txt".to_string(), b"third".to_vec())?;
+                Ok(())
+            }),
+            ("add batch", |storage| {
+                let files = ["c.txt", "d.txt"].map(|name| {
+                    (
+                        name.to_string(),
+                        b"more".to_vec(),
+                        hash_content(name.as_bytes()),
+                    )
+                });
+                storage.add_hashed_files_as(ANONYMOUS, files.to_vec())?;
+                Ok(())
+            }),
+            ("delete", |storage| {
+                storage.delete_file(FileId(1))?;
+                Ok(())
+            }),
+            ("undelete", |storage| {
+                storage.undelete_file(FileId(0))?;
+                Ok(())
+            }),
+            ("join dataset", |storage| {
+                storage.join_dataset(FileId(1), "reports")
+            }),
+        ];
+        for (name, mutation) in mutations {
+            let mut killed = 0;
+            for writes in 0.. {
+                let allowed = Arc::new(std::sync::atomic::AtomicUsize::new(usize::MAX));
+                let mut storage = Storage::open(
+                    Box::new(Killed {
+                        inner: MemoryMetadata::default(),
+                        writes: allowed.clone(),
+                    }),
+                    Box::new(Killed {
+                        inner: MemoryBlobs::default(),
+                        writes: allowed.clone(),
+                    }),
+                )
+                .expect("should open")
+                .with_keep_deleted(true)
+                // the first leaf appended by mutation seals the epoch
+                .with_epoch_policy(EpochPolicy {
+                    max_leaves: Some(4),
+                    max_age: None,
+                });
+                for (file, content) in [("a.txt", "first"), ("b.txt", "second")] {
+                    storage
+                        .add_new_file(file.to_string(), content.as_bytes().to_vec())
+                        .expect("should add");
+                }
+                storage.delete_file(FileId(0)).expect("should delete");
+                let before = snapshot(&storage);
+
+                allowed.store(writes, std::sync::atomic::Ordering::SeqCst);
+                if mutation(&mut storage).is_ok() {
+                    break;
+                }
+                killed += 1;
+                assert_eq!(
+                    snapshot(&storage),
+                    before,
+                    "{name} killed after {writes} writes"
+                );
+
+                // restarted process sees only what was committed
+                allowed.store(usize::MAX, std::sync::atomic::Ordering::SeqCst);
+                let (metadata, blobs) = storage.into_stores();
+                let mut storage = Storage::open(metadata, blobs)
+                    .expect("should open")
+                    .with_keep_deleted(true);
+                assert_eq!(
+                    snapshot(&storage),
+                    before,
+                    "{name} killed after {writes} writes"
+                );
+                assert_eq!(storage.check_integrity(), Ok(None));
+                mutation(&mut storage).expect("should run after restart");
+            }
+            assert!(killed > 1, "{name} should take several writes");
+        }
+    }
+
+    #[test]
+    fn test_disk_blobs_killed_before_commit() {
+        let dir = std::env::temp_dir().join("safe_storage_killed_disk_blobs_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let allowed = Arc::new(std::sync::atomic::AtomicUsize::new(usize::MAX));
+        let blobs = crate::backend::DiskBlobs::open(&dir).expect("should open");
+        let mut storage = Storage::open(
+            Box::new(Killed {
+                inner: MemoryMetadata::default(),
+                writes: allowed.clone(),
+            }),
+            Box::new(blobs),
+        )
+        .expect("should open");
+        let id = storage
+            .add_new_file("a.txt".to_string(), b"first".to_vec())
+            .expect("should add");
+
+        // blob is written, leaf append fails
+        allowed.store(1, std::sync::atomic::Ordering::SeqCst);
+        assert!(storage
+            .add_new_file("b.txt".to_string(), b"second".to_vec())
+            .is_err());
+        assert_eq!(std::fs::read_dir(&dir).expect("should list").count(), 1);
+        allowed.store(usize::MAX, std::sync::atomic::Ordering::SeqCst);
+
+        // leaf is appended, metadata is not updated
+        allowed.store(1, std::sync::atomic::Ordering::SeqCst);
+        assert!(storage.delete_file(id).is_err());
+        assert_eq!(
+            storage.get_file_by_id(id).expect("should get").1,
+            b"first".to_vec()
+        );
+        allowed.store(usize::MAX, std::sync::atomic::Ordering::SeqCst);
+        storage
+            .add_new_file("b.txt".to_string(), b"second".to_vec())
+            .expect("should add");
+        assert_eq!(storage.leaf_count(), 2);
+        drop(storage);
+
+        // process killed in the middle of a transaction leaves pending blob behind
+        let mut blobs = crate::backend::DiskBlobs::open(&dir).expect("should open");
+        blobs.begin().expect("should begin");
+        blobs.put(2, b"third".to_vec()).expect("should put");
+        blobs.remove(0).expect("should remove");
+        assert_eq!(blobs.get(0).expect("should read"), None);
+        drop(blobs);
+        let blobs = crate::backend::DiskBlobs::open(&dir).expect("should reopen");
+        assert_eq!(blobs.get(2).expect("should read"), None);
+        assert_eq!(blobs.get(0).expect("should read"), Some(b"first".to_vec()));
+        assert_eq!(std::fs::read_dir(&dir).expect("should list").count(), 2);
+        std::fs::remove_dir_all(&dir).expect("should remove dir");
+    }
+}
+
+#[cfg(all(test, feature = "loom"))]
+mod loom_test {
+    use super::*;
+    use loom::sync::{Arc, Mutex};
+    use loom::thread;
+
+    /// Roots of trees holding first `size` leaves of `leaves`, for every size
+    fn prefix_roots(leaves: &[merkle::Sha3Hash]) -> Vec<Option<merkle::Sha3Hash>> {
+        (0..=leaves.len())
+            .map(|size| merkle::Sha3Tree::from_manifest(leaves[..size].iter().cloned()).root())
+            .collect()
+    }
+
+    /// Uploads and root reads sharing storage behind the lock the server takes, in every
+    /// interleaving: leaves are appended in the order writers took the lock, each one saw the tree
+    /// ending with its own leaf and readers only ever see roots of complete prefixes
+    #[test]
+    fn test_concurrent_appends_are_linearizable() {
+        loom::model(|| {
+            let storage = Arc::new(Mutex::new(Storage::new()));
+            let writers: Vec<_> = ["a", "b"]
+                .into_iter()
+                .map(|name| {
+                    let storage = storage.clone();
+                    thread::spawn(move || {
+                        let mut storage = storage.lock().unwrap();
+                        let id = storage
+                            .add_new_file(format!("{name}.txt"), name.as_bytes().to_vec())
+                            .expect("should add");
+                        (name, id, storage.root_hash(), storage.leaf_count())
+                    })
+                })
+                .collect();
+            let reader = {
+                let storage = storage.clone();
+                thread::spawn(move || {
+                    let storage = storage.lock().unwrap();
+                    (storage.root_hash(), storage.leaf_count())
+                })
+            };
+            let appended: Vec<_> = writers
+                .into_iter()
+                .map(|writer| writer.join().unwrap())
+                .collect();
+            let (seen_root, seen_size) = reader.join().unwrap();
+
+            let storage = storage.lock().unwrap();
+            let mut leaves = vec![hash_content(b""); appended.len()];
+            for (name, _, _, size) in &appended {
+                leaves[size - 1] = hash_content(name);
+            }
+            let roots = prefix_roots(&leaves);
+            assert_eq!(storage.root_hash(), roots[leaves.len()]);
+            assert_eq!(seen_root, roots[seen_size]);
+            for (name, id, root, size) in appended {
+                assert_eq!(root, roots[size]);
+                let (_, content, proof) = storage.get_file_by_id(id).expect("should get");
+                assert_eq!(content, name.as_bytes());
+                let root = storage.root_hash().expect("tree is not empty");
+                assert!(proof.proof.verify(&root, &leaves[size - 1]));
+            }
+        });
     }
 }
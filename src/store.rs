@@ -0,0 +1,218 @@
+use crate::api::FileId;
+use crate::merkle::{Sha3Hash, Sha3Proof};
+use crate::sha3::hash_content;
+use crate::sparse::{SparseProof, SparseTree};
+use crate::storage::{Storage, StorageError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Embedded tamper-evident store - files are appended to the merkle tree the same way as in the
+/// service, just without http in between. Store opened from a path is kept as json snapshot there.
+/// Next to files it keeps key-value pairs in a sparse Merkle tree of their own, where a key can
+/// be overwritten and proven to be absent.
+pub struct Store {
+    storage: Storage,
+    values: BTreeMap<Vec<u8>, Vec<u8>>,
+    sparse: SparseTree,
+    path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredFile {
+    name: String,
+    #[serde(with = "crate::api::base64")]
+    content: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredValue {
+    #[serde(with = "crate::api::base64")]
+    key: Vec<u8>,
+    #[serde(with = "crate::api::base64")]
+    value: Vec<u8>,
+}
+
+/// Snapshots written before key-value pairs were added are a bare list of files
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Snapshot {
+    Current {
+        files: Vec<StoredFile>,
+        values: Vec<StoredValue>,
+    },
+    Files(Vec<StoredFile>),
+}
+
+impl Store {
+    pub fn in_memory() -> Self {
+        Self {
+            storage: Storage::new(),
+            values: BTreeMap::new(),
+            sparse: SparseTree::new(),
+            path: None,
+        }
+    }
+
+    /// Opens store persisted at given path, or creates an empty one if file doesn't exist yet.
+    /// Tree is rebuilt by appending files in their original order, so root stays the same.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut store = Self {
+            path: Some(path.as_ref().to_path_buf()),
+            ..Self::in_memory()
+        };
+        if path.as_ref().exists() {
+            let (files, values) = match serde_json::from_slice(&std::fs::read(path)?)? {
+                Snapshot::Current { files, values } => (files, values),
+                Snapshot::Files(files) => (files, Vec::new()),
+            };
+            for file in files {
+                store.storage.add_new_file(file.name, file.content)?;
+            }
+            for StoredValue { key, value } in values {
+                store.put(key, value);
+            }
+        }
+        Ok(store)
+    }
+
+    /// Writes all files to the path store was opened from, does nothing for in-memory store
+    pub fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let files = self
+            .storage
+            .list_all_files()
+            .map(|file| {
+                let file = file?;
+                Ok(StoredFile {
+                    content: self.storage.file_content(file.id)?,
+                    name: file.name,
+                })
+            })
+            .collect::<Result<_, StorageError>>()?;
+        let values = self
+            .values
+            .iter()
+            .map(|(key, value)| StoredValue {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        std::fs::write(
+            path,
+            serde_json::to_vec(&Snapshot::Current { files, values })?,
+        )?;
+        Ok(())
+    }
+
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        content: impl Into<Vec<u8>>,
+    ) -> Result<FileId, StorageError> {
+        self.storage.add_new_file(name.into(), content.into())
+    }
+
+    /// Store is kept in memory, so missing file is the only reason for `None`
+    pub fn get(&self, id: FileId) -> Option<(String, Vec<u8>)> {
+        self.storage
+            .get_file_by_id(id)
+            .ok()
+            .map(|(name, content, _)| (name, content))
+    }
+
+    pub fn proof(&self, id: FileId) -> Option<Sha3Proof> {
+        self.storage
+            .get_file_by_id(id)
+            .ok()
+            .map(|(_, _, proof)| proof.proof)
+    }
+
+    pub fn root(&self) -> Option<Sha3Hash> {
+        self.storage.root_hash()
+    }
+
+    /// Puts value under the key, replacing the previous one. Unlike files, values don't get ids
+    /// and their history is not kept.
+    pub fn put(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        let (key, value) = (key.into(), value.into());
+        self.sparse.insert(&key, &value);
+        self.values.insert(key, value);
+    }
+
+    /// Value stored under the key with proof against [`Store::values_root`]. Missing key comes
+    /// with proof of its absence.
+    pub fn get_with_proof(&self, key: &[u8]) -> (Option<Vec<u8>>, SparseProof) {
+        (self.values.get(key).cloned(), self.sparse.proof(key))
+    }
+
+    /// Root of key-value pairs, independent of the file tree and of the order keys were put in
+    pub fn values_root(&self) -> Sha3Hash {
+        self.sparse.root()
+    }
+}
+
+/// Checks that content belongs to the tree with given root
+pub fn verify(root: &Sha3Hash, content: &[u8], proof: &Sha3Proof) -> bool {
+    proof.verify(root, &hash_content(content))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_add_and_verify() {
+        let mut store = Store::in_memory();
+        assert!(store.root().is_none());
+
+        store.add("a.txt", b"first".to_vec()).expect("should add");
+        let id = store.add("b.txt", b"second".to_vec()).expect("should add");
+        let root = store.root().expect("should have root");
+        let proof = store.proof(id).expect("should have proof");
+
+        assert!(verify(&root, b"second", &proof));
+        assert!(!verify(&root, b"tampered", &proof));
+    }
+
+    #[test]
+    fn test_put_and_prove() {
+        let mut store = Store::in_memory();
+        store.put("owner", "alice");
+        store.put("owner", "bob");
+        let root = store.values_root();
+
+        let (value, proof) = store.get_with_proof(b"owner");
+        assert_eq!(value.as_deref(), Some(&b"bob"[..]));
+        assert!(proof.verify(&root, b"owner", value.as_deref()));
+        assert!(!proof.verify(&root, b"owner", Some(b"alice")));
+
+        let (value, proof) = store.get_with_proof(b"missing");
+        assert!(value.is_none());
+        assert!(proof.verify(&root, b"missing", None));
+        assert!(store.root().is_none());
+    }
+
+    #[test]
+    fn test_reopen_keeps_root() {
+        let path = std::env::temp_dir().join("safe_storage_store_test.json");
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = Store::open(&path).expect("should open");
+        store.add("a.txt", b"first".to_vec()).expect("should add");
+        store.add("b.txt", b"second".to_vec()).expect("should add");
+        store.put("key", "value");
+        store.save().expect("should save");
+
+        let reopened = Store::open(&path).expect("should reopen");
+        assert_eq!(store.root(), reopened.root());
+        assert_eq!(store.values_root(), reopened.values_root());
+        assert_eq!(
+            reopened.get(FileId(1)),
+            Some(("b.txt".to_string(), b"second".to_vec()))
+        );
+        std::fs::remove_file(&path).expect("should remove");
+    }
+}
@@ -0,0 +1,231 @@
+use crate::leaf::LeafHashing;
+use crate::merkle::{Sha3Hash, Sha3Proof, Sha3Tree};
+use crate::sha3::{hash_content, tombstone_of};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// Vectors produced by the build that introduced them, any build has to reproduce them exactly
+/// for roots and proofs to stay compatible with data and clients of other builds
+pub const CANONICAL: &str = include_str!("testvectors.json");
+
+/// Largest tree vectors are generated for, enough for odd layers at several depths
+const MAX_TREE_SIZE: usize = 9;
+
+/// Expected leaves, roots and proofs computed from fixed inputs
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TestVectors {
+    pub leaves: Vec<LeafVector>,
+    pub tombstones: Vec<TombstoneVector>,
+    pub trees: Vec<TreeVector>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct LeafVector {
+    pub leaf_hashing: LeafHashing,
+    pub name: String,
+    pub content: String,
+    pub leaf: Sha3Hash,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TombstoneVector {
+    pub leaf: Sha3Hash,
+    pub tombstone: Sha3Hash,
+}
+
+/// Tree of leaves hashed from contents `leaf 0`, `leaf 1`.. with proof of each of them
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TreeVector {
+    pub size: usize,
+    pub root: Sha3Hash,
+    pub proofs: Vec<Sha3Proof>,
+}
+
+/// Value this build computes differently than the vector expects
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub vector: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: expected {}, computed {}",
+            self.vector, self.expected, self.actual
+        )
+    }
+}
+
+pub fn canonical() -> TestVectors {
+    serde_json::from_str(CANONICAL).expect("canonical vectors should parse")
+}
+
+/// Vectors as computed by this build
+pub fn generate() -> TestVectors {
+    let leaves = leaf_inputs()
+        .into_iter()
+        .map(|(leaf_hashing, name, content)| LeafVector {
+            leaf: leaf_hashing.hasher().leaf(&name, content.as_bytes()),
+            leaf_hashing,
+            name,
+            content,
+        })
+        .collect();
+    let tombstones = ["", "leaf 0"]
+        .into_iter()
+        .map(|content| {
+            let leaf = hash_content(content);
+            TombstoneVector {
+                tombstone: tombstone_of(&leaf),
+                leaf,
+            }
+        })
+        .collect();
+    let trees = (1..=MAX_TREE_SIZE)
+        .map(|size| tree_vector(size).expect("tree is not empty"))
+        .collect();
+    TestVectors {
+        leaves,
+        tombstones,
+        trees,
+    }
+}
+
+/// Recomputes every vector from its inputs, nothing is returned if this build agrees with all
+/// of them
+pub fn check(vectors: &TestVectors) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    let mut compare = |vector: String, expected: String, actual: String| {
+        if expected != actual {
+            mismatches.push(Mismatch {
+                vector,
+                expected,
+                actual,
+            });
+        }
+    };
+    for vector in &vectors.leaves {
+        let leaf = vector
+            .leaf_hashing
+            .hasher()
+            .leaf(&vector.name, vector.content.as_bytes());
+        compare(
+            format!(
+                "{} leaf of {:?} named {:?}",
+                vector.leaf_hashing, vector.content, vector.name
+            ),
+            vector.leaf.to_string(),
+            leaf.to_string(),
+        );
+    }
+    for vector in &vectors.tombstones {
+        compare(
+            format!("tombstone of {}", vector.leaf),
+            vector.tombstone.to_string(),
+            tombstone_of(&vector.leaf).to_string(),
+        );
+    }
+    for vector in &vectors.trees {
+        let Some(actual) = tree_vector(vector.size) else {
+            compare(
+                format!("root of {} leaves", vector.size),
+                vector.root.to_string(),
+                "none, empty tree has no root".to_string(),
+            );
+            continue;
+        };
+        compare(
+            format!("root of {} leaves", vector.size),
+            vector.root.to_string(),
+            actual.root.to_string(),
+        );
+        for (index, (expected, proof)) in vector.proofs.iter().zip(&actual.proofs).enumerate() {
+            compare(
+                format!("proof of leaf {index} of {} leaves", vector.size),
+                to_json(expected),
+                to_json(proof),
+            );
+        }
+        let leaf = |index| hash_content(format!("leaf {index}"));
+        if vector.proofs.len() != vector.size
+            || (0..vector.size)
+                .any(|index| !vector.proofs[index].verify(&vector.root, &leaf(index)))
+        {
+            compare(
+                format!("proofs of {} leaves", vector.size),
+                "all leaves proven".to_string(),
+                "proofs not verifying against root".to_string(),
+            );
+        }
+    }
+    mismatches
+}
+
+fn leaf_inputs() -> Vec<(LeafHashing, String, String)> {
+    let strategies = [
+        LeafHashing::Content,
+        LeafHashing::NameAndContent,
+        LeafHashing::NameAndHash,
+        LeafHashing::Chunked(4),
+        LeafHashing::Keyed(b"key".to_vec()),
+    ];
+    let inputs = [
+        ("a.txt", ""),
+        ("a.txt", "content"),
+        ("docs/café.txt", "0123456789"),
+    ];
+    strategies
+        .iter()
+        .flat_map(|strategy| {
+            inputs
+                .iter()
+                .map(|(name, content)| (strategy.clone(), name.to_string(), content.to_string()))
+        })
+        .collect()
+}
+
+/// Vector of tree of given size, none for the empty tree which has no root
+fn tree_vector(size: usize) -> Option<TreeVector> {
+    let tree =
+        Sha3Tree::from_manifest((0..size).map(|index| hash_content(format!("leaf {index}"))));
+    Some(TreeVector {
+        size,
+        root: tree.root()?,
+        proofs: (0..size)
+            .map(|index| tree.proof_for(index).expect("leaf is not pruned"))
+            .collect(),
+    })
+}
+
+fn to_json(proof: &Sha3Proof) -> String {
+    serde_json::to_string(proof).expect("proof should serialize")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_canonical_vectors() {
+        assert_eq!(check(&canonical()), Vec::new());
+        assert_eq!(generate(), canonical());
+    }
+
+    #[test]
+    fn test_check_reports_mismatch() {
+        let mut vectors = canonical();
+        vectors.trees[2].root = hash_content(b"other");
+        let mismatches = check(&vectors);
+        assert_eq!(mismatches.len(), 2, "{mismatches:?}");
+        assert_eq!(mismatches[0].vector, "root of 3 leaves");
+
+        let mut vectors = canonical();
+        vectors.trees[0].size = 0;
+        let mismatches = check(&vectors);
+        assert_eq!(mismatches.len(), 1, "{mismatches:?}");
+        assert_eq!(mismatches[0].vector, "root of 0 leaves");
+    }
+}
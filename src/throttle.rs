@@ -0,0 +1,85 @@
+use anyhow::anyhow;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Bandwidth limit in bytes per second, parsed from values like `800`, `500K` or `2M`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    bytes_per_sec: u64,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "rate limit must be positive");
+        Self { bytes_per_sec }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// How long transfer of given amount of bytes should take at this rate
+    pub fn duration_for(&self, bytes: usize) -> Duration {
+        Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64)
+    }
+
+    /// Chunk size to split transfers into, so that each chunk takes roughly 100ms
+    pub fn chunk_size(&self) -> usize {
+        (self.bytes_per_sec / 10).clamp(1, 64 * 1024) as usize
+    }
+
+    /// Sleeps for the remaining time if `bytes` transferred since `started` went faster than allowed
+    pub async fn pace(&self, bytes: usize, started: Instant) {
+        let expected = self.duration_for(bytes);
+        let elapsed = started.elapsed();
+        if expected > elapsed {
+            tokio::time::sleep(expected - elapsed).await;
+        }
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&s[..s.len() - 1], 1024),
+            Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+            Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+            _ => (s, 1),
+        };
+        let bytes_per_sec = number
+            .parse::<u64>()?
+            .checked_mul(multiplier)
+            .ok_or_else(|| anyhow!("rate limit {s} is too big"))?;
+        if bytes_per_sec == 0 {
+            return Err(anyhow!("rate limit must be positive"));
+        }
+        Ok(RateLimit { bytes_per_sec })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(RateLimit::from_str("800").unwrap().bytes_per_sec(), 800);
+        assert_eq!(RateLimit::from_str("5k").unwrap().bytes_per_sec(), 5 * 1024);
+        assert_eq!(
+            RateLimit::from_str("2M").unwrap().bytes_per_sec(),
+            2 * 1024 * 1024
+        );
+        assert!(RateLimit::from_str("0").is_err());
+        assert!(RateLimit::from_str("fast").is_err());
+    }
+
+    #[test]
+    fn test_duration() {
+        let limit = RateLimit::new(1000);
+        assert_eq!(limit.duration_for(500), Duration::from_millis(500));
+        assert_eq!(limit.chunk_size(), 100);
+    }
+}
@@ -0,0 +1,412 @@
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::fmt::{Display, Formatter};
+use std::future::{ready, Ready};
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// W3C trace context header, propagated from incoming requests to outgoing ones
+pub const TRACEPARENT: &str = "traceparent";
+/// Spans sent to collector in one request at most
+const EXPORT_BATCH: usize = 512;
+/// How long finished spans wait for more of them before they are sent
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Position of a request in a distributed trace: trace it belongs to and the span which is the
+/// parent of spans started from it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Starts a new sampled trace
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: (u128::from(random_id()) << 64) | u128::from(random_id()),
+            span_id: random_id(),
+            sampled: true,
+        }
+    }
+
+    /// New span of the same trace, e.g. for outgoing request made while handling this one
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: random_id(),
+            ..*self
+        }
+    }
+}
+
+/// Parsed from `traceparent` header value, `00-<32 hex trace id>-<16 hex span id>-<2 hex flags>`.
+/// Values of later versions are read as version 00 as long as they start the same way.
+impl FromStr for TraceContext {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.trim().split('-').collect();
+        let invalid = || anyhow!("invalid traceparent: {s}");
+        let (version, trace_id, span_id, flags) = match parts[..] {
+            [version, trace_id, span_id, flags, ..] => (version, trace_id, span_id, flags),
+            _ => return Err(invalid()),
+        };
+        let is_hex = |part: &str, len: usize| {
+            part.len() == len && part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        if !is_hex(version, 2)
+            || version == "ff"
+            || (version == "00" && parts.len() != 4)
+            || !is_hex(trace_id, 32)
+            || !is_hex(span_id, 16)
+            || !is_hex(flags, 2)
+        {
+            return Err(invalid());
+        }
+        let context = Self {
+            trace_id: u128::from_str_radix(trace_id, 16)?,
+            span_id: u64::from_str_radix(span_id, 16)?,
+            sampled: u8::from_str_radix(flags, 16)? & 1 == 1,
+        };
+        if context.trace_id == 0 || context.span_id == 0 {
+            return Err(invalid());
+        }
+        Ok(context)
+    }
+}
+
+impl Display for TraceContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id,
+            self.span_id,
+            u8::from(self.sampled)
+        )
+    }
+}
+
+/// Trace context of request being handled, continuing the one of the caller if it sent valid
+/// `traceparent`. Requests outside of tracing middleware start a new trace.
+impl FromRequest for TraceContext {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let context = req.extensions().get::<TraceContext>().copied();
+        ready(Ok(context.unwrap_or_else(TraceContext::new_root)))
+    }
+}
+
+/// Context sent by the caller, if any, and context of the server span handling the request,
+/// which is also stored in request extensions for handlers
+pub fn start_server_span(req: &ServiceRequest) -> (Option<TraceContext>, TraceContext) {
+    let parent = req
+        .headers()
+        .get(TRACEPARENT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<TraceContext>().ok());
+    let context = parent.map_or_else(TraceContext::new_root, |parent| parent.child());
+    req.extensions_mut().insert(context);
+    (parent, context)
+}
+
+/// Random non zero id, unique enough for tracing without pulling a random number generator in
+fn random_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish().max(1)
+}
+
+/// Finished server span
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub context: TraceContext,
+    /// span of the caller, missing when request started a new trace
+    pub parent_id: Option<u64>,
+    pub name: String,
+    pub started: SystemTime,
+    pub finished: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+    pub failed: bool,
+}
+
+impl Span {
+    /// Span of handled request, named after its route pattern so ids don't make every span unique
+    pub fn of_response<B>(
+        response: &ServiceResponse<B>,
+        parent: Option<TraceContext>,
+        context: TraceContext,
+        started: SystemTime,
+    ) -> Self {
+        let request = response.request();
+        let route = request
+            .match_pattern()
+            .unwrap_or_else(|| request.path().to_string());
+        let status = response.status();
+        Self {
+            context,
+            parent_id: parent.map(|parent| parent.span_id),
+            name: format!("{} {route}", request.method()),
+            started,
+            finished: SystemTime::now(),
+            attributes: vec![
+                ("http.request.method", request.method().to_string()),
+                ("http.route", route),
+                ("http.response.status_code", status.as_u16().to_string()),
+            ],
+            failed: status.is_server_error(),
+        }
+    }
+}
+
+/// Sends finished spans to OpenTelemetry collector over OTLP/HTTP with json encoding, in
+/// batches, from a background task. Spans are dropped if collector can't be reached.
+#[derive(Clone)]
+pub struct OtlpExporter {
+    spans: mpsc::UnboundedSender<Span>,
+}
+
+impl OtlpExporter {
+    /// Starts exporting to collector at given base url, e.g. `http://localhost:4318`, must be
+    /// called within tokio runtime
+    pub fn spawn(endpoint: &str, service_name: &str) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Span>();
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        let service_name = service_name.to_string();
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            let mut batch = Vec::new();
+            let mut deadline = Instant::now() + EXPORT_INTERVAL;
+            loop {
+                let received = tokio::time::timeout_at(deadline, receiver.recv()).await;
+                let closed = matches!(received, Ok(None));
+                if let Ok(Some(span)) = received {
+                    batch.push(span);
+                    if batch.len() < EXPORT_BATCH {
+                        continue;
+                    }
+                }
+                if !batch.is_empty() {
+                    let payload = export_request(&service_name, batch.drain(..));
+                    let sent = client.post(&url).json(&payload).send().await;
+                    if let Err(err) = sent.and_then(|resp| resp.error_for_status()) {
+                        eprintln!("failed to export spans: {err}");
+                    }
+                }
+                deadline = Instant::now() + EXPORT_INTERVAL;
+                if closed {
+                    return;
+                }
+            }
+        });
+        Self { spans: sender }
+    }
+
+    pub fn export(&self, span: Span) {
+        if span.context.sampled {
+            // exporter task only stops once all senders are gone
+            let _ = self.spans.send(span);
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<OtlpSpan>,
+}
+
+#[derive(Serialize)]
+struct Scope {
+    name: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OtlpSpan {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: String,
+    /// 2 is server span
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>,
+    status: Status,
+}
+
+#[derive(Serialize)]
+struct KeyValue {
+    key: &'static str,
+    value: AnyValue,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    string_value: String,
+}
+
+/// 0 is unset, 2 is error
+#[derive(Serialize)]
+struct Status {
+    code: u8,
+}
+
+fn key_value(key: &'static str, value: String) -> KeyValue {
+    KeyValue {
+        key,
+        value: AnyValue {
+            string_value: value,
+        },
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn export_request(service_name: &str, spans: impl Iterator<Item = Span>) -> ExportRequest {
+    let spans = spans
+        .map(|span| OtlpSpan {
+            trace_id: format!("{:032x}", span.context.trace_id),
+            span_id: format!("{:016x}", span.context.span_id),
+            parent_span_id: span.parent_id.map(|parent_id| format!("{parent_id:016x}")),
+            name: span.name,
+            kind: 2,
+            start_time_unix_nano: unix_nanos(span.started),
+            end_time_unix_nano: unix_nanos(span.finished),
+            attributes: span
+                .attributes
+                .into_iter()
+                .map(|(key, value)| key_value(key, value))
+                .collect(),
+            status: Status {
+                code: if span.failed { 2 } else { 0 },
+            },
+        })
+        .collect();
+    ExportRequest {
+        resource_spans: vec![ResourceSpans {
+            resource: Resource {
+                attributes: vec![key_value("service.name", service_name.to_string())],
+            },
+            scope_spans: vec![ScopeSpans {
+                scope: Scope {
+                    name: "safe-storage",
+                },
+                spans,
+            }],
+        }],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context: TraceContext = header.parse().expect("should parse");
+        assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(context.span_id, 0x00f067aa0ba902b7);
+        assert!(context.sampled);
+        assert_eq!(context.to_string(), header);
+
+        let child = context.child();
+        assert_eq!((child.trace_id, child.sampled), (context.trace_id, true));
+        assert_ne!(child.span_id, context.span_id);
+
+        let future: TraceContext = "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-x"
+            .parse()
+            .expect("should parse later version");
+        assert!(!future.sampled);
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6-00f067aa0ba902b7-01",
+            "",
+        ] {
+            assert!(invalid.parse::<TraceContext>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_export_request() {
+        let context: TraceContext = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            .parse()
+            .unwrap();
+        let span = Span {
+            context,
+            parent_id: Some(1),
+            name: "GET /files/{id}".to_string(),
+            started: UNIX_EPOCH + Duration::from_secs(1),
+            finished: UNIX_EPOCH + Duration::from_secs(2),
+            attributes: vec![("http.response.status_code", "200".to_string())],
+            failed: false,
+        };
+        let payload = serde_json::to_string(&export_request("test", [span].into_iter()))
+            .expect("should serialize");
+        for expected in [
+            r#""traceId":"4bf92f3577b34da6a3ce929d0e0e4736""#,
+            r#""spanId":"00f067aa0ba902b7""#,
+            r#""parentSpanId":"0000000000000001""#,
+            r#""startTimeUnixNano":"1000000000""#,
+            r#"{"key":"http.response.status_code","value":{"stringValue":"200"}}"#,
+            r#"{"key":"service.name","value":{"stringValue":"test"}}"#,
+        ] {
+            assert!(payload.contains(expected), "{expected} in {payload}");
+        }
+    }
+
+    #[test]
+    fn test_new_root() {
+        let (first, second) = (TraceContext::new_root(), TraceContext::new_root());
+        assert_ne!(first.trace_id, second.trace_id);
+        assert_eq!(first.to_string().parse::<TraceContext>().unwrap(), first);
+    }
+}
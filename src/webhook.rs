@@ -0,0 +1,399 @@
+use crate::api::{File, FileId, RootHash, TreeSize};
+use crate::storage::{Storage, StorageError};
+use anyhow::anyhow;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha3::Sha3_256;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Header carrying signature of webhook payload, `t=<unix secs>,v1=<hex hmac>`
+pub const SIGNATURE_HEADER: &str = "safe-storage-signature";
+
+/// Environment variable server reads webhook signing key from
+pub const WEBHOOK_KEY_ENV: &str = "SAFE_STORAGE_WEBHOOK_KEY";
+
+/// How far signature timestamp may be from receiver's clock, older deliveries are taken for
+/// replays
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+/// How often storage is checked for changes to deliver by default
+pub const DEFAULT_WEBHOOK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Leaves whose changes are delivered in one request at most, a receiver which was down gets
+/// the backlog in several deliveries rather than in one growing with every retry
+pub const MAX_DELIVERY_LEAVES: u64 = 1000;
+
+/// Change of stored files, delivered in [`WebhookDelivery`]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum WebhookEvent {
+    /// file got its leaf appended to the tree
+    FileStored { file: File },
+    /// file was tombstoned, by request or because it expired
+    FileDeleted { id: FileId },
+    /// tree grew, sent after the file events which grew it by the delivery catching up with
+    /// the tree
+    RootChanged { root: RootHash },
+}
+
+/// Body of a webhook request, events in the order they happened
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    /// header is not `t=<unix secs>,v1=<hex hmac>`
+    Malformed,
+    /// payload was not signed with the key
+    Mismatch,
+    /// signed further from now than tolerated, with the distance in seconds
+    Stale(u64),
+}
+
+impl Display for SignatureError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::Malformed => write!(f, "malformed webhook signature header"),
+            SignatureError::Mismatch => write!(f, "webhook signature doesn't match payload"),
+            SignatureError::Stale(secs) => {
+                write!(f, "webhook was signed {secs}s away from now")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// HMAC-SHA3-256 of `<timestamp>.<payload>`, so a captured delivery can't be replayed later
+/// under fresh timestamp
+fn mac(payload: &[u8], key: &[u8], timestamp: u64) -> Hmac<Sha3_256> {
+    let mut mac = Hmac::<Sha3_256>::new_from_slice(key).expect("hmac takes keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload);
+    mac
+}
+
+/// Value of [`SIGNATURE_HEADER`] of payload sent at given unix time
+pub fn sign(payload: &[u8], key: &[u8], timestamp: u64) -> String {
+    let signature = mac(payload, key, timestamp).finalize().into_bytes();
+    format!("t={timestamp},v1={}", hex::encode(signature))
+}
+
+/// Checks that payload of received webhook was signed with the key within
+/// [`DEFAULT_TOLERANCE`] from now, before anything in it is trusted
+pub fn verify_signature(payload: &[u8], header: &str, key: &[u8]) -> Result<(), SignatureError> {
+    verify_signature_at(payload, header, key, SystemTime::now(), DEFAULT_TOLERANCE)
+}
+
+/// [`verify_signature`] against given clock and tolerance
+pub fn verify_signature_at(
+    payload: &[u8],
+    header: &str,
+    key: &[u8],
+    now: SystemTime,
+    tolerance: Duration,
+) -> Result<(), SignatureError> {
+    let mut timestamp = None;
+    let mut signature = None;
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<u64>().ok(),
+            Some(("v1", value)) => signature = hex::decode(value).ok(),
+            // unknown schemes are skipped, so signatures of newer ones can be sent alongside
+            _ => {}
+        }
+    }
+    let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+        return Err(SignatureError::Malformed);
+    };
+    mac(payload, key, timestamp)
+        .verify_slice(&signature)
+        .map_err(|_| SignatureError::Mismatch)?;
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let distance = now.abs_diff(timestamp);
+    if distance > tolerance.as_secs() {
+        return Err(SignatureError::Stale(distance));
+    }
+    Ok(())
+}
+
+/// Endpoint changes of storage are posted to, signed with a key shared with the receiver
+pub struct Webhook {
+    url: String,
+    key: Vec<u8>,
+    interval: Duration,
+    cursor_file: Option<PathBuf>,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(url: impl Into<String>, key: impl Into<Vec<u8>>) -> Self {
+        Self {
+            url: url.into(),
+            key: key.into(),
+            interval: DEFAULT_WEBHOOK_INTERVAL,
+            cursor_file: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Reads key from [`WEBHOOK_KEY_ENV`]
+    pub fn from_env(url: impl Into<String>) -> anyhow::Result<Self> {
+        let key =
+            std::env::var(WEBHOOK_KEY_ENV).map_err(|_| anyhow!("{WEBHOOK_KEY_ENV} is not set"))?;
+        if key.is_empty() {
+            return Err(anyhow!("{WEBHOOK_KEY_ENV} must not be empty"));
+        }
+        Ok(Self::new(url, key))
+    }
+
+    /// Storage is checked for changes this often, changes in between are delivered together
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Tree size changes were delivered up to is kept in given file, so changes made while
+    /// server was down or receiver was failing are delivered after restart instead of skipped
+    pub fn with_cursor_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cursor_file = Some(path.into());
+        self
+    }
+
+    /// Tree size stored by [`Webhook::store_cursor`], none without cursor file or before the
+    /// first delivery
+    pub async fn load_cursor(&self) -> io::Result<Option<TreeSize>> {
+        let Some(path) = &self.cursor_file else {
+            return Ok(None);
+        };
+        match tokio::fs::read_to_string(path).await {
+            Ok(cursor) => cursor.trim().parse().map(Some).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} doesn't hold a tree size", path.display()),
+                )
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Replaces cursor file, if any, with given tree size. Written aside and renamed, so a crash
+    /// leaves either the old or the new cursor.
+    pub async fn store_cursor(&self, delivered: TreeSize) -> io::Result<()> {
+        let Some(path) = &self.cursor_file else {
+            return Ok(());
+        };
+        let written = path.with_extension("tmp");
+        tokio::fs::write(&written, delivered.to_string()).await?;
+        tokio::fs::rename(&written, path).await
+    }
+
+    /// Posts signed events, failing unless receiver answers with success
+    pub async fn deliver(&self, events: Vec<WebhookEvent>) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(&WebhookDelivery { events })?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.client
+            .post(&self.url)
+            .header(SIGNATURE_HEADER, sign(&payload, &self.key, timestamp))
+            .header("content-type", "application/json")
+            .body(payload)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Delivers changes of storage since given tree size, of [`MAX_DELIVERY_LEAVES`] leaves at
+    /// most, returning the size they were delivered up to. Nothing is delivered if the tree
+    /// didn't grow.
+    pub async fn deliver_changes(
+        &self,
+        storage: &Mutex<Storage>,
+        since: TreeSize,
+    ) -> anyhow::Result<TreeSize> {
+        let (events, tree_size) = {
+            let storage = storage.lock().expect("should lock");
+            changes(&storage, since)?
+        };
+        if !events.is_empty() {
+            self.deliver(events).await?;
+        }
+        Ok(tree_size)
+    }
+}
+
+/// Events of files stored and deleted in up to [`MAX_DELIVERY_LEAVES`] leaves since given tree
+/// size and of the root they led to, once they reach the current tree. Deletions append
+/// tombstone leaves, so they grow the tree as well.
+fn changes(
+    storage: &Storage,
+    since: TreeSize,
+) -> Result<(Vec<WebhookEvent>, TreeSize), StorageError> {
+    if storage.tree_size() <= since {
+        return Ok((Vec::new(), since));
+    }
+    let until = TreeSize(since.0.saturating_add(MAX_DELIVERY_LEAVES));
+    let (files, deleted, tree_size) =
+        storage.list_changes_until(since, until, SystemTime::now())?;
+    let mut events: Vec<_> = files
+        .into_iter()
+        .map(|file| WebhookEvent::FileStored { file })
+        .chain(
+            deleted
+                .into_iter()
+                .map(|id| WebhookEvent::FileDeleted { id }),
+        )
+        .collect();
+    if tree_size < storage.tree_size() {
+        return Ok((events, tree_size));
+    }
+    if let Some(hash) = storage.root_hash() {
+        events.push(WebhookEvent::RootChanged {
+            root: RootHash {
+                hash,
+                size: storage.epoch_size().into(),
+                epoch: storage.current_epoch(),
+            },
+        });
+    }
+    Ok((events, tree_size))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify_signature() {
+        let payload = br#"{"events":[]}"#;
+        let now = SystemTime::now();
+        let timestamp = now
+            .duration_since(UNIX_EPOCH)
+            .expect("should be after epoch")
+            .as_secs();
+        let header = sign(payload, b"secret", timestamp);
+        assert_eq!(verify_signature(payload, &header, b"secret"), Ok(()));
+        assert_eq!(
+            verify_signature(br#"{"events":[{}]}"#, &header, b"secret"),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            verify_signature(payload, &header, b"other"),
+            Err(SignatureError::Mismatch)
+        );
+        let later = now + Duration::from_secs(600);
+        assert_eq!(
+            verify_signature_at(payload, &header, b"secret", later, DEFAULT_TOLERANCE),
+            Err(SignatureError::Stale(600))
+        );
+        let resigned = header.replacen(&timestamp.to_string(), &(timestamp + 600).to_string(), 1);
+        assert_eq!(
+            verify_signature_at(payload, &resigned, b"secret", later, DEFAULT_TOLERANCE),
+            Err(SignatureError::Mismatch)
+        );
+        let with_newer_scheme = format!("{header},v2=abcd");
+        assert_eq!(
+            verify_signature(payload, &with_newer_scheme, b"secret"),
+            Ok(())
+        );
+        for malformed in ["", "t=1", "v1=00", "t=x,v1=00", "t=1,v1=zz"] {
+            assert_eq!(
+                verify_signature(payload, malformed, b"secret"),
+                Err(SignatureError::Malformed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut storage = Storage::new();
+        let first = storage
+            .add_new_file("a.txt".to_string(), b"a".to_vec())
+            .expect("should add");
+        let (events, size) = changes(&storage, TreeSize(0)).expect("should list changes");
+        assert_eq!(size, TreeSize(1));
+        assert!(matches!(&events[..], [
+            WebhookEvent::FileStored { file },
+            WebhookEvent::RootChanged { root },
+        ] if file.id == first && root.size == TreeSize(1)));
+        assert!(changes(&storage, size)
+            .expect("should list changes")
+            .0
+            .is_empty());
+
+        storage.delete_file(first).expect("should delete");
+        let (events, _) = changes(&storage, size).expect("should list changes");
+        assert!(matches!(&events[..], [
+            WebhookEvent::FileDeleted { id },
+            WebhookEvent::RootChanged { .. },
+        ] if *id == first));
+
+        let json = serde_json::to_value(&events[0]).expect("should serialize");
+        assert_eq!(json["type"], "file-deleted");
+        let read: WebhookEvent = serde_json::from_value(json).expect("should deserialize");
+        assert!(matches!(read, WebhookEvent::FileDeleted { id } if id == first));
+    }
+
+    #[test]
+    fn test_changes_are_bounded() {
+        let mut storage = Storage::new();
+        for i in 0..MAX_DELIVERY_LEAVES + 1 {
+            storage
+                .add_new_file(format!("{i}.txt"), i.to_string().into_bytes())
+                .expect("should add");
+        }
+        let (events, size) = changes(&storage, TreeSize(0)).expect("should list changes");
+        assert_eq!(size, TreeSize(MAX_DELIVERY_LEAVES));
+        assert_eq!(events.len() as u64, MAX_DELIVERY_LEAVES);
+        assert!(events
+            .iter()
+            .all(|event| matches!(event, WebhookEvent::FileStored { .. })));
+        let (events, size) = changes(&storage, size).expect("should list changes");
+        assert_eq!(size, storage.tree_size());
+        assert!(matches!(&events[..], [
+            WebhookEvent::FileStored { .. },
+            WebhookEvent::RootChanged { root },
+        ] if root.size == size));
+    }
+
+    #[tokio::test]
+    async fn test_cursor_file() {
+        let path = std::env::temp_dir().join("safe_storage_webhook_cursor_file");
+        let _ = std::fs::remove_file(&path);
+        let webhook = Webhook::new("http://127.0.0.1/hook", "secret");
+        webhook
+            .store_cursor(TreeSize(3))
+            .await
+            .expect("should skip without file");
+        assert_eq!(webhook.load_cursor().await.expect("should load"), None);
+
+        let webhook = webhook.with_cursor_file(&path);
+        assert_eq!(webhook.load_cursor().await.expect("should load"), None);
+        webhook
+            .store_cursor(TreeSize(3))
+            .await
+            .expect("should store");
+        assert_eq!(
+            webhook.load_cursor().await.expect("should load"),
+            Some(TreeSize(3))
+        );
+        std::fs::write(&path, "garbage").expect("should write");
+        assert!(webhook.load_cursor().await.is_err());
+        std::fs::remove_file(&path).expect("should remove");
+    }
+}